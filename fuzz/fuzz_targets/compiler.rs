@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_vm::compiler::{Compiler, Parser};
+use simple_vm::fuzz::{run_bounded, TokenStream};
+
+fuzz_target!(|tokens: TokenStream| {
+    let Ok(statements) = Parser::new(&tokens.source()).parse_program() else {
+        return;
+    };
+    if let Ok(bytecode) = Compiler::new().compile(statements) {
+        let _ = run_bounded(bytecode, 10_000);
+    }
+});
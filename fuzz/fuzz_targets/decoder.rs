@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_vm::{bytecode, disasm};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = bytecode::decode(data);
+    let _ = disasm::disassemble(data);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_vm::fuzz::{run_bounded, StructuredProgram};
+
+fuzz_target!(|program: StructuredProgram| {
+    let _ = run_bounded(program.to_bytecode(), 10_000);
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_vm::fuzz::{run_bounded, StructuredProgram, STACK_LIMIT};
+use simple_vm::stack_depth;
+
+// A program the verifier accepts must stay within the stack limit it was
+// verified for
+fuzz_target!(|program: StructuredProgram| {
+    let bytecode = program.to_bytecode();
+    if stack_depth::verify(&bytecode, STACK_LIMIT).is_ok() {
+        let result = run_bounded(bytecode, 10_000);
+        assert!(!matches!(result, Err(simple_vm::VMError::StackOverflow)));
+    }
+});
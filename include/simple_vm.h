@@ -0,0 +1,67 @@
+/* C API of simple-vm, in the shared library built with
+ *   cargo rustc --release --lib --features capi --crate-type cdylib
+ * as target/release/libsimple_vm.so (libsimple_vm.dylib on macOS,
+ * simple_vm.dll on Windows). */
+#ifndef SIMPLE_VM_H
+#define SIMPLE_VM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum SvmStatus {
+    SVM_OK = 0,
+    SVM_NULL_POINTER = 1,
+    SVM_INVALID_UTF8 = 2,
+    SVM_COMPILE_ERROR = 3,
+    /* A Rust panic was caught; only free the VM afterwards */
+    SVM_PANIC = 4,
+    SVM_STACK_UNDERFLOW = 10,
+    SVM_STACK_OVERFLOW = 11,
+    SVM_INVALID_OPCODE = 12,
+    SVM_OUT_OF_MEMORY = 13,
+    SVM_DIVISION_BY_ZERO = 14,
+    SVM_INVALID_STRING = 15,
+    SVM_INVALID_CONSTANT = 16,
+    SVM_INDEX_OUT_OF_BOUNDS = 17,
+    SVM_INPUT_EXHAUSTED = 18,
+    SVM_INVALID_INPUT = 19,
+    SVM_OUTPUT_FAILED = 20,
+    SVM_ASSERTION_FAILED = 21,
+    SVM_INVALID_ARGUMENT = 22,
+    SVM_KEY_NOT_FOUND = 23,
+    SVM_UNCAUGHT_EXCEPTION = 24,
+    SVM_UNKNOWN_HOST_FUNCTION = 25,
+    SVM_HOST_ARITY_MISMATCH = 26,
+    SVM_UNKNOWN_CHANNEL = 27,
+    SVM_CHANNEL_EMPTY = 28,
+    SVM_TYPE_MISMATCH = 29
+} SvmStatus;
+
+typedef struct SvmVm SvmVm;
+
+/* The message of the last failed call on this thread, or NULL. Valid
+ * until the next call on the thread fails. */
+const char *svm_last_error(void);
+
+/* Compiles `source`; free the bytecode with svm_bytecode_free. */
+SvmStatus svm_compile(const char *source, uint8_t **bytecode, size_t *len);
+void svm_bytecode_free(uint8_t *bytecode, size_t len);
+
+/* Creates a VM for a copy of the bytecode; NULL if `bytecode` is NULL. */
+SvmVm *svm_vm_new(const uint8_t *bytecode, size_t len, size_t stack_limit);
+SvmStatus svm_vm_run(SvmVm *vm);
+/* Copies up to `capacity` values from the bottom of the stack to `out` and
+ * returns the stack's size. */
+size_t svm_vm_get_stack(const SvmVm *vm, int64_t *out, size_t capacity);
+int64_t svm_vm_exit_code(const SvmVm *vm);
+void svm_vm_free(SvmVm *vm);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SIMPLE_VM_H */
@@ -0,0 +1,194 @@
+//! Macros that compile simple-vm programs while the Rust crate embedding
+//! them builds, so a script with a syntax or type error fails `cargo build`
+//! instead of the program that runs it.
+
+use std::path::PathBuf;
+
+use proc_macro2::{Delimiter, Spacing, Span, TokenStream, TokenTree};
+use quote::quote;
+use simple_vm::compiler::{self, Compiler, ModuleLoader, Parser};
+use syn::LitStr;
+
+/// Compiles the program written inside the braces, or in a string literal,
+/// to bytecode, expanding to a `&'static [u8]` for `VM::new(....to_vec(), ...)`.
+///
+/// Rust's tokenizer reads the program first, so comments are dropped and
+/// characters Rust does not accept outside a string need the string form.
+/// Errors point at the token they were found at.
+#[proc_macro]
+pub fn svm(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = TokenStream::from(input);
+    let (source, spans) = match syn::parse2::<LitStr>(input.clone()) {
+        Ok(literal) => (literal.value(), vec![(0..0, literal.span())]),
+        Err(_) => {
+            let mut source = Source::default();
+            source.push_stream(input);
+            (source.text, source.spans)
+        }
+    };
+    let bytecode = match compile(&source) {
+        Ok(bytecode) => bytecode,
+        Err(errors) => {
+            let errors = errors.into_iter().map(|(message, span)| {
+                let span = spans
+                    .iter()
+                    .find(|(range, _)| range.contains(&span.offset))
+                    .or(spans.first())
+                    .map_or_else(Span::call_site, |(_, span)| *span);
+                syn::Error::new(span, message)
+            });
+            let error = errors
+                .reduce(|mut first, error| {
+                    first.combine(error);
+                    first
+                })
+                .expect("a failed compile has an error");
+            return error.to_compile_error().into();
+        }
+    };
+    expand(&bytecode, TokenStream::new()).into()
+}
+
+/// Compiles the program at a path relative to the crate's `Cargo.toml`,
+/// with the modules it imports, expanding to its bytecode as a
+/// `&'static [u8]`. The crate is rebuilt when the file or a module it
+/// imports changes.
+#[proc_macro]
+pub fn svm_file(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let literal = match syn::parse::<LitStr>(input) {
+        Ok(literal) => literal,
+        Err(error) => return error.to_compile_error().into(),
+    };
+    let root = std::env::var_os("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let path = PathBuf::from(root).join(literal.value());
+    let mut loader = ModuleLoader::new();
+    let compiled = loader.load(&path).and_then(|statements| {
+        Compiler::new()
+            .compile(statements)
+            .map_err(|error| loader.locate(&error))
+    });
+    match compiled {
+        Ok(bytecode) => {
+            let track = loader.files().iter().map(|path| {
+                let path = path.to_string_lossy();
+                quote! { const _: &[u8] = include_bytes!(#path); }
+            });
+            expand(&bytecode, track.collect()).into()
+        }
+        Err(message) => syn::Error::new(literal.span(), message)
+            .to_compile_error()
+            .into(),
+    }
+}
+
+/// Error messages, each with its location.
+type Errors = Vec<(String, compiler::Span)>;
+
+/// Compiles a program with the prelude, returning every syntax error, or
+/// the error that stopped compiling.
+fn compile(source: &str) -> Result<Vec<u8>, Errors> {
+    let statements = Parser::new(source).parse_program().map_err(|errors| {
+        errors
+            .iter()
+            .map(|error| (error.message(), error.span()))
+            .collect::<Errors>()
+    })?;
+    Compiler::new()
+        .compile(statements)
+        .map_err(|error| vec![(error.message(), error.span())])
+}
+
+fn expand(bytecode: &[u8], items: TokenStream) -> TokenStream {
+    let bytes = syn::LitByteStr::new(bytecode, Span::call_site());
+    quote! {
+        {
+            #items
+            const BYTECODE: &[u8] = #bytes;
+            BYTECODE
+        }
+    }
+}
+
+/// Source text rebuilt from Rust tokens, with the byte range each token
+/// was written to and its span, to report errors at the right token.
+#[derive(Default)]
+struct Source {
+    text: String,
+    spans: Vec<(std::ops::Range<usize>, Span)>,
+    /// Whether the last token was punctuation joined to the next, as the
+    /// `=` of `==`
+    joint: bool,
+}
+
+impl Source {
+    fn push_stream(&mut self, stream: TokenStream) {
+        for tree in stream {
+            match tree {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => ("", ""),
+                    };
+                    self.push(open, group.span_open());
+                    self.push_stream(group.stream());
+                    self.push(close, group.span_close());
+                }
+                TokenTree::Punct(punct) => {
+                    self.push(&punct.as_char().to_string(), punct.span());
+                    self.joint = punct.spacing() == Spacing::Joint;
+                }
+                TokenTree::Ident(ident) => self.push(&ident.to_string(), ident.span()),
+                TokenTree::Literal(literal) => self.push(&literal.to_string(), literal.span()),
+            }
+        }
+    }
+
+    fn push(&mut self, text: &str, span: Span) {
+        if !self.text.is_empty() && !std::mem::take(&mut self.joint) {
+            self.text.push(' ');
+        }
+        let start = self.text.len();
+        self.text.push_str(text);
+        self.spans
+            .push((start..self.text.len().max(start + 1), span));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proc_macro2::TokenStream;
+
+    use super::{compile, Source};
+
+    #[test]
+    fn rebuilds_source_from_tokens() {
+        let mut source = Source::default();
+        let tokens = r#"let s = "a b"; let n = 1; n++; if n >= 2 && n != 3 { print s, n << 1; }"#;
+        source.push_stream(tokens.parse::<TokenStream>().unwrap());
+        assert_eq!(
+            source.text,
+            "let s = \"a b\" ; let n = 1 ; n ++; if n >= 2 && n != 3 { print s , n << 1 ; }"
+        );
+        assert_eq!(&source.text[source.spans[3].0.clone()], "\"a b\"");
+        assert!(compile(&source.text).is_ok());
+    }
+
+    #[test]
+    fn locates_errors() {
+        let source = "let a = 1 ; let b = ; let c = ) ;";
+        let errors = compile(source).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        let (message, span) = &errors[0];
+        assert_eq!(message, "Expected expression, found ';'");
+        assert_eq!(span.text(source), ";");
+        assert_eq!(errors[1].1.text(source), ")");
+        assert!(compile("let a: int = \"s\" ;").is_err());
+
+        let source = "let a = 1 ; print a + b ;";
+        let errors = compile(source).unwrap_err();
+        assert_eq!(errors[0].0, "Undefined variable 'b'");
+        assert_eq!(errors[0].1.text(source), "b");
+    }
+}
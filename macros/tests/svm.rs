@@ -0,0 +1,34 @@
+use simple_vm::VM;
+use simple_vm_macros::{svm, svm_file};
+
+fn run(bytecode: &[u8]) -> VM {
+    let mut vm = VM::new(bytecode.to_vec(), 100);
+    vm.run().unwrap();
+    vm
+}
+
+#[test]
+fn compiles_inline_programs() {
+    let bytecode: &'static [u8] = svm! {
+        fn square(n) { return n * n; }
+        let total = 0;
+        let i = 1;
+        while i <= 3 {
+            total = total + square(i);
+            i++;
+        }
+        let g = gcd(12, 18);
+    };
+    let vm = run(bytecode);
+    assert_eq!(vm.get_memory()[&0], 14);
+    assert_eq!(vm.get_memory()[&2], 6);
+
+    let vm = run(svm!("let s = \"hi\"; let n = len(s); // counted\n"));
+    assert_eq!(vm.get_memory()[&1], 2);
+}
+
+#[test]
+fn compiles_files() {
+    let vm = run(svm_file!("tests/fixtures/sum.svm"));
+    assert_eq!(vm.get_memory()[&0], 55);
+}
@@ -0,0 +1,261 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Range;
+
+use crate::bytecode::{self, Instruction};
+use crate::cfg::{Cfg, EdgeKind};
+use crate::stack_depth::{self, StackAnalysis, StackError};
+use crate::{Opcode, VMError};
+
+/// Bytes each part of a program takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Sections {
+    pub header: usize,
+    pub code: usize,
+    /// String constants, and any other data but the constant pool
+    pub strings: usize,
+    pub constants: usize,
+}
+
+impl Sections {
+    pub fn total(&self) -> usize {
+        self.header + self.code + self.strings + self.constants
+    }
+}
+
+/// An instruction that transfers control to a jump target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transfer {
+    /// Address of the jump, call or `PushHandler`
+    pub from: usize,
+    pub kind: EdgeKind,
+}
+
+/// An overview of a compiled program's structure.
+#[derive(Debug)]
+pub struct Analysis {
+    pub sections: Sections,
+    /// How many instructions of each opcode the program has, most first
+    pub instruction_mix: Vec<(Opcode, usize)>,
+    /// Each address that is jumped, branched or called to, or installed as
+    /// an exception handler, with the instructions that do so
+    pub jump_targets: BTreeMap<usize, Vec<Transfer>>,
+    /// The most values the stack holds, or why that cannot be known before
+    /// the program runs
+    pub stack: Result<StackAnalysis, StackError>,
+    /// Address ranges of code that no path from the start reaches
+    pub unreachable: Vec<Range<usize>>,
+}
+
+/// Analyzes a program's layout and control flow without running it.
+///
+/// Code counts as reachable when a path of jumps, branches, calls and
+/// handlers leads to it from the start, or from an address the program
+/// pushes, since a function used as a value is called by the address it
+/// was pushed as.
+pub fn analyze(bytecode: &[u8]) -> Result<Analysis, VMError> {
+    let (_, start) = bytecode::read_header(bytecode)?;
+    let instructions = bytecode::decode(bytecode)?;
+    let code_end = instructions.last().map_or(start, Instruction::next_addr);
+    let constants = (0..instructions.len())
+        .filter(|&index| instructions[index].opcode == Opcode::ConstPool)
+        .find_map(|index| bytecode::pushed_operand(&instructions, index))
+        .and_then(|offset| bytecode::constant_pool(bytecode, offset))
+        .map_or(0, |(range, _)| range.len());
+    let sections = Sections {
+        header: start,
+        code: code_end - start,
+        strings: (bytecode.len() - code_end).saturating_sub(constants),
+        constants,
+    };
+
+    let mut counts: BTreeMap<u8, usize> = BTreeMap::new();
+    for instruction in &instructions {
+        *counts.entry(instruction.opcode as u8).or_default() += 1;
+    }
+    let mut instruction_mix: Vec<_> = counts
+        .into_iter()
+        .filter_map(|(byte, count)| Some((Opcode::try_from(byte).ok()?, count)))
+        .collect();
+    instruction_mix.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+    let mut jump_targets: BTreeMap<usize, Vec<Transfer>> = BTreeMap::new();
+    for (index, instruction) in instructions.iter().enumerate() {
+        let kind = match instruction.opcode {
+            Opcode::Jump => EdgeKind::Jump,
+            Opcode::JumpIf => EdgeKind::Branch,
+            Opcode::Call => EdgeKind::Call,
+            Opcode::PushHandler => EdgeKind::Exception,
+            _ => continue,
+        };
+        if let Some(target) = bytecode::pushed_operand(&instructions, index) {
+            jump_targets.entry(target).or_default().push(Transfer {
+                from: instruction.addr,
+                kind,
+            });
+        }
+    }
+
+    Ok(Analysis {
+        sections,
+        instruction_mix,
+        jump_targets,
+        stack: stack_depth::analyze(bytecode),
+        unreachable: unreachable(&Cfg::build(bytecode)?, &instructions),
+    })
+}
+
+/// The blocks no path reaches from the first one or from a pushed address,
+/// with neighbouring blocks joined.
+fn unreachable(cfg: &Cfg, instructions: &[Instruction]) -> Vec<Range<usize>> {
+    let pushed: BTreeSet<usize> = instructions
+        .iter()
+        .filter(|instruction| instruction.opcode == Opcode::Push)
+        .filter_map(|instruction| usize::try_from(instruction.operand?).ok())
+        .collect();
+    let mut reached = BTreeSet::new();
+    let mut pending: Vec<usize> = cfg
+        .blocks
+        .iter()
+        .enumerate()
+        .filter(|(index, block)| *index == 0 || pushed.contains(&block.start))
+        .map(|(_, block)| block.start)
+        .collect();
+    while let Some(start) = pending.pop() {
+        if !reached.insert(start) {
+            continue;
+        }
+        if let Some(block) = cfg.block_at(start) {
+            pending.extend(block.successors.iter().map(|edge| edge.target));
+        }
+    }
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for block in cfg
+        .blocks
+        .iter()
+        .filter(|block| !reached.contains(&block.start))
+    {
+        match ranges.last_mut() {
+            Some(range) if range.end == block.start => range.end = block.end,
+            _ => ranges.push(block.start..block.end),
+        }
+    }
+    ranges
+}
+
+/// Formats the analysis as a report of tables: sections, instruction mix,
+/// jump targets, stack depth and unreachable code.
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sections = &self.sections;
+        writeln!(f, "{:<12} {:>12}", "section", "bytes")?;
+        for (name, bytes) in [
+            ("header", sections.header),
+            ("code", sections.code),
+            ("strings", sections.strings),
+            ("constants", sections.constants),
+            ("total", sections.total()),
+        ] {
+            writeln!(f, "{:<12} {:>12}", name, bytes)?;
+        }
+
+        let total: usize = self.instruction_mix.iter().map(|(_, count)| count).sum();
+        writeln!(f, "\n{:<12} {:>12} {:>8}", "opcode", "count", "%")?;
+        for (opcode, count) in &self.instruction_mix {
+            let name = format!("{:?}", opcode).to_lowercase();
+            let share = *count as f64 * 100.0 / total as f64;
+            writeln!(f, "{:<12} {:>12} {:>7.2}%", name, count, share)?;
+        }
+        writeln!(f, "{:<12} {:>12}", "total", total)?;
+
+        writeln!(f, "\n{:<12} from", "target")?;
+        for (target, transfers) in &self.jump_targets {
+            let sources: Vec<_> = transfers
+                .iter()
+                .map(|transfer| {
+                    let kind = match transfer.kind {
+                        EdgeKind::Jump | EdgeKind::FallThrough => "jump",
+                        EdgeKind::Branch => "branch",
+                        EdgeKind::Call => "call",
+                        EdgeKind::Exception => "handler",
+                    };
+                    format!("{} {:06}", kind, transfer.from)
+                })
+                .collect();
+            writeln!(f, "{:06}       {}", target, sources.join(", "))?;
+        }
+
+        match &self.stack {
+            Ok(stack) => writeln!(
+                f,
+                "\nmax stack depth: {} (at {:06})",
+                stack.max_depth, stack.deepest_pc
+            )?,
+            Err(error) => writeln!(f, "\nmax stack depth: unknown ({})", error)?,
+        }
+
+        writeln!(f, "\n{:<13} {:>11}", "unreachable", "bytes")?;
+        for range in &self.unreachable {
+            writeln!(f, "{:06}-{:06} {:>11}", range.start, range.end, range.len())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::analyze;
+    use crate::cfg::EdgeKind;
+    use crate::compiler::{Compiler, Parser};
+    use crate::Opcode;
+
+    #[test]
+    fn reports_structure_of_hand_written_code() {
+        let bytecode = crate::bytecode![
+            push 1, jumpif end,
+            push 2, print,
+            end: halt,
+            push 3, print
+        ];
+        let analysis = analyze(&bytecode).unwrap();
+        assert_eq!(analysis.sections.code, bytecode.len());
+        assert_eq!(analysis.sections.total(), bytecode.len());
+        assert_eq!(analysis.instruction_mix[0], (Opcode::Push, 4));
+        let targets: Vec<_> = analysis.jump_targets.iter().collect();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(*targets[0].0, 29);
+        assert_eq!(targets[0].1[0].kind, EdgeKind::Branch);
+        assert_eq!(analysis.stack.as_ref().unwrap().max_depth, 2);
+        assert_eq!(analysis.unreachable, vec![30..40]);
+
+        let report = analysis.to_string();
+        assert!(
+            report.contains("000029       branch 000018\n"),
+            "{}",
+            report
+        );
+        assert!(report.contains("max stack depth: 2"), "{}", report);
+        assert!(report.contains("000030-000040          10\n"), "{}", report);
+    }
+
+    #[test]
+    fn reports_sections_and_calls_of_compiled_programs() {
+        let source = "fn twice(x: int) -> int { return 2 * x; }\n\
+                      print twice(21);\n\
+                      print \"done\";";
+        let statements = Parser::new(source).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        let bytecode = compiler.compile(statements).unwrap();
+        let analysis = analyze(&bytecode).unwrap();
+        assert_eq!(analysis.sections.total(), bytecode.len());
+        assert!(analysis.sections.strings >= 8 + "done".len());
+        assert!(analysis
+            .jump_targets
+            .values()
+            .flatten()
+            .any(|transfer| transfer.kind == EdgeKind::Call));
+        // The implicit `return 0` after the function's own return
+        assert_eq!(analysis.unreachable, vec![61..71], "{}", analysis);
+    }
+}
@@ -0,0 +1,778 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::bytecode::{self, OperandEncoding, FLAG_LEB128, MAGIC, PATCHABLE_LEB128_LEN};
+use crate::Opcode;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AsmError {
+    #[error("line {line}: unknown instruction '{name}'")]
+    UnknownInstruction { line: usize, name: String },
+    #[error("line {line}: '{name}' takes an operand")]
+    MissingOperand { line: usize, name: String },
+    #[error("line {line}: '{name}' takes no operand")]
+    UnexpectedOperand { line: usize, name: String },
+    #[error("line {line}: invalid operand '{operand}'")]
+    InvalidOperand { line: usize, operand: String },
+    #[error("line {line}: label '{name}' is not defined")]
+    UndefinedLabel { line: usize, name: String },
+    #[error("line {line}: label '{name}' is already defined")]
+    DuplicateLabel { line: usize, name: String },
+    #[error("line {line}: '.encoding' must come before any instruction or data")]
+    MisplacedEncoding { line: usize },
+}
+
+/// One assembled line: an instruction or a directive, with its operand.
+struct Item<'a> {
+    line: usize,
+    kind: ItemKind<'a>,
+}
+
+enum ItemKind<'a> {
+    /// The header announcing how operands are encoded
+    Encoding(OperandEncoding),
+    /// An instruction, with the operand of `push`, `pushconst`, or the
+    /// target of a jump, call or handler
+    Instruction(Opcode, Option<&'a str>),
+    /// Raw 8-byte little-endian value
+    Int(i64),
+    /// Length-prefixed UTF-8 string, as the compiler lays out its data
+    String(String),
+}
+
+impl ItemKind<'_> {
+    fn size(&self, encoding: OperandEncoding) -> usize {
+        match self {
+            ItemKind::Encoding(_) => bytecode::HEADER_LEN,
+            ItemKind::Instruction(Opcode::Push, Some(operand)) => {
+                1 + operand_size(operand, encoding)
+            }
+            ItemKind::Instruction(Opcode::PushConst, Some(operand)) => match encoding {
+                OperandEncoding::Fixed => 3,
+                OperandEncoding::Leb128 => {
+                    let mut index = Vec::new();
+                    bytecode::write_uleb128(&mut index, parse_int(operand).unwrap_or(0) as u64);
+                    1 + index.len()
+                }
+            },
+            // A target becomes a `push` ahead of the instruction
+            ItemKind::Instruction(_, Some(target)) => 2 + operand_size(target, encoding),
+            ItemKind::Instruction(_, None) => 1,
+            ItemKind::Int(_) => 8,
+            ItemKind::String(value) => 8 + value.len(),
+        }
+    }
+}
+
+/// Bytes a `push` operand takes. In LEB128 a label's address is given
+/// `PATCHABLE_LEB128_LEN` bytes, as it is not known yet.
+fn operand_size(operand: &str, encoding: OperandEncoding) -> usize {
+    match encoding {
+        OperandEncoding::Fixed => 8,
+        OperandEncoding::Leb128 if is_identifier(operand) => PATCHABLE_LEB128_LEN,
+        OperandEncoding::Leb128 => {
+            let value = parse_int(operand)
+                .or_else(|| parse_char(operand))
+                .or_else(|| parse_float(operand))
+                .unwrap_or(0);
+            let mut bytes = Vec::new();
+            bytecode::write_sleb128(&mut bytes, value);
+            bytes.len()
+        }
+    }
+}
+
+/// Appends a `push` operand, a label's address padded as `operand_size`
+/// counted it.
+fn write_operand(bytecode: &mut Vec<u8>, value: i64, label: bool, encoding: OperandEncoding) {
+    match encoding {
+        OperandEncoding::Fixed => bytecode.extend_from_slice(&value.to_le_bytes()),
+        OperandEncoding::Leb128 if label => {
+            let mut slot = [0; PATCHABLE_LEB128_LEN];
+            bytecode::patch_sleb128(&mut slot, value);
+            bytecode.extend_from_slice(&slot);
+        }
+        OperandEncoding::Leb128 => bytecode::write_sleb128(bytecode, value),
+    }
+}
+
+/// The header `.encoding` writes: the magic bytes and the flags byte, even
+/// for `fixed`, so that a disassembled header assembles back.
+fn header(encoding: OperandEncoding) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.push(match encoding {
+        OperandEncoding::Fixed => 0,
+        OperandEncoding::Leb128 => FLAG_LEB128,
+    });
+    header
+}
+
+/// Assembles a program written one instruction per line into bytecode, for
+/// hand-written VM programs.
+///
+/// Instructions are the `Opcode` names in any case (`push 42`, `add`,
+/// `loadlocal`). `push` takes an integer (`-3`, `0xff`), a float (`1.5`,
+/// pushed as its bits), a character (`'a'`) or a label, which stands for
+/// its address. `pushconst` takes a constant pool index. `jump` (or `jmp`),
+/// `jumpif`, `call` and `pushhandler` take an optional target, which is
+/// pushed first, so `jmp loop` is `push loop` then `jump`.
+///
+/// `name:` defines a label at the next instruction or data, and `;` starts
+/// a comment. Data is written with `.int value` and `.string "text"`, the
+/// latter in the layout `loadstr` reads, so a label on it can be pushed for
+/// `loadstr`. Place data after the code that never runs into it.
+///
+/// Operands are fixed-width unless the program starts with `.encoding
+/// leb128`, which writes the header of a program with LEB128 operands, as
+/// `Compiler::set_operand_encoding` does; `.encoding fixed` writes the
+/// header of a fixed-width one. These are the headers `disasm` lists.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut items = Vec::new();
+    let mut labels: HashMap<&str, usize> = HashMap::new();
+    let mut encoding = OperandEncoding::Fixed;
+    let mut addr = 0;
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let mut text = strip_comment(text).trim();
+        while let Some((label, rest)) = split_label(text) {
+            if labels.insert(label, addr).is_some() {
+                return Err(AsmError::DuplicateLabel {
+                    line,
+                    name: label.to_string(),
+                });
+            }
+            text = rest.trim_start();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let kind = parse_item(line, text)?;
+        if let ItemKind::Encoding(header) = kind {
+            if addr > 0 || !labels.is_empty() {
+                return Err(AsmError::MisplacedEncoding { line });
+            }
+            encoding = header;
+        }
+        addr += kind.size(encoding);
+        items.push(Item { line, kind });
+    }
+
+    let mut bytecode = Vec::with_capacity(addr);
+    for item in items {
+        match item.kind {
+            ItemKind::Encoding(encoding) => bytecode.extend(header(encoding)),
+            ItemKind::Instruction(Opcode::Push, Some(operand)) => {
+                bytecode.push(Opcode::Push as u8);
+                let value = value_of(item.line, operand, &labels)?;
+                write_operand(&mut bytecode, value, is_identifier(operand), encoding);
+            }
+            ItemKind::Instruction(Opcode::PushConst, Some(operand)) => {
+                let index = parse_int(operand)
+                    .and_then(|index| u16::try_from(index).ok())
+                    .ok_or_else(|| AsmError::InvalidOperand {
+                        line: item.line,
+                        operand: operand.to_string(),
+                    })?;
+                bytecode.push(Opcode::PushConst as u8);
+                match encoding {
+                    OperandEncoding::Fixed => bytecode.extend_from_slice(&index.to_le_bytes()),
+                    OperandEncoding::Leb128 => bytecode::write_uleb128(&mut bytecode, index as u64),
+                }
+            }
+            ItemKind::Instruction(opcode, Some(target)) => {
+                let value = value_of(item.line, target, &labels)?;
+                bytecode.push(Opcode::Push as u8);
+                write_operand(&mut bytecode, value, is_identifier(target), encoding);
+                bytecode.push(opcode as u8);
+            }
+            ItemKind::Instruction(opcode, None) => bytecode.push(opcode as u8),
+            ItemKind::Int(value) => bytecode.extend_from_slice(&value.to_le_bytes()),
+            ItemKind::String(value) => {
+                bytecode.extend_from_slice(&(value.len() as i64).to_le_bytes());
+                bytecode.extend_from_slice(value.as_bytes());
+            }
+        }
+    }
+    Ok(bytecode)
+}
+
+/// Writes a program as a comma-separated list of instructions, expanding to
+/// its bytecode as a `Vec<u8>` with fixed-width operands.
+///
+/// Instructions are written as for `assemble`: `push 42`, `add`, `jmp loop`,
+/// with `loop:` labelling the instruction after it. An identifier operand
+/// is a label, so a Rust value is pushed as a block or in parentheses:
+/// `push (BASE + 1)`. Integers, characters and floats (as their bits) can be
+/// pushed, and `pushconst` takes a constant pool index.
+///
+/// Each instruction is one level of macro recursion, so programs longer
+/// than about a hundred instructions need a higher `#![recursion_limit]`.
+///
+/// # Panics
+///
+/// If an instruction is unknown, has the wrong operand, or a label is
+/// undefined or defined twice, like the `AsmError`s of `assemble`.
+#[macro_export]
+macro_rules! bytecode {
+    (@items $builder:ident;) => {};
+    (@items $builder:ident; $label:ident : $($rest:tt)*) => {
+        $builder.label(stringify!($label));
+        $crate::bytecode!(@items $builder; $($rest)*);
+    };
+    (@items $builder:ident; $name:ident $(, $($rest:tt)*)?) => {
+        $builder.instruction(stringify!($name));
+        $crate::bytecode!(@items $builder; $($($rest)*)?);
+    };
+    (@items $builder:ident; $name:ident $target:ident $(, $($rest:tt)*)?) => {
+        $builder.target(stringify!($name), stringify!($target));
+        $crate::bytecode!(@items $builder; $($($rest)*)?);
+    };
+    (@items $builder:ident; $name:ident $operand:expr $(, $($rest:tt)*)?) => {
+        $builder.operand(stringify!($name), $operand);
+        $crate::bytecode!(@items $builder; $($($rest)*)?);
+    };
+    ($($items:tt)*) => {{
+        #[allow(unused_mut)]
+        let mut builder = $crate::asm::Builder::new();
+        $crate::bytecode!(@items builder; $($items)*);
+        builder.finish()
+    }};
+}
+
+/// A value `bytecode!` can push.
+pub trait Operand {
+    fn to_operand(self) -> i64;
+}
+
+macro_rules! impl_operand {
+    ($($ty:ty),*) => {
+        $(impl Operand for $ty {
+            fn to_operand(self) -> i64 {
+                self as i64
+            }
+        })*
+    };
+}
+
+impl_operand!(i64, i32, i16, i8, u32, u16, u8, usize, char, bool);
+
+/// Floats are pushed as their bits, which the float opcodes read.
+impl Operand for f64 {
+    fn to_operand(self) -> i64 {
+        self.to_bits() as i64
+    }
+}
+
+/// Builds a program one instruction at a time, filling in the address of
+/// each label once they are all known. `bytecode!` expands to calls to it.
+#[derive(Debug, Default)]
+pub struct Builder {
+    bytecode: Vec<u8>,
+    labels: HashMap<String, usize>,
+    /// Offsets of `push` operands holding a label's address, with the label
+    fixups: Vec<(usize, String)>,
+    encoding: OperandEncoding,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    /// A builder of a program whose operands are in `encoding`, starting
+    /// with the header `.encoding` writes.
+    pub fn with_encoding(encoding: OperandEncoding) -> Self {
+        Builder {
+            bytecode: header(encoding),
+            encoding,
+            ..Builder::default()
+        }
+    }
+
+    /// Labels the next instruction.
+    pub fn label(&mut self, name: &str) {
+        let addr = self.bytecode.len();
+        if self.labels.insert(name.to_string(), addr).is_some() {
+            panic!("label '{}' is already defined", name);
+        }
+    }
+
+    /// Adds an instruction without an operand, or a jump, call or handler
+    /// whose target is already on the stack.
+    pub fn instruction(&mut self, name: &str) {
+        let opcode = Builder::opcode(name);
+        if matches!(opcode, Opcode::Push | Opcode::PushConst) {
+            panic!("'{}' takes an operand", name);
+        }
+        self.bytecode.push(opcode as u8);
+    }
+
+    /// Adds a `push` or `pushconst` with its operand, or a jump, call or
+    /// handler with the address it targets.
+    pub fn operand(&mut self, name: &str, value: impl Operand) {
+        let value = value.to_operand();
+        match Builder::opcode(name) {
+            Opcode::Push => self.push(value),
+            Opcode::PushConst => {
+                let index =
+                    u16::try_from(value).unwrap_or_else(|_| panic!("invalid operand '{}'", value));
+                self.bytecode.push(Opcode::PushConst as u8);
+                match self.encoding {
+                    OperandEncoding::Fixed => self.bytecode.extend_from_slice(&index.to_le_bytes()),
+                    OperandEncoding::Leb128 => {
+                        bytecode::write_uleb128(&mut self.bytecode, index as u64)
+                    }
+                }
+            }
+            opcode if takes_target(opcode) => {
+                self.push(value);
+                self.bytecode.push(opcode as u8);
+            }
+            _ => panic!("'{}' takes no operand", name),
+        }
+    }
+
+    /// Adds a `push` of a label's address, or a jump, call or handler
+    /// targeting it.
+    pub fn target(&mut self, name: &str, label: &str) {
+        let opcode = Builder::opcode(name);
+        if opcode != Opcode::Push && !takes_target(opcode) {
+            panic!("'{}' takes no operand", name);
+        }
+        self.fixups
+            .push((self.bytecode.len() + 1, label.to_string()));
+        self.bytecode.push(Opcode::Push as u8);
+        write_operand(&mut self.bytecode, 0, true, self.encoding);
+        if opcode != Opcode::Push {
+            self.bytecode.push(opcode as u8);
+        }
+    }
+
+    /// The bytecode, with the labels' addresses filled in.
+    pub fn finish(mut self) -> Vec<u8> {
+        for (offset, label) in &self.fixups {
+            let addr = self.labels.get(label).copied();
+            let addr = addr.unwrap_or_else(|| panic!("label '{}' is not defined", label));
+            let mut operand = Vec::new();
+            write_operand(&mut operand, addr as i64, true, self.encoding);
+            self.bytecode[*offset..*offset + operand.len()].copy_from_slice(&operand);
+        }
+        self.bytecode
+    }
+
+    fn push(&mut self, value: i64) {
+        self.bytecode.push(Opcode::Push as u8);
+        write_operand(&mut self.bytecode, value, false, self.encoding);
+    }
+
+    fn opcode(name: &str) -> Opcode {
+        opcode_named(name).unwrap_or_else(|| panic!("unknown instruction '{}'", name))
+    }
+}
+
+/// Removes a `;` comment, unless the `;` is inside a string or character.
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, ch) in text.char_indices() {
+        match (quote, ch) {
+            _ if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(open), _) if ch == open => quote = None,
+            (None, '"' | '\'') => quote = Some(ch),
+            (None, ';') => return &text[..i],
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Splits `name:` off the start of a line.
+fn split_label(text: &str) -> Option<(&str, &str)> {
+    let (label, rest) = text.split_once(':')?;
+    is_identifier(label).then_some((label, rest))
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(ch) if ch.is_ascii_alphabetic() || ch == '_')
+        && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+}
+
+fn parse_item(line: usize, text: &str) -> Result<ItemKind<'_>, AsmError> {
+    let (name, operand) = match text.split_once(char::is_whitespace) {
+        Some((name, operand)) => (name, Some(operand.trim())),
+        None => (text, None),
+    };
+    let invalid = |operand: &str| AsmError::InvalidOperand {
+        line,
+        operand: operand.to_string(),
+    };
+    match (name, operand) {
+        (".int", Some(operand)) => {
+            return parse_int(operand)
+                .map(ItemKind::Int)
+                .ok_or_else(|| invalid(operand))
+        }
+        (".string", Some(operand)) => {
+            return parse_string(operand)
+                .map(ItemKind::String)
+                .ok_or_else(|| invalid(operand))
+        }
+        (".encoding", Some(operand)) => {
+            return match operand {
+                "fixed" => Ok(ItemKind::Encoding(OperandEncoding::Fixed)),
+                "leb128" => Ok(ItemKind::Encoding(OperandEncoding::Leb128)),
+                _ => Err(invalid(operand)),
+            }
+        }
+        (".int" | ".string" | ".encoding", None) => {
+            return Err(AsmError::MissingOperand {
+                line,
+                name: name.to_string(),
+            })
+        }
+        _ => {}
+    }
+    let opcode = opcode_named(name).ok_or_else(|| AsmError::UnknownInstruction {
+        line,
+        name: name.to_string(),
+    })?;
+    let takes_operand = matches!(opcode, Opcode::Push | Opcode::PushConst);
+    let takes_target = takes_target(opcode);
+    match operand {
+        None if takes_operand => Err(AsmError::MissingOperand {
+            line,
+            name: name.to_string(),
+        }),
+        Some(_) if !takes_operand && !takes_target => Err(AsmError::UnexpectedOperand {
+            line,
+            name: name.to_string(),
+        }),
+        _ => Ok(ItemKind::Instruction(opcode, operand)),
+    }
+}
+
+/// The opcode whose name is `name`, ignoring case, or `Jump` for `jmp`.
+fn opcode_named(name: &str) -> Option<Opcode> {
+    if name.eq_ignore_ascii_case("jmp") {
+        return Some(Opcode::Jump);
+    }
+    (0..=u8::MAX)
+        .filter_map(|byte| Opcode::try_from(byte).ok())
+        .find(|opcode| format!("{:?}", opcode).eq_ignore_ascii_case(name))
+}
+
+/// Whether an instruction may be given a target to push before it.
+fn takes_target(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Jump | Opcode::JumpIf | Opcode::Call | Opcode::PushHandler
+    )
+}
+
+/// The value of a `push` operand or a target.
+fn value_of(line: usize, operand: &str, labels: &HashMap<&str, usize>) -> Result<i64, AsmError> {
+    if is_identifier(operand) {
+        return labels.get(operand).map(|addr| *addr as i64).ok_or_else(|| {
+            AsmError::UndefinedLabel {
+                line,
+                name: operand.to_string(),
+            }
+        });
+    }
+    parse_int(operand)
+        .or_else(|| parse_char(operand))
+        .or_else(|| parse_float(operand))
+        .ok_or_else(|| AsmError::InvalidOperand {
+            line,
+            operand: operand.to_string(),
+        })
+}
+
+fn parse_int(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let magnitude = match digits.get(..2) {
+        Some("0x" | "0X") => u64::from_str_radix(&digits[2..], 16).ok()?,
+        Some("0b" | "0B") => u64::from_str_radix(&digits[2..], 2).ok()?,
+        _ => digits.parse::<u64>().ok()?,
+    };
+    if negative {
+        0i64.checked_sub_unsigned(magnitude)
+    } else {
+        i64::try_from(magnitude).ok()
+    }
+}
+
+fn parse_float(text: &str) -> Option<i64> {
+    let is_float = text.contains(['.', 'e', 'E']) && !text.starts_with("0x");
+    is_float
+        .then(|| text.parse::<f64>().ok())
+        .flatten()
+        .map(|value| value.to_bits() as i64)
+}
+
+fn parse_char(text: &str) -> Option<i64> {
+    let inner = text.strip_prefix('\'')?.strip_suffix('\'')?;
+    let value = unescape(inner)?;
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(ch), None) => Some(ch as i64),
+        _ => None,
+    }
+}
+
+fn parse_string(text: &str) -> Option<String> {
+    unescape(text.strip_prefix('"')?.strip_suffix('"')?)
+}
+
+/// Resolves `\n`, `\t`, `\0`, `\\`, `\"` and `\'`.
+fn unescape(text: &str) -> Option<String> {
+    let mut value = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            value.push(ch);
+            continue;
+        }
+        value.push(match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            '0' => '\0',
+            escaped @ ('\\' | '"' | '\'') => escaped,
+            _ => return None,
+        });
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assemble, AsmError, Builder};
+    use crate::bytecode::OperandEncoding;
+    use crate::disasm::disassemble;
+    use crate::{Opcode, VM};
+
+    #[test]
+    fn assembles_loops_with_labels() {
+        // Sums 1 to 10 into address 0
+        let bytecode = assemble(
+            "
+                push 0
+                push 0
+                store           ; sum = 0
+                push 10
+                push 1
+                store           ; i = 10
+            loop:
+                push 1
+                load
+                jumpif body     ; while i != 0
+                jmp done
+            body:
+                push 0
+                load
+                push 1
+                load
+                add
+                push 0
+                store           ; sum = sum + i
+                push 1
+                load
+                dec
+                push 1
+                store           ; i = i - 1
+                jmp loop
+            done: halt
+            ",
+        )
+        .unwrap();
+        assert_eq!(&bytecode[..2], [Opcode::Push as u8, 0]);
+        let mut vm = VM::new(bytecode, 16);
+        vm.run().unwrap();
+        assert_eq!(vm.get_memory()[&0], 55);
+        assert_eq!(vm.get_memory()[&1], 0);
+    }
+
+    #[test]
+    fn assembles_operands_and_data() {
+        let bytecode = assemble(
+            "
+                PUSH -0x10
+                push 'a'
+                push 2.5
+                push greeting
+                loadstr
+                printstr
+                halt
+            greeting: .string \"hi; there\\n\"
+            ",
+        )
+        .unwrap();
+        let instructions = crate::bytecode::decode(&bytecode).unwrap();
+        let operands: Vec<i64> = instructions[..3]
+            .iter()
+            .map(|instruction| instruction.operand.unwrap())
+            .collect();
+        assert_eq!(operands, [-16, 'a' as i64, 2.5f64.to_bits() as i64]);
+        assert_eq!(instructions.last().unwrap().opcode, Opcode::Halt);
+        assert_eq!(&bytecode[bytecode.len() - 10..], b"hi; there\n");
+        VM::new(bytecode, 16).run().unwrap();
+    }
+
+    #[test]
+    fn builds_programs_with_the_macro() {
+        const LIMIT: i64 = 10;
+        let bytecode = crate::bytecode![
+            push 0, push 0, store,
+            push (LIMIT), push 1, store,
+        loop:
+            push 1, load, jumpif body,
+            jmp done,
+        body:
+            push 0, load, push 1, load, add, push 0, store,
+            push 1, load, dec, push 1, store,
+            jmp loop,
+        done:
+            halt,
+        ];
+        let assembled = assemble(
+            "push 0\npush 0\nstore\npush 10\npush 1\nstore\nloop: push 1\nload\njumpif body\n\
+             jmp done\nbody: push 0\nload\npush 1\nload\nadd\npush 0\nstore\npush 1\nload\n\
+             dec\npush 1\nstore\njmp loop\ndone: halt",
+        )
+        .unwrap();
+        assert_eq!(bytecode, assembled);
+
+        let operands = crate::bytecode![push -16, push 'a', push 2.5, pushconst 3, jump 0, halt];
+        let instructions = crate::bytecode::decode(&operands).unwrap();
+        let values: Vec<_> = instructions.iter().map(|i| i.operand).collect();
+        assert_eq!(
+            values,
+            [
+                Some(-16),
+                Some('a' as i64),
+                Some(2.5f64.to_bits() as i64),
+                Some(3),
+                Some(0),
+                None,
+                None
+            ]
+        );
+        assert!(crate::bytecode![].is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "label 'nowhere' is not defined")]
+    fn macro_rejects_undefined_labels() {
+        crate::bytecode![jmp nowhere];
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        assert_eq!(
+            assemble("push 1\nfrobnicate"),
+            Err(AsmError::UnknownInstruction {
+                line: 2,
+                name: "frobnicate".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("jmp nowhere"),
+            Err(AsmError::UndefinedLabel {
+                line: 1,
+                name: "nowhere".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("a: halt\na: halt"),
+            Err(AsmError::DuplicateLabel {
+                line: 2,
+                name: "a".to_string()
+            })
+        );
+        assert!(matches!(
+            assemble("push"),
+            Err(AsmError::MissingOperand { line: 1, .. })
+        ));
+        assert!(matches!(
+            assemble("add 1"),
+            Err(AsmError::UnexpectedOperand { line: 1, .. })
+        ));
+        assert!(matches!(
+            assemble("pushconst 70000"),
+            Err(AsmError::InvalidOperand { line: 1, .. })
+        ));
+        assert_eq!(
+            assemble("halt\n.encoding leb128"),
+            Err(AsmError::MisplacedEncoding { line: 2 })
+        );
+        assert!(matches!(
+            assemble(".encoding zigzag"),
+            Err(AsmError::InvalidOperand { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn assembles_leb128_operands_that_disassemble_back() {
+        let source = "
+            .encoding leb128
+                push 300
+                push -1
+                pushconst 200
+                jumpif done
+                push message
+                loadstr
+                printstr
+            done: halt
+            message: .string \"hi\"
+        ";
+        let bytecode = assemble(source).unwrap();
+        assert!(bytecode.starts_with(b"\0SVM\x01"));
+        let listing: Vec<String> = disassemble(&bytecode)
+            .iter()
+            .map(|line| line.kind.to_string())
+            .collect();
+        let done = bytecode.len() - 11;
+        assert_eq!(
+            listing,
+            [
+                ".encoding leb128".to_string(),
+                "push 300".to_string(),
+                "push -1".to_string(),
+                "pushconst 200".to_string(),
+                format!("push {}", done),
+                "jumpif".to_string(),
+                format!("push {}", done + 1),
+                "loadstr".to_string(),
+                "printstr".to_string(),
+                "halt".to_string(),
+                ".string \"hi\"".to_string(),
+            ]
+        );
+
+        let mut builder = Builder::with_encoding(OperandEncoding::Leb128);
+        builder.operand("push", 300);
+        builder.operand("push", -1);
+        builder.operand("pushconst", 200);
+        builder.target("jumpif", "done");
+        builder.target("push", "message");
+        builder.instruction("loadstr");
+        builder.instruction("printstr");
+        builder.label("done");
+        builder.instruction("halt");
+        builder.label("message");
+        let mut built = builder.finish();
+        built.extend_from_slice(&2i64.to_le_bytes());
+        built.extend_from_slice(b"hi");
+        assert_eq!(built, bytecode);
+
+        let fixed = assemble(".encoding fixed\nhalt").unwrap();
+        assert_eq!(fixed, b"\0SVM\0\xff");
+        assert_eq!(disassemble(&fixed)[0].kind.to_string(), ".encoding fixed");
+    }
+}
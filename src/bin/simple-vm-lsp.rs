@@ -0,0 +1,326 @@
+//! A language server for simple-vm programs, speaking the Language Server
+//! Protocol over stdin and stdout: diagnostics as the file is edited,
+//! go-to-definition and hover with inferred types, all from
+//! `compiler::Analysis`.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+        PublishDiagnostics,
+    },
+    request::{GotoDefinition, HoverRequest, Request as _},
+    Diagnostic, DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents,
+    HoverParams, HoverProviderCapability, Location, MarkupContent, MarkupKind, NumberOrString,
+    OneOf, Position, PublishDiagnosticsParams, Range, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Uri,
+};
+use simple_vm::compiler::{
+    self,
+    analysis::{Analysis, Severity},
+    Span,
+};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+fn main() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        ..ServerCapabilities::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+    Server::default().run(&connection)?;
+    // The writer thread ends once the connection's sender is gone
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+/// The open documents, each analysed again whenever it changes.
+#[derive(Default)]
+struct Server {
+    documents: HashMap<Uri, (String, Analysis)>,
+}
+
+impl Server {
+    /// Handles messages until the client shuts the server down.
+    fn run(&mut self, connection: &Connection) -> Result<()> {
+        for message in &connection.receiver {
+            match message {
+                Message::Request(request) => {
+                    if connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    let response = self.respond(request);
+                    connection.sender.send(Message::Response(response))?;
+                }
+                Message::Notification(notification) => match self.notify(notification) {
+                    Ok(Some(published)) => {
+                        connection.sender.send(Message::Notification(published))?;
+                    }
+                    Ok(None) => {}
+                    // A notification cannot be answered with an error
+                    Err(error) => eprintln!("Ignoring malformed notification: {}", error),
+                },
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn respond(&self, request: Request) -> Response {
+        let id = request.id.clone();
+        let result = match request.method.as_str() {
+            GotoDefinition::METHOD => request
+                .extract(GotoDefinition::METHOD)
+                .map(|(_, params)| serde_json::to_value(self.definition(params))),
+            HoverRequest::METHOD => request
+                .extract(HoverRequest::METHOD)
+                .map(|(_, params)| serde_json::to_value(self.hover(params))),
+            method => {
+                let message = format!("Unsupported request '{}'", method);
+                return Response::new_err(id, ErrorCode::MethodNotFound as i32, message);
+            }
+        };
+        match result {
+            Ok(Ok(value)) => Response::new_ok(id, value),
+            Ok(Err(error)) => {
+                Response::new_err(id, ErrorCode::InternalError as i32, error.to_string())
+            }
+            Err(error) => Response::new_err(id, ErrorCode::InvalidParams as i32, error.to_string()),
+        }
+    }
+
+    /// Keeps the documents up to date, returning the diagnostics to publish
+    /// for one that changed.
+    fn notify(&mut self, notification: Notification) -> Result<Option<Notification>> {
+        let (uri, source) = match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams =
+                    notification.extract(DidOpenTextDocument::METHOD)?;
+                (params.text_document.uri, Some(params.text_document.text))
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: DidChangeTextDocumentParams =
+                    notification.extract(DidChangeTextDocument::METHOD)?;
+                // The whole text is sent on each change
+                let text = params.content_changes.into_iter().last();
+                let Some(text) = text else {
+                    return Ok(None);
+                };
+                (params.text_document.uri, Some(text.text))
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams =
+                    notification.extract(DidCloseTextDocument::METHOD)?;
+                (params.text_document.uri, None)
+            }
+            _ => return Ok(None),
+        };
+
+        let diagnostics = match source {
+            Some(source) => {
+                let analysis = Analysis::new(&source);
+                let mut diagnostics: Vec<Diagnostic> = analysis
+                    .diagnostics()
+                    .iter()
+                    .map(|diagnostic| Diagnostic {
+                        range: range(&source, diagnostic.span),
+                        severity: Some(match diagnostic.severity {
+                            Severity::Error => DiagnosticSeverity::ERROR,
+                            Severity::Warning => DiagnosticSeverity::WARNING,
+                        }),
+                        source: Some("simple-vm".to_string()),
+                        message: diagnostic.message.clone(),
+                        ..Diagnostic::default()
+                    })
+                    .collect();
+                diagnostics.extend(compiler::lint(&source).into_iter().map(|diagnostic| {
+                    Diagnostic {
+                        range: range(&source, diagnostic.span),
+                        severity: Some(DiagnosticSeverity::WARNING),
+                        code: Some(NumberOrString::String(diagnostic.rule.name().to_string())),
+                        source: Some("simple-vm lint".to_string()),
+                        message: diagnostic.message,
+                        ..Diagnostic::default()
+                    }
+                }));
+                self.documents.insert(uri.clone(), (source, analysis));
+                diagnostics
+            }
+            None => {
+                // Clears the closed document's diagnostics
+                self.documents.remove(&uri);
+                Vec::new()
+            }
+        };
+        let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+        Ok(Some(Notification::new(
+            PublishDiagnostics::METHOD.to_string(),
+            params,
+        )))
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let position = params.text_document_position_params;
+        let uri = position.text_document.uri;
+        let (source, analysis) = self.documents.get(&uri)?;
+        let symbol = analysis.symbol_at(offset(source, position.position))?;
+        let location = Location::new(uri.clone(), range(source, symbol.span));
+        Some(GotoDefinitionResponse::Scalar(location))
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let position = params.text_document_position_params;
+        let (source, analysis) = self.documents.get(&position.text_document.uri)?;
+        let symbol = analysis.symbol_at(offset(source, position.position))?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```simple-vm\n{}\n```", symbol),
+            }),
+            range: None,
+        })
+    }
+}
+
+/// The range of a span, in the UTF-16 positions the protocol uses.
+fn range(source: &str, span: Span) -> Range {
+    Range::new(position(source, span.offset), position(source, span.end()))
+}
+
+/// The position of a byte offset, moved back to the start of the character
+/// it is in and clamped to the end of the source.
+fn position(source: &str, offset: usize) -> Position {
+    let mut offset = offset.min(source.len());
+    while !source.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    Position::new(
+        before.matches('\n').count() as u32,
+        before[line_start..].encode_utf16().count() as u32,
+    )
+}
+
+/// The byte offset of a position, clamped to the end of its line.
+fn offset(source: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match source[line_start..].find('\n') {
+            Some(newline) => line_start += newline + 1,
+            None => return source.len(),
+        }
+    }
+    let line = source[line_start..].split('\n').next().unwrap_or("");
+    let mut units = 0;
+    for (index, c) in line.char_indices() {
+        if units >= position.character as usize {
+            return line_start + index;
+        }
+        units += c.len_utf16();
+    }
+    line_start + line.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use lsp_server::{Connection, Message, Notification, Request, RequestId};
+    use lsp_types::{
+        notification::{DidOpenTextDocument, Notification as _, PublishDiagnostics},
+        request::{GotoDefinition, HoverRequest, Request as _},
+        DidOpenTextDocumentParams, GotoDefinitionResponse, Hover, HoverContents, Position,
+        PublishDiagnosticsParams, TextDocumentIdentifier, TextDocumentItem,
+        TextDocumentPositionParams, Uri,
+    };
+    use serde_json::json;
+
+    use super::{offset, position, Server};
+
+    #[test]
+    fn converts_positions_in_utf16() {
+        let source = "let s = \"😀\"; let n = 1;\nprint n;";
+        let n = source.find("n =").unwrap();
+        assert_eq!(position(source, n), Position::new(0, 18));
+        assert_eq!(offset(source, Position::new(0, 18)), n);
+        assert_eq!(
+            offset(source, Position::new(1, 6)),
+            source.rfind('n').unwrap()
+        );
+        assert_eq!(offset(source, Position::new(1, 99)), source.len());
+        assert_eq!(offset(source, Position::new(5, 0)), source.len());
+        let emoji = source.find('😀').unwrap();
+        assert_eq!(position(source, emoji + 2), Position::new(0, 9));
+        assert_eq!(position(source, source.len() + 1), Position::new(1, 8));
+    }
+
+    #[test]
+    fn serves_diagnostics_definitions_and_hover() {
+        let (server, client) = Connection::memory();
+        let handle = thread::spawn(move || Server::default().run(&server).unwrap());
+        let uri: Uri = "file:///main.svm".parse().unwrap();
+        // Skipped rather than ending the server
+        let malformed = Notification::new(DidOpenTextDocument::METHOD.to_string(), json!({}));
+        client
+            .sender
+            .send(Message::Notification(malformed))
+            .unwrap();
+        let source = "fn square(n) { return n * n; }\nlet total = square(3);\nlet unused = total;";
+        let open = DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(uri.clone(), "simple-vm".into(), 1, source.into()),
+        };
+        let open = Notification::new(DidOpenTextDocument::METHOD.to_string(), open);
+        client.sender.send(Message::Notification(open)).unwrap();
+        let Ok(Message::Notification(published)) = client.receiver.recv() else {
+            panic!("expected diagnostics");
+        };
+        let published: PublishDiagnosticsParams =
+            published.extract(PublishDiagnostics::METHOD).unwrap();
+        assert_eq!(published.diagnostics.len(), 1);
+        assert_eq!(published.diagnostics[0].range.start, Position::new(2, 0));
+
+        let at = TextDocumentPositionParams::new(
+            TextDocumentIdentifier::new(uri.clone()),
+            Position::new(2, 15),
+        );
+        let params = json!({ "textDocument": at.text_document, "position": at.position });
+        for (id, method) in [(1, GotoDefinition::METHOD), (2, HoverRequest::METHOD)] {
+            let request = Request::new(RequestId::from(id), method.to_string(), &params);
+            client.sender.send(Message::Request(request)).unwrap();
+        }
+        let Ok(Message::Response(definition)) = client.receiver.recv() else {
+            panic!("expected a response");
+        };
+        let definition: GotoDefinitionResponse =
+            serde_json::from_value(definition.result.unwrap()).unwrap();
+        let GotoDefinitionResponse::Scalar(location) = definition else {
+            panic!("expected a single location");
+        };
+        assert_eq!(location.range.start, Position::new(1, 4));
+        let Ok(Message::Response(hover)) = client.receiver.recv() else {
+            panic!("expected a response");
+        };
+        let hover: Hover = serde_json::from_value(hover.result.unwrap()).unwrap();
+        let HoverContents::Markup(contents) = hover.contents else {
+            panic!("expected markup");
+        };
+        assert!(contents.value.contains("let total: unknown"));
+
+        let shutdown = Request::new(RequestId::from(3), "shutdown".to_string(), ());
+        client.sender.send(Message::Request(shutdown)).unwrap();
+        assert!(matches!(client.receiver.recv(), Ok(Message::Response(_))));
+        let exit = Notification::new("exit".to_string(), ());
+        client.sender.send(Message::Notification(exit)).unwrap();
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Opcode, VMError};
+
+/// Marks a program that starts with a header. No opcode is 0, so programs
+/// without one are still read as plain code.
+pub const MAGIC: [u8; 4] = *b"\0SVM";
+
+/// Header flag: inline operands use LEB128 instead of fixed-width integers.
+pub const FLAG_LEB128: u8 = 0x01;
+
+/// Length of the header: the magic bytes followed by a flags byte.
+pub const HEADER_LEN: usize = MAGIC.len() + 1;
+
+/// Bytes reserved for an LEB128 operand that is patched once its value is
+/// known, enough for addresses below 2^27.
+pub const PATCHABLE_LEB128_LEN: usize = 4;
+
+/// How the inline operands of `Push` and `PushConst` are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum OperandEncoding {
+    /// 8-byte little-endian values and 2-byte pool indices, as in programs
+    /// without a header
+    #[default]
+    Fixed,
+    /// Signed LEB128 values and unsigned LEB128 pool indices, so small
+    /// operands take a single byte
+    Leb128,
+}
+
+impl OperandEncoding {
+    /// The header announcing this encoding, empty for `Fixed` so such
+    /// programs keep their headerless layout.
+    pub fn header(self) -> Vec<u8> {
+        match self {
+            OperandEncoding::Fixed => Vec::new(),
+            OperandEncoding::Leb128 => {
+                let mut header = MAGIC.to_vec();
+                header.push(FLAG_LEB128);
+                header
+            }
+        }
+    }
+}
+
+/// Reads a program's header, returning its operand encoding and the address
+/// of its first instruction. A malformed header reads as an invalid opcode 0.
+pub fn read_header(bytecode: &[u8]) -> Result<(OperandEncoding, usize), VMError> {
+    if !bytecode.starts_with(&MAGIC) {
+        return Ok((OperandEncoding::Fixed, 0));
+    }
+    match bytecode.get(MAGIC.len()) {
+        Some(&0) => Ok((OperandEncoding::Fixed, HEADER_LEN)),
+        Some(&FLAG_LEB128) => Ok((OperandEncoding::Leb128, HEADER_LEN)),
+        _ => Err(VMError::InvalidOpcode(0)),
+    }
+}
+
+/// Appends `value` in signed LEB128, using as few bytes as possible.
+pub fn write_sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        out.push(if done { byte } else { byte | 0x80 });
+        if done {
+            break;
+        }
+    }
+}
+
+/// Fills `slot` with `value` in signed LEB128, padded with continuation
+/// bytes so the encoding takes exactly `slot.len()` bytes.
+pub fn patch_sleb128(slot: &mut [u8], value: i64) {
+    let last = slot.len() - 1;
+    for (i, byte) in slot.iter_mut().enumerate() {
+        let bits = (value >> (7 * i).min(63)) as u8 & 0x7f;
+        *byte = if i < last { bits | 0x80 } else { bits };
+    }
+}
+
+/// Reads a signed LEB128 value from the start of `bytes`, returning it with
+/// the number of bytes it took.
+pub fn read_sleb128(bytes: &[u8]) -> Option<(i64, usize)> {
+    let mut value = 0i64;
+    let mut shift = 0;
+    for (i, &byte) in bytes.iter().enumerate() {
+        if shift >= 64 {
+            return None;
+        }
+        value |= ((byte & 0x7f) as i64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            if shift < 64 && byte & 0x40 != 0 {
+                value |= -1i64 << shift;
+            }
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Appends `value` in unsigned LEB128.
+pub fn write_uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Reads an unsigned LEB128 value from the start of `bytes`, returning it
+/// with the number of bytes it took.
+pub fn read_uleb128(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    for (i, &byte) in bytes.iter().enumerate() {
+        if shift >= 64 {
+            return None;
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Reads the inline operand of `opcode` from the start of `bytes`, returning
+/// it with its length in bytes, or `None` if it is truncated. Opcodes
+/// without an operand read as no value of length 0.
+pub fn read_operand(
+    opcode: Opcode,
+    bytes: &[u8],
+    encoding: OperandEncoding,
+) -> Option<(Option<i64>, usize)> {
+    let (value, len) = match (opcode, encoding) {
+        (Opcode::Push, OperandEncoding::Fixed) => {
+            let bytes = bytes.get(..8)?;
+            (i64::from_le_bytes(bytes.try_into().unwrap()), 8)
+        }
+        (Opcode::PushConst, OperandEncoding::Fixed) => {
+            let bytes = bytes.get(..2)?;
+            (u16::from_le_bytes(bytes.try_into().unwrap()) as i64, 2)
+        }
+        (Opcode::Push, OperandEncoding::Leb128) => read_sleb128(bytes)?,
+        (Opcode::PushConst, OperandEncoding::Leb128) => {
+            let (index, len) = read_uleb128(bytes)?;
+            (i64::try_from(index).ok()?, len)
+        }
+        _ => return Some((None, 0)),
+    };
+    Some((Some(value), len))
+}
+
+/// One decoded instruction of a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Instruction {
+    /// Address of the opcode byte
+    pub addr: usize,
+    pub opcode: Opcode,
+    /// The inline operand: the value of a `Push`, or the constant pool index
+    /// of a `PushConst`
+    pub operand: Option<i64>,
+    /// Length in bytes, opcode included
+    pub size: usize,
+}
+
+impl Instruction {
+    /// Address of the instruction that follows this one.
+    pub fn next_addr(&self) -> usize {
+        self.addr + self.size
+    }
+}
+
+/// Decodes the instructions of a program, after its header if it has one.
+/// Compiled programs end with a data segment of string constants and the
+/// constant pool, which is recognized by the `Push offset` that reads each
+/// string for `LoadStr` or `BindHost`, or locates the pool for `ConstPool`,
+/// and is not decoded.
+pub fn decode(bytecode: &[u8]) -> Result<Vec<Instruction>, VMError> {
+    let (encoding, start) = read_header(bytecode)?;
+    let mut instructions: Vec<Instruction> = Vec::new();
+    let mut code_end = bytecode.len();
+    let mut addr = start;
+    while addr < code_end {
+        let byte = bytecode[addr];
+        let opcode = Opcode::try_from(byte)?;
+        let (operand, operand_len) = read_operand(opcode, &bytecode[addr + 1..], encoding)
+            .ok_or(VMError::InvalidOpcode(byte))?;
+        // `BindHost` takes the name's offset below the declared arity
+        let data_operand = match opcode {
+            Opcode::LoadStr | Opcode::ConstPool => instructions.last(),
+            Opcode::BindHost => instructions.iter().rev().nth(1),
+            _ => None,
+        };
+        if let Some(offset) = data_operand.and_then(|push| push.operand) {
+            if offset > addr as i64 {
+                code_end = code_end.min(offset as usize);
+            }
+        }
+        let instruction = Instruction {
+            addr,
+            opcode,
+            operand,
+            size: 1 + operand_len,
+        };
+        addr = instruction.next_addr();
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+/// The constant pushed right before the instruction at `index`, if it is a
+/// valid address or count. The compiler pushes this way the target of every
+/// `Jump`, `JumpIf`, `Call` and `PushHandler` (except calls through function
+/// values), the element count of `NewArray` and the binding of `CallHost`.
+pub fn pushed_operand(instructions: &[Instruction], index: usize) -> Option<usize> {
+    let push = instructions.get(index.checked_sub(1)?)?;
+    match push.opcode {
+        Opcode::Push => push.operand.and_then(|value| usize::try_from(value).ok()),
+        _ => None,
+    }
+}
+
+/// The constant pool at `offset`, where a `ConstPool` points: the bytes it
+/// takes, its length followed by its values, and the values.
+pub fn constant_pool(bytecode: &[u8], offset: usize) -> Option<(Range<usize>, Vec<i64>)> {
+    let read = |offset: usize| {
+        let bytes = bytecode.get(offset..offset.checked_add(8)?)?;
+        Some(i64::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let count = usize::try_from(read(offset)?).ok()?;
+    let values = (0..count)
+        .map(|index| read(offset + 8 + index * 8))
+        .collect::<Option<Vec<_>>>()?;
+    Some((offset..offset + 8 + count * 8, values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode, patch_sleb128, read_sleb128, read_uleb128, write_sleb128, write_uleb128,
+        Instruction,
+    };
+    use crate::compiler::{Compiler, Parser};
+    use crate::program::Program;
+
+    #[test]
+    fn round_trips_leb128() {
+        for value in [0, 1, -1, 63, 64, -64, -65, 300, i64::MAX, i64::MIN] {
+            let mut bytes = Vec::new();
+            write_sleb128(&mut bytes, value);
+            assert_eq!(read_sleb128(&bytes), Some((value, bytes.len())));
+        }
+        let mut bytes = Vec::new();
+        write_sleb128(&mut bytes, 5);
+        assert_eq!(bytes, [5]);
+        for value in [0, 127, 128, 65535, u64::MAX] {
+            let mut bytes = Vec::new();
+            write_uleb128(&mut bytes, value);
+            assert_eq!(read_uleb128(&bytes), Some((value, bytes.len())));
+        }
+        assert_eq!(read_sleb128(&[0x80, 0x80]), None);
+    }
+
+    #[test]
+    fn pads_patched_values() {
+        for value in [0, 5, -3, 1 << 20] {
+            let mut slot = [0; 4];
+            patch_sleb128(&mut slot, value);
+            assert_eq!(read_sleb128(&slot), Some((value, 4)));
+        }
+    }
+
+    #[test]
+    fn serializes_instructions_and_programs() {
+        let bytecode = crate::bytecode![push 7, jumpif end, end: halt];
+        let instructions = decode(&bytecode).unwrap();
+        let json = serde_json::to_string(&instructions[2]).unwrap();
+        assert_eq!(
+            json,
+            r#"{"addr":18,"opcode":"jumpif","operand":null,"size":1}"#
+        );
+        let read: Vec<Instruction> =
+            serde_json::from_str(&serde_json::to_string(&instructions).unwrap()).unwrap();
+        assert_eq!(read, instructions);
+
+        let statements = Parser::new("print 1 + 2;").parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_debug_info(true);
+        let program = Program::new(
+            compiler.compile(statements).unwrap(),
+            compiler.debug_info().cloned(),
+        );
+        let json = serde_json::to_string(&program).unwrap();
+        assert_eq!(serde_json::from_str::<Program>(&json).unwrap(), program);
+    }
+}
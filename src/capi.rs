@@ -0,0 +1,355 @@
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use crate::compiler::{self, Compiler, Parser};
+use crate::{VMError, VM};
+
+/// What a C API call returned: `Ok`, a problem with its arguments or the
+/// program's source, or the `VMError` a program faulted with. The numbers
+/// are part of the API and match `include/simple_vm.h`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SvmStatus {
+    Ok = 0,
+    /// A pointer argument that must not be null was
+    NullPointer = 1,
+    /// The source was not UTF-8
+    InvalidUtf8 = 2,
+    /// The source did not parse or compile
+    CompileError = 3,
+    /// The call panicked; the VM it was given must not be used again
+    /// except to free it
+    Panic = 4,
+    StackUnderflow = 10,
+    StackOverflow = 11,
+    InvalidOpcode = 12,
+    OutOfMemory = 13,
+    DivisionByZero = 14,
+    InvalidString = 15,
+    InvalidConstant = 16,
+    IndexOutOfBounds = 17,
+    InputExhausted = 18,
+    InvalidInput = 19,
+    OutputFailed = 20,
+    AssertionFailed = 21,
+    InvalidArgument = 22,
+    KeyNotFound = 23,
+    UncaughtException = 24,
+    UnknownHostFunction = 25,
+    HostArityMismatch = 26,
+    UnknownChannel = 27,
+    ChannelEmpty = 28,
+    TypeMismatch = 29,
+}
+
+impl From<&VMError> for SvmStatus {
+    fn from(error: &VMError) -> Self {
+        match error {
+            VMError::StackUnderflow => SvmStatus::StackUnderflow,
+            VMError::StackOverflow => SvmStatus::StackOverflow,
+            VMError::InvalidOpcode(_) => SvmStatus::InvalidOpcode,
+            VMError::OutOfMemory(_) => SvmStatus::OutOfMemory,
+            VMError::DivisionByZero => SvmStatus::DivisionByZero,
+            VMError::InvalidString(_) => SvmStatus::InvalidString,
+            VMError::InvalidConstant(_) => SvmStatus::InvalidConstant,
+            VMError::IndexOutOfBounds { .. } => SvmStatus::IndexOutOfBounds,
+            VMError::InputExhausted => SvmStatus::InputExhausted,
+            VMError::InvalidInput(_) => SvmStatus::InvalidInput,
+            VMError::OutputFailed(_) => SvmStatus::OutputFailed,
+            VMError::AssertionFailed { .. } => SvmStatus::AssertionFailed,
+            VMError::InvalidArgument(..) => SvmStatus::InvalidArgument,
+            VMError::KeyNotFound(_) => SvmStatus::KeyNotFound,
+            VMError::UncaughtException(_) => SvmStatus::UncaughtException,
+            VMError::UnknownHostFunction(_) => SvmStatus::UnknownHostFunction,
+            VMError::HostArityMismatch { .. } => SvmStatus::HostArityMismatch,
+            VMError::UnknownChannel(_) => SvmStatus::UnknownChannel,
+            VMError::ChannelEmpty(_) => SvmStatus::ChannelEmpty,
+            VMError::TypeMismatch { .. } => SvmStatus::TypeMismatch,
+        }
+    }
+}
+
+/// A VM created by `svm_vm_new`, opaque to C.
+pub struct SvmVm(VM);
+
+thread_local! {
+    /// The message of the last call on this thread that failed
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Records `message` for `svm_last_error` and returns `status`.
+fn fail(status: SvmStatus, message: impl ToString) -> SvmStatus {
+    let message = message.to_string().replace('\0', " ");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).ok());
+    status
+}
+
+/// Runs `body`, catching a panic before it can unwind across `extern "C"`
+/// and recording it as a `Panic` failure of `function`, for which
+/// `on_panic` is returned instead.
+fn guard<T>(function: &str, on_panic: T, body: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        fail(SvmStatus::Panic, format!("{function} panicked: {message}"));
+        on_panic
+    })
+}
+
+/// The message of the last call on this thread that did not return
+/// `SVM_OK`, or null if none has failed. The string stays valid until the
+/// next call on the thread fails.
+#[no_mangle]
+pub extern "C" fn svm_last_error() -> *const c_char {
+    guard("svm_last_error", ptr::null(), || {
+        LAST_ERROR.with(|last| {
+            last.borrow()
+                .as_ref()
+                .map_or(ptr::null(), |message| message.as_ptr())
+        })
+    })
+}
+
+/// Compiles the program in `source` and stores its bytecode in `*bytecode`
+/// and its length in `*len`, to be freed with `svm_bytecode_free`.
+///
+/// # Safety
+///
+/// `source` must be a null-terminated string, and `bytecode` and `len`
+/// must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn svm_compile(
+    source: *const c_char,
+    bytecode: *mut *mut u8,
+    len: *mut usize,
+) -> SvmStatus {
+    guard("svm_compile", SvmStatus::Panic, || {
+        if source.is_null() || bytecode.is_null() || len.is_null() {
+            return fail(SvmStatus::NullPointer, "svm_compile: null argument");
+        }
+        let source = match CStr::from_ptr(source).to_str() {
+            Ok(source) => source,
+            Err(error) => return fail(SvmStatus::InvalidUtf8, error),
+        };
+        let compiled = Parser::new(source)
+            .parse_program()
+            .map_err(|errors| compiler::CompileError::Parse(errors).to_string())
+            .and_then(|statements| {
+                Compiler::new()
+                    .compile(statements)
+                    .map_err(|error| error.to_string())
+            });
+        match compiled {
+            Ok(program) => {
+                let program = program.into_boxed_slice();
+                *len = program.len();
+                *bytecode = Box::into_raw(program).cast();
+                SvmStatus::Ok
+            }
+            Err(message) => fail(SvmStatus::CompileError, message),
+        }
+    })
+}
+
+/// Frees bytecode returned by `svm_compile`. Does nothing if `bytecode` is
+/// null.
+///
+/// # Safety
+///
+/// `bytecode` and `len` must be as `svm_compile` returned them, and the
+/// bytecode not already freed.
+#[no_mangle]
+pub unsafe extern "C" fn svm_bytecode_free(bytecode: *mut u8, len: usize) {
+    guard("svm_bytecode_free", (), || {
+        if !bytecode.is_null() {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(bytecode, len)));
+        }
+    })
+}
+
+/// Creates a VM for a copy of the `len` bytes at `bytecode`, with
+/// `stack_limit` operand stack slots. Returns null if `bytecode` is null.
+/// Programs print to stdout and read from stdin.
+///
+/// # Safety
+///
+/// `bytecode` must be valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn svm_vm_new(
+    bytecode: *const u8,
+    len: usize,
+    stack_limit: usize,
+) -> *mut SvmVm {
+    guard("svm_vm_new", ptr::null_mut(), || {
+        if bytecode.is_null() {
+            fail(SvmStatus::NullPointer, "svm_vm_new: null bytecode");
+            return ptr::null_mut();
+        }
+        let program = slice::from_raw_parts(bytecode, len).to_vec();
+        Box::into_raw(Box::new(SvmVm(VM::new(program, stack_limit))))
+    })
+}
+
+/// Runs the program until it halts, exits or faults.
+///
+/// # Safety
+///
+/// `vm` must be null or a VM from `svm_vm_new` that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn svm_vm_run(vm: *mut SvmVm) -> SvmStatus {
+    guard("svm_vm_run", SvmStatus::Panic, || {
+        let Some(SvmVm(vm)) = vm.as_mut() else {
+            return fail(SvmStatus::NullPointer, "svm_vm_run: null VM");
+        };
+        match vm.run() {
+            Ok(()) => SvmStatus::Ok,
+            Err(error) => fail(SvmStatus::from(&error), error),
+        }
+    })
+}
+
+/// Copies up to `capacity` values from the bottom of the operand stack to
+/// `out`, returning how many values the stack holds, so a caller can size
+/// `out` with a first call with a `capacity` of 0.
+///
+/// # Safety
+///
+/// `vm` must be null or a VM from `svm_vm_new` that has not been freed, and
+/// `out` must be valid for writes of `capacity` values.
+#[no_mangle]
+pub unsafe extern "C" fn svm_vm_get_stack(
+    vm: *const SvmVm,
+    out: *mut i64,
+    capacity: usize,
+) -> usize {
+    guard("svm_vm_get_stack", 0, || {
+        let Some(SvmVm(vm)) = vm.as_ref() else {
+            return 0;
+        };
+        let stack = vm.get_stack();
+        if !out.is_null() {
+            let count = stack.len().min(capacity);
+            ptr::copy_nonoverlapping(stack.as_ptr(), out, count);
+        }
+        stack.len()
+    })
+}
+
+/// The code the program passed to `exit`, 0 if it halted or has not run.
+///
+/// # Safety
+///
+/// `vm` must be null or a VM from `svm_vm_new` that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn svm_vm_exit_code(vm: *const SvmVm) -> i64 {
+    guard("svm_vm_exit_code", 0, || {
+        vm.as_ref().map_or(0, |SvmVm(vm)| vm.get_exit_code())
+    })
+}
+
+/// Frees a VM. Does nothing if `vm` is null.
+///
+/// # Safety
+///
+/// `vm` must be null or a VM from `svm_vm_new` that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn svm_vm_free(vm: *mut SvmVm) {
+    guard("svm_vm_free", (), || {
+        if !vm.is_null() {
+            drop(Box::from_raw(vm));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+
+    use super::*;
+
+    /// Compiles `source` through the C API, returning the status and, if it
+    /// compiled, a VM for the program.
+    fn compile(source: &str) -> (SvmStatus, *mut SvmVm) {
+        let source = CString::new(source).unwrap();
+        let (mut bytecode, mut len) = (ptr::null_mut(), 0);
+        unsafe {
+            let status = svm_compile(source.as_ptr(), &mut bytecode, &mut len);
+            if status != SvmStatus::Ok {
+                return (status, ptr::null_mut());
+            }
+            let vm = svm_vm_new(bytecode, len, 64);
+            svm_bytecode_free(bytecode, len);
+            (status, vm)
+        }
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(svm_last_error()) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn compiles_and_runs_programs() {
+        let (status, vm) = compile("let x = 6; exit(x * 7);");
+        assert_eq!(status, SvmStatus::Ok);
+        unsafe {
+            assert_eq!(svm_vm_run(vm), SvmStatus::Ok);
+            assert_eq!(svm_vm_exit_code(vm), 42);
+            svm_vm_free(vm);
+        }
+
+        let bytecode = crate::bytecode![push 1, push 2, push 3, halt];
+        unsafe {
+            let vm = svm_vm_new(bytecode.as_ptr(), bytecode.len(), 64);
+            assert_eq!(svm_vm_run(vm), SvmStatus::Ok);
+            assert_eq!(svm_vm_get_stack(vm, ptr::null_mut(), 0), 3);
+            let mut stack = [0i64; 2];
+            assert_eq!(svm_vm_get_stack(vm, stack.as_mut_ptr(), 2), 3);
+            assert_eq!(stack, [1, 2]);
+            svm_vm_free(vm);
+        }
+    }
+
+    #[test]
+    fn maps_failures_to_status_codes() {
+        let (status, _) = compile("print y;");
+        assert_eq!(status, SvmStatus::CompileError);
+        assert!(last_error().contains('y'), "{}", last_error());
+
+        let (status, vm) = compile("let x = 0; print 1 / x;");
+        assert_eq!(status, SvmStatus::Ok);
+        unsafe {
+            assert_eq!(svm_vm_run(vm), SvmStatus::DivisionByZero);
+            svm_vm_free(vm);
+        }
+        assert_eq!(last_error(), "Division by zero");
+
+        unsafe {
+            assert_eq!(svm_vm_run(ptr::null_mut()), SvmStatus::NullPointer);
+            assert!(svm_vm_new(ptr::null(), 0, 64).is_null());
+            svm_vm_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn panics_become_a_status_instead_of_unwinding() {
+        let status = guard("svm_test", SvmStatus::Panic, || -> SvmStatus {
+            panic!("boom")
+        });
+        assert_eq!(status, SvmStatus::Panic);
+        assert_eq!(last_error(), "svm_test panicked: boom");
+
+        let stack = guard("svm_test", 0usize, || panic!("{} cells", 3));
+        assert_eq!(stack, 0);
+        assert_eq!(last_error(), "svm_test panicked: 3 cells");
+    }
+}
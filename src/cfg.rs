@@ -0,0 +1,247 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::bytecode::{self, Instruction};
+use crate::{Opcode, VMError};
+
+/// How control reaches a block's successor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Execution continues with the next instruction
+    FallThrough,
+    /// An unconditional `Jump`
+    Jump,
+    /// The taken side of a `JumpIf`
+    Branch,
+    /// A `Call` enters the function; the caller's block carries on after it
+    Call,
+    /// A `PushHandler` installs the target as the handler for what follows
+    Exception,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    /// Start address of the successor block
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+/// A run of instructions that is only entered at its first instruction and
+/// only left after its last.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    /// Address of the first instruction
+    pub start: usize,
+    /// Address just past the last instruction
+    pub end: usize,
+    pub instructions: Vec<Instruction>,
+    pub successors: Vec<Edge>,
+}
+
+/// The control-flow graph of a program, with blocks in address order.
+/// Transfers whose target is only known at runtime (`Ret`, `CallClosure`,
+/// and a `Throw` reaching its handler) have no edges.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl Cfg {
+    pub fn build(bytecode: &[u8]) -> Result<Cfg, VMError> {
+        let instructions = bytecode::decode(bytecode)?;
+        let mut leaders = BTreeSet::new();
+        if let Some(first) = instructions.first() {
+            leaders.insert(first.addr);
+        }
+        for (index, instruction) in instructions.iter().enumerate() {
+            if let Some(target) = transfer_target(&instructions, index) {
+                leaders.insert(target);
+            }
+            if ends_block(instruction.opcode) {
+                leaders.insert(instruction.next_addr());
+            }
+        }
+
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        for (index, instruction) in instructions.iter().enumerate() {
+            if leaders.contains(&instruction.addr) || blocks.is_empty() {
+                blocks.push(BasicBlock {
+                    start: instruction.addr,
+                    end: instruction.addr,
+                    instructions: Vec::new(),
+                    successors: Vec::new(),
+                });
+            }
+            let block = blocks.last_mut().unwrap();
+            block.end = instruction.next_addr();
+            block.instructions.push(*instruction);
+            if let Some(target) = transfer_target(&instructions, index) {
+                let kind = match instruction.opcode {
+                    Opcode::Jump => EdgeKind::Jump,
+                    Opcode::JumpIf => EdgeKind::Branch,
+                    Opcode::Call => EdgeKind::Call,
+                    _ => EdgeKind::Exception,
+                };
+                block.successors.push(Edge { target, kind });
+            }
+        }
+
+        // Blocks that don't end in a jump or a terminator run into the next one
+        let starts: BTreeSet<usize> = blocks.iter().map(|block| block.start).collect();
+        for block in &mut blocks {
+            let last = block.instructions.last().unwrap().opcode;
+            if !matches!(
+                last,
+                Opcode::Jump | Opcode::Halt | Opcode::Exit | Opcode::Ret | Opcode::Throw
+            ) && starts.contains(&block.end)
+            {
+                block.successors.push(Edge {
+                    target: block.end,
+                    kind: EdgeKind::FallThrough,
+                });
+            }
+            // Edges into the data segment or past the end lead nowhere
+            block
+                .successors
+                .retain(|edge| starts.contains(&edge.target));
+        }
+        Ok(Cfg { blocks })
+    }
+
+    /// The block that starts at `addr`.
+    pub fn block_at(&self, addr: usize) -> Option<&BasicBlock> {
+        self.blocks
+            .binary_search_by_key(&addr, |block| block.start)
+            .ok()
+            .map(|index| &self.blocks[index])
+    }
+
+    /// Start addresses of the blocks with an edge into the block at `addr`.
+    pub fn predecessors(&self, addr: usize) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .filter(|block| block.successors.iter().any(|edge| edge.target == addr))
+            .map(|block| block.start)
+            .collect()
+    }
+
+    /// Renders the graph in Graphviz DOT format, one box per block listing
+    /// its instructions.
+    pub fn to_dot(&self) -> String {
+        let mut dot =
+            String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for block in &self.blocks {
+            let mut label = String::new();
+            for instruction in &block.instructions {
+                write!(label, "{:04}: {:?}", instruction.addr, instruction.opcode).unwrap();
+                if let Some(operand) = instruction.operand {
+                    write!(label, " {}", operand).unwrap();
+                }
+                label.push_str("\\l");
+            }
+            writeln!(dot, "    block_{} [label=\"{}\"];", block.start, label).unwrap();
+        }
+        for block in &self.blocks {
+            for edge in &block.successors {
+                let attributes = match edge.kind {
+                    EdgeKind::FallThrough | EdgeKind::Jump => "",
+                    EdgeKind::Branch => " [label=\"true\"]",
+                    EdgeKind::Call => " [style=dashed, label=\"call\"]",
+                    EdgeKind::Exception => " [style=dotted, label=\"catch\"]",
+                };
+                writeln!(
+                    dot,
+                    "    block_{} -> block_{}{};",
+                    block.start, edge.target, attributes
+                )
+                .unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn ends_block(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Jump | Opcode::JumpIf | Opcode::Halt | Opcode::Exit | Opcode::Ret | Opcode::Throw
+    )
+}
+
+/// The static target of the control transfer at `index`, if it is one.
+fn transfer_target(instructions: &[Instruction], index: usize) -> Option<usize> {
+    match instructions[index].opcode {
+        Opcode::Jump | Opcode::JumpIf | Opcode::Call | Opcode::PushHandler => {
+            bytecode::pushed_operand(instructions, index)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cfg, EdgeKind};
+    use crate::compiler::Compiler;
+    use crate::test_util::compile_with;
+    use crate::Opcode;
+
+    fn compile(source: &str) -> Vec<u8> {
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compile_with(&mut compiler, source)
+    }
+
+    #[test]
+    fn splits_straight_line_code_into_one_block() {
+        let cfg = Cfg::build(&compile("let x = 1 + 2; print x;")).unwrap();
+        let main = cfg.block_at(0).unwrap();
+        assert_eq!(main.instructions.last().unwrap().opcode, Opcode::Halt);
+        assert!(main.successors.is_empty());
+    }
+
+    #[test]
+    fn connects_loops_and_branches() {
+        let cfg = Cfg::build(&compile(
+            "let i = 0; while i < 3 { if i == 1 { print i; } i = i + 1; } print \"done\";",
+        ))
+        .unwrap();
+        let branches: Vec<_> = cfg
+            .blocks
+            .iter()
+            .flat_map(|block| &block.successors)
+            .filter(|edge| edge.kind == EdgeKind::Branch)
+            .collect();
+        assert_eq!(branches.len(), 2);
+        // The loop header is reached both from the entry and the back edge
+        let back_edge = cfg
+            .blocks
+            .iter()
+            .flat_map(|block| block.successors.iter().map(move |edge| (block.start, edge)))
+            .find(|(from, edge)| edge.kind == EdgeKind::Jump && edge.target < *from)
+            .unwrap();
+        assert_eq!(cfg.predecessors(back_edge.1.target).len(), 2);
+        for block in &cfg.blocks {
+            for edge in &block.successors {
+                assert!(cfg.block_at(edge.target).is_some());
+            }
+        }
+        // The string data segment is not decoded as code
+        let last = cfg.blocks.last().unwrap();
+        assert_eq!(last.instructions.last().unwrap().opcode, Opcode::Halt);
+    }
+
+    #[test]
+    fn renders_dot() {
+        let cfg = Cfg::build(&compile(
+            "fn double(n) { return n * 2; } if double(2) > 3 { print 1; }",
+        ))
+        .unwrap();
+        let dot = cfg.to_dot();
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.contains("block_0 [label=\"0000: "));
+        assert!(dot.contains("[style=dashed, label=\"call\"]"));
+        assert!(dot.contains("[label=\"true\"]"));
+        assert!(dot.ends_with("}\n"));
+    }
+}
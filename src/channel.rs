@@ -0,0 +1,89 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use crate::VM;
+
+/// A queue of values between programs, or between a program and its host.
+/// Clones share the same queue, so a channel attached to two VMs carries
+/// what one sends with `Send` to the other's `Recv`.
+#[derive(Debug, Clone, Default)]
+pub struct Channel(Rc<RefCell<VecDeque<i64>>>);
+
+impl Channel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `value` behind those already sent.
+    pub fn send(&self, value: i64) {
+        self.0.borrow_mut().push_back(value);
+    }
+
+    /// Takes the oldest value sent, if any is waiting.
+    pub fn recv(&self) -> Option<i64> {
+        self.0.borrow_mut().pop_front()
+    }
+
+    /// How many values are waiting.
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+}
+
+/// Wires a new channel from `sender`, where programs send to it as channel
+/// `send_id`, to `receiver`, where they receive from it as `recv_id`, and
+/// returns it so the host can also look into it.
+pub fn connect(sender: &mut VM, send_id: i64, receiver: &mut VM, recv_id: i64) -> Channel {
+    let channel = Channel::new();
+    sender.attach_channel(send_id, channel.clone());
+    receiver.attach_channel(recv_id, channel.clone());
+    channel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, Channel};
+    use crate::test_util::compile;
+    use crate::{VMError, VM};
+
+    #[test]
+    fn passes_values_between_vms() {
+        let mut producer = VM::new(
+            compile("let i = 1; while i <= 3 { send(0, i * i); i = i + 1; }"),
+            64,
+        );
+        let mut consumer = VM::new(
+            compile("let total = recv(5) + recv(5) + recv(5); exit(total);"),
+            64,
+        );
+        let channel = connect(&mut producer, 0, &mut consumer, 5);
+
+        // Nothing has been sent yet, so the consumer waits at its first
+        // `recv` and takes it again once the producer has run
+        assert!(matches!(consumer.run(), Err(VMError::ChannelEmpty(5))));
+        producer.run().unwrap();
+        assert_eq!(channel.len(), 3);
+        consumer.run().unwrap();
+        assert_eq!(consumer.get_exit_code(), 14);
+        assert!(channel.is_empty());
+    }
+
+    #[test]
+    fn hosts_talk_to_programs_through_channels() {
+        let mut vm = VM::new(compile("send(1, recv(0) * 2);"), 64);
+        let (input, output) = (Channel::new(), Channel::new());
+        vm.attach_channel(0, input.clone());
+        vm.attach_channel(1, output.clone());
+        input.send(21);
+        vm.run().unwrap();
+        assert_eq!(output.recv(), Some(42));
+
+        let mut vm = VM::new(compile("send(3, 1);"), 64);
+        assert!(matches!(vm.run(), Err(VMError::UnknownChannel(3))));
+    }
+}
@@ -0,0 +1,496 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::compiler::{
+    diagnostics::Warning,
+    parser::{Expr, ExprKind, Param, Parser, Statement, StatementKind, Type},
+    prelude,
+    span::Span,
+    typeck::TypeChecker,
+    visit::{self, Visitor},
+    Compiler,
+};
+
+/// How serious a diagnostic is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a source file, with where it was found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Variable,
+    Constant,
+    Parameter,
+    Function,
+    Struct,
+}
+
+/// A name declared in a source file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// The name where it is declared
+    pub span: Span,
+    /// The declared or inferred type, `Unknown` where the type checker did
+    /// not get to it
+    pub ty: Type,
+}
+
+/// Formats a symbol as it would be declared, with its type.
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.kind, &self.ty) {
+            (SymbolKind::Function, Type::Function(params, result)) => {
+                let params: Vec<String> = params.iter().map(Type::to_string).collect();
+                write!(f, "fn {}({})", self.name, params.join(", "))?;
+                match **result {
+                    Type::Unknown => Ok(()),
+                    ref result => write!(f, " -> {}", result),
+                }
+            }
+            (SymbolKind::Function, _) => write!(f, "fn {}", self.name),
+            (SymbolKind::Struct, _) => write!(f, "struct {}", self.name),
+            (SymbolKind::Constant, ty) => write!(f, "const {}: {}", self.name, ty),
+            (SymbolKind::Variable, ty) => write!(f, "let {}: {}", self.name, ty),
+            (SymbolKind::Parameter, ty) => write!(f, "{}: {}", self.name, ty),
+        }
+    }
+}
+
+/// What an editor needs to know about a source file: its diagnostics, and
+/// a symbol table of the names it declares with every use of them.
+///
+/// Syntax errors are all reported, thanks to the parser's recovery; the
+/// compiler stops at its first error. Modules a file imports are not
+/// loaded, so a file with imports gets only syntax errors, and calls to
+/// imported functions resolve to nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Analysis {
+    diagnostics: Vec<Diagnostic>,
+    symbols: Vec<Symbol>,
+    /// Uses of names, with the index of the symbol each resolves to
+    references: Vec<(Span, usize)>,
+}
+
+impl Analysis {
+    pub fn new(source: &str) -> Self {
+        let mut analysis = Analysis::default();
+        let mut parser = Parser::new(source);
+        let imports = match parser.parse_imports() {
+            Ok(imports) => imports,
+            Err(error) => {
+                analysis.error(error.message(), error.span());
+                return analysis;
+            }
+        };
+        let statements = match parser.parse_program() {
+            Ok(statements) => statements,
+            Err(errors) => {
+                for error in errors {
+                    analysis.error(error.message(), error.span());
+                }
+                return analysis;
+            }
+        };
+
+        let checker = check_types(&statements);
+        if imports.is_empty() {
+            let mut compiler = Compiler::new();
+            match compiler.compile(statements.clone()) {
+                Ok(_) => analysis.warn(compiler.warnings()),
+                Err(error) => analysis.error(error.message(), error.span()),
+            }
+        }
+
+        let mut resolver = Resolver {
+            source,
+            checker: &checker,
+            scopes: vec![HashMap::new()],
+            analysis: &mut analysis,
+        };
+        resolver.declare_top_level(&statements);
+        visit::walk_block(&mut resolver, &statements);
+        analysis
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// The symbol whose name is at byte `offset`, where it is declared or
+    /// used.
+    pub fn symbol_at(&self, offset: usize) -> Option<&Symbol> {
+        let contains = |span: &Span| (span.offset..=span.end()).contains(&offset);
+        self.references
+            .iter()
+            .find(|(span, _)| contains(span))
+            .map(|&(_, index)| &self.symbols[index])
+            .or_else(|| self.symbols.iter().find(|symbol| contains(&symbol.span)))
+    }
+
+    /// Where `symbol` is used, its declaration excluded.
+    pub fn references(&self, symbol: &Symbol) -> impl Iterator<Item = Span> + '_ {
+        let index = self.symbols.iter().position(|other| other == symbol);
+        self.references
+            .iter()
+            .filter(move |(_, other)| Some(*other) == index)
+            .map(|&(span, _)| span)
+    }
+
+    fn error(&mut self, message: String, span: Span) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message,
+            span,
+        });
+    }
+
+    fn warn(&mut self, warnings: &[Warning]) {
+        self.diagnostics
+            .extend(warnings.iter().map(|warning| Diagnostic {
+                severity: Severity::Warning,
+                message: warning.message.clone(),
+                span: warning.span,
+            }));
+    }
+}
+
+/// Type checks a program as the compiler would, with the prelude's
+/// functions declared, recording the type of each expression. Checking
+/// stops at the first error, leaving later expressions without a type.
+fn check_types(statements: &[Statement]) -> TypeChecker {
+    let mut checker = TypeChecker::new();
+    checker.record_types();
+    // The prelude is checked on its own, so its spans do not mix with the
+    // program's
+    let defined: HashSet<&str> = statements
+        .iter()
+        .filter_map(|statement| match &statement.kind {
+            StatementKind::Function(name, ..) | StatementKind::Extern(name, ..) => {
+                Some(name.as_str())
+            }
+            _ => None,
+        })
+        .collect();
+    if let Ok(prelude) = Parser::new(prelude::SOURCE).parse_program() {
+        let mut prelude_checker = TypeChecker::new();
+        if prelude_checker.check(&prelude).is_ok() {
+            for statement in &prelude {
+                let StatementKind::Function(name, ..) = &statement.kind else {
+                    continue;
+                };
+                match prelude_checker.function_type(name) {
+                    Some(signature) if !defined.contains(name.as_str()) => {
+                        checker.declare_function(name, signature.clone())
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    let _ = checker.check(statements);
+    checker
+}
+
+/// Builds the symbol table, following the compiler's scoping: functions and
+/// structs are known throughout the program, other names from their
+/// declaration to the end of their block.
+struct Resolver<'a> {
+    source: &'a str,
+    checker: &'a TypeChecker,
+    /// Innermost scope last, mapping names to symbol indices
+    scopes: Vec<HashMap<String, usize>>,
+    analysis: &'a mut Analysis,
+}
+
+impl Resolver<'_> {
+    fn declare_top_level(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            let (name, kind, ty) = match &statement.kind {
+                StatementKind::Function(name, ..) | StatementKind::Extern(name, ..) => {
+                    let ty = self.checker.function_type(name).cloned();
+                    (name, SymbolKind::Function, ty.unwrap_or(Type::Unknown))
+                }
+                StatementKind::Struct(name, _) => {
+                    (name, SymbolKind::Struct, Type::Struct(name.clone()))
+                }
+                _ => continue,
+            };
+            let span = self.find_name(statement.span, statement.span.offset, name);
+            self.declare(name, kind, span, ty);
+        }
+    }
+
+    fn declare(&mut self, name: &str, kind: SymbolKind, span: Span, ty: Type) {
+        self.analysis.symbols.push(Symbol {
+            name: name.to_string(),
+            kind,
+            span,
+            ty,
+        });
+        let index = self.analysis.symbols.len() - 1;
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(name.to_string(), index);
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+    }
+
+    fn reference(&mut self, name: &str, span: Span) {
+        if let Some(index) = self.lookup(name) {
+            self.analysis.references.push((span, index));
+        }
+    }
+
+    /// Records a use of `name` in a statement that does not give it a span
+    /// of its own, such as an assignment, declaring it if it is not yet
+    /// defined, as assigning to an unknown name does.
+    fn assign(&mut self, name: &str, span: Span, value: Option<&Expr>) {
+        if self.lookup(name).is_some() {
+            self.reference(name, span);
+        } else {
+            let ty = self.type_of(value);
+            self.declare(name, SymbolKind::Variable, span, ty);
+        }
+    }
+
+    fn type_of(&self, expr: Option<&Expr>) -> Type {
+        expr.and_then(|expr| self.checker.expr_type(expr.span))
+            .cloned()
+            .unwrap_or(Type::Unknown)
+    }
+
+    /// Declares a function's or closure's parameters in a new scope, which
+    /// the caller pops after the body.
+    fn declare_params(&mut self, within: Span, from: usize, params: &[Param]) {
+        self.scopes.push(HashMap::new());
+        let mut from = from;
+        for (param, annotation) in params {
+            let span = self.find_name(within, from, param);
+            from = span.end();
+            let ty = annotation.clone().unwrap_or(Type::Unknown);
+            self.declare(param, SymbolKind::Parameter, span, ty);
+        }
+    }
+
+    /// The first whole-word occurrence of `name` in `within` at or after
+    /// byte `from`, since the AST gives spans to statements but not to the
+    /// names they declare. Falls back to the start of `within`.
+    fn find_name(&self, within: Span, from: usize, name: &str) -> Span {
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let text = within.text(self.source);
+        let mut start = from.saturating_sub(within.offset).min(text.len());
+        while let Some(found) = text[start..].find(name) {
+            let begin = start + found;
+            let end = begin + name.len();
+            let before = text[..begin].chars().next_back();
+            let after = text[end..].chars().next();
+            if !before.is_some_and(is_word) && !after.is_some_and(is_word) {
+                return self.span(within.offset + begin, name.len());
+            }
+            start = end;
+        }
+        self.span(within.offset, 0)
+    }
+
+    fn span(&self, offset: usize, len: usize) -> Span {
+        let before = &self.source[..offset];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        Span {
+            line: before.matches('\n').count() + 1,
+            col: before[line_start..].chars().count() + 1,
+            offset,
+            len,
+            file: 0,
+        }
+    }
+}
+
+impl Visitor for Resolver<'_> {
+    fn visit_block(&mut self, statements: &[Statement]) {
+        self.scopes.push(HashMap::new());
+        visit::walk_block(self, statements);
+        self.scopes.pop();
+    }
+
+    fn visit_statement(&mut self, statement: &Statement) {
+        let span = statement.span;
+        match &statement.kind {
+            StatementKind::Let(name, annotation, value) => {
+                self.visit_expr(value);
+                let ty = annotation
+                    .clone()
+                    .unwrap_or_else(|| self.type_of(Some(value)));
+                let name_span = self.find_name(span, span.offset, name);
+                self.declare(name, SymbolKind::Variable, name_span, ty);
+            }
+            StatementKind::Const(name, value) => {
+                self.visit_expr(value);
+                let ty = self.type_of(Some(value));
+                let name_span = self.find_name(span, span.offset, name);
+                self.declare(name, SymbolKind::Constant, name_span, ty);
+            }
+            StatementKind::LetTuple(names, values) => {
+                values.iter().for_each(|value| self.visit_expr(value));
+                let mut from = span.offset;
+                for (name, value) in names.iter().zip(values) {
+                    let name_span = self.find_name(span, from, name);
+                    from = name_span.end();
+                    let ty = self.type_of(Some(value));
+                    self.declare(name, SymbolKind::Variable, name_span, ty);
+                }
+            }
+            StatementKind::Assign(name, value) => {
+                self.visit_expr(value);
+                let name_span = self.find_name(span, span.offset, name);
+                self.assign(name, name_span, Some(value));
+            }
+            StatementKind::AssignTuple(names, values) => {
+                values.iter().for_each(|value| self.visit_expr(value));
+                let mut from = span.offset;
+                for (name, value) in names.iter().zip(values) {
+                    let name_span = self.find_name(span, from, name);
+                    from = name_span.end();
+                    self.assign(name, name_span, Some(value));
+                }
+            }
+            StatementKind::IndexAssign(name, ..)
+            | StatementKind::Increment(name)
+            | StatementKind::Decrement(name)
+            | StatementKind::Call(name, _) => {
+                let name_span = self.find_name(span, span.offset, name);
+                self.reference(name, name_span);
+                visit::walk_statement(self, statement);
+            }
+            StatementKind::Try(body, name, handler) => {
+                self.visit_block(body);
+                let from = body.last().map_or(span.offset, |last| last.span.end());
+                let catch = self.find_name(span, from, "catch");
+                let name_span = self.find_name(span, catch.end(), name);
+                self.scopes.push(HashMap::new());
+                self.declare(name, SymbolKind::Variable, name_span, Type::Int);
+                self.visit_block(handler);
+                self.scopes.pop();
+            }
+            StatementKind::Function(name, params, _, body) => {
+                let name_span = self.find_name(span, span.offset, name);
+                self.declare_params(span, name_span.end(), params);
+                visit::walk_block(self, body);
+                self.scopes.pop();
+            }
+            _ => visit::walk_statement(self, statement),
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Variable(name) => self.reference(name, expr.span),
+            ExprKind::Call(name, _) => {
+                let name_span = self.span(expr.span.offset, name.len());
+                self.reference(name, name_span);
+                visit::walk_expr(self, expr);
+            }
+            ExprKind::StructLiteral(name, _) => {
+                let name_span = self.span(expr.span.offset, name.len());
+                self.reference(name, name_span);
+                visit::walk_expr(self, expr);
+            }
+            ExprKind::Closure(_, params, _, body) => {
+                self.declare_params(expr.span, expr.span.offset, params);
+                visit::walk_block(self, body);
+                self.scopes.pop();
+            }
+            _ => visit::walk_expr(self, expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Analysis, Severity, SymbolKind};
+
+    #[test]
+    fn reports_syntax_type_and_lint_problems() {
+        let analysis = Analysis::new("let a = ;\nlet b = 1 +;\n");
+        let lines: Vec<usize> = analysis
+            .diagnostics()
+            .iter()
+            .map(|diagnostic| diagnostic.span.line)
+            .collect();
+        assert_eq!(lines, [1, 2]);
+
+        let source = "let n = 1;\nif n > 0 {\n    let s: string = n;\n}\n";
+        let diagnostics = Analysis::new(source).diagnostics().to_vec();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].span.line, 3);
+
+        let diagnostics = Analysis::new("let unused = 1;").diagnostics().to_vec();
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert!(diagnostics[0].message.contains("unused"));
+    }
+
+    #[test]
+    fn resolves_names_to_declarations() {
+        let source = "\
+fn square(n: int) { return n * n; }
+let total = square(3);
+if total > 0 {
+    let total = \"shadow\";
+    print total;
+}
+total = total + gcd(4, 6);
+";
+        let analysis = Analysis::new(source);
+        assert!(analysis.diagnostics().is_empty());
+        let at = |line: usize, text: &str| {
+            let start: usize = source.lines().take(line - 1).map(|l| l.len() + 1).sum();
+            start + source.lines().nth(line - 1).unwrap().find(text).unwrap()
+        };
+
+        let square = analysis.symbol_at(at(2, "square")).unwrap();
+        assert_eq!(square.kind, SymbolKind::Function);
+        assert_eq!(square.span.line, 1);
+        assert_eq!(square.to_string(), "fn square(int) -> int");
+
+        let param = analysis.symbol_at(at(1, "n *")).unwrap();
+        assert_eq!((param.kind, param.span.col), (SymbolKind::Parameter, 11));
+        assert_eq!(param.to_string(), "n: int");
+
+        let total = analysis.symbol_at(at(7, "total")).unwrap();
+        assert_eq!(
+            (total.span.line, total.to_string().as_str()),
+            (2, "let total: int")
+        );
+        assert_eq!(analysis.references(total).count(), 3);
+        let shadow = analysis.symbol_at(at(5, "total")).unwrap();
+        assert_eq!(
+            (shadow.span.line, shadow.to_string().as_str()),
+            (4, "let total: string")
+        );
+
+        // Prelude functions have no declaration in the file
+        assert!(analysis.symbol_at(at(7, "gcd")).is_none());
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::compiler::{
+    error::CompileError,
+    object::{Object, Symbol},
+    parser::{Statement, Type},
+};
+
+/// A program as the front half of the compiler hands it to a code
+/// generator: linked with the prelude, type-checked and optimized.
+#[derive(Debug, Clone, Default)]
+pub struct Ir {
+    /// The top-level statements, prelude functions included
+    pub statements: Vec<Statement>,
+    /// Signatures of every function and `extern` the program declares,
+    /// with the return types inferred by the type checker
+    pub function_types: HashMap<String, Type>,
+    /// Closure signatures inferred by the type checker, keyed by closure id
+    pub closure_types: HashMap<usize, Type>,
+    /// Top-level functions defined by the program rather than the prelude
+    pub own_functions: HashSet<String>,
+    /// Functions defined by other objects that the program may call
+    pub imports: Vec<Symbol>,
+    /// Function called once the top-level code has run, if any
+    pub entry_point: Option<String>,
+}
+
+/// The output of a `Backend`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Artifact {
+    /// A program for the stack-based `VM`
+    Bytecode(Vec<u8>),
+    /// A relocatable object, to be combined with others by a `Linker`
+    Object(Object),
+    /// A binary for another machine, such as a WebAssembly module
+    Binary(Vec<u8>),
+    /// Source code in another language, for transpiling backends
+    Source(String),
+}
+
+/// Generates code for a machine from the compiler's `Ir`.
+///
+/// `Compiler::compile_with` runs the front half of the compiler and hands
+/// the result to any backend; `Compiler::compile` uses the stack-bytecode
+/// `StackBackend`. A backend may be used for any number of programs.
+pub trait Backend {
+    fn emit_program(&mut self, ir: &Ir) -> Result<Artifact, CompileError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Artifact, Backend, Ir};
+    use crate::compiler::{
+        error::CompileError,
+        parser::{ExprKind, Parser, StatementKind, Type},
+        Compiler, StackBackend,
+    };
+
+    /// Lists the top-level variables with the values they are initialized
+    /// to, as constant folding left them, and the functions with their
+    /// arity.
+    struct Summary;
+
+    impl Backend for Summary {
+        fn emit_program(&mut self, ir: &Ir) -> Result<Artifact, CompileError> {
+            let mut lines = Vec::new();
+            for statement in &ir.statements {
+                match &statement.kind {
+                    StatementKind::Let(name, _, value) => match value.kind {
+                        ExprKind::Number(n) => lines.push(format!("{} = {}", name, n)),
+                        _ => lines.push(format!("{} = ?", name)),
+                    },
+                    StatementKind::Function(name, ..) => {
+                        let Some(Type::Function(params, _)) = ir.function_types.get(name) else {
+                            return Err(CompileError::UnknownFunction {
+                                name: name.clone(),
+                                span: statement.span,
+                            });
+                        };
+                        lines.push(format!("fn {}/{}", name, params.len()));
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Artifact::Source(lines.join("\n")))
+        }
+    }
+
+    const PROGRAM: &str = "
+        fn add(a, b) { return a + b; }
+        let x = 2 * 3 + 1;
+        let y = add(x, 1);
+        print(y);
+    ";
+
+    #[test]
+    fn hands_the_checked_and_optimized_program_to_a_backend() {
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        let statements = Parser::new(PROGRAM).parse_program().unwrap();
+        let artifact = compiler.compile_with(&mut Summary, statements).unwrap();
+        assert_eq!(
+            artifact,
+            Artifact::Source("fn add/2\nx = 7\ny = ?".to_string())
+        );
+
+        // The front half still rejects ill-typed programs
+        let statements = Parser::new("let s = \"a\" - 1;").parse_program().unwrap();
+        assert!(matches!(
+            compiler.compile_with(&mut Summary, statements),
+            Err(CompileError::Type(_))
+        ));
+    }
+
+    #[test]
+    fn stack_backend_emits_what_compile_returns() {
+        let mut compiler = Compiler::new();
+        let statements = Parser::new(PROGRAM).parse_program().unwrap();
+        let bytecode = compiler.compile(statements).unwrap();
+        let statements = Parser::new(PROGRAM).parse_program().unwrap();
+        let artifact = compiler
+            .compile_with(&mut StackBackend::new(), statements)
+            .unwrap();
+        assert_eq!(artifact, Artifact::Bytecode(bytecode));
+
+        let mut backend = StackBackend::new();
+        backend.set_object(true);
+        let statements = Parser::new(PROGRAM).parse_program().unwrap();
+        let object = compiler.compile_object(statements).unwrap();
+        let statements = Parser::new(PROGRAM).parse_program().unwrap();
+        let artifact = compiler.compile_with(&mut backend, statements).unwrap();
+        assert_eq!(artifact, Artifact::Object(object));
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+
+use crate::{
+    bytecode::OperandEncoding,
+    compiler::{codegen::Compiler, error::BuildError, pass::OptLevel},
+    FRAME_BASE,
+};
+
+/// The machine a compiler generates code for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Target {
+    /// Bytecode for the stack-based `VM`
+    #[default]
+    Stack,
+    /// Code for a register machine, which has no backend yet
+    Register,
+    /// A WebAssembly module, which has no backend yet
+    Wasm,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Stack => write!(f, "stack"),
+            Target::Register => write!(f, "register"),
+            Target::Wasm => write!(f, "wasm"),
+        }
+    }
+}
+
+/// Configures a `Compiler`, starting from the defaults of `Compiler::new`.
+#[derive(Debug, Clone)]
+pub struct CompilerBuilder {
+    opt_level: OptLevel,
+    target: Target,
+    debug_info: bool,
+    entry_point: Option<String>,
+    global_base: usize,
+    prelude: bool,
+    encoding: OperandEncoding,
+}
+
+impl Default for CompilerBuilder {
+    fn default() -> Self {
+        CompilerBuilder {
+            opt_level: OptLevel::Default,
+            target: Target::Stack,
+            debug_info: false,
+            entry_point: None,
+            global_base: 0,
+            prelude: true,
+            encoding: OperandEncoding::Fixed,
+        }
+    }
+}
+
+impl CompilerBuilder {
+    /// The built-in optimization passes to run (`OptLevel::Default`).
+    pub fn opt_level(mut self, level: OptLevel) -> Self {
+        self.opt_level = level;
+        self
+    }
+
+    /// The machine to generate code for (`Target::Stack`, the only one
+    /// that can be built so far).
+    pub fn target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Whether to build a `DebugInfo` source map (off).
+    pub fn debug_info(mut self, enabled: bool) -> Self {
+        self.debug_info = enabled;
+        self
+    }
+
+    /// A function taking no arguments to call once the top-level code has
+    /// run (none).
+    pub fn entry_point(mut self, name: &str) -> Self {
+        self.entry_point = Some(name.to_string());
+        self
+    }
+
+    /// The address of the first global variable (0).
+    pub fn global_base(mut self, base: usize) -> Self {
+        self.global_base = base;
+        self
+    }
+
+    /// Whether to link the standard prelude (on).
+    pub fn prelude(mut self, enabled: bool) -> Self {
+        self.prelude = enabled;
+        self
+    }
+
+    /// How inline operands are encoded (`OperandEncoding::Fixed`).
+    pub fn operand_encoding(mut self, encoding: OperandEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Creates the compiler, which can then compile any number of programs
+    /// with these options. Fails for a target without a backend, or a
+    /// global base that leaves no room for globals.
+    pub fn build(self) -> Result<Compiler, BuildError> {
+        if self.target != Target::Stack {
+            return Err(BuildError::UnsupportedTarget(self.target));
+        }
+        if self.global_base >= FRAME_BASE {
+            return Err(BuildError::GlobalBase {
+                base: self.global_base,
+                frames: FRAME_BASE,
+            });
+        }
+        let mut compiler = Compiler::new();
+        compiler.set_opt_level(self.opt_level);
+        compiler.set_debug_info(self.debug_info);
+        compiler.set_entry_point(self.entry_point.as_deref());
+        compiler.set_global_base(self.global_base);
+        compiler.set_prelude(self.prelude);
+        compiler.set_operand_encoding(self.encoding);
+        Ok(compiler)
+    }
+}
@@ -1,22 +1,371 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use crate::{
-    compiler::parser::{BinaryOpKind, Expr, Statement},
+    bytecode::{self, OperandEncoding},
+    compiler::{
+        backend::{Artifact, Backend, Ir},
+        builder::CompilerBuilder,
+        const_eval::const_eval,
+        debug_info::DebugInfo,
+        diagnostics::{self, Warning},
+        error::CompileError,
+        object::{Object, Relocation, RelocationKind, Symbol},
+        parser::{
+            BinaryOpKind, Expr, ExprKind, MatchPattern, Param, Statement, StatementKind, Type,
+            UnaryOpKind,
+        },
+        pass::{OptLevel, Pass, PassManager, PassReport},
+        prelude,
+        session::{CachedFunction, FunctionCache, FunctionKey},
+        span::Span,
+        typeck::TypeChecker,
+        visit::{self, Visitor},
+    },
     Opcode,
 };
 
-pub struct Compiler {
+/// Largest count of an `[value; count]` array. Every element is pushed
+/// before the array is created, so the VM's stack must have room for them.
+const MAX_ARRAY_REPEAT: i64 = 1 << 16;
+
+/// The compile-time kind of a value, used to pick the right opcodes for
+/// operations that look the same in source (`+`, `print`, `len`).
+#[derive(Debug, Clone, PartialEq)]
+enum ValueKind {
+    Int,
+    /// An `f64` stored as its bit pattern
+    Float,
+    Str,
+    Array,
+    Map,
+    /// Index into `StackBackend::structs`
+    Struct(usize),
+    /// A code address, with the kind of value the function returns
+    Function(Box<ValueKind>),
+}
+
+/// Where a variable's value lives at run time.
+#[derive(Debug, Clone, Copy)]
+enum Storage {
+    /// A top-level variable at a fixed address in data memory
+    Global(usize),
+    /// A slot in the current call frame
+    Local(usize),
+    /// A value captured by the enclosing closure, at `index` of the
+    /// environment whose pointer is held in local `env_slot`
+    Captured { env_slot: usize, index: usize },
+}
+
+#[derive(Debug, Clone)]
+struct Variable {
+    storage: Storage,
+    kind: ValueKind,
+}
+
+/// Variables declared in one block. Slots are handed out stack-wise, so
+/// leaving a scope makes every slot from `start_slot` up available again.
+struct Scope {
+    variables: HashMap<String, Variable>,
+    /// A global address at the top level, a frame slot inside a function
+    start_slot: usize,
+}
+
+/// The function or closure currently being compiled.
+struct Frame {
+    /// Index into `StackBackend::scopes` of the scope holding the parameters
+    scope_start: usize,
+    next_slot: usize,
+    /// Number of slots the frame needs, the most ever live at once
+    size: usize,
+    /// Slot receiving the environment pointer passed by the caller
+    env_slot: usize,
+    /// Enclosing variables read by the body, as seen where the closure is created
+    captures: Vec<Variable>,
+}
+
+/// A piece of a `print` format string.
+enum FormatPiece {
+    Literal(String),
+    /// A `{}` placeholder, filled by the next argument
+    Argument,
+}
+
+/// Splits a format string into literal text and `{}` placeholders; `{{`
+/// and `}}` stand for literal braces.
+fn parse_format(format: &str, span: Span) -> Result<Vec<FormatPiece>, CompileError> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                literal.push(ch);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                if !literal.is_empty() {
+                    pieces.push(FormatPiece::Literal(std::mem::take(&mut literal)));
+                }
+                pieces.push(FormatPiece::Argument);
+            }
+            ('{', _) => return Err(CompileError::MalformedPlaceholder { span }),
+            ('}', _) => return Err(CompileError::UnmatchedBrace { span }),
+            _ => literal.push(ch),
+        }
+    }
+    if !literal.is_empty() {
+        pieces.push(FormatPiece::Literal(literal));
+    }
+    Ok(pieces)
+}
+
+/// A top-level function, callable by name or through a function value.
+struct Function {
+    /// Entry point, known once the body has been emitted
+    addr: Option<usize>,
+    arity: usize,
+    /// Parameter and return types as inferred by the type checker
+    params: Vec<Type>,
+    result: Type,
+    /// For `extern` functions, the index of the host function binding
+    host: Option<usize>,
+    /// Defined by an imported object, so only its linker knows the entry
+    /// point
+    imported: bool,
+}
+
+impl Function {
+    fn signature(&self) -> Type {
+        Type::Function(self.params.clone(), Box::new(self.result.clone()))
+    }
+}
+
+/// How often each literal value, and each name, appears in a program, to
+/// decide which values are worth a constant pool entry.
+#[derive(Default)]
+struct ConstantUses {
+    values: HashMap<i64, usize>,
+    names: HashMap<String, usize>,
+    /// Names declared with `const`
+    constants: HashSet<String>,
+}
+
+impl ConstantUses {
+    fn of_value(&self, value: i64) -> usize {
+        self.values.get(&value).copied().unwrap_or(0)
+    }
+
+    fn of_name(&self, name: &str) -> usize {
+        self.names.get(name).copied().unwrap_or(0)
+    }
+
+    /// Whether some value will be pushed from the pool.
+    fn any_repeated(&self) -> bool {
+        self.values.values().any(|&uses| uses > 1)
+            || self.constants.iter().any(|name| self.of_name(name) > 1)
+    }
+}
+
+impl Visitor for ConstantUses {
+    fn visit_statement(&mut self, statement: &Statement) {
+        if let StatementKind::Const(name, _) = &statement.kind {
+            self.constants.insert(name.clone());
+        }
+        visit::walk_statement(self, statement);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Number(n) => *self.values.entry(*n).or_default() += 1,
+            ExprKind::Float(value) => *self.values.entry(value.to_bits() as i64).or_default() += 1,
+            ExprKind::Variable(name) => *self.names.entry(name.clone()).or_default() += 1,
+            _ => {}
+        }
+        visit::walk_expr(self, expr);
+    }
+}
+
+/// The variables a loop reads, and those it assigns or declares, which
+/// must be loaded afresh on every iteration.
+#[derive(Default)]
+struct LoopUses {
+    reads: HashSet<String>,
+    writes: HashSet<String>,
+}
+
+impl Visitor for LoopUses {
+    fn visit_statement(&mut self, statement: &Statement) {
+        match &statement.kind {
+            StatementKind::Let(name, ..)
+            | StatementKind::Assign(name, _)
+            | StatementKind::Increment(name)
+            | StatementKind::Decrement(name)
+            | StatementKind::Try(_, name, _) => {
+                self.writes.insert(name.clone());
+            }
+            StatementKind::LetTuple(names, _) | StatementKind::AssignTuple(names, _) => {
+                self.writes.extend(names.iter().cloned());
+            }
+            // The array's elements change, not the variable holding it
+            StatementKind::IndexAssign(name, ..) | StatementKind::Call(name, _) => {
+                self.reads.insert(name.clone());
+            }
+            _ => {}
+        }
+        visit::walk_statement(self, statement);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Variable(name) | ExprKind::Call(name, _) => {
+                self.reads.insert(name.clone());
+            }
+            ExprKind::Closure(_, params, ..) => {
+                self.writes
+                    .extend(params.iter().map(|(name, _)| name.clone()));
+            }
+            _ => {}
+        }
+        visit::walk_expr(self, expr);
+    }
+}
+
+/// The code generator for the stack-based `VM`, emitting bytecode or, for
+/// a `Linker`, relocatable objects.
+pub struct StackBackend {
     bytecode: Vec<u8>,
-    variables: HashMap<String, usize>,
+    /// Innermost scope last; the first scope holds top-level variables
+    scopes: Vec<Scope>,
     next_var_addr: usize,
+    /// Number of global addresses allocated so far
+    globals: usize,
+    /// Enclosing function bodies, innermost last; empty at the top level
+    frames: Vec<Frame>,
+    /// Declared structs with their field names, in layout order
+    structs: Vec<(String, Vec<String>)>,
+    /// Values of `const` declarations, inlined at every use
+    constants: HashMap<String, i64>,
+    /// String constants, appended to the program as a data segment
+    strings: Vec<String>,
+    /// Operand positions to patch with the data offset of a string constant
+    string_fixups: Vec<(usize, usize)>,
+    /// Uses of each literal and name in the program being compiled
+    constant_uses: ConstantUses,
+    /// How `Push` and `PushConst` operands are written
+    encoding: OperandEncoding,
+    /// Values pushed with `PushConst`, in pool order, with their indices
+    pool: Vec<i64>,
+    pool_indices: HashMap<i64, u16>,
+    /// Operand position to patch with the data offset of the constant pool,
+    /// if the program has one
+    pool_fixup: Option<usize>,
+    functions: HashMap<String, Function>,
+    /// Operand positions to patch with the entry point of a function
+    function_fixups: Vec<(usize, String)>,
+    /// Whether the program is being compiled as a relocatable object
+    object: bool,
+    /// Top-level functions defined by the program rather than the prelude
+    own_functions: HashSet<String>,
+    /// Function bodies from earlier compiles of a `Session`, reused when
+    /// unchanged
+    cache: Option<FunctionCache>,
+    /// Operands holding code and global addresses or host binding indices,
+    /// which depend on where a linker places the program
+    relocations: Vec<Relocation>,
+    /// Closure signatures inferred by the type checker, keyed by closure id
+    closure_types: HashMap<usize, Type>,
+    /// Address of the first global variable
+    global_base: usize,
+    /// Whether loops copy the captured variables they read into locals
+    /// before their first iteration, set by the optimization level
+    hoist_loop_loads: bool,
+    /// Source spans of the emitted code, when debug info is enabled
+    debug_info: Option<DebugInfo>,
+    /// Spans of the statements and expressions being compiled, innermost
+    /// last; `None` inside code that did not come from the program's source
+    spans: Vec<Option<Span>>,
 }
 
-impl Compiler {
+impl StackBackend {
     pub fn new() -> Self {
-        Compiler {
+        StackBackend {
             bytecode: Vec::new(),
-            variables: HashMap::new(),
+            scopes: vec![Scope {
+                variables: HashMap::new(),
+                start_slot: 0,
+            }],
             next_var_addr: 0,
+            globals: 0,
+            frames: Vec::new(),
+            structs: Vec::new(),
+            constants: HashMap::new(),
+            strings: Vec::new(),
+            string_fixups: Vec::new(),
+            constant_uses: ConstantUses::default(),
+            encoding: OperandEncoding::Fixed,
+            pool: Vec::new(),
+            pool_indices: HashMap::new(),
+            pool_fixup: None,
+            functions: HashMap::new(),
+            function_fixups: Vec::new(),
+            object: false,
+            own_functions: HashSet::new(),
+            cache: None,
+            relocations: Vec::new(),
+            closure_types: HashMap::new(),
+            global_base: 0,
+            hoist_loop_loads: true,
+            debug_info: None,
+            spans: Vec::new(),
+        }
+    }
+
+    /// Places global variables from address `base` on (0 unless set). It
+    /// must leave room for them below `FRAME_BASE`.
+    pub fn set_global_base(&mut self, base: usize) {
+        self.global_base = base;
+    }
+
+    /// Selects how inline operands are encoded (`OperandEncoding::Fixed`
+    /// unless set). LEB128 programs start with a header announcing it.
+    pub fn set_operand_encoding(&mut self, encoding: OperandEncoding) {
+        self.encoding = encoding;
+    }
+
+    /// Emits relocatable objects for a `Linker` rather than runnable
+    /// programs (off by default).
+    pub fn set_object(&mut self, enabled: bool) {
+        self.object = enabled;
+    }
+
+    /// Enables or disables building a `DebugInfo` table that maps the
+    /// emitted code back to source spans (off by default).
+    pub fn set_debug_info(&mut self, enabled: bool) {
+        self.debug_info = enabled.then(DebugInfo::default);
+    }
+
+    /// The source map of the last program emitted, if debug info is
+    /// enabled.
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// Attributes the code emitted from here on to `span`, until the
+    /// matching `exit_span`.
+    fn enter_span(&mut self, span: Option<Span>) {
+        let span = span.filter(|_| !matches!(self.spans.last(), Some(None)));
+        self.spans.push(span);
+        if let Some(debug_info) = &mut self.debug_info {
+            debug_info.mark(self.bytecode.len(), span);
+        }
+    }
+
+    /// Returns to attributing code to the enclosing span.
+    fn exit_span(&mut self) {
+        self.spans.pop();
+        if let (Some(debug_info), Some(&span)) = (&mut self.debug_info, self.spans.last()) {
+            debug_info.mark(self.bytecode.len(), span);
         }
     }
 
@@ -24,122 +373,2005 @@ impl Compiler {
         self.bytecode.push(opcode);
     }
 
+    /// Emits the operand of a `Push`.
     fn emit_i64(&mut self, value: i64) {
+        match self.encoding {
+            OperandEncoding::Fixed => self.emit_data(value),
+            OperandEncoding::Leb128 => bytecode::write_sleb128(&mut self.bytecode, value),
+        }
+    }
+
+    /// Records that the operand emitted next is a `kind` of value that
+    /// depends on where the program is placed.
+    fn relocate(&mut self, kind: RelocationKind) {
+        self.relocations.push(Relocation {
+            offset: self.bytecode.len(),
+            kind,
+        });
+    }
+
+    /// Emits a `Push` operand holding a variable's slot, or its address if
+    /// it is a global.
+    fn emit_slot(&mut self, slot: usize, global: bool) {
+        if global {
+            self.relocate(RelocationKind::Global);
+        }
+        self.emit_i64(slot as i64);
+    }
+
+    /// Emits a value of the data segment, which is always 8 bytes wide.
+    fn emit_data(&mut self, value: i64) {
         self.bytecode.extend_from_slice(&value.to_le_bytes());
     }
 
-    fn get_var_address(&mut self, name: &str) -> usize {
-        if let Some(&addr) = self.variables.get(name) {
-            addr
+    fn lookup(&self, name: &str) -> Option<Variable> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.variables.get(name).cloned())
+    }
+
+    /// Allocates a frame slot inside a function and a global address outside.
+    fn alloc_storage(&mut self) -> Storage {
+        match self.frames.last_mut() {
+            Some(frame) => {
+                frame.next_slot += 1;
+                frame.size = frame.size.max(frame.next_slot);
+                Storage::Local(frame.next_slot - 1)
+            }
+            None => {
+                self.next_var_addr += 1;
+                self.globals = self.globals.max(self.next_var_addr);
+                Storage::Global(self.next_var_addr - 1)
+            }
+        }
+    }
+
+    /// Declares a new variable in the innermost scope, shadowing any outer one.
+    fn declare(&mut self, name: &str, kind: ValueKind) -> Variable {
+        let variable = Variable {
+            storage: self.alloc_storage(),
+            kind,
+        };
+        if let (Storage::Global(addr), Some(debug_info), 1) =
+            (&variable.storage, &mut self.debug_info, self.scopes.len())
+        {
+            debug_info.declare_global(*addr, name);
+        }
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .variables
+            .insert(name.to_string(), variable.clone());
+        variable
+    }
+
+    /// Looks up a variable used at `span` for code generation. A local of an
+    /// enclosing function is captured by every closure between it and the use.
+    fn resolve(&mut self, name: &str, span: Span) -> Result<Variable, CompileError> {
+        let Some(depth) = self
+            .scopes
+            .iter()
+            .rposition(|scope| scope.variables.contains_key(name))
+        else {
+            return Err(CompileError::UndefinedVariable {
+                name: name.to_string(),
+                span,
+            });
+        };
+        let mut variable = self.scopes[depth].variables[name].clone();
+        // Top-level variables have fixed addresses and need no capturing
+        if depth == 0 {
+            return Ok(variable);
+        }
+        for frame in self.frames.iter_mut().filter(|f| f.scope_start > depth) {
+            let index = frame.captures.len();
+            frame.captures.push(variable.clone());
+            variable = Variable {
+                storage: Storage::Captured {
+                    env_slot: frame.env_slot,
+                    index,
+                },
+                kind: variable.kind,
+            };
+            self.scopes[frame.scope_start]
+                .variables
+                .insert(name.to_string(), variable.clone());
+        }
+        Ok(variable)
+    }
+
+    /// Resolves a variable about to be assigned; only `let` declares one.
+    fn resolve_for_store(&mut self, name: &str, span: Span) -> Result<Variable, CompileError> {
+        let variable = self.resolve(name, span)?;
+        if let Storage::Captured { .. } = variable.storage {
+            return Err(CompileError::AssignToCaptured {
+                name: name.to_string(),
+                span,
+            });
+        }
+        Ok(variable)
+    }
+
+    fn emit_load(&mut self, variable: &Variable) {
+        let (slot, opcode) = match variable.storage {
+            Storage::Global(addr) => (addr, Opcode::Load),
+            Storage::Local(slot) => (slot, Opcode::LoadLocal),
+            Storage::Captured { env_slot, .. } => (env_slot, Opcode::LoadLocal),
+        };
+        self.emit(Opcode::Push as u8);
+        self.emit_slot(slot, matches!(variable.storage, Storage::Global(_)));
+        self.emit(opcode as u8);
+        if let Storage::Captured { index, .. } = variable.storage {
+            // Skip the environment's length and code address
+            self.emit(Opcode::Push as u8);
+            self.emit_i64(index as i64 + 2);
+            self.emit(Opcode::Add as u8);
+            self.emit(Opcode::Load as u8);
+        }
+    }
+
+    /// Stores the value on top of the stack into a global or local variable.
+    fn emit_store(&mut self, variable: &Variable) {
+        let (slot, opcode) = match variable.storage {
+            Storage::Global(addr) => (addr, Opcode::Store),
+            Storage::Local(slot) => (slot, Opcode::StoreLocal),
+            Storage::Captured { .. } => unreachable!("captured variables are read-only"),
+        };
+        self.emit(Opcode::Push as u8);
+        self.emit_slot(slot, opcode == Opcode::Store);
+        self.emit(opcode as u8);
+    }
+
+    fn set_var_kind(&mut self, name: &str, kind: ValueKind) {
+        for scope in self.scopes.iter_mut().rev() {
+            if let Some(variable) = scope.variables.get_mut(name) {
+                variable.kind = kind;
+                return;
+            }
+        }
+    }
+
+    fn enter_scope(&mut self) {
+        let start_slot = match self.frames.last() {
+            Some(frame) => frame.next_slot,
+            None => self.next_var_addr,
+        };
+        self.scopes.push(Scope {
+            variables: HashMap::new(),
+            start_slot,
+        });
+    }
+
+    fn exit_scope(&mut self) {
+        let scope = self.scopes.pop().unwrap();
+        match self.frames.last_mut() {
+            Some(frame) => frame.next_slot = scope.start_slot,
+            None => self.next_var_addr = scope.start_slot,
+        }
+    }
+
+    fn intern_string(&mut self, value: &str) -> usize {
+        if let Some(index) = self.strings.iter().position(|s| s == value) {
+            index
+        } else {
+            self.strings.push(value.to_string());
+            self.strings.len() - 1
+        }
+    }
+
+    fn kind_from_type(&self, ty: &Type) -> ValueKind {
+        match ty {
+            Type::Float => ValueKind::Float,
+            Type::Str => ValueKind::Str,
+            Type::Array => ValueKind::Array,
+            Type::Map => ValueKind::Map,
+            Type::Struct(name) => self
+                .struct_index(name)
+                .map_or(ValueKind::Int, ValueKind::Struct),
+            Type::Function(_, result) => ValueKind::Function(Box::new(self.kind_from_type(result))),
+            Type::Int | Type::Bool | Type::Unknown => ValueKind::Int,
+        }
+    }
+
+    fn function_kind(&self, name: &str) -> Option<ValueKind> {
+        let function = self.functions.get(name)?;
+        Some(ValueKind::Function(Box::new(
+            self.kind_from_type(&function.result),
+        )))
+    }
+
+    fn kind_of(&self, expr: &Expr) -> ValueKind {
+        match &expr.kind {
+            ExprKind::Float(_) => ValueKind::Float,
+            ExprKind::Str(_) => ValueKind::Str,
+            ExprKind::Array(_) | ExprKind::ArrayRepeat(..) => ValueKind::Array,
+            ExprKind::Map(_) => ValueKind::Map,
+            ExprKind::Variable(name) => match self.lookup(name) {
+                Some(variable) => variable.kind,
+                None => self.function_kind(name).unwrap_or(ValueKind::Int),
+            },
+            ExprKind::UnaryOp(UnaryOpKind::Neg, operand) => self.kind_of(operand),
+            ExprKind::BinaryOp(
+                left,
+                BinaryOpKind::Add
+                | BinaryOpKind::Sub
+                | BinaryOpKind::Mul
+                | BinaryOpKind::Div
+                | BinaryOpKind::Mod,
+                _,
+            ) => self.kind_of(left),
+            ExprKind::Call(name, _) => {
+                let callee = match self.lookup(name) {
+                    Some(variable) => Some(variable.kind),
+                    None => self.function_kind(name),
+                };
+                match callee {
+                    Some(ValueKind::Function(result)) => *result,
+                    Some(_) => ValueKind::Int,
+                    None if name == "float" => ValueKind::Float,
+                    None => ValueKind::Int,
+                }
+            }
+            ExprKind::Conditional(_, then_expr, _) => self.kind_of(then_expr),
+            ExprKind::Closure(id, ..) => self
+                .closure_types
+                .get(id)
+                .map_or(ValueKind::Int, |ty| self.kind_from_type(ty)),
+            ExprKind::StructLiteral(name, _) => self
+                .struct_index(name)
+                .map_or(ValueKind::Int, ValueKind::Struct),
+            _ => ValueKind::Int,
+        }
+    }
+
+    fn is_string(&self, expr: &Expr) -> bool {
+        self.kind_of(expr) == ValueKind::Str
+    }
+
+    fn check_not_constant(&self, name: &str, span: Span) -> Result<(), CompileError> {
+        if self.constants.contains_key(name) {
+            Err(CompileError::AssignToConstant {
+                name: name.to_string(),
+                span,
+            })
         } else {
-            let addr = self.next_var_addr;
-            self.variables.insert(name.to_string(), addr);
-            self.next_var_addr += 1;
-            addr
+            Ok(())
+        }
+    }
+
+    fn struct_index(&self, name: &str) -> Option<usize> {
+        self.structs
+            .iter()
+            .position(|(struct_name, _)| struct_name == name)
+    }
+
+    /// Records are laid out like arrays: a field-count header at the base
+    /// address followed by one cell per field in declaration order.
+    fn field_offset(&self, target: &Expr, field: &str, span: Span) -> Result<usize, CompileError> {
+        let ValueKind::Struct(index) = self.kind_of(target) else {
+            return Err(CompileError::NotAStruct {
+                field: field.to_string(),
+                span,
+            });
+        };
+        let (name, fields) = &self.structs[index];
+        fields
+            .iter()
+            .position(|f| f == field)
+            .map(|position| position + 1)
+            .ok_or_else(|| CompileError::NoField {
+                name: name.clone(),
+                field: field.to_string(),
+                span,
+            })
+    }
+
+    /// Compiles a sequence (string or array) or a map and an index or key,
+    /// leaving both on the stack.
+    fn compile_indexed(&mut self, target: &Expr, index: &Expr) -> Result<(), CompileError> {
+        if self.kind_of(target) == ValueKind::Int {
+            return Err(CompileError::NotIndexable { span: target.span });
         }
+        if self.kind_of(index) != ValueKind::Int {
+            let what = if self.kind_of(target) == ValueKind::Map {
+                "Map key"
+            } else {
+                "Index"
+            };
+            return Err(CompileError::ExpectedInteger {
+                what,
+                span: index.span,
+            });
+        }
+        self.compile_expr(target)?;
+        self.compile_expr(index)
+    }
+
+    fn compile_expr(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        self.enter_span(Some(expr.span));
+        let result = self.compile_expr_kind(expr);
+        self.exit_span();
+        result
     }
 
-    fn compile_expr(&mut self, expr: &Expr) {
-        match expr {
-            Expr::Number(n) => {
+    fn compile_expr_kind(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        match &expr.kind {
+            ExprKind::Number(n) => {
+                let uses = self.constant_uses.of_value(*n);
+                self.emit_constant(*n, uses);
+            }
+            ExprKind::Variable(name) if self.constants.contains_key(name) => {
+                let value = self.constants[name];
+                let uses = self
+                    .constant_uses
+                    .of_name(name)
+                    .max(self.constant_uses.of_value(value));
+                self.emit_constant(value, uses);
+            }
+            ExprKind::Variable(name)
+                if self.lookup(name).is_none() && self.functions.contains_key(name) =>
+            {
+                if self.functions[name].host.is_some() {
+                    return Err(CompileError::HostFunctionValue {
+                        name: name.clone(),
+                        span: expr.span,
+                    });
+                }
+                // A function value is a closure without captures
+                self.emit_function_address(name, expr.span)?;
+                self.emit(Opcode::Push as u8);
+                self.emit_i64(1);
+                self.emit(Opcode::NewArray as u8);
+            }
+            ExprKind::Variable(name) => {
+                let variable = self.resolve(name, expr.span)?;
+                self.emit_load(&variable);
+            }
+            ExprKind::Bool(value) => {
                 self.emit(Opcode::Push as u8);
-                self.emit_i64(*n);
+                self.emit_i64(*value as i64);
             }
-            Expr::Variable(name) => {
-                let addr = self.get_var_address(name);
+            ExprKind::Str(value) => {
+                let index = self.intern_string(value);
                 self.emit(Opcode::Push as u8);
-                self.emit_i64(addr as i64);
-                self.emit(Opcode::Load as u8);
+                let operand_pos = self.emit_placeholder();
+                self.string_fixups.push((operand_pos, index));
+                self.emit(Opcode::LoadStr as u8);
+            }
+            ExprKind::Float(value) => {
+                let bits = value.to_bits() as i64;
+                let uses = self.constant_uses.of_value(bits);
+                self.emit_constant(bits, uses);
+            }
+            ExprKind::UnaryOp(UnaryOpKind::Neg, operand) => {
+                let opcode = match self.kind_of(operand) {
+                    ValueKind::Int => Opcode::Neg,
+                    ValueKind::Float => Opcode::FNeg,
+                    _ => return Err(CompileError::CannotNegate { span: expr.span }),
+                };
+                self.compile_expr(operand)?;
+                self.emit(opcode as u8);
+            }
+            ExprKind::UnaryOp(UnaryOpKind::Not, operand) => {
+                self.compile_expr(operand)?;
+                self.emit(Opcode::Push as u8);
+                self.emit_i64(0);
+                self.emit(Opcode::Equal as u8);
             }
-            Expr::BinaryOp(left, op, right) => {
-                self.compile_expr(left);
-                self.compile_expr(right);
+            ExprKind::BinaryOp(left, BinaryOpKind::And, right) => {
+                // Short-circuit: `right` only runs when `left` is truthy
+                let false_jump = self.emit_jump_if_false(left)?;
+                self.compile_truthiness(right)?;
+                let end_jump = self.emit_jump(Opcode::Jump);
+                let false_pos = self.bytecode.len();
+                self.patch_operand(false_jump, false_pos);
+                self.emit(Opcode::Push as u8);
+                self.emit_i64(0);
+                let end_pos = self.bytecode.len();
+                self.patch_operand(end_jump, end_pos);
+            }
+            ExprKind::BinaryOp(left, BinaryOpKind::Or, right) => {
+                // Short-circuit: `right` only runs when `left` is falsy
+                self.compile_truthiness(left)?;
+                let true_jump = self.emit_jump(Opcode::JumpIf);
+                self.compile_truthiness(right)?;
+                let end_jump = self.emit_jump(Opcode::Jump);
+                let true_pos = self.bytecode.len();
+                self.patch_operand(true_jump, true_pos);
+                self.emit(Opcode::Push as u8);
+                self.emit_i64(1);
+                let end_pos = self.bytecode.len();
+                self.patch_operand(end_jump, end_pos);
+            }
+            ExprKind::BinaryOp(left, op, right) => {
+                let kind = self.check_operands(left, op, right)?;
+                self.compile_expr(left)?;
+                self.compile_expr(right)?;
+                if kind == ValueKind::Float {
+                    self.emit(Self::float_opcode(op) as u8);
+                    return Ok(());
+                }
                 match op {
+                    BinaryOpKind::Add if kind == ValueKind::Str => self.emit(Opcode::Concat as u8),
                     BinaryOpKind::Add => self.emit(Opcode::Add as u8),
                     BinaryOpKind::Sub => self.emit(Opcode::Sub as u8),
                     BinaryOpKind::Mul => self.emit(Opcode::Mul as u8),
                     BinaryOpKind::Div => self.emit(Opcode::Div as u8),
+                    BinaryOpKind::Mod => self.emit(Opcode::Mod as u8),
+                    BinaryOpKind::ShiftLeft => self.emit(Opcode::Shl as u8),
+                    BinaryOpKind::ShiftRight => self.emit(Opcode::Shr as u8),
                     BinaryOpKind::Equals => self.emit(Opcode::Equal as u8),
+                    BinaryOpKind::NotEquals => self.emit(Opcode::NotEqual as u8),
                     BinaryOpKind::LessThan => self.emit(Opcode::Less as u8),
                     BinaryOpKind::LessEqual => self.emit(Opcode::LessEqual as u8),
                     BinaryOpKind::GreaterEqual => self.emit(Opcode::GreaterEqual as u8),
-                    BinaryOpKind::GreaterThan => {
-                        // a > b is equivalent to b < a
-                        let temp = self.bytecode.len() - 16; // Assuming each push takes 8 bytes
-                        self.bytecode.swap(temp, temp + 8); // Swap the order of operands
-                        self.emit(Opcode::Less as u8);
+                    BinaryOpKind::GreaterThan => self.emit(Opcode::Greater as u8),
+                    BinaryOpKind::And | BinaryOpKind::Or => unreachable!(),
+                }
+            }
+            ExprKind::Array(elements) => {
+                for element in elements {
+                    if self.kind_of(element) != ValueKind::Int {
+                        return Err(CompileError::ExpectedInteger {
+                            what: "Array element",
+                            span: element.span,
+                        });
                     }
+                    self.compile_expr(element)?;
                 }
+                self.emit(Opcode::Push as u8);
+                self.emit_i64(elements.len() as i64);
+                self.emit(Opcode::NewArray as u8);
+            }
+            ExprKind::ArrayRepeat(value, count) => {
+                if self.kind_of(value) != ValueKind::Int {
+                    return Err(CompileError::ExpectedInteger {
+                        what: "Array element",
+                        span: value.span,
+                    });
+                }
+                let span = count.span;
+                let count = const_eval(count, &self.constants)
+                    .map_err(|error| CompileError::ConstEval { error, span })?;
+                if !(0..=MAX_ARRAY_REPEAT).contains(&count) {
+                    return Err(CompileError::ArraySize {
+                        size: count,
+                        max: MAX_ARRAY_REPEAT,
+                        span,
+                    });
+                }
+                // The value is computed once and copied into every element
+                self.compile_expr(value)?;
+                if count == 0 {
+                    self.emit(Opcode::Pop as u8);
+                }
+                for _ in 1..count {
+                    self.emit(Opcode::Dup as u8);
+                }
+                self.emit(Opcode::Push as u8);
+                self.emit_i64(count);
+                self.emit(Opcode::NewArray as u8);
             }
+            ExprKind::Map(entries) => {
+                self.emit(Opcode::NewMap as u8);
+                for (key, value) in entries {
+                    for (what, entry) in [("Map key", key), ("Map value", value)] {
+                        if self.kind_of(entry) != ValueKind::Int {
+                            return Err(CompileError::ExpectedInteger {
+                                what,
+                                span: entry.span,
+                            });
+                        }
+                    }
+                    self.emit(Opcode::Dup as u8);
+                    self.compile_expr(key)?;
+                    self.compile_expr(value)?;
+                    self.emit(Opcode::MapSet as u8);
+                }
+            }
+            ExprKind::Index(target, index) => {
+                let opcode = if self.kind_of(target) == ValueKind::Map {
+                    Opcode::MapGet
+                } else {
+                    Opcode::Index
+                };
+                self.compile_indexed(target, index)?;
+                self.emit(opcode as u8);
+            }
+            ExprKind::StructLiteral(name, initializers) => {
+                let index = self
+                    .struct_index(name)
+                    .ok_or_else(|| CompileError::UnknownStruct {
+                        name: name.clone(),
+                        span: expr.span,
+                    })?;
+                let fields = self.structs[index].1.clone();
+                for (field, value) in initializers {
+                    if !fields.contains(field) {
+                        return Err(CompileError::NoField {
+                            name: name.clone(),
+                            field: field.clone(),
+                            span: value.span,
+                        });
+                    }
+                }
+                for field in &fields {
+                    let mut matching = initializers.iter().filter(|(f, _)| f == field);
+                    let value = match (matching.next(), matching.next()) {
+                        (Some((_, value)), None) => value,
+                        (None, _) => {
+                            return Err(CompileError::MissingField {
+                                name: name.clone(),
+                                field: field.clone(),
+                                span: expr.span,
+                            })
+                        }
+                        (Some(_), Some((_, duplicate))) => {
+                            return Err(CompileError::DuplicateField {
+                                name: name.clone(),
+                                field: field.clone(),
+                                span: duplicate.span,
+                            })
+                        }
+                    };
+                    if self.kind_of(value) != ValueKind::Int {
+                        return Err(CompileError::ExpectedInteger {
+                            what: "Struct field",
+                            span: value.span,
+                        });
+                    }
+                    self.compile_expr(value)?;
+                }
+                self.emit(Opcode::Push as u8);
+                self.emit_i64(fields.len() as i64);
+                self.emit(Opcode::NewArray as u8);
+            }
+            ExprKind::Conditional(condition, then_expr, else_expr) => {
+                if self.kind_of(then_expr) != self.kind_of(else_expr) {
+                    return Err(CompileError::ConditionalKinds { span: expr.span });
+                }
+                let else_jump = self.emit_jump_if_false(condition)?;
+                self.compile_expr(then_expr)?;
+                let end_jump = self.emit_jump(Opcode::Jump);
+                let else_pos = self.bytecode.len();
+                self.patch_operand(else_jump, else_pos);
+                self.compile_expr(else_expr)?;
+                let end_pos = self.bytecode.len();
+                self.patch_operand(end_jump, end_pos);
+            }
+            ExprKind::Field(target, field) => {
+                let offset = self.field_offset(target, field, expr.span)?;
+                self.compile_expr(target)?;
+                self.emit(Opcode::Push as u8);
+                self.emit_i64(offset as i64);
+                self.emit(Opcode::Add as u8);
+                self.emit(Opcode::Load as u8);
+            }
+            ExprKind::Call(name, args) => self.compile_call(name, args, expr.span)?,
+            ExprKind::Closure(_, params, _, body) => {
+                // The closure record doubles as the environment of the body
+                let (entry, captures) = self.compile_function_body(params, body)?;
+                if let Some(debug_info) = &mut self.debug_info {
+                    debug_info.declare_function(entry, format!("closure@{}", expr.span));
+                }
+                self.emit(Opcode::Push as u8);
+                self.relocate(RelocationKind::Code);
+                self.emit_i64(entry as i64);
+                for variable in &captures {
+                    self.emit_load(variable);
+                }
+                self.emit(Opcode::Push as u8);
+                self.emit_i64(captures.len() as i64 + 1);
+                self.emit(Opcode::NewArray as u8);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that strings only appear where a string operation exists and
+    /// returns whether the operation is a string concatenation.
+    fn check_operands(
+        &self,
+        left: &Expr,
+        op: &BinaryOpKind,
+        right: &Expr,
+    ) -> Result<ValueKind, CompileError> {
+        let span = left.span.to(right.span);
+        match (self.kind_of(left), self.kind_of(right)) {
+            (ValueKind::Int, ValueKind::Int) => Ok(ValueKind::Int),
+            (ValueKind::Float, ValueKind::Float)
+                if matches!(op, BinaryOpKind::ShiftLeft | BinaryOpKind::ShiftRight) =>
+            {
+                Err(CompileError::UnsupportedOperator {
+                    op: op.clone(),
+                    operands: "floats",
+                    span,
+                })
+            }
+            (ValueKind::Float, ValueKind::Float) => Ok(ValueKind::Float),
+            (ValueKind::Str, ValueKind::Str) if matches!(op, BinaryOpKind::Add) => {
+                Ok(ValueKind::Str)
+            }
+            (ValueKind::Str, ValueKind::Str) => Err(CompileError::UnsupportedOperator {
+                op: op.clone(),
+                operands: "strings",
+                span,
+            }),
+            (left, right) => Err(CompileError::MixedOperands {
+                op: op.clone(),
+                left: format!("{:?}", left),
+                right: format!("{:?}", right),
+                span,
+            }),
+        }
+    }
+
+    fn float_opcode(op: &BinaryOpKind) -> Opcode {
+        match op {
+            BinaryOpKind::Add => Opcode::FAdd,
+            BinaryOpKind::Sub => Opcode::FSub,
+            BinaryOpKind::Mul => Opcode::FMul,
+            BinaryOpKind::Div => Opcode::FDiv,
+            BinaryOpKind::Mod => Opcode::FMod,
+            BinaryOpKind::Equals => Opcode::FEqual,
+            BinaryOpKind::NotEquals => Opcode::FNotEqual,
+            BinaryOpKind::LessThan => Opcode::FLess,
+            BinaryOpKind::LessEqual => Opcode::FLessEqual,
+            BinaryOpKind::GreaterThan => Opcode::FGreater,
+            BinaryOpKind::GreaterEqual => Opcode::FGreaterEqual,
+            BinaryOpKind::ShiftLeft
+            | BinaryOpKind::ShiftRight
+            | BinaryOpKind::And
+            | BinaryOpKind::Or => unreachable!(),
         }
     }
 
-    pub fn compile(&mut self, statements: Vec<Statement>) -> Vec<u8> {
-        for statement in statements {
-            match statement {
-                Statement::Let(name, expr) | Statement::Assign(name, expr) => {
-                    let addr = self.get_var_address(&name);
-                    self.compile_expr(&expr);
+    /// Pushes a value written in the source. Values used more than once are
+    /// pushed by their index in the constant pool, which takes 3 bytes
+    /// instead of 9 for every use after the first.
+    fn emit_constant(&mut self, value: i64, uses: usize) {
+        if uses > 1 && self.pool_fixup.is_some() {
+            let next = self.pool.len();
+            let index = match self.pool_indices.get(&value) {
+                Some(&index) => Some(index),
+                None => u16::try_from(next).ok().inspect(|&index| {
+                    self.pool.push(value);
+                    self.pool_indices.insert(value, index);
+                }),
+            };
+            // Values past the pool's last index are inlined
+            if let Some(index) = index {
+                self.emit(Opcode::PushConst as u8);
+                match self.encoding {
+                    OperandEncoding::Fixed => self.bytecode.extend_from_slice(&index.to_le_bytes()),
+                    OperandEncoding::Leb128 => {
+                        bytecode::write_uleb128(&mut self.bytecode, index as u64)
+                    }
+                }
+                return;
+            }
+        }
+        self.emit(Opcode::Push as u8);
+        self.emit_i64(value);
+    }
+
+    /// Pushes the entry point of a top-level function, used at `span`.
+    /// Only objects may refer to an imported one, for the linker to fill in.
+    fn emit_function_address(&mut self, name: &str, span: Span) -> Result<(), CompileError> {
+        if self.functions[name].imported && !self.object {
+            return Err(CompileError::ImportedFunction {
+                name: name.to_string(),
+                span,
+            });
+        }
+        self.emit(Opcode::Push as u8);
+        let operand_pos = self.emit_placeholder();
+        self.function_fixups.push((operand_pos, name.to_string()));
+        Ok(())
+    }
+
+    /// Compiles a call through a variable holding a function value, to a
+    /// top-level function, or to a built-in, in that order of precedence.
+    /// Arguments are pushed left to right, then the environment pointer.
+    fn compile_call(&mut self, name: &str, args: &[Expr], span: Span) -> Result<(), CompileError> {
+        if let Some(variable) = self.lookup(name) {
+            if !matches!(variable.kind, ValueKind::Function(_) | ValueKind::Int) {
+                return Err(CompileError::NotCallable {
+                    name: name.to_string(),
+                    span,
+                });
+            }
+            for arg in args {
+                self.compile_expr(arg)?;
+            }
+            let variable = self.resolve(name, span)?;
+            self.emit_load(&variable);
+            self.emit(Opcode::CallClosure as u8);
+        } else if let Some(function) = self.functions.get(name) {
+            if args.len() != function.arity {
+                return Err(CompileError::ArgumentCount {
+                    name: name.to_string(),
+                    expected: function.arity,
+                    found: args.len(),
+                    span,
+                });
+            }
+            for arg in args {
+                self.compile_expr(arg)?;
+            }
+            if let Some(binding) = self.functions[name].host {
+                self.emit(Opcode::Push as u8);
+                self.relocate(RelocationKind::HostBinding);
+                self.emit_i64(binding as i64);
+                self.emit(Opcode::CallHost as u8);
+                return Ok(());
+            }
+            // Top-level functions have no environment
+            self.emit(Opcode::Push as u8);
+            self.emit_i64(0);
+            self.emit_function_address(name, span)?;
+            self.emit(Opcode::Call as u8);
+        } else {
+            self.compile_builtin(name, args, span)?;
+        }
+        Ok(())
+    }
+
+    fn compile_builtin(
+        &mut self,
+        name: &str,
+        args: &[Expr],
+        span: Span,
+    ) -> Result<(), CompileError> {
+        let arity = match name {
+            "read" => 0,
+            "len" | "abs" | "sqrt_int" | "float" | "int" | "printChar" | "recv" => 1,
+            "charAt" | "min" | "max" | "pow" | "has" | "send" => 2,
+            _ => {
+                return Err(CompileError::UnknownFunction {
+                    name: name.to_string(),
+                    span,
+                })
+            }
+        };
+        if args.len() != arity {
+            return Err(CompileError::ArgumentCount {
+                name: name.to_string(),
+                expected: arity,
+                found: args.len(),
+                span,
+            });
+        }
+        let argument_kind = |expected, arg: &Expr| CompileError::ArgumentKind {
+            name: name.to_string(),
+            expected,
+            span: arg.span,
+        };
+
+        match name {
+            "read" => self.emit(Opcode::Read as u8),
+            "len" => {
+                if self.kind_of(&args[0]) == ValueKind::Int {
+                    return Err(argument_kind("a string or array argument", &args[0]));
+                }
+                // The length prefix lives at the sequence's base address
+                self.compile_expr(&args[0])?;
+                self.emit(Opcode::Load as u8);
+            }
+            "has" => {
+                if self.kind_of(&args[0]) != ValueKind::Map {
+                    return Err(argument_kind("a map argument", &args[0]));
+                }
+                if self.kind_of(&args[1]) != ValueKind::Int {
+                    return Err(CompileError::ExpectedInteger {
+                        what: "Map key",
+                        span: args[1].span,
+                    });
+                }
+                self.compile_expr(&args[0])?;
+                self.compile_expr(&args[1])?;
+                self.emit(Opcode::MapHas as u8);
+            }
+            "charAt" => {
+                if !self.is_string(&args[0]) {
+                    return Err(argument_kind("a string argument", &args[0]));
+                }
+                self.compile_indexed(&args[0], &args[1])?;
+                self.emit(Opcode::Index as u8);
+            }
+            "printChar" => {
+                if self.kind_of(&args[0]) != ValueKind::Int {
+                    return Err(argument_kind("an integer argument", &args[0]));
+                }
+                self.compile_expr(&args[0])?;
+                self.emit(Opcode::PrintChar as u8);
+                // Like any call, printChar has a value; it is always 0
+                self.emit(Opcode::Push as u8);
+                self.emit_i64(0);
+            }
+            "send" | "recv" => {
+                for arg in args {
+                    if self.kind_of(arg) != ValueKind::Int {
+                        return Err(argument_kind("integer arguments", arg));
+                    }
+                    self.compile_expr(arg)?;
+                }
+                if name == "send" {
+                    self.emit(Opcode::Send as u8);
+                    // Like printChar, send has the value 0
                     self.emit(Opcode::Push as u8);
-                    self.emit_i64(addr as i64);
-                    self.emit(Opcode::Store as u8);
+                    self.emit_i64(0);
+                } else {
+                    self.emit(Opcode::Recv as u8);
+                }
+            }
+            "float" | "int" => {
+                let (expected, description, opcode) = if name == "float" {
+                    (ValueKind::Int, "an integer argument", Opcode::IntToFloat)
+                } else {
+                    (ValueKind::Float, "a float argument", Opcode::FloatToInt)
+                };
+                if self.kind_of(&args[0]) != expected {
+                    return Err(argument_kind(description, &args[0]));
                 }
-                Statement::If(condition, then_block, else_block) => {
-                    self.compile_expr(&condition);
+                self.compile_expr(&args[0])?;
+                self.emit(opcode as u8);
+            }
+            "abs" | "sqrt_int" | "min" | "max" | "pow" => {
+                for arg in args {
+                    if self.kind_of(arg) != ValueKind::Int {
+                        return Err(argument_kind("integer arguments", arg));
+                    }
+                    self.compile_expr(arg)?;
+                }
+                let opcode = match name {
+                    "abs" => Opcode::Abs,
+                    "sqrt_int" => Opcode::SqrtInt,
+                    "min" => Opcode::Min,
+                    "max" => Opcode::Max,
+                    _ => Opcode::Pow,
+                };
+                self.emit(opcode as u8);
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Emits a `Push <placeholder>` followed by `opcode` and returns the
+    /// position of the placeholder so it can be patched with `patch_operand`.
+    fn emit_jump(&mut self, opcode: Opcode) -> usize {
+        self.emit(Opcode::Push as u8);
+        self.relocate(RelocationKind::Code);
+        let operand_pos = self.emit_placeholder();
+        self.emit(opcode as u8);
+        operand_pos
+    }
+
+    /// Reserves room for a `Push` operand whose value is only known later,
+    /// returning its position for `patch_operand`.
+    fn emit_placeholder(&mut self) -> usize {
+        let operand_pos = self.bytecode.len();
+        let len = match self.encoding {
+            OperandEncoding::Fixed => 8,
+            OperandEncoding::Leb128 => bytecode::PATCHABLE_LEB128_LEN,
+        };
+        self.bytecode.resize(operand_pos + len, 0);
+        operand_pos
+    }
+
+    fn patch_operand(&mut self, operand_pos: usize, target: usize) {
+        match self.encoding {
+            OperandEncoding::Fixed => self.bytecode[operand_pos..operand_pos + 8]
+                .copy_from_slice(&(target as i64).to_le_bytes()),
+            OperandEncoding::Leb128 => bytecode::patch_sleb128(
+                &mut self.bytecode[operand_pos..operand_pos + bytecode::PATCHABLE_LEB128_LEN],
+                target as i64,
+            ),
+        }
+    }
 
-                    // Placeholder for jump addresses
-                    let jump_if_pos = self.bytecode.len();
-                    self.emit(Opcode::JumpIf as u8);
-                    self.emit_i64(0); // Placeholder for else block
+    /// Compiles an expression and normalizes it to a boolean 0 or 1, the same
+    /// way `if`/`while` interpret conditions (any non-zero value is true).
+    fn compile_truthiness(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        self.compile_expr(expr)?;
+        self.emit(Opcode::Push as u8);
+        self.emit_i64(0);
+        self.emit(Opcode::NotEqual as u8);
+        Ok(())
+    }
 
-                    self.compile(then_block);
+    /// Compiles a condition and jumps to a placeholder target when it is false.
+    fn emit_jump_if_false(&mut self, condition: &Expr) -> Result<usize, CompileError> {
+        self.compile_expr(condition)?;
+        self.emit(Opcode::Push as u8);
+        self.emit_i64(0);
+        self.emit(Opcode::Equal as u8);
+        Ok(self.emit_jump(Opcode::JumpIf))
+    }
 
-                    let jump_end_pos = self.bytecode.len();
-                    self.emit(Opcode::Jump as u8);
-                    self.emit_i64(0); // Placeholder for end
+    fn compile_statement(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        self.enter_span(Some(statement.span));
+        let result = self.compile_statement_kind(statement);
+        self.exit_span();
+        result
+    }
+
+    fn compile_statement_kind(&mut self, statement: &Statement) -> Result<(), CompileError> {
+        match &statement.kind {
+            StatementKind::Const(name, expr) => {
+                if self.constants.contains_key(name) || self.lookup(name).is_some() {
+                    return Err(CompileError::AlreadyDefined {
+                        name: name.clone(),
+                        span: statement.span,
+                    });
+                }
+                let value =
+                    const_eval(expr, &self.constants).map_err(|error| CompileError::ConstEval {
+                        error,
+                        span: expr.span,
+                    })?;
+                self.constants.insert(name.clone(), value);
+            }
+            StatementKind::Let(name, _, expr) => {
+                self.check_not_constant(name, statement.span)?;
+                let kind = self.kind_of(expr);
+                // The initializer still sees any binding this one shadows
+                self.compile_expr(expr)?;
+                let variable = self.declare(name, kind);
+                self.emit_store(&variable);
+            }
+            StatementKind::Assign(name, expr) => {
+                self.check_not_constant(name, statement.span)?;
+                let kind = self.kind_of(expr);
+                let variable = self.resolve_for_store(name, statement.span)?;
+                self.set_var_kind(name, kind);
+                self.compile_expr(expr)?;
+                self.emit_store(&variable);
+            }
+            StatementKind::LetTuple(names, values) => {
+                for name in names {
+                    self.check_not_constant(name, statement.span)?;
+                }
+                let kinds: Vec<ValueKind> =
+                    values.iter().map(|value| self.kind_of(value)).collect();
+                for value in values {
+                    self.compile_expr(value)?;
+                }
+                let variables: Vec<Variable> = names
+                    .iter()
+                    .zip(kinds)
+                    .map(|(name, kind)| self.declare(name, kind))
+                    .collect();
+                // The last value is on top of the stack
+                for variable in variables.iter().rev() {
+                    self.emit_store(variable);
+                }
+            }
+            StatementKind::AssignTuple(names, values) => {
+                let mut variables = Vec::with_capacity(names.len());
+                for name in names {
+                    self.check_not_constant(name, statement.span)?;
+                    variables.push(self.resolve_for_store(name, statement.span)?);
+                }
+                let kinds: Vec<ValueKind> =
+                    values.iter().map(|value| self.kind_of(value)).collect();
+                for value in values {
+                    self.compile_expr(value)?;
+                }
+                for (name, kind) in names.iter().zip(kinds) {
+                    self.set_var_kind(name, kind);
+                }
+                for variable in variables.iter().rev() {
+                    self.emit_store(variable);
+                }
+            }
+            StatementKind::IndexAssign(name, index, value) => {
+                self.check_not_constant(name, statement.span)?;
+                let opcode = match self.resolve(name, statement.span)?.kind {
+                    ValueKind::Array => Opcode::SetIndex,
+                    ValueKind::Map => Opcode::MapSet,
+                    _ => {
+                        return Err(CompileError::IndexAssignTarget {
+                            name: name.clone(),
+                            span: statement.span,
+                        })
+                    }
+                };
+                if self.kind_of(value) != ValueKind::Int {
+                    return Err(CompileError::ExpectedInteger {
+                        what: match opcode {
+                            Opcode::MapSet => "Map value",
+                            _ => "Array element",
+                        },
+                        span: value.span,
+                    });
+                }
+                self.compile_indexed(
+                    &Expr::new(ExprKind::Variable(name.clone()), statement.span),
+                    index,
+                )?;
+                self.compile_expr(value)?;
+                self.emit(opcode as u8);
+            }
+            StatementKind::Struct(name, fields) => {
+                if self.struct_index(name).is_some() {
+                    return Err(CompileError::AlreadyDefined {
+                        name: name.clone(),
+                        span: statement.span,
+                    });
+                }
+                for (i, field) in fields.iter().enumerate() {
+                    if fields[..i].contains(field) {
+                        return Err(CompileError::DuplicateField {
+                            name: name.clone(),
+                            field: field.clone(),
+                            span: statement.span,
+                        });
+                    }
+                }
+                self.structs.push((name.clone(), fields.clone()));
+            }
+            StatementKind::Increment(name) => {
+                self.compile_step(name, Opcode::Inc, statement.span)?
+            }
+            StatementKind::Decrement(name) => {
+                self.compile_step(name, Opcode::Dec, statement.span)?
+            }
+            StatementKind::Assert(expr) => {
+                if self.kind_of(expr) != ValueKind::Int {
+                    return Err(CompileError::ExpectedInteger {
+                        what: "Assertion condition",
+                        span: expr.span,
+                    });
+                }
+                self.compile_expr(expr)?;
+                self.emit(Opcode::Assert as u8);
+            }
+            StatementKind::Exit(code) => {
+                if self.kind_of(code) != ValueKind::Int {
+                    return Err(CompileError::ExpectedInteger {
+                        what: "Exit code",
+                        span: code.span,
+                    });
+                }
+                self.compile_expr(code)?;
+                self.emit(Opcode::Exit as u8);
+            }
+            StatementKind::Throw(value) => {
+                if self.kind_of(value) != ValueKind::Int {
+                    return Err(CompileError::ExpectedInteger {
+                        what: "Thrown value",
+                        span: value.span,
+                    });
+                }
+                self.compile_expr(value)?;
+                self.emit(Opcode::Throw as u8);
+            }
+            StatementKind::Try(body, name, handler) => {
+                let handler_jump = self.emit_jump(Opcode::PushHandler);
+                self.compile_block(body)?;
+                self.emit(Opcode::PopHandler as u8);
+                let end_jump = self.emit_jump(Opcode::Jump);
 
+                // The VM jumps here with the thrown value on the stack
+                self.patch_operand(handler_jump, self.bytecode.len());
+                self.enter_scope();
+                let variable = self.declare(name, ValueKind::Int);
+                self.emit_store(&variable);
+                let result = handler
+                    .iter()
+                    .try_for_each(|statement| self.compile_statement(statement));
+                self.exit_scope();
+                result?;
+                self.patch_operand(end_jump, self.bytecode.len());
+            }
+            StatementKind::Match(scrutinee, arms) => {
+                self.compile_match(scrutinee, arms, statement.span)?
+            }
+            StatementKind::Function(name, params, _, body) => {
+                let key = self.function_key(statement, name);
+                let cached = key
+                    .as_ref()
+                    .and_then(|key| self.cache.as_ref()?.entries.get(key).cloned());
+                let entry = match cached {
+                    Some(cached) => self.splice_function(&cached),
+                    None => {
+                        let start = self.bytecode.len();
+                        let (entry, _) = self.compile_function_body(params, body)?;
+                        if let Some(key) = key {
+                            self.cache_function(key, start, entry);
+                        }
+                        entry
+                    }
+                };
+                self.functions.get_mut(name).unwrap().addr = Some(entry);
+                if let Some(debug_info) = &mut self.debug_info {
+                    debug_info.declare_function(entry, name.clone());
+                }
+            }
+            // Bound at the start of the program
+            StatementKind::Extern(..) => {}
+            StatementKind::Call(name, args) => {
+                self.compile_call(name, args, statement.span)?;
+                self.emit(Opcode::Pop as u8);
+            }
+            StatementKind::Return(value) => {
+                match value {
+                    Some(value) => self.compile_expr(value)?,
+                    None => {
+                        self.emit(Opcode::Push as u8);
+                        self.emit_i64(0);
+                    }
+                }
+                self.emit(Opcode::Ret as u8);
+            }
+            StatementKind::If(condition, then_block, else_block) => {
+                let else_jump = self.emit_jump_if_false(condition)?;
+                self.compile_block(then_block)?;
+
+                if else_block.is_empty() {
+                    let end_pos = self.bytecode.len();
+                    self.patch_operand(else_jump, end_pos);
+                } else {
+                    let end_jump = self.emit_jump(Opcode::Jump);
                     let else_pos = self.bytecode.len();
-                    self.compile(else_block);
+                    self.patch_operand(else_jump, else_pos);
+                    self.compile_block(else_block)?;
                     let end_pos = self.bytecode.len();
+                    self.patch_operand(end_jump, end_pos);
+                }
+            }
+            StatementKind::While(condition, block) => {
+                let hoisted = self.hoist_loop_loads(condition, block)?;
+                let start_pos = self.bytecode.len();
+                let end_jump = self.emit_jump_if_false(condition)?;
+
+                self.compile_block(block)?;
+
+                // Jump back to start
+                let back_jump = self.emit_jump(Opcode::Jump);
+                self.patch_operand(back_jump, start_pos);
 
-                    // Fix up the jump addresses
-                    let else_addr = else_pos as i64;
-                    let end_addr = end_pos as i64;
-                    self.bytecode[jump_if_pos + 1..jump_if_pos + 9]
-                        .copy_from_slice(&else_addr.to_le_bytes());
-                    self.bytecode[jump_end_pos + 1..jump_end_pos + 9]
-                        .copy_from_slice(&end_addr.to_le_bytes());
+                let end_pos = self.bytecode.len();
+                self.patch_operand(end_jump, end_pos);
+                if hoisted {
+                    self.exit_scope();
                 }
-                Statement::While(condition, block) => {
-                    let start_pos = self.bytecode.len();
+            }
+            StatementKind::Print(exprs) => match exprs.as_slice() {
+                [expr] => {
+                    self.compile_expr(expr)?;
+                    match self.kind_of(expr) {
+                        ValueKind::Str => self.emit(Opcode::PrintStr as u8),
+                        ValueKind::Float => self.emit(Opcode::PrintFloat as u8),
+                        _ => self.emit(Opcode::Print as u8),
+                    }
+                }
+                // Several values print like `print("{} {} ...", ...)`
+                _ => {
+                    let mut pieces = Vec::with_capacity(exprs.len() * 2);
+                    for i in 0..exprs.len() {
+                        if i > 0 {
+                            pieces.push(FormatPiece::Literal(" ".to_string()));
+                        }
+                        pieces.push(FormatPiece::Argument);
+                    }
+                    self.compile_format(pieces, exprs)?;
+                }
+            },
+            StatementKind::PrintFormatted(format, args) => {
+                let pieces = parse_format(format, statement.span)?;
+                let placeholders = pieces
+                    .iter()
+                    .filter(|piece| matches!(piece, FormatPiece::Argument))
+                    .count();
+                if placeholders != args.len() {
+                    return Err(CompileError::FormatArguments {
+                        placeholders,
+                        arguments: args.len(),
+                        span: statement.span,
+                    });
+                }
+                self.compile_format(pieces, args)?;
+            }
+        }
+        Ok(())
+    }
 
-                    self.compile_expr(&condition);
+    /// Writes the pieces of a format string in order, filling placeholders
+    /// with `args`, then ends the line.
+    fn compile_format(
+        &mut self,
+        pieces: Vec<FormatPiece>,
+        args: &[Expr],
+    ) -> Result<(), CompileError> {
+        let mut args = args.iter();
+        for piece in pieces {
+            match piece {
+                FormatPiece::Literal(text) => {
+                    self.compile_expr(&Expr::new(ExprKind::Str(text), Span::default()))?;
+                    self.emit(Opcode::WriteStr as u8);
+                }
+                FormatPiece::Argument => {
+                    let arg = args.next().unwrap();
+                    let opcode = match self.kind_of(arg) {
+                        ValueKind::Int => Opcode::WriteInt,
+                        ValueKind::Float => Opcode::WriteFloat,
+                        ValueKind::Str => Opcode::WriteStr,
+                        _ => return Err(CompileError::NotFormattable { span: arg.span }),
+                    };
+                    self.compile_expr(arg)?;
+                    self.emit(opcode as u8);
+                }
+            }
+        }
+        self.emit(Opcode::Push as u8);
+        self.emit_i64('\n' as i64);
+        self.emit(Opcode::PrintChar as u8);
+        Ok(())
+    }
 
-                    let jump_pos = self.bytecode.len();
-                    self.emit(Opcode::JumpIf as u8);
-                    self.emit_i64(0); // Placeholder for end
+    /// Compiles `name++` / `name--` as load, step, store.
+    fn compile_step(&mut self, name: &str, opcode: Opcode, span: Span) -> Result<(), CompileError> {
+        self.check_not_constant(name, span)?;
+        if self.lookup(name).map_or(ValueKind::Int, |v| v.kind) != ValueKind::Int {
+            return Err(CompileError::StepNonInteger {
+                name: name.to_string(),
+                span,
+            });
+        }
+        let variable = self.resolve_for_store(name, span)?;
+        self.emit_load(&variable);
+        self.emit(opcode as u8);
+        self.emit_store(&variable);
+        Ok(())
+    }
 
-                    self.compile(block);
+    /// Resolves an `extern` function against the VM's registered host
+    /// functions. Bindings are emitted ahead of all other code, so a missing
+    /// or mismatched host function stops the program before it does anything.
+    fn emit_host_binding(&mut self, name: &str, arity: usize) {
+        let index = self.intern_string(name);
+        self.emit(Opcode::Push as u8);
+        let operand_pos = self.emit_placeholder();
+        self.string_fixups.push((operand_pos, index));
+        self.emit(Opcode::Push as u8);
+        self.emit_i64(arity as i64);
+        self.emit(Opcode::BindHost as u8);
+    }
 
-                    // Jump back to start
-                    self.emit(Opcode::Push as u8);
-                    self.emit_i64(start_pos as i64);
-                    self.emit(Opcode::Jump as u8);
+    /// Compiles a function body in place, behind a jump that skips it during
+    /// straight-line execution, and returns its entry point with the enclosing
+    /// variables it captures. Callers push the arguments in order followed by
+    /// an environment pointer; the prologue enters a fresh frame and pops them
+    /// into its first slots, and falling off the end returns 0.
+    fn compile_function_body(
+        &mut self,
+        params: &[Param],
+        body: &[Statement],
+    ) -> Result<(usize, Vec<Variable>), CompileError> {
+        let skip_jump = self.emit_jump(Opcode::Jump);
+        let entry = self.bytecode.len();
+        // The frame size is only known once the body has been compiled
+        self.emit(Opcode::Push as u8);
+        let frame_size = self.emit_placeholder();
+        self.emit(Opcode::Enter as u8);
 
-                    let end_pos = self.bytecode.len();
-                    let end_addr = end_pos as i64;
-                    self.bytecode[jump_pos + 1..jump_pos + 9]
-                        .copy_from_slice(&end_addr.to_le_bytes());
+        self.frames.push(Frame {
+            scope_start: self.scopes.len(),
+            next_slot: 1,
+            size: 1,
+            env_slot: 0,
+            captures: Vec::new(),
+        });
+        self.enter_scope();
+        let mut slots = Vec::with_capacity(params.len() + 1);
+        for (param, annotation) in params {
+            let kind = self.kind_from_type(annotation.as_ref().unwrap_or(&Type::Unknown));
+            slots.push(self.declare(param, kind));
+        }
+        // The environment pointer is on top of the stack, then the last argument
+        self.emit(Opcode::Push as u8);
+        self.emit_i64(0);
+        self.emit(Opcode::StoreLocal as u8);
+        for slot in slots.iter().rev() {
+            self.emit_store(slot);
+        }
+        let result = body
+            .iter()
+            .try_for_each(|statement| self.compile_statement(statement));
+        self.exit_scope();
+        let frame = self.frames.pop().unwrap();
+        result?;
+        self.patch_operand(frame_size, frame.size);
+
+        self.emit(Opcode::Push as u8);
+        self.emit_i64(0);
+        self.emit(Opcode::Ret as u8);
+        let end_pos = self.bytecode.len();
+        self.patch_operand(skip_jump, end_pos);
+        Ok((entry, frame.captures))
+    }
+
+    /// Compiles a match as a compare-and-jump chain. The scrutinee stays on
+    /// the stack while arms are tested and is popped on entry to the taken arm.
+    fn compile_match(
+        &mut self,
+        scrutinee: &Expr,
+        arms: &[(MatchPattern, Vec<Statement>)],
+        span: Span,
+    ) -> Result<(), CompileError> {
+        if self.kind_of(scrutinee) != ValueKind::Int {
+            return Err(CompileError::ExpectedInteger {
+                what: "Match scrutinee",
+                span: scrutinee.span,
+            });
+        }
+        let mut labels = Vec::with_capacity(arms.len());
+        for (pattern, _) in arms {
+            let label = match pattern {
+                MatchPattern::Number(n) => Some(*n),
+                MatchPattern::Const(expr) => {
+                    Some(const_eval(expr, &self.constants).map_err(|error| {
+                        CompileError::ConstEval {
+                            error,
+                            span: expr.span,
+                        }
+                    })?)
                 }
-                Statement::Print(expr) => {
-                    self.compile_expr(&expr);
-                    self.emit(Opcode::Print as u8);
+                MatchPattern::Wildcard => None,
+            };
+            if let Some(n) = label {
+                if labels.contains(&Some(n)) {
+                    return Err(CompileError::DuplicateMatchArm { value: n, span });
                 }
             }
+            labels.push(label);
         }
 
+        self.compile_expr(scrutinee)?;
+        let mut end_jumps = Vec::new();
+        let mut has_wildcard = false;
+        for ((_, body), label) in arms.iter().zip(labels) {
+            let next_jump = match label {
+                Some(n) => {
+                    self.emit(Opcode::Dup as u8);
+                    self.emit(Opcode::Push as u8);
+                    self.emit_i64(n);
+                    self.emit(Opcode::NotEqual as u8);
+                    Some(self.emit_jump(Opcode::JumpIf))
+                }
+                None => None,
+            };
+            self.emit(Opcode::Pop as u8);
+            self.compile_block(body)?;
+            end_jumps.push(self.emit_jump(Opcode::Jump));
+            match next_jump {
+                Some(next_jump) => {
+                    let next_pos = self.bytecode.len();
+                    self.patch_operand(next_jump, next_pos);
+                }
+                None => {
+                    // Later arms can never be reached
+                    has_wildcard = true;
+                    break;
+                }
+            }
+        }
+        if !has_wildcard {
+            self.emit(Opcode::Pop as u8);
+        }
+        let end_pos = self.bytecode.len();
+        for end_jump in end_jumps {
+            self.patch_operand(end_jump, end_pos);
+        }
+        Ok(())
+    }
+
+    /// Lends the compiler a `Session`'s cache of function bodies.
+    pub(crate) fn set_function_cache(&mut self, cache: FunctionCache) {
+        self.cache = Some(cache);
+    }
+
+    pub(crate) fn take_function_cache(&mut self) -> Option<FunctionCache> {
+        self.cache.take()
+    }
+
+    /// Identifies the code of a top-level function: its source text and
+    /// everything its body is compiled against, from the signatures of all
+    /// functions to the global variables in scope. `None` without a cache.
+    fn function_key(&mut self, statement: &Statement, name: &str) -> Option<FunctionKey> {
+        let cache = self.cache.as_ref().filter(|_| self.frames.is_empty())?;
+        let source = if self.own_functions.contains(name) {
+            cache.source.as_str()
+        } else {
+            prelude::SOURCE
+        };
+        let span = statement.span;
+        let text = source.get(span.offset..span.offset + span.len)?;
+        let mut functions: Vec<String> = self
+            .functions
+            .iter()
+            .map(|(name, f)| format!("{} {:?} {:?} {:?}", name, f.params, f.result, f.host))
+            .collect();
+        functions.sort();
+        let mut variables: Vec<String> = self
+            .scopes
+            .iter()
+            .enumerate()
+            .flat_map(|(depth, scope)| {
+                scope
+                    .variables
+                    .iter()
+                    .map(move |(name, variable)| format!("{} {} {:?}", depth, name, variable))
+            })
+            .collect();
+        variables.sort();
+        let mut constants: Vec<(String, i64)> = self
+            .constants
+            .iter()
+            .map(|(name, value)| (name.clone(), *value))
+            .collect();
+        constants.sort();
+
+        let key = FunctionKey {
+            text: text.to_string(),
+            functions,
+            variables,
+            structs: self.structs.clone(),
+            constants,
+        };
+        self.cache.as_mut().unwrap().used.insert(key.clone());
+        Some(key)
+    }
+
+    /// Caches the function body emitted from `start` on, before its calls
+    /// and strings are patched.
+    fn cache_function(&mut self, key: FunctionKey, start: usize, entry: usize) {
+        let end = self.bytecode.len();
+        let mut code = self.bytecode[start..end].to_vec();
+        let relocations: Vec<Relocation> = self
+            .relocations
+            .iter()
+            .filter(|relocation| (start..end).contains(&relocation.offset))
+            .map(|relocation| Relocation {
+                offset: relocation.offset - start,
+                kind: relocation.kind.clone(),
+            })
+            .collect();
+        for relocation in &relocations {
+            if relocation.kind == RelocationKind::Code {
+                let operand = &mut code[relocation.offset..relocation.offset + 8];
+                let addr = i64::from_le_bytes((&*operand).try_into().unwrap()) - start as i64;
+                operand.copy_from_slice(&addr.to_le_bytes());
+            }
+        }
+        let function_fixups = self
+            .function_fixups
+            .iter()
+            .filter(|(offset, _)| (start..end).contains(offset))
+            .map(|(offset, name)| (offset - start, name.clone()))
+            .collect();
+        let string_fixups = self
+            .string_fixups
+            .iter()
+            .filter(|(offset, _)| (start..end).contains(offset))
+            .map(|(offset, index)| (offset - start, self.strings[*index].clone()))
+            .collect();
+        let cache = self.cache.as_mut().unwrap();
+        cache.stats.compiled += 1;
+        cache.entries.insert(
+            key,
+            CachedFunction {
+                code,
+                entry: entry - start,
+                relocations,
+                function_fixups,
+                string_fixups,
+            },
+        );
+    }
+
+    /// Emits a cached function body and returns its entry point.
+    fn splice_function(&mut self, cached: &CachedFunction) -> usize {
+        let base = self.bytecode.len();
+        self.bytecode.extend_from_slice(&cached.code);
+        for relocation in &cached.relocations {
+            let offset = base + relocation.offset;
+            if relocation.kind == RelocationKind::Code {
+                let operand = &self.bytecode[offset..offset + 8];
+                let addr = i64::from_le_bytes(operand.try_into().unwrap()) as usize;
+                self.patch_operand(offset, base + addr);
+            }
+            self.relocations.push(Relocation {
+                offset,
+                kind: relocation.kind.clone(),
+            });
+        }
+        for (offset, name) in &cached.function_fixups {
+            self.function_fixups.push((base + offset, name.clone()));
+        }
+        for (offset, value) in &cached.string_fixups {
+            let index = self.intern_string(value);
+            self.string_fixups.push((base + offset, index));
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.stats.reused += 1;
+        }
+        base + cached.entry
+    }
+
+    /// Appends string constants after the code as `[len: i64][utf-8 bytes]`
+    /// records and patches every reference with the record's offset.
+    fn emit_data_segment(&mut self) {
+        let mut offsets = Vec::with_capacity(self.strings.len());
+        for value in std::mem::take(&mut self.strings) {
+            offsets.push(self.bytecode.len());
+            self.emit_data(value.len() as i64);
+            self.bytecode.extend_from_slice(value.as_bytes());
+        }
+        for (operand_pos, index) in std::mem::take(&mut self.string_fixups) {
+            self.patch_operand(operand_pos, offsets[index]);
+        }
+        if let Some(operand_pos) = self.pool_fixup {
+            self.patch_operand(operand_pos, self.bytecode.len());
+            self.emit_data(self.pool.len() as i64);
+            for value in std::mem::take(&mut self.pool) {
+                self.emit_data(value);
+            }
+        }
+    }
+
+    /// Copies the captured variables that a loop reads but never writes or
+    /// redeclares into locals before the loop, so every iteration loads
+    /// them with a single `LoadLocal` instead of going through the closure's
+    /// environment. The copies live in a new scope around the loop; returns
+    /// whether it was entered. Only captured variables are worth copying:
+    /// globals and locals already load with one push and one load, as the
+    /// copy would.
+    fn hoist_loop_loads(
+        &mut self,
+        condition: &Expr,
+        body: &[Statement],
+    ) -> Result<bool, CompileError> {
+        if !self.hoist_loop_loads || self.frames.is_empty() {
+            return Ok(false);
+        }
+        let mut uses = LoopUses::default();
+        uses.visit_expr(condition);
+        uses.visit_block(body);
+        let mut invariant: Vec<&String> = uses.reads.difference(&uses.writes).collect();
+        invariant.sort();
+        let mut hoisted = Vec::new();
+        for name in invariant {
+            if self.lookup(name).is_none() {
+                continue;
+            }
+            let variable = self.resolve(name, condition.span)?;
+            if let Storage::Captured { .. } = variable.storage {
+                hoisted.push((name, variable));
+            }
+        }
+        if hoisted.is_empty() {
+            return Ok(false);
+        }
+        self.enter_scope();
+        for (name, variable) in hoisted {
+            self.emit_load(&variable);
+            let copy = self.declare(name, variable.kind);
+            self.emit_store(&copy);
+        }
+        Ok(true)
+    }
+
+    /// Compiles a nested block in its own scope.
+    fn compile_block(&mut self, statements: &[Statement]) -> Result<(), CompileError> {
+        self.enter_scope();
+        let result = statements
+            .iter()
+            .try_for_each(|statement| self.compile_statement(statement));
+        self.exit_scope();
+        result
+    }
+
+    /// Clears what is left of the last program emitted, keeping the
+    /// configuration, so one backend can emit any number of programs.
+    fn reset(&mut self) {
+        let mut fresh = StackBackend::new();
+        fresh.encoding = self.encoding;
+        fresh.global_base = self.global_base;
+        fresh.next_var_addr = self.global_base;
+        fresh.globals = self.global_base;
+        fresh.object = self.object;
+        fresh.hoist_loop_loads = self.hoist_loop_loads;
+        fresh.cache = self.cache.take();
+        fresh.debug_info = self.debug_info.as_ref().map(|_| DebugInfo::default());
+        *self = fresh;
+    }
+
+    fn emit_bytecode(&mut self, ir: &Ir) -> Result<Vec<u8>, CompileError> {
+        self.bytecode.extend(self.encoding.header());
+        self.generate(ir)?;
+        self.enter_span(None);
         self.emit(Opcode::Halt as u8);
-        self.bytecode.clone()
+        self.exit_span();
+        for (operand_pos, name) in std::mem::take(&mut self.function_fixups) {
+            let entry = self.functions[&name]
+                .addr
+                .expect("only imported functions have no entry point");
+            self.patch_operand(operand_pos, entry);
+        }
+        self.emit_data_segment();
+        if self.encoding == OperandEncoding::Leb128
+            && self.bytecode.len() >= 1 << (7 * bytecode::PATCHABLE_LEB128_LEN - 1)
+        {
+            return Err(CompileError::ProgramTooLarge {
+                span: Span::start(),
+            });
+        }
+        Ok(self.bytecode.clone())
+    }
+
+    fn emit_object(&mut self, ir: &Ir) -> Result<Object, CompileError> {
+        let encoding = std::mem::replace(&mut self.encoding, OperandEncoding::Fixed);
+        let result = self.generate(ir);
+        self.encoding = encoding;
+        result?;
+        let mut relocations = std::mem::take(&mut self.relocations);
+        let mut symbols: Vec<Symbol> = Vec::new();
+        for (operand_pos, name) in std::mem::take(&mut self.function_fixups) {
+            let function = &self.functions[&name];
+            let kind = match function.addr {
+                Some(entry) => {
+                    self.patch_operand(operand_pos, entry);
+                    RelocationKind::Code
+                }
+                None => {
+                    if !symbols.iter().any(|symbol| symbol.name == name) {
+                        symbols.push(Symbol {
+                            signature: function.signature(),
+                            name: name.clone(),
+                            offset: None,
+                        });
+                    }
+                    RelocationKind::Symbol(name)
+                }
+            };
+            relocations.push(Relocation {
+                offset: operand_pos,
+                kind,
+            });
+        }
+        relocations.extend(std::mem::take(&mut self.string_fixups).into_iter().map(
+            |(offset, index)| Relocation {
+                offset,
+                kind: RelocationKind::String(index),
+            },
+        ));
+        relocations.sort_by_key(|relocation| relocation.offset);
+        let mut exports: Vec<Symbol> = self
+            .own_functions
+            .iter()
+            .map(|name| {
+                let function = &self.functions[name];
+                Symbol {
+                    name: name.clone(),
+                    signature: function.signature(),
+                    offset: function.addr,
+                }
+            })
+            .collect();
+        exports.sort_by_key(|symbol| symbol.offset);
+        exports.extend(symbols);
+        Ok(Object {
+            code: std::mem::take(&mut self.bytecode),
+            strings: std::mem::take(&mut self.strings),
+            globals: self.globals,
+            host_bindings: self.functions.values().filter(|f| f.host.is_some()).count(),
+            symbols: exports,
+            relocations,
+        })
+    }
+
+    /// Generates the code of a program up to the end of its top-level
+    /// statements.
+    fn generate(&mut self, ir: &Ir) -> Result<(), CompileError> {
+        self.own_functions = ir.own_functions.clone();
+        self.closure_types = ir.closure_types.clone();
+        self.constant_uses = ConstantUses::default();
+        self.constant_uses.visit_block(&ir.statements);
+        // Cached function bodies cannot depend on the program's pool layout
+        if !self.object && self.cache.is_none() && self.constant_uses.any_repeated() {
+            // Runs first so the pool is set up before any `PushConst`
+            self.emit(Opcode::Push as u8);
+            self.pool_fixup = Some(self.emit_placeholder());
+            self.emit(Opcode::ConstPool as u8);
+        }
+        // Register every function first so calls may precede the declaration
+        for symbol in &ir.imports {
+            let Type::Function(params, result) = &symbol.signature else {
+                return Err(CompileError::ImportNotFunction {
+                    name: symbol.name.clone(),
+                    span: Span::start(),
+                });
+            };
+            let function = Function {
+                addr: None,
+                arity: params.len(),
+                params: params.clone(),
+                result: (**result).clone(),
+                host: None,
+                imported: true,
+            };
+            self.functions.insert(symbol.name.clone(), function);
+        }
+        let mut host_bindings = 0;
+        for statement in &ir.statements {
+            let (name, params, host) = match &statement.kind {
+                StatementKind::Function(name, params, _, _) => (name, params, None),
+                StatementKind::Extern(name, params, _) => {
+                    host_bindings += 1;
+                    (name, params, Some(host_bindings - 1))
+                }
+                _ => continue,
+            };
+            let Some(Type::Function(param_types, result)) = ir.function_types.get(name) else {
+                unreachable!("the type checker registers every function");
+            };
+            let function = Function {
+                addr: None,
+                arity: params.len(),
+                params: param_types.clone(),
+                result: (**result).clone(),
+                host,
+                imported: false,
+            };
+            self.functions.insert(name.clone(), function);
+            if host.is_some() {
+                self.enter_span(Some(statement.span));
+                self.emit_host_binding(name, params.len());
+                self.exit_span();
+            }
+        }
+        for statement in &ir.statements {
+            // Prelude functions have spans into the prelude's source
+            let linked = matches!(&statement.kind, StatementKind::Function(name, ..) if !self.own_functions.contains(name));
+            self.enter_span(if linked { None } else { Some(statement.span) });
+            let result = self.compile_statement_kind(statement);
+            self.exit_span();
+            result?;
+        }
+        if let Some(name) = ir.entry_point.clone() {
+            self.enter_span(None);
+            let result = self.compile_call(&name, &[], Span::default());
+            self.exit_span();
+            result?;
+            self.emit(Opcode::Pop as u8);
+        }
+        Ok(())
+    }
+}
+
+impl Backend for StackBackend {
+    fn emit_program(&mut self, ir: &Ir) -> Result<Artifact, CompileError> {
+        self.reset();
+        if self.object {
+            self.emit_object(ir).map(Artifact::Object)
+        } else {
+            self.emit_bytecode(ir).map(Artifact::Bytecode)
+        }
+    }
+}
+
+impl Default for StackBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compiles programs: links them with the prelude, type-checks and
+/// optimizes them, then hands the resulting `Ir` to a `Backend`, the
+/// `StackBackend` unless `compile_with` is given another.
+pub struct Compiler {
+    /// Whether to link the standard prelude into the program
+    prelude: bool,
+    /// Function called once the top-level code has run, if any
+    entry_point: Option<String>,
+    /// Functions defined by other objects that the program may call
+    imports: Vec<Symbol>,
+    /// Passes run on the type-checked program before code generation
+    passes: PassManager,
+    /// Size change of each pass, once a program is compiled
+    pass_reports: Vec<PassReport>,
+    /// Non-fatal problems found in the last program compiled
+    warnings: Vec<Warning>,
+    backend: StackBackend,
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Compiler {
+            prelude: true,
+            entry_point: None,
+            imports: Vec::new(),
+            passes: PassManager::for_level(OptLevel::Default),
+            pass_reports: Vec::new(),
+            warnings: Vec::new(),
+            backend: StackBackend::new(),
+        }
+    }
+
+    /// A builder for a compiler with options other than the defaults that
+    /// `Compiler::new` uses.
+    pub fn builder() -> CompilerBuilder {
+        CompilerBuilder::default()
+    }
+
+    /// Enables or disables linking the standard prelude (on by default).
+    pub fn set_prelude(&mut self, enabled: bool) {
+        self.prelude = enabled;
+    }
+
+    /// Calls the function `name`, which takes no arguments, after the
+    /// program's top-level code, or nothing with `None` (the default).
+    pub fn set_entry_point(&mut self, name: Option<&str>) {
+        self.entry_point = name.map(str::to_string);
+    }
+
+    /// Places global variables from address `base` on (0 unless set). It
+    /// must leave room for them below `FRAME_BASE`.
+    pub fn set_global_base(&mut self, base: usize) {
+        self.backend.set_global_base(base);
+    }
+
+    /// Selects how inline operands are encoded (`OperandEncoding::Fixed`
+    /// unless set). LEB128 programs start with a header announcing it.
+    pub fn set_operand_encoding(&mut self, encoding: OperandEncoding) {
+        self.backend.set_operand_encoding(encoding);
+    }
+
+    /// Lets the program call the functions `object` defines, to be linked
+    /// with it by a `Linker`. Only programs compiled with `compile_object`
+    /// may use them.
+    pub fn import_object(&mut self, object: &Object) {
+        let defined = object
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.offset.is_some());
+        self.imports.extend(defined.map(|symbol| Symbol {
+            offset: None,
+            ..symbol.clone()
+        }));
+    }
+
+    /// Replaces the optimization passes with the built-in pipeline for
+    /// `level` (`OptLevel::Default` unless set).
+    pub fn set_opt_level(&mut self, level: OptLevel) {
+        self.passes = PassManager::for_level(level);
+        self.backend.hoist_loop_loads = level != OptLevel::None;
+    }
+
+    /// Appends a custom pass to run after the built-in ones.
+    pub fn add_pass(&mut self, pass: impl Pass + 'static) {
+        self.passes.add(pass);
+    }
+
+    /// How each pass changed the program's size, once it is compiled.
+    pub fn pass_reports(&self) -> &[PassReport] {
+        &self.pass_reports
+    }
+
+    /// Warnings about the program, such as unused variables and unreachable
+    /// code, once it is compiled. The prelude is not checked.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Enables or disables building a `DebugInfo` table that maps the
+    /// compiled code back to source spans (off by default).
+    pub fn set_debug_info(&mut self, enabled: bool) {
+        self.backend.set_debug_info(enabled);
+    }
+
+    /// The source map of the compiled program, if debug info is enabled.
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.backend.debug_info()
+    }
+
+    /// Lends the compiler a `Session`'s cache of function bodies.
+    pub(crate) fn set_function_cache(&mut self, cache: FunctionCache) {
+        self.backend.set_function_cache(cache);
+    }
+
+    pub(crate) fn take_function_cache(&mut self) -> Option<FunctionCache> {
+        self.backend.take_function_cache()
+    }
+
+    pub fn compile(&mut self, statements: Vec<Statement>) -> Result<Vec<u8>, CompileError> {
+        self.backend.set_object(false);
+        let ir = self.lower(statements)?;
+        match self.backend.emit_program(&ir)? {
+            Artifact::Bytecode(bytecode) => Ok(bytecode),
+            _ => unreachable!("the stack backend emits bytecode"),
+        }
+    }
+
+    /// Compiles a program as a relocatable object, to be combined with
+    /// others by a `Linker`. Calls to functions declared with
+    /// `import_object` are left for the linker to resolve, and the object
+    /// exports every top-level function of the program; those linked in from
+    /// the prelude stay private. Objects always use the fixed operand
+    /// encoding and have no constant pool.
+    pub fn compile_object(&mut self, statements: Vec<Statement>) -> Result<Object, CompileError> {
+        self.backend.set_object(true);
+        let ir = self.lower(statements)?;
+        match self.backend.emit_program(&ir)? {
+            Artifact::Object(object) => Ok(object),
+            _ => unreachable!("the stack backend emits an object"),
+        }
+    }
+
+    /// Compiles a program with another code generator. The program goes
+    /// through the same prelude, type checking and passes as with
+    /// `compile`; the backend's own options, such as the operand encoding
+    /// or debug info of a `StackBackend`, are set on the backend.
+    pub fn compile_with(
+        &mut self,
+        backend: &mut dyn Backend,
+        statements: Vec<Statement>,
+    ) -> Result<Artifact, CompileError> {
+        let ir = self.lower(statements)?;
+        backend.emit_program(&ir)
+    }
+
+    /// Checks, links and optimizes a program for a backend.
+    fn lower(&mut self, statements: Vec<Statement>) -> Result<Ir, CompileError> {
+        self.warnings.clear();
+        self.pass_reports.clear();
+        let warnings = diagnostics::check(&statements);
+        let own_functions: HashSet<String> = statements
+            .iter()
+            .filter_map(|statement| match &statement.kind {
+                StatementKind::Function(name, ..) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        let mut statements = if self.prelude {
+            prelude::link(statements)?
+        } else {
+            statements
+        };
+        // Imported functions replace any prelude function of the same name
+        statements.retain(|statement| {
+            !matches!(&statement.kind, StatementKind::Function(name, ..)
+                if !own_functions.contains(name) && self.imports.iter().any(|symbol| &symbol.name == name))
+        });
+        let mut checker = TypeChecker::new();
+        for symbol in &self.imports {
+            checker.declare_function(&symbol.name, symbol.signature.clone());
+        }
+        checker.check(&statements)?;
+        self.warnings = warnings;
+        self.pass_reports = self.passes.run(&mut statements)?;
+        let function_types = statements
+            .iter()
+            .filter_map(|statement| match &statement.kind {
+                StatementKind::Function(name, ..) | StatementKind::Extern(name, ..) => {
+                    Some((name.clone(), checker.function_type(name)?.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        Ok(Ir {
+            statements,
+            function_types,
+            closure_types: checker.closure_types().clone(),
+            own_functions,
+            imports: self.imports.clone(),
+            entry_point: self.entry_point.clone(),
+        })
     }
 }
 
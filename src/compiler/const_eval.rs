@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+
+use crate::compiler::{
+    error::ConstEvalError,
+    fold::shift_amount,
+    parser::{BinaryOpKind, Expr, ExprKind, UnaryOpKind},
+};
+
+/// Evaluates an expression the compiler needs the value of: the value of a
+/// `const` declaration, the count of an `[value; count]` array and a match
+/// pattern.
+///
+/// Supports integer, character and boolean literals, the arithmetic,
+/// comparison and logical operators, `?:`, and the `const` declarations in
+/// `constants`, which must be defined before the expression. Booleans are
+/// 0 or 1. Unlike at run time, overflow and division by zero are errors.
+pub fn const_eval(expr: &Expr, constants: &HashMap<String, i64>) -> Result<i64, ConstEvalError> {
+    match &expr.kind {
+        ExprKind::Number(n) => Ok(*n),
+        ExprKind::Bool(value) => Ok(*value as i64),
+        ExprKind::Variable(name) => constants
+            .get(name)
+            .copied()
+            .ok_or_else(|| ConstEvalError::NotConstant(name.clone())),
+        ExprKind::UnaryOp(UnaryOpKind::Not, operand) => {
+            Ok((const_eval(operand, constants)? == 0) as i64)
+        }
+        ExprKind::UnaryOp(UnaryOpKind::Neg, operand) => const_eval(operand, constants)?
+            .checked_neg()
+            .ok_or(ConstEvalError::Overflow),
+        ExprKind::Conditional(condition, then_expr, else_expr) => {
+            if const_eval(condition, constants)? != 0 {
+                const_eval(then_expr, constants)
+            } else {
+                const_eval(else_expr, constants)
+            }
+        }
+        ExprKind::BinaryOp(left, op, right) => {
+            let a = const_eval(left, constants)?;
+            // `&&` and `||` short-circuit, as they do at run time
+            match (op, a != 0) {
+                (BinaryOpKind::And, false) => return Ok(0),
+                (BinaryOpKind::Or, true) => return Ok(1),
+                _ => {}
+            }
+            let b = const_eval(right, constants)?;
+            match op {
+                BinaryOpKind::Add => a.checked_add(b).ok_or(ConstEvalError::Overflow),
+                BinaryOpKind::Sub => a.checked_sub(b).ok_or(ConstEvalError::Overflow),
+                BinaryOpKind::Mul => a.checked_mul(b).ok_or(ConstEvalError::Overflow),
+                BinaryOpKind::Div | BinaryOpKind::Mod if b == 0 => {
+                    Err(ConstEvalError::DivisionByZero)
+                }
+                BinaryOpKind::Div => a.checked_div(b).ok_or(ConstEvalError::Overflow),
+                BinaryOpKind::Mod => a.checked_rem(b).ok_or(ConstEvalError::Overflow),
+                BinaryOpKind::ShiftLeft | BinaryOpKind::ShiftRight => {
+                    let amount = shift_amount(b).ok_or(ConstEvalError::InvalidShift(b))?;
+                    Ok(match op {
+                        BinaryOpKind::ShiftLeft => a << amount,
+                        _ => a >> amount,
+                    })
+                }
+                BinaryOpKind::Equals => Ok((a == b) as i64),
+                BinaryOpKind::NotEquals => Ok((a != b) as i64),
+                BinaryOpKind::LessThan => Ok((a < b) as i64),
+                BinaryOpKind::GreaterThan => Ok((a > b) as i64),
+                BinaryOpKind::LessEqual => Ok((a <= b) as i64),
+                BinaryOpKind::GreaterEqual => Ok((a >= b) as i64),
+                BinaryOpKind::And | BinaryOpKind::Or => Ok((b != 0) as i64),
+            }
+        }
+        _ => Err(ConstEvalError::NotConstantExpression),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::const_eval;
+    use crate::compiler::{
+        error::ConstEvalError,
+        parser::{Parser, StatementKind},
+    };
+
+    fn eval(source: &str, constants: &[(&str, i64)]) -> Result<i64, ConstEvalError> {
+        let code = format!("let r = {};", source);
+        let statements = Parser::new(&code).parse_program().unwrap();
+        let StatementKind::Let(_, _, expr) = &statements[0].kind else {
+            panic!("expected a let");
+        };
+        let constants: HashMap<String, i64> = constants
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect();
+        const_eval(expr, &constants)
+    }
+
+    #[test]
+    fn evaluates_arithmetic_and_comparisons() {
+        assert_eq!(eval("1 + 2 * 3 - 8 / 3 % 2", &[]), Ok(7));
+        assert_eq!(eval("-(4 - 10)", &[]), Ok(6));
+        assert_eq!(eval("'a' + 1 == 'b'", &[]), Ok(1));
+        assert_eq!(eval("3 <= 2 || !false && 2 > 1", &[]), Ok(1));
+        assert_eq!(
+            eval(
+                "WIDTH * HEIGHT > 100 ? WIDTH : HEIGHT",
+                &[("WIDTH", 16), ("HEIGHT", 9)]
+            ),
+            Ok(16)
+        );
+        // The right side of a decided `&&` or `||` is not evaluated
+        assert_eq!(eval("false && 1 / 0", &[]), Ok(0));
+        assert_eq!(eval("1 || 1 / 0", &[]), Ok(1));
+    }
+
+    #[test]
+    fn rejects_non_constants() {
+        assert_eq!(
+            eval("x + 1", &[]),
+            Err(ConstEvalError::NotConstant("x".to_string()))
+        );
+        assert_eq!(
+            eval("f(1)", &[]),
+            Err(ConstEvalError::NotConstantExpression)
+        );
+        assert_eq!(
+            eval("\"s\"", &[]),
+            Err(ConstEvalError::NotConstantExpression)
+        );
+        assert_eq!(
+            eval("1 % (2 - 2)", &[]),
+            Err(ConstEvalError::DivisionByZero)
+        );
+        assert_eq!(
+            eval("9223372036854775807 + 1", &[]),
+            Err(ConstEvalError::Overflow)
+        );
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::HashSet;
+
+use crate::compiler::{
+    parser::{BinaryOpKind, Expr, ExprKind, Statement, StatementKind},
+    pass::count_statements,
+};
+
+/// Removes code that cannot affect the program's behavior:
+///
+/// - statements after a `return`, `throw` or `exit` in the same block,
+/// - the untaken branch of an `if` with a constant condition, and loops
+///   whose condition is constantly false,
+/// - `let` and assignments to variables that are never read, when the
+///   value has no side effects.
+///
+/// Declarations (functions, externs, structs and constants) are always
+/// kept, since they are visible before the point where they appear. Run
+/// this after constant folding so that conditions like `1 > 2` are literals.
+///
+/// Values of removed variables no longer appear in VM memory, which is
+/// why this pass only runs at `OptLevel::Aggressive`.
+pub fn eliminate_dead_code(statements: &mut Vec<Statement>) {
+    loop {
+        let before = count_statements(statements);
+        remove_unreachable(statements);
+        let mut reads = HashSet::new();
+        collect_reads(statements, &mut reads);
+        remove_dead_stores(statements, &reads);
+        if count_statements(statements) == before {
+            break;
+        }
+    }
+}
+
+pub(crate) fn nested_blocks(statement: &Statement) -> Vec<&Vec<Statement>> {
+    match &statement.kind {
+        StatementKind::If(_, then_block, else_block) => vec![then_block, else_block],
+        StatementKind::While(_, body) | StatementKind::Function(_, _, _, body) => vec![body],
+        StatementKind::Try(body, _, handler) => vec![body, handler],
+        StatementKind::Match(_, arms) => arms.iter().map(|(_, body)| body).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn is_declaration(statement: &Statement) -> bool {
+    matches!(
+        statement.kind,
+        StatementKind::Function(..)
+            | StatementKind::Extern(..)
+            | StatementKind::Struct(..)
+            | StatementKind::Const(..)
+    )
+}
+
+/// Whether `statement` declares a name in the enclosing block's scope, so
+/// its block cannot be merged into the enclosing one.
+fn declares_binding(statement: &Statement) -> bool {
+    is_declaration(statement)
+        || matches!(
+            statement.kind,
+            StatementKind::Let(..) | StatementKind::LetTuple(..)
+        )
+}
+
+fn constant_condition(condition: &Expr) -> Option<bool> {
+    match condition.kind {
+        ExprKind::Bool(value) => Some(value),
+        ExprKind::Number(n) => Some(n != 0),
+        _ => None,
+    }
+}
+
+fn remove_unreachable(statements: &mut Vec<Statement>) {
+    let mut result = Vec::with_capacity(statements.len());
+    let mut reachable = true;
+    for mut statement in statements.drain(..) {
+        if !reachable && !is_declaration(&statement) {
+            continue;
+        }
+        match &mut statement.kind {
+            StatementKind::If(condition, then_block, else_block) => {
+                if let Some(value) = constant_condition(condition) {
+                    let mut taken = std::mem::take(if value { then_block } else { else_block });
+                    remove_unreachable(&mut taken);
+                    if taken.iter().any(declares_binding) {
+                        // Keep the block for its scope
+                        let condition = Expr::new(ExprKind::Bool(true), condition.span);
+                        result.push(Statement::new(
+                            StatementKind::If(condition, taken, Vec::new()),
+                            statement.span,
+                        ));
+                    } else {
+                        reachable = !taken.iter().any(is_terminator);
+                        result.extend(taken);
+                    }
+                    continue;
+                }
+            }
+            StatementKind::While(condition, _) if constant_condition(condition) == Some(false) => {
+                continue;
+            }
+            _ => {}
+        }
+        for block in nested_blocks_mut(&mut statement) {
+            remove_unreachable(block);
+        }
+        for body in closure_bodies_mut(&mut statement) {
+            remove_unreachable(body);
+        }
+        reachable = reachable && !is_terminator(&statement);
+        result.push(statement);
+    }
+    *statements = result;
+}
+
+fn is_terminator(statement: &Statement) -> bool {
+    matches!(
+        statement.kind,
+        StatementKind::Return(_) | StatementKind::Throw(_) | StatementKind::Exit(_)
+    )
+}
+
+fn nested_blocks_mut(statement: &mut Statement) -> Vec<&mut Vec<Statement>> {
+    match &mut statement.kind {
+        StatementKind::If(_, then_block, else_block) => vec![then_block, else_block],
+        StatementKind::While(_, body) | StatementKind::Function(_, _, _, body) => vec![body],
+        StatementKind::Try(body, _, handler) => vec![body, handler],
+        StatementKind::Match(_, arms) => arms.iter_mut().map(|(_, body)| body).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The expressions a statement evaluates directly, not counting nested blocks.
+fn expressions_mut(statement: &mut Statement) -> Vec<&mut Expr> {
+    match &mut statement.kind {
+        StatementKind::Let(_, _, expr)
+        | StatementKind::Const(_, expr)
+        | StatementKind::Assign(_, expr)
+        | StatementKind::Assert(expr)
+        | StatementKind::Exit(expr)
+        | StatementKind::Throw(expr)
+        | StatementKind::Return(Some(expr))
+        | StatementKind::If(expr, ..)
+        | StatementKind::While(expr, _)
+        | StatementKind::Match(expr, _) => vec![expr],
+        StatementKind::LetTuple(_, exprs)
+        | StatementKind::AssignTuple(_, exprs)
+        | StatementKind::Print(exprs)
+        | StatementKind::PrintFormatted(_, exprs)
+        | StatementKind::Call(_, exprs) => exprs.iter_mut().collect(),
+        StatementKind::IndexAssign(_, index, value) => vec![index, value],
+        _ => Vec::new(),
+    }
+}
+
+/// Bodies of the closures created by a statement's expressions.
+fn closure_bodies_mut(statement: &mut Statement) -> Vec<&mut Vec<Statement>> {
+    let mut bodies = Vec::new();
+    for expr in expressions_mut(statement) {
+        collect_closure_bodies(expr, &mut bodies);
+    }
+    bodies
+}
+
+fn collect_closure_bodies<'a>(expr: &'a mut Expr, bodies: &mut Vec<&'a mut Vec<Statement>>) {
+    match &mut expr.kind {
+        ExprKind::Closure(_, _, _, body) => bodies.push(body),
+        ExprKind::UnaryOp(_, operand) | ExprKind::Field(operand, _) => {
+            collect_closure_bodies(operand, bodies)
+        }
+        ExprKind::BinaryOp(left, _, right)
+        | ExprKind::Index(left, right)
+        | ExprKind::ArrayRepeat(left, right) => {
+            collect_closure_bodies(left, bodies);
+            collect_closure_bodies(right, bodies);
+        }
+        ExprKind::Conditional(condition, then_expr, else_expr) => {
+            collect_closure_bodies(condition, bodies);
+            collect_closure_bodies(then_expr, bodies);
+            collect_closure_bodies(else_expr, bodies);
+        }
+        ExprKind::Call(_, exprs) | ExprKind::Array(exprs) => {
+            for expr in exprs {
+                collect_closure_bodies(expr, bodies);
+            }
+        }
+        ExprKind::Map(entries) => {
+            for (key, value) in entries {
+                collect_closure_bodies(key, bodies);
+                collect_closure_bodies(value, bodies);
+            }
+        }
+        ExprKind::StructLiteral(_, fields) => {
+            for (_, value) in fields {
+                collect_closure_bodies(value, bodies);
+            }
+        }
+        ExprKind::Number(_)
+        | ExprKind::Float(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Str(_)
+        | ExprKind::Variable(_) => {}
+    }
+}
+
+/// Collects every name whose value the program reads.
+fn collect_reads(statements: &[Statement], reads: &mut HashSet<String>) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Increment(name) | StatementKind::Decrement(name) => {
+                reads.insert(name.clone());
+            }
+            // Element stores read the array or map itself
+            StatementKind::IndexAssign(name, ..) | StatementKind::Call(name, _) => {
+                reads.insert(name.clone());
+            }
+            _ => {}
+        }
+        let mut statement_exprs = Vec::new();
+        statement_exprs_of(statement, &mut statement_exprs);
+        for expr in statement_exprs {
+            collect_expr_reads(expr, reads);
+        }
+        for block in nested_blocks(statement) {
+            collect_reads(block, reads);
+        }
+    }
+}
+
+fn statement_exprs_of<'a>(statement: &'a Statement, exprs: &mut Vec<&'a Expr>) {
+    match &statement.kind {
+        StatementKind::Let(_, _, expr)
+        | StatementKind::Const(_, expr)
+        | StatementKind::Assign(_, expr)
+        | StatementKind::Assert(expr)
+        | StatementKind::Exit(expr)
+        | StatementKind::Throw(expr)
+        | StatementKind::Return(Some(expr))
+        | StatementKind::If(expr, ..)
+        | StatementKind::While(expr, _)
+        | StatementKind::Match(expr, _) => exprs.push(expr),
+        StatementKind::LetTuple(_, values)
+        | StatementKind::AssignTuple(_, values)
+        | StatementKind::Print(values)
+        | StatementKind::PrintFormatted(_, values)
+        | StatementKind::Call(_, values) => exprs.extend(values),
+        StatementKind::IndexAssign(_, index, value) => {
+            exprs.push(index);
+            exprs.push(value);
+        }
+        _ => {}
+    }
+}
+
+fn collect_expr_reads(expr: &Expr, reads: &mut HashSet<String>) {
+    match &expr.kind {
+        ExprKind::Variable(name) => {
+            reads.insert(name.clone());
+        }
+        ExprKind::Call(name, args) => {
+            reads.insert(name.clone());
+            for arg in args {
+                collect_expr_reads(arg, reads);
+            }
+        }
+        ExprKind::Closure(_, _, _, body) => collect_reads(body, reads),
+        ExprKind::UnaryOp(_, operand) | ExprKind::Field(operand, _) => {
+            collect_expr_reads(operand, reads)
+        }
+        ExprKind::BinaryOp(left, _, right)
+        | ExprKind::Index(left, right)
+        | ExprKind::ArrayRepeat(left, right) => {
+            collect_expr_reads(left, reads);
+            collect_expr_reads(right, reads);
+        }
+        ExprKind::Conditional(condition, then_expr, else_expr) => {
+            collect_expr_reads(condition, reads);
+            collect_expr_reads(then_expr, reads);
+            collect_expr_reads(else_expr, reads);
+        }
+        ExprKind::Array(elements) => {
+            for element in elements {
+                collect_expr_reads(element, reads);
+            }
+        }
+        ExprKind::Map(entries) => {
+            for (key, value) in entries {
+                collect_expr_reads(key, reads);
+                collect_expr_reads(value, reads);
+            }
+        }
+        ExprKind::StructLiteral(_, fields) => {
+            for (_, value) in fields {
+                collect_expr_reads(value, reads);
+            }
+        }
+        ExprKind::Number(_) | ExprKind::Float(_) | ExprKind::Bool(_) | ExprKind::Str(_) => {}
+    }
+}
+
+/// Whether evaluating `expr` can have no effect other than producing its
+/// value: no calls, and no operations that may stop the VM.
+fn is_pure(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Number(_)
+        | ExprKind::Float(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Str(_)
+        | ExprKind::Variable(_)
+        | ExprKind::Closure(..) => true,
+        ExprKind::UnaryOp(_, operand) => is_pure(operand),
+        // Integer division traps on a zero divisor
+        ExprKind::BinaryOp(left, BinaryOpKind::Div | BinaryOpKind::Mod, right) => {
+            is_pure(left) && matches!(right.kind, ExprKind::Number(n) if n != 0 && n != -1)
+        }
+        // So do shifts by a negative or too large amount
+        ExprKind::BinaryOp(left, BinaryOpKind::ShiftLeft | BinaryOpKind::ShiftRight, right) => {
+            is_pure(left) && matches!(right.kind, ExprKind::Number(n) if (0..64).contains(&n))
+        }
+        ExprKind::BinaryOp(left, _, right) => is_pure(left) && is_pure(right),
+        ExprKind::Conditional(condition, then_expr, else_expr) => {
+            is_pure(condition) && is_pure(then_expr) && is_pure(else_expr)
+        }
+        ExprKind::Array(elements) => elements.iter().all(is_pure),
+        ExprKind::ArrayRepeat(value, _) => is_pure(value),
+        ExprKind::Map(entries) => entries
+            .iter()
+            .all(|(key, value)| is_pure(key) && is_pure(value)),
+        ExprKind::StructLiteral(_, fields) => fields.iter().all(|(_, value)| is_pure(value)),
+        ExprKind::Call(..) | ExprKind::Index(..) | ExprKind::Field(..) => false,
+    }
+}
+
+fn remove_dead_stores(statements: &mut Vec<Statement>, reads: &HashSet<String>) {
+    statements.retain(|statement| match &statement.kind {
+        StatementKind::Let(name, _, expr) | StatementKind::Assign(name, expr) => {
+            reads.contains(name) || !is_pure(expr)
+        }
+        _ => true,
+    });
+    for statement in statements.iter_mut() {
+        for body in closure_bodies_mut(statement) {
+            remove_dead_stores(body, reads);
+        }
+        for block in nested_blocks_mut(statement) {
+            remove_dead_stores(block, reads);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::eliminate_dead_code;
+    use crate::compiler::fold::fold_constants;
+    use crate::compiler::parser::{Parser, Statement, StatementKind};
+    use crate::compiler::pass::count_statements;
+
+    fn eliminated(code: &str) -> Vec<Statement> {
+        let mut statements = Parser::new(code).parse_program().unwrap();
+        fold_constants(&mut statements);
+        eliminate_dead_code(&mut statements);
+        statements
+    }
+
+    #[test]
+    fn removes_unreachable_code() {
+        let statements = eliminated(
+            "let x = read();
+             if 1 > 2 { print 1; } else { print 2; }
+             while false { print 3; }
+             exit(x);
+             print 4;
+             fn f() { return 1; print 5; }",
+        );
+        assert_eq!(statements.len(), 4);
+        assert!(matches!(statements[1].kind, StatementKind::Print(_)));
+        assert!(matches!(statements[2].kind, StatementKind::Exit(_)));
+        assert!(
+            matches!(&statements[3].kind, StatementKind::Function(_, _, _, body) if body.len() == 1)
+        );
+        assert_eq!(count_statements(&statements), 5);
+    }
+
+    #[test]
+    fn removes_stores_that_are_never_read() {
+        let statements = eliminated(
+            "let unused = 1;
+             let chain = 2;
+             let also_unused = chain * 2;
+             let effect = read();
+             let used = 3;
+             unused = 4;
+             print used;",
+        );
+        assert_eq!(statements.len(), 3);
+        assert!(matches!(&statements[0].kind, StatementKind::Let(name, ..) if name == "effect"));
+        assert!(matches!(&statements[1].kind, StatementKind::Let(name, ..) if name == "used"));
+    }
+
+    #[test]
+    fn keeps_scoped_branches_and_declarations() {
+        let statements = eliminated(
+            "let x = 1;
+             if true { let x = 2; print x; }
+             print x;
+             return;
+             fn later() { return 1; }",
+        );
+        assert!(
+            matches!(&statements[1].kind, StatementKind::If(_, then_block, _) if then_block.len() == 2)
+        );
+        assert!(matches!(
+            statements.last().map(|statement| &statement.kind),
+            Some(StatementKind::Function(..))
+        ));
+    }
+}
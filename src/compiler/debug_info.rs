@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::span::Span;
+
+/// A side table mapping bytecode offsets to the source they were compiled
+/// from, emitted by `Compiler::set_debug_info(true)` and kept next to the
+/// program it describes.
+///
+/// Each entry covers the bytes from its offset up to the next entry's. Code
+/// with no source of its own, such as the prelude, maps to `None`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DebugInfo {
+    entries: Vec<(usize, Option<Span>)>,
+    /// Addresses of the program's top-level variables, in declaration order
+    #[serde(default)]
+    globals: Vec<(usize, String)>,
+    /// Entry points of the program's functions and closures
+    #[serde(default)]
+    functions: Vec<(usize, String)>,
+    /// Paths of the program's source files, indexed by the file id of
+    /// each span
+    #[serde(default)]
+    files: Vec<String>,
+}
+
+impl DebugInfo {
+    /// The source span of the instruction at `pc`.
+    pub fn span_at(&self, pc: usize) -> Option<Span> {
+        let index = self.entries.partition_point(|&(start, _)| start <= pc);
+        self.entries[..index].last().and_then(|&(_, span)| span)
+    }
+
+    /// The lowest offset of the code compiled from `line` of the entry
+    /// file, where a breakpoint on the line goes.
+    pub fn line_start(&self, line: usize) -> Option<usize> {
+        self.entries
+            .iter()
+            .find(|(_, span)| span.is_some_and(|span| span.file == 0 && span.line == line))
+            .map(|&(start, _)| start)
+    }
+
+    /// The start offset of every run of code and the span it maps to, in
+    /// address order.
+    pub fn entries(&self) -> &[(usize, Option<Span>)] {
+        &self.entries
+    }
+
+    /// The address and name of every top-level variable of the program, in
+    /// declaration order. A variable shadowing another has an address of
+    /// its own.
+    pub fn globals(&self) -> &[(usize, String)] {
+        &self.globals
+    }
+
+    /// The name of the top-level variable at `addr`.
+    pub fn global_name(&self, addr: usize) -> Option<&str> {
+        self.globals
+            .iter()
+            .rev()
+            .find(|(global, _)| *global == addr)
+            .map(|(_, name)| name.as_str())
+    }
+
+    /// The entry point and name of every function and closure of the
+    /// program, in the order they were compiled.
+    pub fn functions(&self) -> &[(usize, String)] {
+        &self.functions
+    }
+
+    /// The name of the function whose code starts at `entry`. Closures are
+    /// named after where they are written, as in `closure@3:9`.
+    pub fn function_at(&self, entry: usize) -> Option<&str> {
+        self.functions
+            .iter()
+            .find(|(start, _)| *start == entry)
+            .map(|(_, name)| name.as_str())
+    }
+
+    /// The path of every source file of the program, indexed by file id.
+    /// Empty unless the host recorded them with `set_files`.
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// The path of the source file a span with file id `file` is in.
+    pub fn file(&self, file: usize) -> Option<&str> {
+        self.files.get(file).map(String::as_str)
+    }
+
+    /// Records the paths of the program's source files, such as those of
+    /// `ModuleLoader::files`, for `file` to look spans up in.
+    pub fn set_files(&mut self, files: Vec<String>) {
+        self.files = files;
+    }
+
+    pub(crate) fn declare_function(&mut self, entry: usize, name: String) {
+        self.functions.push((entry, name));
+    }
+
+    pub(crate) fn declare_global(&mut self, addr: usize, name: &str) {
+        self.globals.push((addr, name.to_string()));
+    }
+
+    /// Maps the code from `pc` on to `span`, replacing an entry for the same
+    /// offset that has no code yet.
+    pub(crate) fn mark(&mut self, pc: usize, span: Option<Span>) {
+        if let Some(last) = self.entries.last_mut() {
+            if last.0 == pc {
+                last.1 = span;
+                let len = self.entries.len();
+                if len > 1 && self.entries[len - 2].1 == span {
+                    self.entries.pop();
+                }
+                return;
+            }
+            if last.1 == span {
+                return;
+            }
+        }
+        self.entries.push((pc, span));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DebugInfo;
+    use crate::compiler::span::Span;
+
+    fn line(line: usize) -> Option<Span> {
+        Some(Span {
+            line,
+            ..Span::default()
+        })
+    }
+
+    #[test]
+    fn looks_up_the_run_containing_a_pc() {
+        let mut info = DebugInfo::default();
+        info.mark(0, line(1));
+        info.mark(10, line(2));
+        info.mark(10, line(3));
+        info.mark(15, line(3));
+        info.mark(20, None);
+        info.mark(30, line(1));
+        assert_eq!(
+            info.entries(),
+            [(0, line(1)), (10, line(3)), (20, None), (30, line(1))]
+        );
+        assert_eq!(info.span_at(9), line(1));
+        assert_eq!(info.span_at(19), line(3));
+        assert_eq!(info.span_at(25), None);
+        assert_eq!(info.span_at(100), line(1));
+        assert_eq!(info.line_start(1), Some(0));
+        assert_eq!(info.line_start(3), Some(10));
+        assert_eq!(info.line_start(2), None);
+    }
+
+    #[test]
+    fn resolves_the_file_of_a_span() {
+        let mut info = DebugInfo::default();
+        let in_module = Span {
+            line: 4,
+            file: 1,
+            ..Span::default()
+        };
+        info.mark(0, Some(in_module));
+        info.mark(8, line(4));
+        // Breakpoints are set on lines of the entry file
+        assert_eq!(info.line_start(4), Some(8));
+        assert_eq!(info.file(in_module.file), None);
+
+        info.set_files(vec!["main.svm".to_string(), "lib/a.svm".to_string()]);
+        assert_eq!(info.file(info.span_at(3).unwrap().file), Some("lib/a.svm"));
+        assert_eq!(info.file(info.span_at(8).unwrap().file), Some("main.svm"));
+        assert_eq!(info.file(2), None);
+    }
+}
@@ -0,0 +1,339 @@
+use std::fmt;
+
+use crate::compiler::{
+    fold::ConstantFolder,
+    parser::{Expr, ExprKind, Param, Statement, StatementKind},
+    span::Span,
+};
+
+/// What a warning is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarningKind {
+    /// A variable declared with `let` whose value is never read
+    UnusedVariable,
+    /// Statements after a `return`, `throw` or `exit` in the same block
+    UnreachableCode,
+    /// A `while` loop whose condition is constantly false
+    DeadLoop,
+}
+
+/// A non-fatal problem found while compiling a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: warning: {}", self.span, self.message)
+    }
+}
+
+/// Finds the warnings in a program that has been type checked. Variables
+/// whose name starts with `_` are never reported as unused.
+pub fn check(statements: &[Statement]) -> Vec<Warning> {
+    let mut checker = WarningChecker::default();
+    checker.check_block(statements);
+    checker.warnings
+}
+
+struct Binding {
+    name: String,
+    /// The declaring statement
+    span: Span,
+    read: bool,
+    /// Whether to report the binding if it is never read; parameters and
+    /// caught exceptions are not reported
+    reported: bool,
+}
+
+#[derive(Default)]
+struct WarningChecker {
+    /// Innermost scope last, mirroring the compiler's block scoping
+    scopes: Vec<Vec<Binding>>,
+    /// Tracks `const` values to recognize constant loop conditions
+    folder: ConstantFolder,
+    warnings: Vec<Warning>,
+}
+
+impl WarningChecker {
+    fn warn(&mut self, kind: WarningKind, message: String, span: Span) {
+        self.warnings.push(Warning {
+            kind,
+            message,
+            span,
+        });
+    }
+
+    fn declare(&mut self, name: &str, span: Span, reported: bool) {
+        self.scopes.last_mut().unwrap().push(Binding {
+            name: name.to_string(),
+            span,
+            read: false,
+            reported,
+        });
+    }
+
+    fn mark_read(&mut self, name: &str) {
+        let binding = self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.iter_mut().rev().find(|binding| binding.name == name));
+        if let Some(binding) = binding {
+            binding.read = true;
+        }
+    }
+
+    fn check_block(&mut self, statements: &[Statement]) {
+        self.scopes.push(Vec::new());
+        self.check_statements(statements);
+        self.exit_scope();
+    }
+
+    fn exit_scope(&mut self) {
+        for binding in self.scopes.pop().unwrap() {
+            if binding.reported && !binding.read && !binding.name.starts_with('_') {
+                self.warn(
+                    WarningKind::UnusedVariable,
+                    format!("Variable '{}' is never read", binding.name),
+                    binding.span,
+                );
+            }
+        }
+    }
+
+    fn check_statements(&mut self, statements: &[Statement]) {
+        let mut terminator = None;
+        for statement in statements {
+            if let Some(keyword) = terminator {
+                if !is_declaration(statement) {
+                    self.warn(
+                        WarningKind::UnreachableCode,
+                        format!("Unreachable code after '{}'", keyword),
+                        statement.span,
+                    );
+                    // Report each run of unreachable statements once
+                    terminator = None;
+                }
+            }
+            self.check_statement(statement);
+            terminator = terminator.or(match statement.kind {
+                StatementKind::Return(_) => Some("return"),
+                StatementKind::Throw(_) => Some("throw"),
+                StatementKind::Exit(_) => Some("exit"),
+                _ => None,
+            });
+        }
+    }
+
+    fn check_statement(&mut self, statement: &Statement) {
+        let span = statement.span;
+        match &statement.kind {
+            StatementKind::Let(name, _, expr) => {
+                // The initializer still sees any binding this one shadows
+                self.check_expr(expr);
+                self.declare(name, span, true);
+            }
+            StatementKind::LetTuple(names, values) => {
+                values.iter().for_each(|value| self.check_expr(value));
+                for name in names {
+                    self.declare(name, span, true);
+                }
+            }
+            StatementKind::Const(..) => {
+                self.folder.fold_statement(&mut statement.clone());
+            }
+            StatementKind::Assign(_, expr)
+            | StatementKind::Assert(expr)
+            | StatementKind::Exit(expr)
+            | StatementKind::Throw(expr)
+            | StatementKind::Return(Some(expr)) => self.check_expr(expr),
+            StatementKind::AssignTuple(_, values)
+            | StatementKind::Print(values)
+            | StatementKind::PrintFormatted(_, values) => {
+                values.iter().for_each(|value| self.check_expr(value));
+            }
+            StatementKind::Call(name, args) => {
+                self.mark_read(name);
+                args.iter().for_each(|arg| self.check_expr(arg));
+            }
+            // Element stores and steps read the variable they update
+            StatementKind::IndexAssign(name, index, value) => {
+                self.mark_read(name);
+                self.check_expr(index);
+                self.check_expr(value);
+            }
+            StatementKind::Increment(name) | StatementKind::Decrement(name) => self.mark_read(name),
+            StatementKind::If(condition, then_block, else_block) => {
+                self.check_expr(condition);
+                self.check_block(then_block);
+                self.check_block(else_block);
+            }
+            StatementKind::While(condition, body) => {
+                self.check_expr(condition);
+                if matches!(
+                    self.folder.fold(condition.clone()).kind,
+                    ExprKind::Bool(false) | ExprKind::Number(0)
+                ) {
+                    self.warn(
+                        WarningKind::DeadLoop,
+                        "Loop condition is always false".to_string(),
+                        condition.span,
+                    );
+                }
+                self.check_block(body);
+            }
+            StatementKind::Try(body, name, handler) => {
+                self.check_block(body);
+                self.scopes.push(Vec::new());
+                self.declare(name, span, false);
+                self.check_statements(handler);
+                self.exit_scope();
+            }
+            StatementKind::Match(scrutinee, arms) => {
+                self.check_expr(scrutinee);
+                for (_, body) in arms {
+                    self.check_block(body);
+                }
+            }
+            StatementKind::Function(_, params, _, body) => self.check_function(params, body, span),
+            StatementKind::Struct(..) | StatementKind::Extern(..) | StatementKind::Return(None) => {
+            }
+        }
+    }
+
+    fn check_function(&mut self, params: &[Param], body: &[Statement], span: Span) {
+        self.scopes.push(Vec::new());
+        for (param, _) in params {
+            self.declare(param, span, false);
+        }
+        self.check_statements(body);
+        self.exit_scope();
+    }
+
+    fn check_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Variable(name) => self.mark_read(name),
+            ExprKind::Call(name, args) => {
+                self.mark_read(name);
+                args.iter().for_each(|arg| self.check_expr(arg));
+            }
+            ExprKind::Closure(_, params, _, body) => self.check_function(params, body, expr.span),
+            ExprKind::UnaryOp(_, operand) | ExprKind::Field(operand, _) => self.check_expr(operand),
+            ExprKind::BinaryOp(left, _, right)
+            | ExprKind::Index(left, right)
+            | ExprKind::ArrayRepeat(left, right) => {
+                self.check_expr(left);
+                self.check_expr(right);
+            }
+            ExprKind::Conditional(condition, then_expr, else_expr) => {
+                self.check_expr(condition);
+                self.check_expr(then_expr);
+                self.check_expr(else_expr);
+            }
+            ExprKind::Array(elements) => {
+                elements.iter().for_each(|element| self.check_expr(element))
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.check_expr(key);
+                    self.check_expr(value);
+                }
+            }
+            ExprKind::StructLiteral(_, fields) => {
+                fields.iter().for_each(|(_, value)| self.check_expr(value));
+            }
+            ExprKind::Number(_) | ExprKind::Float(_) | ExprKind::Bool(_) | ExprKind::Str(_) => {}
+        }
+    }
+}
+
+fn is_declaration(statement: &Statement) -> bool {
+    matches!(
+        statement.kind,
+        StatementKind::Function(..)
+            | StatementKind::Extern(..)
+            | StatementKind::Struct(..)
+            | StatementKind::Const(..)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, WarningKind};
+    use crate::compiler::parser::Parser;
+
+    fn warnings(code: &str) -> Vec<(WarningKind, String, String)> {
+        let statements = Parser::new(code).parse_program().unwrap();
+        check(&statements)
+            .into_iter()
+            .map(|warning| (warning.kind, warning.message, warning.span.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn reports_unused_variables() {
+        let unused = |name: &str, location: &str| {
+            (
+                WarningKind::UnusedVariable,
+                format!("Variable '{}' is never read", name),
+                location.to_string(),
+            )
+        };
+        assert_eq!(
+            warnings(
+                "let a = 1;
+let b = 2;
+let _c = 3;
+let (d, e) = (4, 5);
+let f = [1];
+f[0] = b + d;
+fn g(n) { let m = n; m = 1; }
+let h = fn() { return a; };
+try { throw 1; } catch (err) { }"
+            ),
+            [unused("m", "7:11"), unused("e", "4:1"), unused("h", "8:1")]
+        );
+        assert!(warnings("let x = 1; let x = x + 1; print x;").is_empty());
+    }
+
+    #[test]
+    fn reports_unreachable_code_and_dead_loops() {
+        assert_eq!(
+            warnings(
+                "const DEBUG = false;
+fn f() { return 1; print 2; print 3; }
+while DEBUG { print 4; }
+while 1 > 2 { }
+exit(0);
+fn after() { }
+print 5;"
+            ),
+            [
+                (
+                    WarningKind::UnreachableCode,
+                    "Unreachable code after 'return'".to_string(),
+                    "2:20".to_string()
+                ),
+                (
+                    WarningKind::DeadLoop,
+                    "Loop condition is always false".to_string(),
+                    "3:7".to_string()
+                ),
+                (
+                    WarningKind::DeadLoop,
+                    "Loop condition is always false".to_string(),
+                    "4:7".to_string()
+                ),
+                (
+                    WarningKind::UnreachableCode,
+                    "Unreachable code after 'exit'".to_string(),
+                    "7:1".to_string()
+                ),
+            ]
+        );
+    }
+}
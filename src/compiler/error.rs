@@ -0,0 +1,429 @@
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::compiler::{builder::Target, lexer::Token, parser::BinaryOpKind, span::Span};
+
+/// A malformed token, reported by the lexer as `Token::Invalid`.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum LexError {
+    #[error("Malformed integer literal '{0}'")]
+    MalformedInteger(String),
+    #[error("Malformed float literal '{0}'")]
+    MalformedFloat(String),
+    #[error("Integer literal '{0}' is out of range")]
+    IntegerOutOfRange(String),
+    #[error("Empty character literal")]
+    EmptyCharacter,
+    #[error("Unterminated character literal")]
+    UnterminatedCharacter,
+    #[error("Character literals must contain exactly one character")]
+    MultipleCharacters,
+    #[error("Unterminated string literal")]
+    UnterminatedString,
+    #[error("Unterminated block comment")]
+    UnterminatedComment,
+    #[error("Unexpected character '{0}'")]
+    UnexpectedCharacter(char),
+    #[error("Unknown escape sequence '\\{0}'")]
+    UnknownEscape(char),
+    #[error("Unicode escape must be written as '\\u{{...}}'")]
+    UnbracedUnicodeEscape,
+    #[error("Unterminated unicode escape '\\u{{'")]
+    UnterminatedUnicodeEscape,
+    #[error("Malformed unicode escape '\\u{{{0}}}'")]
+    MalformedUnicodeEscape(String),
+    /// Well-formed hex digits that are not a Unicode scalar value
+    #[error("Invalid unicode escape '\\u{{{0}}}'")]
+    InvalidUnicodeEscape(String),
+}
+
+/// What the parser was looking for when it found something else.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expected {
+    Token(Token),
+    Identifier,
+    Number,
+    Expression,
+    Statement,
+    MatchPattern,
+    FunctionBody,
+    ModulePath,
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expected::Token(token) => write!(f, "{}", token),
+            Expected::Identifier => write!(f, "identifier"),
+            Expected::Number => write!(f, "number"),
+            Expected::Expression => write!(f, "expression"),
+            Expected::Statement => write!(f, "statement"),
+            Expected::MatchPattern => write!(f, "match pattern"),
+            Expected::FunctionBody => write!(f, "function body"),
+            Expected::ModulePath => write!(f, "module path"),
+        }
+    }
+}
+
+/// A syntax error, located at the token where it was found, which it is
+/// displayed after as `line:col: message`.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ParseError {
+    Lex {
+        error: LexError,
+        span: Span,
+    },
+    /// `found` is `None` at the end of the input
+    Unexpected {
+        expected: Expected,
+        found: Option<Token>,
+        span: Span,
+    },
+    IntegerOutOfRange {
+        literal: String,
+        span: Span,
+    },
+    DuplicateName {
+        name: String,
+        span: Span,
+    },
+    DestructuringMismatch {
+        values: usize,
+        names: usize,
+        span: Span,
+    },
+    MisplacedImport {
+        span: Span,
+    },
+}
+
+impl ParseError {
+    /// Where in the source the error was found.
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::Lex { span, .. }
+            | ParseError::Unexpected { span, .. }
+            | ParseError::IntegerOutOfRange { span, .. }
+            | ParseError::DuplicateName { span, .. }
+            | ParseError::DestructuringMismatch { span, .. }
+            | ParseError::MisplacedImport { span } => *span,
+        }
+    }
+
+    /// What is wrong, without where, for tools that show the location
+    /// their own way.
+    pub fn message(&self) -> String {
+        match self {
+            ParseError::Lex { error, .. } => error.to_string(),
+            ParseError::Unexpected {
+                expected, found, ..
+            } => format!("Expected {}, found {}", expected, describe_found(found)),
+            ParseError::IntegerOutOfRange { literal, .. } => {
+                format!("Integer literal '{}' is out of range", literal)
+            }
+            ParseError::DuplicateName { name, .. } => {
+                format!("'{}' appears more than once in a destructuring", name)
+            }
+            ParseError::DestructuringMismatch { values, names, .. } => format!(
+                "Cannot destructure {} value(s) into {} name(s)",
+                values, names
+            ),
+            ParseError::MisplacedImport { .. } => {
+                "Imports must come first in a module and be loaded with ModuleLoader".to_string()
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span(), self.message())
+    }
+}
+
+fn describe_found(found: &Option<Token>) -> String {
+    match found {
+        Some(token) => token.to_string(),
+        None => "end of input".to_string(),
+    }
+}
+
+/// Errors one per line, for those reported together.
+pub(crate) fn lines<E: fmt::Display>(errors: &[E]) -> String {
+    let lines: Vec<String> = errors.iter().map(E::to_string).collect();
+    lines.join("\n")
+}
+
+/// Matches an error as `$errors` if it is a list of syntax errors, and
+/// otherwise binds its span to `$span`, so that `span` and `span_mut` list
+/// every variant once.
+macro_rules! match_span {
+    ($error:expr, $errors:ident => $parse:expr, $span:ident => $located:expr) => {
+        match $error {
+            CompileError::Prelude($errors) | CompileError::Parse($errors) => $parse,
+            CompileError::Type(TypeError { span: $span, .. })
+            | CompileError::Pass { span: $span, .. }
+            | CompileError::UndefinedVariable { span: $span, .. }
+            | CompileError::UnknownFunction { span: $span, .. }
+            | CompileError::UnknownStruct { span: $span, .. }
+            | CompileError::ArgumentCount { span: $span, .. }
+            | CompileError::ArgumentKind { span: $span, .. }
+            | CompileError::NotCallable { span: $span, .. }
+            | CompileError::HostFunctionValue { span: $span, .. }
+            | CompileError::ImportedFunction { span: $span, .. }
+            | CompileError::ImportNotFunction { span: $span, .. }
+            | CompileError::AlreadyDefined { span: $span, .. }
+            | CompileError::AssignToConstant { span: $span, .. }
+            | CompileError::AssignToCaptured { span: $span, .. }
+            | CompileError::StepNonInteger { span: $span, .. }
+            | CompileError::IndexAssignTarget { span: $span, .. }
+            | CompileError::NotIndexable { span: $span, .. }
+            | CompileError::ExpectedInteger { span: $span, .. }
+            | CompileError::CannotNegate { span: $span, .. }
+            | CompileError::UnsupportedOperator { span: $span, .. }
+            | CompileError::MixedOperands { span: $span, .. }
+            | CompileError::ConditionalKinds { span: $span, .. }
+            | CompileError::NotAStruct { span: $span, .. }
+            | CompileError::NoField { span: $span, .. }
+            | CompileError::MissingField { span: $span, .. }
+            | CompileError::DuplicateField { span: $span, .. }
+            | CompileError::NotFormattable { span: $span, .. }
+            | CompileError::MalformedPlaceholder { span: $span, .. }
+            | CompileError::UnmatchedBrace { span: $span, .. }
+            | CompileError::FormatArguments { span: $span, .. }
+            | CompileError::DuplicateMatchArm { span: $span, .. }
+            | CompileError::ArraySize { span: $span, .. }
+            | CompileError::ConstEval { span: $span, .. }
+            | CompileError::ProgramTooLarge { span: $span, .. } => $located,
+        }
+    };
+}
+
+/// An error that stops a program from compiling, located where it was
+/// found and displayed after it as `line:col: message`.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum CompileError {
+    /// The built-in prelude failed to parse
+    #[error("prelude: {}", lines(.0))]
+    Prelude(Vec<ParseError>),
+    /// The program failed to parse, when compiling from source, with
+    /// every syntax error found
+    #[error("{}", lines(.0))]
+    Parse(Vec<ParseError>),
+    /// Rejected by the type checker
+    #[error("{0}")]
+    Type(#[from] TypeError),
+    #[error("{span}: {pass}: {message}")]
+    Pass {
+        pass: String,
+        message: String,
+        span: Span,
+    },
+    #[error("{span}: Undefined variable '{name}'")]
+    UndefinedVariable { name: String, span: Span },
+    #[error("{span}: Unknown function '{name}'")]
+    UnknownFunction { name: String, span: Span },
+    #[error("{span}: Unknown struct '{name}'")]
+    UnknownStruct { name: String, span: Span },
+    #[error("{span}: Function '{name}' expects {expected} argument(s), got {found}")]
+    ArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    /// A built-in called with an argument of a kind it does not take
+    #[error("{span}: Function '{name}' expects {expected}")]
+    ArgumentKind {
+        name: String,
+        expected: &'static str,
+        span: Span,
+    },
+    #[error("{span}: '{name}' is not a function")]
+    NotCallable { name: String, span: Span },
+    #[error("{span}: Host function '{name}' can only be called, not used as a value")]
+    HostFunctionValue { name: String, span: Span },
+    /// A function imported from an object, used by a program compiled to
+    /// run rather than to be linked
+    #[error("{span}: '{name}' is imported and can only be called from an object")]
+    ImportedFunction { name: String, span: Span },
+    #[error("{span}: Imported symbol '{name}' is not a function")]
+    ImportNotFunction { name: String, span: Span },
+    /// A constant, or a struct, declared twice
+    #[error("{span}: '{name}' is already defined")]
+    AlreadyDefined { name: String, span: Span },
+    #[error("{span}: Cannot assign to constant '{name}'")]
+    AssignToConstant { name: String, span: Span },
+    #[error("{span}: Cannot assign to captured variable '{name}'")]
+    AssignToCaptured { name: String, span: Span },
+    #[error("{span}: Cannot increment or decrement non-integer '{name}'")]
+    StepNonInteger { name: String, span: Span },
+    #[error("{span}: '{name}' is not an array or map")]
+    IndexAssignTarget { name: String, span: Span },
+    #[error("{span}: Only strings, arrays and maps can be indexed")]
+    NotIndexable { span: Span },
+    /// A value of another kind where only integers, booleans included, are
+    /// stored or used
+    #[error("{span}: {what} must be an integer")]
+    ExpectedInteger { what: &'static str, span: Span },
+    #[error("{span}: Cannot negate a non-numeric value")]
+    CannotNegate { span: Span },
+    #[error("{span}: Operator {op:?} is not supported on {operands}")]
+    UnsupportedOperator {
+        op: BinaryOpKind,
+        operands: &'static str,
+        span: Span,
+    },
+    #[error("{span}: Cannot apply {op:?} to {left} and {right} values")]
+    MixedOperands {
+        op: BinaryOpKind,
+        left: String,
+        right: String,
+        span: Span,
+    },
+    #[error("{span}: Both branches of a conditional must have the same kind")]
+    ConditionalKinds { span: Span },
+    #[error("{span}: Cannot access field '{field}' on a non-struct value")]
+    NotAStruct { field: String, span: Span },
+    #[error("{span}: Struct '{name}' has no field '{field}'")]
+    NoField {
+        name: String,
+        field: String,
+        span: Span,
+    },
+    #[error("{span}: Missing field '{field}' in '{name}'")]
+    MissingField {
+        name: String,
+        field: String,
+        span: Span,
+    },
+    /// A field declared, or initialized, twice
+    #[error("{span}: Duplicate field '{field}' in '{name}'")]
+    DuplicateField {
+        name: String,
+        field: String,
+        span: Span,
+    },
+    #[error("{span}: Only numbers and strings can be formatted")]
+    NotFormattable { span: Span },
+    #[error("{span}: Format placeholders must be written as '{{}}'")]
+    MalformedPlaceholder { span: Span },
+    #[error("{span}: Unmatched '}}' in format string")]
+    UnmatchedBrace { span: Span },
+    #[error("{span}: Format string has {placeholders} placeholder(s) but {arguments} argument(s) were given")]
+    FormatArguments {
+        placeholders: usize,
+        arguments: usize,
+        span: Span,
+    },
+    #[error("{span}: Duplicate match arm for {value}")]
+    DuplicateMatchArm { value: i64, span: Span },
+    #[error("{span}: Array size {size} is not between 0 and {max}")]
+    ArraySize { size: i64, max: i64, span: Span },
+    /// A constant, array size or match pattern that cannot be evaluated
+    #[error("{span}: {error}")]
+    ConstEval { error: ConstEvalError, span: Span },
+    #[error("{span}: Program is too large for LEB128 operands")]
+    ProgramTooLarge { span: Span },
+}
+
+impl CompileError {
+    /// Where in the source the error was found; the first syntax error's
+    /// location for those that failed to parse.
+    pub fn span(&self) -> Span {
+        match_span!(
+            self,
+            errors => errors.first().map_or(Span::start(), ParseError::span),
+            span => *span
+        )
+    }
+
+    /// The span `span` returns, to locate the error in another source, or
+    /// `None` for syntax errors, which are each located on their own.
+    pub fn span_mut(&mut self) -> Option<&mut Span> {
+        match_span!(self, _errors => None, span => Some(span))
+    }
+
+    /// What is wrong, without where, for tools that show the location
+    /// their own way. Parse errors other than the first are left out.
+    pub fn message(&self) -> String {
+        match self {
+            CompileError::Prelude(errors) | CompileError::Parse(errors) => {
+                errors.first().map_or_else(String::new, ParseError::message)
+            }
+            CompileError::Type(error) => error.message.clone(),
+            error => {
+                let text = error.to_string();
+                let location = format!("{}: ", error.span());
+                text.strip_prefix(&location).unwrap_or(&text).to_string()
+            }
+        }
+    }
+}
+
+impl From<Vec<ParseError>> for CompileError {
+    fn from(errors: Vec<ParseError>) -> Self {
+        CompileError::Parse(errors)
+    }
+}
+
+/// Options a `Compiler` cannot be built with.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum BuildError {
+    #[error("No backend for the {0} target yet")]
+    UnsupportedTarget(Target),
+    #[error("Global base {base} is not below the call frames at {frames}")]
+    GlobalBase { base: usize, frames: usize },
+}
+
+/// Why a custom optimization pass gave up, located at the code it could
+/// not transform.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("{span}: {message}")]
+pub struct PassError {
+    pub message: String,
+    pub span: Span,
+}
+
+/// A type error, located at the innermost statement it was found in and
+/// displayed after it as `line:col: message`.
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("{span}: {message}")]
+pub struct TypeError {
+    pub message: String,
+    pub span: Span,
+}
+
+/// Why an expression has no value at compile time.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ConstEvalError {
+    #[error("'{0}' is not a constant")]
+    NotConstant(String),
+    #[error("Expected a constant expression")]
+    NotConstantExpression,
+    #[error("Overflow in constant expression")]
+    Overflow,
+    #[error("Division by zero in constant expression")]
+    DivisionByZero,
+    #[error("Invalid shift amount {0} in constant expression")]
+    InvalidShift(i64),
+}
+
+/// An error that stops objects from being linked into a program.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum LinkError {
+    #[error("Function '{0}' is defined by more than one object")]
+    DuplicateSymbol(String),
+    #[error("Undefined function '{0}'")]
+    UndefinedSymbol(String),
+    #[error("Function '{name}' is called with {expected} argument(s) but takes {found}")]
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// An object's relocation or symbol points outside its code or strings
+    #[error("Malformed object: {0}")]
+    MalformedObject(String),
+}
@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+
+use crate::compiler::parser::{
+    BinaryOpKind, Expr, ExprKind, Statement, StatementKind, UnaryOpKind,
+};
+
+/// Evaluates constant subexpressions at compile time, so `let x = 2 * 3 + 4;`
+/// compiles to a single push and `if 1 > 2 { ... }` tests a literal `false`.
+///
+/// Runs after type checking, and only folds what the VM would compute the
+/// same way: integer overflow and division by zero are left for run time.
+pub fn fold_constants(statements: &mut [Statement]) {
+    ConstantFolder::default().fold_statements(statements);
+}
+
+#[derive(Default)]
+pub(crate) struct ConstantFolder {
+    /// Values of the `const` declarations seen so far, in compilation order
+    constants: HashMap<String, i64>,
+}
+
+impl ConstantFolder {
+    fn fold_statements(&mut self, statements: &mut [Statement]) {
+        for statement in statements {
+            self.fold_statement(statement);
+        }
+    }
+
+    pub(crate) fn fold_statement(&mut self, statement: &mut Statement) {
+        match &mut statement.kind {
+            StatementKind::Const(name, expr) => {
+                self.fold_in_place(expr);
+                match expr.kind {
+                    ExprKind::Number(value) => {
+                        self.constants.insert(name.clone(), value);
+                    }
+                    ExprKind::Bool(value) => {
+                        self.constants.insert(name.clone(), value as i64);
+                    }
+                    _ => {}
+                }
+            }
+            StatementKind::Let(_, _, expr)
+            | StatementKind::Assign(_, expr)
+            | StatementKind::Assert(expr)
+            | StatementKind::Exit(expr)
+            | StatementKind::Throw(expr)
+            | StatementKind::Return(Some(expr)) => self.fold_in_place(expr),
+            StatementKind::LetTuple(_, exprs)
+            | StatementKind::AssignTuple(_, exprs)
+            | StatementKind::Print(exprs)
+            | StatementKind::PrintFormatted(_, exprs)
+            | StatementKind::Call(_, exprs) => {
+                for expr in exprs {
+                    self.fold_in_place(expr);
+                }
+            }
+            StatementKind::IndexAssign(_, index, value) => {
+                self.fold_in_place(index);
+                self.fold_in_place(value);
+            }
+            StatementKind::If(condition, then_block, else_block) => {
+                self.fold_in_place(condition);
+                self.fold_statements(then_block);
+                self.fold_statements(else_block);
+            }
+            StatementKind::While(condition, body) => {
+                self.fold_in_place(condition);
+                self.fold_statements(body);
+            }
+            StatementKind::Try(body, _, handler) => {
+                self.fold_statements(body);
+                self.fold_statements(handler);
+            }
+            StatementKind::Match(scrutinee, arms) => {
+                self.fold_in_place(scrutinee);
+                for (_, body) in arms {
+                    self.fold_statements(body);
+                }
+            }
+            StatementKind::Function(_, _, _, body) => self.fold_statements(body),
+            StatementKind::Increment(_)
+            | StatementKind::Decrement(_)
+            | StatementKind::Struct(..)
+            | StatementKind::Extern(..)
+            | StatementKind::Return(None) => {}
+        }
+    }
+
+    fn fold_in_place(&mut self, expr: &mut Expr) {
+        let owned = std::mem::replace(expr, Expr::new(ExprKind::Number(0), expr.span));
+        *expr = self.fold(owned);
+    }
+
+    /// Folds `expr`; a folded node keeps the span of the expression it replaces.
+    pub(crate) fn fold(&mut self, expr: Expr) -> Expr {
+        let span = expr.span;
+        let kind = match expr.kind {
+            ExprKind::Variable(name) => match self.constants.get(&name) {
+                Some(&value) => ExprKind::Number(value),
+                None => ExprKind::Variable(name),
+            },
+            ExprKind::UnaryOp(op, operand) => {
+                let operand = self.fold(*operand);
+                match (&op, &operand.kind) {
+                    (UnaryOpKind::Neg, ExprKind::Number(n)) if n.checked_neg().is_some() => {
+                        ExprKind::Number(-n)
+                    }
+                    (UnaryOpKind::Neg, ExprKind::Float(x)) => ExprKind::Float(-x),
+                    (UnaryOpKind::Not, _) if truthiness(&operand).is_some() => {
+                        ExprKind::Bool(!truthiness(&operand).unwrap())
+                    }
+                    _ => ExprKind::UnaryOp(op, Box::new(operand)),
+                }
+            }
+            ExprKind::BinaryOp(left, op, right) => {
+                let left = self.fold(*left);
+                let right = self.fold(*right);
+                fold_binary(&left, &op, &right)
+                    .unwrap_or_else(|| ExprKind::BinaryOp(Box::new(left), op, Box::new(right)))
+            }
+            ExprKind::Conditional(condition, then_expr, else_expr) => {
+                let condition = self.fold(*condition);
+                let then_expr = self.fold(*then_expr);
+                let else_expr = self.fold(*else_expr);
+                match truthiness(&condition) {
+                    Some(true) => then_expr.kind,
+                    Some(false) => else_expr.kind,
+                    None => ExprKind::Conditional(
+                        Box::new(condition),
+                        Box::new(then_expr),
+                        Box::new(else_expr),
+                    ),
+                }
+            }
+            ExprKind::Call(name, args) => ExprKind::Call(name, self.fold_all(args)),
+            ExprKind::Array(elements) => ExprKind::Array(self.fold_all(elements)),
+            ExprKind::ArrayRepeat(value, count) => {
+                ExprKind::ArrayRepeat(Box::new(self.fold(*value)), Box::new(self.fold(*count)))
+            }
+            ExprKind::Map(entries) => ExprKind::Map(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (self.fold(key), self.fold(value)))
+                    .collect(),
+            ),
+            ExprKind::Index(target, index) => {
+                ExprKind::Index(Box::new(self.fold(*target)), Box::new(self.fold(*index)))
+            }
+            ExprKind::StructLiteral(name, fields) => ExprKind::StructLiteral(
+                name,
+                fields
+                    .into_iter()
+                    .map(|(field, value)| (field, self.fold(value)))
+                    .collect(),
+            ),
+            ExprKind::Field(target, field) => ExprKind::Field(Box::new(self.fold(*target)), field),
+            ExprKind::Closure(id, params, return_type, mut body) => {
+                self.fold_statements(&mut body);
+                ExprKind::Closure(id, params, return_type, body)
+            }
+            kind @ (ExprKind::Number(_)
+            | ExprKind::Float(_)
+            | ExprKind::Bool(_)
+            | ExprKind::Str(_)) => kind,
+        };
+        Expr::new(kind, span)
+    }
+
+    fn fold_all(&mut self, exprs: Vec<Expr>) -> Vec<Expr> {
+        exprs.into_iter().map(|expr| self.fold(expr)).collect()
+    }
+}
+
+/// A shift amount the VM accepts, which is less than the width of a value.
+pub(crate) fn shift_amount(amount: i64) -> Option<u32> {
+    u32::try_from(amount)
+        .ok()
+        .filter(|amount| *amount < i64::BITS)
+}
+
+/// The truth value of a constant condition, the way `if` and `&&` see it.
+fn truthiness(expr: &Expr) -> Option<bool> {
+    match expr.kind {
+        ExprKind::Number(n) => Some(n != 0),
+        ExprKind::Bool(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn fold_binary(left: &Expr, op: &BinaryOpKind, right: &Expr) -> Option<ExprKind> {
+    // `&&` and `||` short-circuit, so a constant left side may decide them
+    match (op, truthiness(left)) {
+        (BinaryOpKind::And, Some(false)) => return Some(ExprKind::Bool(false)),
+        (BinaryOpKind::Or, Some(true)) => return Some(ExprKind::Bool(true)),
+        _ => {}
+    }
+    if let (ExprKind::Float(a), ExprKind::Float(b)) = (&left.kind, &right.kind) {
+        return fold_float(*a, op, *b);
+    }
+    let a = match left.kind {
+        ExprKind::Number(n) => n,
+        ExprKind::Bool(value) => value as i64,
+        _ => return None,
+    };
+    let b = match right.kind {
+        ExprKind::Number(n) => n,
+        ExprKind::Bool(value) => value as i64,
+        _ => return None,
+    };
+    Some(match op {
+        BinaryOpKind::Add => ExprKind::Number(a.checked_add(b)?),
+        BinaryOpKind::Sub => ExprKind::Number(a.checked_sub(b)?),
+        BinaryOpKind::Mul => ExprKind::Number(a.checked_mul(b)?),
+        BinaryOpKind::Div => ExprKind::Number(a.checked_div(b)?),
+        BinaryOpKind::Mod => ExprKind::Number(a.checked_rem(b)?),
+        BinaryOpKind::ShiftLeft => ExprKind::Number(a << shift_amount(b)?),
+        BinaryOpKind::ShiftRight => ExprKind::Number(a >> shift_amount(b)?),
+        BinaryOpKind::Equals => ExprKind::Bool(a == b),
+        BinaryOpKind::NotEquals => ExprKind::Bool(a != b),
+        BinaryOpKind::LessThan => ExprKind::Bool(a < b),
+        BinaryOpKind::GreaterThan => ExprKind::Bool(a > b),
+        BinaryOpKind::LessEqual => ExprKind::Bool(a <= b),
+        BinaryOpKind::GreaterEqual => ExprKind::Bool(a >= b),
+        BinaryOpKind::And => ExprKind::Bool(a != 0 && b != 0),
+        BinaryOpKind::Or => ExprKind::Bool(a != 0 || b != 0),
+    })
+}
+
+fn fold_float(a: f64, op: &BinaryOpKind, b: f64) -> Option<ExprKind> {
+    Some(match op {
+        BinaryOpKind::Add => ExprKind::Float(a + b),
+        BinaryOpKind::Sub => ExprKind::Float(a - b),
+        BinaryOpKind::Mul => ExprKind::Float(a * b),
+        BinaryOpKind::Div => ExprKind::Float(a / b),
+        BinaryOpKind::Mod => ExprKind::Float(a % b),
+        BinaryOpKind::Equals => ExprKind::Bool(a == b),
+        BinaryOpKind::NotEquals => ExprKind::Bool(a != b),
+        BinaryOpKind::LessThan => ExprKind::Bool(a < b),
+        BinaryOpKind::GreaterThan => ExprKind::Bool(a > b),
+        BinaryOpKind::LessEqual => ExprKind::Bool(a <= b),
+        BinaryOpKind::GreaterEqual => ExprKind::Bool(a >= b),
+        BinaryOpKind::ShiftLeft
+        | BinaryOpKind::ShiftRight
+        | BinaryOpKind::And
+        | BinaryOpKind::Or => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fold_constants;
+    use crate::compiler::parser::{Expr, ExprKind, Parser, Statement, StatementKind};
+
+    fn folded(code: &str) -> Vec<Statement> {
+        let mut statements = Parser::new(code).parse_program().unwrap();
+        fold_constants(&mut statements);
+        statements
+    }
+
+    #[test]
+    fn folds_constant_expressions() {
+        let statements = folded(
+            "const N = 4;
+             let x = 2 * 3 + N;
+             let f = 1.5 * 2.0;
+             let b = !(1 > 2) && 3 <= 3;
+             let c = N > 2 ? 10 : 20;
+             if 1 == 2 { print x; }",
+        );
+        assert!(matches!(
+            statements[1].kind,
+            StatementKind::Let(
+                _,
+                _,
+                Expr {
+                    kind: ExprKind::Number(10),
+                    ..
+                }
+            )
+        ));
+        assert!(
+            matches!(statements[2].kind, StatementKind::Let(_, _, Expr { kind: ExprKind::Float(f), .. }) if f == 3.0)
+        );
+        assert!(matches!(
+            statements[3].kind,
+            StatementKind::Let(
+                _,
+                _,
+                Expr {
+                    kind: ExprKind::Bool(true),
+                    ..
+                }
+            )
+        ));
+        assert!(matches!(
+            statements[4].kind,
+            StatementKind::Let(
+                _,
+                _,
+                Expr {
+                    kind: ExprKind::Number(10),
+                    ..
+                }
+            )
+        ));
+        assert!(matches!(
+            statements[5].kind,
+            StatementKind::If(
+                Expr {
+                    kind: ExprKind::Bool(false),
+                    ..
+                },
+                _,
+                _
+            )
+        ));
+    }
+
+    #[test]
+    fn leaves_run_time_behavior_alone() {
+        let statements = folded(
+            "let y = 1;
+             let a = 1 / 0;
+             let b = 9223372036854775807 + 1;
+             let c = y + 2 * 3;
+             let d = true && y;
+             let e = false && y;",
+        );
+        assert!(matches!(
+            statements[1].kind,
+            StatementKind::Let(
+                _,
+                _,
+                Expr {
+                    kind: ExprKind::BinaryOp(..),
+                    ..
+                }
+            )
+        ));
+        assert!(matches!(
+            statements[2].kind,
+            StatementKind::Let(
+                _,
+                _,
+                Expr {
+                    kind: ExprKind::BinaryOp(..),
+                    ..
+                }
+            )
+        ));
+        assert!(matches!(&statements[3].kind,
+            StatementKind::Let(_, _, Expr { kind: ExprKind::BinaryOp(_, _, right), .. }) if matches!(**right, Expr { kind: ExprKind::Number(6), .. })
+        ));
+        assert!(matches!(
+            statements[4].kind,
+            StatementKind::Let(
+                _,
+                _,
+                Expr {
+                    kind: ExprKind::BinaryOp(..),
+                    ..
+                }
+            )
+        ));
+        assert!(matches!(
+            statements[5].kind,
+            StatementKind::Let(
+                _,
+                _,
+                Expr {
+                    kind: ExprKind::Bool(false),
+                    ..
+                }
+            )
+        ));
+    }
+}
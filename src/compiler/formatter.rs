@@ -0,0 +1,699 @@
+use std::fmt::Display;
+
+use crate::compiler::{
+    error::ParseError,
+    lexer::{Comment, Lexer, SpannedToken, Token},
+    operators::{
+        binary_precedence,
+        precedence::{CONDITIONAL, POSTFIX, PREFIX},
+    },
+    parser::{
+        BinaryOpKind, Expr, ExprKind, MatchPattern, Param, Parser, Statement, StatementKind, Type,
+        UnaryOpKind,
+    },
+};
+
+const INDENT: &str = "    ";
+
+/// Reprints a program in the canonical style: one statement per line,
+/// four-space indentation, spaces around binary operators and braces around
+/// every block. Comments are kept, on their own line before the statement
+/// that follows them or at the end of the line they trail, and single blank
+/// lines between statements are preserved. Literals keep their source
+/// spelling, so `0xFF` and `'a'` are not rewritten as decimals.
+pub fn format(source: &str) -> Result<String, Vec<ParseError>> {
+    let mut parser = Parser::new(source);
+    parser.parse_imports().map_err(|error| vec![error])?;
+    let statements = parser.parse_program()?;
+
+    let mut lexer = Lexer::new(source);
+    // The source parsed, so every token is well-formed
+    let tokens: Vec<SpannedToken> = lexer.by_ref().filter_map(Result::ok).collect();
+    let mut formatter = Formatter {
+        source,
+        tokens,
+        comments: lexer.comments().to_vec(),
+        next_comment: 0,
+        out: String::new(),
+        indent: 0,
+        last_end: None,
+    };
+    formatter.imports();
+    for statement in &statements {
+        formatter.statement(statement);
+    }
+    formatter.comments_before(usize::MAX);
+    Ok(formatter.out)
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    /// Every token of the source, to find the braces and keywords the AST
+    /// does not record
+    tokens: Vec<SpannedToken>,
+    comments: Vec<Comment>,
+    /// Index of the first comment not yet printed
+    next_comment: usize,
+    out: String,
+    indent: usize,
+    /// Byte offset where the last printed line ended in the source; `None`
+    /// at the start of a block
+    last_end: Option<usize>,
+}
+
+impl Formatter<'_> {
+    fn write(&mut self, text: impl Display) {
+        self.out.push_str(&text.to_string());
+    }
+
+    fn write_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    /// Keeps one blank line where the source has any before `start`.
+    fn blank_line_before(&mut self, start: usize) {
+        let Some(last) = self.last_end else {
+            return;
+        };
+        let gap = self.source.get(last..start).unwrap_or("");
+        if gap.matches('\n').count() >= 2 {
+            self.out.push('\n');
+        }
+    }
+
+    /// Prints the comments that start before `limit`, each on its own line.
+    fn comments_before(&mut self, limit: usize) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.span.offset >= limit {
+                break;
+            }
+            let comment = comment.clone();
+            self.next_comment += 1;
+            self.blank_line_before(comment.span.offset);
+            self.write_indent();
+            self.write(&comment.text);
+            self.out.push('\n');
+            self.last_end = Some(comment.span.end());
+        }
+    }
+
+    /// Starts the line for a statement covering `start` in the source,
+    /// printing the comments before it first.
+    fn begin_line(&mut self, start: usize) {
+        self.comments_before(start);
+        self.blank_line_before(start);
+        self.write_indent();
+    }
+
+    /// Ends the line for a statement ending at `end`, keeping a comment that
+    /// follows it on the same source line.
+    fn end_line(&mut self, end: usize) {
+        let mut last_end = end;
+        if let Some(comment) = self.comments.get(self.next_comment) {
+            let between = self.source.get(end..comment.span.offset).unwrap_or("\n");
+            if comment.span.offset >= end && !between.contains('\n') && between.trim().is_empty() {
+                self.out.push(' ');
+                self.out.push_str(&comment.text);
+                last_end = comment.span.end();
+                self.next_comment += 1;
+            }
+        }
+        self.out.push('\n');
+        self.last_end = Some(last_end);
+    }
+
+    /// Index of the first token at or after byte `offset`.
+    fn token_at(&self, offset: usize) -> usize {
+        self.tokens
+            .partition_point(|token| token.span.offset < offset)
+    }
+
+    /// Index of the first `token` at or after byte `offset`.
+    fn find(&self, offset: usize, token: &Token) -> Option<usize> {
+        let start = self.token_at(offset);
+        (start..self.tokens.len()).find(|&i| &self.tokens[i].token == token)
+    }
+
+    /// Offset of the `}` closing the block that starts at `offset`, or
+    /// `None` when the block is a single statement without braces.
+    fn block_close(&self, offset: usize) -> Option<usize> {
+        let open = self.token_at(offset);
+        if self.tokens.get(open)?.token != Token::LBrace {
+            return None;
+        }
+        self.matching_brace(open)
+    }
+
+    fn matching_brace(&self, open: usize) -> Option<usize> {
+        let mut depth = 0;
+        for token in &self.tokens[open..] {
+            match token.token {
+                Token::LBrace => depth += 1,
+                Token::RBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(token.span.offset);
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Offset of the `}` closing the first braced block at or after `offset`.
+    fn next_block_close(&self, offset: usize) -> Option<usize> {
+        self.find(offset, &Token::LBrace)
+            .and_then(|open| self.matching_brace(open))
+    }
+
+    fn token_text(&self, index: usize) -> &str {
+        self.tokens[index].span.text(self.source)
+    }
+
+    /// Prints the `import "path";` statements the program starts with.
+    fn imports(&mut self) {
+        let mut i = 0;
+        while self.tokens.get(i).map(|t| &t.token) == Some(&Token::Import) {
+            self.begin_line(self.tokens[i].span.offset);
+            self.write(format!("import {};", self.token_text(i + 1)));
+            self.end_line(self.tokens[i + 2].span.end());
+            i += 3;
+        }
+    }
+
+    /// Prints a block's braces and statements. `close` is the offset of its
+    /// `}` in the source, used to keep the comments before it inside.
+    fn block(&mut self, statements: &[Statement], close: Option<usize>) {
+        let has_comments = close.is_some_and(|close| {
+            self.comments
+                .get(self.next_comment)
+                .is_some_and(|comment| comment.span.offset < close)
+        });
+        if statements.is_empty() && !has_comments {
+            self.write("{}");
+            return;
+        }
+        self.write("{\n");
+        self.indent += 1;
+        self.last_end = None;
+        for statement in statements {
+            self.statement(statement);
+        }
+        if let Some(close) = close {
+            self.comments_before(close);
+        }
+        self.indent -= 1;
+        self.write_indent();
+        self.write("}");
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        let span = statement.span;
+        self.begin_line(span.offset);
+        self.statement_kind(statement);
+        self.end_line(span.end());
+    }
+
+    fn statement_kind(&mut self, statement: &Statement) {
+        let start = statement.span.offset;
+        match &statement.kind {
+            StatementKind::Let(name, annotation, expr) => {
+                self.write(format!("let {}", name));
+                if let Some(ty) = annotation {
+                    self.write(format!(": {}", ty));
+                }
+                self.write(" = ");
+                self.expr(expr, 0);
+                self.write(";");
+            }
+            StatementKind::Const(name, expr) => {
+                self.write(format!("const {} = ", name));
+                self.expr(expr, 0);
+                self.write(";");
+            }
+            StatementKind::Assign(name, expr) => {
+                self.write(format!("{} = ", name));
+                self.expr(expr, 0);
+                self.write(";");
+            }
+            StatementKind::LetTuple(names, values) => {
+                self.write(format!("let ({}) = (", names.join(", ")));
+                self.list(values);
+                self.write(");");
+            }
+            StatementKind::AssignTuple(names, values) => {
+                self.write(format!("({}) = (", names.join(", ")));
+                self.list(values);
+                self.write(");");
+            }
+            StatementKind::IndexAssign(name, index, value) => {
+                self.write(format!("{}[", name));
+                self.expr(index, 0);
+                self.write("] = ");
+                self.expr(value, 0);
+                self.write(";");
+            }
+            StatementKind::Increment(name) => self.write(format!("{}++;", name)),
+            StatementKind::Decrement(name) => self.write(format!("{}--;", name)),
+            StatementKind::If(..) => self.if_statement(statement),
+            StatementKind::While(condition, body) => {
+                self.write("while ");
+                self.expr(condition, 0);
+                self.write(" ");
+                let close = self.block_close(condition.span.end());
+                self.block(body, close);
+            }
+            StatementKind::Print(values) => {
+                self.write("print ");
+                self.list(values);
+                self.write(";");
+            }
+            StatementKind::PrintFormatted(_, args) => {
+                let format = self.find(start, &Token::LParen).unwrap() + 1;
+                let format = self.token_text(format).to_string();
+                self.write(format!("print({}", format));
+                for arg in args {
+                    self.write(", ");
+                    self.expr(arg, 0);
+                }
+                self.write(");");
+            }
+            StatementKind::Assert(expr) => {
+                self.write("assert ");
+                self.expr(expr, 0);
+                self.write(";");
+            }
+            StatementKind::Exit(code) => {
+                self.write("exit(");
+                self.expr(code, 0);
+                self.write(");");
+            }
+            StatementKind::Throw(value) => {
+                self.write("throw ");
+                self.expr(value, 0);
+                self.write(";");
+            }
+            StatementKind::Try(body, name, handler) => {
+                self.write("try ");
+                let close = self.next_block_close(start);
+                self.block(body, close);
+                self.write(format!(" catch ({}) ", name));
+                let close = close.and_then(|close| self.next_block_close(close + 1));
+                self.block(handler, close);
+            }
+            StatementKind::Struct(name, fields) => {
+                if fields.is_empty() {
+                    self.write(format!("struct {} {{}}", name));
+                } else {
+                    self.write(format!("struct {} {{ {} }}", name, fields.join(", ")));
+                }
+            }
+            StatementKind::Match(scrutinee, arms) => {
+                self.write("match ");
+                self.expr(scrutinee, 0);
+                self.write(" {\n");
+                self.indent += 1;
+                self.last_end = None;
+                let mut offset = scrutinee.span.end();
+                for (pattern, body) in arms {
+                    let arrow = self.find(offset, &Token::FatArrow).unwrap();
+                    self.pattern(pattern, arrow);
+                    self.write(" => ");
+                    let arrow_end = self.tokens[arrow].span.end();
+                    let close = self.block_close(arrow_end);
+                    self.block(body, close);
+                    offset = match close {
+                        Some(close) => close + 1,
+                        None => body.last().map_or(arrow_end, |s| s.span.end()),
+                    };
+                    self.end_line(offset);
+                }
+                self.comments_before(statement.span.end());
+                self.indent -= 1;
+                self.write_indent();
+                self.write("}");
+            }
+            StatementKind::Function(name, params, result, body) => {
+                self.write(format!("fn {}", name));
+                self.signature(params, result);
+                self.write(" ");
+                let close = self.next_block_close(start);
+                self.block(body, close);
+            }
+            StatementKind::Extern(name, params, result) => {
+                self.write(format!("extern fn {}", name));
+                self.signature(params, result);
+                self.write(";");
+            }
+            StatementKind::Return(None) => self.write("return;"),
+            StatementKind::Return(Some(value)) => {
+                self.write("return ");
+                self.expr(value, 0);
+                self.write(";");
+            }
+            StatementKind::Call(name, args) => {
+                self.write(format!("{}(", name));
+                self.list(args);
+                self.write(");");
+            }
+        }
+    }
+
+    /// Prints `if`, keeping `else if` chains flat where the source has them.
+    fn if_statement(&mut self, statement: &Statement) {
+        let StatementKind::If(condition, then_block, else_block) = &statement.kind else {
+            unreachable!()
+        };
+        self.write("if ");
+        self.expr(condition, 0);
+        self.write(" ");
+        let condition_end = condition.span.end();
+        let then_close = self.block_close(condition_end);
+        self.block(then_block, then_close);
+        if else_block.is_empty() {
+            return;
+        }
+        let then_end = then_close.map_or_else(
+            || then_block.last().map_or(condition_end, |s| s.span.end()),
+            |close| close + 1,
+        );
+        let Some(else_token) = self.find(then_end, &Token::Else) else {
+            return;
+        };
+        let else_close = self.block_close(self.tokens[else_token].span.end());
+        self.write(" else ");
+        match else_block.as_slice() {
+            [nested] if else_close.is_none() && matches!(nested.kind, StatementKind::If(..)) => {
+                self.if_statement(nested)
+            }
+            _ => self.block(else_block, else_close),
+        }
+    }
+
+    fn signature(&mut self, params: &[Param], result: &Option<Type>) {
+        let params: Vec<String> = params
+            .iter()
+            .map(|(name, ty)| match ty {
+                Some(ty) => format!("{}: {}", name, ty),
+                None => name.clone(),
+            })
+            .collect();
+        self.write(format!("({})", params.join(", ")));
+        if let Some(result) = result {
+            self.write(format!(" -> {}", result));
+        }
+    }
+
+    /// Starts the line of the arm whose pattern ends before the `=>` at
+    /// `arrow` and writes the pattern, literals as spelled in the source.
+    fn pattern(&mut self, pattern: &MatchPattern, arrow: usize) {
+        let text = match pattern {
+            MatchPattern::Wildcard => "_".to_string(),
+            MatchPattern::Number(_) => {
+                let literal = self.token_text(arrow - 1);
+                match self.tokens[arrow - 2].token {
+                    Token::Minus => format!("-{}", literal),
+                    _ => literal.to_string(),
+                }
+            }
+            MatchPattern::Const(expr) => {
+                self.begin_line(expr.span.offset);
+                self.expr(expr, 0);
+                return;
+            }
+        };
+        self.begin_line(self.tokens[arrow - 1].span.end() - text.len());
+        self.write(text);
+    }
+
+    fn list(&mut self, exprs: &[Expr]) {
+        for (i, expr) in exprs.iter().enumerate() {
+            if i > 0 {
+                self.write(", ");
+            }
+            self.expr(expr, 0);
+        }
+    }
+
+    /// Prints an expression, parenthesized if it binds more loosely than
+    /// `min_precedence`.
+    fn expr(&mut self, expr: &Expr, min_precedence: u8) {
+        let parenthesize = precedence(expr) < min_precedence;
+        if parenthesize {
+            self.write("(");
+        }
+        match &expr.kind {
+            ExprKind::Number(n) => self.literal(expr, n),
+            ExprKind::Float(x) => self.literal(expr, format!("{:?}", x)),
+            ExprKind::Str(s) => self.literal(expr, format!("{:?}", s)),
+            ExprKind::Bool(value) => self.write(value),
+            ExprKind::Variable(name) => self.write(name),
+            ExprKind::UnaryOp(op, operand) => {
+                self.write(match op {
+                    UnaryOpKind::Not => "!",
+                    UnaryOpKind::Neg => "-",
+                });
+                // `- -x` must not become `--x`
+                let negative = matches!(op, UnaryOpKind::Neg) && starts_with_minus(operand);
+                self.expr(operand, if negative { u8::MAX } else { PREFIX });
+            }
+            ExprKind::BinaryOp(left, op, right) => {
+                let precedence = binary_precedence(op);
+                self.expr(left, precedence);
+                self.write(format!(" {} ", operator(op)));
+                self.expr(right, precedence + 1);
+            }
+            ExprKind::Conditional(condition, then_expr, else_expr) => {
+                self.expr(condition, CONDITIONAL + 1);
+                self.write(" ? ");
+                self.expr(then_expr, 0);
+                self.write(" : ");
+                self.expr(else_expr, CONDITIONAL);
+            }
+            ExprKind::Call(name, args) => {
+                self.write(format!("{}(", name));
+                self.list(args);
+                self.write(")");
+            }
+            ExprKind::Array(elements) => {
+                self.write("[");
+                self.list(elements);
+                self.write("]");
+            }
+            ExprKind::ArrayRepeat(value, count) => {
+                self.write("[");
+                self.expr(value, 0);
+                self.write("; ");
+                self.expr(count, 0);
+                self.write("]");
+            }
+            ExprKind::Map(entries) => {
+                self.write("{");
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        self.write(", ");
+                    }
+                    self.expr(key, 0);
+                    self.write(": ");
+                    self.expr(value, 0);
+                }
+                self.write("}");
+            }
+            ExprKind::Index(target, index) => {
+                self.expr(target, POSTFIX);
+                self.write("[");
+                self.expr(index, 0);
+                self.write("]");
+            }
+            ExprKind::StructLiteral(name, fields) => {
+                if fields.is_empty() {
+                    self.write(format!("{} {{}}", name));
+                } else {
+                    self.write(format!("{} {{ ", name));
+                    for (i, (field, value)) in fields.iter().enumerate() {
+                        if i > 0 {
+                            self.write(", ");
+                        }
+                        self.write(format!("{}: ", field));
+                        self.expr(value, 0);
+                    }
+                    self.write(" }");
+                }
+            }
+            ExprKind::Field(target, field) => {
+                self.expr(target, POSTFIX);
+                self.write(format!(".{}", field));
+            }
+            ExprKind::Closure(_, params, result, body) => {
+                self.write("fn");
+                self.signature(params, result);
+                self.write(" ");
+                let close = self.next_block_close(expr.span.offset);
+                self.block(body, close);
+            }
+        }
+        if parenthesize {
+            self.write(")");
+        }
+    }
+
+    /// Prints a literal as written in the source, falling back to `value`
+    /// when the source spelling cannot be reused.
+    fn literal(&mut self, expr: &Expr, value: impl Display) {
+        let mut text = expr.span.text(self.source).trim();
+        // A parenthesized literal's span covers the parentheses
+        while text.starts_with('(') && text.ends_with(')') {
+            text = text[1..text.len() - 1].trim();
+        }
+        let is_number = !matches!(expr.kind, ExprKind::Str(_));
+        if text.is_empty() || (is_number && text.contains('/')) {
+            self.write(value);
+        } else if let Some(digits) = text.strip_prefix('-') {
+            self.write(format!("-{}", digits.trim_start()));
+        } else {
+            self.write(text);
+        }
+    }
+}
+
+fn precedence(expr: &Expr) -> u8 {
+    match &expr.kind {
+        ExprKind::Conditional(..) => CONDITIONAL,
+        ExprKind::BinaryOp(_, op, _) => binary_precedence(op),
+        ExprKind::UnaryOp(..) => PREFIX,
+        _ if starts_with_minus(expr) => PREFIX,
+        _ => POSTFIX,
+    }
+}
+
+/// Whether the expression prints with a leading `-`.
+fn starts_with_minus(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Number(n) => *n < 0,
+        ExprKind::Float(x) => x.is_sign_negative(),
+        ExprKind::UnaryOp(UnaryOpKind::Neg, _) => true,
+        _ => false,
+    }
+}
+
+fn operator(op: &BinaryOpKind) -> &'static str {
+    match op {
+        BinaryOpKind::Add => "+",
+        BinaryOpKind::Sub => "-",
+        BinaryOpKind::Mul => "*",
+        BinaryOpKind::Div => "/",
+        BinaryOpKind::Mod => "%",
+        BinaryOpKind::Equals => "==",
+        BinaryOpKind::NotEquals => "!=",
+        BinaryOpKind::LessThan => "<",
+        BinaryOpKind::GreaterThan => ">",
+        BinaryOpKind::LessEqual => "<=",
+        BinaryOpKind::GreaterEqual => ">=",
+        BinaryOpKind::ShiftLeft => "<<",
+        BinaryOpKind::ShiftRight => ">>",
+        BinaryOpKind::And => "&&",
+        BinaryOpKind::Or => "||",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+    use crate::compiler::prelude;
+
+    #[test]
+    fn formats_canonically() {
+        let source = "let  x=1+2*3 ;let y = (1+2)*3;
+fn add(a:int,b)->int{return a+b;}
+if x>1 print x; else if x<0 {print -x;} else { }
+while x { x--; }
+let f = fn(n) { return n ? 0xFF : 'a'; };
+match x { 1 => { print 1; }, -2 => print 2; N+1=>{} _ => {} }
+try{throw 1;}catch(e){print e;}
+struct P{a,b}
+let p = P{a:1,b:-(-1)};
+print(\"{} {}\", p.a, [1, 2][0]);
+let a=[0;N* 2];";
+        assert_eq!(
+            format(source).unwrap(),
+            "let x = 1 + 2 * 3;
+let y = (1 + 2) * 3;
+fn add(a: int, b) -> int {
+    return a + b;
+}
+if x > 1 {
+    print x;
+} else if x < 0 {
+    print -x;
+}
+while x {
+    x--;
+}
+let f = fn(n) {
+    return n ? 0xFF : 'a';
+};
+match x {
+    1 => {
+        print 1;
+    }
+    -2 => {
+        print 2;
+    }
+    N + 1 => {}
+    _ => {}
+}
+try {
+    throw 1;
+} catch (e) {
+    print e;
+}
+struct P { a, b }
+let p = P { a: 1, b: -(-1) };
+print(\"{} {}\", p.a, [1, 2][0]);
+let a = [0; N * 2];
+"
+        );
+    }
+
+    #[test]
+    fn keeps_comments_and_blank_lines() {
+        let source = "// Header
+
+let a = 1; // one
+/* block */ let b = 2;
+
+
+fn f() {
+    // inside
+    return a; // trailing
+    // before close
+}
+// end";
+        assert_eq!(
+            format(source).unwrap(),
+            "// Header
+
+let a = 1; // one
+/* block */
+let b = 2;
+
+fn f() {
+    // inside
+    return a; // trailing
+    // before close
+}
+// end
+"
+        );
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let once = format(prelude::SOURCE).unwrap();
+        assert_eq!(format(&once).unwrap(), once);
+        assert!(format("let x = ;").is_err());
+    }
+}
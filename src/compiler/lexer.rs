@@ -1,41 +1,203 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::compiler::{error::LexError, span::Span};
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
-    Number(i64),
+    /// An unsigned integer literal; a leading `-` is a separate token so
+    /// that `-9223372036854775808` can be recognized by the parser
+    Number(u64),
+    Float(f64),
+    Str(String),
+    /// A character literal such as `'a'`
+    Char(char),
     Plus,
+    PlusPlus,
     Minus,
+    MinusMinus,
     Star,
     Slash,
+    Percent,
     LParen,
     RParen,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
     Semicolon,
+    Comma,
+    Colon,
+    Question,
+    Dot,
     Equals,
     Identifier(String),
     Let,
+    Const,
     If,
     Else,
     While,
     Print,
+    Assert,
+    Exit,
+    Throw,
+    Try,
+    Catch,
+    Struct,
+    Match,
+    Fn,
+    Return,
+    Import,
+    Extern,
+    True,
+    False,
+    Bang,
+    AndAnd,
+    OrOr,
     DoubleEquals,
+    FatArrow,
+    Arrow,
+    NotEquals,
     LessThan,
     GreaterThan,
     LessEqual,
     GreaterEqual,
+    ShiftLeft,
+    ShiftRight,
+    /// A malformed token, carrying the problem with it
+    Invalid(LexError),
+}
+
+/// Formats the token as it appears in source, for error messages.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            Token::Number(n) => return write!(f, "number {}", n),
+            Token::Float(x) => return write!(f, "number {}", x),
+            Token::Str(s) => return write!(f, "string {:?}", s),
+            Token::Char(ch) => return write!(f, "character {:?}", ch),
+            Token::Identifier(name) => return write!(f, "identifier '{}'", name),
+            Token::Invalid(error) => return write!(f, "{}", error),
+            Token::Plus => "+",
+            Token::PlusPlus => "++",
+            Token::Minus => "-",
+            Token::MinusMinus => "--",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::Percent => "%",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::Semicolon => ";",
+            Token::Comma => ",",
+            Token::Colon => ":",
+            Token::Question => "?",
+            Token::Dot => ".",
+            Token::Equals => "=",
+            Token::Let => "let",
+            Token::Const => "const",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::While => "while",
+            Token::Print => "print",
+            Token::Assert => "assert",
+            Token::Exit => "exit",
+            Token::Throw => "throw",
+            Token::Try => "try",
+            Token::Catch => "catch",
+            Token::Struct => "struct",
+            Token::Match => "match",
+            Token::Fn => "fn",
+            Token::Return => "return",
+            Token::Import => "import",
+            Token::Extern => "extern",
+            Token::True => "true",
+            Token::False => "false",
+            Token::Bang => "!",
+            Token::AndAnd => "&&",
+            Token::OrOr => "||",
+            Token::DoubleEquals => "==",
+            Token::FatArrow => "=>",
+            Token::Arrow => "->",
+            Token::NotEquals => "!=",
+            Token::LessThan => "<",
+            Token::GreaterThan => ">",
+            Token::LessEqual => "<=",
+            Token::GreaterEqual => ">=",
+            Token::ShiftLeft => "<<",
+            Token::ShiftRight => ">>",
+        };
+        write!(f, "'{}'", text)
+    }
+}
+
+/// A token with the source it was read from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+/// A `// line` or `/* block */` comment, with its delimiters. A line
+/// comment does not include the newline ending it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Comment {
+    pub text: String,
+    pub span: Span,
 }
 
+/// Reads the tokens of a source, as an iterator of tokens with their spans
+/// that yields an error for each malformed token and carries on after it.
+/// Tokens can be looked at ahead of time with `peek` and `peek_n`.
 pub struct Lexer {
     input: Vec<char>,
     position: usize,
+    /// Line, column and byte offset of `position`
+    line: usize,
+    col: usize,
+    offset: usize,
+    /// Comments skipped so far, in source order
+    comments: Vec<Comment>,
+    /// Tokens read ahead by `peek_n`, with their spans, in source order
+    lookahead: VecDeque<(Result<SpannedToken, LexError>, Span)>,
+    /// Span of the last token or error returned
+    last_span: Span,
+    /// The file id every span is given
+    file: usize,
 }
 
 impl Lexer {
     pub fn new(input: &str) -> Self {
+        Self::with_file(input, 0)
+    }
+
+    /// A lexer whose spans are in file `file` of a program's file table.
+    pub fn with_file(input: &str, file: usize) -> Self {
         Lexer {
             input: input.chars().collect(),
             position: 0,
+            line: 1,
+            col: 1,
+            offset: 0,
+            comments: Vec::new(),
+            lookahead: VecDeque::new(),
+            last_span: Span {
+                file,
+                ..Span::default()
+            },
+            file,
         }
     }
 
-    fn peek(&self) -> Option<char> {
+    /// The comments in the source read so far; tokens never include them.
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
+    fn peek_char(&self) -> Option<char> {
         self.input.get(self.position).copied()
     }
 
@@ -43,6 +205,13 @@ impl Lexer {
         if self.position < self.input.len() {
             let ch = self.input[self.position];
             self.position += 1;
+            self.offset += ch.len_utf8();
+            if ch == '\n' {
+                self.line += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
             Some(ch)
         } else {
             None
@@ -50,7 +219,7 @@ impl Lexer {
     }
 
     fn skip_whitespace(&mut self) {
-        while let Some(ch) = self.peek() {
+        while let Some(ch) = self.peek_char() {
             if !ch.is_whitespace() {
                 break;
             }
@@ -58,21 +227,244 @@ impl Lexer {
         }
     }
 
-    fn read_number(&mut self) -> Token {
-        let mut number = String::new();
-        while let Some(ch) = self.peek() {
-            if !ch.is_ascii_digit() {
+    fn peek_next_char(&self) -> Option<char> {
+        self.input.get(self.position + 1).copied()
+    }
+
+    /// Skips whitespace, `// line` comments and (possibly nested) `/* block */`
+    /// comments. Returns the span of a block comment left open at the end of
+    /// input.
+    fn skip_trivia(&mut self) -> Option<Span> {
+        loop {
+            self.skip_whitespace();
+            let (start, start_position) = (self.location(), self.position);
+            let closed = match (self.peek_char(), self.peek_next_char()) {
+                (Some('/'), Some('/')) => {
+                    self.skip_line_comment();
+                    true
+                }
+                (Some('/'), Some('*')) => self.skip_block_comment(),
+                _ => return None,
+            };
+            let span = start.to(self.location());
+            self.comments.push(Comment {
+                text: self.input[start_position..self.position].iter().collect(),
+                span,
+            });
+            if !closed {
+                return Some(span);
+            }
+        }
+    }
+
+    fn skip_line_comment(&mut self) {
+        while self.peek_char().is_some_and(|ch| ch != '\n') {
+            self.advance();
+        }
+    }
+
+    /// Skips a block comment, returning whether it was closed before the
+    /// end of input.
+    fn skip_block_comment(&mut self) -> bool {
+        // Consume the opening "/*"
+        self.advance();
+        self.advance();
+        let mut depth = 1;
+        while depth > 0 {
+            match (self.peek_char(), self.peek_next_char()) {
+                (Some('/'), Some('*')) => {
+                    self.advance();
+                    self.advance();
+                    depth += 1;
+                }
+                (Some('*'), Some('/')) => {
+                    self.advance();
+                    self.advance();
+                    depth -= 1;
+                }
+                (Some(_), _) => {
+                    self.advance();
+                }
+                (None, _) => return false,
+            }
+        }
+        true
+    }
+
+    fn read_alphanumeric_run(&mut self, literal: &mut String) {
+        while let Some(ch) = self.peek_char() {
+            if !ch.is_ascii_alphanumeric() && ch != '_' {
                 break;
             }
-            number.push(ch);
+            literal.push(ch);
             self.advance();
         }
-        Token::Number(number.parse().unwrap())
+    }
+
+    /// Reads a decimal, `0x` hexadecimal or `0b` binary literal, or a decimal
+    /// float such as `1.5` or `2.5e-3`. Digits may be separated by `_`, as in
+    /// `1_000_000`.
+    fn read_number(&mut self) -> Token {
+        let mut literal = String::new();
+        self.read_alphanumeric_run(&mut literal);
+
+        let is_decimal = !matches!(literal.get(..2), Some("0x" | "0X" | "0b" | "0B"));
+        if is_decimal {
+            let mut is_float = false;
+            if self.peek_char() == Some('.')
+                && self.peek_next_char().is_some_and(|ch| ch.is_ascii_digit())
+            {
+                is_float = true;
+                literal.push('.');
+                self.advance();
+                self.read_alphanumeric_run(&mut literal);
+            }
+            if literal.ends_with(['e', 'E']) && matches!(self.peek_char(), Some('+' | '-')) {
+                literal.push(self.advance().unwrap());
+                self.read_alphanumeric_run(&mut literal);
+            }
+            if is_float || literal.contains(['e', 'E']) {
+                let digits: String = literal.chars().filter(|&ch| ch != '_').collect();
+                return match digits.parse() {
+                    Ok(value) => Token::Float(value),
+                    Err(_) => Token::Invalid(LexError::MalformedFloat(literal)),
+                };
+            }
+        }
+
+        let (digits, radix) = match literal.get(..2) {
+            Some("0x") | Some("0X") => (&literal[2..], 16),
+            Some("0b") | Some("0B") => (&literal[2..], 2),
+            _ => (literal.as_str(), 10),
+        };
+        let digits: String = digits.chars().filter(|&ch| ch != '_').collect();
+        if digits.is_empty() || !digits.chars().all(|ch| ch.is_digit(radix)) {
+            return Token::Invalid(LexError::MalformedInteger(literal));
+        }
+        match u64::from_str_radix(&digits, radix) {
+            Ok(value) => Token::Number(value),
+            Err(_) => Token::Invalid(LexError::IntegerOutOfRange(literal)),
+        }
+    }
+
+    /// Reads a string literal, decoding the `\n`, `\t`, `\"`, `\'`, `\\`
+    /// and `\u{...}` escapes. A malformed escape makes the whole literal invalid,
+    /// but the literal is still consumed up to its closing quote.
+    fn read_string(&mut self) -> Token {
+        // Consume the opening quote
+        self.advance();
+        let mut value = String::new();
+        let mut error = None;
+        loop {
+            match self.advance() {
+                Some('"') => break,
+                Some('\\') => match self.read_escape() {
+                    Ok(ch) => value.push(ch),
+                    Err(message) => {
+                        error.get_or_insert(message);
+                    }
+                },
+                Some(ch) => value.push(ch),
+                None => return Token::Invalid(LexError::UnterminatedString),
+            }
+        }
+        match error {
+            Some(message) => Token::Invalid(message),
+            None => Token::Str(value),
+        }
+    }
+
+    /// Reads a character literal holding exactly one character or escape
+    /// sequence.
+    fn read_char(&mut self) -> Token {
+        // Consume the opening quote
+        self.advance();
+        let value = match self.advance() {
+            Some('\'') => return Token::Invalid(LexError::EmptyCharacter),
+            Some('\\') => self.read_escape(),
+            Some(ch) => Ok(ch),
+            None => return Token::Invalid(LexError::UnterminatedCharacter),
+        };
+        if self.peek_char() != Some('\'') {
+            // Skip the rest of the literal, which must not span lines
+            while let Some(ch) = self.peek_char() {
+                if ch == '\'' || ch == '\n' {
+                    break;
+                }
+                self.advance();
+            }
+            if self.advance() != Some('\'') {
+                return Token::Invalid(LexError::UnterminatedCharacter);
+            }
+            return Token::Invalid(LexError::MultipleCharacters);
+        }
+        self.advance();
+        match value {
+            Ok(ch) => Token::Char(ch),
+            Err(message) => Token::Invalid(message),
+        }
+    }
+
+    /// Decodes the escape sequence following a backslash.
+    fn read_escape(&mut self) -> Result<char, LexError> {
+        match self.peek_char() {
+            Some('n') => {
+                self.advance();
+                Ok('\n')
+            }
+            Some('t') => {
+                self.advance();
+                Ok('\t')
+            }
+            Some('"') => {
+                self.advance();
+                Ok('"')
+            }
+            Some('\'') => {
+                self.advance();
+                Ok('\'')
+            }
+            Some('\\') => {
+                self.advance();
+                Ok('\\')
+            }
+            Some('u') => {
+                self.advance();
+                if self.peek_char() != Some('{') {
+                    return Err(LexError::UnbracedUnicodeEscape);
+                }
+                self.advance();
+                let mut digits = String::new();
+                while let Some(ch) = self.peek_char() {
+                    if ch == '}' || ch == '"' {
+                        break;
+                    }
+                    digits.push(ch);
+                    self.advance();
+                }
+                if self.peek_char() != Some('}') {
+                    return Err(LexError::UnterminatedUnicodeEscape);
+                }
+                self.advance();
+                if digits.is_empty() || digits.len() > 6 {
+                    return Err(LexError::MalformedUnicodeEscape(digits));
+                }
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(LexError::InvalidUnicodeEscape(digits))
+            }
+            Some(ch) => {
+                self.advance();
+                Err(LexError::UnknownEscape(ch))
+            }
+            None => Err(LexError::UnterminatedString),
+        }
     }
 
     fn read_identifier(&mut self) -> Token {
         let mut ident = String::new();
-        while let Some(ch) = self.peek() {
+        while let Some(ch) = self.peek_char() {
             if !ch.is_ascii_alphanumeric() && ch != '_' {
                 break;
             }
@@ -82,28 +474,129 @@ impl Lexer {
 
         match ident.as_str() {
             "let" => Token::Let,
+            "const" => Token::Const,
             "if" => Token::If,
             "else" => Token::Else,
             "while" => Token::While,
             "print" => Token::Print,
+            "assert" => Token::Assert,
+            "exit" => Token::Exit,
+            "throw" => Token::Throw,
+            "try" => Token::Try,
+            "catch" => Token::Catch,
+            "struct" => Token::Struct,
+            "match" => Token::Match,
+            "fn" => Token::Fn,
+            "return" => Token::Return,
+            "import" => Token::Import,
+            "extern" => Token::Extern,
+            "true" => Token::True,
+            "false" => Token::False,
             _ => Token::Identifier(ident),
         }
     }
 
+    /// The next token or error, without consuming it.
+    pub fn peek(&mut self) -> Option<&Result<SpannedToken, LexError>> {
+        self.peek_n(0)
+    }
+
+    /// The token or error `n` places ahead (0 being the next one), without
+    /// consuming anything.
+    pub fn peek_n(&mut self, n: usize) -> Option<&Result<SpannedToken, LexError>> {
+        while self.lookahead.len() <= n {
+            let SpannedToken { token, span } = self.scan()?;
+            let item = match token {
+                Token::Invalid(error) => Err(error),
+                token => Ok(SpannedToken { token, span }),
+            };
+            self.lookahead.push_back((item, span));
+        }
+        self.lookahead.get(n).map(|(item, _)| item)
+    }
+
+    /// The span of the last token or error returned by `next`.
+    pub fn last_span(&self) -> Span {
+        self.last_span
+    }
+
+    /// An empty span at the lexer's position, past any token peeked at,
+    /// such as the end of input once every token has been read.
+    pub fn location(&self) -> Span {
+        Span {
+            line: self.line,
+            col: self.col,
+            offset: self.offset,
+            len: 0,
+            file: self.file,
+        }
+    }
+
     pub fn next_token(&mut self) -> Option<Token> {
-        self.skip_whitespace();
+        self.next_spanned_token().map(|spanned| spanned.token)
+    }
+
+    /// Reads the next token along with its location in the source, with a
+    /// malformed one as a `Token::Invalid` carrying the error.
+    pub fn next_spanned_token(&mut self) -> Option<SpannedToken> {
+        Some(match self.next()? {
+            Ok(spanned) => spanned,
+            Err(error) => SpannedToken {
+                token: Token::Invalid(error),
+                span: self.last_span,
+            },
+        })
+    }
+
+    /// Reads a token from the source, past the tokens already peeked at.
+    fn scan(&mut self) -> Option<SpannedToken> {
+        if let Some(span) = self.skip_trivia() {
+            return Some(SpannedToken {
+                token: Token::Invalid(LexError::UnterminatedComment),
+                span,
+            });
+        }
+        let (line, col, offset) = (self.line, self.col, self.offset);
+        let token = self.read_token()?;
+        Some(SpannedToken {
+            token,
+            span: Span {
+                line,
+                col,
+                offset,
+                len: self.offset - offset,
+                file: self.file,
+            },
+        })
+    }
 
-        let ch = self.peek()?;
+    fn read_token(&mut self) -> Option<Token> {
+        let ch = self.peek_char()?;
         match ch {
             '0'..='9' => Some(self.read_number()),
             'a'..='z' | 'A'..='Z' | '_' => Some(self.read_identifier()),
+            '"' => Some(self.read_string()),
+            '\'' => Some(self.read_char()),
             '+' => {
                 self.advance();
-                Some(Token::Plus)
+                if self.peek_char() == Some('+') {
+                    self.advance();
+                    Some(Token::PlusPlus)
+                } else {
+                    Some(Token::Plus)
+                }
             }
             '-' => {
                 self.advance();
-                Some(Token::Minus)
+                if self.peek_char() == Some('-') {
+                    self.advance();
+                    Some(Token::MinusMinus)
+                } else if self.peek_char() == Some('>') {
+                    self.advance();
+                    Some(Token::Arrow)
+                } else {
+                    Some(Token::Minus)
+                }
             }
             '*' => {
                 self.advance();
@@ -113,6 +606,10 @@ impl Lexer {
                 self.advance();
                 Some(Token::Slash)
             }
+            '%' => {
+                self.advance();
+                Some(Token::Percent)
+            }
             '(' => {
                 self.advance();
                 Some(Token::LParen)
@@ -121,45 +618,128 @@ impl Lexer {
                 self.advance();
                 Some(Token::RParen)
             }
+            '{' => {
+                self.advance();
+                Some(Token::LBrace)
+            }
+            '}' => {
+                self.advance();
+                Some(Token::RBrace)
+            }
+            '[' => {
+                self.advance();
+                Some(Token::LBracket)
+            }
+            ']' => {
+                self.advance();
+                Some(Token::RBracket)
+            }
             ';' => {
                 self.advance();
                 Some(Token::Semicolon)
             }
+            ',' => {
+                self.advance();
+                Some(Token::Comma)
+            }
+            ':' => {
+                self.advance();
+                Some(Token::Colon)
+            }
+            '?' => {
+                self.advance();
+                Some(Token::Question)
+            }
+            '.' => {
+                self.advance();
+                Some(Token::Dot)
+            }
             '=' => {
                 self.advance();
-                if self.peek() == Some('=') {
+                if self.peek_char() == Some('=') {
                     self.advance();
                     Some(Token::DoubleEquals)
+                } else if self.peek_char() == Some('>') {
+                    self.advance();
+                    Some(Token::FatArrow)
                 } else {
                     Some(Token::Equals)
                 }
             }
+            '!' => {
+                self.advance();
+                if self.peek_char() == Some('=') {
+                    self.advance();
+                    Some(Token::NotEquals)
+                } else {
+                    Some(Token::Bang)
+                }
+            }
+            '&' => {
+                self.advance();
+                if self.peek_char() == Some('&') {
+                    self.advance();
+                    Some(Token::AndAnd)
+                } else {
+                    Some(Token::Invalid(LexError::UnexpectedCharacter('&')))
+                }
+            }
+            '|' => {
+                self.advance();
+                if self.peek_char() == Some('|') {
+                    self.advance();
+                    Some(Token::OrOr)
+                } else {
+                    Some(Token::Invalid(LexError::UnexpectedCharacter('|')))
+                }
+            }
             '<' => {
                 self.advance();
-                if self.peek() == Some('=') {
+                if self.peek_char() == Some('=') {
                     self.advance();
                     Some(Token::LessEqual)
+                } else if self.peek_char() == Some('<') {
+                    self.advance();
+                    Some(Token::ShiftLeft)
                 } else {
                     Some(Token::LessThan)
                 }
             }
             '>' => {
                 self.advance();
-                if self.peek() == Some('=') {
+                if self.peek_char() == Some('=') {
                     self.advance();
                     Some(Token::GreaterEqual)
+                } else if self.peek_char() == Some('>') {
+                    self.advance();
+                    Some(Token::ShiftRight)
                 } else {
                     Some(Token::GreaterThan)
                 }
             }
-            _ => None,
+            _ => {
+                self.advance();
+                Some(Token::Invalid(LexError::UnexpectedCharacter(ch)))
+            }
         }
     }
 }
 
+impl Iterator for Lexer {
+    type Item = Result<SpannedToken, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.peek_n(0)?;
+        let (item, span) = self.lookahead.pop_front()?;
+        self.last_span = span;
+        Some(item)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Lexer, Token};
+    use super::{Lexer, SpannedToken, Token};
+    use crate::compiler::{error::LexError, span::Span};
 
     fn collect_tokens(input: &str) -> Vec<Token> {
         let mut lexer = Lexer::new(input);
@@ -205,4 +785,368 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn tokenizes_not_equals() {
+        assert_eq!(
+            collect_tokens("x != 1; y == 2;"),
+            vec![
+                Token::Identifier("x".to_string()),
+                Token::NotEquals,
+                Token::Number(1),
+                Token::Semicolon,
+                Token::Identifier("y".to_string()),
+                Token::DoubleEquals,
+                Token::Number(2),
+                Token::Semicolon,
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_braces() {
+        assert_eq!(
+            collect_tokens("while x { print (x); }"),
+            vec![
+                Token::While,
+                Token::Identifier("x".to_string()),
+                Token::LBrace,
+                Token::Print,
+                Token::LParen,
+                Token::Identifier("x".to_string()),
+                Token::RParen,
+                Token::Semicolon,
+                Token::RBrace,
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_line_comments() {
+        assert_eq!(
+            collect_tokens("// leading\nx / 2; // trailing\n// last"),
+            vec![
+                Token::Identifier("x".to_string()),
+                Token::Slash,
+                Token::Number(2),
+                Token::Semicolon,
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_nested_block_comments() {
+        assert_eq!(
+            collect_tokens("let /* outer /* inner */ still outer */ x = 1;"),
+            vec![
+                Token::Let,
+                Token::Identifier("x".to_string()),
+                Token::Equals,
+                Token::Number(1),
+                Token::Semicolon,
+            ]
+        );
+    }
+
+    #[test]
+    fn records_comments() {
+        let mut lexer = Lexer::new("// one\nx /* two\n */ ; // three");
+        while lexer.next_token().is_some() {}
+        let comments: Vec<(&str, String)> = lexer
+            .comments()
+            .iter()
+            .map(|comment| (comment.text.as_str(), comment.span.to_string()))
+            .collect();
+        assert_eq!(
+            comments,
+            [
+                ("// one", "1:1".to_string()),
+                ("/* two\n */", "2:3".to_string()),
+                ("// three", "3:7".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_booleans_and_logical_operators() {
+        assert_eq!(
+            collect_tokens("!done && true || false"),
+            vec![
+                Token::Bang,
+                Token::Identifier("done".to_string()),
+                Token::AndAnd,
+                Token::True,
+                Token::OrOr,
+                Token::False,
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_string_literals() {
+        assert_eq!(
+            collect_tokens("print \"hello, world\"; \"\""),
+            vec![
+                Token::Print,
+                Token::Str("hello, world".to_string()),
+                Token::Semicolon,
+                Token::Str(String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_string_escapes() {
+        assert_eq!(
+            collect_tokens(r#""a\tb\n" "say \"hi\"" "back\\slash" "\u{48}\u{e9}\u{1F600}""#),
+            vec![
+                Token::Str("a\tb\n".to_string()),
+                Token::Str("say \"hi\"".to_string()),
+                Token::Str("back\\slash".to_string()),
+                Token::Str("H\u{e9}\u{1F600}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_character_literals() {
+        assert_eq!(
+            collect_tokens(r"'a' '\n' '\'' '\u{e9}' 'é'"),
+            vec![
+                Token::Char('a'),
+                Token::Char('\n'),
+                Token::Char('\''),
+                Token::Char('\u{e9}'),
+                Token::Char('é'),
+            ]
+        );
+        for (literal, error) in [
+            ("'';", LexError::EmptyCharacter),
+            ("'ab';", LexError::MultipleCharacters),
+            ("'\\q';", LexError::UnknownEscape('q')),
+        ] {
+            assert_eq!(
+                collect_tokens(literal),
+                vec![Token::Invalid(error), Token::Semicolon],
+                "{}",
+                literal
+            );
+        }
+        assert_eq!(
+            collect_tokens("'a\n;"),
+            vec![
+                Token::Invalid(LexError::UnterminatedCharacter),
+                Token::Semicolon
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_string_escapes() {
+        for (literal, error, message) in [
+            (
+                r#""\q""#,
+                LexError::UnknownEscape('q'),
+                "Unknown escape sequence '\\q'",
+            ),
+            (
+                r#""\u41""#,
+                LexError::UnbracedUnicodeEscape,
+                "Unicode escape must be written as '\\u{...}'",
+            ),
+            (
+                r#""\u{41""#,
+                LexError::UnterminatedUnicodeEscape,
+                "Unterminated unicode escape '\\u{'",
+            ),
+            (
+                r#""\u{}""#,
+                LexError::MalformedUnicodeEscape(String::new()),
+                "Malformed unicode escape '\\u{}'",
+            ),
+            (
+                r#""\u{zz}""#,
+                LexError::InvalidUnicodeEscape("zz".to_string()),
+                "Invalid unicode escape '\\u{zz}'",
+            ),
+            (
+                r#""\u{D800}""#,
+                LexError::InvalidUnicodeEscape("D800".to_string()),
+                "Invalid unicode escape '\\u{D800}'",
+            ),
+        ] {
+            assert_eq!(error.to_string(), message);
+            assert_eq!(
+                collect_tokens(&format!("{};", literal)),
+                vec![Token::Invalid(error), Token::Semicolon],
+                "{}",
+                literal
+            );
+        }
+    }
+
+    #[test]
+    fn unterminated_string_ends_token_stream() {
+        assert_eq!(
+            collect_tokens("print \"oops"),
+            vec![Token::Print, Token::Invalid(LexError::UnterminatedString)]
+        );
+    }
+
+    #[test]
+    fn iterates_with_errors_and_lookahead() {
+        let source = "a & b # /* open";
+        let mut lexer = Lexer::new(source);
+        let text = |item: Option<&Result<SpannedToken, LexError>>| match item {
+            Some(Ok(spanned)) => spanned.span.text(source).to_string(),
+            other => panic!("expected a token, got {:?}", other),
+        };
+        assert_eq!(text(lexer.peek()), "a");
+        assert_eq!(text(lexer.peek_n(2)), "b");
+        assert_eq!(lexer.peek_n(5), None);
+        assert_eq!(text(lexer.next().as_ref()), "a");
+        assert_eq!(lexer.next(), Some(Err(LexError::UnexpectedCharacter('&'))));
+        assert_eq!(lexer.last_span().text(source), "&");
+        assert_eq!(text(lexer.next().as_ref()), "b");
+        let rest: Vec<_> = lexer.by_ref().collect();
+        assert_eq!(
+            rest,
+            [
+                Err(LexError::UnexpectedCharacter('#')),
+                Err(LexError::UnterminatedComment)
+            ]
+        );
+        assert_eq!(lexer.last_span().text(source), "/* open");
+        assert_eq!(lexer.comments().len(), 1);
+    }
+
+    #[test]
+    fn tokenizes_increment_and_decrement() {
+        assert_eq!(
+            collect_tokens("i++; j--; k + 1 - 2;"),
+            vec![
+                Token::Identifier("i".to_string()),
+                Token::PlusPlus,
+                Token::Semicolon,
+                Token::Identifier("j".to_string()),
+                Token::MinusMinus,
+                Token::Semicolon,
+                Token::Identifier("k".to_string()),
+                Token::Plus,
+                Token::Number(1),
+                Token::Minus,
+                Token::Number(2),
+                Token::Semicolon,
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_hex_binary_and_separated_literals() {
+        assert_eq!(
+            collect_tokens("0xFF 0B1010 1_000_000 0x_dead_BEEF 0"),
+            vec![
+                Token::Number(255),
+                Token::Number(10),
+                Token::Number(1_000_000),
+                Token::Number(0xdead_beef),
+                Token::Number(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_malformed_literals() {
+        for literal in ["0x", "0b102", "12ab", "0xG1", "99999999999999999999"] {
+            assert!(
+                matches!(collect_tokens(literal).as_slice(), [Token::Invalid(_)]),
+                "{}",
+                literal
+            );
+        }
+    }
+
+    #[test]
+    fn tokenizes_float_literals() {
+        assert_eq!(
+            collect_tokens("1.5 2.0e3 1e-2 1_000.25 p.x"),
+            vec![
+                Token::Float(1.5),
+                Token::Float(2000.0),
+                Token::Float(0.01),
+                Token::Float(1000.25),
+                Token::Identifier("p".to_string()),
+                Token::Dot,
+                Token::Identifier("x".to_string()),
+            ]
+        );
+        assert!(matches!(
+            collect_tokens("1.5x").as_slice(),
+            [Token::Invalid(_)]
+        ));
+    }
+
+    #[test]
+    fn tokenizes_modulo() {
+        assert_eq!(
+            collect_tokens("x % 3;"),
+            vec![
+                Token::Identifier("x".to_string()),
+                Token::Percent,
+                Token::Number(3),
+                Token::Semicolon,
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_function_declarations() {
+        assert_eq!(
+            collect_tokens("fn f(x) -> int { return x; }"),
+            vec![
+                Token::Fn,
+                Token::Identifier("f".to_string()),
+                Token::LParen,
+                Token::Identifier("x".to_string()),
+                Token::RParen,
+                Token::Arrow,
+                Token::Identifier("int".to_string()),
+                Token::LBrace,
+                Token::Return,
+                Token::Identifier("x".to_string()),
+                Token::Semicolon,
+                Token::RBrace,
+            ]
+        );
+    }
+
+    #[test]
+    fn tracks_token_spans() {
+        let source = "let s = \"é\";\n  /* ok */ print s;";
+        let mut lexer = Lexer::new(source);
+        let mut spans = Vec::new();
+        while let Some(spanned) = lexer.next_spanned_token() {
+            spans.push((spanned.span.text(source).to_string(), spanned.span));
+        }
+        let span = |line, col, offset, len| Span {
+            line,
+            col,
+            offset,
+            len,
+            file: 0,
+        };
+        assert_eq!(
+            spans,
+            [
+                ("let".to_string(), span(1, 1, 0, 3)),
+                ("s".to_string(), span(1, 5, 4, 1)),
+                ("=".to_string(), span(1, 7, 6, 1)),
+                ("\"é\"".to_string(), span(1, 9, 8, 4)),
+                (";".to_string(), span(1, 12, 12, 1)),
+                ("print".to_string(), span(2, 12, 25, 5)),
+                ("s".to_string(), span(2, 18, 31, 1)),
+                (";".to_string(), span(2, 19, 32, 1)),
+            ]
+        );
+    }
 }
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use crate::{
+    compiler::{
+        error::LinkError,
+        object::{Object, RelocationKind, Symbol},
+    },
+    Opcode,
+};
+
+/// Combines objects into one program image.
+///
+/// Objects are placed one after another in the order they were added, so
+/// their top-level code runs in that order: add libraries before the objects
+/// that use them, so their globals and host bindings are set up first. Each
+/// object gets its own range of global addresses and host binding indices.
+/// A single `Halt` follows the last object, then the strings of all of them.
+///
+/// Every function an object defines is visible to all the others. Functions
+/// the compiler links in from the prelude stay private to each object.
+#[derive(Default)]
+pub struct Linker {
+    objects: Vec<Object>,
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends an object to the program.
+    pub fn add(&mut self, object: Object) {
+        self.objects.push(object);
+    }
+
+    pub fn link(&self) -> Result<Vec<u8>, LinkError> {
+        let mut code_bases = Vec::with_capacity(self.objects.len());
+        let mut global_bases = Vec::with_capacity(self.objects.len());
+        let mut binding_bases = Vec::with_capacity(self.objects.len());
+        let (mut code_len, mut globals, mut bindings) = (0, 0, 0);
+        for object in &self.objects {
+            code_bases.push(code_len);
+            global_bases.push(globals);
+            binding_bases.push(bindings);
+            code_len += object.code.len();
+            globals += object.globals;
+            bindings += object.host_bindings;
+        }
+
+        let mut definitions: HashMap<&str, (usize, &Symbol)> = HashMap::new();
+        for (object, base) in self.objects.iter().zip(&code_bases) {
+            for symbol in &object.symbols {
+                let Some(offset) = symbol.offset else {
+                    continue;
+                };
+                if offset >= object.code.len() {
+                    return Err(LinkError::MalformedObject(format!(
+                        "'{}' starts past the end of its code",
+                        symbol.name
+                    )));
+                }
+                if definitions
+                    .insert(&symbol.name, (base + offset, symbol))
+                    .is_some()
+                {
+                    return Err(LinkError::DuplicateSymbol(symbol.name.clone()));
+                }
+            }
+        }
+        // Calls were checked against the signature the caller imported
+        for object in &self.objects {
+            for symbol in object.symbols.iter().filter(|s| s.offset.is_none()) {
+                let (_, definition) = definitions
+                    .get(symbol.name.as_str())
+                    .ok_or_else(|| LinkError::UndefinedSymbol(symbol.name.clone()))?;
+                if definition.arity() != symbol.arity() {
+                    return Err(LinkError::ArityMismatch {
+                        name: symbol.name.clone(),
+                        expected: symbol.arity(),
+                        found: definition.arity(),
+                    });
+                }
+            }
+        }
+
+        let mut program: Vec<u8> = Vec::with_capacity(code_len + 1);
+        for object in &self.objects {
+            program.extend_from_slice(&object.code);
+        }
+        program.push(Opcode::Halt as u8);
+        let mut string_offsets = Vec::with_capacity(self.objects.len());
+        for object in &self.objects {
+            let mut offsets = Vec::with_capacity(object.strings.len());
+            for value in &object.strings {
+                offsets.push(program.len());
+                program.extend_from_slice(&(value.len() as i64).to_le_bytes());
+                program.extend_from_slice(value.as_bytes());
+            }
+            string_offsets.push(offsets);
+        }
+
+        for (index, object) in self.objects.iter().enumerate() {
+            for relocation in &object.relocations {
+                let operand = relocation.offset;
+                let Some(bytes) = object.code.get(operand..operand + 8) else {
+                    return Err(LinkError::MalformedObject(format!(
+                        "relocation at {} is past the end of its code",
+                        operand
+                    )));
+                };
+                let value = i64::from_le_bytes(bytes.try_into().unwrap());
+                let value = match &relocation.kind {
+                    RelocationKind::Code => value + code_bases[index] as i64,
+                    RelocationKind::Global => value + global_bases[index] as i64,
+                    RelocationKind::HostBinding => value + binding_bases[index] as i64,
+                    RelocationKind::String(string) => {
+                        let offset = string_offsets[index].get(*string).ok_or_else(|| {
+                            LinkError::MalformedObject(format!("no string at index {}", string))
+                        })?;
+                        *offset as i64
+                    }
+                    RelocationKind::Symbol(name) => {
+                        let (entry, _) = definitions
+                            .get(name.as_str())
+                            .ok_or_else(|| LinkError::UndefinedSymbol(name.clone()))?;
+                        *entry as i64
+                    }
+                };
+                let at = code_bases[index] + operand;
+                program[at..at + 8].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        Ok(program)
+    }
+}
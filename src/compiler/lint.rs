@@ -0,0 +1,343 @@
+use std::fmt;
+
+use crate::compiler::{
+    fold::ConstantFolder,
+    lexer::{Lexer, Token},
+    parser::{Expr, ExprKind, Param, Parser, Statement, StatementKind},
+    span::Span,
+    visit::{self, Visitor},
+};
+
+/// Blocks may be nested this deep before `Rule::DeepNesting` reports them.
+pub const MAX_NESTING: usize = 4;
+
+/// What a lint diagnostic is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// A `let` reusing the name of a variable still in scope
+    ShadowedVariable,
+    /// `=` in an `if` or `while` condition, where `==` was likely meant
+    AssignmentInCondition,
+    /// An `if` or `?:` condition whose value is known without running, or
+    /// a `while` condition that is constantly false
+    ConstantCondition,
+    /// Blocks nested more than `MAX_NESTING` deep
+    DeepNesting,
+}
+
+impl Rule {
+    /// The rule's name, as shown in brackets after `warning`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::ShadowedVariable => "shadowed-variable",
+            Rule::AssignmentInCondition => "assignment-in-condition",
+            Rule::ConstantCondition => "constant-condition",
+            Rule::DeepNesting => "deep-nesting",
+        }
+    }
+}
+
+/// A likely mistake or hard-to-read construct found by `lint`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub rule: Rule,
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: warning[{}]: {}",
+            self.span,
+            self.rule.name(),
+            self.message
+        )
+    }
+}
+
+/// Checks a source file for likely mistakes, in source order.
+///
+/// Linting only parses the file, without type checking or compiling it,
+/// so it is cheap enough to run on every edit. A file that does not parse
+/// is only checked for assignments in conditions, which the parser rejects
+/// with a less helpful message.
+pub fn lint(source: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = assignments_in_conditions(source);
+    let mut parser = Parser::new(source);
+    let statements = parser
+        .parse_imports()
+        .ok()
+        .and_then(|_| parser.parse_program().ok());
+    if let Some(statements) = statements {
+        let mut linter = Linter {
+            scopes: vec![Vec::new()],
+            ..Linter::default()
+        };
+        visit::walk_block(&mut linter, &statements);
+        diagnostics.extend(linter.diagnostics);
+    }
+    diagnostics.sort_by_key(|diagnostic| diagnostic.span.offset);
+    diagnostics
+}
+
+/// Finds a lone `=` between `if` or `while` and the `{` opening its body.
+fn assignments_in_conditions(source: &str) -> Vec<Diagnostic> {
+    let tokens: Vec<_> = Lexer::new(source).filter_map(Result::ok).collect();
+    let mut diagnostics = Vec::new();
+    for (index, keyword) in tokens.iter().enumerate() {
+        if !matches!(keyword.token, Token::If | Token::While) {
+            continue;
+        }
+        // Brackets in the condition, such as a closure's, may hold braces
+        let mut depth = 0;
+        for token in &tokens[index + 1..] {
+            match token.token {
+                Token::LParen | Token::LBracket => depth += 1,
+                Token::RParen | Token::RBracket => depth -= 1,
+                Token::LBrace | Token::Semicolon if depth <= 0 => break,
+                Token::Equals => {
+                    diagnostics.push(Diagnostic {
+                        rule: Rule::AssignmentInCondition,
+                        message: "Assignment in condition; did you mean '=='?".to_string(),
+                        span: token.span,
+                    });
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    diagnostics
+}
+
+#[derive(Default)]
+struct Linter {
+    /// Names declared in each enclosing scope, innermost last
+    scopes: Vec<Vec<String>>,
+    /// Blocks enclosing the statement being checked
+    depth: usize,
+    /// Tracks `const` values to recognize constant conditions
+    folder: ConstantFolder,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter {
+    fn report(&mut self, rule: Rule, message: String, span: Span) {
+        self.diagnostics.push(Diagnostic {
+            rule,
+            message,
+            span,
+        });
+    }
+
+    /// Declares a `let` binding, reporting it if it shadows another.
+    /// Names starting with `_` may be reused freely.
+    fn declare(&mut self, name: &str, span: Span) {
+        let shadows = self.scopes.iter().flatten().any(|other| other == name);
+        if shadows && !name.starts_with('_') {
+            self.report(
+                Rule::ShadowedVariable,
+                format!("'{}' shadows an earlier declaration", name),
+                span,
+            );
+        }
+        self.scopes.last_mut().unwrap().push(name.to_string());
+    }
+
+    /// The value of a condition, if it is a constant.
+    fn constant(&mut self, condition: &Expr) -> Option<bool> {
+        match self.folder.fold(condition.clone()).kind {
+            ExprKind::Bool(value) => Some(value),
+            ExprKind::Number(value) => Some(value != 0),
+            _ => None,
+        }
+    }
+
+    fn check_condition(&mut self, condition: &Expr) {
+        if let Some(value) = self.constant(condition) {
+            self.report(
+                Rule::ConstantCondition,
+                format!("Condition is always {}", value),
+                condition.span,
+            );
+        }
+    }
+
+    /// Checks a function's or closure's body, with its parameters in scope.
+    fn check_function(&mut self, params: &[Param], body: &[Statement]) {
+        self.scopes
+            .push(params.iter().map(|(name, _)| name.clone()).collect());
+        self.depth += 1;
+        visit::walk_block(self, body);
+        self.depth -= 1;
+        self.scopes.pop();
+    }
+}
+
+impl Visitor for Linter {
+    fn visit_block(&mut self, statements: &[Statement]) {
+        self.scopes.push(Vec::new());
+        self.depth += 1;
+        visit::walk_block(self, statements);
+        self.depth -= 1;
+        self.scopes.pop();
+    }
+
+    fn visit_statement(&mut self, statement: &Statement) {
+        let span = statement.span;
+        let opens_block = matches!(
+            statement.kind,
+            StatementKind::If(..)
+                | StatementKind::While(..)
+                | StatementKind::Try(..)
+                | StatementKind::Match(..)
+                | StatementKind::Function(..)
+        );
+        // Only the outermost block too deep is reported
+        if opens_block && self.depth == MAX_NESTING {
+            self.report(
+                Rule::DeepNesting,
+                format!("Blocks are nested more than {} deep", MAX_NESTING),
+                span,
+            );
+        }
+        match &statement.kind {
+            StatementKind::Let(name, _, value) => {
+                // The initializer still sees the binding this one shadows
+                self.visit_expr(value);
+                self.declare(name, span);
+            }
+            StatementKind::LetTuple(names, values) => {
+                values.iter().for_each(|value| self.visit_expr(value));
+                for name in names {
+                    self.declare(name, span);
+                }
+            }
+            StatementKind::Const(..) => {
+                self.folder.fold_statement(&mut statement.clone());
+            }
+            StatementKind::If(condition, ..) => {
+                self.check_condition(condition);
+                visit::walk_statement(self, statement);
+            }
+            // `while true` is how a loop runs until it breaks out
+            StatementKind::While(condition, _) => {
+                if self.constant(condition) == Some(false) {
+                    self.check_condition(condition);
+                }
+                visit::walk_statement(self, statement);
+            }
+            StatementKind::Try(body, name, handler) => {
+                self.visit_block(body);
+                self.scopes.push(vec![name.clone()]);
+                self.visit_block(handler);
+                self.scopes.pop();
+            }
+            StatementKind::Function(_, params, _, body) => self.check_function(params, body),
+            _ => visit::walk_statement(self, statement),
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Conditional(condition, ..) => {
+                self.check_condition(condition);
+                visit::walk_expr(self, expr);
+            }
+            ExprKind::Closure(_, params, _, body) => self.check_function(params, body),
+            _ => visit::walk_expr(self, expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint, Rule};
+
+    fn findings(source: &str) -> Vec<(Rule, String)> {
+        lint(source)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.rule, diagnostic.span.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn reports_shadowed_variables() {
+        let source = "\
+let total = 0;
+fn f(n) { let n = 2; return n; }
+if total == 0 {
+    let total = 1;
+    let _x = 1;
+    let _x = 2;
+}
+let (a, total) = (1, 2);
+";
+        assert_eq!(
+            findings(source),
+            [
+                (Rule::ShadowedVariable, "2:11".to_string()),
+                (Rule::ShadowedVariable, "4:5".to_string()),
+                (Rule::ShadowedVariable, "8:1".to_string()),
+            ]
+        );
+        assert!(lint("if 1 > 0 { let a = 1; } let a = 2;")
+            .iter()
+            .all(|diagnostic| diagnostic.rule != Rule::ShadowedVariable));
+    }
+
+    #[test]
+    fn reports_assignments_in_conditions() {
+        let diagnostics = lint("let x = 1;\nif x = 2 { print x; }\nwhile f(x == 1) { }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].rule, Rule::AssignmentInCondition);
+        assert_eq!(diagnostics[0].span.to_string(), "2:6");
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "2:6: warning[assignment-in-condition]: Assignment in condition; did you mean '=='?"
+        );
+    }
+
+    #[test]
+    fn reports_constant_conditions() {
+        let source = "\
+const DEBUG = false;
+let x = 1;
+if DEBUG { print 1; }
+if x > 0 { print 2; }
+while 1 > 2 { print 3; }
+while true { exit(0); }
+let y = 1 < 2 ? 3 : 4;
+";
+        assert_eq!(
+            findings(source),
+            [
+                (Rule::ConstantCondition, "3:4".to_string()),
+                (Rule::ConstantCondition, "5:7".to_string()),
+                (Rule::ConstantCondition, "7:9".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_deep_nesting_once() {
+        let source = "\
+fn f(x) {
+    while x > 0 {
+        if x > 1 {
+            if x > 2 {
+                if x > 3 {
+                    if x > 4 { print x; }
+                }
+            }
+        }
+        x = x - 1;
+    }
+    return x;
+}
+";
+        assert_eq!(findings(source), [(Rule::DeepNesting, "5:17".to_string())]);
+    }
+}
@@ -1,6 +1,49 @@
+pub mod analysis;
+pub mod backend;
+pub mod builder;
 pub mod codegen;
+pub mod const_eval;
+pub mod dce;
+pub mod debug_info;
+pub mod diagnostics;
+pub mod error;
+pub mod fold;
+pub mod formatter;
 pub mod lexer;
+pub mod linker;
+pub mod lint;
+pub mod module;
+pub mod object;
+pub mod operators;
 pub mod parser;
+pub mod pass;
+pub mod prelude;
+pub mod session;
+pub mod span;
+pub mod strength;
+pub mod typeck;
+pub mod visit;
 
-pub use codegen::Compiler;
-pub use parser::Parser;
+pub use analysis::Analysis;
+pub use backend::{Artifact, Backend, Ir};
+pub use builder::{CompilerBuilder, Target};
+pub use codegen::{Compiler, StackBackend};
+pub use const_eval::const_eval;
+pub use debug_info::DebugInfo;
+pub use diagnostics::Warning;
+pub use error::{
+    BuildError, CompileError, ConstEvalError, Expected, LexError, LinkError, ParseError, PassError,
+    TypeError,
+};
+pub use formatter::format;
+pub use linker::Linker;
+pub use lint::lint;
+pub use module::ModuleLoader;
+pub use object::{Object, Relocation, RelocationKind, Symbol};
+pub use operators::{Associativity, OperatorTable};
+pub use parser::{parse_to_json, Parser};
+pub use pass::{OptLevel, Pass, PassManager};
+pub use session::{CacheStats, Session};
+pub use span::Span;
+pub use typeck::TypeChecker;
+pub use visit::{MutVisitor, Visitor};
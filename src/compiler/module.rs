@@ -0,0 +1,655 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::compiler::error::{CompileError, ParseError};
+use crate::compiler::parser::{
+    Expr, ExprKind, MatchPattern, Param, Parser, Statement, StatementKind, Type,
+};
+
+type ReadModule = Box<dyn Fn(&Path) -> io::Result<String>>;
+
+/// Loads a program split across files with `import "path";` and links it
+/// into a single statement list for the compiler.
+///
+/// Import paths are relative to the importing file. Each module is loaded
+/// once, however many modules import it, and import cycles are rejected.
+/// Imported modules may only contain declarations (functions, externs,
+/// structs and constants), which are placed ahead of the entry module's
+/// statements. Each module has its own namespace: it sees its own
+/// declarations and those of the modules it imports, its own taking
+/// precedence. A name declared by several modules is qualified with the
+/// module's path, as `lib/a.svm::helper`, everywhere but in the entry
+/// module, so modules can each have a helper of the same name. Externs
+/// keep their names, which the host binds them by.
+pub struct ModuleLoader {
+    read: ReadModule,
+    /// Modules already linked
+    loaded: HashSet<PathBuf>,
+    /// Modules being loaded, innermost last
+    loading: Vec<PathBuf>,
+    /// Modules parsed so far, dependencies first
+    modules: Vec<Module>,
+    /// Every module read, indexed by the file id of its spans
+    files: Vec<PathBuf>,
+}
+
+/// A parsed module, before its names are resolved.
+struct Module {
+    path: PathBuf,
+    /// The modules it imports directly
+    imports: Vec<PathBuf>,
+    statements: Vec<Statement>,
+}
+
+impl Module {
+    /// The names the module declares at the top level, with whether each
+    /// is an extern.
+    fn declarations(&self) -> impl Iterator<Item = (&String, bool)> {
+        self.statements
+            .iter()
+            .filter_map(|statement| match &statement.kind {
+                StatementKind::Extern(name, ..) => Some((name, true)),
+                StatementKind::Function(name, ..)
+                | StatementKind::Struct(name, _)
+                | StatementKind::Const(name, _) => Some((name, false)),
+                _ => None,
+            })
+    }
+}
+
+/// What a name at the top level of a module refers to.
+#[derive(Clone)]
+enum Resolution {
+    /// A declaration, by its name in the linked program
+    Linked(String),
+    /// Declarations of two imported modules
+    Ambiguous(PathBuf, PathBuf),
+}
+
+impl ModuleLoader {
+    /// Creates a loader that reads modules from the file system.
+    pub fn new() -> Self {
+        Self::with_reader(|path| fs::read_to_string(path))
+    }
+
+    /// Creates a loader that reads module sources with `read`, for programs
+    /// that do not live on disk.
+    pub fn with_reader(read: impl Fn(&Path) -> io::Result<String> + 'static) -> Self {
+        ModuleLoader {
+            read: Box::new(read),
+            loaded: HashSet::new(),
+            loading: Vec::new(),
+            modules: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Loads the entry module at `path` with everything it imports.
+    pub fn load(&mut self, path: impl AsRef<Path>) -> Result<Vec<Statement>, String> {
+        self.loaded.clear();
+        self.modules.clear();
+        self.files.clear();
+        self.load_module(path.as_ref())?;
+        self.link()
+    }
+
+    /// The modules that `load` read besides the entry module, in no
+    /// particular order.
+    pub fn imports(&self) -> impl Iterator<Item = &Path> + '_ {
+        self.loaded.iter().map(PathBuf::as_path)
+    }
+
+    /// The files of the program `load` read, indexed by the file id of the
+    /// spans in its statements: the entry module is file 0, and the modules
+    /// it imports follow in the order they were read.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// `error`, from compiling the program `load` read, after the file it
+    /// was found in as `path:line:col: message`.
+    pub fn locate(&self, error: &CompileError) -> String {
+        match self.files.get(error.span().file) {
+            // Prelude spans point into the prelude, not a file of the program
+            Some(path) if !matches!(error, CompileError::Prelude(_)) => {
+                format!("{}:{}", path.display(), error)
+            }
+            _ => error.to_string(),
+        }
+    }
+
+    /// Parses the module at `path` after the modules it imports.
+    fn load_module(&mut self, path: &Path) -> Result<(), String> {
+        let source = (self.read)(path)
+            .map_err(|e| format!("Cannot read module '{}': {}", path.display(), e))?;
+        let in_module = |message: String| format!("{}: {}", path.display(), message);
+        // Syntax errors start with their location in the module
+        let at = |errors: &[ParseError]| {
+            let lines: Vec<String> = errors
+                .iter()
+                .map(|error| format!("{}:{}", path.display(), error))
+                .collect();
+            lines.join("\n")
+        };
+
+        let mut parser = Parser::with_file(&source, self.files.len());
+        self.files.push(path.to_path_buf());
+        let imports = parser.parse_imports().map_err(|error| at(&[error]))?;
+        let imports: Vec<PathBuf> = imports
+            .into_iter()
+            .map(|import| path.parent().unwrap_or(Path::new("")).join(import))
+            .collect();
+        self.loading.push(path.to_path_buf());
+        for import in &imports {
+            if self.loading.contains(import) {
+                let cycle: Vec<String> = self
+                    .loading
+                    .iter()
+                    .chain([import])
+                    .map(|module| module.display().to_string())
+                    .collect();
+                return Err(format!("Import cycle: {}", cycle.join(" -> ")));
+            }
+            if self.loaded.insert(import.clone()) {
+                self.load_module(import)?;
+            }
+        }
+        self.loading.pop();
+
+        parser.declare_structs(
+            self.modules
+                .iter()
+                .flat_map(|module| &module.statements)
+                .filter_map(|statement| match &statement.kind {
+                    StatementKind::Struct(name, _) => Some(name.clone()),
+                    _ => None,
+                }),
+        );
+        let statements = parser.parse_program().map_err(|errors| at(&errors))?;
+        let is_entry = self.loading.is_empty();
+        let module = Module {
+            path: path.to_path_buf(),
+            imports,
+            statements,
+        };
+        if !is_entry && module.declarations().count() < module.statements.len() {
+            return Err(in_module(
+                "Imported modules may only contain declarations".to_string(),
+            ));
+        }
+        self.modules.push(module);
+        Ok(())
+    }
+
+    /// Resolves the names of every module loaded, the entry module last,
+    /// and concatenates their statements.
+    fn link(&mut self) -> Result<Vec<Statement>, String> {
+        let entry = self.modules.len() - 1;
+        let mut declared_in: HashMap<&str, Vec<(usize, bool)>> = HashMap::new();
+        for (index, module) in self.modules.iter().enumerate() {
+            for (name, is_extern) in module.declarations() {
+                declared_in
+                    .entry(name)
+                    .or_default()
+                    .push((index, is_extern));
+            }
+        }
+        // Externs cannot be qualified, as the host binds them by name
+        for (name, modules) in &declared_in {
+            if !modules.iter().any(|(_, is_extern)| *is_extern) {
+                continue;
+            }
+            let mut paths = modules.iter().map(|(index, _)| &self.modules[*index].path);
+            let first = paths.next().unwrap();
+            if let Some(other) = paths.find(|path| *path != first) {
+                return Err(format!(
+                    "'{}' is defined in both '{}' and '{}'",
+                    name,
+                    first.display(),
+                    other.display()
+                ));
+            }
+        }
+        // A name several modules declare is qualified outside the entry
+        let linked_name = |index: usize, name: &str| {
+            let path = &self.modules[index].path;
+            let shared = declared_in[name]
+                .iter()
+                .any(|(other, _)| self.modules[*other].path != *path);
+            if shared && index != entry {
+                format!("{}::{}", path.display(), name)
+            } else {
+                name.to_string()
+            }
+        };
+        let index_of: HashMap<&Path, usize> = self
+            .modules
+            .iter()
+            .enumerate()
+            .map(|(index, module)| (module.path.as_path(), index))
+            .collect();
+
+        let mut scopes = Vec::with_capacity(self.modules.len());
+        for (index, module) in self.modules.iter().enumerate() {
+            let mut scope = HashMap::new();
+            let mut origins: HashMap<&String, usize> = HashMap::new();
+            for import in &module.imports {
+                let imported = index_of[import.as_path()];
+                for (name, _) in self.modules[imported].declarations() {
+                    let resolution = match origins.insert(name, imported) {
+                        Some(first) if first != imported => {
+                            Resolution::Ambiguous(self.modules[first].path.clone(), import.clone())
+                        }
+                        _ => Resolution::Linked(linked_name(imported, name)),
+                    };
+                    scope.insert(name.clone(), resolution);
+                }
+            }
+            for (name, _) in module.declarations() {
+                scope.insert(name.clone(), Resolution::Linked(linked_name(index, name)));
+            }
+            scopes.push(scope);
+        }
+
+        let mut program = Vec::new();
+        for (module, scope) in std::mem::take(&mut self.modules).into_iter().zip(scopes) {
+            let mut renamer = Renamer {
+                names: &scope,
+                locals: Vec::new(),
+                error: None,
+            };
+            let mut statements = module.statements;
+            renamer.block(&mut statements);
+            if let Some(error) = renamer.error {
+                return Err(format!("{}: {}", module.path.display(), error));
+            }
+            program.append(&mut statements);
+        }
+        Ok(program)
+    }
+}
+
+impl Default for ModuleLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rewrites the top-level names a module uses to their names in the linked
+/// program, leaving alone those its local variables and parameters shadow.
+struct Renamer<'a> {
+    names: &'a HashMap<String, Resolution>,
+    /// Names bound in each enclosing block or function, innermost last
+    locals: Vec<HashSet<String>>,
+    /// The first ambiguous name used
+    error: Option<String>,
+}
+
+impl Renamer<'_> {
+    fn resolve(&mut self, name: &mut String) {
+        if self
+            .locals
+            .iter()
+            .any(|scope| scope.contains(name.as_str()))
+        {
+            return;
+        }
+        match self.names.get(name.as_str()) {
+            Some(Resolution::Linked(linked)) => *name = linked.clone(),
+            Some(Resolution::Ambiguous(first, second)) => {
+                self.error.get_or_insert_with(|| {
+                    format!(
+                        "'{}' is imported from both '{}' and '{}'",
+                        name,
+                        first.display(),
+                        second.display()
+                    )
+                });
+            }
+            None => {}
+        }
+    }
+
+    fn bind(&mut self, name: &str) {
+        if let Some(scope) = self.locals.last_mut() {
+            scope.insert(name.to_string());
+        }
+    }
+
+    fn block(&mut self, statements: &mut [Statement]) {
+        self.locals.push(HashSet::new());
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.locals.pop();
+    }
+
+    /// Renames the types of a function's parameters and result, and its
+    /// body with the parameters bound.
+    fn function(
+        &mut self,
+        params: &mut [Param],
+        result: &mut Option<Type>,
+        body: &mut [Statement],
+    ) {
+        for (_, ty) in params.iter_mut() {
+            self.optional_type(ty);
+        }
+        self.optional_type(result);
+        self.locals
+            .push(params.iter().map(|(name, _)| name.clone()).collect());
+        self.block(body);
+        self.locals.pop();
+    }
+
+    fn statement(&mut self, statement: &mut Statement) {
+        // Declarations at the top level are the module's own names
+        let top_level = self.locals.len() == 1;
+        match &mut statement.kind {
+            StatementKind::Let(name, ty, value) => {
+                self.expr(value);
+                self.optional_type(ty);
+                self.bind(name);
+            }
+            StatementKind::Const(name, value) => {
+                self.expr(value);
+                if top_level {
+                    self.resolve(name);
+                } else {
+                    self.bind(name);
+                }
+            }
+            StatementKind::Assign(name, value) => {
+                self.expr(value);
+                self.resolve(name);
+            }
+            StatementKind::LetTuple(names, values) => {
+                values.iter_mut().for_each(|value| self.expr(value));
+                names.iter().for_each(|name| self.bind(name));
+            }
+            StatementKind::AssignTuple(names, values) => {
+                values.iter_mut().for_each(|value| self.expr(value));
+                names.iter_mut().for_each(|name| self.resolve(name));
+            }
+            StatementKind::IndexAssign(name, index, value) => {
+                self.expr(index);
+                self.expr(value);
+                self.resolve(name);
+            }
+            StatementKind::Increment(name) | StatementKind::Decrement(name) => self.resolve(name),
+            StatementKind::If(condition, then_block, else_block) => {
+                self.expr(condition);
+                self.block(then_block);
+                self.block(else_block);
+            }
+            StatementKind::While(condition, body) => {
+                self.expr(condition);
+                self.block(body);
+            }
+            StatementKind::Print(values) | StatementKind::PrintFormatted(_, values) => {
+                values.iter_mut().for_each(|value| self.expr(value));
+            }
+            StatementKind::Assert(value)
+            | StatementKind::Exit(value)
+            | StatementKind::Throw(value)
+            | StatementKind::Return(Some(value)) => self.expr(value),
+            StatementKind::Return(None) => {}
+            StatementKind::Try(body, name, handler) => {
+                self.block(body);
+                self.locals.push(HashSet::from([name.clone()]));
+                self.block(handler);
+                self.locals.pop();
+            }
+            StatementKind::Struct(name, _) => self.resolve(name),
+            StatementKind::Match(scrutinee, arms) => {
+                self.expr(scrutinee);
+                for (pattern, body) in arms {
+                    if let MatchPattern::Const(value) = pattern {
+                        self.expr(value);
+                    }
+                    self.block(body);
+                }
+            }
+            StatementKind::Function(name, params, result, body) => {
+                if top_level {
+                    self.resolve(name);
+                } else {
+                    self.bind(name);
+                }
+                self.function(params, result, body);
+            }
+            StatementKind::Extern(_, params, result) => {
+                for (_, ty) in params.iter_mut() {
+                    self.optional_type(ty);
+                }
+                self.optional_type(result);
+            }
+            StatementKind::Call(name, args) => {
+                args.iter_mut().for_each(|arg| self.expr(arg));
+                self.resolve(name);
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::Number(_) | ExprKind::Float(_) | ExprKind::Bool(_) | ExprKind::Str(_) => {}
+            ExprKind::Variable(name) => self.resolve(name),
+            ExprKind::Call(name, args) => {
+                args.iter_mut().for_each(|arg| self.expr(arg));
+                self.resolve(name);
+            }
+            ExprKind::UnaryOp(_, operand) | ExprKind::Field(operand, _) => self.expr(operand),
+            ExprKind::BinaryOp(left, _, right)
+            | ExprKind::Index(left, right)
+            | ExprKind::ArrayRepeat(left, right) => {
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::Array(values) => values.iter_mut().for_each(|value| self.expr(value)),
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.expr(value);
+                }
+            }
+            ExprKind::StructLiteral(name, fields) => {
+                fields.iter_mut().for_each(|(_, value)| self.expr(value));
+                self.resolve(name);
+            }
+            ExprKind::Conditional(condition, then_expr, else_expr) => {
+                self.expr(condition);
+                self.expr(then_expr);
+                self.expr(else_expr);
+            }
+            ExprKind::Closure(_, params, result, body) => self.function(params, result, body),
+        }
+    }
+
+    fn optional_type(&mut self, ty: &mut Option<Type>) {
+        if let Some(ty) = ty {
+            self.ty(ty);
+        }
+    }
+
+    fn ty(&mut self, ty: &mut Type) {
+        match ty {
+            Type::Struct(name) => self.resolve(name),
+            Type::Function(params, result) => {
+                params.iter_mut().for_each(|param| self.ty(param));
+                self.ty(result);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io;
+    use std::path::Path;
+
+    use super::ModuleLoader;
+    use crate::compiler::Compiler;
+    use crate::VM;
+
+    fn in_memory(files: &[(&str, &str)]) -> ModuleLoader {
+        let files: HashMap<String, String> = files
+            .iter()
+            .map(|(path, source)| (path.to_string(), source.to_string()))
+            .collect();
+        ModuleLoader::with_reader(move |path: &Path| {
+            files
+                .get(path.to_str().unwrap())
+                .cloned()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        })
+    }
+
+    #[test]
+    fn links_imported_declarations() {
+        let mut loader = in_memory(&[
+            (
+                "main.svm",
+                "import \"lib/math.svm\"; import \"lib/shapes.svm\";
+                 let p = Point { x: 3, y: 4 };
+                 let d = square(p.x) + square(p.y);
+                 let s = SCALE;",
+            ),
+            (
+                "lib/math.svm",
+                "const SCALE = 10; fn square(n) { return n * n; }",
+            ),
+            (
+                "lib/shapes.svm",
+                "import \"math.svm\"; struct Point { x, y }",
+            ),
+        ]);
+        let statements = loader.load("main.svm").unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_memory().get(&1), Some(&25));
+        assert_eq!(vm.get_memory().get(&2), Some(&10));
+        let mut imports: Vec<&Path> = loader.imports().collect();
+        imports.sort();
+        assert_eq!(
+            imports,
+            [Path::new("lib/math.svm"), Path::new("lib/shapes.svm")]
+        );
+    }
+
+    #[test]
+    fn rejects_import_cycles() {
+        let mut loader = in_memory(&[
+            ("a.svm", "import \"b.svm\"; fn a() { return 1; }"),
+            ("b.svm", "import \"a.svm\"; fn b() { return 2; }"),
+        ]);
+        assert_eq!(
+            loader.load("a.svm").err(),
+            Some("Import cycle: a.svm -> b.svm -> a.svm".to_string())
+        );
+    }
+
+    #[test]
+    fn gives_each_module_its_own_namespace() {
+        let mut loader = in_memory(&[
+            (
+                "main.svm",
+                "import \"a.svm\"; import \"b.svm\";
+                 fn helper() { return 100; }
+                 let x = a() + b() + helper();",
+            ),
+            (
+                "a.svm",
+                "fn helper(n) { return n + 1; } fn a() { return helper(1); }",
+            ),
+            (
+                "b.svm",
+                "const K = 20; fn helper() { return K; }
+                 fn b() { let helper = 3; return helper + K; }",
+            ),
+        ]);
+        let statements = loader.load("main.svm").unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+        vm.run().unwrap();
+        assert_eq!(vm.get_memory().get(&0), Some(&(2 + 23 + 100)));
+    }
+
+    #[test]
+    fn rejects_invalid_modules() {
+        let mut loader = in_memory(&[
+            (
+                "main.svm",
+                "import \"a.svm\"; import \"b.svm\"; let x = f();",
+            ),
+            ("a.svm", "fn f() { return 1; }"),
+            ("b.svm", "fn f() { return 2; }"),
+        ]);
+        assert_eq!(
+            loader.load("main.svm").err(),
+            Some("main.svm: 'f' is imported from both 'a.svm' and 'b.svm'".to_string())
+        );
+
+        let mut loader = in_memory(&[
+            ("main.svm", "import \"a.svm\"; import \"b.svm\";"),
+            ("a.svm", "extern fn log(x);"),
+            ("b.svm", "fn log(x) { return x; }"),
+        ]);
+        assert_eq!(
+            loader.load("main.svm").err(),
+            Some("'log' is defined in both 'a.svm' and 'b.svm'".to_string())
+        );
+
+        let mut loader = in_memory(&[("main.svm", "import \"a.svm\";"), ("a.svm", "print 1;")]);
+        assert!(loader.load("main.svm").is_err());
+
+        let mut loader = in_memory(&[("main.svm", "import \"missing.svm\";")]);
+        assert!(loader.load("main.svm").is_err());
+
+        // Every syntax error of a module is reported, each where it is
+        let mut loader = in_memory(&[
+            ("main.svm", "import \"a.svm\";"),
+            (
+                "a.svm",
+                "fn f() { return 1; }\nconst K = ;\nconst L = 0b12;",
+            ),
+        ]);
+        assert_eq!(
+            loader.load("main.svm").err(),
+            Some(
+                "a.svm:2:11: Expected expression, found ';'\n\
+                 a.svm:3:11: Malformed integer literal '0b12'"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn locates_compile_errors_in_the_module_they_are_in() {
+        let mut loader = in_memory(&[
+            ("main.svm", "import \"lib.svm\";\nprint twice(1);"),
+            ("lib.svm", "fn twice(n) {\n    return n + m;\n}"),
+        ]);
+        let statements = loader.load("main.svm").unwrap();
+        let error = Compiler::new().compile(statements).unwrap_err();
+        assert_eq!(
+            loader.locate(&error),
+            "lib.svm:2:16: Undefined variable 'm'"
+        );
+
+        let mut loader = in_memory(&[("main.svm", "print 1;\nprint y;")]);
+        let statements = loader.load("main.svm").unwrap();
+        let error = Compiler::new().compile(statements).unwrap_err();
+        assert_eq!(
+            loader.locate(&error),
+            "main.svm:2:7: Undefined variable 'y'"
+        );
+    }
+}
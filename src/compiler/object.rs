@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::parser::Type;
+
+/// A compiled program that is not yet runnable on its own, emitted by
+/// `Compiler::compile_object` and combined with others by a `Linker`.
+///
+/// The code is laid out as if the object were loaded at address 0, with
+/// its globals from address 0 and its host bindings numbered from 0. It has
+/// no `Halt` and no data segment; the linker appends both. Objects always
+/// use the fixed operand encoding.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Object {
+    pub code: Vec<u8>,
+    /// String constants, referenced by index from `RelocationKind::String`
+    pub strings: Vec<String>,
+    /// Number of global variable addresses the code uses
+    pub globals: usize,
+    /// Number of `extern` functions the code binds
+    pub host_bindings: usize,
+    /// The functions the object defines, followed by the ones it calls but
+    /// expects another object to define
+    pub symbols: Vec<Symbol>,
+    /// Operands the linker has to fix up, in code order
+    pub relocations: Vec<Relocation>,
+}
+
+impl Object {
+    /// The function named `name` that this object defines.
+    pub fn defined(&self, name: &str) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name && symbol.offset.is_some())
+    }
+}
+
+/// A top-level function in an object's symbol table.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Symbol {
+    pub name: String,
+    /// The function's signature as inferred by the type checker
+    pub signature: Type,
+    /// Entry point within the object's code, `None` for a function the
+    /// object only calls
+    pub offset: Option<usize>,
+}
+
+impl Symbol {
+    pub fn arity(&self) -> usize {
+        match &self.signature {
+            Type::Function(params, _) => params.len(),
+            _ => 0,
+        }
+    }
+}
+
+/// An 8-byte `Push` operand whose value depends on where the linker places
+/// the object and its data.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Relocation {
+    /// Position of the operand within the object's code
+    pub offset: usize,
+    pub kind: RelocationKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RelocationKind {
+    /// A code address within the object, such as a jump target
+    Code,
+    /// The address of one of the object's global variables
+    Global,
+    /// The index of one of the object's host function bindings
+    HostBinding,
+    /// The data offset of the string at this index of `Object::strings`
+    String(usize),
+    /// The entry point of a function, possibly defined by another object
+    Symbol(String),
+}
@@ -0,0 +1,277 @@
+use crate::compiler::{
+    lexer::Token,
+    parser::{BinaryOpKind, UnaryOpKind},
+};
+
+/// Binding strength of the built-in operator groups, weakest first. The
+/// levels are spaced out so custom operators can be placed between them.
+pub mod precedence {
+    /// `c ? a : b`
+    pub const CONDITIONAL: u8 = 10;
+    /// `||`
+    pub const OR: u8 = 20;
+    /// `&&`
+    pub const AND: u8 = 30;
+    /// `==`, `!=`, `<`, `>`, `<=`, `>=`
+    pub const COMPARISON: u8 = 40;
+    /// `<<`, `>>`
+    pub const SHIFT: u8 = 45;
+    /// `+`, `-`
+    pub const ADDITIVE: u8 = 50;
+    /// `*`, `/`, `%`
+    pub const MULTIPLICATIVE: u8 = 60;
+    /// `!`, unary `-` and custom prefix operators
+    pub const PREFIX: u8 = 70;
+    /// Indexing, field access and everything that needs no parentheses
+    pub const POSTFIX: u8 = 80;
+}
+
+/// How a chain of operators with the same precedence groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Associativity {
+    /// `a - b - c` is `(a - b) - c`
+    Left,
+    /// `a ? b : c ? d : e` is `a ? b : (c ? d : e)`
+    Right,
+}
+
+/// The expression an operator builds from its operands.
+#[derive(Debug, Clone)]
+pub enum OperatorAction {
+    /// A built-in binary operation
+    Binary(BinaryOpKind),
+    /// A built-in unary operation
+    Unary(UnaryOpKind),
+    /// A call to the named function, with the operands as its arguments
+    Call(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct BinaryOperator {
+    pub token: Token,
+    pub precedence: u8,
+    pub associativity: Associativity,
+    pub action: OperatorAction,
+}
+
+#[derive(Debug, Clone)]
+pub struct PrefixOperator {
+    pub token: Token,
+    pub action: OperatorAction,
+}
+
+/// The binary and prefix operators the parser recognizes in expressions,
+/// starting with the built-in ones.
+///
+/// Custom operators are sugar for function calls: registering `max` as a
+/// binary operator calling `max` makes `a max b` parse as `max(a, b)`. Any
+/// token can be an operator, most usefully an identifier, which then no
+/// longer reads as a variable where the operator is expected. Registering
+/// a token again replaces its previous meaning.
+#[derive(Debug, Clone)]
+pub struct OperatorTable {
+    binary: Vec<BinaryOperator>,
+    prefix: Vec<PrefixOperator>,
+}
+
+impl Default for OperatorTable {
+    fn default() -> Self {
+        let binary = [
+            (Token::OrOr, BinaryOpKind::Or),
+            (Token::AndAnd, BinaryOpKind::And),
+            (Token::DoubleEquals, BinaryOpKind::Equals),
+            (Token::NotEquals, BinaryOpKind::NotEquals),
+            (Token::LessThan, BinaryOpKind::LessThan),
+            (Token::GreaterThan, BinaryOpKind::GreaterThan),
+            (Token::LessEqual, BinaryOpKind::LessEqual),
+            (Token::GreaterEqual, BinaryOpKind::GreaterEqual),
+            (Token::ShiftLeft, BinaryOpKind::ShiftLeft),
+            (Token::ShiftRight, BinaryOpKind::ShiftRight),
+            (Token::Plus, BinaryOpKind::Add),
+            (Token::Minus, BinaryOpKind::Sub),
+            (Token::Star, BinaryOpKind::Mul),
+            (Token::Slash, BinaryOpKind::Div),
+            (Token::Percent, BinaryOpKind::Mod),
+        ];
+        let prefix = [
+            (Token::Bang, UnaryOpKind::Not),
+            (Token::Minus, UnaryOpKind::Neg),
+        ];
+        OperatorTable {
+            binary: binary
+                .into_iter()
+                .map(|(token, op)| BinaryOperator {
+                    token,
+                    precedence: binary_precedence(&op),
+                    associativity: Associativity::Left,
+                    action: OperatorAction::Binary(op),
+                })
+                .collect(),
+            prefix: prefix
+                .into_iter()
+                .map(|(token, op)| PrefixOperator {
+                    token,
+                    action: OperatorAction::Unary(op),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl OperatorTable {
+    /// Makes `left <token> right` a call to `function(left, right)`. The
+    /// precedence must be below `precedence::POSTFIX`; at or above
+    /// `precedence::PREFIX` the operator binds tighter than prefix operators.
+    pub fn add_binary(
+        &mut self,
+        token: Token,
+        precedence: u8,
+        associativity: Associativity,
+        function: &str,
+    ) {
+        self.binary.retain(|operator| operator.token != token);
+        self.binary.push(BinaryOperator {
+            token,
+            precedence: precedence.min(precedence::POSTFIX - 1),
+            associativity,
+            action: OperatorAction::Call(function.to_string()),
+        });
+    }
+
+    /// Makes `<token> operand` a call to `function(operand)`.
+    pub fn add_prefix(&mut self, token: Token, function: &str) {
+        self.prefix.retain(|operator| operator.token != token);
+        self.prefix.push(PrefixOperator {
+            token,
+            action: OperatorAction::Call(function.to_string()),
+        });
+    }
+
+    /// The binary operator spelled `token`, if there is one.
+    pub fn binary(&self, token: &Token) -> Option<&BinaryOperator> {
+        self.binary.iter().find(|operator| operator.token == *token)
+    }
+
+    /// The prefix operator spelled `token`, if there is one.
+    pub fn prefix(&self, token: &Token) -> Option<&PrefixOperator> {
+        self.prefix.iter().find(|operator| operator.token == *token)
+    }
+}
+
+/// The precedence of a built-in binary operation.
+pub fn binary_precedence(op: &BinaryOpKind) -> u8 {
+    use precedence::*;
+
+    match op {
+        BinaryOpKind::Or => OR,
+        BinaryOpKind::And => AND,
+        BinaryOpKind::Equals
+        | BinaryOpKind::NotEquals
+        | BinaryOpKind::LessThan
+        | BinaryOpKind::GreaterThan
+        | BinaryOpKind::LessEqual
+        | BinaryOpKind::GreaterEqual => COMPARISON,
+        BinaryOpKind::ShiftLeft | BinaryOpKind::ShiftRight => SHIFT,
+        BinaryOpKind::Add | BinaryOpKind::Sub => ADDITIVE,
+        BinaryOpKind::Mul | BinaryOpKind::Div | BinaryOpKind::Mod => MULTIPLICATIVE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{precedence, Associativity};
+    use crate::compiler::{
+        lexer::Token,
+        parser::{Expr, ExprKind, Parser, StatementKind, UnaryOpKind},
+        Compiler,
+    };
+    use crate::VM;
+
+    /// Renders an expression with every operation parenthesized.
+    fn render(expr: &Expr) -> String {
+        match &expr.kind {
+            ExprKind::Number(n) => n.to_string(),
+            ExprKind::Variable(name) => name.clone(),
+            ExprKind::UnaryOp(UnaryOpKind::Not, operand) => format!("(!{})", render(operand)),
+            ExprKind::UnaryOp(UnaryOpKind::Neg, operand) => format!("(-{})", render(operand)),
+            ExprKind::BinaryOp(left, op, right) => {
+                format!("({} {:?} {})", render(left), op, render(right))
+            }
+            ExprKind::Conditional(condition, then_expr, else_expr) => format!(
+                "({} ? {} : {})",
+                render(condition),
+                render(then_expr),
+                render(else_expr)
+            ),
+            ExprKind::Call(name, args) => {
+                let args: Vec<String> = args.iter().map(render).collect();
+                format!("{}({})", name, args.join(", "))
+            }
+            other => panic!("unexpected expression {:?}", other),
+        }
+    }
+
+    fn parse(parser: &mut Parser) -> String {
+        let statements = parser.parse_program().unwrap();
+        match &statements[0].kind {
+            StatementKind::Let(_, _, value) => render(value),
+            other => panic!("unexpected statement {:?}", other),
+        }
+    }
+
+    fn identifier(name: &str) -> Token {
+        Token::Identifier(name.to_string())
+    }
+
+    #[test]
+    fn parses_built_in_operators() {
+        let mut parser = Parser::new("let r = !a || b && c == 1 + 2 * -d ? e : f ? 1 : 2 - 3 - 4;");
+        assert_eq!(
+            parse(&mut parser),
+            "(((!a) Or (b And (c Equals (1 Add (2 Mul (-d)))))) ? e : (f ? 1 : ((2 Sub 3) Sub 4)))"
+        );
+    }
+
+    #[test]
+    fn parses_custom_operators() {
+        let mut parser = Parser::new("let r = twice a + b max c * d pow e pow -2;");
+        parser.register_binary_operator(
+            identifier("max"),
+            precedence::ADDITIVE + 5,
+            Associativity::Left,
+            "max",
+        );
+        parser.register_binary_operator(
+            identifier("pow"),
+            precedence::PREFIX + 1,
+            Associativity::Right,
+            "pow",
+        );
+        parser.register_prefix_operator(identifier("twice"), "twice");
+        assert_eq!(
+            parse(&mut parser),
+            "(twice(a) Add max(b, (c Mul pow(d, pow(e, -2)))))"
+        );
+    }
+
+    #[test]
+    fn compiles_custom_operators_as_calls() {
+        let code = "
+            fn pow(base, exp) { let r = 1; while exp > 0 { r = r * base; exp--; } return r; }
+            let r = 2 pow 3 pow 2 - 1;
+        ";
+        let mut parser = Parser::new(code);
+        parser.register_binary_operator(
+            identifier("pow"),
+            precedence::PREFIX + 1,
+            Associativity::Right,
+            "pow",
+        );
+        let statements = parser.parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        let mut vm = VM::new(compiler.compile(statements).unwrap(), 100);
+        vm.run().unwrap();
+        assert_eq!(vm.get_memory()[&0], 511);
+    }
+}
@@ -1,87 +1,456 @@
-use crate::compiler::lexer::{Lexer, Token};
+use std::collections::HashSet;
+use std::fmt;
 
-#[derive(Debug)]
-pub enum Expr {
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::{
+    error::{Expected, ParseError},
+    lexer::{Lexer, SpannedToken, Token},
+    operators::{precedence, Associativity, OperatorAction, OperatorTable},
+    span::Span,
+};
+
+/// An expression with the source it was parsed from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ExprKind {
     Number(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    UnaryOp(UnaryOpKind, Box<Expr>),
     BinaryOp(Box<Expr>, BinaryOpKind, Box<Expr>),
     Variable(String),
+    Call(String, Vec<Expr>),
+    Array(Vec<Expr>),
+    /// `[value; count]`, where the count is a constant expression
+    ArrayRepeat(Box<Expr>, Box<Expr>),
+    /// `{key: value, ...}`
+    Map(Vec<(Expr, Expr)>),
+    Index(Box<Expr>, Box<Expr>),
+    StructLiteral(String, Vec<(String, Expr)>),
+    Field(Box<Expr>, String),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// `fn(param: type, ...) -> type { ... }`; the id is unique per parser
+    /// and keys the type checker's inferred signature
+    Closure(usize, Vec<Param>, Option<Type>, Vec<Statement>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum BinaryOpKind {
     Add,
     Sub,
     Mul,
     Div,
+    Mod,
     Equals,
+    NotEquals,
     LessThan,
     GreaterThan,
     LessEqual,
     GreaterEqual,
+    ShiftLeft,
+    /// Arithmetic shift, keeping the sign
+    ShiftRight,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum UnaryOpKind {
+    Not,
+    Neg,
+}
+
+/// A type named in a `let x: type = ...;` or parameter annotation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Type {
+    Int,
+    Float,
+    Bool,
+    Str,
+    Array,
+    Map,
+    Struct(String),
+    /// `fn(params) -> result`, the type of a function value
+    Function(Vec<Type>, Box<Type>),
+    /// The type of a binding without an annotation
+    Unknown,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Float => write!(f, "float"),
+            Type::Bool => write!(f, "bool"),
+            Type::Str => write!(f, "string"),
+            Type::Array => write!(f, "array"),
+            Type::Map => write!(f, "map"),
+            Type::Struct(name) => write!(f, "{}", name),
+            Type::Function(params, result) => {
+                write!(f, "fn(")?;
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", param)?;
+                }
+                write!(f, ")")?;
+                if **result != Type::Unknown {
+                    write!(f, " -> {}", result)?;
+                }
+                Ok(())
+            }
+            Type::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// A function parameter with its optional type annotation.
+pub type Param = (String, Option<Type>);
+
+/// Converts an integer literal, negated when preceded by a unary minus.
+/// `i64::MIN` is only representable in its negated form.
+fn integer_literal(value: u64, negative: bool, span: Span) -> Result<i64, ParseError> {
+    if negative {
+        0i64.checked_sub_unsigned(value)
+    } else {
+        i64::try_from(value).ok()
+    }
+    .ok_or_else(|| ParseError::IntegerOutOfRange {
+        literal: format!("{}{}", if negative { "-" } else { "" }, value),
+        span,
+    })
+}
+
+/// A statement with the source it was parsed from, up to and including
+/// its closing `;` or `}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
 }
 
-#[derive(Debug)]
-pub enum Statement {
-    Let(String, Expr),
+impl Statement {
+    pub fn new(kind: StatementKind, span: Span) -> Self {
+        Statement { kind, span }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StatementKind {
+    Let(String, Option<Type>, Expr),
+    Const(String, Expr),
     Assign(String, Expr),
+    /// `let (a, b) = (x, y);`, binding each name to the value at its position
+    LetTuple(Vec<String>, Vec<Expr>),
+    /// `(a, b) = (b, a);`; every value is evaluated before any is assigned
+    AssignTuple(Vec<String>, Vec<Expr>),
+    IndexAssign(String, Expr, Expr),
+    Increment(String),
+    Decrement(String),
     If(Expr, Vec<Statement>, Vec<Statement>),
     While(Expr, Vec<Statement>),
-    Print(Expr),
+    /// `print a, b, ...;`, printing several values on one line separated by spaces
+    Print(Vec<Expr>),
+    /// `print("x = {}", x);`, with one argument per `{}` placeholder
+    PrintFormatted(String, Vec<Expr>),
+    Assert(Expr),
+    /// `exit(code);`, stopping the program with an exit code
+    Exit(Expr),
+    /// `throw code;`, unwinding to the innermost enclosing `try`
+    Throw(Expr),
+    /// `try { ... } catch (name) { ... }`; the handler runs with the thrown
+    /// value bound to `name`
+    Try(Vec<Statement>, String, Vec<Statement>),
+    Struct(String, Vec<String>),
+    Match(Expr, Vec<(MatchPattern, Vec<Statement>)>),
+    /// `fn name(param: type, ...) -> type { ... }`; annotations are optional
+    Function(String, Vec<Param>, Option<Type>, Vec<Statement>),
+    /// `extern fn name(params) -> type;`, a function provided by the host
+    Extern(String, Vec<Param>, Option<Type>),
+    Return(Option<Expr>),
+    /// A call evaluated for its effects, `name(args);`
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MatchPattern {
+    /// An integer or character literal
+    Number(i64),
+    /// Any other constant expression, such as the name of a `const`
+    Const(Expr),
+    Wildcard,
+}
+
+/// Reads the next token and its span; the end of input has an empty span
+/// after the last token.
+fn read_token(lexer: &mut Lexer) -> (Option<Token>, Span) {
+    match lexer.next() {
+        Some(Ok(SpannedToken { token, span })) => (Some(token), span),
+        Some(Err(error)) => (Some(Token::Invalid(error)), lexer.last_span()),
+        None => (None, lexer.location()),
+    }
+}
+
+fn binary(left: Expr, op: BinaryOpKind, right: Expr) -> Expr {
+    let span = left.span.to(right.span);
+    Expr::new(
+        ExprKind::BinaryOp(Box::new(left), op, Box::new(right)),
+        span,
+    )
 }
 
 pub struct Parser {
     lexer: Lexer,
     current_token: Option<Token>,
+    current_span: Span,
+    /// Span of the last token consumed, where the node being parsed ends
+    previous_span: Span,
+    /// Struct names declared so far; `Name { ... }` is only a struct literal
+    /// for these, so `if flag { ... }` keeps parsing as a block.
+    struct_names: HashSet<String>,
+    next_closure_id: usize,
+    /// Binary and prefix operators recognized in expressions
+    operators: OperatorTable,
+    /// Errors recovered from so far
+    errors: Vec<ParseError>,
 }
 
 impl Parser {
     pub fn new(input: &str) -> Self {
-        let mut lexer = Lexer::new(input);
-        let current_token = lexer.next_token();
+        Self::with_file(input, 0)
+    }
+
+    /// A parser whose spans are in file `file` of a program's file table,
+    /// for a module of a program split across files.
+    pub fn with_file(input: &str, file: usize) -> Self {
+        let mut lexer = Lexer::with_file(input, file);
+        let (current_token, current_span) = read_token(&mut lexer);
         Parser {
             lexer,
             current_token,
+            current_span,
+            previous_span: Span {
+                file,
+                ..Span::default()
+            },
+            struct_names: HashSet::new(),
+            next_closure_id: 0,
+            operators: OperatorTable::default(),
+            errors: Vec::new(),
         }
     }
 
     fn advance(&mut self) {
-        self.current_token = self.lexer.next_token();
+        self.previous_span = self.current_span;
+        (self.current_token, self.current_span) = read_token(&mut self.lexer);
+    }
+
+    /// The span from `start` to the end of the last token consumed.
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.previous_span)
+    }
+
+    /// An error for finding the current token where `expected` should be.
+    /// A malformed token is reported as the lexer error it carries.
+    fn unexpected(&self, expected: Expected) -> ParseError {
+        match &self.current_token {
+            Some(Token::Invalid(error)) => ParseError::Lex {
+                error: error.clone(),
+                span: self.current_span,
+            },
+            found => ParseError::Unexpected {
+                expected,
+                found: found.clone(),
+                span: self.current_span,
+            },
+        }
+    }
+
+    fn expect_identifier(&mut self) -> Result<String, ParseError> {
+        if let Some(Token::Identifier(name)) = self.current_token.clone() {
+            self.advance();
+            Ok(name)
+        } else {
+            Err(self.unexpected(Expected::Identifier))
+        }
     }
 
-    fn expect(&mut self, expected: Token) -> Result<(), String> {
-        if self.current_token == Some(expected.clone()) {
+    fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+        if self.current_token.as_ref() == Some(&expected) {
             self.advance();
             Ok(())
         } else {
-            Err(format!(
-                "Expected {:?}, got {:?}",
-                expected, self.current_token
-            ))
+            Err(self.unexpected(Expected::Token(expected)))
         }
     }
 
-    pub fn parse_program(&mut self) -> Result<Vec<Statement>, String> {
+    /// Parses the `import "path";` statements at the start of a module and
+    /// returns their paths. Imports are resolved by `ModuleLoader`.
+    pub fn parse_imports(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut imports = Vec::new();
+        while self.current_token == Some(Token::Import) {
+            self.advance();
+            match self.current_token.clone() {
+                Some(Token::Str(path)) => {
+                    self.advance();
+                    imports.push(path);
+                }
+                _ => return Err(self.unexpected(Expected::ModulePath)),
+            }
+            self.expect(Token::Semicolon)?;
+        }
+        Ok(imports)
+    }
+
+    /// Makes structs declared elsewhere, such as in imported modules, usable
+    /// in struct literals.
+    pub fn declare_structs(&mut self, names: impl IntoIterator<Item = String>) {
+        self.struct_names.extend(names);
+    }
+
+    /// Registers a custom binary operator: `left <token> right` parses as a
+    /// call to `function(left, right)`. See `OperatorTable` for the
+    /// precedence levels of the built-in operators.
+    pub fn register_binary_operator(
+        &mut self,
+        token: Token,
+        precedence: u8,
+        associativity: Associativity,
+        function: &str,
+    ) {
+        self.operators
+            .add_binary(token, precedence, associativity, function);
+    }
+
+    /// Registers a custom prefix operator: `<token> operand` parses as a call
+    /// to `function(operand)`, binding as tightly as `!` and unary `-`.
+    pub fn register_prefix_operator(&mut self, token: Token, function: &str) {
+        self.operators.add_prefix(token, function);
+    }
+
+    /// Replaces the operators recognized in expressions, for sharing one
+    /// table between parsers.
+    pub fn set_operators(&mut self, operators: OperatorTable) {
+        self.operators = operators;
+    }
+
+    /// Parses a whole program, recovering from syntax errors to report every
+    /// one of them, in source order. A statement with an error is skipped up
+    /// to the next `;`, the end of its block, or the start of a statement.
+    pub fn parse_program(&mut self) -> Result<Vec<Statement>, Vec<ParseError>> {
         let mut statements = Vec::new();
         while self.current_token.is_some() {
-            statements.push(self.parse_statement()?);
+            statements.extend(self.parse_statement_or_recover());
+            // Skip a `}` that closes no block
+            if self.current_token == Some(Token::RBrace) {
+                self.errors.push(self.unexpected(Expected::Statement));
+                self.advance();
+            }
+        }
+        if self.errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    fn parse_statement_or_recover(&mut self) -> Option<Statement> {
+        let start = self.current_span;
+        match self.parse_statement() {
+            Ok(statement) => Some(statement),
+            Err(error) => {
+                self.errors.push(error);
+                self.synchronize(start);
+                None
+            }
         }
-        Ok(statements)
     }
 
-    fn parse_statement(&mut self) -> Result<Statement, String> {
+    /// Skips tokens after a syntax error until the parser is at a point where
+    /// a statement can start: after a `;`, before a `}` or before a keyword
+    /// that starts a statement. Always moves past the token the failed
+    /// statement started at.
+    fn synchronize(&mut self, start: Span) {
+        if self.current_span == start {
+            self.advance();
+        }
+        while let Some(token) = &self.current_token {
+            match token {
+                Token::Semicolon => {
+                    self.advance();
+                    return;
+                }
+                Token::RBrace
+                | Token::Let
+                | Token::Const
+                | Token::If
+                | Token::While
+                | Token::Print
+                | Token::Assert
+                | Token::Exit
+                | Token::Throw
+                | Token::Try
+                | Token::Struct
+                | Token::Match
+                | Token::Fn
+                | Token::Extern
+                | Token::Return
+                | Token::Import => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn parse_statement(&mut self) -> Result<Statement, ParseError> {
+        let start = self.current_span;
+        let kind = self.parse_statement_kind()?;
+        Ok(Statement::new(kind, self.span_from(start)))
+    }
+
+    fn parse_statement_kind(&mut self) -> Result<StatementKind, ParseError> {
         match &self.current_token {
+            Some(Token::Import) => Err(ParseError::MisplacedImport {
+                span: self.current_span,
+            }),
             Some(Token::Let) => {
                 self.advance();
+                if self.current_token == Some(Token::LParen) {
+                    let (names, values) = self.parse_destructuring()?;
+                    return Ok(StatementKind::LetTuple(names, values));
+                }
                 if let Some(Token::Identifier(name)) = self.current_token.clone() {
                     self.advance();
+                    let annotation = self.parse_annotation()?;
                     self.expect(Token::Equals)?;
                     let expr = self.parse_expression()?;
                     self.expect(Token::Semicolon)?;
-                    Ok(Statement::Let(name, expr))
+                    Ok(StatementKind::Let(name, annotation, expr))
                 } else {
-                    Err("Expected identifier after 'let'".to_string())
+                    Err(self.unexpected(Expected::Identifier))
                 }
             }
+            Some(Token::Const) => {
+                self.advance();
+                let name = self.expect_identifier()?;
+                self.expect(Token::Equals)?;
+                let expr = self.parse_expression()?;
+                self.expect(Token::Semicolon)?;
+                Ok(StatementKind::Const(name, expr))
+            }
             Some(Token::If) => {
                 self.advance();
                 let condition = self.parse_expression()?;
@@ -92,42 +461,179 @@ impl Parser {
                 } else {
                     Vec::new()
                 };
-                Ok(Statement::If(condition, then_block, else_block))
+                Ok(StatementKind::If(condition, then_block, else_block))
             }
             Some(Token::While) => {
                 self.advance();
                 let condition = self.parse_expression()?;
                 let block = self.parse_block()?;
-                Ok(Statement::While(condition, block))
+                Ok(StatementKind::While(condition, block))
             }
             Some(Token::Print) => {
+                self.advance();
+                if self.at_format_string() {
+                    self.advance();
+                    let Some(Token::Str(format)) = self.current_token.take() else {
+                        unreachable!()
+                    };
+                    self.advance();
+                    let args = if self.current_token == Some(Token::Comma) {
+                        self.advance();
+                        self.parse_arguments()?
+                    } else {
+                        self.expect(Token::RParen)?;
+                        Vec::new()
+                    };
+                    self.expect(Token::Semicolon)?;
+                    return Ok(StatementKind::PrintFormatted(format, args));
+                }
+                let mut exprs = vec![self.parse_expression()?];
+                while self.current_token == Some(Token::Comma) {
+                    self.advance();
+                    exprs.push(self.parse_expression()?);
+                }
+                self.expect(Token::Semicolon)?;
+                Ok(StatementKind::Print(exprs))
+            }
+            Some(Token::Match) => {
+                self.advance();
+                let scrutinee = self.parse_expression()?;
+                self.expect(Token::LBrace)?;
+                let mut arms = Vec::new();
+                while self.current_token != Some(Token::RBrace) {
+                    let pattern = self.parse_match_pattern()?;
+                    self.expect(Token::FatArrow)?;
+                    let body = self.parse_block()?;
+                    arms.push((pattern, body));
+                    if self.current_token == Some(Token::Comma) {
+                        self.advance();
+                    }
+                }
+                self.expect(Token::RBrace)?;
+                Ok(StatementKind::Match(scrutinee, arms))
+            }
+            Some(Token::Assert) => {
                 self.advance();
                 let expr = self.parse_expression()?;
                 self.expect(Token::Semicolon)?;
-                Ok(Statement::Print(expr))
+                Ok(StatementKind::Assert(expr))
+            }
+            Some(Token::Exit) => {
+                self.advance();
+                self.expect(Token::LParen)?;
+                let code = self.parse_expression()?;
+                self.expect(Token::RParen)?;
+                self.expect(Token::Semicolon)?;
+                Ok(StatementKind::Exit(code))
+            }
+            Some(Token::Throw) => {
+                self.advance();
+                let value = self.parse_expression()?;
+                self.expect(Token::Semicolon)?;
+                Ok(StatementKind::Throw(value))
+            }
+            Some(Token::Try) => {
+                self.advance();
+                let body = self.parse_function_body()?;
+                self.expect(Token::Catch)?;
+                self.expect(Token::LParen)?;
+                let name = self.expect_identifier()?;
+                self.expect(Token::RParen)?;
+                let handler = self.parse_function_body()?;
+                Ok(StatementKind::Try(body, name, handler))
+            }
+            Some(Token::Struct) => {
+                self.advance();
+                let name = self.expect_identifier()?;
+                self.expect(Token::LBrace)?;
+                let mut fields = Vec::new();
+                while self.current_token != Some(Token::RBrace) {
+                    fields.push(self.expect_identifier()?);
+                    if self.current_token != Some(Token::Comma) {
+                        break;
+                    }
+                    self.advance();
+                }
+                self.expect(Token::RBrace)?;
+                self.struct_names.insert(name.clone());
+                Ok(StatementKind::Struct(name, fields))
+            }
+            Some(Token::Fn) => {
+                self.advance();
+                let name = self.expect_identifier()?;
+                let (params, return_type) = self.parse_signature()?;
+                let body = self.parse_function_body()?;
+                Ok(StatementKind::Function(name, params, return_type, body))
+            }
+            Some(Token::Extern) => {
+                self.advance();
+                self.expect(Token::Fn)?;
+                let name = self.expect_identifier()?;
+                let (params, return_type) = self.parse_signature()?;
+                self.expect(Token::Semicolon)?;
+                Ok(StatementKind::Extern(name, params, return_type))
+            }
+            Some(Token::Return) => {
+                self.advance();
+                let value = if self.current_token == Some(Token::Semicolon) {
+                    None
+                } else {
+                    Some(self.parse_expression()?)
+                };
+                self.expect(Token::Semicolon)?;
+                Ok(StatementKind::Return(value))
+            }
+            Some(Token::LParen) => {
+                let (names, values) = self.parse_destructuring()?;
+                Ok(StatementKind::AssignTuple(names, values))
             }
             Some(Token::Identifier(name)) => {
                 let name = name.clone();
                 self.advance();
+                if self.current_token == Some(Token::LParen) {
+                    self.advance();
+                    let args = self.parse_arguments()?;
+                    self.expect(Token::Semicolon)?;
+                    return Ok(StatementKind::Call(name, args));
+                }
+                if self.current_token == Some(Token::LBracket) {
+                    self.advance();
+                    let index = self.parse_expression()?;
+                    self.expect(Token::RBracket)?;
+                    self.expect(Token::Equals)?;
+                    let value = self.parse_expression()?;
+                    self.expect(Token::Semicolon)?;
+                    return Ok(StatementKind::IndexAssign(name, index, value));
+                }
+                if self.current_token == Some(Token::PlusPlus) {
+                    self.advance();
+                    self.expect(Token::Semicolon)?;
+                    return Ok(StatementKind::Increment(name));
+                }
+                if self.current_token == Some(Token::MinusMinus) {
+                    self.advance();
+                    self.expect(Token::Semicolon)?;
+                    return Ok(StatementKind::Decrement(name));
+                }
                 self.expect(Token::Equals)?;
                 let expr = self.parse_expression()?;
                 self.expect(Token::Semicolon)?;
-                Ok(Statement::Assign(name, expr))
+                Ok(StatementKind::Assign(name, expr))
             }
-            _ => Err("Expected statement".to_string()),
+            _ => Err(self.unexpected(Expected::Statement)),
         }
     }
 
-    fn parse_block(&mut self) -> Result<Vec<Statement>, String> {
+    fn parse_block(&mut self) -> Result<Vec<Statement>, ParseError> {
         let mut statements = Vec::new();
 
         match &self.current_token {
-            Some(Token::LParen) => {
+            Some(Token::LBrace) => {
                 self.advance();
-                while self.current_token.is_some() && self.current_token != Some(Token::RParen) {
-                    statements.push(self.parse_statement()?);
+                while self.current_token.is_some() && self.current_token != Some(Token::RBrace) {
+                    statements.extend(self.parse_statement_or_recover());
                 }
-                self.expect(Token::RParen)?;
+                self.expect(Token::RBrace)?;
             }
             _ => {
                 statements.push(self.parse_statement()?);
@@ -137,83 +643,410 @@ impl Parser {
         Ok(statements)
     }
 
-    fn parse_expression(&mut self) -> Result<Expr, String> {
-        self.parse_comparison()
+    fn parse_expression(&mut self) -> Result<Expr, ParseError> {
+        self.parse_binary(precedence::CONDITIONAL)
     }
 
-    fn parse_comparison(&mut self) -> Result<Expr, String> {
-        let mut expr = self.parse_additive()?;
+    /// Parses an expression made of operators that bind at least as tightly
+    /// as `min_precedence`, looking each one up in the operator table.
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_prefix()?;
 
         while let Some(token) = &self.current_token {
-            let op = match token {
-                Token::DoubleEquals => BinaryOpKind::Equals,
-                Token::LessThan => BinaryOpKind::LessThan,
-                Token::GreaterThan => BinaryOpKind::GreaterThan,
-                Token::LessEqual => BinaryOpKind::LessEqual,
-                Token::GreaterEqual => BinaryOpKind::GreaterEqual,
-                _ => break,
+            if *token == Token::Question {
+                if precedence::CONDITIONAL < min_precedence {
+                    break;
+                }
+                self.advance();
+                let then_expr = self.parse_expression()?;
+                self.expect(Token::Colon)?;
+                // Right-associative: `a ? b : c ? d : e` is `a ? b : (c ? d : e)`
+                let else_expr = self.parse_binary(precedence::CONDITIONAL)?;
+                let span = expr.span.to(else_expr.span);
+                expr = Expr::new(
+                    ExprKind::Conditional(Box::new(expr), Box::new(then_expr), Box::new(else_expr)),
+                    span,
+                );
+                continue;
+            }
+            let Some(operator) = self.operators.binary(token) else {
+                break;
+            };
+            if operator.precedence < min_precedence {
+                break;
+            }
+            let next_precedence = match operator.associativity {
+                Associativity::Left => operator.precedence + 1,
+                Associativity::Right => operator.precedence,
             };
+            let action = operator.action.clone();
             self.advance();
-            let right = self.parse_additive()?;
-            expr = Expr::BinaryOp(Box::new(expr), op, Box::new(right));
+            let right = self.parse_binary(next_precedence)?;
+            expr = match action {
+                OperatorAction::Binary(op) => binary(expr, op, right),
+                OperatorAction::Unary(op) => unreachable!("binary operator builds {:?}", op),
+                OperatorAction::Call(function) => {
+                    let span = expr.span.to(right.span);
+                    Expr::new(ExprKind::Call(function, vec![expr, right]), span)
+                }
+            };
         }
 
         Ok(expr)
     }
 
-    fn parse_additive(&mut self) -> Result<Expr, String> {
-        let mut expr = self.parse_multiplicative()?;
+    fn parse_prefix(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span;
+        let Some(operator) = self
+            .current_token
+            .as_ref()
+            .and_then(|token| self.operators.prefix(token))
+        else {
+            return self.parse_postfix();
+        };
+        let action = operator.action.clone();
+        self.advance();
+        let kind = match action {
+            OperatorAction::Unary(UnaryOpKind::Neg) => {
+                // Fold negative literals directly so `i64::MIN` can be written
+                match self.current_token {
+                    Some(Token::Number(n)) => {
+                        self.advance();
+                        ExprKind::Number(integer_literal(n, true, self.span_from(start))?)
+                    }
+                    Some(Token::Float(value)) => {
+                        self.advance();
+                        ExprKind::Float(-value)
+                    }
+                    _ => {
+                        let operand = self.parse_binary(precedence::PREFIX)?;
+                        ExprKind::UnaryOp(UnaryOpKind::Neg, Box::new(operand))
+                    }
+                }
+            }
+            OperatorAction::Unary(op) => {
+                let operand = self.parse_binary(precedence::PREFIX)?;
+                ExprKind::UnaryOp(op, Box::new(operand))
+            }
+            OperatorAction::Binary(op) => unreachable!("prefix operator builds {:?}", op),
+            OperatorAction::Call(function) => {
+                let operand = self.parse_binary(precedence::PREFIX)?;
+                ExprKind::Call(function, vec![operand])
+            }
+        };
+        Ok(Expr::new(kind, self.span_from(start)))
+    }
 
-        while let Some(token) = &self.current_token {
-            let op = match token {
-                Token::Plus => BinaryOpKind::Add,
-                Token::Minus => BinaryOpKind::Sub,
+    fn parse_postfix(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_primary()?;
+
+        loop {
+            match self.current_token {
+                Some(Token::LBracket) => {
+                    self.advance();
+                    let index = self.parse_expression()?;
+                    self.expect(Token::RBracket)?;
+                    let span = self.span_from(expr.span);
+                    expr = Expr::new(ExprKind::Index(Box::new(expr), Box::new(index)), span);
+                }
+                Some(Token::Dot) => {
+                    self.advance();
+                    let field = self.expect_identifier()?;
+                    let span = self.span_from(expr.span);
+                    expr = Expr::new(ExprKind::Field(Box::new(expr), field), span);
+                }
                 _ => break,
-            };
-            self.advance();
-            let right = self.parse_multiplicative()?;
-            expr = Expr::BinaryOp(Box::new(expr), op, Box::new(right));
+            }
         }
 
         Ok(expr)
     }
 
-    fn parse_multiplicative(&mut self) -> Result<Expr, String> {
-        let mut expr = self.parse_primary()?;
-
-        while let Some(token) = &self.current_token {
-            let op = match token {
-                Token::Star => BinaryOpKind::Mul,
-                Token::Slash => BinaryOpKind::Div,
-                _ => break,
-            };
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        let start = self.current_span;
+        if self.current_token == Some(Token::LParen) {
             self.advance();
-            let right = self.parse_primary()?;
-            expr = Expr::BinaryOp(Box::new(expr), op, Box::new(right));
+            let mut expr = self.parse_expression()?;
+            self.expect(Token::RParen)?;
+            // A parenthesized expression covers its parentheses
+            expr.span = self.span_from(start);
+            return Ok(expr);
         }
-
-        Ok(expr)
+        let kind = self.parse_primary_kind()?;
+        Ok(Expr::new(kind, self.span_from(start)))
     }
 
-    fn parse_primary(&mut self) -> Result<Expr, String> {
+    fn parse_primary_kind(&mut self) -> Result<ExprKind, ParseError> {
         match &self.current_token {
             Some(Token::Number(n)) => {
-                let n = *n;
+                let n = integer_literal(*n, false, self.current_span)?;
                 self.advance();
-                Ok(Expr::Number(n))
+                Ok(ExprKind::Number(n))
+            }
+            Some(Token::Float(value)) => {
+                let value = *value;
+                self.advance();
+                Ok(ExprKind::Float(value))
+            }
+            Some(Token::Str(value)) => {
+                let value = value.clone();
+                self.advance();
+                Ok(ExprKind::Str(value))
+            }
+            // Characters are integers holding their Unicode scalar value
+            Some(Token::Char(ch)) => {
+                let ch = *ch;
+                self.advance();
+                Ok(ExprKind::Number(ch as i64))
+            }
+            Some(Token::True) => {
+                self.advance();
+                Ok(ExprKind::Bool(true))
+            }
+            Some(Token::False) => {
+                self.advance();
+                Ok(ExprKind::Bool(false))
             }
             Some(Token::Identifier(name)) => {
                 let name = name.clone();
                 self.advance();
-                Ok(Expr::Variable(name))
+                if self.current_token == Some(Token::LParen) {
+                    self.advance();
+                    let args = self.parse_arguments()?;
+                    Ok(ExprKind::Call(name, args))
+                } else if self.current_token == Some(Token::LBrace)
+                    && self.struct_names.contains(&name)
+                {
+                    self.advance();
+                    let fields = self.parse_field_initializers()?;
+                    Ok(ExprKind::StructLiteral(name, fields))
+                } else {
+                    Ok(ExprKind::Variable(name))
+                }
             }
-            Some(Token::LParen) => {
+            Some(Token::Fn) => {
                 self.advance();
+                let (params, return_type) = self.parse_signature()?;
+                let body = self.parse_function_body()?;
+                let id = self.next_closure_id;
+                self.next_closure_id += 1;
+                Ok(ExprKind::Closure(id, params, return_type, body))
+            }
+            Some(Token::LBracket) => {
+                self.advance();
+                let mut elements = Vec::new();
+                if self.current_token != Some(Token::RBracket) {
+                    let value = self.parse_expression()?;
+                    if self.current_token == Some(Token::Semicolon) {
+                        self.advance();
+                        let count = self.parse_expression()?;
+                        self.expect(Token::RBracket)?;
+                        return Ok(ExprKind::ArrayRepeat(Box::new(value), Box::new(count)));
+                    }
+                    elements.push(value);
+                    while self.current_token == Some(Token::Comma) {
+                        self.advance();
+                        elements.push(self.parse_expression()?);
+                    }
+                }
+                self.expect(Token::RBracket)?;
+                Ok(ExprKind::Array(elements))
+            }
+            Some(Token::LBrace) => {
+                self.advance();
+                let mut entries = Vec::new();
+                while self.current_token != Some(Token::RBrace) {
+                    let key = self.parse_expression()?;
+                    self.expect(Token::Colon)?;
+                    entries.push((key, self.parse_expression()?));
+                    if self.current_token != Some(Token::Comma) {
+                        break;
+                    }
+                    self.advance();
+                }
+                self.expect(Token::RBrace)?;
+                Ok(ExprKind::Map(entries))
+            }
+            _ => Err(self.unexpected(Expected::Expression)),
+        }
+    }
+
+    /// Parses a comma-separated argument list up to and including the closing `)`.
+    fn parse_arguments(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut args = Vec::new();
+        if self.current_token != Some(Token::RParen) {
+            loop {
+                args.push(self.parse_expression()?);
+                if self.current_token != Some(Token::Comma) {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.expect(Token::RParen)?;
+        Ok(args)
+    }
+
+    /// Whether the parser is at `("...",` or `("...")`, the start of a
+    /// formatted print rather than a parenthesized expression.
+    fn at_format_string(&mut self) -> bool {
+        let token = |item: Option<&Result<SpannedToken, _>>| match item {
+            Some(Ok(spanned)) => Some(spanned.token.clone()),
+            _ => None,
+        };
+        self.current_token == Some(Token::LParen)
+            && matches!(token(self.lexer.peek()), Some(Token::Str(_)))
+            && matches!(
+                token(self.lexer.peek_n(1)),
+                Some(Token::Comma | Token::RParen)
+            )
+    }
+
+    /// Parses `(name, ...) = (expr, ...);`, with as many values as names.
+    fn parse_destructuring(&mut self) -> Result<(Vec<String>, Vec<Expr>), ParseError> {
+        self.expect(Token::LParen)?;
+        let mut names: Vec<String> = Vec::new();
+        loop {
+            let span = self.current_span;
+            let name = self.expect_identifier()?;
+            if names.contains(&name) {
+                return Err(ParseError::DuplicateName { name, span });
+            }
+            names.push(name);
+            if self.current_token != Some(Token::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        self.expect(Token::RParen)?;
+        self.expect(Token::Equals)?;
+        let start = self.current_span;
+        self.expect(Token::LParen)?;
+        let values = self.parse_arguments()?;
+        let span = self.span_from(start);
+        self.expect(Token::Semicolon)?;
+        if values.len() != names.len() {
+            return Err(ParseError::DestructuringMismatch {
+                values: values.len(),
+                names: names.len(),
+                span,
+            });
+        }
+        Ok((names, values))
+    }
+
+    /// Parses `field: expr, ...` up to and including the closing `}`.
+    fn parse_field_initializers(&mut self) -> Result<Vec<(String, Expr)>, ParseError> {
+        let mut fields = Vec::new();
+        while self.current_token != Some(Token::RBrace) {
+            let field = self.expect_identifier()?;
+            self.expect(Token::Colon)?;
+            fields.push((field, self.parse_expression()?));
+            if self.current_token != Some(Token::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        self.expect(Token::RBrace)?;
+        Ok(fields)
+    }
+
+    fn parse_match_pattern(&mut self) -> Result<MatchPattern, ParseError> {
+        match &self.current_token {
+            Some(Token::Identifier(name)) if name == "_" => {
+                self.advance();
+                Ok(MatchPattern::Wildcard)
+            }
+            Some(Token::FatArrow) | None => Err(self.unexpected(Expected::MatchPattern)),
+            _ => {
+                let parenthesized = self.current_token == Some(Token::LParen);
                 let expr = self.parse_expression()?;
-                self.expect(Token::RParen)?;
-                Ok(expr)
+                match expr.kind {
+                    ExprKind::Number(n) if !parenthesized => Ok(MatchPattern::Number(n)),
+                    _ => Ok(MatchPattern::Const(expr)),
+                }
+            }
+        }
+    }
+
+    /// Parses `(params) -> type`, with the return type optional.
+    fn parse_signature(&mut self) -> Result<(Vec<Param>, Option<Type>), ParseError> {
+        self.expect(Token::LParen)?;
+        let mut params = Vec::new();
+        while self.current_token != Some(Token::RParen) {
+            let param = self.expect_identifier()?;
+            params.push((param, self.parse_annotation()?));
+            if self.current_token != Some(Token::Comma) {
+                break;
             }
-            _ => Err("Expected expression".to_string()),
+            self.advance();
+        }
+        self.expect(Token::RParen)?;
+        let return_type = if self.current_token == Some(Token::Arrow) {
+            self.advance();
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        Ok((params, return_type))
+    }
+
+    fn parse_function_body(&mut self) -> Result<Vec<Statement>, ParseError> {
+        if self.current_token != Some(Token::LBrace) {
+            return Err(self.unexpected(Expected::FunctionBody));
         }
+        self.parse_block()
     }
+
+    /// Parses an optional `: type` annotation.
+    fn parse_annotation(&mut self) -> Result<Option<Type>, ParseError> {
+        if self.current_token == Some(Token::Colon) {
+            self.advance();
+            Ok(Some(self.parse_type()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn parse_type(&mut self) -> Result<Type, ParseError> {
+        if self.current_token == Some(Token::Fn) {
+            self.advance();
+            self.expect(Token::LParen)?;
+            let mut params = Vec::new();
+            while self.current_token != Some(Token::RParen) {
+                params.push(self.parse_type()?);
+                if self.current_token != Some(Token::Comma) {
+                    break;
+                }
+                self.advance();
+            }
+            self.expect(Token::RParen)?;
+            let result = if self.current_token == Some(Token::Arrow) {
+                self.advance();
+                self.parse_type()?
+            } else {
+                Type::Unknown
+            };
+            return Ok(Type::Function(params, Box::new(result)));
+        }
+        let name = self.expect_identifier()?;
+        Ok(match name.as_str() {
+            "int" => Type::Int,
+            "float" => Type::Float,
+            "bool" => Type::Bool,
+            "string" => Type::Str,
+            "array" => Type::Array,
+            "map" => Type::Map,
+            _ => Type::Struct(name),
+        })
+    }
+}
+
+/// Parses a program and returns its AST as pretty-printed JSON, for tools
+/// that consume the AST without linking against the parser. Every node
+/// carries its `span`; the statements deserialize back into a
+/// `Vec<Statement>` that `Compiler::compile` accepts.
+pub fn parse_to_json(source: &str) -> Result<String, Vec<ParseError>> {
+    let statements = Parser::new(source).parse_program()?;
+    Ok(serde_json::to_string_pretty(&statements).expect("the AST serializes to JSON"))
 }
@@ -0,0 +1,292 @@
+use std::fmt;
+
+use crate::compiler::{
+    dce,
+    error::{CompileError, PassError},
+    fold,
+    parser::Statement,
+    strength,
+};
+
+/// A transformation of a type-checked program, run before code generation.
+pub trait Pass {
+    /// Short name identifying the pass in reports.
+    fn name(&self) -> &str;
+
+    /// Transforms `statements` in place, or fails at the code it cannot
+    /// handle.
+    fn run(&mut self, statements: &mut Vec<Statement>) -> Result<(), PassError>;
+}
+
+/// How much optimization the compiler applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    /// Compile the program as written
+    None,
+    /// Constant folding, strength reduction and hoisting the loads of
+    /// captured variables out of loops in closures
+    #[default]
+    Default,
+    /// Constant folding, strength reduction, then dead code elimination;
+    /// removed variables no longer appear in VM memory
+    Aggressive,
+}
+
+/// Evaluates constant subexpressions; see [`fold::fold_constants`].
+pub struct ConstantFolding;
+
+impl Pass for ConstantFolding {
+    fn name(&self) -> &str {
+        "constant-folding"
+    }
+
+    fn run(&mut self, statements: &mut Vec<Statement>) -> Result<(), PassError> {
+        fold::fold_constants(statements);
+        Ok(())
+    }
+}
+
+/// Replaces arithmetic by powers of two and identity elements with cheaper
+/// operations; see [`strength::reduce_strength`].
+pub struct StrengthReduction;
+
+impl Pass for StrengthReduction {
+    fn name(&self) -> &str {
+        "strength-reduction"
+    }
+
+    fn run(&mut self, statements: &mut Vec<Statement>) -> Result<(), PassError> {
+        strength::reduce_strength(statements);
+        Ok(())
+    }
+}
+
+/// Removes unreachable code and unread variables; see
+/// [`dce::eliminate_dead_code`].
+pub struct DeadCodeElimination;
+
+impl Pass for DeadCodeElimination {
+    fn name(&self) -> &str {
+        "dead-code-elimination"
+    }
+
+    fn run(&mut self, statements: &mut Vec<Statement>) -> Result<(), PassError> {
+        dce::eliminate_dead_code(statements);
+        Ok(())
+    }
+}
+
+/// Program size before and after a pass, in statements (counting those
+/// nested in blocks and function bodies).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassReport {
+    pub pass: String,
+    pub statements_before: usize,
+    pub statements_after: usize,
+}
+
+impl fmt::Display for PassReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {} statements",
+            self.pass, self.statements_before, self.statements_after
+        )
+    }
+}
+
+/// An ordered pipeline of passes.
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl PassManager {
+    /// Creates an empty pipeline.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the pipeline of built-in passes for `level`.
+    pub fn for_level(level: OptLevel) -> Self {
+        let mut manager = Self::new();
+        if level != OptLevel::None {
+            manager.add(ConstantFolding);
+            manager.add(StrengthReduction);
+        }
+        if level == OptLevel::Aggressive {
+            manager.add(DeadCodeElimination);
+        }
+        manager
+    }
+
+    /// Appends `pass` to the end of the pipeline.
+    pub fn add(&mut self, pass: impl Pass + 'static) {
+        self.passes.push(Box::new(pass));
+    }
+
+    /// Names of the passes, in the order they run.
+    pub fn pass_names(&self) -> Vec<&str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    /// Runs every pass in order, reporting the size change of each.
+    pub fn run(
+        &mut self,
+        statements: &mut Vec<Statement>,
+    ) -> Result<Vec<PassReport>, CompileError> {
+        let mut reports = Vec::with_capacity(self.passes.len());
+        for pass in &mut self.passes {
+            let statements_before = count_statements(statements);
+            pass.run(statements)
+                .map_err(|PassError { message, span }| CompileError::Pass {
+                    pass: pass.name().to_string(),
+                    message,
+                    span,
+                })?;
+            reports.push(PassReport {
+                pass: pass.name().to_string(),
+                statements_before,
+                statements_after: count_statements(statements),
+            });
+        }
+        Ok(reports)
+    }
+}
+
+/// Counts statements, including those nested in blocks and function bodies.
+pub fn count_statements(statements: &[Statement]) -> usize {
+    statements
+        .iter()
+        .map(|statement| {
+            1 + dce::nested_blocks(statement)
+                .into_iter()
+                .map(|block| count_statements(block))
+                .sum::<usize>()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OptLevel, Pass, PassError, PassManager};
+    use crate::compiler::parser::{Expr, ExprKind, Parser, Statement, StatementKind};
+    use crate::compiler::Compiler;
+    use crate::VM;
+
+    /// Replaces every `print` with nothing, for testing custom passes.
+    struct StripPrints;
+
+    impl Pass for StripPrints {
+        fn name(&self) -> &str {
+            "strip-prints"
+        }
+
+        fn run(&mut self, statements: &mut Vec<Statement>) -> Result<(), PassError> {
+            statements.retain(|statement| !matches!(statement.kind, StatementKind::Print(_)));
+            Ok(())
+        }
+    }
+
+    /// Fails at the first `print`, for testing how pass errors are reported.
+    struct RejectPrints;
+
+    impl Pass for RejectPrints {
+        fn name(&self) -> &str {
+            "reject-prints"
+        }
+
+        fn run(&mut self, statements: &mut Vec<Statement>) -> Result<(), PassError> {
+            match statements
+                .iter()
+                .find(|statement| matches!(statement.kind, StatementKind::Print(_)))
+            {
+                Some(statement) => Err(PassError {
+                    message: "Programs may not print".to_string(),
+                    span: statement.span,
+                }),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn builds_pipelines_for_each_level() {
+        assert!(PassManager::for_level(OptLevel::None)
+            .pass_names()
+            .is_empty());
+        assert_eq!(
+            PassManager::for_level(OptLevel::Default).pass_names(),
+            ["constant-folding", "strength-reduction"]
+        );
+        assert_eq!(
+            PassManager::for_level(OptLevel::Aggressive).pass_names(),
+            [
+                "constant-folding",
+                "strength-reduction",
+                "dead-code-elimination"
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_custom_passes_with_reports() {
+        let mut manager = PassManager::for_level(OptLevel::Default);
+        manager.add(StripPrints);
+        let mut statements = Parser::new("let x = 1 + 2; print x; print 3;")
+            .parse_program()
+            .unwrap();
+
+        let reports = manager.run(&mut statements).unwrap();
+
+        assert_eq!(statements.len(), 1);
+        assert!(matches!(
+            statements[0].kind,
+            StatementKind::Let(
+                _,
+                _,
+                Expr {
+                    kind: ExprKind::Number(3),
+                    ..
+                }
+            )
+        ));
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[2].to_string(), "strip-prints: 3 -> 1 statements");
+    }
+
+    #[test]
+    fn locates_errors_of_custom_passes() {
+        let statements = Parser::new("let x = 1;\nprint x;").parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.add_pass(RejectPrints);
+        let error = compiler.compile(statements).unwrap_err();
+        assert_eq!(error.span().line, 2);
+        assert_eq!(
+            error.to_string(),
+            "2:1: reject-prints: Programs may not print"
+        );
+        assert_eq!(error.message(), "reject-prints: Programs may not print");
+    }
+
+    #[test]
+    fn opt_level_none_compiles_as_written() {
+        let compile = |level| {
+            let statements = Parser::new("let x = 2 * 3 + 4;").parse_program().unwrap();
+            let mut compiler = Compiler::new();
+            compiler.set_prelude(false);
+            compiler.set_opt_level(level);
+            compiler.compile(statements).unwrap()
+        };
+        let unoptimized = compile(OptLevel::None);
+        let optimized = compile(OptLevel::Default);
+        assert!(optimized.len() < unoptimized.len());
+
+        for bytecode in [unoptimized, optimized] {
+            let mut vm = VM::new(bytecode, 100);
+            vm.run().unwrap();
+            assert_eq!(vm.get_memory().get(&0), Some(&10));
+        }
+    }
+}
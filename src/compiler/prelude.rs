@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+
+use crate::compiler::{
+    error::CompileError,
+    parser::{Parser, Statement, StatementKind},
+};
+
+/// Source of the standard prelude: math, array and string helpers written in
+/// the language itself.
+pub const SOURCE: &str = include_str!("prelude.svm");
+
+/// Places the prelude's functions ahead of `statements`, leaving out any
+/// the program defines itself.
+pub fn link(statements: Vec<Statement>) -> Result<Vec<Statement>, CompileError> {
+    let defined: HashSet<&str> = statements
+        .iter()
+        .filter_map(|statement| match &statement.kind {
+            StatementKind::Function(name, ..) | StatementKind::Extern(name, ..) => {
+                Some(name.as_str())
+            }
+            _ => None,
+        })
+        .collect();
+    let prelude = Parser::new(SOURCE)
+        .parse_program()
+        .map_err(CompileError::Prelude)?;
+    let mut program: Vec<Statement> = prelude
+        .into_iter()
+        .filter(|statement| {
+            !matches!(&statement.kind, StatementKind::Function(name, ..) if defined.contains(name.as_str()))
+        })
+        .collect();
+    program.extend(statements);
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::{Compiler, Parser};
+    use crate::test_util::run;
+
+    #[test]
+    fn provides_helpers() {
+        let vm = run("
+            let g = gcd(-12, 18);
+            let l = lcm(4, 6);
+            let c = clamp(15, 0, 10);
+            let a = [3, 9, 4];
+            let s = array_sum(a);
+            let m = array_max(a);
+            let i = index_of(a, 4);
+            let found = array_contains(a, 7);
+            array_reverse(a);
+            let first = a[0];
+            let starts = starts_with(\"prelude\", \"pre\");
+            let ends = ends_with(\"prelude\", \"lude\");
+            let r = len(repeat(\"ab\", 3));
+        ");
+        let memory = vm.get_memory();
+        // Address 3 holds the array
+        let expected = [
+            (0, 6),
+            (1, 12),
+            (2, 10),
+            (4, 16),
+            (5, 9),
+            (6, 2),
+            (7, 0),
+            (8, 4),
+            (9, 1),
+            (10, 1),
+            (11, 6),
+        ];
+        for (addr, value) in expected {
+            assert_eq!(memory.get(&addr), Some(&value), "address {}", addr);
+        }
+    }
+
+    #[test]
+    fn program_definitions_replace_prelude_functions() {
+        let vm = run("let g = gcd(4, 6); fn gcd(a, b) { return a + b; }");
+        assert_eq!(vm.get_memory().get(&0), Some(&10));
+    }
+
+    #[test]
+    fn can_be_disabled() {
+        let statements = Parser::new("let g = gcd(4, 6);").parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        assert!(compiler.compile(statements).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::compiler::{
+    codegen::Compiler,
+    diagnostics::Warning,
+    error::CompileError,
+    object::Relocation,
+    parser::Parser,
+    pass::{OptLevel, PassReport},
+};
+
+/// Compiles successive versions of a program, reusing the code of every
+/// top-level function whose source and surroundings are unchanged since the
+/// previous `compile`, for REPLs and watch modes.
+///
+/// A function's code is reused when its text, the signatures of all
+/// functions, and the structs, constants and global variables it can see
+/// are the same as when it was compiled, wherever it now sits in the source.
+/// Prelude functions are reused the same way. The rest of the pipeline
+/// (parsing, type checking and the top-level code) runs on every compile.
+///
+/// Programs compiled in a session have no constant pool, since the pool is
+/// laid out for the whole program.
+pub struct Session {
+    prelude: bool,
+    opt_level: OptLevel,
+    cache: FunctionCache,
+    /// The last source compiled and its result, returned as is when the
+    /// same source is compiled again
+    last: Option<(String, Vec<u8>)>,
+    stats: CacheStats,
+    warnings: Vec<Warning>,
+    pass_reports: Vec<PassReport>,
+}
+
+/// How many function bodies the last `Session::compile` reused from earlier
+/// compiles and how many it compiled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub reused: usize,
+    pub compiled: usize,
+}
+
+/// Compiled function bodies, keyed by `Compiler::function_key`.
+#[derive(Default)]
+pub(crate) struct FunctionCache {
+    /// Source of the program being compiled, which function keys quote
+    pub(crate) source: String,
+    pub(crate) entries: HashMap<FunctionKey, CachedFunction>,
+    /// Keys looked up while compiling the current program
+    pub(crate) used: HashSet<FunctionKey>,
+    pub(crate) stats: CacheStats,
+}
+
+/// A top-level function's text and everything its body is compiled
+/// against, compared in full so that two functions whose keys only hash
+/// the same never share code.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct FunctionKey {
+    pub(crate) text: String,
+    /// Signatures of all functions, sorted
+    pub(crate) functions: Vec<String>,
+    /// Variables in scope with their depths, sorted
+    pub(crate) variables: Vec<String>,
+    pub(crate) structs: Vec<(String, Vec<String>)>,
+    /// Constants, sorted by name
+    pub(crate) constants: Vec<(String, i64)>,
+}
+
+/// The code of one function body, with every position relative to its
+/// start, as it was emitted before calls and strings were patched.
+#[derive(Debug, Clone)]
+pub(crate) struct CachedFunction {
+    pub(crate) code: Vec<u8>,
+    /// Offset of the entry point
+    pub(crate) entry: usize,
+    /// Operands depending on the code's placement; `Code` operands hold
+    /// addresses relative to the start
+    pub(crate) relocations: Vec<Relocation>,
+    /// Operands to patch with the entry point of the named function
+    pub(crate) function_fixups: Vec<(usize, String)>,
+    /// Operands to patch with the data offset of the string
+    pub(crate) string_fixups: Vec<(usize, String)>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            prelude: true,
+            opt_level: OptLevel::Default,
+            cache: FunctionCache::default(),
+            last: None,
+            stats: CacheStats::default(),
+            warnings: Vec::new(),
+            pass_reports: Vec::new(),
+        }
+    }
+
+    /// Enables or disables linking the standard prelude (on by default).
+    /// Changing it drops the cached code.
+    pub fn set_prelude(&mut self, enabled: bool) {
+        if enabled != self.prelude {
+            self.clear();
+        }
+        self.prelude = enabled;
+    }
+
+    /// Selects the optimization passes (`OptLevel::Default` unless set).
+    /// Changing the level drops the cached code.
+    pub fn set_opt_level(&mut self, level: OptLevel) {
+        if level != self.opt_level {
+            self.clear();
+        }
+        self.opt_level = level;
+    }
+
+    /// Forgets every cached function.
+    pub fn clear(&mut self) {
+        self.cache.entries.clear();
+        self.last = None;
+    }
+
+    /// Parses and compiles `source`, reusing what it can from earlier calls.
+    /// Functions that are no longer part of the program are dropped from
+    /// the cache.
+    pub fn compile(&mut self, source: &str) -> Result<Vec<u8>, CompileError> {
+        if let Some((last, bytecode)) = &self.last {
+            if last == source {
+                self.stats = CacheStats {
+                    reused: self.cache.entries.len(),
+                    compiled: 0,
+                };
+                return Ok(bytecode.clone());
+            }
+        }
+
+        let statements = Parser::new(source).parse_program()?;
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(self.prelude);
+        compiler.set_opt_level(self.opt_level);
+        self.cache.source = source.to_string();
+        self.cache.used.clear();
+        self.cache.stats = CacheStats::default();
+        compiler.set_function_cache(std::mem::take(&mut self.cache));
+        let result = compiler.compile(statements);
+        self.cache = compiler.take_function_cache().unwrap_or_default();
+        let used = std::mem::take(&mut self.cache.used);
+        self.cache.entries.retain(|key, _| used.contains(key));
+        self.stats = self.cache.stats;
+        let bytecode = result?;
+        self.warnings = compiler.warnings().to_vec();
+        self.pass_reports = compiler.pass_reports().to_vec();
+        self.last = Some((source.to_string(), bytecode.clone()));
+        Ok(bytecode)
+    }
+
+    /// How much the last `compile` reused.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Warnings about the last program compiled.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// How each pass changed the last program compiled.
+    pub fn pass_reports(&self) -> &[PassReport] {
+        &self.pass_reports
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheStats, Session};
+    use crate::test_util::run_bytecode as run;
+
+    #[test]
+    fn recompiles_only_changed_functions() {
+        let mut session = Session::new();
+        session.set_prelude(false);
+        let v1 = "
+            fn double(n) { return n * 2; }
+            fn greet() { return \"hi\"; }
+            let a = double(21);
+            let s = greet();
+        ";
+        let vm = run(session.compile(v1).unwrap());
+        assert_eq!(vm.get_memory()[&0], 42);
+        assert_eq!(
+            session.stats(),
+            CacheStats {
+                reused: 0,
+                compiled: 2
+            }
+        );
+
+        // Moving `greet` and editing `double` only recompiles `double`
+        let v2 = "
+            // doubling, now tripling
+            fn double(n) { return n * 3; }
+
+            fn greet() { return \"hi\"; }
+            let a = double(21) + len(greet());
+            let s = greet() + \"!\";
+        ";
+        let vm = run(session.compile(v2).unwrap());
+        assert_eq!(vm.get_memory()[&0], 65);
+        assert_eq!(
+            session.stats(),
+            CacheStats {
+                reused: 1,
+                compiled: 1
+            }
+        );
+
+        session.compile(v2).unwrap();
+        assert_eq!(session.stats().compiled, 0);
+    }
+
+    #[test]
+    fn recompiles_functions_whose_surroundings_change() {
+        let mut session = Session::new();
+        session.set_prelude(false);
+        let v1 = "let base = 1; fn add(n) { return n + base; } let r = add(1);";
+        assert_eq!(run(session.compile(v1).unwrap()).get_memory()[&1], 2);
+        // `base` moves to another address, so `add` must not be reused
+        let v2 = "let pad = 0; let base = 10; fn add(n) { return n + base; } let r = add(1);";
+        let vm = run(session.compile(v2).unwrap());
+        assert_eq!(vm.get_memory()[&2], 11);
+        assert_eq!(
+            session.stats(),
+            CacheStats {
+                reused: 0,
+                compiled: 1
+            }
+        );
+    }
+
+    #[test]
+    fn reuses_the_prelude() {
+        let mut session = Session::new();
+        session.compile("print gcd(12, 18);").unwrap();
+        let compiled = session.stats().compiled;
+        assert!(compiled > 0);
+        let vm = run(session.compile("let g = gcd(12, 18) + 1;").unwrap());
+        assert_eq!(vm.get_memory()[&0], 7);
+        assert_eq!(
+            session.stats(),
+            CacheStats {
+                reused: compiled,
+                compiled: 0
+            }
+        );
+        assert!(session.compile("let x = ;").is_err());
+    }
+}
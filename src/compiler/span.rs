@@ -0,0 +1,57 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// A range of source text. Lines and columns count from 1, columns in
+/// characters; `offset` and `len` are in bytes, for slicing the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub offset: usize,
+    pub len: usize,
+    /// The source file, as an index into the file table of a program
+    /// loaded from modules; the entry file, and any lone source, is 0
+    #[serde(default, skip_serializing_if = "is_entry_file")]
+    pub file: usize,
+}
+
+fn is_entry_file(file: &usize) -> bool {
+    *file == 0
+}
+
+impl Span {
+    /// An empty span at the start of the entry file, for errors about a
+    /// whole program.
+    pub fn start() -> Span {
+        Span {
+            line: 1,
+            col: 1,
+            ..Span::default()
+        }
+    }
+
+    /// The span from the start of `self` to the end of `end`.
+    pub fn to(self, end: Span) -> Span {
+        Span {
+            len: (end.offset + end.len).saturating_sub(self.offset),
+            ..self
+        }
+    }
+
+    /// The byte offset just past the end of the span.
+    pub fn end(&self) -> usize {
+        self.offset + self.len
+    }
+
+    /// The source text covered by the span.
+    pub fn text<'a>(&self, source: &'a str) -> &'a str {
+        &source[self.offset..self.offset + self.len]
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
@@ -0,0 +1,238 @@
+use crate::compiler::{
+    parser::{BinaryOpKind, Expr, ExprKind, Statement, StatementKind},
+    visit::{self, MutVisitor, Visitor},
+};
+
+/// Replaces integer arithmetic by a power of two or an identity element
+/// with cheaper operations:
+///
+/// - `x * 2^k` and `2^k * x` become `x << k`,
+/// - `x / 2^k` becomes `x >> k` when `x` cannot be negative, since a shift
+///   rounds toward negative infinity where division truncates toward zero,
+/// - `x + 0`, `0 + x`, `x - 0`, `x * 1`, `1 * x` and `x / 1` become `x`.
+///
+/// A literal operand makes the other one an integer, since type checking
+/// has already run. Run this after constant folding, so that constants and
+/// expressions like `2 * 4` are literals.
+pub fn reduce_strength(statements: &mut Vec<Statement>) {
+    let mut declarations = Declares {
+        name: "len",
+        found: false,
+    };
+    declarations.visit_block(statements);
+    StrengthReducer {
+        builtin_len: !declarations.found,
+    }
+    .visit_block(statements);
+}
+
+struct StrengthReducer {
+    /// Whether `len(...)` always calls the built-in, which no program
+    /// variable or function shadows
+    builtin_len: bool,
+}
+
+impl MutVisitor for StrengthReducer {
+    fn visit_expr(&mut self, expr: &mut Expr) {
+        visit::walk_expr_mut(self, expr);
+        let kind = std::mem::replace(&mut expr.kind, ExprKind::Number(0));
+        expr.kind = match kind {
+            ExprKind::BinaryOp(left, op, right) => self.reduce(*left, op, *right),
+            kind => kind,
+        };
+    }
+}
+
+impl StrengthReducer {
+    fn reduce(&self, left: Expr, op: BinaryOpKind, right: Expr) -> ExprKind {
+        use BinaryOpKind::*;
+
+        match (&op, literal(&left), literal(&right)) {
+            (Add | Sub, _, Some(0)) | (Mul | Div, _, Some(1)) => left.kind,
+            (Add, Some(0), _) | (Mul, Some(1), _) => right.kind,
+            (Mul, _, Some(n)) if is_power_of_two(n) => shift(left, ShiftLeft, right),
+            (Mul, Some(n), _) if is_power_of_two(n) => shift(right, ShiftLeft, left),
+            (Div, _, Some(n)) if is_power_of_two(n) && self.is_non_negative(&left) => {
+                shift(left, ShiftRight, right)
+            }
+            _ => ExprKind::BinaryOp(Box::new(left), op, Box::new(right)),
+        }
+    }
+
+    /// Whether `expr` can only evaluate to a non-negative integer.
+    fn is_non_negative(&self, expr: &Expr) -> bool {
+        match &expr.kind {
+            ExprKind::Number(n) => *n >= 0,
+            ExprKind::Call(name, _) => name == "len" && self.builtin_len,
+            ExprKind::BinaryOp(
+                left,
+                BinaryOpKind::Div | BinaryOpKind::Mod | BinaryOpKind::ShiftRight,
+                right,
+            ) => self.is_non_negative(left) && self.is_non_negative(right),
+            _ => false,
+        }
+    }
+}
+
+fn literal(expr: &Expr) -> Option<i64> {
+    match expr.kind {
+        ExprKind::Number(n) => Some(n),
+        _ => None,
+    }
+}
+
+fn is_power_of_two(n: i64) -> bool {
+    n > 1 && n.count_ones() == 1
+}
+
+/// `value <op> log2(power)`, with the shift amount in place of the power.
+fn shift(value: Expr, op: BinaryOpKind, power: Expr) -> ExprKind {
+    let amount = match power.kind {
+        ExprKind::Number(n) => n.trailing_zeros() as i64,
+        _ => unreachable!("shift by a non-literal power"),
+    };
+    let amount = Expr::new(ExprKind::Number(amount), power.span);
+    ExprKind::BinaryOp(Box::new(value), op, Box::new(amount))
+}
+
+/// Looks for a declaration of `name` anywhere in a program.
+struct Declares<'a> {
+    name: &'a str,
+    found: bool,
+}
+
+impl Visitor for Declares<'_> {
+    fn visit_statement(&mut self, statement: &Statement) {
+        let declared = match &statement.kind {
+            StatementKind::Let(name, ..)
+            | StatementKind::Const(name, _)
+            | StatementKind::Extern(name, ..)
+            | StatementKind::Try(_, name, _) => name == self.name,
+            StatementKind::LetTuple(names, _) => names.iter().any(|name| name == self.name),
+            StatementKind::Function(name, params, ..) => {
+                name == self.name || params.iter().any(|(param, _)| param == self.name)
+            }
+            _ => false,
+        };
+        self.found |= declared;
+        visit::walk_statement(self, statement);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let ExprKind::Closure(_, params, ..) = &expr.kind {
+            self.found |= params.iter().any(|(param, _)| param == self.name);
+        }
+        visit::walk_expr(self, expr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reduce_strength;
+    use crate::compiler::{
+        parser::{BinaryOpKind, Expr, ExprKind, Parser, StatementKind},
+        Compiler, OptLevel,
+    };
+    use crate::{Opcode, VM};
+
+    /// The initializers of the program's `let` statements after the pass.
+    fn reduced(code: &str) -> Vec<Expr> {
+        let mut statements = Parser::new(code).parse_program().unwrap();
+        reduce_strength(&mut statements);
+        statements
+            .into_iter()
+            .filter_map(|statement| match statement.kind {
+                StatementKind::Let(_, _, value) => Some(value),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn is_shift(expr: &Expr, op: BinaryOpKind, amount: i64) -> bool {
+        matches!(
+            &expr.kind,
+            ExprKind::BinaryOp(_, found, right)
+                if std::mem::discriminant(found) == std::mem::discriminant(&op)
+                    && matches!(right.kind, ExprKind::Number(n) if n == amount)
+        )
+    }
+
+    #[test]
+    fn rewrites_powers_of_two_and_identities() {
+        let values = reduced(
+            "let x = 5;
+             let a = x * 8;
+             let b = 1024 * x;
+             let c = x + 0 - 0;
+             let d = 1 * (x / 1);
+             let e = x * 6;
+             let f = x * -4;",
+        );
+        assert!(is_shift(&values[1], BinaryOpKind::ShiftLeft, 3));
+        assert!(is_shift(&values[2], BinaryOpKind::ShiftLeft, 10));
+        for value in &values[3..5] {
+            assert!(matches!(&value.kind, ExprKind::Variable(name) if name == "x"));
+        }
+        for value in &values[5..] {
+            assert!(matches!(
+                value.kind,
+                ExprKind::BinaryOp(_, BinaryOpKind::Mul, _)
+            ));
+        }
+    }
+
+    #[test]
+    fn shifts_only_non_negative_dividends() {
+        let values = reduced(
+            "let x = -7;
+             let a = x / 2;
+             let b = len(\"abcdefg\") / 4;
+             let c = len(\"abc\") % 8 / 2;",
+        );
+        assert!(matches!(
+            values[1].kind,
+            ExprKind::BinaryOp(_, BinaryOpKind::Div, _)
+        ));
+        assert!(is_shift(&values[2], BinaryOpKind::ShiftRight, 2));
+        assert!(is_shift(&values[3], BinaryOpKind::ShiftRight, 1));
+
+        // A user-defined `len` may return anything
+        let values = reduced("fn len(s) { return -1; } let a = len(\"ab\") / 2;");
+        assert!(matches!(
+            values[0].kind,
+            ExprKind::BinaryOp(_, BinaryOpKind::Div, _)
+        ));
+    }
+
+    #[test]
+    fn reduced_programs_compute_the_same_values() {
+        let code = "
+            let x = -7;
+            let a = x * 4 + 0;
+            let b = x / 2;
+            let c = len(\"abcdefg\") / 4;
+            let d = (x * 1) * 16 / 1;
+        ";
+        let run = |level| {
+            let statements = Parser::new(code).parse_program().unwrap();
+            let mut compiler = Compiler::new();
+            compiler.set_prelude(false);
+            compiler.set_opt_level(level);
+            let bytecode = compiler.compile(statements).unwrap();
+            let shifts = crate::bytecode::decode(&bytecode)
+                .unwrap()
+                .iter()
+                .filter(|instruction| matches!(instruction.opcode, Opcode::Shl | Opcode::Shr))
+                .count();
+            let mut vm = VM::new(bytecode, 100);
+            vm.run().unwrap();
+            let values: Vec<i64> = (0..5).map(|addr| vm.get_memory()[&addr]).collect();
+            (values, shifts)
+        };
+        let (unoptimized, _) = run(OptLevel::None);
+        let (optimized, shifts) = run(OptLevel::Default);
+        assert_eq!(unoptimized, [-7, -28, -3, 1, -112]);
+        assert_eq!(optimized, unoptimized);
+        assert_eq!(shifts, 3);
+    }
+}
@@ -0,0 +1,767 @@
+use std::collections::HashMap;
+
+use crate::compiler::{
+    error::TypeError,
+    parser::{BinaryOpKind, Expr, ExprKind, Param, Statement, StatementKind, Type, UnaryOpKind},
+    span::Span,
+};
+
+/// Static type checker run between parsing and code generation.
+///
+/// Bindings without an annotation take the type inferred from their
+/// initializer, and functions without a return annotation take the type of
+/// their first `return`. Only values whose type cannot be known statically, such as
+/// unannotated parameters, are `Unknown` and accepted anywhere.
+pub struct TypeChecker {
+    /// Innermost scope last, mirroring the compiler's block scoping
+    scopes: Vec<HashMap<String, Type>>,
+    structs: HashMap<String, Vec<String>>,
+    constants: HashMap<String, Type>,
+    /// Signatures of top-level functions, registered before any body is checked
+    functions: HashMap<String, Type>,
+    /// Inferred signatures of closures, keyed by their parser-assigned id
+    closure_types: HashMap<usize, Type>,
+    /// Return type of the function being checked, `None` at the top level
+    return_type: Option<Type>,
+    /// Type of each expression checked, by offset and length, when
+    /// recording
+    expr_types: Option<HashMap<(usize, usize), Type>>,
+    /// The innermost expression, or statement, the error being reported
+    /// was found in
+    error_span: Option<Span>,
+}
+
+/// Returns whether a value of type `actual` can be used where `expected` is required.
+fn compatible(expected: &Type, actual: &Type) -> bool {
+    match (expected, actual) {
+        (Type::Unknown, _) | (_, Type::Unknown) => true,
+        (Type::Function(expected_params, expected_result), Type::Function(params, result)) => {
+            expected_params.len() == params.len()
+                && expected_params
+                    .iter()
+                    .zip(params)
+                    .all(|(expected, actual)| compatible(expected, actual))
+                && compatible(expected_result, result)
+        }
+        _ => expected == actual,
+    }
+}
+
+fn is_truthy(ty: &Type) -> bool {
+    matches!(ty, Type::Int | Type::Bool | Type::Unknown)
+}
+
+impl TypeChecker {
+    pub fn new() -> Self {
+        TypeChecker {
+            scopes: vec![HashMap::new()],
+            structs: HashMap::new(),
+            constants: HashMap::new(),
+            functions: HashMap::new(),
+            closure_types: HashMap::new(),
+            return_type: None,
+            expr_types: None,
+            error_span: None,
+        }
+    }
+
+    /// Checks a program, or more statements of one, returning the first
+    /// error found, located at the innermost expression it is in, or at
+    /// the statement for errors outside any expression.
+    pub fn check(&mut self, statements: &[Statement]) -> Result<(), TypeError> {
+        self.error_span = None;
+        self.check_statements(statements)
+            .map_err(|message| TypeError {
+                message,
+                span: self.error_span.take().unwrap_or_default(),
+            })
+    }
+
+    fn check_statements(&mut self, statements: &[Statement]) -> Result<(), String> {
+        if self.scopes.len() == 1 {
+            self.declare_functions(statements)?;
+        }
+        statements.iter().try_for_each(|statement| {
+            let result = self.check_statement(statement);
+            self.locate(statement.span, result)
+        })
+    }
+
+    /// Locates an error at `span`, unless it was found in something inside.
+    fn locate<T>(&mut self, span: Span, result: Result<T, String>) -> Result<T, String> {
+        result.inspect_err(|_| {
+            self.error_span.get_or_insert(span);
+        })
+    }
+
+    /// Makes `check` remember the type of every expression it checks, for
+    /// `expr_type`. Tools such as editors use it; the compiler does not.
+    pub fn record_types(&mut self) {
+        self.expr_types.get_or_insert_with(HashMap::new);
+    }
+
+    /// The type inferred for the expression at `span`, if types are
+    /// recorded and checking reached it.
+    pub fn expr_type(&self, span: Span) -> Option<&Type> {
+        self.expr_types.as_ref()?.get(&(span.offset, span.len))
+    }
+
+    /// Returns the signature of a top-level function, with its inferred
+    /// return type once the body has been checked.
+    pub fn function_type(&self, name: &str) -> Option<&Type> {
+        self.functions.get(name)
+    }
+
+    /// Registers a function defined outside the program, such as one
+    /// imported from another object, so the program may call it.
+    pub fn declare_function(&mut self, name: &str, signature: Type) {
+        self.functions.insert(name.to_string(), signature);
+    }
+
+    /// Returns the inferred signatures of every closure checked so far.
+    pub fn closure_types(&self) -> &HashMap<usize, Type> {
+        &self.closure_types
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Type> {
+        self.constants
+            .get(name)
+            .or_else(|| self.scopes.iter().rev().find_map(|scope| scope.get(name)))
+            .or_else(|| self.functions.get(name))
+    }
+
+    /// Registers every top-level function up front so calls may precede
+    /// the declaration, as in mutual recursion.
+    fn declare_functions(&mut self, statements: &[Statement]) -> Result<(), String> {
+        for statement in statements {
+            let (name, params, result) = match &statement.kind {
+                StatementKind::Function(name, params, return_type, _) => {
+                    (name, params, return_type.clone().unwrap_or(Type::Unknown))
+                }
+                // Host functions return an integer unless annotated
+                StatementKind::Extern(name, params, return_type) => {
+                    (name, params, return_type.clone().unwrap_or(Type::Int))
+                }
+                _ => continue,
+            };
+            if self.functions.contains_key(name) {
+                self.error_span = Some(statement.span);
+                return Err(format!("Function '{}' is already defined", name));
+            }
+            let params = params
+                .iter()
+                .map(|(_, annotation)| annotation.clone().unwrap_or(Type::Unknown))
+                .collect();
+            self.functions
+                .insert(name.clone(), Type::Function(params, Box::new(result)));
+        }
+        Ok(())
+    }
+
+    fn check_type_exists(&self, ty: &Type) -> Result<(), String> {
+        match ty {
+            Type::Struct(name) if !self.structs.contains_key(name) => {
+                Err(format!("Unknown type '{}'", name))
+            }
+            Type::Function(params, result) => {
+                params
+                    .iter()
+                    .try_for_each(|param| self.check_type_exists(param))?;
+                self.check_type_exists(result)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn declare(&mut self, name: &str, ty: Type) {
+        self.scopes.last_mut().unwrap().insert(name.to_string(), ty);
+    }
+
+    fn check_block(&mut self, statements: &[Statement]) -> Result<(), String> {
+        self.scopes.push(HashMap::new());
+        let result = self.check_statements(statements);
+        self.scopes.pop();
+        result
+    }
+
+    /// Checks that `expr` has a type compatible with `expected`, returning
+    /// its type.
+    fn expect_expr(&mut self, expected: &Type, expr: &Expr, context: &str) -> Result<Type, String> {
+        let ty = self.type_of(expr)?;
+        let result = self.expect(expected, &ty, context);
+        self.locate(expr.span, result)?;
+        Ok(ty)
+    }
+
+    fn expect(&self, expected: &Type, actual: &Type, context: &str) -> Result<(), String> {
+        if compatible(expected, actual) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Type mismatch in {}: expected {}, found {}",
+                context, expected, actual
+            ))
+        }
+    }
+
+    /// Checks that `expr` can be used as a condition, returning its type.
+    fn expect_condition(&mut self, expr: &Expr, context: &str) -> Result<Type, String> {
+        let ty = self.type_of(expr)?;
+        let result = if is_truthy(&ty) {
+            Ok(ty)
+        } else {
+            Err(format!(
+                "Type mismatch in {}: expected int or bool, found {}",
+                context, ty
+            ))
+        };
+        self.locate(expr.span, result)
+    }
+
+    fn check_statement(&mut self, statement: &Statement) -> Result<(), String> {
+        match &statement.kind {
+            StatementKind::Let(name, annotation, expr) => {
+                let ty = match annotation {
+                    Some(annotation) => {
+                        self.check_type_exists(annotation)?;
+                        self.expect_expr(annotation, expr, &format!("'let {}'", name))?;
+                        annotation.clone()
+                    }
+                    None => self.type_of(expr)?,
+                };
+                self.declare(name, ty);
+            }
+            StatementKind::Const(name, expr) => {
+                let ty = self.type_of(expr)?;
+                self.constants.insert(name.clone(), ty);
+            }
+            StatementKind::Assign(name, expr) => match self.lookup(name).cloned() {
+                Some(declared) => {
+                    self.expect_expr(&declared, expr, &format!("assignment to '{}'", name))?;
+                }
+                None => {
+                    let ty = self.type_of(expr)?;
+                    self.declare(name, ty);
+                }
+            },
+            StatementKind::LetTuple(names, values) => {
+                let types = values
+                    .iter()
+                    .map(|value| self.type_of(value))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for (name, ty) in names.iter().zip(types) {
+                    self.declare(name, ty);
+                }
+            }
+            StatementKind::AssignTuple(names, values) => {
+                let types = values
+                    .iter()
+                    .map(|value| self.type_of(value))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for ((name, ty), value) in names.iter().zip(types).zip(values) {
+                    match self.lookup(name).cloned() {
+                        Some(declared) => {
+                            let context = format!("assignment to '{}'", name);
+                            let result = self.expect(&declared, &ty, &context);
+                            self.locate(value.span, result)?;
+                        }
+                        None => self.declare(name, ty),
+                    }
+                }
+            }
+            StatementKind::IndexAssign(name, index, value) => {
+                let target = self.lookup(name).cloned().unwrap_or(Type::Unknown);
+                let (index_context, value_context) = if target == Type::Map {
+                    ("map key", "map value")
+                } else {
+                    self.expect(
+                        &Type::Array,
+                        &target,
+                        &format!("assignment to '{}[...]'", name),
+                    )?;
+                    ("array index", "array element")
+                };
+                self.expect_expr(&Type::Int, index, index_context)?;
+                self.expect_expr(&Type::Int, value, value_context)?;
+            }
+            StatementKind::Increment(name) | StatementKind::Decrement(name) => {
+                let ty = self.lookup(name).cloned().unwrap_or(Type::Unknown);
+                self.expect(&Type::Int, &ty, &format!("increment of '{}'", name))?;
+            }
+            StatementKind::If(condition, then_block, else_block) => {
+                self.expect_condition(condition, "if condition")?;
+                self.check_block(then_block)?;
+                self.check_block(else_block)?;
+            }
+            StatementKind::While(condition, block) => {
+                self.expect_condition(condition, "while condition")?;
+                self.check_block(block)?;
+            }
+            StatementKind::Print(exprs) if exprs.len() == 1 => {
+                self.type_of(&exprs[0])?;
+            }
+            StatementKind::Print(args) | StatementKind::PrintFormatted(_, args) => {
+                for arg in args {
+                    let ty = self.type_of(arg)?;
+                    if !matches!(
+                        ty,
+                        Type::Int | Type::Float | Type::Bool | Type::Str | Type::Unknown
+                    ) {
+                        let error = Err(format!("Cannot format a value of type {}", ty));
+                        return self.locate(arg.span, error);
+                    }
+                }
+            }
+            StatementKind::Call(name, args) => {
+                self.check_call(name, args)?;
+            }
+            StatementKind::Assert(expr) => {
+                self.expect_condition(expr, "assertion")?;
+            }
+            StatementKind::Exit(code) => {
+                self.expect_expr(&Type::Int, code, "exit code")?;
+            }
+            StatementKind::Throw(value) => {
+                self.expect_expr(&Type::Int, value, "thrown value")?;
+            }
+            StatementKind::Try(body, name, handler) => {
+                self.check_block(body)?;
+                self.scopes.push(HashMap::from([(name.clone(), Type::Int)]));
+                let result = self.check_statements(handler);
+                self.scopes.pop();
+                result?;
+            }
+            StatementKind::Struct(name, fields) => {
+                self.structs.insert(name.clone(), fields.clone());
+            }
+            StatementKind::Match(scrutinee, arms) => {
+                self.expect_expr(&Type::Int, scrutinee, "match scrutinee")?;
+                for (_, body) in arms {
+                    self.check_block(body)?;
+                }
+            }
+            StatementKind::Function(name, params, return_type, body) => {
+                if self.scopes.len() > 1 {
+                    return Err(format!(
+                        "Function '{}' must be declared at the top level",
+                        name
+                    ));
+                }
+                let ty = self.check_function(params, return_type, body)?;
+                self.functions.insert(name.clone(), ty);
+            }
+            StatementKind::Extern(name, params, return_type) => {
+                if self.scopes.len() > 1 {
+                    return Err(format!(
+                        "Extern function '{}' must be declared at the top level",
+                        name
+                    ));
+                }
+                for annotation in params.iter().filter_map(|(_, a)| a.as_ref()) {
+                    self.check_type_exists(annotation)?;
+                }
+                if let Some(return_type) = return_type {
+                    self.check_type_exists(return_type)?;
+                }
+            }
+            StatementKind::Return(value) => {
+                let ty = match value {
+                    Some(value) => self.type_of(value)?,
+                    // A bare `return` yields 0, like falling off the end
+                    None => Type::Int,
+                };
+                let Some(expected) = self.return_type.clone() else {
+                    return Err("'return' outside of a function".to_string());
+                };
+                let result = self.expect(&expected, &ty, "return value");
+                match value {
+                    Some(value) => self.locate(value.span, result)?,
+                    None => result?,
+                }
+                if expected == Type::Unknown {
+                    self.return_type = Some(ty);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a function body in its own scope and returns the function's
+    /// type, with the result inferred from the body when not annotated.
+    fn check_function(
+        &mut self,
+        params: &[Param],
+        return_type: &Option<Type>,
+        body: &[Statement],
+    ) -> Result<Type, String> {
+        for annotation in params.iter().filter_map(|(_, a)| a.as_ref()) {
+            self.check_type_exists(annotation)?;
+        }
+        if let Some(return_type) = return_type {
+            self.check_type_exists(return_type)?;
+        }
+        self.scopes.push(HashMap::new());
+        let mut param_types = Vec::with_capacity(params.len());
+        for (param, annotation) in params {
+            let ty = annotation.clone().unwrap_or(Type::Unknown);
+            self.declare(param, ty.clone());
+            param_types.push(ty);
+        }
+        let enclosing = self
+            .return_type
+            .replace(return_type.clone().unwrap_or(Type::Unknown));
+        let result = self.check_statements(body);
+        let inferred = std::mem::replace(&mut self.return_type, enclosing).unwrap();
+        self.scopes.pop();
+        result?;
+        Ok(Type::Function(param_types, Box::new(inferred)))
+    }
+
+    fn builtin_signature(name: &str) -> Result<(Vec<Type>, Type), String> {
+        Ok(match name {
+            "read" => (vec![], Type::Int),
+            "abs" | "sqrt_int" | "printChar" | "recv" => (vec![Type::Int], Type::Int),
+            "min" | "max" | "pow" | "send" => (vec![Type::Int, Type::Int], Type::Int),
+            "charAt" => (vec![Type::Str, Type::Int], Type::Int),
+            "has" => (vec![Type::Map, Type::Int], Type::Bool),
+            "float" => (vec![Type::Int], Type::Float),
+            "int" => (vec![Type::Float], Type::Int),
+            // `len` accepts both strings and arrays
+            "len" => (vec![Type::Unknown], Type::Int),
+            _ => return Err(format!("Unknown function '{}'", name)),
+        })
+    }
+
+    /// Checks a call to a user function, a function value or a built-in,
+    /// in that order of precedence.
+    fn check_call(&mut self, name: &str, args: &[Expr]) -> Result<Type, String> {
+        let callee = self.lookup(name).cloned();
+        let is_builtin = callee.is_none();
+        let (params, result) = match callee {
+            Some(Type::Function(params, result)) => (params, *result),
+            Some(Type::Unknown) => {
+                for arg in args {
+                    self.type_of(arg)?;
+                }
+                return Ok(Type::Unknown);
+            }
+            Some(ty) => return Err(format!("Cannot call '{}' of type {}", name, ty)),
+            None => Self::builtin_signature(name)?,
+        };
+        if args.len() != params.len() {
+            return Err(format!(
+                "Function '{}' expects {} argument(s), got {}",
+                name,
+                params.len(),
+                args.len()
+            ));
+        }
+        for (i, (param, arg)) in params.iter().zip(args).enumerate() {
+            let ty = self.expect_expr(param, arg, &format!("argument {} of '{}'", i + 1, name))?;
+            if is_builtin
+                && name == "len"
+                && !matches!(ty, Type::Str | Type::Array | Type::Map | Type::Unknown)
+            {
+                let error = Err(format!(
+                    "Type mismatch in argument 1 of 'len': expected string, array or map, found {}",
+                    ty
+                ));
+                return self.locate(arg.span, error);
+            }
+        }
+        Ok(result)
+    }
+
+    fn type_of(&mut self, expr: &Expr) -> Result<Type, String> {
+        let result = self.infer(expr);
+        let ty = self.locate(expr.span, result)?;
+        if let Some(types) = &mut self.expr_types {
+            types.insert((expr.span.offset, expr.span.len), ty.clone());
+        }
+        Ok(ty)
+    }
+
+    fn infer(&mut self, expr: &Expr) -> Result<Type, String> {
+        Ok(match &expr.kind {
+            ExprKind::Number(_) => Type::Int,
+            ExprKind::Float(_) => Type::Float,
+            ExprKind::Bool(_) => Type::Bool,
+            ExprKind::Str(_) => Type::Str,
+            ExprKind::Variable(name) => self.lookup(name).cloned().unwrap_or(Type::Unknown),
+            ExprKind::UnaryOp(UnaryOpKind::Not, operand) => {
+                self.expect_condition(operand, "operand of '!'")?;
+                Type::Bool
+            }
+            ExprKind::UnaryOp(UnaryOpKind::Neg, operand) => {
+                let ty = self.type_of(operand)?;
+                if !matches!(ty, Type::Int | Type::Float | Type::Unknown) {
+                    return Err(format!("Cannot negate a value of type {}", ty));
+                }
+                ty
+            }
+            ExprKind::BinaryOp(left, op, right) => {
+                let left = self.type_of(left)?;
+                let right = self.type_of(right)?;
+                self.binary_result(op, left, right)?
+            }
+            ExprKind::Call(name, args) => self.check_call(name, args)?,
+            ExprKind::Array(elements) => {
+                for element in elements {
+                    self.expect_expr(&Type::Int, element, "array element")?;
+                }
+                Type::Array
+            }
+            ExprKind::ArrayRepeat(value, count) => {
+                self.expect_expr(&Type::Int, value, "array element")?;
+                self.expect_expr(&Type::Int, count, "array size")?;
+                Type::Array
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    self.expect_expr(&Type::Int, key, "map key")?;
+                    self.expect_expr(&Type::Int, value, "map value")?;
+                }
+                Type::Map
+            }
+            ExprKind::Index(target, index) => {
+                let target = self.type_of(target)?;
+                if !matches!(target, Type::Array | Type::Map | Type::Str | Type::Unknown) {
+                    return Err(format!("Cannot index a value of type {}", target));
+                }
+                self.expect_expr(&Type::Int, index, "index")?;
+                Type::Int
+            }
+            ExprKind::StructLiteral(name, fields) => {
+                for (field, value) in fields {
+                    self.expect_expr(
+                        &Type::Int,
+                        value,
+                        &format!("field '{}' of '{}'", field, name),
+                    )?;
+                }
+                Type::Struct(name.clone())
+            }
+            ExprKind::Field(target, field) => match self.type_of(target)? {
+                Type::Struct(name) => {
+                    let fields = &self.structs[&name];
+                    if !fields.contains(field) {
+                        return Err(format!("Struct '{}' has no field '{}'", name, field));
+                    }
+                    Type::Int
+                }
+                Type::Unknown => Type::Unknown,
+                ty => {
+                    return Err(format!(
+                        "Cannot access field '{}' on a value of type {}",
+                        field, ty
+                    ))
+                }
+            },
+            ExprKind::Closure(id, params, return_type, body) => {
+                let ty = self.check_function(params, return_type, body)?;
+                self.closure_types.insert(*id, ty.clone());
+                ty
+            }
+            ExprKind::Conditional(condition, then_expr, else_expr) => {
+                self.expect_condition(condition, "conditional expression")?;
+                let then_ty = self.type_of(then_expr)?;
+                let else_ty = self.type_of(else_expr)?;
+                self.expect(&then_ty, &else_ty, "conditional expression branches")?;
+                if then_ty == Type::Unknown {
+                    else_ty
+                } else {
+                    then_ty
+                }
+            }
+        })
+    }
+
+    fn binary_result(&self, op: &BinaryOpKind, left: Type, right: Type) -> Result<Type, String> {
+        let mismatch = || {
+            Err(format!(
+                "Cannot apply {:?} to values of type {} and {}",
+                op, left, right
+            ))
+        };
+        if matches!(op, BinaryOpKind::And | BinaryOpKind::Or) {
+            return if is_truthy(&left) && is_truthy(&right) {
+                Ok(Type::Bool)
+            } else {
+                mismatch()
+            };
+        }
+        // An unknown operand takes on the type of the other side
+        let operand = match (&left, &right) {
+            (Type::Unknown, other) | (other, Type::Unknown) => other.clone(),
+            (a, b) if a == b => a.clone(),
+            _ => return mismatch(),
+        };
+        match op {
+            BinaryOpKind::Add if operand == Type::Str => Ok(Type::Str),
+            BinaryOpKind::Add
+            | BinaryOpKind::Sub
+            | BinaryOpKind::Mul
+            | BinaryOpKind::Div
+            | BinaryOpKind::Mod => match operand {
+                Type::Int | Type::Float | Type::Unknown => Ok(operand),
+                _ => mismatch(),
+            },
+            BinaryOpKind::ShiftLeft | BinaryOpKind::ShiftRight => match operand {
+                Type::Int | Type::Unknown => Ok(Type::Int),
+                _ => mismatch(),
+            },
+            BinaryOpKind::Equals | BinaryOpKind::NotEquals => match operand {
+                Type::Int | Type::Float | Type::Bool | Type::Unknown => Ok(Type::Bool),
+                _ => mismatch(),
+            },
+            BinaryOpKind::LessThan
+            | BinaryOpKind::GreaterThan
+            | BinaryOpKind::LessEqual
+            | BinaryOpKind::GreaterEqual => match operand {
+                Type::Int | Type::Float | Type::Unknown => Ok(Type::Bool),
+                _ => mismatch(),
+            },
+            BinaryOpKind::And | BinaryOpKind::Or => unreachable!(),
+        }
+    }
+}
+
+impl Default for TypeChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TypeChecker;
+    use crate::compiler::parser::{Parser, StatementKind, Type};
+
+    fn check(code: &str) -> Result<(), String> {
+        let statements = Parser::new(code)
+            .parse_program()
+            .map_err(|errors| errors[0].to_string())?;
+        TypeChecker::new()
+            .check(&statements)
+            .map_err(|error| error.message)
+    }
+
+    #[test]
+    fn accepts_well_typed_annotations() {
+        let code = "
+            struct Point { x, y }
+            let n: int = 1 + 2;
+            let f: float = 1.5 * 2.0;
+            let b: bool = n > 2 && true;
+            let s: string = \"a\" + \"b\";
+            let a: array = [1, 2];
+            let p: Point = Point { x: 1, y: n };
+            let untyped = 5;
+            n = len(s) + a[0] + p.x;
+        ";
+        assert_eq!(check(code), Ok(()));
+    }
+
+    #[test]
+    fn infers_types_of_unannotated_bindings() {
+        for code in [
+            "let s = \"text\"; let n: int = s;",
+            "let n = 1; n = \"text\";",
+            "let f = 1.5; let g = f + 1;",
+            "let a = [1]; let b = a * 2;",
+            "let n = 1; if n > 0 { let n = \"inner\"; n = 2; }",
+            "x = 1.5; x = 1;",
+        ] {
+            assert!(check(code).is_err(), "{}", code);
+        }
+        assert_eq!(
+            check("let n = 1; if n > 0 { let n = \"inner\"; } n = 2;"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_annotations() {
+        assert_eq!(
+            check("let n: int = \"text\";"),
+            Err("Type mismatch in 'let n': expected int, found string".to_string())
+        );
+        assert!(check("let b: bool = 1;").is_err());
+        assert!(check("let p: Missing = 1;").is_err());
+    }
+
+    #[test]
+    fn rejects_ill_typed_expressions() {
+        for code in [
+            "let n: int = 1; let s: string = \"a\"; let x = n + s;",
+            "let b: bool = true; let x = b * 2;",
+            "let n: int = 1; n = 2.5;",
+            "let x = charAt(1, 2);",
+            "let x = max(1);",
+            "let s: string = \"a\"; if s { print 1; }",
+        ] {
+            assert!(check(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn checks_function_signatures() {
+        let code = "
+            fn apply(f: fn(int) -> int, x: int) -> int { return f(x); }
+            fn double(x) { return x * 2; }
+            fn label() { return \"n\"; }
+            let n: int = apply(double, 3);
+            let s: string = label();
+        ";
+        assert_eq!(check(code), Ok(()));
+        for code in [
+            "fn f(a) { return a; } let x = f(1, 2);",
+            "fn f() -> int { return \"s\"; }",
+            "fn f(x) { if x { return 1; } return \"s\"; }",
+            "fn label() { return \"n\"; } let n: int = label();",
+            "fn g(f: fn(int) -> int) { return f(1); } fn s(x: string) { return x; } let y = g(s);",
+            "let s = \"a\"; let x = s(1);",
+            "fn f() { return 1; } fn f() { return 2; }",
+            "if true { fn f() { return 1; } }",
+            "return 1;",
+            "let f = fn(x: int) { return \"s\"; }; let n: int = f(1);",
+        ] {
+            assert!(check(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn records_expression_types_and_error_spans() {
+        let code = "let n = 1 + 2; if n > 0 { let s = \"a\" + n; }";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let mut checker = TypeChecker::new();
+        checker.record_types();
+        let error = checker.check(&statements).unwrap_err();
+        let StatementKind::Let(_, _, sum) = &statements[0].kind else {
+            unreachable!()
+        };
+        assert_eq!(checker.expr_type(sum.span), Some(&Type::Int));
+        // The operation inside the block, not its `let` or the whole `if`
+        assert_eq!(error.span.text(code), "\"a\" + n");
+        assert_eq!(
+            error.to_string(),
+            "1:35: Cannot apply Add to values of type string and int"
+        );
+
+        let code = "let x = 1; let y = x + \"a\";";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let error = TypeChecker::new().check(&statements).unwrap_err();
+        assert_eq!(error.span.text(code), "x + \"a\"");
+
+        // A value of the wrong type, not the statement it is used in
+        let code = "let n: int = 1;\nwhile true { n = \"s\"; }";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let error = TypeChecker::new().check(&statements).unwrap_err();
+        assert_eq!((error.span.line, error.span.text(code)), (2, "\"s\""));
+
+        let code = "fn f() { return 1; }\nfn f() { return 2; }";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let error = TypeChecker::new().check(&statements).unwrap_err();
+        assert_eq!(error.span.line, 2);
+    }
+}
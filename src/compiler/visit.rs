@@ -0,0 +1,304 @@
+use crate::compiler::parser::{Expr, ExprKind, Statement, StatementKind};
+
+/// Read-only traversal of the AST. Every method defaults to visiting the
+/// node's children through the matching `walk_*` function, so a visitor
+/// only overrides the nodes it cares about and calls `walk_*` itself to
+/// keep descending.
+pub trait Visitor {
+    /// Visits a block: a program, a branch, a loop or function body, or a
+    /// `catch` handler.
+    fn visit_block(&mut self, statements: &[Statement]) {
+        walk_block(self, statements);
+    }
+
+    fn visit_statement(&mut self, statement: &Statement) {
+        walk_statement(self, statement);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+}
+
+pub fn walk_block<V: Visitor + ?Sized>(visitor: &mut V, statements: &[Statement]) {
+    for statement in statements {
+        visitor.visit_statement(statement);
+    }
+}
+
+/// Visits the expressions and blocks of a statement, in source order.
+pub fn walk_statement<V: Visitor + ?Sized>(visitor: &mut V, statement: &Statement) {
+    match &statement.kind {
+        StatementKind::Let(_, _, expr)
+        | StatementKind::Const(_, expr)
+        | StatementKind::Assign(_, expr)
+        | StatementKind::Assert(expr)
+        | StatementKind::Exit(expr)
+        | StatementKind::Throw(expr)
+        | StatementKind::Return(Some(expr)) => visitor.visit_expr(expr),
+        StatementKind::LetTuple(_, values)
+        | StatementKind::AssignTuple(_, values)
+        | StatementKind::Print(values)
+        | StatementKind::PrintFormatted(_, values)
+        | StatementKind::Call(_, values) => {
+            values.iter().for_each(|value| visitor.visit_expr(value));
+        }
+        StatementKind::IndexAssign(_, index, value) => {
+            visitor.visit_expr(index);
+            visitor.visit_expr(value);
+        }
+        StatementKind::If(condition, then_block, else_block) => {
+            visitor.visit_expr(condition);
+            visitor.visit_block(then_block);
+            visitor.visit_block(else_block);
+        }
+        StatementKind::While(condition, body) => {
+            visitor.visit_expr(condition);
+            visitor.visit_block(body);
+        }
+        StatementKind::Try(body, _, handler) => {
+            visitor.visit_block(body);
+            visitor.visit_block(handler);
+        }
+        StatementKind::Match(scrutinee, arms) => {
+            visitor.visit_expr(scrutinee);
+            for (_, body) in arms {
+                visitor.visit_block(body);
+            }
+        }
+        StatementKind::Function(_, _, _, body) => visitor.visit_block(body),
+        StatementKind::Increment(_)
+        | StatementKind::Decrement(_)
+        | StatementKind::Struct(..)
+        | StatementKind::Extern(..)
+        | StatementKind::Return(None) => {}
+    }
+}
+
+/// Visits the subexpressions of an expression, and the body of a closure.
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match &expr.kind {
+        ExprKind::UnaryOp(_, operand) | ExprKind::Field(operand, _) => visitor.visit_expr(operand),
+        ExprKind::BinaryOp(left, _, right)
+        | ExprKind::Index(left, right)
+        | ExprKind::ArrayRepeat(left, right) => {
+            visitor.visit_expr(left);
+            visitor.visit_expr(right);
+        }
+        ExprKind::Conditional(condition, then_expr, else_expr) => {
+            visitor.visit_expr(condition);
+            visitor.visit_expr(then_expr);
+            visitor.visit_expr(else_expr);
+        }
+        ExprKind::Call(_, args) | ExprKind::Array(args) => {
+            args.iter().for_each(|arg| visitor.visit_expr(arg));
+        }
+        ExprKind::Map(entries) => {
+            for (key, value) in entries {
+                visitor.visit_expr(key);
+                visitor.visit_expr(value);
+            }
+        }
+        ExprKind::StructLiteral(_, fields) => {
+            fields
+                .iter()
+                .for_each(|(_, value)| visitor.visit_expr(value));
+        }
+        ExprKind::Closure(_, _, _, body) => visitor.visit_block(body),
+        ExprKind::Number(_)
+        | ExprKind::Float(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Str(_)
+        | ExprKind::Variable(_) => {}
+    }
+}
+
+/// Traversal that may rewrite the AST in place, the mutable counterpart of
+/// `Visitor`. Blocks are passed as the `Vec` itself so statements can be
+/// added or removed.
+pub trait MutVisitor {
+    fn visit_block(&mut self, statements: &mut Vec<Statement>) {
+        walk_block_mut(self, statements);
+    }
+
+    fn visit_statement(&mut self, statement: &mut Statement) {
+        walk_statement_mut(self, statement);
+    }
+
+    fn visit_expr(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr);
+    }
+}
+
+pub fn walk_block_mut<V: MutVisitor + ?Sized>(visitor: &mut V, statements: &mut [Statement]) {
+    for statement in statements {
+        visitor.visit_statement(statement);
+    }
+}
+
+pub fn walk_statement_mut<V: MutVisitor + ?Sized>(visitor: &mut V, statement: &mut Statement) {
+    match &mut statement.kind {
+        StatementKind::Let(_, _, expr)
+        | StatementKind::Const(_, expr)
+        | StatementKind::Assign(_, expr)
+        | StatementKind::Assert(expr)
+        | StatementKind::Exit(expr)
+        | StatementKind::Throw(expr)
+        | StatementKind::Return(Some(expr)) => visitor.visit_expr(expr),
+        StatementKind::LetTuple(_, values)
+        | StatementKind::AssignTuple(_, values)
+        | StatementKind::Print(values)
+        | StatementKind::PrintFormatted(_, values)
+        | StatementKind::Call(_, values) => {
+            values
+                .iter_mut()
+                .for_each(|value| visitor.visit_expr(value));
+        }
+        StatementKind::IndexAssign(_, index, value) => {
+            visitor.visit_expr(index);
+            visitor.visit_expr(value);
+        }
+        StatementKind::If(condition, then_block, else_block) => {
+            visitor.visit_expr(condition);
+            visitor.visit_block(then_block);
+            visitor.visit_block(else_block);
+        }
+        StatementKind::While(condition, body) => {
+            visitor.visit_expr(condition);
+            visitor.visit_block(body);
+        }
+        StatementKind::Try(body, _, handler) => {
+            visitor.visit_block(body);
+            visitor.visit_block(handler);
+        }
+        StatementKind::Match(scrutinee, arms) => {
+            visitor.visit_expr(scrutinee);
+            for (_, body) in arms {
+                visitor.visit_block(body);
+            }
+        }
+        StatementKind::Function(_, _, _, body) => visitor.visit_block(body),
+        StatementKind::Increment(_)
+        | StatementKind::Decrement(_)
+        | StatementKind::Struct(..)
+        | StatementKind::Extern(..)
+        | StatementKind::Return(None) => {}
+    }
+}
+
+pub fn walk_expr_mut<V: MutVisitor + ?Sized>(visitor: &mut V, expr: &mut Expr) {
+    match &mut expr.kind {
+        ExprKind::UnaryOp(_, operand) | ExprKind::Field(operand, _) => visitor.visit_expr(operand),
+        ExprKind::BinaryOp(left, _, right)
+        | ExprKind::Index(left, right)
+        | ExprKind::ArrayRepeat(left, right) => {
+            visitor.visit_expr(left);
+            visitor.visit_expr(right);
+        }
+        ExprKind::Conditional(condition, then_expr, else_expr) => {
+            visitor.visit_expr(condition);
+            visitor.visit_expr(then_expr);
+            visitor.visit_expr(else_expr);
+        }
+        ExprKind::Call(_, args) | ExprKind::Array(args) => {
+            args.iter_mut().for_each(|arg| visitor.visit_expr(arg));
+        }
+        ExprKind::Map(entries) => {
+            for (key, value) in entries {
+                visitor.visit_expr(key);
+                visitor.visit_expr(value);
+            }
+        }
+        ExprKind::StructLiteral(_, fields) => {
+            fields
+                .iter_mut()
+                .for_each(|(_, value)| visitor.visit_expr(value));
+        }
+        ExprKind::Closure(_, _, _, body) => visitor.visit_block(body),
+        ExprKind::Number(_)
+        | ExprKind::Float(_)
+        | ExprKind::Bool(_)
+        | ExprKind::Str(_)
+        | ExprKind::Variable(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{walk_block_mut, walk_expr, walk_expr_mut, MutVisitor, Visitor};
+    use crate::compiler::parser::{Expr, ExprKind, Parser, Statement, StatementKind};
+
+    /// Collects every variable read, in the order visited.
+    #[derive(Default)]
+    struct Reads(Vec<String>);
+
+    impl Visitor for Reads {
+        fn visit_expr(&mut self, expr: &Expr) {
+            if let ExprKind::Variable(name) = &expr.kind {
+                self.0.push(name.clone());
+            }
+            walk_expr(self, expr);
+        }
+    }
+
+    #[test]
+    fn visits_every_nested_expression() {
+        let statements = Parser::new(
+            "let a = b + c[d];
+if e { print f; } else { while g { h(i); } }
+fn k() { return fn() { return m ? n : o; }; }
+match p { 1 => { exit(q); } _ => { } }",
+        )
+        .parse_program()
+        .unwrap();
+        let mut reads = Reads::default();
+        reads.visit_block(&statements);
+        assert_eq!(
+            reads.0,
+            ["b", "c", "d", "e", "f", "g", "i", "m", "n", "o", "p", "q"]
+        );
+    }
+
+    /// Doubles every integer literal and drops `print` statements.
+    struct Rewrite;
+
+    impl MutVisitor for Rewrite {
+        fn visit_block(&mut self, statements: &mut Vec<Statement>) {
+            statements.retain(|statement| !matches!(statement.kind, StatementKind::Print(_)));
+            walk_block_mut(self, statements);
+        }
+
+        fn visit_expr(&mut self, expr: &mut Expr) {
+            if let ExprKind::Number(n) = &mut expr.kind {
+                *n *= 2;
+            }
+            walk_expr_mut(self, expr);
+        }
+    }
+
+    #[test]
+    fn rewrites_in_place() {
+        let mut statements = Parser::new("let a = 1 + 2; print a; while a < 3 { print a; a = 4; }")
+            .parse_program()
+            .unwrap();
+        Rewrite.visit_block(&mut statements);
+        assert_eq!(statements.len(), 2);
+        let StatementKind::Let(
+            _,
+            _,
+            Expr {
+                kind: ExprKind::BinaryOp(left, _, right),
+                ..
+            },
+        ) = &statements[0].kind
+        else {
+            panic!("expected a let");
+        };
+        assert!(matches!(left.kind, ExprKind::Number(2)));
+        assert!(matches!(right.kind, ExprKind::Number(4)));
+        let StatementKind::While(_, body) = &statements[1].kind else {
+            panic!("expected a loop");
+        };
+        assert_eq!(body.len(), 1);
+    }
+}
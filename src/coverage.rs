@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::{
+    compiler::DebugInfo,
+    disasm::{self, DisasmKind},
+};
+
+/// Which instructions of a program ran, and how often, collected by a `VM`
+/// with coverage enabled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coverage {
+    /// Addresses of the program's instructions
+    instructions: Vec<usize>,
+    /// Executions, indexed by address
+    hits: Vec<u64>,
+}
+
+impl Coverage {
+    /// Starts tracking `program`, none of which has run yet.
+    pub fn new(program: &[u8]) -> Self {
+        let instructions = disasm::disassemble(program)
+            .into_iter()
+            .filter(|line| matches!(line.kind, DisasmKind::Instruction { .. }))
+            .map(|line| line.offset)
+            .collect();
+        Coverage {
+            instructions,
+            hits: vec![0; program.len()],
+        }
+    }
+
+    pub(crate) fn record(&mut self, pc: usize) {
+        if let Some(hits) = self.hits.get_mut(pc) {
+            *hits += 1;
+        }
+    }
+
+    /// How many times the instruction at `pc` ran.
+    pub fn hits(&self, pc: usize) -> u64 {
+        self.hits.get(pc).copied().unwrap_or(0)
+    }
+
+    /// The number of the program's instructions that ran, and of all of
+    /// them.
+    pub fn instructions_covered(&self) -> (usize, usize) {
+        let covered = self
+            .instructions
+            .iter()
+            .filter(|&&pc| self.hits[pc] > 0)
+            .count();
+        (covered, self.instructions.len())
+    }
+
+    /// Every source line with code, in order of file id and line, with
+    /// how many times it ran: the executions of its most run instruction.
+    /// Lines are `(file, line, hits)`. Code without a span in `debug_info`,
+    /// such as the prelude, is left out.
+    pub fn lines(&self, debug_info: &DebugInfo) -> Vec<(usize, usize, u64)> {
+        let mut lines = BTreeMap::new();
+        for &pc in &self.instructions {
+            if let Some(span) = debug_info.span_at(pc) {
+                let hits = lines.entry((span.file, span.line)).or_insert(0);
+                *hits = self.hits[pc].max(*hits);
+            }
+        }
+        lines
+            .into_iter()
+            .map(|((file, line), hits)| (file, line, hits))
+            .collect()
+    }
+
+    /// The coverage as an lcov tracefile, for `genhtml` and coverage
+    /// services: a record per source file with a `DA` line per line with
+    /// code and an `FN` / `FNDA` pair per function, with their totals.
+    /// Files are named from `debug_info`, or `path` for one it has no name
+    /// for, as in a program built from a single file.
+    pub fn lcov(&self, debug_info: &DebugInfo, path: &str) -> String {
+        let mut files: BTreeMap<usize, (Vec<_>, Vec<_>)> = BTreeMap::new();
+        for (entry, name) in debug_info.functions() {
+            // Functions linked in from the prelude have no line
+            if let Some(span) = debug_info.span_at(*entry) {
+                let functions = &mut files.entry(span.file).or_default().0;
+                functions.push((span.line, name, self.hits(*entry)));
+            }
+        }
+        for (file, line, hits) in self.lines(debug_info) {
+            files.entry(file).or_default().1.push((line, hits));
+        }
+
+        let mut report = String::new();
+        for (file, (functions, lines)) in files {
+            let name = debug_info.file(file).unwrap_or(path);
+            writeln!(report, "TN:\nSF:{}", name).unwrap();
+            for (line, name, _) in &functions {
+                writeln!(report, "FN:{},{}", line, name).unwrap();
+            }
+            for (_, name, hits) in &functions {
+                writeln!(report, "FNDA:{},{}", hits, name).unwrap();
+            }
+            let functions_hit = functions.iter().filter(|(.., hits)| *hits > 0).count();
+            writeln!(report, "FNF:{}\nFNH:{}", functions.len(), functions_hit).unwrap();
+
+            for (line, hits) in &lines {
+                writeln!(report, "DA:{},{}", line, hits).unwrap();
+            }
+            let lines_hit = lines.iter().filter(|(_, hits)| *hits > 0).count();
+            writeln!(report, "LF:{}\nLH:{}", lines.len(), lines_hit).unwrap();
+            report.push_str("end_of_record\n");
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::{
+        compiler::{Compiler, ModuleLoader, OptLevel, Parser},
+        VM,
+    };
+
+    #[test]
+    fn tracks_instructions_and_lines_run() {
+        let source = "fn twice(n) {\n\
+                      \x20   return n * 2;\n\
+                      }\n\
+                      fn unused() {\n\
+                      \x20   return 0;\n\
+                      }\n\
+                      let x = 3;\n\
+                      if x > 5 {\n\
+                      \x20   x = twice(x);\n\
+                      } else {\n\
+                      \x20   x = twice(twice(x));\n\
+                      }\n";
+        let statements = Parser::new(source).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.set_opt_level(OptLevel::None);
+        compiler.set_debug_info(true);
+        let mut vm = VM::new(compiler.compile(statements).unwrap(), 16);
+        let debug_info = compiler.debug_info().unwrap();
+        assert!(vm.coverage().is_none());
+        vm.set_coverage(true);
+        vm.run().unwrap();
+        let coverage = vm.coverage().unwrap();
+
+        assert_eq!(coverage.hits(0), 1);
+        let (covered, total) = coverage.instructions_covered();
+        assert!(covered > 0 && covered < total);
+        assert_eq!(
+            coverage.lines(debug_info),
+            [
+                (0, 1, 2),
+                (0, 2, 2),
+                (0, 4, 1),
+                (0, 5, 0),
+                (0, 7, 1),
+                (0, 8, 1),
+                (0, 9, 0),
+                (0, 11, 1)
+            ]
+        );
+
+        let lcov = coverage.lcov(debug_info, "main.svm");
+        assert!(lcov.starts_with("TN:\nSF:main.svm\nFN:1,twice\nFN:4,unused\n"));
+        assert!(lcov.contains("FNDA:2,twice\nFNDA:0,unused\nFNF:2\nFNH:1\n"));
+        assert!(lcov.contains("DA:9,0\n"));
+        assert!(lcov.ends_with("LF:8\nLH:6\nend_of_record\n"));
+    }
+
+    #[test]
+    fn writes_a_record_per_source_file() {
+        let mut loader = ModuleLoader::with_reader(|path| match path.to_str() {
+            Some("main.svm") => Ok("import \"lib.svm\";\nlet x = 1;\nprint twice(x);".into()),
+            Some("lib.svm") => Ok("fn twice(n) {\n    return n * 2;\n}".into()),
+            _ => Err(io::ErrorKind::NotFound.into()),
+        });
+        let statements = loader.load("main.svm").unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.set_debug_info(true);
+        let mut vm = VM::new(compiler.compile(statements).unwrap(), 16);
+        let mut debug_info = compiler.debug_info().unwrap().clone();
+        debug_info.set_files(vec!["main.svm".to_string(), "lib.svm".to_string()]);
+        vm.set_coverage(true);
+        vm.run().unwrap();
+        let coverage = vm.coverage().unwrap();
+
+        assert_eq!(
+            coverage.lines(&debug_info),
+            [(0, 2, 1), (0, 3, 1), (1, 1, 1), (1, 2, 1)]
+        );
+        let lcov = coverage.lcov(&debug_info, "unused.svm");
+        let records: Vec<_> = lcov.split_terminator("end_of_record\n").collect();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].starts_with("TN:\nSF:main.svm\nFNF:0\nFNH:0\nDA:2,1\nDA:3,1\n"));
+        assert!(records[1].starts_with("TN:\nSF:lib.svm\nFN:1,twice\nFNDA:1,twice\n"));
+        assert!(records[1].ends_with("DA:1,1\nDA:2,1\nLF:2\nLH:2\n"));
+    }
+}
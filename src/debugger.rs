@@ -0,0 +1,434 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::io;
+
+use thiserror::Error;
+
+use crate::{
+    compiler::DebugInfo,
+    disasm::{self, DisasmKind, DisasmLine},
+    inspect, StopReason, VMError, VM,
+};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DebugError {
+    #[error("Unknown command '{0}', try 'help'")]
+    UnknownCommand(String),
+    #[error("Invalid argument '{0}'")]
+    InvalidArgument(String),
+    #[error("The program is not running, start it with 'run'")]
+    NotRunning,
+    #[error("No debug info to find line {0} with, break at an address with '*pc'")]
+    NoDebugInfo(usize),
+    #[error("No code on line {0}")]
+    NoCodeOnLine(usize),
+    #[error("No instruction at {0}")]
+    NoInstruction(usize),
+    #[error("No top-level variable '{0}' in the debug info")]
+    UnknownVariable(String),
+}
+
+const HELP: &str = "\
+break <line> | break *<pc>  stop before the code of a source line, or an address
+delete [<pc>]               remove a breakpoint, or all of them
+run                         start the program, from the beginning if it was running
+step                        execute one instruction, stepping into calls
+next                        execute one instruction, running calls to completion
+finish                      run until the current call returns
+continue                    run to the next breakpoint
+print stack                 show the stack, top last
+print <name>                show a top-level variable
+x/<n>[x] <addr>             show n memory cells from addr, in decimal or hex
+backtrace                   show the active calls, innermost first
+info breakpoints            list the breakpoints
+info globals                list the top-level variables
+quit                        leave the debugger";
+
+/// A command-line debugger in the style of gdb, for scripts and terminals
+/// without a full screen: each command is a line of text, and the result
+/// is text to print.
+///
+/// Breakpoints are kept across `run`s, which start the program afresh. The
+/// program reads no input, since the debugger's commands come from the
+/// terminal it would read from. Its output goes to stdout as usual.
+pub struct Debugger {
+    program: Vec<u8>,
+    debug_info: Option<DebugInfo>,
+    stack_limit: usize,
+    /// The disassembly, to show the instruction at an address
+    lines: Vec<DisasmLine>,
+    breakpoints: BTreeSet<usize>,
+    /// The program being debugged, from `run` until it ends
+    vm: Option<VM>,
+}
+
+impl Debugger {
+    pub fn new(program: Vec<u8>, debug_info: Option<DebugInfo>, stack_limit: usize) -> Self {
+        let lines = disasm::disassemble(&program);
+        Debugger {
+            program,
+            debug_info,
+            stack_limit,
+            lines,
+            breakpoints: BTreeSet::new(),
+            vm: None,
+        }
+    }
+
+    /// The VM running the program, between `run` and the program's end.
+    pub fn vm(&self) -> Option<&VM> {
+        self.vm.as_ref()
+    }
+
+    /// Runs one command, returning what to print. Commands may be shortened
+    /// to their first letter, as in `b 3`, `s` or `c`, `bt` stands for
+    /// `backtrace` and `fin` for `finish`.
+    pub fn execute(&mut self, command: &str) -> Result<String, DebugError> {
+        let mut words = command.split_whitespace();
+        let Some(name) = words.next() else {
+            return Ok(String::new());
+        };
+        let argument = words.next();
+        match (name, argument) {
+            ("help" | "h", _) => Ok(HELP.to_string()),
+            ("break" | "b", Some(target)) => self.add_breakpoint(target),
+            ("delete" | "d", target) => self.delete_breakpoint(target),
+            ("info" | "i", Some("breakpoints" | "b")) => Ok(self.list_breakpoints()),
+            ("run" | "r", None) => self.run(),
+            ("step" | "s", None) => self.advance(VM::step),
+            ("next" | "n", None) => self.advance(VM::step_over),
+            ("finish" | "fin", None) => self.advance(VM::step_out),
+            ("continue" | "c", None) => self.advance(VM::resume),
+            ("print" | "p", Some("stack")) => Ok(format!("{:?}", self.running_vm()?.dump_stack())),
+            ("print" | "p", Some(name)) => self.print_global(name),
+            ("info" | "i", Some("globals" | "g")) => Ok(self.list_globals()?),
+            ("backtrace" | "bt", None) => Ok(self.backtrace()?),
+            (name, Some(addr)) if name.starts_with("x/") => self.examine(&name[2..], addr),
+            _ => Err(DebugError::UnknownCommand(command.trim().to_string())),
+        }
+    }
+
+    fn running_vm(&self) -> Result<&VM, DebugError> {
+        self.vm.as_ref().ok_or(DebugError::NotRunning)
+    }
+
+    fn add_breakpoint(&mut self, target: &str) -> Result<String, DebugError> {
+        let pc = match target.strip_prefix('*') {
+            Some(pc) => {
+                let pc = parse_number(pc)?;
+                if !self.is_instruction(pc) {
+                    return Err(DebugError::NoInstruction(pc));
+                }
+                pc
+            }
+            None => {
+                let line = parse_number(target)?;
+                self.debug_info
+                    .as_ref()
+                    .ok_or(DebugError::NoDebugInfo(line))?
+                    .line_start(line)
+                    .ok_or(DebugError::NoCodeOnLine(line))?
+            }
+        };
+        self.breakpoints.insert(pc);
+        if let Some(vm) = &mut self.vm {
+            vm.add_breakpoint(pc);
+        }
+        Ok(format!("Breakpoint at {}", self.location(pc)))
+    }
+
+    fn delete_breakpoint(&mut self, target: Option<&str>) -> Result<String, DebugError> {
+        let removed: Vec<usize> = match target {
+            Some(pc) => {
+                let pc = parse_number(pc.trim_start_matches('*'))?;
+                self.breakpoints.take(&pc).into_iter().collect()
+            }
+            None => std::mem::take(&mut self.breakpoints).into_iter().collect(),
+        };
+        if let Some(vm) = &mut self.vm {
+            for &pc in &removed {
+                vm.remove_breakpoint(pc);
+            }
+        }
+        Ok(format!("Deleted {} breakpoint(s)", removed.len()))
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "No breakpoints".to_string();
+        }
+        self.breakpoints
+            .iter()
+            .map(|&pc| self.location(pc))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn run(&mut self) -> Result<String, DebugError> {
+        let mut vm = VM::new(self.program.clone(), self.stack_limit);
+        if let Some(debug_info) = &self.debug_info {
+            vm.set_debug_info(debug_info.clone());
+        }
+        vm.set_input(io::empty());
+        for &pc in &self.breakpoints {
+            vm.add_breakpoint(pc);
+        }
+        // Stop at a breakpoint on the very first instruction, which
+        // `resume` would run past
+        if self.breakpoints.contains(&vm.pc()) {
+            let stopped = format!("Breakpoint at {}", self.location(vm.pc()));
+            self.vm = Some(vm);
+            return Ok(stopped);
+        }
+        self.vm = Some(vm);
+        self.advance(VM::resume)
+    }
+
+    /// Runs the program with one of the VM's stepping methods and
+    /// describes where it stopped, forgetting it once it has ended.
+    fn advance(
+        &mut self,
+        run: fn(&mut VM) -> Result<StopReason, VMError>,
+    ) -> Result<String, DebugError> {
+        let vm = self.vm.as_mut().ok_or(DebugError::NotRunning)?;
+        let result = run(vm);
+        let pc = vm.pc();
+        match result {
+            Ok(StopReason::Stepped) => Ok(self.location(pc)),
+            Ok(StopReason::Breakpoint(pc)) => Ok(format!("Breakpoint at {}", self.location(pc))),
+            Ok(StopReason::Halted) => {
+                let exit_code = vm.get_exit_code();
+                self.vm = None;
+                Ok(format!("Program halted, exit code {}", exit_code))
+            }
+            Err(error) => {
+                let message = match vm.current_span() {
+                    Some(span) => format!("Program failed at {}: {}", span, error),
+                    None => format!("Program failed: {}", error),
+                };
+                self.vm = None;
+                Ok(message)
+            }
+        }
+    }
+
+    fn backtrace(&self) -> Result<String, DebugError> {
+        let vm = self.running_vm()?;
+        let frames = std::iter::once(vm.pc()).chain(vm.call_stack().rev());
+        let mut trace = String::new();
+        for (depth, pc) in frames.enumerate() {
+            if depth > 0 {
+                trace.push('\n');
+            }
+            write!(trace, "#{} {}", depth, self.location(pc)).unwrap();
+        }
+        Ok(trace)
+    }
+
+    fn print_global(&self, name: &str) -> Result<String, DebugError> {
+        // The last declaration of a name is the one in scope at the end
+        let (_, addr, value) = self
+            .running_vm()?
+            .dump_globals()
+            .into_iter()
+            .rev()
+            .find(|(global, ..)| *global == name)
+            .ok_or_else(|| DebugError::UnknownVariable(name.to_string()))?;
+        Ok(format!("{} = {} (at {})", name, value, addr))
+    }
+
+    fn list_globals(&self) -> Result<String, DebugError> {
+        let globals = self.running_vm()?.dump_globals();
+        if globals.is_empty() {
+            return Ok("No top-level variables".to_string());
+        }
+        Ok(globals
+            .iter()
+            .map(|(name, addr, value)| format!("{:06}  {} = {}", addr, name, value))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    /// Shows memory cells in decimal, or in hex with an `x` format, as in
+    /// `x/8x 100`.
+    fn examine(&self, format: &str, addr: &str) -> Result<String, DebugError> {
+        let (count, hex) = match format.strip_suffix('x') {
+            Some(count) => (count, true),
+            None => (format.strip_suffix('d').unwrap_or(format), false),
+        };
+        let count = parse_number(count)?;
+        let addr = parse_number(addr)?;
+        let cells = self.running_vm()?.dump_memory_range(addr, count);
+        if hex {
+            return Ok(inspect::hex_dump(addr, &cells).trim_end().to_string());
+        }
+        let mut dump = String::new();
+        for (row, chunk) in cells.chunks(8).enumerate() {
+            if row > 0 {
+                dump.push('\n');
+            }
+            write!(dump, "{:06}:", addr + row * 8).unwrap();
+            for cell in chunk {
+                write!(dump, " {}", cell).unwrap();
+            }
+        }
+        Ok(dump)
+    }
+
+    fn is_instruction(&self, pc: usize) -> bool {
+        self.lines
+            .iter()
+            .any(|line| line.offset == pc && matches!(line.kind, DisasmKind::Instruction { .. }))
+    }
+
+    /// The instruction at `pc`, with its source location if known.
+    fn location(&self, pc: usize) -> String {
+        let instruction = self
+            .lines
+            .iter()
+            .find(|line| line.offset == pc)
+            .map_or_else(|| format!("{:06}", pc), |line| line.to_string());
+        match self.debug_info.as_ref().and_then(|info| info.span_at(pc)) {
+            Some(span) => format!("{} ({})", instruction, span),
+            None => instruction,
+        }
+    }
+}
+
+/// Parses a decimal or `0x` hexadecimal number.
+fn parse_number(text: &str) -> Result<usize, DebugError> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| DebugError::InvalidArgument(text.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DebugError, Debugger};
+    use crate::compiler::{Compiler, OptLevel, Parser};
+
+    const SQUARES: &str = "fn square(n) {\n\
+                           \x20   return n * n;\n\
+                           }\n\
+                           let a = 3;\n\
+                           let b = square(a);\n\
+                           let c = square(b);\n";
+
+    fn debugger(source: &str) -> Debugger {
+        let statements = Parser::new(source).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.set_opt_level(OptLevel::None);
+        compiler.set_debug_info(true);
+        let program = compiler.compile(statements).unwrap();
+        Debugger::new(program, compiler.debug_info().cloned(), 64)
+    }
+
+    #[test]
+    fn stops_at_line_breakpoints_and_inspects_state() {
+        let mut debugger = debugger(SQUARES);
+        assert_eq!(debugger.execute("step"), Err(DebugError::NotRunning));
+        let set = debugger.execute("break 2").unwrap();
+        assert!(set.starts_with("Breakpoint at "), "{}", set);
+        assert!(set.ends_with("(2:12)"), "{}", set);
+
+        let stop = debugger.execute("run").unwrap();
+        assert_eq!(stop, set);
+        let trace = debugger.execute("bt").unwrap();
+        assert_eq!(trace.lines().count(), 2);
+        assert!(trace.starts_with("#0 "));
+        assert!(
+            trace.lines().nth(1).unwrap().ends_with("(5:1)"),
+            "{}",
+            trace
+        );
+        assert_eq!(debugger.execute("x/3 0").unwrap(), "000000: 3 0 0");
+
+        let step = debugger.execute("step").unwrap();
+        assert_eq!(step, "000049  loadlocal (2:12)");
+        assert_eq!(debugger.execute("c").unwrap(), stop);
+        assert_eq!(debugger.execute("x/2 0").unwrap(), "000000: 3 9");
+        assert_eq!(debugger.execute("p b").unwrap(), "b = 9 (at 1)");
+        assert_eq!(
+            debugger.execute("info globals").unwrap(),
+            "000000  a = 3\n000001  b = 9\n000002  c = 0"
+        );
+        assert_eq!(
+            debugger.execute("x/2x 0").unwrap(),
+            "000000: 0000000000000003 0000000000000009                                    |..|"
+        );
+        assert_eq!(
+            debugger.execute("p d"),
+            Err(DebugError::UnknownVariable("d".to_string()))
+        );
+
+        assert_eq!(
+            debugger.execute("delete").unwrap(),
+            "Deleted 1 breakpoint(s)"
+        );
+        assert_eq!(
+            debugger.execute("continue").unwrap(),
+            "Program halted, exit code 0"
+        );
+        assert!(debugger.vm().is_none());
+        assert_eq!(debugger.execute("p stack"), Err(DebugError::NotRunning));
+    }
+
+    #[test]
+    fn steps_over_and_out_of_calls() {
+        let mut debugger = debugger(SQUARES);
+        debugger.execute("break 2").unwrap();
+        debugger.execute("run").unwrap();
+        let finish = debugger.execute("finish").unwrap();
+        assert!(finish.ends_with("(5:1)"), "{}", finish);
+        assert_eq!(debugger.execute("bt").unwrap().lines().count(), 1);
+
+        // `next` runs the second call whole
+        debugger.execute("delete").unwrap();
+        let mut steps = 0;
+        while debugger.vm().is_some() {
+            debugger.execute("next").unwrap();
+            if let Some(vm) = debugger.vm() {
+                assert_eq!(vm.call_stack().count(), 0);
+            }
+            steps += 1;
+        }
+        assert!(steps < 20, "{}", steps);
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        let mut debugger = debugger("let a = [1];\nprint a[4];\n");
+        assert_eq!(
+            debugger.execute("break 9"),
+            Err(DebugError::NoCodeOnLine(9))
+        );
+        assert_eq!(
+            debugger.execute("break *3"),
+            Err(DebugError::NoInstruction(3))
+        );
+        assert_eq!(
+            debugger.execute("x/2 zero"),
+            Err(DebugError::InvalidArgument("zero".to_string()))
+        );
+        assert!(matches!(
+            debugger.execute("jump 4"),
+            Err(DebugError::UnknownCommand(_))
+        ));
+        assert_eq!(
+            debugger.execute("info breakpoints").unwrap(),
+            "No breakpoints"
+        );
+        let set = debugger.execute("break *0").unwrap();
+        assert!(set.ends_with("push 1 (1:10)"), "{}", set);
+        assert!(debugger
+            .execute("run")
+            .unwrap()
+            .starts_with("Breakpoint at 000000"));
+        assert_eq!(debugger.execute("print stack").unwrap(), "[]");
+        let failed = debugger.execute("continue").unwrap();
+        assert!(failed.starts_with("Program failed at 2:"), "{}", failed);
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+use crate::bytecode;
+use crate::disasm::{self, DisasmKind, DisasmLine};
+use crate::Opcode;
+
+/// Unchanged lines shown around each change.
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Same,
+    /// Only in the old program
+    Removed,
+    /// Only in the new program
+    Added,
+}
+
+/// A line of the old program, of the new one, or of both where they do the
+/// same thing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffLine {
+    pub change: Change,
+    pub old: Option<DisasmLine>,
+    pub new: Option<DisasmLine>,
+}
+
+/// Two programs' disassemblies, aligned line by line. Displays the lines
+/// that differ with a few lines around them, `-` for the old program's and
+/// `+` for the new one's, each with its offsets in both programs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BytecodeDiff {
+    pub lines: Vec<DiffLine>,
+}
+
+impl BytecodeDiff {
+    /// Lines only one of the programs has.
+    pub fn changes(&self) -> usize {
+        self.lines
+            .iter()
+            .filter(|line| line.change != Change::Same)
+            .count()
+    }
+
+    /// Whether the programs do the same thing, wherever their code lies.
+    pub fn is_same(&self) -> bool {
+        self.changes() == 0
+    }
+}
+
+impl fmt::Display for BytecodeDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let changed: Vec<_> = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| line.change != Change::Same)
+            .map(|(index, _)| index)
+            .collect();
+        let shown = |index: usize| {
+            changed
+                .iter()
+                .any(|changed| index + CONTEXT >= *changed && index <= changed + CONTEXT)
+        };
+        let offset = |line: &Option<DisasmLine>| match line {
+            Some(line) => format!("{:06}", line.offset),
+            None => " ".repeat(6),
+        };
+        let mut skipped = false;
+        for (index, line) in self.lines.iter().enumerate() {
+            if !shown(index) {
+                skipped = true;
+                continue;
+            }
+            if std::mem::take(&mut skipped) {
+                writeln!(f, "...")?;
+            }
+            let (sign, kind) = match (&line.change, &line.old, &line.new) {
+                (Change::Added, _, Some(new)) => ('+', &new.kind),
+                (Change::Removed, Some(old), _) => ('-', &old.kind),
+                (_, Some(old), _) | (_, None, Some(old)) => (' ', &old.kind),
+                (_, None, None) => continue,
+            };
+            writeln!(
+                f,
+                "{} {} {}  {}",
+                sign,
+                offset(&line.old),
+                offset(&line.new),
+                kind
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A line as it is compared: what it does, with the line a pushed address
+/// refers to in place of the address.
+struct Entry {
+    line: DisasmLine,
+    key: String,
+    /// Index of the line whose address is pushed
+    reference: Option<usize>,
+}
+
+fn entries(bytecode: &[u8]) -> Vec<Entry> {
+    let lines = disasm::disassemble(bytecode);
+    let pool = constant_pool(bytecode, &lines);
+    let indices: HashMap<usize, usize> = lines
+        .iter()
+        .enumerate()
+        .map(|(index, line)| (line.offset, index))
+        .collect();
+    let mut entries = Vec::with_capacity(lines.len());
+    for (index, line) in lines.iter().enumerate() {
+        let mut reference = None;
+        let key = match line.kind {
+            _ if pool
+                .as_ref()
+                .is_some_and(|pool| pool.range.contains(&line.offset)) =>
+            {
+                continue
+            }
+            DisasmKind::Instruction {
+                opcode: Opcode::Push,
+                operand: Some(value),
+            } if pushes_address(&lines, index) => {
+                if pool
+                    .as_ref()
+                    .is_some_and(|pool| pool.range.start as i64 == value)
+                {
+                    "push @pool".to_string()
+                } else {
+                    reference = usize::try_from(value)
+                        .ok()
+                        .and_then(|offset| indices.get(&offset).copied());
+                    match reference {
+                        Some(_) => "push @".to_string(),
+                        None => line.kind.to_string(),
+                    }
+                }
+            }
+            DisasmKind::Instruction {
+                opcode: Opcode::PushConst,
+                operand: Some(index),
+            } => match pool
+                .as_ref()
+                .and_then(|pool| pool.values.get(index as usize))
+            {
+                Some(value) => format!("push {}", value),
+                None => line.kind.to_string(),
+            },
+            _ => line.kind.to_string(),
+        };
+        entries.push(Entry {
+            line: line.clone(),
+            key,
+            reference,
+        });
+    }
+    entries
+}
+
+/// A program's constant pool: where it lies and the values in it.
+struct ConstantPool {
+    range: Range<usize>,
+    values: Vec<i64>,
+}
+
+/// Finds the constant pool from the address the first `ConstPool` is given.
+fn constant_pool(bytecode: &[u8], lines: &[DisasmLine]) -> Option<ConstantPool> {
+    let index = lines.iter().position(|line| {
+        matches!(
+            line.kind,
+            DisasmKind::Instruction {
+                opcode: Opcode::ConstPool,
+                ..
+            }
+        )
+    })?;
+    let DisasmKind::Instruction {
+        opcode: Opcode::Push,
+        operand: Some(start),
+    } = lines.get(index.checked_sub(1)?)?.kind
+    else {
+        return None;
+    };
+    let (range, values) = bytecode::constant_pool(bytecode, usize::try_from(start).ok()?)?;
+    Some(ConstantPool { range, values })
+}
+
+/// Whether the value the line at `index` pushes is an address: the target
+/// of a jump, call or handler, or the data `LoadStr`, `ConstPool` or
+/// `BindHost` reads.
+fn pushes_address(lines: &[DisasmLine], index: usize) -> bool {
+    let opcode = |ahead: usize| match lines.get(index + ahead).map(|line| &line.kind) {
+        Some(DisasmKind::Instruction { opcode, .. }) => Some(*opcode),
+        _ => None,
+    };
+    matches!(
+        opcode(1),
+        Some(
+            Opcode::Jump
+                | Opcode::JumpIf
+                | Opcode::Call
+                | Opcode::PushHandler
+                | Opcode::LoadStr
+                | Opcode::ConstPool
+        )
+    ) || opcode(2) == Some(Opcode::BindHost)
+}
+
+/// Disassembles two programs and aligns their lines, to see what an
+/// optimizer or compiler change did to a program.
+///
+/// Lines match when they do the same thing. An address pushed for a jump,
+/// call or handler, or for the data a string or the constant pool is read
+/// from, matches when the lines it points to match, so code that only
+/// moved is not a difference. A `pushconst` matches a `push` of its value,
+/// so the constant pool itself is left out. Addresses pushed as values,
+/// such as those of functions used as values, are compared as numbers.
+pub fn bytecode(old: &[u8], new: &[u8]) -> BytecodeDiff {
+    let old = entries(old);
+    let new = entries(new);
+    let old_keys: Vec<_> = old.iter().map(|entry| &entry.key).collect();
+    let new_keys: Vec<_> = new.iter().map(|entry| &entry.key).collect();
+    let pairs = align(&old_keys, &new_keys);
+    let mut matches = vec![None; old.len()];
+    for pair in &pairs {
+        if let (Some(i), Some(j)) = *pair {
+            matches[i] = Some(j);
+        }
+    }
+    let mut lines = Vec::with_capacity(pairs.len());
+    for pair in pairs {
+        let old = pair.0.map(|i| &old[i]);
+        let new = pair.1.map(|j| &new[j]);
+        match (old, new) {
+            (Some(old), Some(new))
+                if old.reference.and_then(|reference| matches[reference]) != new.reference =>
+            {
+                lines.push(DiffLine {
+                    change: Change::Removed,
+                    old: Some(old.line.clone()),
+                    new: None,
+                });
+                lines.push(DiffLine {
+                    change: Change::Added,
+                    old: None,
+                    new: Some(new.line.clone()),
+                });
+            }
+            (old, new) => lines.push(DiffLine {
+                change: match (old, new) {
+                    (Some(_), Some(_)) => Change::Same,
+                    (Some(_), None) => Change::Removed,
+                    _ => Change::Added,
+                },
+                old: old.map(|entry| entry.line.clone()),
+                new: new.map(|entry| entry.line.clone()),
+            }),
+        }
+    }
+    BytecodeDiff { lines }
+}
+
+/// Pairs up two sequences along a longest common subsequence: the indices
+/// of items both have, and of items only one has, with the old sequence's
+/// ahead of the new one's where they differ.
+pub(crate) fn align<T: PartialEq>(old: &[T], new: &[T]) -> Vec<(Option<usize>, Option<usize>)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_end, new_end) = (old.len() - suffix, new.len() - suffix);
+    let mut pairs: Vec<_> = (0..prefix).map(|i| (Some(i), Some(i))).collect();
+    // Length of the longest common subsequence of old[i..] and new[j..],
+    // within the part that differs
+    let (rows, columns) = (old_end - prefix, new_end - prefix);
+    let mut lcs = vec![vec![0usize; columns + 1]; rows + 1];
+    for i in (0..rows).rev() {
+        for j in (0..columns).rev() {
+            lcs[i][j] = if old[prefix + i] == new[prefix + j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < rows || j < columns {
+        if i < rows && j < columns && old[prefix + i] == new[prefix + j] {
+            pairs.push((Some(prefix + i), Some(prefix + j)));
+            i += 1;
+            j += 1;
+        } else if i < rows && (j == columns || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            pairs.push((Some(prefix + i), None));
+            i += 1;
+        } else {
+            pairs.push((None, Some(prefix + j)));
+            j += 1;
+        }
+    }
+    pairs.extend((0..suffix).map(|k| (Some(old_end + k), Some(new_end + k))));
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{align, bytecode, Change};
+    use crate::compiler::Compiler;
+
+    #[test]
+    fn aligns_sequences() {
+        let pairs = align(&[1, 2, 3, 4], &[1, 5, 3, 4, 6]);
+        assert_eq!(
+            pairs,
+            [
+                (Some(0), Some(0)),
+                (Some(1), None),
+                (None, Some(1)),
+                (Some(2), Some(2)),
+                (Some(3), Some(3)),
+                (None, Some(4)),
+            ]
+        );
+        assert!(align::<u8>(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn ignores_addresses_that_only_moved() {
+        let old = crate::bytecode![push 1, jumpif end, push 2, print, end: halt];
+        let new = crate::bytecode![push 1, jumpif end, push 2, print, push 3, pop, end: halt];
+        let diff = bytecode(&old, &new);
+        assert_eq!(diff.changes(), 2);
+        assert_eq!(
+            diff.to_string(),
+            "...\n  000018 000018  jumpif\n  000019 000019  push 2\n  000028 000028  print\n\
+             +        000029  push 3\n+        000038  pop\n  000029 000039  halt\n"
+        );
+
+        // The same jump, to a different instruction
+        let retargeted = crate::bytecode![push 1, jumpif end, push 2, end: print, halt];
+        let diff = bytecode(&old, &retargeted);
+        let changes: Vec<_> = diff.lines.iter().map(|line| line.change).collect();
+        assert_eq!(changes[1..3], [Change::Removed, Change::Added], "{}", diff);
+        assert!(bytecode(&old, &old).is_same());
+    }
+
+    #[test]
+    fn compares_strings_by_value() {
+        let compile = |source: &str| {
+            let statements = crate::compiler::Parser::new(source)
+                .parse_program()
+                .unwrap();
+            let mut compiler = Compiler::new();
+            compiler.set_prelude(false);
+            compiler.compile(statements).unwrap()
+        };
+        let old = compile("print \"a\"; print \"b\";");
+        let new = compile("print 1; print \"a\"; print \"b\";");
+        let diff = bytecode(&old, &new);
+        assert!(
+            diff.lines
+                .iter()
+                .filter(|line| line.change != Change::Same)
+                .all(|line| line.new.as_ref().is_some_and(|new| new.offset < 20)),
+            "{}",
+            diff
+        );
+
+        let changed = compile("print \"a\"; print \"c\";");
+        assert!(!bytecode(&old, &changed).is_same());
+    }
+}
@@ -0,0 +1,174 @@
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::golden::{self, Snapshot, MAX_INSTRUCTIONS, STACK_LIMIT};
+use crate::{StopReason, VM};
+
+/// A way of executing bytecode. Every engine must behave exactly like the
+/// others, which `compare` checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// `VM::run_for`, checking the stack limit on every push
+    Interpreter,
+    /// `VM::new_verified`, which proves the stack depth before running and
+    /// leaves out the checks, for programs `stack_depth::verify` accepts
+    Verified,
+    /// `VM::step` one instruction at a time, as the debuggers run programs
+    Stepped,
+}
+
+impl Engine {
+    pub const ALL: [Engine; 3] = [Engine::Interpreter, Engine::Verified, Engine::Stepped];
+
+    /// Runs a program to its end, or for at most `golden::MAX_INSTRUCTIONS`,
+    /// reading `input`, and records what it did.
+    pub fn run(self, bytecode: &[u8], input: &[u8]) -> Result<Snapshot, DifferentialError> {
+        let bytecode = bytecode.to_vec();
+        let snapshot = match self {
+            Engine::Interpreter => {
+                let vm = VM::new(bytecode, STACK_LIMIT);
+                Snapshot::of_run(vm, input, |vm| vm.run_for(MAX_INSTRUCTIONS))
+            }
+            Engine::Verified => {
+                let vm = VM::new_verified(bytecode, STACK_LIMIT).map_err(|error| {
+                    DifferentialError::Unsupported {
+                        engine: self,
+                        reason: error.to_string(),
+                    }
+                })?;
+                Snapshot::of_run(vm, input, |vm| vm.run_for(MAX_INSTRUCTIONS))
+            }
+            Engine::Stepped => {
+                let vm = VM::new(bytecode, STACK_LIMIT);
+                Snapshot::of_run(vm, input, |vm| {
+                    for _ in 0..MAX_INSTRUCTIONS {
+                        if vm.step()? == StopReason::Halted {
+                            return Ok(false);
+                        }
+                    }
+                    Ok(true)
+                })
+            }
+        };
+        Ok(snapshot)
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Engine::Interpreter => write!(f, "interpreter"),
+            Engine::Verified => write!(f, "verified"),
+            Engine::Stepped => write!(f, "stepped"),
+        }
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Engine::ALL
+            .into_iter()
+            .find(|engine| engine.to_string() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = Engine::ALL.iter().map(Engine::to_string).collect();
+                format!(
+                    "unknown engine '{}', expected one of {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DifferentialError {
+    #[error("the {engine} engine cannot run this program: {reason}")]
+    Unsupported { engine: Engine, reason: String },
+    #[error("the {first} and {second} engines disagree\n{diff}")]
+    Diverged {
+        first: Engine,
+        second: Engine,
+        /// A line diff of the two snapshots, as `golden::diff` gives
+        diff: String,
+    },
+}
+
+/// Runs a program on two engines with the same input, checking that they
+/// print the same output and leave the same stack, memory and exit code,
+/// or fail with the same error. Returns what the program did.
+pub fn compare(
+    bytecode: &[u8],
+    input: &[u8],
+    first: Engine,
+    second: Engine,
+) -> Result<Snapshot, DifferentialError> {
+    let expected = first.run(bytecode, input)?;
+    let actual = second.run(bytecode, input)?;
+    if expected != actual {
+        return Err(DifferentialError::Diverged {
+            first,
+            second,
+            diff: golden::diff(&expected.to_string(), &actual.to_string()),
+        });
+    }
+    Ok(expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{compare, DifferentialError, Engine};
+    use crate::compiler::{Compiler, ModuleLoader};
+
+    #[test]
+    fn engines_agree_on_golden_programs() {
+        let mut compared = 0;
+        for entry in fs::read_dir("tests/golden").unwrap() {
+            let path = entry.unwrap().path();
+            let Ok(statements) = ModuleLoader::new().load(&path) else {
+                continue;
+            };
+            let Ok(bytecode) = Compiler::new().compile(statements) else {
+                continue;
+            };
+            for engine in [Engine::Verified, Engine::Stepped] {
+                match compare(&bytecode, b"", Engine::Interpreter, engine) {
+                    Ok(_) | Err(DifferentialError::Unsupported { .. }) => {}
+                    Err(error) => panic!("{}: {}", path.display(), error),
+                }
+            }
+            compared += 1;
+        }
+        assert!(compared > 0);
+    }
+
+    #[test]
+    fn compares_output_state_and_faults() {
+        let program = crate::bytecode![read, dup, print, push 2, mul, halt];
+        for engine in Engine::ALL {
+            let snapshot = compare(&program, b"21\n", Engine::Interpreter, engine).unwrap();
+            assert_eq!(snapshot.output, "Output: 21\n");
+            assert_eq!(snapshot.stack, [42]);
+        }
+        let faulting = crate::bytecode![push 1, push 0, div, halt];
+        let snapshot = compare(&faulting, b"", Engine::Verified, Engine::Stepped).unwrap();
+        assert_eq!(snapshot.result, "error: Division by zero");
+
+        // A program the verifier cannot bound
+        let unbounded = crate::bytecode![top: push 1, jmp top];
+        assert!(matches!(
+            Engine::Verified.run(&unbounded, b""),
+            Err(DifferentialError::Unsupported {
+                engine: Engine::Verified,
+                ..
+            })
+        ));
+        assert_eq!("stepped".parse(), Ok(Engine::Stepped));
+        assert!("jit".parse::<Engine>().is_err());
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::{
+    bytecode::{self, OperandEncoding},
+    compiler::DebugInfo,
+    Opcode,
+};
+
+/// One line of a disassembly: an instruction or a piece of data, at the
+/// offset of its first byte.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisasmLine {
+    pub offset: usize,
+    pub kind: DisasmKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisasmKind {
+    /// The program's header, announcing its operand encoding
+    Header(OperandEncoding),
+    Instruction {
+        opcode: Opcode,
+        /// The value of a `Push`, or the pool index of a `PushConst`
+        operand: Option<i64>,
+    },
+    /// A byte in the code that is not an opcode, or an opcode whose
+    /// operand is cut off by the end of the program
+    Invalid(u8),
+    /// A string constant of the data segment
+    String(String),
+    /// An 8-byte value of the data segment, such as a constant pool entry
+    Int(i64),
+    /// Data bytes that are none of the above
+    Bytes(Vec<u8>),
+}
+
+impl DisasmLine {
+    /// Whether the line is a byte that cannot be decoded.
+    pub fn is_invalid(&self) -> bool {
+        matches!(self.kind, DisasmKind::Invalid(_))
+    }
+}
+
+/// Formats a line as its offset followed by the instruction in the syntax
+/// of `asm::assemble`, with invalid bytes in a `.bytes` directive.
+impl fmt::Display for DisasmLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:06}  {}", self.offset, self.kind)
+    }
+}
+
+impl fmt::Display for DisasmKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisasmKind::Header(OperandEncoding::Fixed) => write!(f, ".encoding fixed"),
+            DisasmKind::Header(OperandEncoding::Leb128) => write!(f, ".encoding leb128"),
+            DisasmKind::Instruction { opcode, operand } => {
+                write!(f, "{}", format!("{:?}", opcode).to_lowercase())?;
+                match operand {
+                    Some(operand) => write!(f, " {}", operand),
+                    None => Ok(()),
+                }
+            }
+            DisasmKind::Invalid(byte) => write!(f, ".bytes {:#04x}  ; invalid", byte),
+            DisasmKind::String(value) => write!(f, ".string {:?}", value),
+            DisasmKind::Int(value) => write!(f, ".int {}", value),
+            DisasmKind::Bytes(bytes) => {
+                write!(f, ".bytes")?;
+                for byte in bytes {
+                    write!(f, " {:#04x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Decodes a program for display, one line per instruction, from its
+/// header to its data segment.
+///
+/// Unlike `bytecode::decode`, this never fails: a byte that is not an
+/// opcode, or an opcode whose operand runs past the end of the program, is
+/// an `Invalid` line and decoding carries on with the next byte. The data
+/// segment is found the way `bytecode::decode` finds it; the strings that
+/// `LoadStr` and `BindHost` read are shown as strings, and the rest as
+/// 8-byte values.
+pub fn disassemble(bytecode: &[u8]) -> Vec<DisasmLine> {
+    let mut lines = Vec::new();
+    let (encoding, start) = bytecode::read_header(bytecode).unwrap_or((OperandEncoding::Fixed, 0));
+    if start > 0 {
+        lines.push(DisasmLine {
+            offset: 0,
+            kind: DisasmKind::Header(encoding),
+        });
+    }
+
+    let mut code_end = bytecode.len();
+    let mut strings = BTreeSet::new();
+    // Operands of the last two instructions, most recent last
+    let mut operands: [Option<i64>; 2] = [None, None];
+    let mut offset = start;
+    while offset < code_end {
+        let byte = bytecode[offset];
+        let decoded = Opcode::try_from(byte).ok().and_then(|opcode| {
+            bytecode::read_operand(opcode, &bytecode[offset + 1..], encoding)
+                .map(|(operand, len)| (opcode, operand, len))
+        });
+        let Some((opcode, operand, len)) = decoded else {
+            lines.push(DisasmLine {
+                offset,
+                kind: DisasmKind::Invalid(byte),
+            });
+            offset += 1;
+            operands = [None, None];
+            continue;
+        };
+        // `BindHost` takes the name's offset below the declared arity
+        let data_operand = match opcode {
+            Opcode::LoadStr | Opcode::ConstPool => operands[1],
+            Opcode::BindHost => operands[0],
+            _ => None,
+        };
+        if let Some(data) = data_operand.filter(|data| *data > offset as i64) {
+            code_end = code_end.min(data as usize);
+            if opcode != Opcode::ConstPool {
+                strings.insert(data as usize);
+            }
+        }
+        lines.push(DisasmLine {
+            offset,
+            kind: DisasmKind::Instruction { opcode, operand },
+        });
+        offset += 1 + len;
+        operands = [operands[1], operand];
+    }
+
+    offset = code_end;
+    while offset < bytecode.len() {
+        let (kind, len) = match data_at(bytecode, offset) {
+            Some(value) if strings.contains(&offset) => match string_at(bytecode, offset) {
+                Some(string) => {
+                    let len = 8 + string.len();
+                    (DisasmKind::String(string), len)
+                }
+                None => (DisasmKind::Int(value), 8),
+            },
+            Some(value) => (DisasmKind::Int(value), 8),
+            None => (
+                DisasmKind::Bytes(bytecode[offset..].to_vec()),
+                bytecode.len() - offset,
+            ),
+        };
+        lines.push(DisasmLine { offset, kind });
+        offset += len;
+    }
+    lines
+}
+
+/// Lists a program like `disassemble`, with each source line ahead of the
+/// instructions compiled from it, as `objdump -S` does.
+///
+/// A source line is shown again whenever the code returns to it, such as
+/// for a loop condition evaluated after the body. Code without a span, such
+/// as the prelude's functions, is headed `(no source)`, and the data segment
+/// `(data)`. `sources` holds the text of each source file the program was
+/// compiled from, indexed by file id; lines of imported modules are headed
+/// with the module's path from `debug_info`, as `lib.svm:2`.
+pub fn listing(bytecode: &[u8], debug_info: &DebugInfo, sources: &[&str]) -> String {
+    let files: Vec<Vec<&str>> = sources
+        .iter()
+        .map(|source| source.lines().collect())
+        .collect();
+    let mut listing = String::new();
+    // Whether the lines listed last are data, and the file and source line
+    // heading them if they are code with a span
+    let mut current: Option<(bool, Option<(usize, usize)>)> = None;
+    for line in disassemble(bytecode) {
+        let data = matches!(
+            line.kind,
+            DisasmKind::String(_) | DisasmKind::Int(_) | DisasmKind::Bytes(_)
+        );
+        let heading = match line.kind {
+            DisasmKind::Header(_) => None,
+            _ if data => Some((true, None)),
+            _ => Some((
+                false,
+                debug_info
+                    .span_at(line.offset)
+                    .map(|span| (span.file, span.line)),
+            )),
+        };
+        if heading.is_some() && heading != current {
+            current = heading;
+            let text = match heading.and_then(|(_, location)| location) {
+                Some((file, number)) => {
+                    let text = files
+                        .get(file)
+                        .and_then(|lines| lines.get(number - 1))
+                        .map_or("", |text| text.trim());
+                    let number = match debug_info.file(file) {
+                        Some(path) if file > 0 => format!("{}:{}", path, number),
+                        _ => number.to_string(),
+                    };
+                    format!("{:>6} | {}", number, text)
+                }
+                None if data => format!("{:>6} | (data)", ""),
+                None => format!("{:>6} | (no source)", ""),
+            };
+            listing.push_str(text.trim_end());
+            listing.push('\n');
+        }
+        listing.push_str(&line.to_string());
+        listing.push('\n');
+    }
+    listing
+}
+
+fn data_at(bytecode: &[u8], offset: usize) -> Option<i64> {
+    let bytes = bytecode.get(offset..offset + 8)?;
+    Some(i64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn string_at(bytecode: &[u8], offset: usize) -> Option<String> {
+    let len = usize::try_from(data_at(bytecode, offset)?).ok()?;
+    let bytes = bytecode.get(offset + 8..(offset + 8).checked_add(len)?)?;
+    String::from_utf8(bytes.to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{disassemble, DisasmKind};
+    use crate::{
+        asm,
+        bytecode::OperandEncoding,
+        compiler::{Compiler, ModuleLoader, OptLevel, Parser},
+        Opcode,
+    };
+
+    fn disassembly(bytecode: &[u8]) -> String {
+        disassemble(bytecode)
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect()
+    }
+
+    #[test]
+    fn lists_code_and_data() {
+        let statements = Parser::new("let s = \"hi\"; print s; print 7 << 2;")
+            .parse_program()
+            .unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.set_opt_level(OptLevel::None);
+        let bytecode = compiler.compile(statements).unwrap();
+        assert_eq!(
+            disassembly(&bytecode),
+            "000000  push 52\n\
+             000009  loadstr\n\
+             000010  push 0\n\
+             000019  store\n\
+             000020  push 0\n\
+             000029  load\n\
+             000030  printstr\n\
+             000031  push 7\n\
+             000040  push 2\n\
+             000049  shl\n\
+             000050  print\n\
+             000051  halt\n\
+             000052  .string \"hi\"\n"
+        );
+    }
+
+    #[test]
+    fn reads_leb128_operands() {
+        let statements = Parser::new("print 300;").parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.set_operand_encoding(OperandEncoding::Leb128);
+        let lines = disassemble(&compiler.compile(statements).unwrap());
+        assert_eq!(lines[0].kind, DisasmKind::Header(OperandEncoding::Leb128));
+        assert_eq!(
+            lines[1].kind,
+            DisasmKind::Instruction {
+                opcode: Opcode::Push,
+                operand: Some(300)
+            }
+        );
+        assert_eq!(lines[1].to_string(), "000005  push 300");
+    }
+
+    #[test]
+    fn flags_invalid_bytes_and_carries_on() {
+        let mut bytecode = asm::assemble("push 1\nprint").unwrap();
+        bytecode.insert(9, 0xEE);
+        // A `Push` cut off after two of its operand bytes
+        bytecode.extend([Opcode::Push as u8, Opcode::Push as u8, Opcode::Pop as u8]);
+        assert_eq!(
+            disassembly(&bytecode),
+            "000000  push 1\n\
+             000009  .bytes 0xee  ; invalid\n\
+             000010  print\n\
+             000011  .bytes 0x01  ; invalid\n\
+             000012  .bytes 0x01  ; invalid\n\
+             000013  pop\n"
+        );
+        assert!(disassemble(&bytecode)[1].is_invalid());
+    }
+
+    #[test]
+    fn interleaves_source_lines() {
+        let source = "let i = 2;\nwhile i > 0 {\n    i = i - 1;\n}\nprint \"done\";\n";
+        let statements = Parser::new(source).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.set_opt_level(OptLevel::None);
+        compiler.set_debug_info(true);
+        let bytecode = compiler.compile(statements).unwrap();
+        let listing = super::listing(&bytecode, compiler.debug_info().unwrap(), &[source]);
+        assert_eq!(
+            listing,
+            "     1 | let i = 2;\n\
+             000000  push 2\n\
+             000009  push 0\n\
+             000018  store\n\
+             \x20    2 | while i > 0 {\n\
+             000019  push 0\n\
+             000028  load\n\
+             000029  push 0\n\
+             000038  greater\n\
+             000039  push 0\n\
+             000048  equal\n\
+             000049  push 99\n\
+             000058  jumpif\n\
+             \x20    3 | i = i - 1;\n\
+             000059  push 0\n\
+             000068  load\n\
+             000069  push 1\n\
+             000078  sub\n\
+             000079  push 0\n\
+             000088  store\n\
+             \x20    2 | while i > 0 {\n\
+             000089  push 19\n\
+             000098  jump\n\
+             \x20    5 | print \"done\";\n\
+             000099  push 111\n\
+             000108  loadstr\n\
+             000109  printstr\n\
+             \x20      | (no source)\n\
+             000110  halt\n\
+             \x20      | (data)\n\
+             000111  .string \"done\"\n"
+        );
+    }
+
+    #[test]
+    fn heads_lines_of_imported_modules_with_their_file() {
+        let sources = [
+            "import \"lib.svm\";\nprint one();",
+            "fn one() {\n    return 1;\n}",
+        ];
+        let mut loader = ModuleLoader::with_reader(move |path| match path.to_str() {
+            Some("main.svm") => Ok(sources[0].to_string()),
+            Some("lib.svm") => Ok(sources[1].to_string()),
+            _ => Err(io::ErrorKind::NotFound.into()),
+        });
+        let statements = loader.load("main.svm").unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.set_debug_info(true);
+        let bytecode = compiler.compile(statements).unwrap();
+        let mut debug_info = compiler.debug_info().unwrap().clone();
+        debug_info.set_files(vec!["main.svm".to_string(), "lib.svm".to_string()]);
+        let listing = super::listing(&bytecode, &debug_info, &sources);
+        assert!(listing.contains("\nlib.svm:2 | return 1;\n"), "{}", listing);
+        assert!(listing.contains("\n     2 | print one();\n"), "{}", listing);
+    }
+}
@@ -0,0 +1,310 @@
+//! Building blocks for fuzz targets, with the `arbitrary` feature:
+//! `Arbitrary` for `Opcode`, for programs of whole instructions and for
+//! streams of tokens, and `run_bounded` to run what a fuzzer made without
+//! hanging or printing.
+
+use std::io;
+use std::sync::OnceLock;
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::compiler::lexer::{Lexer, Token};
+use crate::{Opcode, VMError, VM};
+
+/// Operand stack slots, and call depth, of the VMs `run_bounded` creates.
+pub const STACK_LIMIT: usize = 256;
+
+/// Every opcode, in order of their bytes.
+fn opcodes() -> &'static [Opcode] {
+    static OPCODES: OnceLock<Vec<Opcode>> = OnceLock::new();
+    OPCODES.get_or_init(|| {
+        (0..=u8::MAX)
+            .filter_map(|byte| Opcode::try_from(byte).ok())
+            .collect()
+    })
+}
+
+impl<'a> Arbitrary<'a> for Opcode {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        u.choose(opcodes()).copied()
+    }
+}
+
+/// The operand of a `Push` or `PushConst` in a `StructuredProgram`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Arbitrary)]
+pub enum Operand {
+    /// A value, or a constant pool index for `PushConst`
+    Value(i64),
+    /// The address of the instruction at this index, wrapping around the
+    /// program's length, so a jump to it lands on an instruction
+    Target(usize),
+}
+
+/// A program of whole instructions ending with `Halt`, each `Push` and
+/// `PushConst` with its operand. Fuzzing with these reaches deeper into the
+/// interpreter than raw bytes, which mostly stop at an invalid opcode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructuredProgram {
+    pub instructions: Vec<(Opcode, Option<Operand>)>,
+}
+
+impl<'a> Arbitrary<'a> for StructuredProgram {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = u.arbitrary_len::<(Opcode, Operand)>()?;
+        let mut instructions = Vec::with_capacity(len + 1);
+        for _ in 0..len {
+            let opcode = u.arbitrary()?;
+            let operand = match opcode {
+                Opcode::Push | Opcode::PushConst => Some(u.arbitrary()?),
+                _ => None,
+            };
+            instructions.push((opcode, operand));
+        }
+        instructions.push((Opcode::Halt, None));
+        Ok(StructuredProgram { instructions })
+    }
+}
+
+impl StructuredProgram {
+    /// The program as bytecode with fixed-width operands.
+    pub fn to_bytecode(&self) -> Vec<u8> {
+        let mut addrs = Vec::with_capacity(self.instructions.len());
+        let mut addr = 0;
+        for (opcode, _) in &self.instructions {
+            addrs.push(addr);
+            addr += match opcode {
+                Opcode::Push => 9,
+                Opcode::PushConst => 3,
+                _ => 1,
+            };
+        }
+        let mut bytecode = Vec::with_capacity(addr);
+        for (opcode, operand) in &self.instructions {
+            bytecode.push(*opcode as u8);
+            let value = match operand {
+                Some(Operand::Value(value)) => *value,
+                Some(Operand::Target(index)) => addrs[index % addrs.len()] as i64,
+                None => continue,
+            };
+            match opcode {
+                Opcode::Push => bytecode.extend_from_slice(&value.to_le_bytes()),
+                Opcode::PushConst => bytecode.extend_from_slice(&(value as u16).to_le_bytes()),
+                _ => {}
+            }
+        }
+        bytecode
+    }
+}
+
+/// Tokens without a value, each written as it appears in source.
+const FIXED_TOKENS: &[Token] = &[
+    Token::Plus,
+    Token::PlusPlus,
+    Token::Minus,
+    Token::MinusMinus,
+    Token::Star,
+    Token::Slash,
+    Token::Percent,
+    Token::LParen,
+    Token::RParen,
+    Token::LBrace,
+    Token::RBrace,
+    Token::LBracket,
+    Token::RBracket,
+    Token::Semicolon,
+    Token::Comma,
+    Token::Colon,
+    Token::Question,
+    Token::Dot,
+    Token::Equals,
+    Token::Let,
+    Token::Const,
+    Token::If,
+    Token::Else,
+    Token::While,
+    Token::Print,
+    Token::Assert,
+    Token::Exit,
+    Token::Throw,
+    Token::Try,
+    Token::Catch,
+    Token::Struct,
+    Token::Match,
+    Token::Fn,
+    Token::Return,
+    Token::Import,
+    Token::Extern,
+    Token::True,
+    Token::False,
+    Token::Bang,
+    Token::AndAnd,
+    Token::OrOr,
+    Token::DoubleEquals,
+    Token::FatArrow,
+    Token::Arrow,
+    Token::NotEquals,
+    Token::LessThan,
+    Token::GreaterThan,
+    Token::LessEqual,
+    Token::GreaterEqual,
+    Token::ShiftLeft,
+    Token::ShiftRight,
+];
+
+/// Any token the lexer reads without error, mostly keywords and
+/// punctuation, with short identifiers so that names repeat.
+impl<'a> Arbitrary<'a> for Token {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=9)? {
+            0 => Token::Number(u.arbitrary()?),
+            // Finite and short enough to be written without an exponent
+            1 => Token::Float(f64::from(u.arbitrary::<u32>()?) / 16.0),
+            2 => Token::Str(u.arbitrary()?),
+            3 => Token::Char(u.arbitrary()?),
+            4 | 5 => {
+                let len = u.int_in_range(1..=3)?;
+                let mut name = String::with_capacity(len + 1);
+                for _ in 0..len {
+                    name.push(*u.choose(b"abcnx_")? as char);
+                }
+                // A name spelled like a keyword would read back as the keyword
+                if !matches!(
+                    Lexer::new(&name).next(),
+                    Some(Ok(token)) if token.token == Token::Identifier(name.clone())
+                ) {
+                    name.push('_');
+                }
+                Token::Identifier(name)
+            }
+            _ => u.choose(FIXED_TOKENS)?.clone(),
+        })
+    }
+}
+
+/// A sequence of tokens that `source` writes out as text the lexer reads
+/// back as the same tokens, to fuzz the parser and the compiler with input
+/// that gets past the lexer.
+#[derive(Debug, Clone, PartialEq, Arbitrary)]
+pub struct TokenStream(pub Vec<Token>);
+
+impl TokenStream {
+    /// The tokens as source, separated by spaces.
+    pub fn source(&self) -> String {
+        let tokens: Vec<_> = self.0.iter().map(token_source).collect();
+        tokens.join(" ")
+    }
+}
+
+fn token_source(token: &Token) -> String {
+    match token {
+        Token::Number(value) => value.to_string(),
+        Token::Float(value) => format!("{:?}", value),
+        Token::Str(text) => format!("\"{}\"", escape(text)),
+        Token::Char(ch) => format!("'{}'", escape(&ch.to_string())),
+        Token::Identifier(name) => name.clone(),
+        // Fixed tokens display quoted, as in error messages
+        token => token.to_string().trim_matches('\'').to_string(),
+    }
+}
+
+/// Escapes quotes, backslashes and characters that are not printed as is.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '"' | '\'' | '\\' => {
+                escaped.push('\\');
+                escaped.push(ch);
+            }
+            ch if ch.is_control() || ch.is_whitespace() => {
+                escaped.push_str(&format!("\\u{{{:x}}}", ch as u32));
+            }
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// Runs bytecode for at most `fuel` instructions with no input, dropping
+/// its output, and returns whether it was still running. A fuzz target
+/// only needs to call this: what matters is that it returns.
+pub fn run_bounded(bytecode: Vec<u8>, fuel: u64) -> std::result::Result<bool, VMError> {
+    let mut vm = VM::new(bytecode, STACK_LIMIT);
+    vm.set_input(io::empty());
+    vm.set_output(io::sink());
+    vm.run_for(fuel)
+}
+
+#[cfg(test)]
+mod tests {
+    use arbitrary::{Arbitrary, Unstructured};
+
+    use super::{run_bounded, Operand, StructuredProgram, TokenStream};
+    use crate::compiler::lexer::Lexer;
+    use crate::{bytecode, Opcode};
+
+    /// Deterministic bytes for `Unstructured`, different for each seed.
+    fn bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn structured_programs_decode_and_run_bounded() {
+        let program = StructuredProgram {
+            instructions: vec![
+                (Opcode::Push, Some(Operand::Target(2))),
+                (Opcode::Jump, None),
+                (Opcode::Push, Some(Operand::Value(7))),
+                (Opcode::Halt, None),
+            ],
+        };
+        assert_eq!(
+            program.to_bytecode(),
+            bytecode![push 10, jump, push 7, halt]
+        );
+        assert!(matches!(run_bounded(program.to_bytecode(), 100), Ok(false)));
+
+        for seed in 0..200 {
+            let data = bytes(seed, 512);
+            let program = StructuredProgram::arbitrary(&mut Unstructured::new(&data)).unwrap();
+            let bytecode = program.to_bytecode();
+            let decoded: Vec<_> = crate::bytecode::decode(&bytecode)
+                .unwrap()
+                .iter()
+                .map(|instruction| instruction.opcode)
+                .collect();
+            let opcodes: Vec<_> = program.instructions.iter().map(|(op, _)| *op).collect();
+            assert!(opcodes.starts_with(&decoded));
+            // Decoding stops early only where a string or pool offset
+            // pushed before one of these reads as the data segment
+            if decoded.len() < opcodes.len() {
+                assert!(decoded.iter().any(|opcode| matches!(
+                    opcode,
+                    Opcode::LoadStr | Opcode::ConstPool | Opcode::BindHost
+                )));
+            }
+            let _ = run_bounded(bytecode, 1_000);
+        }
+    }
+
+    #[test]
+    fn token_streams_lex_back_to_the_same_tokens() {
+        for seed in 0..200 {
+            let data = bytes(seed, 256);
+            let stream = TokenStream::arbitrary(&mut Unstructured::new(&data)).unwrap();
+            let source = stream.source();
+            let tokens: Vec<_> = Lexer::new(&source)
+                .map(|token| token.unwrap().token)
+                .collect();
+            assert_eq!(tokens, stream.0, "{}", source);
+        }
+    }
+}
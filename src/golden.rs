@@ -0,0 +1,259 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use thiserror::Error;
+
+use crate::compiler::{Compiler, ModuleLoader};
+use crate::diff::align;
+use crate::{VMError, VM};
+
+/// Set to `1` to write the snapshots of the programs checked instead of
+/// comparing them, after a change to what programs do.
+pub const UPDATE_VAR: &str = "UPDATE_SNAPSHOTS";
+
+/// Instructions a program may run before it is stopped, so a program that
+/// loops forever fails its check instead of hanging the test.
+pub const MAX_INSTRUCTIONS: u64 = 10_000_000;
+
+/// Operand stack size programs run with, as with `simple-vm run`.
+pub(crate) const STACK_LIMIT: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum GoldenError {
+    #[error("{}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("{}: snapshot does not match\n{diff}", path.display())]
+    Mismatch { path: PathBuf, diff: String },
+    #[error("{}: new snapshot written, review it and check it in", path.display())]
+    New { path: PathBuf },
+    #[error("{}", join(.0))]
+    Failed(Vec<GoldenError>),
+}
+
+fn join(errors: &[GoldenError]) -> String {
+    let messages: Vec<_> = errors.iter().map(|error| error.to_string()).collect();
+    messages.join("\n\n")
+}
+
+/// What a program did: its output, the stack and memory it left, and how it
+/// ended. Displays in the format snapshot files are stored in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub output: String,
+    pub stack: Vec<i64>,
+    /// Memory cells in use, by address
+    pub memory: Vec<(usize, i64)>,
+    /// `exit code N`, the compile or runtime error, or that the program
+    /// ran out of instructions
+    pub result: String,
+}
+
+impl Snapshot {
+    /// Compiles and runs the program at `path` with the modules it imports,
+    /// with no input. A program that does not compile gives a snapshot with
+    /// only its error.
+    pub fn of_file(path: impl AsRef<Path>) -> Snapshot {
+        let path = path.as_ref();
+        let mut loader = ModuleLoader::new();
+        let compiled = loader.load(path).and_then(|statements| {
+            Compiler::new()
+                .compile(statements)
+                .map_err(|error| loader.locate(&error))
+        });
+        match compiled {
+            Ok(bytecode) => Snapshot::of_program(bytecode),
+            Err(error) => Snapshot {
+                output: String::new(),
+                stack: Vec::new(),
+                memory: Vec::new(),
+                result: format!("compile error: {}", error),
+            },
+        }
+    }
+
+    /// Runs compiled bytecode with no input.
+    pub fn of_program(bytecode: Vec<u8>) -> Snapshot {
+        let vm = VM::new(bytecode, STACK_LIMIT);
+        Snapshot::of_run(vm, &[], |vm| vm.run_for(MAX_INSTRUCTIONS))
+    }
+
+    /// Runs a VM with `execute`, which tells whether the program is still
+    /// running once it returns, reading `input` and capturing its output.
+    pub(crate) fn of_run(
+        mut vm: VM,
+        input: &[u8],
+        execute: impl FnOnce(&mut VM) -> Result<bool, VMError>,
+    ) -> Snapshot {
+        let output = Capture::default();
+        vm.set_input(io::Cursor::new(input.to_vec()));
+        vm.set_output(output.clone());
+        let result = match execute(&mut vm) {
+            Ok(false) => format!("exit code {}", vm.get_exit_code()),
+            Ok(true) => format!("still running after {} instructions", MAX_INSTRUCTIONS),
+            Err(error) => format!("error: {}", error),
+        };
+        let mut memory: Vec<_> = vm.get_memory().iter().map(|(a, v)| (*a, *v)).collect();
+        memory.sort_unstable();
+        let output = String::from_utf8_lossy(&output.0.borrow()).into_owned();
+        Snapshot {
+            output,
+            stack: vm.get_stack().to_vec(),
+            memory,
+            result,
+        }
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "-- output")?;
+        for line in self.output.lines() {
+            writeln!(f, "{}", line)?;
+        }
+        writeln!(f, "-- stack")?;
+        for value in &self.stack {
+            writeln!(f, "{}", value)?;
+        }
+        writeln!(f, "-- memory")?;
+        for (addr, value) in &self.memory {
+            writeln!(f, "{}: {}", addr, value)?;
+        }
+        writeln!(f, "-- result")?;
+        writeln!(f, "{}", self.result)
+    }
+}
+
+/// Shares the text a VM writes with whoever reads it after it runs.
+#[derive(Clone, Default)]
+pub(crate) struct Capture(pub(crate) Rc<RefCell<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the program at `path` and compares what it did with the snapshot
+/// stored beside it, `path` with the extension `snap`.
+///
+/// A missing snapshot is written and reported with `GoldenError::New`, so
+/// a new program fails once until its snapshot is reviewed. With
+/// `UPDATE_SNAPSHOTS=1` in the environment, snapshots are written instead
+/// of compared.
+pub fn check(path: impl AsRef<Path>) -> Result<(), GoldenError> {
+    let path = path.as_ref();
+    let snap = path.with_extension("snap");
+    let actual = Snapshot::of_file(path).to_string();
+    let update = std::env::var(UPDATE_VAR).is_ok_and(|value| value == "1");
+    let expected = match fs::read_to_string(&snap) {
+        Ok(expected) if !update => expected,
+        Err(error) if error.kind() != io::ErrorKind::NotFound => {
+            return Err(GoldenError::Io {
+                path: snap,
+                source: error,
+            })
+        }
+        _ => {
+            fs::write(&snap, actual).map_err(|source| GoldenError::Io {
+                path: snap.clone(),
+                source,
+            })?;
+            return if update {
+                Ok(())
+            } else {
+                Err(GoldenError::New { path: snap })
+            };
+        }
+    };
+    if expected == actual {
+        return Ok(());
+    }
+    Err(GoldenError::Mismatch {
+        path: path.to_path_buf(),
+        diff: diff(&expected, &actual),
+    })
+}
+
+/// Checks every `.svm` file directly in `dir`, reporting all that fail
+/// together. Returns how many programs were checked.
+pub fn check_dir(dir: impl AsRef<Path>) -> Result<usize, GoldenError> {
+    let dir = dir.as_ref();
+    let io_error = |source| GoldenError::Io {
+        path: dir.to_path_buf(),
+        source,
+    };
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        if path.extension().is_some_and(|extension| extension == "svm") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    let failures: Vec<_> = paths.iter().filter_map(|path| check(path).err()).collect();
+    if failures.is_empty() {
+        Ok(paths.len())
+    } else {
+        Err(GoldenError::Failed(failures))
+    }
+}
+
+/// A line diff of two snapshots, marking lines only the expected one has
+/// with `-` and lines only the actual one has with `+`.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let old: Vec<_> = expected.lines().collect();
+    let new: Vec<_> = actual.lines().collect();
+    let mut out = String::new();
+    for pair in align(&old, &new) {
+        let line = match pair {
+            (Some(i), Some(_)) => format!("  {}", old[i]),
+            (Some(i), None) => format!("- {}", old[i]),
+            (None, Some(j)) => format!("+ {}", new[j]),
+            (None, None) => continue,
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_dir, diff, Snapshot};
+
+    #[test]
+    fn golden_programs() {
+        if let Err(error) = check_dir("tests/golden") {
+            panic!("{}", error);
+        }
+    }
+
+    #[test]
+    fn snapshots_output_state_and_errors() {
+        let bytecode = crate::bytecode![push 7, print, push 1, push 0, div, halt];
+        let snapshot = Snapshot::of_program(bytecode);
+        assert_eq!(
+            snapshot.to_string(),
+            "-- output\nOutput: 7\n-- stack\n-- memory\n-- result\nerror: Division by zero\n"
+        );
+        let looping = Snapshot::of_program(crate::bytecode![top: jmp top]);
+        assert!(looping.result.starts_with("still running"));
+    }
+
+    #[test]
+    fn diffs_lines() {
+        assert_eq!(
+            diff("-- stack\n1\n2\n-- result\n", "-- stack\n1\n3\n-- result\n"),
+            "  -- stack\n  1\n- 2\n+ 3\n  -- result\n"
+        );
+    }
+}
@@ -0,0 +1,45 @@
+use std::fmt::Write;
+
+/// Memory cells per line of a hex dump.
+const CELLS_PER_LINE: usize = 4;
+
+/// Formats memory cells, such as those of `VM::dump_memory_range`, as a hex
+/// dump: each line holds the address of its first cell, four cells as
+/// 16-digit two's complement hex, and the cells as characters, since
+/// strings keep one character per cell. A cell that is not a printable
+/// ASCII character shows as `.`.
+pub fn hex_dump(start: usize, cells: &[i64]) -> String {
+    let mut dump = String::new();
+    for (line, chunk) in cells.chunks(CELLS_PER_LINE).enumerate() {
+        write!(dump, "{:06}:", start + line * CELLS_PER_LINE).unwrap();
+        for cell in chunk {
+            write!(dump, " {:016x}", cell).unwrap();
+        }
+        let padding = (CELLS_PER_LINE - chunk.len()) * 17;
+        write!(dump, "{:padding$}  |", "").unwrap();
+        for &cell in chunk {
+            let printable = u8::try_from(cell)
+                .ok()
+                .filter(|byte| byte.is_ascii_graphic() || *byte == b' ');
+            dump.push(printable.map_or('.', char::from));
+        }
+        dump.push_str("|\n");
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hex_dump;
+
+    #[test]
+    fn dumps_cells_in_hex_and_as_characters() {
+        let cells = [2, 'h' as i64, 'i' as i64, 0, -1, 300];
+        assert_eq!(
+            hex_dump(100, &cells),
+            "000100: 0000000000000002 0000000000000068 0000000000000069 0000000000000000  |.hi.|\n\
+             000104: ffffffffffffffff 000000000000012c                                    |..|\n"
+        );
+        assert_eq!(hex_dump(0, &[]), "");
+    }
+}
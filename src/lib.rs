@@ -1,7 +1,55 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::{Deref, DerefMut, Range};
+use std::time::Instant;
 use thiserror::Error;
 
+pub mod analysis;
+pub mod asm;
+pub mod bytecode;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod cfg;
+pub mod channel;
 pub mod compiler;
+pub mod coverage;
+pub mod debugger;
+pub mod diff;
+pub mod differential;
+pub mod disasm;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+pub mod golden;
+pub mod inspect;
+pub mod metrics;
+pub mod profile;
+pub mod program;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod repl;
+pub mod scheduler;
+
+use bytecode::OperandEncoding;
+use channel::Channel;
+use compiler::{DebugInfo, Span};
+use coverage::Coverage;
+use metrics::Metrics;
+use profile::{HotspotProfile, OpcodeProfile};
+pub use program::{compile_file, run_file, Program, VmConfig};
+pub mod stack_depth;
+#[cfg(feature = "proptest")]
+pub mod strategies;
+pub mod svb;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod value;
+
+use value::TypeTracker;
+pub use value::{Kind, Value};
 
 #[derive(Debug, Error)]
 pub enum VMError {
@@ -15,9 +63,81 @@ pub enum VMError {
     OutOfMemory(usize),
     #[error("Division by zero")]
     DivisionByZero,
+    #[error("Invalid string constant at offset: {0}")]
+    InvalidString(usize),
+    #[error("Invalid constant pool index: {0}")]
+    InvalidConstant(usize),
+    #[error("Index {index} out of bounds for length {len}")]
+    IndexOutOfBounds { index: i64, len: i64 },
+    #[error("No more input to read")]
+    InputExhausted,
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    #[error("Could not write output: {0}")]
+    OutputFailed(String),
+    #[error("Assertion failed at pc {pc}")]
+    AssertionFailed { pc: usize },
+    #[error("Invalid argument to {0}: {1}")]
+    InvalidArgument(&'static str, i64),
+    #[error("Key {0} not found in map")]
+    KeyNotFound(i64),
+    #[error("Uncaught exception: {0}")]
+    UncaughtException(i64),
+    #[error("No host function registered as '{0}'")]
+    UnknownHostFunction(String),
+    #[error("Host function '{name}' takes {registered} argument(s), but the program declares {declared}")]
+    HostArityMismatch {
+        name: String,
+        declared: usize,
+        registered: usize,
+    },
+    #[error("No channel attached as {0}")]
+    UnknownChannel(i64),
+    /// Raised by `Recv` before it takes anything off the stack, so running
+    /// the VM again once a value has been sent retries it
+    #[error("Nothing to receive on channel {0}")]
+    ChannelEmpty(i64),
+    /// An opcode given a value of a kind it does not work on, with type
+    /// checking enabled
+    #[error("Type mismatch in {opcode:?}: expected {expected}, found {found}")]
+    TypeMismatch {
+        opcode: Opcode,
+        expected: &'static str,
+        found: Kind,
+    },
+}
+
+/// A function provided by the embedding application, called with its
+/// arguments in declaration order.
+pub type HostFunction = Box<dyn FnMut(&[i64]) -> Result<i64, VMError>>;
+
+/// Receives the value of each `Print` in place of the VM's output.
+pub type PrintHandler = Box<dyn FnMut(i64)>;
+
+/// First memory address handed out for runtime allocations such as strings.
+/// Compiled variables live below this address.
+pub const HEAP_BASE: usize = 1 << 20;
+
+/// Checks the amount of a `Shl` or `Shr`, which must be less than the width
+/// of a value.
+fn shift_amount(amount: i64) -> Result<u32, VMError> {
+    u32::try_from(amount)
+        .ok()
+        .filter(|amount| *amount < i64::BITS)
+        .ok_or(VMError::InvalidArgument("shift", amount))
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Number of slots in a new map's table; tables double when 3/4 full.
+const MAP_INITIAL_CAPACITY: usize = 8;
+
+/// First memory address of the call frame stack. Top-level variables live
+/// below it, and each active call's locals in a frame between it and `HEAP_BASE`.
+pub const FRAME_BASE: usize = HEAP_BASE / 2;
+
+/// An instruction's operation. Serializes as its lowercase name, as the
+/// assembler and disassembler write it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Opcode {
     Push = 0x01,
     Pop = 0x02,
@@ -35,6 +155,67 @@ pub enum Opcode {
     Halt = 0xFF,
     LessEqual = 0x0E,
     GreaterEqual = 0x0F,
+    Mod = 0x10,
+    NotEqual = 0x11,
+    Greater = 0x12,
+    LoadStr = 0x13,
+    PrintStr = 0x14,
+    Concat = 0x15,
+    Index = 0x16,
+    NewArray = 0x17,
+    SetIndex = 0x18,
+    Dup = 0x19,
+    Inc = 0x1A,
+    Dec = 0x1B,
+    Read = 0x1C,
+    Assert = 0x1D,
+    Neg = 0x1E,
+    Abs = 0x1F,
+    Min = 0x20,
+    Max = 0x21,
+    Pow = 0x22,
+    SqrtInt = 0x23,
+    FAdd = 0x24,
+    FSub = 0x25,
+    FMul = 0x26,
+    FDiv = 0x27,
+    FMod = 0x28,
+    FNeg = 0x29,
+    FEqual = 0x2A,
+    FNotEqual = 0x2B,
+    FLess = 0x2C,
+    FLessEqual = 0x2D,
+    FGreater = 0x2E,
+    FGreaterEqual = 0x2F,
+    IntToFloat = 0x30,
+    FloatToInt = 0x31,
+    PrintFloat = 0x32,
+    Call = 0x33,
+    Ret = 0x34,
+    CallClosure = 0x35,
+    BindHost = 0x36,
+    CallHost = 0x37,
+    Enter = 0x38,
+    LoadLocal = 0x39,
+    StoreLocal = 0x3A,
+    PrintChar = 0x3B,
+    Exit = 0x3C,
+    PushHandler = 0x3D,
+    PopHandler = 0x3E,
+    Throw = 0x3F,
+    NewMap = 0x40,
+    MapGet = 0x41,
+    MapSet = 0x42,
+    MapHas = 0x43,
+    WriteInt = 0x44,
+    WriteFloat = 0x45,
+    WriteStr = 0x46,
+    PushConst = 0x47,
+    ConstPool = 0x48,
+    Shl = 0x49,
+    Shr = 0x4A,
+    Send = 0x4B,
+    Recv = 0x4C,
 }
 
 impl TryFrom<u8> for Opcode {
@@ -58,40 +239,436 @@ impl TryFrom<u8> for Opcode {
             0xFF => Ok(Opcode::Halt),
             0x0E => Ok(Opcode::LessEqual),
             0x0F => Ok(Opcode::GreaterEqual),
+            0x10 => Ok(Opcode::Mod),
+            0x11 => Ok(Opcode::NotEqual),
+            0x12 => Ok(Opcode::Greater),
+            0x13 => Ok(Opcode::LoadStr),
+            0x14 => Ok(Opcode::PrintStr),
+            0x15 => Ok(Opcode::Concat),
+            0x16 => Ok(Opcode::Index),
+            0x17 => Ok(Opcode::NewArray),
+            0x18 => Ok(Opcode::SetIndex),
+            0x19 => Ok(Opcode::Dup),
+            0x1A => Ok(Opcode::Inc),
+            0x1B => Ok(Opcode::Dec),
+            0x1C => Ok(Opcode::Read),
+            0x1D => Ok(Opcode::Assert),
+            0x1E => Ok(Opcode::Neg),
+            0x1F => Ok(Opcode::Abs),
+            0x20 => Ok(Opcode::Min),
+            0x21 => Ok(Opcode::Max),
+            0x22 => Ok(Opcode::Pow),
+            0x23 => Ok(Opcode::SqrtInt),
+            0x24 => Ok(Opcode::FAdd),
+            0x25 => Ok(Opcode::FSub),
+            0x26 => Ok(Opcode::FMul),
+            0x27 => Ok(Opcode::FDiv),
+            0x28 => Ok(Opcode::FMod),
+            0x29 => Ok(Opcode::FNeg),
+            0x2A => Ok(Opcode::FEqual),
+            0x2B => Ok(Opcode::FNotEqual),
+            0x2C => Ok(Opcode::FLess),
+            0x2D => Ok(Opcode::FLessEqual),
+            0x2E => Ok(Opcode::FGreater),
+            0x2F => Ok(Opcode::FGreaterEqual),
+            0x30 => Ok(Opcode::IntToFloat),
+            0x31 => Ok(Opcode::FloatToInt),
+            0x32 => Ok(Opcode::PrintFloat),
+            0x33 => Ok(Opcode::Call),
+            0x34 => Ok(Opcode::Ret),
+            0x35 => Ok(Opcode::CallClosure),
+            0x36 => Ok(Opcode::BindHost),
+            0x37 => Ok(Opcode::CallHost),
+            0x38 => Ok(Opcode::Enter),
+            0x39 => Ok(Opcode::LoadLocal),
+            0x3A => Ok(Opcode::StoreLocal),
+            0x3B => Ok(Opcode::PrintChar),
+            0x3C => Ok(Opcode::Exit),
+            0x3D => Ok(Opcode::PushHandler),
+            0x3E => Ok(Opcode::PopHandler),
+            0x3F => Ok(Opcode::Throw),
+            0x40 => Ok(Opcode::NewMap),
+            0x41 => Ok(Opcode::MapGet),
+            0x42 => Ok(Opcode::MapSet),
+            0x43 => Ok(Opcode::MapHas),
+            0x44 => Ok(Opcode::WriteInt),
+            0x45 => Ok(Opcode::WriteFloat),
+            0x46 => Ok(Opcode::WriteStr),
+            0x47 => Ok(Opcode::PushConst),
+            0x48 => Ok(Opcode::ConstPool),
+            0x49 => Ok(Opcode::Shl),
+            0x4A => Ok(Opcode::Shr),
+            0x4B => Ok(Opcode::Send),
+            0x4C => Ok(Opcode::Recv),
             _ => Err(VMError::InvalidOpcode(value)),
         }
     }
 }
 
+/// State restored when a call returns.
+struct CallFrame {
+    return_addr: usize,
+    fp: usize,
+    frame_top: usize,
+}
+
+/// An active `try` block: where to resume, and the state to unwind to.
+struct Handler {
+    addr: usize,
+    stack_len: usize,
+    call_depth: usize,
+    fp: usize,
+    frame_top: usize,
+}
+
+/// Why `VM::resume` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The VM is about to execute the instruction at this breakpoint
+    Breakpoint(usize),
+    /// A step finished, in the frame it started in unless it stepped
+    /// into or out of a call
+    Stepped,
+    /// The program halted or called `exit`
+    Halted,
+}
+
+/// A run of memory cells mapped by `VM::memory_slice`, read and written as
+/// a `[i64]`. Cells the host changed are stored back into the VM's memory
+/// when the slice is dropped.
+pub struct MemorySlice<'a> {
+    memory: &'a mut HashMap<usize, i64>,
+    start: usize,
+    original: Vec<i64>,
+    cells: Vec<i64>,
+}
+
+impl Deref for MemorySlice<'_> {
+    type Target = [i64];
+
+    fn deref(&self) -> &[i64] {
+        &self.cells
+    }
+}
+
+impl DerefMut for MemorySlice<'_> {
+    fn deref_mut(&mut self) -> &mut [i64] {
+        &mut self.cells
+    }
+}
+
+impl Drop for MemorySlice<'_> {
+    fn drop(&mut self) {
+        let changed = self.cells.iter().zip(&self.original).enumerate();
+        for (offset, (&value, &original)) in changed {
+            if value != original {
+                self.memory.insert(self.start + offset, value);
+            }
+        }
+    }
+}
+
 pub struct VM {
     /// Program counter
     pc: usize,
+    /// Address of the instruction being executed, or executed last
+    instruction_pc: usize,
     /// Stack for operands
     stack: Vec<i64>,
     /// Program memory (bytecode)
     program: Vec<u8>,
     /// Data memory (heap)
     memory: HashMap<usize, i64>,
-    /// Maximum stack size
+    /// Active calls, innermost last
+    call_stack: Vec<CallFrame>,
+    /// Active exception handlers, innermost last
+    handlers: Vec<Handler>,
+    /// Base address of the current call's locals
+    fp: usize,
+    /// First address above the innermost frame
+    frame_top: usize,
+    /// Maximum stack size, also applied to the call depth
     stack_limit: usize,
+    /// Whether the program was shown to stay within `stack_limit`, so pushes
+    /// need not check it
+    verified: bool,
+    /// Next free heap address
+    heap_next: usize,
+    /// Source of values for the Read opcode, one integer per line
+    input: Box<dyn BufRead>,
+    /// Destination of the Print and Write opcodes
+    output: Box<dyn Write>,
+    /// Takes the values of Print instead of `output`, if set
+    print_handler: Option<PrintHandler>,
+    /// Registered host functions with their arity
+    host_functions: Vec<(String, usize, HostFunction)>,
+    /// Indices into `host_functions` of the program's `extern` declarations,
+    /// in the order they were bound
+    host_bindings: Vec<usize>,
+    /// Channels for Send and Recv, by the id programs use for them
+    channels: HashMap<i64, Channel>,
     /// Whether the VM is running
     running: bool,
+    /// Status passed to the Exit opcode, 0 if the program halted normally
+    exit_code: i64,
+    /// Source map of the program, if it was compiled with debug info
+    debug_info: Option<DebugInfo>,
+    /// Offset of the constant pool in the program, once `ConstPool` has run
+    const_pool: Option<usize>,
+    /// Encoding of inline operands, from the program's header
+    encoding: OperandEncoding,
+    /// Addresses where `resume` stops
+    breakpoints: BTreeSet<usize>,
+    /// Executions of each opcode, when profiling is enabled
+    profile: Option<Box<OpcodeProfile>>,
+    /// Executions of each instruction, when hotspot profiling is enabled
+    hotspots: Option<Box<HotspotProfile>>,
+    /// The instructions executed, when coverage is enabled
+    coverage: Option<Box<Coverage>>,
+    /// The kinds of values on the stack and in memory, when type checking
+    /// is enabled
+    types: Option<Box<TypeTracker>>,
+    /// Counters for `metrics`, always kept
+    metrics: Box<Metrics>,
 }
 
 impl VM {
     pub fn new(program: Vec<u8>, stack_limit: usize) -> Self {
+        // A malformed header is left to fail as an invalid opcode
+        let (encoding, start) =
+            bytecode::read_header(&program).unwrap_or((OperandEncoding::Fixed, 0));
+        #[cfg(feature = "tracing")]
+        tracing::debug!(len = program.len(), ?encoding, start, "program loaded");
         VM {
-            pc: 0,
+            pc: start,
+            instruction_pc: start,
             stack: Vec::with_capacity(stack_limit),
             program,
             memory: HashMap::new(),
+            call_stack: Vec::new(),
+            handlers: Vec::new(),
+            fp: FRAME_BASE,
+            frame_top: FRAME_BASE,
             stack_limit,
+            verified: false,
+            heap_next: HEAP_BASE,
+            input: Box::new(BufReader::new(io::stdin())),
+            output: Box::new(io::stdout()),
+            print_handler: None,
+            host_functions: Vec::new(),
+            host_bindings: Vec::new(),
+            channels: HashMap::new(),
             running: false,
+            exit_code: 0,
+            debug_info: None,
+            const_pool: None,
+            encoding,
+            breakpoints: BTreeSet::new(),
+            profile: None,
+            hotspots: None,
+            coverage: None,
+            types: None,
+            metrics: Box::default(),
+        }
+    }
+
+    /// Creates a VM for a program that `stack_depth::verify` proves never
+    /// needs more than `stack_limit` operand stack slots, which lets it skip
+    /// the limit check on every push.
+    pub fn new_verified(
+        program: Vec<u8>,
+        stack_limit: usize,
+    ) -> Result<Self, stack_depth::StackError> {
+        stack_depth::verify(&program, stack_limit)?;
+        Ok(VM {
+            verified: true,
+            ..VM::new(program, stack_limit)
+        })
+    }
+
+    /// Attaches the source map the program was compiled with, so errors can
+    /// be traced back to source with `current_span`.
+    pub fn set_debug_info(&mut self, debug_info: DebugInfo) {
+        self.debug_info = Some(debug_info);
+    }
+
+    /// Replaces the program and starts the next `run` at `start`, keeping
+    /// data memory, the heap, host functions and their bindings. The stack,
+    /// calls and exception handlers start empty. For hosts such as a REPL
+    /// that extend the program they run, so `start` must not depend on
+    /// anything the old program left on the stack.
+    pub fn load_program(&mut self, program: Vec<u8>, start: usize) {
+        let (encoding, _) = bytecode::read_header(&program).unwrap_or((OperandEncoding::Fixed, 0));
+        #[cfg(feature = "tracing")]
+        tracing::debug!(len = program.len(), ?encoding, start, "program loaded");
+        self.program = program;
+        self.encoding = encoding;
+        self.pc = start;
+        self.instruction_pc = start;
+        self.stack.clear();
+        self.call_stack.clear();
+        self.handlers.clear();
+        self.fp = FRAME_BASE;
+        self.frame_top = FRAME_BASE;
+        self.verified = false;
+        self.running = false;
+        self.exit_code = 0;
+        self.debug_info = None;
+        self.const_pool = None;
+        if let Some(types) = &mut self.types {
+            types.stack.clear();
+        }
+    }
+
+    /// Puts the VM back in the state `VM::new` left it in, to run the
+    /// program again from the start: the stack, memory, heap, calls,
+    /// exception handlers, host bindings and metrics are cleared. Host
+    /// functions, the print handler, channels, debug info, breakpoints, the
+    /// input and the profilers are kept.
+    pub fn reset(&mut self) {
+        let start = bytecode::read_header(&self.program).map_or(0, |(_, start)| start);
+        self.pc = start;
+        self.instruction_pc = start;
+        self.stack.clear();
+        self.memory.clear();
+        self.call_stack.clear();
+        self.handlers.clear();
+        self.fp = FRAME_BASE;
+        self.frame_top = FRAME_BASE;
+        self.heap_next = HEAP_BASE;
+        self.host_bindings.clear();
+        self.running = false;
+        self.exit_code = 0;
+        self.const_pool = None;
+        if let Some(types) = &mut self.types {
+            types.stack.clear();
+            types.clear_memory();
+        }
+        *self.metrics = Metrics::default();
+    }
+
+    /// What the VM has executed since it was created or last reset.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Enables or disables counting the executions of each opcode (off by
+    /// default). Enabling it starts a fresh profile.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profile = enabled.then(|| Box::new(OpcodeProfile::new()));
+    }
+
+    /// The opcodes executed since profiling was enabled.
+    pub fn profile(&self) -> Option<&OpcodeProfile> {
+        self.profile.as_deref()
+    }
+
+    /// Enables or disables counting the executions of each instruction, by
+    /// the calls it ran in (off by default). Enabling it starts a fresh
+    /// profile.
+    pub fn set_hotspot_profiling(&mut self, enabled: bool) {
+        self.hotspots = enabled.then(|| Box::new(HotspotProfile::new()));
+    }
+
+    /// The instructions executed since hotspot profiling was enabled.
+    pub fn hotspots(&self) -> Option<&HotspotProfile> {
+        self.hotspots.as_deref()
+    }
+
+    /// Enables or disables recording which instructions run (off by
+    /// default). Enabling it starts with none covered.
+    pub fn set_coverage(&mut self, enabled: bool) {
+        self.coverage = enabled.then(|| Box::new(Coverage::new(&self.program)));
+    }
+
+    /// The instructions executed since coverage was enabled.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_deref()
+    }
+
+    /// Enables or disables checking that each opcode is given values of the
+    /// kinds it works on (off by default), faulting with `TypeMismatch`
+    /// when it is not, such as for an `Add` of a float or a `Concat` of an
+    /// integer. Kinds are known for what opcodes compute and carried
+    /// through memory; literal operands, the addresses of arrays and maps,
+    /// and values already on the stack when it is enabled are accepted
+    /// anywhere.
+    pub fn set_type_checking(&mut self, enabled: bool) {
+        self.types = enabled.then(|| Box::new(TypeTracker::new(self.stack.len())));
+    }
+
+    /// Replaces the input source (stdin by default) used by the Read opcode.
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        self.input = Box::new(input);
+    }
+
+    /// Replaces where the Print and Write opcodes send their text (stdout
+    /// by default).
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// Sends the value of each Print to `handler` instead of writing it to
+    /// the output, so a host can collect program output as values. Other
+    /// output, such as strings and characters, still goes to the output.
+    pub fn set_print_handler(&mut self, handler: impl FnMut(i64) + 'static) {
+        self.print_handler = Some(Box::new(handler));
+    }
+
+    /// Goes back to writing the values of Print to the output.
+    pub fn clear_print_handler(&mut self) {
+        self.print_handler = None;
+    }
+
+    /// Makes `function` callable from programs that declare
+    /// `extern fn name(...)` with `arity` parameters.
+    pub fn register_host_function(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl FnMut(&[i64]) -> Result<i64, VMError> + 'static,
+    ) {
+        self.host_functions
+            .push((name.to_string(), arity, Box::new(function)));
+    }
+
+    /// Lets programs send to and receive from `channel` as channel `id`,
+    /// replacing any channel attached as `id` before.
+    pub fn attach_channel(&mut self, id: i64, channel: Channel) {
+        self.channels.insert(id, channel);
+    }
+
+    /// Detaches the channel attached as `id`, returning it.
+    pub fn detach_channel(&mut self, id: i64) -> Option<Channel> {
+        self.channels.remove(&id)
+    }
+
+    fn channel(&self, id: i64) -> Result<&Channel, VMError> {
+        self.channels.get(&id).ok_or(VMError::UnknownChannel(id))
+    }
+
+    fn read_input(&mut self) -> Result<i64, VMError> {
+        let mut line = String::new();
+        let bytes = self
+            .input
+            .read_line(&mut line)
+            .map_err(|e| VMError::InvalidInput(e.to_string()))?;
+        if bytes == 0 {
+            return Err(VMError::InputExhausted);
         }
+        let line = line.trim();
+        line.parse()
+            .map_err(|_| VMError::InvalidInput(line.to_string()))
+    }
+
+    fn write(&mut self, text: fmt::Arguments) -> Result<(), VMError> {
+        self.output
+            .write_fmt(text)
+            .map_err(|e| VMError::OutputFailed(e.to_string()))
     }
 
     fn push(&mut self, value: i64) -> Result<(), VMError> {
-        if self.stack.len() >= self.stack_limit {
+        if !self.verified && self.stack.len() >= self.stack_limit {
             return Err(VMError::StackOverflow);
         }
         self.stack.push(value);
@@ -102,6 +679,27 @@ impl VM {
         self.stack.pop().ok_or(VMError::StackUnderflow)
     }
 
+    /// Floats live on the stack as their IEEE-754 bit pattern.
+    fn push_f64(&mut self, value: f64) -> Result<(), VMError> {
+        self.push(value.to_bits() as i64)
+    }
+
+    fn pop_f64(&mut self) -> Result<f64, VMError> {
+        Ok(f64::from_bits(self.pop()? as u64))
+    }
+
+    fn float_binary(&mut self, op: impl FnOnce(f64, f64) -> f64) -> Result<(), VMError> {
+        let b = self.pop_f64()?;
+        let a = self.pop_f64()?;
+        self.push_f64(op(a, b))
+    }
+
+    fn float_compare(&mut self, op: impl FnOnce(f64, f64) -> bool) -> Result<(), VMError> {
+        let b = self.pop_f64()?;
+        let a = self.pop_f64()?;
+        self.push(op(a, b) as i64)
+    }
+
     fn fetch(&mut self) -> Option<u8> {
         if self.pc < self.program.len() {
             let opcode = self.program[self.pc];
@@ -112,40 +710,365 @@ impl VM {
         }
     }
 
-    fn fetch_i64(&mut self) -> Option<i64> {
-        if self.pc + 8 <= self.program.len() {
-            let bytes = &self.program[self.pc..self.pc + 8];
-            self.pc += 8;
-            Some(i64::from_le_bytes(bytes.try_into().unwrap()))
-        } else {
-            None
+    /// Reads the inline operand of the instruction being executed.
+    fn fetch_operand(&mut self, opcode: Opcode) -> Option<i64> {
+        let (value, len) =
+            bytecode::read_operand(opcode, self.program.get(self.pc..)?, self.encoding)?;
+        self.pc += len;
+        value
+    }
+
+    /// Reads entry `index` of the constant pool: a count followed by that
+    /// many 8-byte values.
+    fn read_constant(&self, index: usize) -> Result<i64, VMError> {
+        let invalid = || VMError::InvalidConstant(index);
+        let read = |offset: usize| {
+            self.program
+                .get(offset..offset.checked_add(8)?)
+                .map(|bytes| i64::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let pool = self.const_pool.ok_or_else(invalid)?;
+        let count = read(pool).ok_or_else(invalid)?;
+        if usize::try_from(count).map_or(true, |count| index >= count) {
+            return Err(invalid());
+        }
+        // The values follow the count
+        index
+            .checked_add(1)
+            .and_then(|entry| entry.checked_mul(8))
+            .and_then(|offset| pool.checked_add(offset))
+            .and_then(read)
+            .ok_or_else(invalid)
+    }
+
+    /// Reserves `cells` consecutive heap cells and returns the first address.
+    fn alloc(&mut self, cells: usize) -> Result<usize, VMError> {
+        let addr = self.heap_next;
+        self.heap_next = addr.checked_add(cells).ok_or(VMError::OutOfMemory(addr))?;
+        Ok(addr)
+    }
+
+    /// Allocates an empty map. A map is a `[count][capacity][table]` header,
+    /// so it keeps its address when it grows, pointing at an open-addressing
+    /// table of `[occupied][key][value]` slots probed linearly.
+    fn new_map(&mut self) -> Result<usize, VMError> {
+        let map = self.alloc(3)?;
+        let table = self.alloc(MAP_INITIAL_CAPACITY * 3)?;
+        self.memory.insert(map, 0);
+        self.memory.insert(map + 1, MAP_INITIAL_CAPACITY as i64);
+        self.memory.insert(map + 2, table as i64);
+        Ok(map)
+    }
+
+    /// Finds the table slot holding `key`, or the empty slot where it would
+    /// be inserted, and whether the key is present. Fails for an address
+    /// whose header or table does not fit in memory.
+    fn map_slot(&self, map: usize, key: i64) -> Result<(usize, bool), VMError> {
+        let invalid = || VMError::InvalidArgument("map", map as i64);
+        let header_end = map.checked_add(2).ok_or_else(invalid)?;
+        let capacity = *self.memory.get(&(header_end - 1)).unwrap_or(&0);
+        let capacity = usize::try_from(capacity)
+            .ok()
+            .filter(|capacity| *capacity > 0)
+            .ok_or_else(invalid)?;
+        let table = *self.memory.get(&header_end).unwrap_or(&0);
+        let table = usize::try_from(table).map_err(|_| invalid())?;
+        capacity
+            .checked_mul(3)
+            .and_then(|cells| table.checked_add(cells))
+            .ok_or_else(invalid)?;
+        let hash = (key as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32;
+        let mut index = hash as usize % capacity;
+        loop {
+            let slot = table + index * 3;
+            if self.memory.get(&slot).copied().unwrap_or(0) == 0 {
+                return Ok((slot, false));
+            }
+            if self.memory.get(&(slot + 1)) == Some(&key) {
+                return Ok((slot, true));
+            }
+            index = (index + 1) % capacity;
+        }
+    }
+
+    fn map_insert(&mut self, map: usize, key: i64, value: i64) -> Result<(), VMError> {
+        let (mut slot, found) = self.map_slot(map, key)?;
+        if !found {
+            let count = *self.memory.get(&map).unwrap_or(&0);
+            let capacity = *self.memory.get(&(map + 1)).unwrap_or(&0);
+            if count.saturating_add(1).saturating_mul(4) > capacity.saturating_mul(3) {
+                self.grow_map(map, capacity as usize * 2)?;
+                slot = self.map_slot(map, key)?.0;
+            }
+            self.memory.insert(map, count + 1);
+            self.memory.insert(slot, 1);
+            self.memory.insert(slot + 1, key);
+        }
+        self.memory.insert(slot + 2, value);
+        Ok(())
+    }
+
+    fn grow_map(&mut self, map: usize, capacity: usize) -> Result<(), VMError> {
+        let old_capacity = *self.memory.get(&(map + 1)).unwrap_or(&0) as usize;
+        let old_table = *self.memory.get(&(map + 2)).unwrap_or(&0) as usize;
+        let cells = capacity
+            .checked_mul(3)
+            .ok_or(VMError::InvalidArgument("map", map as i64))?;
+        let table = self.alloc(cells)?;
+        self.memory.insert(map + 1, capacity as i64);
+        self.memory.insert(map + 2, table as i64);
+        for index in 0..old_capacity {
+            let old_slot = old_table + index * 3;
+            if self.memory.get(&old_slot).copied().unwrap_or(0) == 0 {
+                continue;
+            }
+            let key = *self.memory.get(&(old_slot + 1)).unwrap_or(&0);
+            let value = *self.memory.get(&(old_slot + 2)).unwrap_or(&0);
+            let (slot, _) = self.map_slot(map, key)?;
+            self.memory.insert(slot, 1);
+            self.memory.insert(slot + 1, key);
+            self.memory.insert(slot + 2, value);
+        }
+        Ok(())
+    }
+
+    /// Reads a `[len: i64][utf-8 bytes]` string constant from the program.
+    fn read_str_constant(&self, offset: usize) -> Result<&str, VMError> {
+        let start = offset.checked_add(8).ok_or(VMError::OutOfMemory(offset))?;
+        let len_bytes = self
+            .program
+            .get(offset..start)
+            .ok_or(VMError::OutOfMemory(offset))?;
+        let len = usize::try_from(i64::from_le_bytes(len_bytes.try_into().unwrap()))
+            .map_err(|_| VMError::InvalidString(offset))?;
+        let end = start
+            .checked_add(len)
+            .ok_or(VMError::InvalidString(offset))?;
+        let bytes = self
+            .program
+            .get(start..end)
+            .ok_or(VMError::OutOfMemory(offset))?;
+        std::str::from_utf8(bytes).map_err(|_| VMError::InvalidString(offset))
+    }
+
+    /// The address of element `index` of the string or array at `base`,
+    /// which must be within its length and the address space.
+    fn element_addr(&self, base: usize, index: i64) -> Result<usize, VMError> {
+        let len = *self.memory.get(&base).unwrap_or(&0);
+        let out_of_bounds = VMError::IndexOutOfBounds { index, len };
+        if index < 0 || index >= len {
+            return Err(out_of_bounds);
         }
+        base.checked_add(1)
+            .and_then(|start| start.checked_add(index as usize))
+            .ok_or(out_of_bounds)
+    }
+
+    /// The length of the string at `addr`, which must be a number of cells
+    /// that ends within the memory allocated so far.
+    fn str_len(&self, addr: usize) -> Result<usize, VMError> {
+        let len = *self.memory.get(&addr).unwrap_or(&0);
+        usize::try_from(len)
+            .ok()
+            .filter(|&len| {
+                addr.checked_add(1)
+                    .and_then(|start| start.checked_add(len))
+                    .is_some_and(|end| end <= self.heap_next)
+            })
+            .ok_or(VMError::InvalidString(addr))
+    }
+
+    /// Reads a length-prefixed string (one character per cell) from memory.
+    fn read_str(&self, addr: usize) -> Result<String, VMError> {
+        (0..self.str_len(addr)?)
+            .map(|i| {
+                let code = *self.memory.get(&(addr + 1 + i)).unwrap_or(&0);
+                char::from_u32(code as u32).ok_or(VMError::InvalidString(addr))
+            })
+            .collect()
     }
 
+    /// Executes the instruction at the program counter, returning whether
+    /// the program goes on.
     pub fn execute_next(&mut self) -> Result<bool, VMError> {
+        let result = self.execute_instruction();
+        if result.is_ok() {
+            self.metrics.retire(self.stack.len(), self.memory.len());
+        }
+        #[cfg(feature = "tracing")]
+        if let Err(error) = &result {
+            tracing::warn!(pc = self.instruction_pc, %error, "fault");
+        }
+        result
+    }
+
+    fn execute_instruction(&mut self) -> Result<bool, VMError> {
+        self.instruction_pc = self.pc;
         let opcode = self.fetch().ok_or(VMError::InvalidOpcode(0))?;
-        match Opcode::try_from(opcode)? {
+        let decoded = Opcode::try_from(opcode)?;
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            pc = self.instruction_pc,
+            opcode = ?decoded,
+            stack_depth = self.stack.len(),
+            "execute"
+        );
+        self.metrics.execute(decoded);
+        if let Some(profile) = &mut self.profile {
+            profile.record(decoded);
+        }
+        if let Some(hotspots) = &mut self.hotspots {
+            hotspots.record(self.instruction_pc, self.call_stack.len());
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(self.instruction_pc);
+        }
+        let Some(mut types) = self.types.take() else {
+            return self.execute_opcode(decoded, opcode);
+        };
+        // Values pushed from outside an instruction are of unknown kind
+        types.stack.resize(self.stack.len(), None);
+        let result = types.check(self, decoded).and_then(|pending| {
+            let result = self.execute_opcode(decoded, opcode);
+            types.update(self, decoded, pending, result.is_ok());
+            result
+        });
+        self.types = Some(types);
+        result
+    }
+
+    /// Executes an instruction whose opcode was fetched as `opcode`.
+    fn execute_opcode(&mut self, decoded: Opcode, opcode: u8) -> Result<bool, VMError> {
+        match decoded {
             Opcode::Push => {
-                let value = self.fetch_i64().ok_or(VMError::InvalidOpcode(opcode))?;
+                let value = self
+                    .fetch_operand(Opcode::Push)
+                    .ok_or(VMError::InvalidOpcode(opcode))?;
+                self.push(value)?;
+            }
+            Opcode::PushConst => {
+                let index = self
+                    .fetch_operand(Opcode::PushConst)
+                    .ok_or(VMError::InvalidOpcode(opcode))?;
+                let value = self.read_constant(index as usize)?;
                 self.push(value)?;
             }
+            Opcode::ConstPool => {
+                let offset = self.pop()? as usize;
+                self.const_pool = Some(offset);
+            }
             Opcode::Pop => {
                 self.pop()?;
             }
+            Opcode::Dup => {
+                let value = *self.stack.last().ok_or(VMError::StackUnderflow)?;
+                self.push(value)?;
+            }
+            Opcode::Assert => {
+                if self.pop()? == 0 {
+                    return Err(VMError::AssertionFailed { pc: self.pc - 1 });
+                }
+            }
+            Opcode::Read => {
+                let value = self.read_input()?;
+                self.push(value)?;
+            }
+            Opcode::Neg => {
+                let value = self.pop()?;
+                self.push(value.wrapping_neg())?;
+            }
+            Opcode::Abs => {
+                let value = self.pop()?;
+                self.push(value.wrapping_abs())?;
+            }
+            Opcode::Min => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a.min(b))?;
+            }
+            Opcode::Max => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a.max(b))?;
+            }
+            Opcode::Pow => {
+                let exponent = self.pop()?;
+                let base = self.pop()?;
+                let exponent = u32::try_from(exponent)
+                    .map_err(|_| VMError::InvalidArgument("pow", exponent))?;
+                self.push(base.wrapping_pow(exponent))?;
+            }
+            Opcode::SqrtInt => {
+                let value = self.pop()?;
+                if value < 0 {
+                    return Err(VMError::InvalidArgument("sqrt_int", value));
+                }
+                self.push(value.isqrt())?;
+            }
+            Opcode::FAdd => self.float_binary(|a, b| a + b)?,
+            Opcode::FSub => self.float_binary(|a, b| a - b)?,
+            Opcode::FMul => self.float_binary(|a, b| a * b)?,
+            Opcode::FDiv => self.float_binary(|a, b| a / b)?,
+            Opcode::FMod => self.float_binary(|a, b| a % b)?,
+            Opcode::FNeg => {
+                let value = self.pop_f64()?;
+                self.push_f64(-value)?;
+            }
+            Opcode::FEqual => self.float_compare(|a, b| a == b)?,
+            Opcode::FNotEqual => self.float_compare(|a, b| a != b)?,
+            Opcode::FLess => self.float_compare(|a, b| a < b)?,
+            Opcode::FLessEqual => self.float_compare(|a, b| a <= b)?,
+            Opcode::FGreater => self.float_compare(|a, b| a > b)?,
+            Opcode::FGreaterEqual => self.float_compare(|a, b| a >= b)?,
+            Opcode::IntToFloat => {
+                let value = self.pop()?;
+                self.push_f64(value as f64)?;
+            }
+            Opcode::FloatToInt => {
+                // Truncates toward zero, saturating at the i64 range; NaN becomes 0
+                let value = self.pop_f64()?;
+                self.push(value as i64)?;
+            }
+            Opcode::PrintFloat => {
+                let value = self.pop_f64()?;
+                self.write(format_args!("Output: {}\n", value))?;
+            }
+            // The Write opcodes print a value as is, with no prefix or newline
+            Opcode::WriteInt => {
+                let value = self.pop()?;
+                self.write(format_args!("{}", value))?;
+            }
+            Opcode::WriteFloat => {
+                let value = self.pop_f64()?;
+                self.write(format_args!("{}", value))?;
+            }
+            Opcode::WriteStr => {
+                let addr = self.pop()? as usize;
+                let text = self.read_str(addr)?;
+                self.write(format_args!("{}", text))?;
+            }
+            Opcode::Inc => {
+                let value = self.pop()?;
+                self.push(value.wrapping_add(1))?;
+            }
+            Opcode::Dec => {
+                let value = self.pop()?;
+                self.push(value.wrapping_sub(1))?;
+            }
             Opcode::Add => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                self.push(a + b)?;
+                self.push(a.wrapping_add(b))?;
             }
             Opcode::Sub => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                self.push(a - b)?;
+                self.push(a.wrapping_sub(b))?;
             }
             Opcode::Mul => {
                 let b = self.pop()?;
                 let a = self.pop()?;
-                self.push(a * b)?;
+                self.push(a.wrapping_mul(b))?;
             }
             Opcode::Div => {
                 let b = self.pop()?;
@@ -153,7 +1076,26 @@ impl VM {
                 if b == 0 {
                     return Err(VMError::DivisionByZero);
                 }
-                self.push(a / b)?;
+                self.push(a.wrapping_div(b))?;
+            }
+            Opcode::Mod => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                if b == 0 {
+                    return Err(VMError::DivisionByZero);
+                }
+                self.push(a.wrapping_rem(b))?;
+            }
+            Opcode::Shl => {
+                let amount = shift_amount(self.pop()?)?;
+                let value = self.pop()?;
+                self.push(value << amount)?;
+            }
+            // An arithmetic shift, rounding toward negative infinity
+            Opcode::Shr => {
+                let amount = shift_amount(self.pop()?)?;
+                let value = self.pop()?;
+                self.push(value >> amount)?;
             }
             Opcode::Load => {
                 let addr = self.pop()? as usize;
@@ -172,6 +1114,139 @@ impl VM {
                 }
                 self.pc = addr;
             }
+            Opcode::Call => {
+                let addr = self.pop()? as usize;
+                self.call(addr)?;
+            }
+            Opcode::CallClosure => {
+                // A closure is a record of its code address and captured values
+                let closure = self.pop()?;
+                let code = usize::try_from(closure)
+                    .ok()
+                    .and_then(|closure| closure.checked_add(1))
+                    .ok_or(VMError::InvalidArgument("CallClosure", closure))?;
+                let addr = *self.memory.get(&code).unwrap_or(&0) as usize;
+                // The callee receives the record as its environment pointer
+                self.push(closure)?;
+                self.call(addr)?;
+            }
+            Opcode::Ret => {
+                let frame = self.call_stack.pop().ok_or(VMError::StackUnderflow)?;
+                self.pc = frame.return_addr;
+                self.fp = frame.fp;
+                self.frame_top = frame.frame_top;
+                // Returning from inside a `try` leaves its handler behind
+                while self
+                    .handlers
+                    .last()
+                    .is_some_and(|handler| handler.call_depth > self.call_stack.len())
+                {
+                    self.handlers.pop();
+                }
+            }
+            Opcode::PushHandler => {
+                let addr = self.pop()? as usize;
+                self.handlers.push(Handler {
+                    addr,
+                    stack_len: self.stack.len(),
+                    call_depth: self.call_stack.len(),
+                    fp: self.fp,
+                    frame_top: self.frame_top,
+                });
+            }
+            Opcode::PopHandler => {
+                self.handlers.pop().ok_or(VMError::StackUnderflow)?;
+            }
+            Opcode::Throw => {
+                let value = self.pop()?;
+                let handler = self
+                    .handlers
+                    .pop()
+                    .ok_or(VMError::UncaughtException(value))?;
+                self.stack.truncate(handler.stack_len);
+                self.call_stack.truncate(handler.call_depth);
+                self.fp = handler.fp;
+                self.frame_top = handler.frame_top;
+                self.push(value)?;
+                self.pc = handler.addr;
+            }
+            Opcode::Enter => {
+                let size = self.pop()?;
+                let size =
+                    usize::try_from(size).map_err(|_| VMError::InvalidArgument("Enter", size))?;
+                if size > HEAP_BASE - self.frame_top {
+                    return Err(VMError::StackOverflow);
+                }
+                self.fp = self.frame_top;
+                self.frame_top += size;
+                for addr in self.fp..self.frame_top {
+                    self.memory.insert(addr, 0);
+                }
+            }
+            Opcode::LoadLocal => {
+                let slot = self.pop()? as usize;
+                let value = *self.memory.get(&self.fp.wrapping_add(slot)).unwrap_or(&0);
+                self.push(value)?;
+            }
+            Opcode::StoreLocal => {
+                let slot = self.pop()? as usize;
+                let value = self.pop()?;
+                self.memory.insert(self.fp.wrapping_add(slot), value);
+            }
+            Opcode::BindHost => {
+                let declared = self.pop()? as usize;
+                let offset = self.pop()? as usize;
+                let name = self.read_str_constant(offset)?.to_string();
+                let index = self
+                    .host_functions
+                    .iter()
+                    .rposition(|(registered, _, _)| *registered == name)
+                    .ok_or_else(|| VMError::UnknownHostFunction(name.clone()))?;
+                let registered = self.host_functions[index].1;
+                if registered != declared {
+                    return Err(VMError::HostArityMismatch {
+                        name,
+                        declared,
+                        registered,
+                    });
+                }
+                self.host_bindings.push(index);
+            }
+            Opcode::CallHost => {
+                let binding = self.pop()?;
+                let index = *usize::try_from(binding)
+                    .ok()
+                    .and_then(|binding| self.host_bindings.get(binding))
+                    .ok_or(VMError::InvalidArgument("CallHost", binding))?;
+                let arity = self.host_functions[index].1;
+                if arity > self.stack.len() {
+                    return Err(VMError::StackUnderflow);
+                }
+                let args = self.stack.split_off(self.stack.len() - arity);
+                let result = (self.host_functions[index].2)(&args)?;
+                #[cfg(feature = "tracing")]
+                tracing::debug!(
+                    name = %self.host_functions[index].0,
+                    ?args,
+                    result,
+                    "host call"
+                );
+                self.push(result)?;
+            }
+            Opcode::Send => {
+                let value = self.pop()?;
+                let id = self.pop()?;
+                self.channel(id)?.send(value);
+            }
+            Opcode::Recv => {
+                let id = *self.stack.last().ok_or(VMError::StackUnderflow)?;
+                let Some(value) = self.channel(id)?.recv() else {
+                    self.pc = self.instruction_pc;
+                    return Err(VMError::ChannelEmpty(id));
+                };
+                self.pop()?;
+                self.push(value)?;
+            }
             Opcode::JumpIf => {
                 let addr = self.pop()? as usize;
                 let condition = self.pop()?;
@@ -194,12 +1269,28 @@ impl VM {
             }
             Opcode::Print => {
                 let value = self.pop()?;
-                println!("Output: {}", value);
+                match &mut self.print_handler {
+                    Some(handler) => handler(value),
+                    None => self.write(format_args!("Output: {}\n", value))?,
+                }
+            }
+            Opcode::PrintChar => {
+                let value = self.pop()?;
+                let ch = u32::try_from(value)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(VMError::InvalidArgument("PrintChar", value))?;
+                self.write(format_args!("{}", ch))?;
             }
             Opcode::Halt => {
                 self.running = false;
                 return Ok(false);
             }
+            Opcode::Exit => {
+                self.exit_code = self.pop()?;
+                self.running = false;
+                return Ok(false);
+            }
             Opcode::LessEqual => {
                 let b = self.pop()?;
                 let a = self.pop()?;
@@ -210,59 +1301,355 @@ impl VM {
                 let a = self.pop()?;
                 self.push(if a >= b { 1 } else { 0 })?;
             }
+            Opcode::NotEqual => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(if a != b { 1 } else { 0 })?;
+            }
+            Opcode::Greater => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(if a > b { 1 } else { 0 })?;
+            }
+            Opcode::LoadStr => {
+                let offset = self.pop()? as usize;
+                let chars: Vec<char> = self.read_str_constant(offset)?.chars().collect();
+                let addr = self.alloc(chars.len() + 1)?;
+                self.memory.insert(addr, chars.len() as i64);
+                for (i, ch) in chars.into_iter().enumerate() {
+                    self.memory.insert(addr + 1 + i, ch as i64);
+                }
+                self.push(addr as i64)?;
+            }
+            Opcode::PrintStr => {
+                let addr = self.pop()? as usize;
+                let text = self.read_str(addr)?;
+                self.write(format_args!("{}\n", text))?;
+            }
+            Opcode::Concat => {
+                let b = self.pop()? as usize;
+                let a = self.pop()? as usize;
+                let len_a = self.str_len(a)?;
+                let len_b = self.str_len(b)?;
+                let addr = self.alloc(len_a + len_b + 1)?;
+                self.memory.insert(addr, (len_a + len_b) as i64);
+                for i in 0..len_a {
+                    let cell = *self.memory.get(&(a + 1 + i)).unwrap_or(&0);
+                    self.memory.insert(addr + 1 + i, cell);
+                }
+                for i in 0..len_b {
+                    let cell = *self.memory.get(&(b + 1 + i)).unwrap_or(&0);
+                    self.memory.insert(addr + 1 + len_a + i, cell);
+                }
+                self.push(addr as i64)?;
+            }
+            Opcode::Index => {
+                let index = self.pop()?;
+                let base = self.pop()? as usize;
+                let addr = self.element_addr(base, index)?;
+                let value = *self.memory.get(&addr).unwrap_or(&0);
+                self.push(value)?;
+            }
+            Opcode::NewArray => {
+                let count = self.pop()?;
+                if count < 0 || count as usize > self.stack.len() {
+                    return Err(VMError::StackUnderflow);
+                }
+                let count = count as usize;
+                let elements = self.stack.split_off(self.stack.len() - count);
+                let addr = self.alloc(count + 1)?;
+                self.memory.insert(addr, count as i64);
+                for (i, element) in elements.into_iter().enumerate() {
+                    self.memory.insert(addr + 1 + i, element);
+                }
+                self.push(addr as i64)?;
+            }
+            Opcode::NewMap => {
+                let map = self.new_map()?;
+                self.push(map as i64)?;
+            }
+            Opcode::MapGet => {
+                let key = self.pop()?;
+                let map = self.pop()? as usize;
+                let (slot, found) = self.map_slot(map, key)?;
+                if !found {
+                    return Err(VMError::KeyNotFound(key));
+                }
+                self.push(*self.memory.get(&(slot + 2)).unwrap_or(&0))?;
+            }
+            Opcode::MapSet => {
+                let value = self.pop()?;
+                let key = self.pop()?;
+                let map = self.pop()? as usize;
+                self.map_insert(map, key, value)?;
+            }
+            Opcode::MapHas => {
+                let key = self.pop()?;
+                let map = self.pop()? as usize;
+                let (_, found) = self.map_slot(map, key)?;
+                self.push(found as i64)?;
+            }
+            Opcode::SetIndex => {
+                let value = self.pop()?;
+                let index = self.pop()?;
+                let base = self.pop()? as usize;
+                let addr = self.element_addr(base, index)?;
+                self.memory.insert(addr, value);
+            }
         }
         Ok(true)
     }
 
+    fn call(&mut self, addr: usize) -> Result<(), VMError> {
+        if addr >= self.program.len() {
+            return Err(VMError::OutOfMemory(addr));
+        }
+        if self.call_stack.len() >= self.stack_limit {
+            return Err(VMError::StackOverflow);
+        }
+        self.call_stack.push(CallFrame {
+            return_addr: self.pc,
+            fp: self.fp,
+            frame_top: self.frame_top,
+        });
+        self.pc = addr;
+        Ok(())
+    }
+
     pub fn run(&mut self) -> Result<(), VMError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("run", pc = self.pc).entered();
         self.running = true;
-        while self.running {
-            if !self.execute_next()? {
-                break;
+        self.timed(|vm| {
+            while vm.running {
+                if !vm.execute_next()? {
+                    break;
+                }
             }
-        }
-        Ok(())
+            Ok(())
+        })
     }
 
-    pub fn get_stack(&self) -> &[i64] {
-        &self.stack
+    /// Runs at most `instructions` instructions, returning whether the
+    /// program goes on, so a host can run it in slices and do other work in
+    /// between.
+    pub fn run_for(&mut self, instructions: u64) -> Result<bool, VMError> {
+        self.running = true;
+        self.timed(|vm| {
+            for _ in 0..instructions {
+                if !vm.running || !vm.execute_next()? {
+                    return Ok(false);
+                }
+            }
+            Ok(vm.running)
+        })
     }
 
-    pub fn get_memory(&self) -> &HashMap<usize, i64> {
-        &self.memory
+    /// Runs until the next instruction to execute is at a breakpoint, or the
+    /// program ends. The instruction at the current address runs even if it
+    /// has a breakpoint, so a VM stopped at one carries on past it.
+    pub fn resume(&mut self) -> Result<StopReason, VMError> {
+        self.running = true;
+        self.timed(|vm| loop {
+            if !vm.execute_next()? {
+                return Ok(StopReason::Halted);
+            }
+            if vm.breakpoints.contains(&vm.pc) {
+                return Ok(StopReason::Breakpoint(vm.pc));
+            }
+        })
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::compiler::{parser::Parser, Compiler};
+    /// Executes one instruction, stepping into the function it calls if it
+    /// is a call.
+    pub fn step(&mut self) -> Result<StopReason, VMError> {
+        self.running = true;
+        if self.timed(Self::execute_next)? {
+            Ok(StopReason::Stepped)
+        } else {
+            Ok(StopReason::Halted)
+        }
+    }
+
+    /// Executes one instruction, running a call it makes to completion.
+    /// Breakpoints in the called function stop it early.
+    pub fn step_over(&mut self) -> Result<StopReason, VMError> {
+        let depth = self.call_stack.len();
+        let reason = self.step()?;
+        if self.call_stack.len() > depth {
+            self.run_to_depth(depth)
+        } else {
+            Ok(reason)
+        }
+    }
+
+    /// Runs until the current call returns, stopping at the instruction it
+    /// returns to, or at a breakpoint before that. Outside any call, runs
+    /// like `resume`.
+    pub fn step_out(&mut self) -> Result<StopReason, VMError> {
+        match self.call_stack.len() {
+            0 => self.resume(),
+            depth => self.run_to_depth(depth - 1),
+        }
+    }
+
+    /// Runs until only `depth` calls are active. Rather than a breakpoint on
+    /// the return address, this watches the call depth, which also stops
+    /// when a `throw` unwinds the calls to a handler further out.
+    fn run_to_depth(&mut self, depth: usize) -> Result<StopReason, VMError> {
+        self.running = true;
+        self.timed(|vm| loop {
+            if !vm.execute_next()? {
+                return Ok(StopReason::Halted);
+            }
+            if vm.call_stack.len() <= depth {
+                return Ok(StopReason::Stepped);
+            }
+            if vm.breakpoints.contains(&vm.pc) {
+                return Ok(StopReason::Breakpoint(vm.pc));
+            }
+        })
+    }
+
+    /// Runs `execute`, adding the time it takes to the metrics.
+    fn timed<T>(&mut self, execute: impl FnOnce(&mut Self) -> T) -> T {
+        let start = Instant::now();
+        let result = execute(self);
+        self.metrics.elapsed += start.elapsed();
+        result
+    }
+
+    /// Makes `resume` stop before executing the instruction at `pc`.
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Removes the breakpoint at `pc`, returning whether there was one.
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    /// The addresses with a breakpoint, in order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// The return address of every active call, innermost last.
+    pub fn call_stack(&self) -> impl DoubleEndedIterator<Item = usize> + '_ {
+        self.call_stack.iter().map(|frame| frame.return_addr)
+    }
+
+    /// The program being run.
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    /// Address of the next instruction to execute.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The source map set with `set_debug_info`.
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    pub fn get_stack(&self) -> &[i64] {
+        &self.stack
+    }
+
+    /// The operand stack, bottom first, as values of the kinds the VM knows
+    /// them by with type checking enabled, and otherwise as integers.
+    pub fn stack_values(&self) -> Vec<Value> {
+        let kinds = self.types.as_ref().map(|types| &types.stack);
+        self.stack
+            .iter()
+            .enumerate()
+            .map(
+                |(i, &value)| match kinds.and_then(|kinds| kinds.get(i).copied().flatten()) {
+                    Some(Kind::Float) => Value::Float(f64::from_bits(value as u64)),
+                    Some(Kind::Bool) => Value::Bool(value != 0),
+                    Some(Kind::Str) => self
+                        .read_str(value as usize)
+                        .map_or(Value::Int(value), Value::Str),
+                    Some(Kind::Nil) => Value::Nil,
+                    Some(Kind::Int) | None => Value::Int(value),
+                },
+            )
+            .collect()
+    }
+
+    pub fn get_memory(&self) -> &HashMap<usize, i64> {
+        &self.memory
+    }
+
+    /// A copy of the operand stack, bottom first.
+    pub fn dump_stack(&self) -> Vec<i64> {
+        self.stack.clone()
+    }
+
+    /// The `len` memory cells from `start`, with 0 for cells never written.
+    pub fn dump_memory_range(&self, start: usize, len: usize) -> Vec<i64> {
+        (start..start.saturating_add(len))
+            .map(|addr| self.memory.get(&addr).copied().unwrap_or(0))
+            .collect()
+    }
+
+    /// Maps the memory cells in `range` as one mutable slice, with 0 for
+    /// cells never written, so a host can fill a program's input area before
+    /// `run` or read its results afterwards in bulk.
+    pub fn memory_slice(&mut self, range: Range<usize>) -> MemorySlice<'_> {
+        let cells = self.dump_memory_range(range.start, range.len());
+        MemorySlice {
+            memory: &mut self.memory,
+            start: range.start,
+            original: cells.clone(),
+            cells,
+        }
+    }
+
+    /// The program's top-level variables as name, address and value, in
+    /// declaration order. Needs debug info.
+    pub fn dump_globals(&self) -> Vec<(&str, usize, i64)> {
+        let Some(debug_info) = &self.debug_info else {
+            return Vec::new();
+        };
+        debug_info
+            .globals()
+            .iter()
+            .map(|(addr, name)| {
+                let value = self.memory.get(addr).copied().unwrap_or(0);
+                (name.as_str(), *addr, value)
+            })
+            .collect()
+    }
+
+    /// Source location of the instruction executed last, which is the one
+    /// that failed once `run` has returned an error. Needs debug info.
+    pub fn current_span(&self) -> Option<Span> {
+        self.debug_info.as_ref()?.span_at(self.instruction_pc)
+    }
+
+    /// The code the program passed to `exit`, or 0 if it ran to the end.
+    pub fn get_exit_code(&self) -> i64 {
+        self.exit_code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::OperandEncoding;
+    use crate::compiler::{
+        parser::{ExprKind, Parser, Statement, StatementKind},
+        BuildError, CompileError, Compiler, LexError, LinkError, Linker, OptLevel, ParseError,
+        Span, Target,
+    };
 
     use super::*;
 
     #[test]
     fn test_push_pop() {
-        let program = vec![
-            Opcode::Push as u8,
-            42,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0, // Push 42
-            Opcode::Push as u8,
-            123,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0, // Push 123
-            Opcode::Pop as u8,
-            Opcode::Halt as u8,
-        ];
+        let program = crate::bytecode![push 42, push 123, pop, halt];
 
         let mut vm = VM::new(program, 100);
         vm.run().unwrap();
@@ -272,38 +1659,7 @@ mod tests {
 
     #[test]
     fn test_arithmetic() {
-        let program = vec![
-            Opcode::Push as u8,
-            10,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0, // Push 10
-            Opcode::Push as u8,
-            5,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,                 // Push 5
-            Opcode::Add as u8, // 10 + 5
-            Opcode::Push as u8,
-            2,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,                 // Push 2
-            Opcode::Mul as u8, // (10 + 5) * 2
-            Opcode::Halt as u8,
-        ];
+        let program = crate::bytecode![push 10, push 5, add, push 2, mul, halt];
 
         let mut vm = VM::new(program, 100);
         vm.run().unwrap();
@@ -312,60 +1668,35 @@ mod tests {
     }
 
     #[test]
-    fn test_less_equal_comparison() {
-        let program = vec![
-            Opcode::Push as u8,
-            5,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            Opcode::Push as u8,
-            5,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            Opcode::LessEqual as u8,
-            Opcode::Halt as u8,
+    fn test_arithmetic_wraps_on_overflow() {
+        let program = crate::bytecode![
+            push i64::MAX, inc,
+            push i64::MIN, push 1, sub,
+            push i64::MAX, push 2, mul,
+            push i64::MIN, push -1, div,
+            push i64::MIN, push -1, mod,
+            halt
         ];
 
         let mut vm = VM::new(program, 100);
         vm.run().unwrap();
 
+        assert_eq!(vm.get_stack(), &[i64::MIN, i64::MAX, -2, i64::MIN, 0]);
+    }
+
+    #[test]
+    fn test_less_equal_comparison() {
+        let program = crate::bytecode![push 5, push 5, lessequal, halt];
+
+        let mut vm = VM::new(program, 100);
+        vm.run().unwrap();
+
         assert_eq!(vm.get_stack(), &[1]);
     }
 
     #[test]
     fn test_greater_equal_comparison() {
-        let program = vec![
-            Opcode::Push as u8,
-            9,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            Opcode::Push as u8,
-            4,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            0,
-            Opcode::GreaterEqual as u8,
-            Opcode::Halt as u8,
-        ];
+        let program = crate::bytecode![push 9, push 4, greaterequal, halt];
 
         let mut vm = VM::new(program, 100);
         vm.run().unwrap();
@@ -380,7 +1711,7 @@ mod tests {
             let greater_equal = 9 >= 3;
         ";
         let statements = Parser::new(code).parse_program().unwrap();
-        let bytecode = Compiler::new().compile(statements);
+        let bytecode = Compiler::new().compile(statements).unwrap();
         let mut vm = VM::new(bytecode, 100);
 
         vm.run().unwrap();
@@ -388,4 +1719,2049 @@ mod tests {
         assert_eq!(vm.get_memory().get(&0), Some(&1));
         assert_eq!(vm.get_memory().get(&1), Some(&1));
     }
+
+    #[test]
+    fn test_compiled_not_equal_and_greater() {
+        let code = "
+            let a = 3;
+            let b = 7;
+            let ne = a != b;
+            let eq = a != 3;
+            let gt = b > a;
+            let ngt = a > b;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_memory().get(&2), Some(&1));
+        assert_eq!(vm.get_memory().get(&3), Some(&0));
+        assert_eq!(vm.get_memory().get(&4), Some(&1));
+        assert_eq!(vm.get_memory().get(&5), Some(&0));
+    }
+
+    #[test]
+    fn test_compiled_while_loop_with_braces() {
+        let code = "
+            let x = 5;
+            let sum = 0;
+            while x > 0 {
+                sum = sum + x;
+                x = x - 1;
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_memory().get(&0), Some(&0));
+        assert_eq!(vm.get_memory().get(&1), Some(&15));
+        assert!(vm.get_stack().is_empty());
+    }
+
+    #[test]
+    fn test_compiled_nested_if_else() {
+        let code = "
+            let i = 0;
+            let evens = 0;
+            let odds = 0;
+            while i < 10 {
+                if i % 2 == 0 {
+                    evens = evens + 1;
+                } else {
+                    if i > 5 {
+                        odds = odds + 10;
+                    } else {
+                        odds = odds + 1;
+                    }
+                }
+                i = i + 1;
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_memory().get(&1), Some(&5));
+        assert_eq!(vm.get_memory().get(&2), Some(&23));
+    }
+
+    #[test]
+    fn test_compiled_booleans_and_logical_operators() {
+        let code = "
+            let t = true;
+            let f = false;
+            let and = t && 5;
+            let or = f || 0;
+            let not = !f;
+            let mixed = 1 < 2 && !(3 == 4) || f;
+            let ran = 0;
+            while f {
+                ran = 1;
+            }
+            if t && !f {
+                ran = ran + 2;
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&0), Some(&1));
+        assert_eq!(memory.get(&1), Some(&0));
+        assert_eq!(memory.get(&2), Some(&1));
+        assert_eq!(memory.get(&3), Some(&0));
+        assert_eq!(memory.get(&4), Some(&1));
+        assert_eq!(memory.get(&5), Some(&1));
+        assert_eq!(memory.get(&6), Some(&2));
+    }
+
+    #[test]
+    fn test_logical_operators_short_circuit() {
+        let code = "
+            let a = false && 1 / 0;
+            let b = true || 1 / 0;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_memory().get(&0), Some(&0));
+        assert_eq!(vm.get_memory().get(&1), Some(&1));
+    }
+
+    #[test]
+    fn test_compiled_string_literals() {
+        let code = "
+            let greeting = \"héllo\";
+            let again = \"héllo\";
+            print greeting;
+            print \"done\";
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let addr = *vm.get_memory().get(&0).unwrap() as usize;
+        assert_eq!(vm.read_str(addr).unwrap(), "héllo");
+        assert_eq!(vm.get_memory().get(&addr), Some(&5));
+        let again = *vm.get_memory().get(&1).unwrap() as usize;
+        assert_ne!(addr, again);
+        assert_eq!(vm.read_str(again).unwrap(), "héllo");
+        assert!(vm.get_stack().is_empty());
+    }
+
+    #[test]
+    fn test_compiled_string_builtins() {
+        let code = "
+            let name = \"world\";
+            let greeting = \"hello, \" + name + \"!\";
+            let n = len(greeting);
+            let first = charAt(greeting, 0);
+            let last = charAt(greeting, n - 1);
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let greeting = *vm.get_memory().get(&1).unwrap() as usize;
+        assert_eq!(vm.read_str(greeting).unwrap(), "hello, world!");
+        assert_eq!(vm.get_memory().get(&2), Some(&13));
+        assert_eq!(vm.get_memory().get(&3), Some(&('h' as i64)));
+        assert_eq!(vm.get_memory().get(&4), Some(&('!' as i64)));
+    }
+
+    #[test]
+    fn test_char_at_out_of_bounds() {
+        let statements = Parser::new("let c = charAt(\"abc\", 3);")
+            .parse_program()
+            .unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        assert!(matches!(
+            vm.run(),
+            Err(VMError::IndexOutOfBounds { index: 3, len: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_string_type_errors() {
+        for code in [
+            "let x = \"a\" + 1;",
+            "let x = \"a\" * \"b\";",
+            "let x = len(5);",
+            "let x = len(\"a\", \"b\");",
+            "let x = nope(1);",
+        ] {
+            let statements = Parser::new(code).parse_program().unwrap();
+            assert!(Compiler::new().compile(statements).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_compiled_arrays() {
+        let code = "
+            let a = [1, 2, 3, 4];
+            let i = 0;
+            let sum = 0;
+            while i < len(a) {
+                a[i] = a[i] * 10;
+                sum = sum + a[i];
+                i = i + 1;
+            }
+            let empty = len([]);
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        let base = *memory.get(&0).unwrap() as usize;
+        assert_eq!(memory.get(&base), Some(&4));
+        assert_eq!(memory.get(&(base + 4)), Some(&40));
+        assert_eq!(memory.get(&2), Some(&100));
+        assert_eq!(memory.get(&3), Some(&0));
+    }
+
+    #[test]
+    fn test_array_bounds_checks() {
+        for code in ["let a = [1, 2]; let x = a[2];", "let a = [1]; a[-1] = 5;"] {
+            let statements = Parser::new(code).parse_program().unwrap();
+            let bytecode = Compiler::new().compile(statements).unwrap();
+            let mut vm = VM::new(bytecode, 100);
+
+            assert!(
+                matches!(vm.run(), Err(VMError::IndexOutOfBounds { .. })),
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn test_compiled_structs() {
+        let code = "
+            struct Point { x, y }
+            let p = Point { y: 2, x: 7 };
+            let flag = 1;
+            if flag {
+                p = Point { x: p.y * 10, y: p.x };
+            }
+            let sum = p.x + p.y;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        let p = *memory.get(&0).unwrap() as usize;
+        assert_eq!(memory.get(&(p + 1)), Some(&20));
+        assert_eq!(memory.get(&(p + 2)), Some(&7));
+        assert_eq!(memory.get(&2), Some(&27));
+    }
+
+    #[test]
+    fn test_struct_errors() {
+        for code in [
+            "struct P { x } let p = P { x: 1, y: 2 };",
+            "struct P { x, y } let p = P { x: 1 };",
+            "struct P { x } let p = P { x: 1, x: 2 };",
+            "struct P { x } let p = P { x: 1 }; let z = p.z;",
+            "let n = 1; let z = n.x;",
+        ] {
+            let statements = Parser::new(code).parse_program().unwrap();
+            assert!(Compiler::new().compile(statements).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_compiled_match() {
+        let code = "
+            let i = 0;
+            let ones = 0;
+            let twos = 0;
+            let others = 0;
+            while i < 6 {
+                match i % 3 {
+                    1 => { ones = ones + 1; },
+                    2 => twos = twos + 10;
+                    _ => { others = others + 100; }
+                }
+                match i {
+                    5 => { i = i + 1; }
+                }
+                i = i + 1;
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&1), Some(&2));
+        assert_eq!(memory.get(&2), Some(&20));
+        assert_eq!(memory.get(&3), Some(&200));
+        assert!(vm.get_stack().is_empty());
+    }
+
+    #[test]
+    fn test_match_duplicate_arm() {
+        let statements = Parser::new("match 1 { 1 => {} 1 => {} }")
+            .parse_program()
+            .unwrap();
+        assert!(Compiler::new().compile(statements).is_err());
+    }
+
+    #[test]
+    fn test_compiled_shifts() {
+        let code = "
+            let n = 3;
+            let a = 1 << n + 1;
+            let b = -9 >> 1;
+            let c = a >> n == 2;
+            const MASK = (1 << 4) - 1;
+            let d = MASK;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_memory().get(&1), Some(&16));
+        assert_eq!(vm.get_memory().get(&2), Some(&-5));
+        assert_eq!(vm.get_memory().get(&3), Some(&1));
+        assert_eq!(vm.get_memory().get(&4), Some(&15));
+
+        let statements = Parser::new("let n = 64; let x = 1 << n;")
+            .parse_program()
+            .unwrap();
+        let mut vm = VM::new(Compiler::new().compile(statements).unwrap(), 100);
+        assert!(matches!(
+            vm.run(),
+            Err(VMError::InvalidArgument("shift", 64))
+        ));
+        let statements = Parser::new("let x = 1.0 << 2.0;").parse_program().unwrap();
+        assert!(Compiler::new().compile(statements).is_err());
+    }
+
+    #[test]
+    fn test_compiled_increment_decrement() {
+        let code = "
+            let up = 0;
+            let down = 10;
+            while up < 4 {
+                up++;
+                down--;
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_memory().get(&0), Some(&4));
+        assert_eq!(vm.get_memory().get(&1), Some(&6));
+    }
+
+    #[test]
+    fn test_compiled_conditional_expression() {
+        let code = "
+            let a = 3;
+            let b = 8;
+            let max = a > b ? a : b;
+            let sign = a < 0 ? 0 - 1 : a == 0 ? 0 : 1;
+            let safe = b == 0 ? 0 : a / b;
+            let zero = 0;
+            let lazy = zero == 0 ? 7 : 1 / zero;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&2), Some(&8));
+        assert_eq!(memory.get(&3), Some(&1));
+        assert_eq!(memory.get(&4), Some(&0));
+        assert_eq!(memory.get(&6), Some(&7));
+        assert!(vm.get_stack().is_empty());
+    }
+
+    #[test]
+    fn test_compiled_constants() {
+        let code = "
+            const KB = 1024;
+            const SIZE = 10 * KB;
+            const BIG = SIZE > 4096;
+            let x = SIZE + 1;
+            let y = BIG;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        // Constants are inlined, so `x` takes the first memory cell
+        assert_eq!(vm.get_memory().get(&0), Some(&10241));
+        assert_eq!(vm.get_memory().get(&1), Some(&1));
+        assert_eq!(vm.get_memory().len(), 2);
+    }
+
+    #[test]
+    fn test_constant_errors() {
+        for code in [
+            "const N = 1; N = 2;",
+            "const N = 1; let N = 2;",
+            "const N = 1; N++;",
+            "const N = 1; const N = 2;",
+            "let x = 1; const N = x + 1;",
+            "const N = 1 / 0;",
+        ] {
+            let statements = Parser::new(code).parse_program().unwrap();
+            assert!(Compiler::new().compile(statements).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_constant_array_sizes_and_match_labels() {
+        let code = "
+            const WIDTH = 4;
+            const CELLS = WIDTH * 2 + 1;
+            const LAST = CELLS - 1;
+            let grid = [7; CELLS];
+            grid[LAST] = 1;
+            let empty = [1; WIDTH - 4];
+            let kind = 0;
+            match len(grid) {
+                WIDTH => { kind = 1; }
+                WIDTH * 2 + 1 => { kind = 2; }
+                (0) => { kind = 3; }
+                _ => {}
+            }
+            let total = grid[0] + grid[LAST] + len(empty);
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        let mut vm = VM::new(compiler.compile(statements).unwrap(), 100);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_memory().get(&2), Some(&2));
+        assert_eq!(vm.get_memory().get(&3), Some(&8));
+
+        for code in [
+            "let n = 3; let a = [0; n];",
+            "let a = [0; -1];",
+            "let a = [0; 100000];",
+            "let a = [\"s\"; 2];",
+            "const A = 1; match 1 { A => {} 2 - 1 => {} }",
+            "let x = 1; match 1 { x => {} }",
+        ] {
+            let result = Parser::new(code)
+                .parse_program()
+                .map_err(CompileError::from)
+                .and_then(|statements| Compiler::new().compile(statements));
+            assert!(result.is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_compiled_block_scoping_and_shadowing() {
+        let code = "
+            let x = 1;
+            let total = 0;
+            if x == 1 {
+                let x = x + 10;
+                let inner = x * 2;
+                total = total + inner;
+            }
+            let after = 5;
+            total = total + x;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&0), Some(&1));
+        assert_eq!(memory.get(&1), Some(&23));
+        // The block's slots are recycled once it ends
+        assert_eq!(memory.get(&2), Some(&5));
+    }
+
+    #[test]
+    fn test_compiled_read() {
+        let code = "
+            let n = read();
+            let sum = 0;
+            while n > 0 {
+                sum = sum + read();
+                n--;
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+        vm.set_input(io::Cursor::new("3\n10\n 20 \n-5\n"));
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_memory().get(&1), Some(&25));
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let statements = Parser::new("let a = read(); let b = read();")
+            .parse_program()
+            .unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+
+        let mut vm = VM::new(bytecode.clone(), 100);
+        vm.set_input(io::Cursor::new("1\n"));
+        assert!(matches!(vm.run(), Err(VMError::InputExhausted)));
+
+        let mut vm = VM::new(bytecode, 100);
+        vm.set_input(io::Cursor::new("1\nabc\n"));
+        assert!(matches!(vm.run(), Err(VMError::InvalidInput(input)) if input == "abc"));
+    }
+
+    #[test]
+    fn test_compiled_assert() {
+        let statements = Parser::new("let x = 4; assert x % 2 == 0; assert x > 10;")
+            .parse_program()
+            .unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let assert_pcs: Vec<usize> = bytecode
+            .iter()
+            .enumerate()
+            .filter(|(_, &byte)| byte == Opcode::Assert as u8)
+            .map(|(pc, _)| pc)
+            .collect();
+        let mut vm = VM::new(bytecode, 100);
+
+        match vm.run() {
+            Err(VMError::AssertionFailed { pc }) => assert_eq!(Some(&pc), assert_pcs.last()),
+            other => panic!("expected assertion failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compiled_number_literals() {
+        let statements = Parser::new("let a = 0xFF; let b = 1_000 + 0b11;")
+            .parse_program()
+            .unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_memory().get(&0), Some(&255));
+        assert_eq!(vm.get_memory().get(&1), Some(&1003));
+    }
+
+    #[test]
+    fn test_malformed_literal_is_parse_error() {
+        let errors = Parser::new("let a = 0b12;").parse_program().unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "1:9: Malformed integer literal '0b12'"
+        );
+        assert_eq!(
+            errors,
+            [ParseError::Lex {
+                error: LexError::MalformedInteger("0b12".to_string()),
+                span: Span {
+                    line: 1,
+                    col: 9,
+                    offset: 8,
+                    len: 4,
+                    file: 0,
+                },
+            }]
+        );
+
+        let errors = Parser::new("let a = 1 & 2;").parse_program().unwrap_err();
+        assert_eq!(errors[0].to_string(), "1:11: Unexpected character '&'");
+        assert_eq!(errors[0].span().col, 11);
+    }
+
+    #[test]
+    fn test_parser_reports_every_error() {
+        let code = "let a = 1 +;
+fn f(x) {
+    let y = ;
+    print y;
+    return x
+}
+let b = 0b12;
+}
+print a";
+        let errors: Vec<String> = Parser::new(code)
+            .parse_program()
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "1:12: Expected expression, found ';'",
+                "3:13: Expected expression, found ';'",
+                "6:1: Expected ';', found '}'",
+                "7:9: Malformed integer literal '0b12'",
+                "8:1: Expected statement, found '}'",
+                "9:8: Expected ';', found end of input",
+            ]
+        );
+
+        // Without errors, the statements are returned
+        let statements = Parser::new("let a = 1; print a;").parse_program().unwrap();
+        assert_eq!(statements.len(), 2);
+    }
+
+    #[test]
+    fn test_parser_spans() {
+        let code = "let x = -a[0] * (b + 1);\nwhile x > 0 {\n    x--;\n}";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let text = |span: Span| span.text(code);
+
+        assert_eq!(text(statements[0].span), "let x = -a[0] * (b + 1);");
+        let StatementKind::Let(_, _, value) = &statements[0].kind else {
+            panic!("expected let");
+        };
+        assert_eq!(text(value.span), "-a[0] * (b + 1)");
+        let ExprKind::BinaryOp(left, _, right) = &value.kind else {
+            panic!("expected binary operation");
+        };
+        assert_eq!(text(left.span), "-a[0]");
+        assert_eq!(text(right.span), "(b + 1)");
+
+        let StatementKind::While(condition, body) = &statements[1].kind else {
+            panic!("expected while");
+        };
+        assert_eq!(text(statements[1].span), "while x > 0 {\n    x--;\n}");
+        assert_eq!(text(condition.span), "x > 0");
+        assert_eq!(body[0].span.to_string(), "3:5");
+    }
+
+    #[test]
+    fn test_ast_json_round_trip() {
+        let code = "fn sq(n: int) -> int { return n * n; } let x = sq(7); print x;";
+        let json = crate::compiler::parse_to_json(code).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[1]["kind"]["Let"][0], "x");
+        assert_eq!(value[1]["span"]["col"], 40);
+
+        let statements: Vec<Statement> = serde_json::from_str(&json).unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+        vm.run().unwrap();
+        assert_eq!(vm.get_memory().get(&0), Some(&49));
+
+        assert!(crate::compiler::parse_to_json("let = 1;").is_err());
+    }
+
+    #[test]
+    fn test_compiled_negative_numbers() {
+        let code = "
+            let min = -9223372036854775808;
+            let max = 9223372036854775807;
+            let a = -5;
+            let b = -a * 2;
+            let c = 3 - -2;
+            match a {
+                -5 => { a = 1; }
+                _ => { a = 0; }
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&0), Some(&i64::MIN));
+        assert_eq!(memory.get(&1), Some(&i64::MAX));
+        assert_eq!(memory.get(&2), Some(&1));
+        assert_eq!(memory.get(&3), Some(&10));
+        assert_eq!(memory.get(&4), Some(&5));
+    }
+
+    #[test]
+    fn test_out_of_range_literals() {
+        for code in [
+            "let x = 9223372036854775808;",
+            "let x = -9223372036854775809;",
+        ] {
+            assert!(Parser::new(code).parse_program().is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_compiled_math_builtins() {
+        let code = "
+            let a = abs(-7);
+            let b = min(3, -4);
+            let c = max(3, -4);
+            let d = pow(2, 10);
+            let e = sqrt_int(99);
+            let f = sqrt_int(100);
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        let values: Vec<i64> = (0..6).map(|addr| memory[&addr]).collect();
+        assert_eq!(values, vec![7, -4, 3, 1024, 9, 10]);
+    }
+
+    #[test]
+    fn test_math_builtin_errors() {
+        for code in ["let x = sqrt_int(-1);", "let x = pow(2, -1);"] {
+            let statements = Parser::new(code).parse_program().unwrap();
+            let bytecode = Compiler::new().compile(statements).unwrap();
+            let mut vm = VM::new(bytecode, 100);
+            assert!(
+                matches!(vm.run(), Err(VMError::InvalidArgument(_, -1))),
+                "{}",
+                code
+            );
+        }
+
+        let statements = Parser::new("let x = min(1);").parse_program().unwrap();
+        assert!(Compiler::new().compile(statements).is_err());
+    }
+
+    #[test]
+    fn test_compiled_floats() {
+        let code = "
+            let g = 9.81;
+            let t = 2.0;
+            let d = 0.5 * g * t * t;
+            let neg = -d;
+            let whole = int(d);
+            let ratio = float(7) / 2.0;
+            let faster = d > 19.5 && ratio == 3.5;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        let float_at = |addr: usize| f64::from_bits(memory[&addr] as u64);
+        assert!((float_at(2) - 19.62).abs() < 1e-9);
+        assert!((float_at(3) + 19.62).abs() < 1e-9);
+        assert_eq!(memory.get(&4), Some(&19));
+        assert_eq!(float_at(5), 3.5);
+        assert_eq!(memory.get(&6), Some(&1));
+    }
+
+    #[test]
+    fn test_mixed_int_float_is_compile_error() {
+        for code in ["let x = 1 + 2.0;", "let x = int(3);", "let x = float(1.5);"] {
+            let statements = Parser::new(code).parse_program().unwrap();
+            assert!(Compiler::new().compile(statements).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_compiled_modulo() {
+        let code = "
+            let r = 17 % 5;
+            let s = 2 + 9 % 4 * 3;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_memory().get(&0), Some(&2));
+        assert_eq!(vm.get_memory().get(&1), Some(&5));
+    }
+
+    #[test]
+    fn test_modulo_by_zero() {
+        let code = "let r = 1 % 0;";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        assert!(matches!(vm.run(), Err(VMError::DivisionByZero)));
+    }
+
+    #[test]
+    fn test_compiled_functions() {
+        let code = "
+            let sum = add(2, 3);
+            let op = add;
+            let indirect = op(10, 4);
+            let applied = twice(inc, 5);
+            let early = sign(-7);
+            let name = greet();
+            let n = len(name);
+
+            fn add(a, b) { return a + b; }
+            fn inc(n) { return n + 1; }
+            fn twice(f: fn(int) -> int, x) { return f(f(x)); }
+            fn sign(x) {
+                if x < 0 { return -1; }
+                return 1;
+            }
+            fn greet() { return \"hi\" + \"!\"; }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&0), Some(&5));
+        assert_eq!(memory.get(&2), Some(&14));
+        assert_eq!(memory.get(&3), Some(&7));
+        assert_eq!(memory.get(&4), Some(&-1));
+        assert_eq!(memory.get(&6), Some(&3));
+        assert!(vm.get_stack().is_empty());
+    }
+
+    #[test]
+    fn test_compiled_recursion() {
+        let code = "
+            let total = 0;
+            let f = fact(5);
+            let r = fib(10);
+            let s = sum_to(4);
+
+            fn fact(n) {
+                if n <= 1 { return 1; }
+                return n * fact(n - 1);
+            }
+            fn fib(n) {
+                if n < 2 { return n; }
+                let a = fib(n - 1);
+                let b = fib(n - 2);
+                return a + b;
+            }
+            fn sum_to(n) {
+                total = total + n;
+                if n == 0 { return 0; }
+                let rest = sum_to(n - 1);
+                return n + rest;
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&0), Some(&10));
+        assert_eq!(memory.get(&1), Some(&120));
+        assert_eq!(memory.get(&2), Some(&55));
+        assert_eq!(memory.get(&3), Some(&10));
+        assert!(vm.get_stack().is_empty());
+    }
+
+    #[test]
+    fn test_unbounded_recursion_overflows() {
+        let code = "
+            let x = forever(1);
+            fn forever(n) { return forever(n + 1); }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        assert!(matches!(vm.run(), Err(VMError::StackOverflow)));
+    }
+
+    #[test]
+    fn test_compiled_character_literals() {
+        let code = "
+            let a = 'a';
+            let next = 'a' + 1;
+            let accent = '\\u{e9}';
+            let kind = 0;
+            match charAt(\"x=1\", 1) {
+                '=' => { kind = 1; },
+                _ => { kind = 2; },
+            }
+            printChar('o');
+            printChar('k');
+            printChar('\\n');
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&0), Some(&97));
+        assert_eq!(memory.get(&1), Some(&98));
+        assert_eq!(memory.get(&2), Some(&0xe9));
+        assert_eq!(memory.get(&3), Some(&1));
+        assert!(vm.get_stack().is_empty());
+    }
+
+    #[test]
+    fn test_print_char_rejects_invalid_scalar_values() {
+        let program = crate::bytecode![push 0xD800, printchar, halt];
+        let mut vm = VM::new(program, 100);
+        assert!(matches!(
+            vm.run(),
+            Err(VMError::InvalidArgument("PrintChar", 0xD800))
+        ));
+    }
+
+    #[test]
+    fn test_string_constants_at_crafted_offsets_are_errors() {
+        let mut vm = VM::new(crate::bytecode![push - 1, loadstr, halt], 100);
+        assert!(matches!(vm.run(), Err(VMError::OutOfMemory(usize::MAX))));
+        let mut vm = VM::new(crate::bytecode![push -1, push 0, bindhost, halt], 100);
+        assert!(matches!(vm.run(), Err(VMError::OutOfMemory(usize::MAX))));
+        // A length that runs past the end of the address space
+        let mut program = crate::bytecode![push 11, loadstr, halt];
+        program.extend_from_slice(&(-2i64).to_le_bytes());
+        let mut vm = VM::new(program, 100);
+        assert!(matches!(vm.run(), Err(VMError::InvalidString(11))));
+    }
+
+    #[test]
+    fn test_maps_at_crafted_addresses_are_errors() {
+        let mut vm = VM::new(crate::bytecode![push -1, push 1, maphas, halt], 100);
+        assert!(matches!(vm.run(), Err(VMError::InvalidArgument("map", -1))));
+        // A capacity whose table would run past the end of the address space
+        let program = crate::bytecode![
+            push (i64::MAX), push 1, store,
+            push 0, push 5, push 6, mapset, halt,
+        ];
+        let mut vm = VM::new(program, 100);
+        assert!(matches!(vm.run(), Err(VMError::InvalidArgument("map", 0))));
+    }
+
+    #[test]
+    fn test_closures_at_crafted_addresses_are_errors() {
+        let mut vm = VM::new(crate::bytecode![push - 1, callclosure, halt], 100);
+        assert!(matches!(
+            vm.run(),
+            Err(VMError::InvalidArgument("CallClosure", -1))
+        ));
+        // A record whose code address is outside the program
+        let mut vm = VM::new(crate::bytecode![push 0, callclosure, halt], 100);
+        vm.memory_slice(0..2)[1] = -5;
+        assert!(matches!(vm.run(), Err(VMError::OutOfMemory(_))));
+    }
+
+    #[test]
+    fn test_strings_with_crafted_lengths_are_errors() {
+        // A negative length at address 0, and one past the end of memory
+        let program = crate::bytecode![push -3, push 0, store, push 0, push 0, concat, halt];
+        let mut vm = VM::new(program, 100);
+        assert!(matches!(vm.run(), Err(VMError::InvalidString(0))));
+        let program = crate::bytecode![push (i64::MAX), push 0, store, push 0, printstr, halt];
+        let mut vm = VM::new(program, 100);
+        assert!(matches!(vm.run(), Err(VMError::InvalidString(0))));
+        let mut vm = VM::new(crate::bytecode![push - 1, push - 1, concat, halt], 100);
+        assert!(matches!(vm.run(), Err(VMError::InvalidString(usize::MAX))));
+    }
+
+    #[test]
+    fn test_constant_pools_at_crafted_offsets_are_errors() {
+        let mut vm = VM::new(crate::bytecode![push -1, constpool, pushconst 0, halt], 100);
+        assert!(matches!(vm.run(), Err(VMError::InvalidConstant(0))));
+        // A pool claiming more values than fit in the address space
+        let mut program = crate::bytecode![push 14, constpool, pushconst 3, halt];
+        program.extend_from_slice(&i64::MAX.to_le_bytes());
+        let mut vm = VM::new(program, 100);
+        assert!(matches!(vm.run(), Err(VMError::InvalidConstant(3))));
+    }
+
+    #[test]
+    fn test_indexing_crafted_addresses_is_an_error() {
+        // A length stored at the last address, so elements would wrap around
+        let program = crate::bytecode![push 5, push -1, store, push -1, push 2, index, halt];
+        let mut vm = VM::new(program, 100);
+        assert!(matches!(
+            vm.run(),
+            Err(VMError::IndexOutOfBounds { index: 2, len: 5 })
+        ));
+        let program = crate::bytecode![
+            push 5, push -1, store, push -1, push 0, push 7, setindex, halt,
+        ];
+        let mut vm = VM::new(program, 100);
+        assert!(matches!(
+            vm.run(),
+            Err(VMError::IndexOutOfBounds { index: 0, len: 5 })
+        ));
+    }
+
+    #[test]
+    fn test_compiled_tuple_destructuring() {
+        let code = "
+            let (a, b) = (1, 2);
+            (a, b) = (b, a);
+            let (x, y, z) = (a * 10, b * 10, \"s\");
+            let n = len(z);
+            let (p, q) = (swap_sum(3, 4), 0);
+
+            fn swap_sum(m, k) {
+                let (lo, hi) = (m, k);
+                (lo, hi) = (hi, lo);
+                return lo * 10 + hi;
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&0), Some(&2));
+        assert_eq!(memory.get(&1), Some(&1));
+        assert_eq!(memory.get(&2), Some(&20));
+        assert_eq!(memory.get(&3), Some(&10));
+        assert_eq!(memory.get(&5), Some(&1));
+        assert_eq!(memory.get(&6), Some(&43));
+        assert!(vm.get_stack().is_empty());
+
+        for code in ["let (a, b) = (1, 2, 3);", "let (a, a) = (1, 2);"] {
+            assert!(Parser::new(code).parse_program().is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_compiled_exit() {
+        let code = "
+            let i = 0;
+            while true {
+                i = i + 1;
+                if i == 3 { check(i); }
+            }
+            fn check(n) {
+                while true {
+                    exit(n * 10);
+                }
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_exit_code(), 30);
+        assert_eq!(vm.get_memory().get(&0), Some(&3));
+
+        let statements = Parser::new("let x = 1;").parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+        vm.run().unwrap();
+        assert_eq!(vm.get_exit_code(), 0);
+
+        let statements = Parser::new("exit(\"no\");").parse_program().unwrap();
+        assert!(Compiler::new().compile(statements).is_err());
+    }
+
+    #[test]
+    fn test_compiled_exceptions() {
+        let code = "
+            let caught = 0;
+            let after = 0;
+            try {
+                let x = 1 + checked(5);
+                caught = checked(-2);
+                after = 1;
+            } catch (e) {
+                caught = e;
+            }
+            let outer = 0;
+            try {
+                try {
+                    throw 7;
+                } catch (e) {
+                    throw e * 2;
+                }
+            } catch (e) {
+                outer = e;
+            }
+            let recovered = safe_div(10, 0);
+            let normal = safe_div(10, 2);
+            let late = 0;
+            try {
+                throw 99;
+            } catch (e) {
+                late = e;
+            }
+
+            fn checked(n) {
+                if n < 0 { throw 100 - n; }
+                return n;
+            }
+            fn safe_div(a, b) {
+                try {
+                    if b == 0 { throw 1; }
+                    return a / b;
+                } catch (e) {
+                    return -1;
+                }
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&0), Some(&102));
+        assert_eq!(memory.get(&1), Some(&0));
+        assert_eq!(memory.get(&2), Some(&14));
+        assert_eq!(memory.get(&3), Some(&-1));
+        assert_eq!(memory.get(&4), Some(&5));
+        assert_eq!(memory.get(&5), Some(&99));
+        assert!(vm.get_stack().is_empty());
+    }
+
+    #[test]
+    fn test_uncaught_exception() {
+        let code = "
+            try { let x = 1; } catch (e) { print e; }
+            throw 42;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        assert!(matches!(vm.run(), Err(VMError::UncaughtException(42))));
+    }
+
+    #[test]
+    fn test_compiled_maps() {
+        let code = "
+            let squares: map = {};
+            let i = 0;
+            while i < 20 {
+                squares[i * 7] = i * i;
+                i++;
+            }
+            let ages = {'a': 30, 'b': 41};
+            ages['a'] = ages['a'] + 1;
+            let a = ages['a'];
+            let found = has(ages, 'b');
+            let missing = has(ages, 'z');
+            let n = len(squares);
+            let last = squares[19 * 7];
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&3), Some(&31));
+        assert_eq!(memory.get(&4), Some(&1));
+        assert_eq!(memory.get(&5), Some(&0));
+        assert_eq!(memory.get(&6), Some(&20));
+        assert_eq!(memory.get(&7), Some(&361));
+        assert!(vm.get_stack().is_empty());
+    }
+
+    #[test]
+    fn test_missing_map_key() {
+        let statements = Parser::new("let m = {1: 2}; let x = m[3];")
+            .parse_program()
+            .unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        assert!(matches!(vm.run(), Err(VMError::KeyNotFound(3))));
+    }
+
+    #[test]
+    fn test_compiled_formatted_print() {
+        let code = "
+            let x = 3;
+            let name = \"vm\";
+            print(\"x = {}, half = {}, name = {} {{ok}}\", x, float(x) / 2.0, name);
+            print(\"no placeholders\");
+            print (\"parenthesized \" + name);
+            print (x) + 1;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        assert!(matches!(&statements[2].kind,
+            StatementKind::PrintFormatted(format, args) if format.starts_with("x = ") && args.len() == 3
+        ));
+        assert!(
+            matches!(&statements[3].kind, StatementKind::PrintFormatted(_, args) if args.is_empty())
+        );
+        assert!(matches!(&statements[4].kind, StatementKind::Print(_)));
+        assert!(matches!(&statements[5].kind, StatementKind::Print(_)));
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+        assert!(vm.get_stack().is_empty());
+
+        for code in [
+            "print(\"{} {}\", 1);",
+            "print(\"{}\", 1, 2);",
+            "print(\"{x}\", 1);",
+            "print(\"}\");",
+            "print(\"{}\", [1]);",
+        ] {
+            let statements = Parser::new(code).parse_program().unwrap();
+            assert!(Compiler::new().compile(statements).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_print_multiple_values() {
+        let code = "
+            let x = 1;
+            print x, \"and\", 2.5, x + 1;
+            print x;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        assert!(matches!(&statements[1].kind, StatementKind::Print(values) if values.len() == 4));
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+        assert!(vm.get_stack().is_empty());
+
+        let statements = Parser::new("print 1, [2];").parse_program().unwrap();
+        assert!(Compiler::new().compile(statements).is_err());
+    }
+
+    #[test]
+    fn test_dead_code_elimination() {
+        let code = "
+            let scratch = 1 + 2;
+            let n = 5;
+            if n > 10 { n = 0; }
+            let result = twice(n);
+            fn twice(x) {
+                return x * 2;
+                print x;
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.set_opt_level(OptLevel::Aggressive);
+        let bytecode = compiler.compile(statements).unwrap();
+        let report = &compiler.pass_reports()[2];
+        assert_eq!(report.pass, "dead-code-elimination");
+        assert!(report.statements_after < report.statements_before);
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        // `scratch` is gone, so `n` and `result` take the first addresses
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&0), Some(&5));
+        assert_eq!(memory.get(&1), Some(&10));
+    }
+
+    #[test]
+    fn test_ret_without_call() {
+        let mut vm = VM::new(vec![Opcode::Ret as u8], 100);
+        assert!(matches!(vm.run(), Err(VMError::StackUnderflow)));
+    }
+
+    #[test]
+    fn test_compiled_closures() {
+        let code = "
+            fn make_adder(n) { return fn(x) { return x + n; }; }
+            fn compose(f: fn(int) -> int, g: fn(int) -> int) {
+                return fn(x) { return f(g(x)); };
+            }
+
+            let add5 = make_adder(5);
+            let add10 = make_adder(10);
+            let a = add5(1);
+            let b = add10(1);
+            let base = 100;
+            let offset = fn(x) { return x + base; };
+            base = 200;
+            let c = offset(1);
+            let both = compose(add5, add10);
+            let d = both(0);
+            if true {
+                let k = 3;
+                let get = fn() { return k * 2; };
+                d = d + get();
+            }
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+
+        vm.run().unwrap();
+
+        let memory = vm.get_memory();
+        assert_eq!(memory.get(&2), Some(&6));
+        assert_eq!(memory.get(&3), Some(&11));
+        // Top-level variables are read when the closure runs, not captured
+        assert_eq!(memory.get(&6), Some(&201));
+        assert_eq!(memory.get(&8), Some(&21));
+    }
+
+    #[test]
+    fn test_assign_to_captured_variable_is_compile_error() {
+        let code = "fn counter() { let n = 0; return fn() { n = n + 1; return n; }; }";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let error = Compiler::new().compile(statements).unwrap_err();
+        assert!(matches!(error, CompileError::AssignToCaptured { ref name, .. } if name == "n"));
+        assert_eq!(
+            error.to_string(),
+            "1:41: Cannot assign to captured variable 'n'"
+        );
+    }
+
+    #[test]
+    fn test_loop_invariant_loads_are_hoisted() {
+        let code = "
+            fn scaler(k, step) {
+                return fn(n) {
+                    let total = 0;
+                    let i = 0;
+                    while i < n {
+                        total = total + i * k;
+                        if i == 2 {
+                            let step = 5;
+                            total = total + step;
+                        }
+                        i = i + step;
+                    }
+                    return total;
+                };
+            }
+            let f = scaler(3, 1);
+            let r = f(10);
+        ";
+        let run = |level| {
+            let statements = Parser::new(code).parse_program().unwrap();
+            let mut compiler = Compiler::new();
+            compiler.set_prelude(false);
+            compiler.set_opt_level(level);
+            let mut vm = VM::new(compiler.compile(statements).unwrap(), 100);
+            let mut steps = 0;
+            while vm.execute_next().unwrap() {
+                steps += 1;
+            }
+            (vm.get_memory()[&1], steps)
+        };
+        let (unoptimized, unoptimized_steps) = run(OptLevel::None);
+        let (optimized, optimized_steps) = run(OptLevel::Default);
+        assert_eq!(unoptimized, 140);
+        assert_eq!(optimized, unoptimized);
+        // `k` is copied out of the environment once, saving three
+        // instructions on each of the ten iterations for the seven of the
+        // copy; `step` is redeclared in the loop, so it is not hoisted
+        assert_eq!(unoptimized_steps - optimized_steps, 10 * 3 - 7);
+
+        // Nothing to gain for globals and locals, so their loops are left
+        // as they are
+        let code = "
+            let k = 3; let i = 0; let total = 0;
+            while i < 10 { total = total + k; i = i + 1; }
+            fn f(k) { let i = 0; while i < k { i = i + 1; } return i; }
+            print f(k);
+        ";
+        let compile = |level| {
+            let statements = Parser::new(code).parse_program().unwrap();
+            let mut compiler = Compiler::new();
+            compiler.set_prelude(false);
+            compiler.set_opt_level(level);
+            compiler.compile(statements).unwrap()
+        };
+        assert_eq!(compile(OptLevel::Default), compile(OptLevel::None));
+    }
+
+    #[test]
+    fn test_compiler_warnings() {
+        let code = "let unused = 1; let x = 2; print x; exit(0); print x;";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        let bytecode = compiler.compile(statements).unwrap();
+        let warnings: Vec<String> = compiler.warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            [
+                "1:46: warning: Unreachable code after 'exit'",
+                "1:1: warning: Variable 'unused' is never read",
+            ]
+        );
+
+        // Warnings never stop the program from compiling and running
+        let mut vm = VM::new(bytecode, 100);
+        vm.run().unwrap();
+        assert_eq!(vm.get_memory().get(&0), Some(&1));
+    }
+
+    #[test]
+    fn test_undefined_variable_is_compile_error() {
+        let cases = [
+            ("let x = 1; print x + y;", "1:22: Undefined variable 'y'"),
+            ("total = 5;", "1:1: Undefined variable 'total'"),
+            (
+                "if true { let t = 1; } t++;",
+                "1:24: Undefined variable 't'",
+            ),
+            ("a[0] = 1;", "1:1: Undefined variable 'a'"),
+            (
+                "fn f(n) {\n    return n + m;\n}",
+                "2:16: Undefined variable 'm'",
+            ),
+            (
+                "let g = fn() { return z; };",
+                "1:23: Undefined variable 'z'",
+            ),
+        ];
+        for (code, message) in cases {
+            let statements = Parser::new(code).parse_program().unwrap();
+            let error = Compiler::new().compile(statements).unwrap_err();
+            assert!(
+                matches!(error, CompileError::UndefinedVariable { .. }),
+                "{}",
+                code
+            );
+            assert_eq!(error.to_string(), message, "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_extern_host_functions() {
+        let code = "
+            extern fn log(x);
+            extern fn add3(a: int, b: int, c: int) -> int;
+            let r = add3(1, 2, 3);
+            log(r);
+            log(add3(r, r, r) * 2);
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+        let logged = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let sink = logged.clone();
+        vm.register_host_function("log", 1, move |args| {
+            sink.borrow_mut().push(args[0]);
+            Ok(0)
+        });
+        vm.register_host_function("add3", 3, |args| Ok(args.iter().sum()));
+
+        vm.run().unwrap();
+
+        assert_eq!(vm.get_memory().get(&0), Some(&6));
+        assert_eq!(*logged.borrow(), vec![6, 36]);
+        assert!(vm.get_stack().is_empty());
+    }
+
+    #[test]
+    fn test_print_handler_receives_printed_values() {
+        let code = "print 1 + 2; print \"text\"; print 4 * 5;";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let mut vm = VM::new(bytecode, 100);
+        let output = crate::golden::Capture::default();
+        vm.set_output(output.clone());
+        let printed = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let sink = printed.clone();
+        vm.set_print_handler(move |value| sink.borrow_mut().push(value));
+
+        vm.run().unwrap();
+        assert_eq!(*printed.borrow(), vec![3, 20]);
+        assert_eq!(String::from_utf8_lossy(&output.0.borrow()), "text\n");
+
+        vm.reset();
+        vm.clear_print_handler();
+        vm.run().unwrap();
+        assert_eq!(printed.borrow().len(), 2);
+        assert!(
+            String::from_utf8_lossy(&output.0.borrow()).ends_with("Output: 3\ntext\nOutput: 20\n")
+        );
+    }
+
+    #[test]
+    fn test_extern_bindings_are_checked_before_running() {
+        let code = "extern fn log(x); let r = 1; log(r);";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+
+        let mut vm = VM::new(bytecode.clone(), 100);
+        assert!(matches!(vm.run(), Err(VMError::UnknownHostFunction(name)) if name == "log"));
+        assert!(vm.get_memory().is_empty());
+
+        let mut vm = VM::new(bytecode, 100);
+        vm.register_host_function("log", 2, |_| Ok(0));
+        assert!(matches!(
+            vm.run(),
+            Err(VMError::HostArityMismatch {
+                declared: 1,
+                registered: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_runtime_error_location() {
+        let code = "let values = [4, 2, 0];
+let i = 0;
+fn ratio(a, b) {
+    return a
+        / b;
+}
+while i < len(values) {
+    print ratio(12, values[i]);
+    i++;
+}";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_debug_info(true);
+        let bytecode = compiler.compile(statements).unwrap();
+        let debug_info = compiler.debug_info().unwrap().clone();
+        // Prelude functions are linked in without a source location
+        assert!(debug_info.entries().iter().any(|(_, span)| span.is_none()));
+
+        let mut vm = VM::new(bytecode, 100);
+        assert_eq!(vm.current_span(), None);
+        vm.set_debug_info(debug_info);
+        let error = vm.run().unwrap_err();
+        let span = vm.current_span().unwrap();
+        assert_eq!(
+            format!("{} at line {}", error, span.line),
+            "Division by zero at line 4"
+        );
+        assert_eq!(span.text(code), "a\n        / b");
+
+        let code = "let x = 1;\nextern fn missing(n);\nmissing(x);";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_debug_info(true);
+        let mut vm = VM::new(compiler.compile(statements).unwrap(), 100);
+        vm.set_debug_info(compiler.debug_info().unwrap().clone());
+        assert!(matches!(vm.run(), Err(VMError::UnknownHostFunction(_))));
+        assert_eq!(vm.current_span().unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_constant_pool() {
+        let code = "
+            const BIG = 123456789;
+            let n = 7;
+            let a = n + 1000000;
+            let b = n * 1000000;
+            let c = 1000000 - n;
+            let f = 2.5 * float(n) * 2.5;
+            let d = n * BIG;
+            let e = BIG - n;
+        ";
+        let statements = Parser::new(code).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        let bytecode = compiler.compile(statements).unwrap();
+        let instructions = crate::bytecode::decode(&bytecode).unwrap();
+        let pushed_from_pool = instructions
+            .iter()
+            .filter(|instruction| instruction.opcode == Opcode::PushConst)
+            .count();
+        assert_eq!(pushed_from_pool, 7);
+        assert!(!instructions
+            .iter()
+            .any(|instruction| instruction.operand == Some(123456789)));
+
+        let mut vm = VM::new(bytecode, 100);
+        vm.run().unwrap();
+        let memory = vm.get_memory();
+        assert_eq!(memory[&1], 1000007);
+        assert_eq!(memory[&2], 7000000);
+        assert_eq!(memory[&3], 999993);
+        assert_eq!(f64::from_bits(memory[&4] as u64), 43.75);
+        assert_eq!(memory[&5], 864197523);
+        assert_eq!(memory[&6], 123456782);
+
+        let mut vm = VM::new(vec![Opcode::PushConst as u8, 0, 0], 100);
+        assert!(matches!(vm.run(), Err(VMError::InvalidConstant(0))));
+    }
+
+    #[test]
+    fn test_leb128_operands() {
+        let code = "
+            fn fib(n) { if n < 2 { return n; } return fib(n - 1) + fib(n - 2); }
+            let total = 0;
+            let i = 0;
+            while i < 10 { total = total + fib(i); i++; }
+            let big = -5000000000 + total;
+            let name = \"leb\" + \"128\";
+            let m = {1: 100, 2: 200};
+            let f = 0.5 * float(total);
+            let caught = 0;
+            try { throw 300; } catch (e) { caught = e + gcd(12, 18); }
+        ";
+        let compile = |encoding| {
+            let statements = Parser::new(code).parse_program().unwrap();
+            let mut compiler = Compiler::new();
+            compiler.set_operand_encoding(encoding);
+            compiler.compile(statements).unwrap()
+        };
+        let fixed = compile(OperandEncoding::Fixed);
+        let leb128 = compile(OperandEncoding::Leb128);
+        assert!(leb128.starts_with(&crate::bytecode::MAGIC));
+        assert!(leb128.len() * 2 < fixed.len());
+        let opcodes = |program: &[u8]| {
+            crate::bytecode::decode(program)
+                .unwrap()
+                .iter()
+                .map(|instruction| instruction.opcode)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(opcodes(&leb128), opcodes(&fixed));
+
+        let mut fixed_vm = VM::new(fixed, 100);
+        fixed_vm.run().unwrap();
+        let mut leb128_vm = VM::new(leb128, 100);
+        leb128_vm.run().unwrap();
+        assert_eq!(leb128_vm.get_memory(), fixed_vm.get_memory());
+        assert_eq!(leb128_vm.get_memory()[&2], -5000000000 + 88);
+
+        let mut vm = VM::new(vec![0, b'S', b'V', b'M', 0x80, Opcode::Halt as u8], 100);
+        assert!(matches!(vm.run(), Err(VMError::InvalidOpcode(0))));
+    }
+
+    #[test]
+    fn test_linked_objects() {
+        let library = "
+            extern fn log(x);
+            let scale = 10;
+            fn scaled(n: int) -> int { log(n); return n * scale + gcd(4, 6); }
+            fn label() -> string { return \"lib\"; }
+            fn adder(k) { return fn(x) { return x + k; }; }
+        ";
+        let main = "
+            extern fn log(x);
+            let a = 3;
+            let b = scaled(a);
+            let name = label() + \"+main\";
+            let add = adder(b);
+            let c = add(gcd(9, 6));
+            log(c);
+        ";
+        let compile_object = |code: &str, imports: &[&crate::compiler::Object]| {
+            let statements = Parser::new(code).parse_program().unwrap();
+            let mut compiler = Compiler::new();
+            for object in imports {
+                compiler.import_object(object);
+            }
+            compiler.compile_object(statements).unwrap()
+        };
+        let library = compile_object(library, &[]);
+        assert!(library.defined("scaled").is_some());
+        assert!(library.defined("gcd").is_none());
+        let main = compile_object(main, &[&library]);
+        assert_eq!(
+            main.symbols.iter().filter(|s| s.offset.is_none()).count(),
+            3
+        );
+
+        let mut linker = Linker::new();
+        linker.add(library.clone());
+        linker.add(main.clone());
+        let bytecode = linker.link().unwrap();
+        let mut vm = VM::new(bytecode, 100);
+        let logged = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let sink = logged.clone();
+        vm.register_host_function("log", 1, move |args| {
+            sink.borrow_mut().push(args[0]);
+            Ok(0)
+        });
+        vm.run().unwrap();
+        let memory = vm.get_memory();
+        assert_eq!(memory[&0], 10);
+        assert_eq!(memory[&1], 3);
+        assert_eq!(memory[&2], 32);
+        assert_eq!(vm.read_str(memory[&3] as usize).unwrap(), "lib+main");
+        assert_eq!(*logged.borrow(), vec![3, 35]);
+
+        let mut linker = Linker::new();
+        linker.add(main.clone());
+        assert!(matches!(linker.link(), Err(LinkError::UndefinedSymbol(_))));
+        let mut linker = Linker::new();
+        linker.add(library.clone());
+        linker.add(library.clone());
+        assert_eq!(
+            linker.link(),
+            Err(LinkError::DuplicateSymbol("scaled".to_string()))
+        );
+
+        let statements = Parser::new("print label();").parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.import_object(&library);
+        assert_eq!(
+            compiler.compile(statements).unwrap_err().to_string(),
+            "1:7: 'label' is imported and can only be called from an object"
+        );
+    }
+
+    #[test]
+    fn test_compiler_builder() {
+        let code = "
+            let calls = 0;
+            fn main() { calls = calls + 1; print calls; }
+            let x = 41;
+        ";
+        let mut compiler = Compiler::builder()
+            .opt_level(OptLevel::None)
+            .debug_info(true)
+            .entry_point("main")
+            .global_base(100)
+            .prelude(false)
+            .build()
+            .unwrap();
+        let first = compiler
+            .compile(Parser::new(code).parse_program().unwrap())
+            .unwrap();
+        // A compiler can be reused and compiles the same program the same way
+        let second = compiler
+            .compile(Parser::new(code).parse_program().unwrap())
+            .unwrap();
+        assert_eq!(first, second);
+        assert!(compiler.debug_info().is_some());
+
+        let mut vm = VM::new(first, 100);
+        vm.run().unwrap();
+        assert_eq!(vm.get_memory()[&100], 1);
+        assert_eq!(vm.get_memory()[&101], 41);
+        assert!(!vm.get_memory().contains_key(&0));
+
+        let mut compiler = Compiler::builder().entry_point("start").build().unwrap();
+        let statements = Parser::new("fn start(n) {}").parse_program().unwrap();
+        assert!(matches!(
+            compiler.compile(statements),
+            Err(CompileError::ArgumentCount { .. })
+        ));
+        assert!(Compiler::builder().target(Target::Stack).build().is_ok());
+        assert_eq!(
+            Compiler::builder().target(Target::Wasm).build().err(),
+            Some(BuildError::UnsupportedTarget(Target::Wasm))
+        );
+        assert!(Compiler::builder().global_base(FRAME_BASE).build().is_err());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        // A loop counting memory cell 0 up to 3, with its `load` at 9
+        let bytecode = crate::asm::assemble(
+            "loop: push 0\nload\ninc\ndup\npush 0\nstore\npush 3\nless\njumpif loop\nhalt",
+        )
+        .unwrap();
+        let mut vm = VM::new(bytecode, 16);
+        vm.add_breakpoint(9);
+        vm.add_breakpoint(1000);
+        for count in 0..3 {
+            assert_eq!(vm.resume().unwrap(), StopReason::Breakpoint(9));
+            assert_eq!(vm.pc(), 9);
+            assert_eq!(vm.get_stack(), [0]);
+            assert_eq!(vm.get_memory().get(&0).copied().unwrap_or(0), count);
+        }
+        assert!(vm.remove_breakpoint(9));
+        assert!(!vm.remove_breakpoint(9));
+        assert_eq!(vm.breakpoints().collect::<Vec<_>>(), [1000]);
+        assert_eq!(vm.resume().unwrap(), StopReason::Halted);
+        assert_eq!(vm.get_memory()[&0], 3);
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        let program = crate::asm::assemble(
+            "push 1\ncall f\npush 0\nstore\nhalt\n\
+             f: call g\ninc\nret\n\
+             g: inc\nret",
+        )
+        .unwrap();
+        // `call f` is at 18 and returns to 19; `call g` is at 39, `g` at 42
+        let mut vm = VM::new(program.clone(), 16);
+        assert_eq!(vm.step_over().unwrap(), StopReason::Stepped);
+        assert_eq!(vm.step_over().unwrap(), StopReason::Stepped);
+        assert_eq!(vm.pc(), 18);
+        assert_eq!(vm.step_over().unwrap(), StopReason::Stepped);
+        assert_eq!(vm.pc(), 19);
+        assert_eq!(vm.get_stack(), [3]);
+
+        let mut vm = VM::new(program, 16);
+        vm.add_breakpoint(42);
+        for _ in 0..2 {
+            vm.step_over().unwrap();
+        }
+        assert_eq!(vm.step_over().unwrap(), StopReason::Breakpoint(42));
+        assert_eq!(vm.call_stack().collect::<Vec<_>>(), [19, 40]);
+        assert_eq!(vm.step_out().unwrap(), StopReason::Stepped);
+        assert_eq!(vm.pc(), 40);
+        assert_eq!(vm.step().unwrap(), StopReason::Stepped);
+        assert_eq!(vm.step_out().unwrap(), StopReason::Stepped);
+        assert_eq!(vm.pc(), 19);
+        assert_eq!(vm.step_out().unwrap(), StopReason::Halted);
+        assert_eq!(vm.get_memory()[&0], 3);
+    }
+
+    #[test]
+    fn test_dump_state() {
+        let code = "
+            let total = 0;
+            let s = \"hi\";
+            if total == 0 { let inner = 5; total = inner * 2; }
+            let total = total + 1;
+        ";
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.set_debug_info(true);
+        let program = compiler
+            .compile(Parser::new(code).parse_program().unwrap())
+            .unwrap();
+        let mut vm = VM::new(program, 16);
+        assert!(vm.dump_globals().is_empty());
+        vm.set_debug_info(compiler.debug_info().unwrap().clone());
+        vm.run().unwrap();
+
+        assert_eq!(vm.dump_stack(), Vec::<i64>::new());
+        let s = vm.get_memory()[&1] as usize;
+        assert_eq!(vm.dump_memory_range(s, 4), [2, 'h' as i64, 'i' as i64, 0]);
+        // `inner` is scoped to its block
+        assert_eq!(
+            vm.dump_globals(),
+            [("total", 0, 10), ("s", 1, s as i64), ("total", 2, 11)]
+        );
+        let debug_info = vm.debug_info().unwrap();
+        assert_eq!(debug_info.global_name(2), Some("total"));
+        assert_eq!(debug_info.global_name(3), None);
+    }
+
+    #[test]
+    fn test_memory_slice_maps_cells_in_bulk() {
+        let program = crate::bytecode![
+            push 100, load, push 101, load, add, push 102, load, add,
+            push 200, store, halt,
+        ];
+        let mut vm = VM::new(program, 16);
+        vm.memory_slice(100..110)[..3].copy_from_slice(&[1, 2, 3]);
+        // Cells left at 0 are not stored
+        assert_eq!(vm.get_memory().len(), 3);
+        vm.run().unwrap();
+        let results = vm.memory_slice(199..201);
+        assert_eq!(*results, [0, 6]);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_events() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+        impl io::Write for Buffer {
+            fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(bytes)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let code = "extern fn twice(x); print twice(4); print 1 / 0;";
+            let statements = Parser::new(code).parse_program().unwrap();
+            let mut compiler = Compiler::new();
+            compiler.set_prelude(false);
+            let mut vm = VM::new(compiler.compile(statements).unwrap(), 100);
+            vm.register_host_function("twice", 1, |args| Ok(args[0] * 2));
+            assert!(vm.run().is_err());
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("program loaded"));
+        assert!(output.contains("opcode=CallHost"));
+        assert!(output.contains("host call name=twice args=[4] result=8"));
+        assert!(output.contains("fault"));
+        assert!(output.contains("error=Division by zero"));
+    }
+
+    #[test]
+    fn test_run_for() {
+        let code = "let i = 0; while i < 100 { i = i + 1; }";
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        let program = compiler
+            .compile(Parser::new(code).parse_program().unwrap())
+            .unwrap();
+        let mut vm = VM::new(program.clone(), 100);
+        let mut slices = 0;
+        while vm.run_for(50).unwrap() {
+            slices += 1;
+        }
+        assert_eq!(vm.get_memory()[&0], 100);
+        assert!(slices > 10);
+
+        let mut whole = VM::new(program, 100);
+        whole.run().unwrap();
+        assert_eq!(
+            vm.metrics().instructions_retired,
+            whole.metrics().instructions_retired
+        );
+    }
+
+    #[test]
+    fn test_metrics() {
+        let code = "
+            fn sum(n) { if n == 0 { return 0; } return n + sum(n - 1); }
+            let total = sum(10);
+            let s = \"abc\";
+        ";
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        let program = compiler
+            .compile(Parser::new(code).parse_program().unwrap())
+            .unwrap();
+        let mut vm = VM::new(program, 100);
+        assert_eq!(vm.metrics(), &metrics::Metrics::default());
+        vm.run().unwrap();
+
+        let metrics = vm.metrics().clone();
+        assert_eq!(metrics.instructions_retired, metrics.opcodes.total());
+        assert_eq!(metrics.opcodes.count(Opcode::Call), 11);
+        assert_eq!(metrics.opcodes.count(Opcode::Halt), 1);
+        assert!(metrics.peak_stack_depth >= 10);
+        // `total`, `s`, and the string's length and characters
+        assert_eq!(metrics.peak_memory_cells, vm.get_memory().len());
+        assert!(metrics.peak_memory_cells >= 6);
+        assert!(metrics.elapsed > std::time::Duration::ZERO);
+
+        vm.reset();
+        assert_eq!(vm.metrics(), &metrics::Metrics::default());
+        assert!(vm.get_memory().is_empty());
+        vm.run().unwrap();
+        assert_eq!(
+            vm.metrics().instructions_retired,
+            metrics.instructions_retired
+        );
+        assert_eq!(vm.get_memory()[&0], 55);
+
+        // A fault is counted as executed, not retired
+        let mut vm = VM::new(vec![Opcode::Pop as u8], 10);
+        assert!(vm.run().is_err());
+        assert_eq!(vm.metrics().opcodes.count(Opcode::Pop), 1);
+        assert_eq!(vm.metrics().instructions_retired, 0);
+    }
+
+    #[test]
+    fn test_type_checking() {
+        let program = crate::bytecode![push 1, inttofloat, push 2, add, halt];
+        let mut vm = VM::new(program.clone(), 100);
+        vm.set_type_checking(true);
+        assert!(matches!(
+            vm.run(),
+            Err(VMError::TypeMismatch {
+                opcode: Opcode::Add,
+                expected: "int",
+                found: Kind::Float,
+            })
+        ));
+        // Off by default, when the float's bits are added as an integer
+        let mut vm = VM::new(program, 100);
+        vm.run().unwrap();
+
+        // Kinds are carried through memory
+        let statements = Parser::new("let s = \"a\" + \"b\"; let f = float(3) / 2.0;")
+            .parse_program()
+            .unwrap();
+        let mut bytecode = Compiler::new().compile(statements).unwrap();
+        bytecode.pop();
+        let load_s = crate::bytecode![push 0, load, push 1, load, halt];
+        let mut vm = VM::new(bytecode.clone(), 100);
+        vm.set_type_checking(true);
+        vm.run().unwrap();
+        vm.load_program(load_s, 0);
+        vm.run().unwrap();
+        assert_eq!(
+            vm.stack_values(),
+            [Value::Str("ab".to_string()), Value::Float(1.5)]
+        );
+        let mut vm = VM::new(bytecode, 100);
+        vm.set_type_checking(true);
+        vm.run().unwrap();
+        vm.load_program(crate::bytecode![push 1, load, print, halt], 0);
+        let error = vm.run().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Type mismatch in Print: expected int, found float"
+        );
+    }
+
+    #[test]
+    fn test_type_checking_accepts_compiled_programs() {
+        for entry in std::fs::read_dir("tests/golden").unwrap() {
+            let path = entry.unwrap().path();
+            let Ok(statements) = crate::compiler::ModuleLoader::new().load(&path) else {
+                continue;
+            };
+            let Ok(bytecode) = Compiler::new().compile(statements) else {
+                continue;
+            };
+            let mut vm = VM::new(bytecode, 1000);
+            vm.set_output(std::io::sink());
+            vm.set_type_checking(true);
+            if let Err(error @ VMError::TypeMismatch { .. }) = vm.run() {
+                panic!("{}: {}", path.display(), error);
+            }
+        }
+    }
 }
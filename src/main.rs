@@ -0,0 +1,937 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use clap::{Args, Parser, Subcommand};
+#[cfg(feature = "tui")]
+use simple_vm::tui;
+use simple_vm::{
+    analysis, asm, bytecode,
+    compiler::{self, Compiler, DebugInfo, ModuleLoader, OptLevel, Span},
+    debugger, diff,
+    differential::{self, Engine},
+    disasm,
+    profile::HotspotProfile,
+    repl::Repl,
+    svb::{self, SvbFile},
+    VMError, VM,
+};
+
+/// Operand stack slots, and call depth, of the VM running a program.
+const STACK_LIMIT: usize = 1024;
+
+/// Rows of the `--hotspots` table.
+const HOTSPOTS_SHOWN: usize = 10;
+
+/// How often `run --watch` looks for changes while the program is not
+/// running.
+const WATCH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Instructions a watched program runs between looks for changes.
+const WATCH_SLICE: u64 = 100_000;
+
+/// What errors call a program read from stdin.
+const STDIN_NAME: &str = "<stdin>";
+
+/// What errors call a program given with `run -e`.
+const EVAL_NAME: &str = "<eval>";
+
+/// Exit status when a program does not compile, `EX_DATAERR` in sysexits.h.
+const COMPILE_ERROR_STATUS: u8 = 65;
+
+/// Exit status when a program faults at runtime, `EX_SOFTWARE` in sysexits.h.
+const RUNTIME_ERROR_STATUS: u8 = 70;
+
+/// Compiles and runs simple-vm programs.
+#[derive(Parser)]
+#[command(name = "simple-vm", version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compiles a program and runs it
+    Run {
+        /// The program, or `-` to read its source from stdin, which leaves
+        /// the program no input to `read`
+        #[arg(required_unless_present = "eval")]
+        file: Option<PathBuf>,
+        /// Runs CODE as the program instead of a file
+        #[arg(short, long, value_name = "CODE", conflicts_with_all = ["file", "watch"])]
+        eval: Option<String>,
+        #[command(flatten)]
+        options: CompileOptions,
+        #[command(flatten)]
+        run: RunOptions,
+        /// Runs the program again whenever it, or a module it imports,
+        /// changes, until interrupted
+        #[arg(long)]
+        watch: bool,
+        /// With `--watch`, loads the changed program into the running VM,
+        /// keeping its memory, instead of starting a new one; the program
+        /// runs from its start, so its `let`s re-initialize the globals
+        #[arg(long, requires = "watch")]
+        hot_swap: bool,
+        /// Runs the program on the interpreter and on ENGINE (`verified` or
+        /// `stepped`) with the same input, failing if they disagree, then
+        /// prints its output
+        #[arg(long, value_name = "ENGINE", conflicts_with = "watch")]
+        differential: Option<Engine>,
+    },
+    /// Compiles a program to a bytecode file
+    Build {
+        file: PathBuf,
+        /// The bytecode file to write, by default the program's path with
+        /// the `.svb` extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Also prints the instructions, each source line ahead of the
+        /// code compiled from it
+        #[arg(long)]
+        listing: bool,
+        #[command(flatten)]
+        options: CompileOptions,
+    },
+    /// Assembles a file of VM instructions to a bytecode file
+    Asm {
+        file: PathBuf,
+        /// The bytecode file to write, by default the source's path with
+        /// the `.svb` extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Runs a bytecode file written by `build` or `asm`
+    Exec {
+        file: PathBuf,
+        #[command(flatten)]
+        run: RunOptions,
+    },
+    /// Lists the instructions of a bytecode file
+    Disasm { file: PathBuf },
+    /// Compares the instructions of two bytecode files, showing what
+    /// changed besides addresses that only moved, and fails if anything did
+    Diff { old: PathBuf, new: PathBuf },
+    /// Reports a program's, or a bytecode file's, size by section,
+    /// instruction mix, jump targets, maximum stack depth and unreachable
+    /// code
+    Analyze {
+        file: PathBuf,
+        #[command(flatten)]
+        options: CompileOptions,
+    },
+    /// Checks programs for likely mistakes, such as shadowed variables or
+    /// constant conditions, without compiling them
+    Lint {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Reformats a program's source in place, in the canonical style
+    Fmt {
+        file: PathBuf,
+        /// Writes nothing, and fails if the file is not already formatted
+        #[arg(long)]
+        check: bool,
+    },
+    /// Reads statements and expressions from stdin and evaluates them one
+    /// at a time
+    Repl {
+        #[command(flatten)]
+        options: CompileOptions,
+    },
+    /// Debugs a program, or a bytecode file, with commands read from stdin
+    Debug {
+        file: PathBuf,
+        #[command(flatten)]
+        options: CompileOptions,
+    },
+    /// Steps through a program, or a bytecode file, in a terminal debugger
+    #[cfg(feature = "tui")]
+    Tui {
+        file: PathBuf,
+        #[command(flatten)]
+        options: CompileOptions,
+    },
+}
+
+#[derive(Args)]
+struct CompileOptions {
+    /// Optimization level: 0 (none), 1 (default) or 2 (aggressive)
+    #[arg(short = 'O', default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=2))]
+    opt_level: u8,
+    /// Leaves the standard prelude out of the program
+    #[arg(long)]
+    no_prelude: bool,
+}
+
+#[derive(Args)]
+struct RunOptions {
+    /// Prints how many times each opcode ran to stderr once the program
+    /// ends
+    #[arg(long)]
+    profile: bool,
+    /// Prints the source lines that ran the most instructions to stderr
+    /// once the program ends
+    #[arg(long)]
+    hotspots: bool,
+    /// Writes the instructions run in each function and source line to a
+    /// file of folded stacks, for `inferno-flamegraph` or `flamegraph.pl`
+    #[arg(long, value_name = "FILE")]
+    flamegraph: Option<PathBuf>,
+    /// Writes the source lines and functions that ran to an lcov
+    /// tracefile, and prints a summary to stderr
+    #[arg(long, value_name = "FILE")]
+    coverage: Option<PathBuf>,
+}
+
+impl CompileOptions {
+    fn opt_level(&self) -> OptLevel {
+        match self.opt_level {
+            0 => OptLevel::None,
+            1 => OptLevel::Default,
+            _ => OptLevel::Aggressive,
+        }
+    }
+}
+
+/// Why a command did not succeed, which decides the process's exit status.
+enum Failure {
+    /// The program did not compile
+    Compile(String),
+    /// The program faulted, or engines running it disagreed
+    Runtime(String),
+    /// The program called `exit` with a code other than 0
+    Exit(i64),
+    /// Anything else, such as a file that cannot be read or written
+    Other(String),
+}
+
+impl Failure {
+    fn status(&self) -> ExitCode {
+        match self {
+            Failure::Compile(_) => ExitCode::from(COMPILE_ERROR_STATUS),
+            Failure::Runtime(_) => ExitCode::from(RUNTIME_ERROR_STATUS),
+            // Codes a process cannot exit with become 255, as `exit(-1)` does
+            Failure::Exit(code) => ExitCode::from(u8::try_from(*code).unwrap_or(u8::MAX)),
+            Failure::Other(_) => ExitCode::FAILURE,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Compile(message) | Failure::Runtime(message) | Failure::Other(message) => {
+                write!(f, "{}", message)
+            }
+            Failure::Exit(code) => write!(f, "exited with code {}", code),
+        }
+    }
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure::Other(message)
+    }
+}
+
+/// Succeeds if a program's exit code is 0.
+fn exited(code: i64) -> Result<(), Failure> {
+    match code {
+        0 => Ok(()),
+        code => Err(Failure::Exit(code)),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match execute(cli.command) {
+        Ok(()) => ExitCode::SUCCESS,
+        // The program chose its status, and has printed what it had to say
+        Err(failure @ Failure::Exit(_)) => failure.status(),
+        Err(failure) => {
+            // Each of several errors, such as syntax errors, on its own line
+            for line in failure.to_string().lines() {
+                eprintln!("error: {}", line);
+            }
+            failure.status()
+        }
+    }
+}
+
+/// Runs a command. `run` and `exec` exit with the program's exit code, or
+/// with `COMPILE_ERROR_STATUS` or `RUNTIME_ERROR_STATUS` when it fails.
+fn execute(command: Command) -> Result<(), Failure> {
+    match command {
+        Command::Run {
+            file: Some(file),
+            options,
+            run,
+            watch: true,
+            hot_swap,
+            ..
+        } => {
+            if file == Path::new("-") {
+                return Err(Failure::Other(
+                    "cannot watch a program read from stdin".into(),
+                ));
+            }
+            Ok(watch(&file, &options, &run, hot_swap)?)
+        }
+        Command::Run {
+            file,
+            eval,
+            options,
+            differential: Some(engine),
+            ..
+        } => {
+            let (file, mut loader) = program_loader(file, eval)?;
+            let (bytecode, _) =
+                compile_with(&mut loader, &file, &options).map_err(Failure::Compile)?;
+            // Each engine reads its own copy of the input
+            let mut input = Vec::new();
+            if !io::stdin().is_terminal() {
+                io::stdin()
+                    .read_to_end(&mut input)
+                    .map_err(|error| format!("cannot read stdin: {}", error))?;
+            }
+            let snapshot = differential::compare(&bytecode, &input, Engine::Interpreter, engine)
+                .map_err(|error| Failure::Runtime(format!("{}: {}", file.display(), error)))?;
+            print!("{}", snapshot.output);
+            let result = snapshot.result;
+            match result.strip_prefix("exit code ") {
+                Some(code) => exited(code.parse().unwrap_or_default()),
+                None => Err(Failure::Runtime(format!(
+                    "{}: {}",
+                    file.display(),
+                    result.strip_prefix("error: ").unwrap_or(&result)
+                ))),
+            }
+        }
+        Command::Run {
+            file,
+            eval,
+            options,
+            run,
+            ..
+        } => {
+            let (file, mut loader) = program_loader(file, eval)?;
+            let (bytecode, debug_info) =
+                compile_with(&mut loader, &file, &options).map_err(Failure::Compile)?;
+            let mut vm = VM::new(bytecode, STACK_LIMIT);
+            vm.set_debug_info(debug_info);
+            run_vm(&mut vm, &run, &file, VM::run)?;
+            exited(vm.get_exit_code())
+        }
+        Command::Build {
+            file,
+            output,
+            listing,
+            options,
+        } => {
+            let (bytecode, debug_info) = compile_file(&file, &options).map_err(Failure::Compile)?;
+            if listing {
+                // Every file the program was loaded from, by file id
+                let sources = debug_info
+                    .files()
+                    .iter()
+                    .map(|path| {
+                        fs::read_to_string(path)
+                            .map_err(|error| format!("cannot read {}: {}", path, error))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let sources: Vec<&str> = sources.iter().map(String::as_str).collect();
+                print!("{}", disasm::listing(&bytecode, &debug_info, &sources));
+            }
+            let output = output.unwrap_or_else(|| file.with_extension("svb"));
+            Ok(write_svb(
+                &output,
+                &SvbFile::new(&bytecode, Some(debug_info)),
+            )?)
+        }
+        Command::Asm { file, output } => {
+            let source = fs::read_to_string(&file)
+                .map_err(|error| format!("cannot read {}: {}", file.display(), error))?;
+            let bytecode = asm::assemble(&source)
+                .map_err(|error| Failure::Compile(format!("{}: {}", file.display(), error)))?;
+            let output = output.unwrap_or_else(|| file.with_extension("svb"));
+            Ok(write_svb(&output, &SvbFile::new(&bytecode, None))?)
+        }
+        Command::Exec { file, run } => {
+            let svb = read_svb(&file)?;
+            let mut vm = VM::new(svb.program(), STACK_LIMIT);
+            if let Some(debug_info) = svb.debug_info {
+                vm.set_debug_info(debug_info);
+            }
+            // The tracefile names the source the bytecode was built from
+            run_vm(&mut vm, &run, &file.with_extension("svm"), VM::run)?;
+            exited(vm.get_exit_code())
+        }
+        Command::Disasm { file } => {
+            for line in disasm::disassemble(&read_svb(&file)?.program()) {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+        Command::Diff { old, new } => {
+            let difference = diff::bytecode(&read_svb(&old)?.program(), &read_svb(&new)?.program());
+            print!("{}", difference);
+            match difference.changes() {
+                0 => Ok(()),
+                changes => Err(Failure::Other(format!("{} line(s) differ", changes))),
+            }
+        }
+        Command::Analyze { file, options } => {
+            let (svb, _) = load_for_debugging(&file, &options)?;
+            let analysis = analysis::analyze(&svb.program()).map_err(|error| error.to_string())?;
+            print!("{}", analysis);
+            Ok(())
+        }
+        Command::Lint { files } => {
+            let mut problems = 0;
+            for file in files {
+                let source = fs::read_to_string(&file)
+                    .map_err(|error| format!("cannot read {}: {}", file.display(), error))?;
+                for diagnostic in compiler::lint(&source) {
+                    println!("{}:{}", file.display(), diagnostic);
+                    problems += 1;
+                }
+            }
+            match problems {
+                0 => Ok(()),
+                _ => Err(Failure::Other(format!("found {} problem(s)", problems))),
+            }
+        }
+        Command::Fmt { file, check } => {
+            let source = fs::read_to_string(&file)
+                .map_err(|error| format!("cannot read {}: {}", file.display(), error))?;
+            let formatted = compiler::format(&source).map_err(|errors| {
+                let lines: Vec<String> = errors
+                    .iter()
+                    .map(|error| format!("{}:{}", file.display(), error))
+                    .collect();
+                Failure::Compile(lines.join("\n"))
+            })?;
+            if formatted == source {
+                Ok(())
+            } else if check {
+                Err(Failure::Other(format!(
+                    "{} is not formatted",
+                    file.display()
+                )))
+            } else {
+                Ok(fs::write(&file, formatted)
+                    .map_err(|error| format!("cannot write {}: {}", file.display(), error))?)
+            }
+        }
+        Command::Repl { options } => {
+            let mut repl = Repl::new();
+            repl.set_opt_level(options.opt_level());
+            repl.set_prelude(!options.no_prelude);
+            Ok(repl_loop(&mut repl).map_err(|error| error.to_string())?)
+        }
+        Command::Debug { file, options } => {
+            let (svb, _) = load_for_debugging(&file, &options)?;
+            let mut debugger = debugger::Debugger::new(svb.program(), svb.debug_info, STACK_LIMIT);
+            Ok(debug_loop(&mut debugger).map_err(|error| error.to_string())?)
+        }
+        #[cfg(feature = "tui")]
+        Command::Tui { file, options } => {
+            let (svb, source) = load_for_debugging(&file, &options)?;
+            let mut vm = VM::new(svb.program(), STACK_LIMIT);
+            if let Some(debug_info) = svb.debug_info {
+                vm.set_debug_info(debug_info);
+            }
+            // The terminal's input belongs to the debugger
+            vm.set_input(io::empty());
+            let mut terminal = ratatui::init();
+            let result = tui::Debugger::new(vm, source).run(&mut terminal);
+            ratatui::restore();
+            Ok(result.map_err(|error| error.to_string())?)
+        }
+    }
+}
+
+/// Runs a program compiled from `source` with `execute`, then reports on it
+/// as `options` ask. A runtime error comes with its source location, if
+/// known.
+fn run_vm(
+    vm: &mut VM,
+    options: &RunOptions,
+    source: &Path,
+    execute: impl FnOnce(&mut VM) -> Result<(), VMError>,
+) -> Result<(), Failure> {
+    vm.set_profiling(options.profile);
+    vm.set_hotspot_profiling(options.hotspots || options.flamegraph.is_some());
+    vm.set_coverage(options.coverage.is_some());
+    let result = execute(vm).map_err(|error| {
+        Failure::Runtime(match vm.current_span() {
+            Some(span) => format!("{}: {}", locate(span, vm.debug_info()), error),
+            None => error.to_string(),
+        })
+    });
+    if let Some(profile) = vm.profile() {
+        eprint!("{}", profile);
+    }
+    if let Some(hotspots) = vm.hotspots() {
+        if options.hotspots {
+            eprint!("{}", hotspot_table(hotspots, vm.debug_info()));
+        }
+        if let Some(path) = &options.flamegraph {
+            fs::write(path, hotspots.folded(vm.debug_info()))
+                .map_err(|error| format!("cannot write {}: {}", path.display(), error))?;
+        }
+    }
+    if let (Some(coverage), Some(path)) = (vm.coverage(), &options.coverage) {
+        let (covered, total) = coverage.instructions_covered();
+        eprintln!("coverage: {}/{} instructions", covered, total);
+        let debug_info = vm.debug_info().ok_or_else(|| {
+            Failure::Other("line coverage needs a program built with debug info".to_string())
+        })?;
+        let lines = coverage.lines(debug_info);
+        let hit = lines.iter().filter(|(.., hits)| *hits > 0).count();
+        eprintln!("coverage: {}/{} lines", hit, lines.len());
+        let report = coverage.lcov(debug_info, &source.display().to_string());
+        fs::write(path, report)
+            .map_err(|error| format!("cannot write {}: {}", path.display(), error))?;
+    }
+    result
+}
+
+/// Runs a program, and again each time it or a module it imports changes.
+/// A program still running when a file changes is stopped. Errors are
+/// printed and the watch goes on.
+///
+/// With `hot_swap`, each new version is loaded into the same VM, which
+/// keeps its memory and heap. The new version still runs from its start,
+/// not from where the old one ended as a REPL input would, because it is
+/// a whole program rather than code appended to the old one: its top-level
+/// `let`s re-initialize the globals, and only what it does not overwrite,
+/// such as the arrays and strings the old globals pointed to, carries over.
+/// The stack and calls start afresh either way.
+fn watch(
+    file: &Path,
+    options: &CompileOptions,
+    run: &RunOptions,
+    hot_swap: bool,
+) -> Result<(), String> {
+    let mut files = WatchedFiles::default();
+    files.add(file);
+    let mut vm: Option<VM> = None;
+    loop {
+        let mut loader = ModuleLoader::new();
+        let compiled = compile_with(&mut loader, file, options);
+        for import in loader.imports() {
+            files.add(import);
+        }
+        let mut changed = false;
+        match compiled {
+            Ok((bytecode, debug_info)) => {
+                let vm = match &mut vm {
+                    Some(vm) if hot_swap => {
+                        let start = bytecode::read_header(&bytecode).map_or(0, |(_, start)| start);
+                        vm.load_program(bytecode, start);
+                        vm
+                    }
+                    _ => vm.insert(VM::new(bytecode, STACK_LIMIT)),
+                };
+                vm.set_debug_info(debug_info);
+                let result = run_vm(vm, run, file, |vm| {
+                    while vm.run_for(WATCH_SLICE)? {
+                        if files.changed() {
+                            changed = true;
+                            break;
+                        }
+                    }
+                    Ok(())
+                });
+                if let Err(message) = result {
+                    eprintln!("error: {}", message);
+                }
+            }
+            Err(message) => eprintln!("error: {}", message),
+        }
+        while !changed {
+            thread::sleep(WATCH_INTERVAL);
+            changed = files.changed();
+        }
+        let action = if hot_swap { "reloading" } else { "restarting" };
+        eprintln!("[{} changed, {}]", file.display(), action);
+    }
+}
+
+/// The files of a watched program, with when each was last modified.
+#[derive(Default)]
+struct WatchedFiles {
+    modified: HashMap<PathBuf, Option<SystemTime>>,
+}
+
+impl WatchedFiles {
+    /// Watches `path`, if it is not already watched.
+    fn add(&mut self, path: &Path) {
+        if !self.modified.contains_key(path) {
+            self.modified.insert(path.to_path_buf(), modified(path));
+        }
+    }
+
+    /// Whether any file was modified, created or deleted since the last
+    /// call.
+    fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, time) in &mut self.modified {
+            let now = modified(path);
+            if now != *time {
+                *time = now;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// The source lines that ran the most instructions, or the instructions
+/// that ran most for a program without debug info.
+fn hotspot_table(hotspots: &HotspotProfile, debug_info: Option<&DebugInfo>) -> String {
+    let (heading, rows) = match debug_info {
+        Some(debug_info) => ("line", hotspots.by_line(debug_info)),
+        None => ("pc", hotspots.by_pc()),
+    };
+    let total: u64 = rows.iter().map(|(_, count)| count).sum();
+    let mut table = format!("{:<8} {:>12} {:>8}\n", heading, "count", "%");
+    for (at, count) in rows.into_iter().take(HOTSPOTS_SHOWN) {
+        let share = count as f64 * 100.0 / total as f64;
+        table.push_str(&format!("{:<8} {:>12} {:>7.2}%\n", at, count, share));
+    }
+    table
+}
+
+/// Evaluates the inputs read from stdin until it ends. An input whose
+/// braces are not balanced at the end of a line continues on the next one.
+fn repl_loop(repl: &mut Repl) -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { ">> " } else { ".. " });
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        input.push_str(&line);
+        if brace_depth(&input) > 0 {
+            continue;
+        }
+        if let Err(error) = repl.eval(&input) {
+            eprintln!("error: {}", error);
+        }
+        input.clear();
+    }
+}
+
+/// Runs debugger commands read from stdin until `quit` or the end of
+/// stdin. An empty line repeats the last command, as in gdb.
+fn debug_loop(debugger: &mut debugger::Debugger) -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut last = String::new();
+    loop {
+        print!("(simple-vm) ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        let command = match line.trim() {
+            "" => last.clone(),
+            "quit" | "q" => return Ok(()),
+            command => command.to_string(),
+        };
+        match debugger.execute(&command) {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(error) => eprintln!("error: {}", error),
+        }
+        last = command;
+    }
+}
+
+/// Number of `{` not yet closed by a `}`, outside string and character
+/// literals.
+fn brace_depth(input: &str) -> i64 {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut chars = input.chars();
+    while let Some(ch) = chars.next() {
+        match (quote, ch) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(open), _) if ch == open => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(ch),
+            (None, '{') => depth += 1,
+            (None, '}') => depth -= 1,
+            _ => {}
+        }
+    }
+    depth
+}
+
+/// The program `run` compiles, from a file, stdin for `-` or `-e`, as the
+/// path errors name it by and a loader that reads it. A program not read
+/// from a file imports modules relative to the working directory.
+fn program_loader(
+    file: Option<PathBuf>,
+    eval: Option<String>,
+) -> Result<(PathBuf, ModuleLoader), String> {
+    let (name, source) = match (file, eval) {
+        (_, Some(code)) => (EVAL_NAME, code),
+        (Some(file), None) if file == Path::new("-") => {
+            let mut source = String::new();
+            io::stdin()
+                .read_to_string(&mut source)
+                .map_err(|error| format!("cannot read stdin: {}", error))?;
+            (STDIN_NAME, source)
+        }
+        (Some(file), None) => return Ok((file, ModuleLoader::new())),
+        (None, None) => unreachable!("clap requires a file or -e"),
+    };
+    let loader = ModuleLoader::with_reader(move |path| {
+        if path == Path::new(name) {
+            Ok(source.clone())
+        } else {
+            fs::read_to_string(path)
+        }
+    });
+    Ok((PathBuf::from(name), loader))
+}
+
+/// Loads a program with the modules it imports and compiles it with debug
+/// info, printing its warnings.
+fn compile_file(path: &Path, options: &CompileOptions) -> Result<(Vec<u8>, DebugInfo), String> {
+    compile_with(&mut ModuleLoader::new(), path, options)
+}
+
+/// Like `compile_file`, loading the program with `loader`.
+fn compile_with(
+    loader: &mut ModuleLoader,
+    path: &Path,
+    options: &CompileOptions,
+) -> Result<(Vec<u8>, DebugInfo), String> {
+    let statements = loader.load(path)?;
+    let mut compiler = Compiler::new();
+    compiler.set_opt_level(options.opt_level());
+    compiler.set_prelude(!options.no_prelude);
+    compiler.set_debug_info(true);
+    let bytecode = compiler
+        .compile(statements)
+        .map_err(|error| loader.locate(&error))?;
+    for warning in compiler.warnings() {
+        eprintln!("{}:{}", path.display(), warning);
+    }
+    let mut debug_info = compiler.debug_info().cloned().unwrap_or_default();
+    debug_info.set_files(
+        loader
+            .files()
+            .iter()
+            .map(|file| file.display().to_string())
+            .collect(),
+    );
+    Ok((bytecode, debug_info))
+}
+
+/// Where `span` is, as `file:line:col`, with the file looked up in the
+/// program's debug info, or as `line:col` if it is not recorded there.
+fn locate(span: Span, debug_info: Option<&DebugInfo>) -> String {
+    match debug_info.and_then(|debug_info| debug_info.file(span.file)) {
+        Some(file) => format!("{}:{}", file, span),
+        None => span.to_string(),
+    }
+}
+
+/// Loads a program to debug: a source file, compiled with debug info, or
+/// a bytecode file, with the debug info it was built with if any. Only a
+/// source file comes with its source.
+fn load_for_debugging(
+    path: &Path,
+    options: &CompileOptions,
+) -> Result<(SvbFile, Option<String>), String> {
+    if path.extension().is_some_and(|ext| ext == "svm") {
+        let (bytecode, debug_info) = compile_file(path, options)?;
+        let source = fs::read_to_string(path)
+            .map_err(|error| format!("cannot read {}: {}", path.display(), error))?;
+        Ok((SvbFile::new(&bytecode, Some(debug_info)), Some(source)))
+    } else {
+        Ok((read_svb(path)?, None))
+    }
+}
+
+fn write_svb(path: &Path, svb: &SvbFile) -> Result<(), String> {
+    fs::write(path, svb.to_bytes())
+        .map_err(|error| format!("cannot write {}: {}", path.display(), error))
+}
+
+/// Reads a `.svb` file, or a file of bare bytecode.
+fn read_svb(path: &Path) -> Result<SvbFile, String> {
+    let bytes =
+        fs::read(path).map_err(|error| format!("cannot read {}: {}", path.display(), error))?;
+    if !bytes.starts_with(&svb::MAGIC) {
+        return Ok(SvbFile::new(&bytes, None));
+    }
+    SvbFile::from_bytes(&bytes).map_err(|error| format!("{}: {}", path.display(), error))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::path::Path;
+    use std::process::ExitCode;
+
+    use simple_vm::{compiler::ModuleLoader, VM};
+
+    use super::{
+        brace_depth, compile_with, exited, locate, program_loader, Cli, Command, CompileOptions,
+        Failure, COMPILE_ERROR_STATUS, EVAL_NAME, RUNTIME_ERROR_STATUS, STACK_LIMIT,
+    };
+    use clap::{CommandFactory, Parser};
+    use simple_vm::compiler;
+
+    #[test]
+    fn parses_subcommands_and_options() {
+        Cli::command().debug_assert();
+        let cli =
+            Cli::try_parse_from(["simple-vm", "build", "a.svm", "-O2", "--no-prelude"]).unwrap();
+        let Command::Build {
+            file,
+            output,
+            listing,
+            options,
+        } = cli.command
+        else {
+            panic!("expected build");
+        };
+        assert_eq!(file.to_str(), Some("a.svm"));
+        assert_eq!(output, None);
+        assert!(!listing);
+        assert_eq!(options.opt_level(), compiler::OptLevel::Aggressive);
+        assert!(options.no_prelude);
+        assert!(Cli::try_parse_from(["simple-vm", "run", "a.svm", "-O3"]).is_err());
+
+        let cli = Cli::try_parse_from(["simple-vm", "run", "--watch", "--hot-swap", "a.svm"]);
+        assert!(matches!(
+            cli.unwrap().command,
+            Command::Run {
+                watch: true,
+                hot_swap: true,
+                ..
+            }
+        ));
+        assert!(Cli::try_parse_from(["simple-vm", "lint"]).is_err());
+        let cli = Cli::try_parse_from(["simple-vm", "fmt", "--check", "a.svm"]).unwrap();
+        assert!(matches!(cli.command, Command::Fmt { check: true, .. }));
+        // Hot-swapping only applies to a watched program
+        assert!(Cli::try_parse_from(["simple-vm", "run", "--hot-swap", "a.svm"]).is_err());
+
+        let cli = Cli::try_parse_from(["simple-vm", "run", "-e", "print 1 + 2;"]).unwrap();
+        let Command::Run { file, eval, .. } = cli.command else {
+            panic!("expected run");
+        };
+        assert_eq!(file, None);
+        assert_eq!(eval.as_deref(), Some("print 1 + 2;"));
+        assert!(Cli::try_parse_from(["simple-vm", "run", "-"]).is_ok());
+        assert!(Cli::try_parse_from(["simple-vm", "run"]).is_err());
+        assert!(Cli::try_parse_from(["simple-vm", "run", "-e", "print 1;", "a.svm"]).is_err());
+        assert!(Cli::try_parse_from(["simple-vm", "run", "--watch", "-e", "print 1;"]).is_err());
+    }
+
+    #[test]
+    fn compiles_programs_given_with_eval() {
+        let options = CompileOptions {
+            opt_level: 1,
+            no_prelude: false,
+        };
+        let (path, mut loader) = program_loader(None, Some("print gcd(12, 18);".into())).unwrap();
+        assert_eq!(path.to_str(), Some(EVAL_NAME));
+        assert!(compile_with(&mut loader, &path, &options).is_ok());
+
+        let (path, mut loader) = program_loader(None, Some("print ;".into())).unwrap();
+        let error = compile_with(&mut loader, &path, &options).unwrap_err();
+        assert_eq!(error, "<eval>:1:7: Expected expression, found ';'");
+
+        let (path, mut loader) = program_loader(None, Some("print 1 + y;".into())).unwrap();
+        let error = compile_with(&mut loader, &path, &options).unwrap_err();
+        assert_eq!(error, "<eval>:1:11: Undefined variable 'y'");
+
+        let mut loader = ModuleLoader::with_reader(|path| match path.to_str() {
+            Some("main.svm") => Ok("import \"lib.svm\";\nprint f();".to_string()),
+            Some("lib.svm") => Ok("fn f() {\n    return \"a\" - 1;\n}".to_string()),
+            _ => Err(io::ErrorKind::NotFound.into()),
+        });
+        let error = compile_with(&mut loader, Path::new("main.svm"), &options).unwrap_err();
+        assert_eq!(
+            error,
+            "lib.svm:2:12: Cannot apply Sub to values of type string and int"
+        );
+    }
+
+    #[test]
+    fn locates_runtime_errors_in_the_module_they_happen_in() {
+        let options = CompileOptions {
+            opt_level: 1,
+            no_prelude: false,
+        };
+        let mut loader = ModuleLoader::with_reader(|path| match path.to_str() {
+            Some("main.svm") => Ok("import \"lib.svm\";\nprint half(0);".to_string()),
+            Some("lib.svm") => Ok("fn half(n) {\n    return 1 / n;\n}".to_string()),
+            _ => Err(io::ErrorKind::NotFound.into()),
+        });
+        let (bytecode, debug_info) =
+            compile_with(&mut loader, Path::new("main.svm"), &options).unwrap();
+        assert_eq!(debug_info.files(), ["main.svm", "lib.svm"]);
+        let mut vm = VM::new(bytecode, STACK_LIMIT);
+        vm.set_debug_info(debug_info);
+        assert!(vm.run().is_err());
+        let span = vm.current_span().unwrap();
+        assert_eq!(locate(span, vm.debug_info()), "lib.svm:2:12");
+        assert_eq!(locate(span, None), "2:12");
+    }
+
+    #[test]
+    fn continues_inputs_with_open_braces() {
+        assert_eq!(brace_depth("fn f(x) {"), 1);
+        assert_eq!(brace_depth("fn f(x) { return x; }"), 0);
+        assert_eq!(brace_depth("if x > 0 { print(\"{} {{\", x);"), 1);
+        assert_eq!(brace_depth("let c = '{';"), 0);
+    }
+
+    #[test]
+    fn exits_with_the_program_code_or_the_kind_of_failure() {
+        assert!(exited(0).is_ok());
+        let status = |failure: Failure| failure.status();
+        assert_eq!(status(exited(3).unwrap_err()), ExitCode::from(3));
+        assert_eq!(status(Failure::Exit(-1)), ExitCode::from(255));
+        assert_eq!(status(Failure::Exit(256)), ExitCode::from(255));
+        assert_eq!(
+            status(Failure::Compile(String::new())),
+            ExitCode::from(COMPILE_ERROR_STATUS)
+        );
+        assert_eq!(
+            status(Failure::Runtime(String::new())),
+            ExitCode::from(RUNTIME_ERROR_STATUS)
+        );
+        assert_eq!(status(Failure::Other(String::new())), ExitCode::FAILURE);
+    }
+}
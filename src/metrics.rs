@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use crate::{profile::OpcodeProfile, Opcode};
+
+/// Counters a `VM` keeps about the programs it runs, for hosts to publish
+/// to their monitoring. They add up across `run`s and `load_program`, and
+/// start over on `VM::reset`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// Instructions that completed without a fault
+    pub instructions_retired: u64,
+    /// Executions of each opcode, an instruction that faulted included
+    pub opcodes: OpcodeProfile,
+    /// Most values the operand stack held between two instructions
+    pub peak_stack_depth: usize,
+    /// Most memory cells written to at once
+    pub peak_memory_cells: usize,
+    /// Time spent executing instructions, in `run`, `resume` and the step
+    /// methods
+    pub elapsed: Duration,
+}
+
+impl Metrics {
+    pub(crate) fn execute(&mut self, opcode: Opcode) {
+        self.opcodes.record(opcode);
+    }
+
+    pub(crate) fn retire(&mut self, stack_depth: usize, memory_cells: usize) {
+        self.instructions_retired += 1;
+        self.peak_stack_depth = self.peak_stack_depth.max(stack_depth);
+        self.peak_memory_cells = self.peak_memory_cells.max(memory_cells);
+    }
+}
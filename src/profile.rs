@@ -0,0 +1,289 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write};
+
+use crate::{compiler::DebugInfo, Opcode};
+
+/// How many times each opcode ran, collected by a `VM` with profiling
+/// enabled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpcodeProfile {
+    /// Executions, indexed by opcode byte
+    counts: [u64; 256],
+}
+
+impl OpcodeProfile {
+    pub fn new() -> Self {
+        OpcodeProfile { counts: [0; 256] }
+    }
+
+    pub(crate) fn record(&mut self, opcode: Opcode) {
+        self.counts[opcode as usize] += 1;
+    }
+
+    /// How many times `opcode` ran.
+    pub fn count(&self, opcode: Opcode) -> u64 {
+        self.counts[opcode as usize]
+    }
+
+    /// How many instructions ran.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The opcodes that ran with their counts, most frequent first, ties in
+    /// opcode order.
+    pub fn sorted(&self) -> Vec<(Opcode, u64)> {
+        let mut counts: Vec<(Opcode, u64)> = (0..=255u8)
+            .filter(|&byte| self.counts[byte as usize] > 0)
+            .filter_map(|byte| Some((Opcode::try_from(byte).ok()?, self.counts[byte as usize])))
+            .collect();
+        counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        counts
+    }
+}
+
+impl Default for OpcodeProfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Formats the profile as a table of opcodes, most frequent first, with
+/// their counts and share of all instructions run.
+impl fmt::Display for OpcodeProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total();
+        writeln!(f, "{:<12} {:>12} {:>8}", "opcode", "count", "%")?;
+        for (opcode, count) in self.sorted() {
+            let name = format!("{:?}", opcode).to_lowercase();
+            let share = count as f64 * 100.0 / total as f64;
+            writeln!(f, "{:<12} {:>12} {:>7.2}%", name, count, share)?;
+        }
+        writeln!(f, "{:<12} {:>12} {:>7.2}%", "total", total, 100.0)
+    }
+}
+
+/// How many times each instruction ran, and in which calls, collected by a
+/// `VM` with hotspot profiling enabled.
+///
+/// Hits are kept per call path: the entry points of the active calls, from
+/// the outermost in. Paths are interned in a tree, so recording a hit costs
+/// a hash map update whatever the call depth.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HotspotProfile {
+    /// The parent and entry point of each call path; path 0 is the top
+    /// level
+    paths: Vec<(usize, usize)>,
+    path_ids: HashMap<(usize, usize), usize>,
+    /// The path of each active call, outermost first
+    active: Vec<usize>,
+    /// Hits per call path and instruction address
+    hits: HashMap<(usize, usize), u64>,
+}
+
+impl HotspotProfile {
+    pub fn new() -> Self {
+        HotspotProfile {
+            paths: vec![(0, 0)],
+            ..Default::default()
+        }
+    }
+
+    /// Counts a hit of the instruction at `pc`, run with `depth` calls
+    /// active. A call deeper than the last hit's entered a function at
+    /// `pc`.
+    pub(crate) fn record(&mut self, pc: usize, depth: usize) {
+        self.active.truncate(depth);
+        while self.active.len() < depth {
+            let parent = self.active.last().copied().unwrap_or(0);
+            let next = self.paths.len();
+            let id = *self.path_ids.entry((parent, pc)).or_insert(next);
+            if id == next {
+                self.paths.push((parent, pc));
+            }
+            self.active.push(id);
+        }
+        let path = self.active.last().copied().unwrap_or(0);
+        *self.hits.entry((path, pc)).or_insert(0) += 1;
+    }
+
+    /// How many times the instruction at `pc` ran.
+    pub fn hits(&self, pc: usize) -> u64 {
+        self.hits
+            .iter()
+            .filter(|((_, hit), _)| *hit == pc)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// The addresses of the instructions that ran with their hits, most hit
+    /// first, ties in address order.
+    pub fn by_pc(&self) -> Vec<(usize, u64)> {
+        let mut pcs = BTreeMap::new();
+        for (&(_, pc), count) in &self.hits {
+            *pcs.entry(pc).or_insert(0) += count;
+        }
+        sorted(pcs)
+    }
+
+    /// The source lines whose code ran with the instructions run for them,
+    /// most first, ties in line order. Code without a span in `debug_info`,
+    /// such as the prelude, is left out.
+    pub fn by_line(&self, debug_info: &DebugInfo) -> Vec<(usize, u64)> {
+        let mut lines = BTreeMap::new();
+        for (&(_, pc), count) in &self.hits {
+            if let Some(span) = debug_info.span_at(pc) {
+                *lines.entry(span.line).or_insert(0) += count;
+            }
+        }
+        sorted(lines)
+    }
+
+    /// The profile as folded stacks, the input of `inferno-flamegraph` and
+    /// `flamegraph.pl`: a line per call path and source line, such as
+    /// `(top level);main;square;line 2 17`, with the instructions run there.
+    /// Functions are named from `debug_info`, and code without a span in it
+    /// is `(no source)`. Without debug info, functions show as their entry
+    /// point and lines as the address of each instruction.
+    pub fn folded(&self, debug_info: Option<&DebugInfo>) -> String {
+        let mut stacks = BTreeMap::new();
+        for (&(path, pc), count) in &self.hits {
+            let mut frames = Vec::new();
+            let mut id = path;
+            while id != 0 {
+                let (parent, entry) = self.paths[id];
+                frames.push(
+                    debug_info
+                        .and_then(|info| info.function_at(entry))
+                        .map_or_else(|| format!("{:06}", entry), str::to_string),
+                );
+                id = parent;
+            }
+            frames.push("(top level)".to_string());
+            frames.reverse();
+            frames.push(match debug_info.map(|info| info.span_at(pc)) {
+                Some(Some(span)) => format!("line {}", span.line),
+                Some(None) => "(no source)".to_string(),
+                None => format!("{:06}", pc),
+            });
+            *stacks.entry(frames.join(";")).or_insert(0) += count;
+        }
+        let mut folded = String::new();
+        for (stack, count) in stacks {
+            writeln!(folded, "{} {}", stack, count).unwrap();
+        }
+        folded
+    }
+}
+
+/// The entries of a map, most first.
+fn sorted(counts: BTreeMap<usize, u64>) -> Vec<(usize, u64)> {
+    let mut counts: Vec<(usize, u64)> = counts.into_iter().collect();
+    counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OpcodeProfile;
+    use crate::{
+        compiler::{Compiler, OptLevel, Parser},
+        Opcode, VM,
+    };
+
+    #[test]
+    fn counts_opcodes_run_by_the_vm() {
+        let statements = Parser::new("let i = 0; while i < 3 { i = i + 1; }")
+            .parse_program()
+            .unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.set_opt_level(OptLevel::None);
+        let mut vm = VM::new(compiler.compile(statements).unwrap(), 16);
+        assert!(vm.profile().is_none());
+        vm.set_profiling(true);
+        vm.run().unwrap();
+
+        let profile = vm.profile().unwrap();
+        // The condition runs 4 times, the body 3
+        assert_eq!(profile.count(Opcode::Less), 4);
+        assert_eq!(profile.count(Opcode::Add), 3);
+        assert_eq!(profile.count(Opcode::Halt), 1);
+        assert_eq!(profile.sorted()[0].0, Opcode::Push);
+        let total = profile.total();
+        assert_eq!(total, profile.sorted().iter().map(|(_, n)| n).sum::<u64>());
+
+        let table = profile.to_string();
+        let mut lines = table.lines();
+        assert_eq!(
+            lines.next().unwrap().split_whitespace().collect::<Vec<_>>(),
+            ["opcode", "count", "%"]
+        );
+        assert!(lines.next().unwrap().starts_with("push "));
+        assert_eq!(
+            table.lines().last().unwrap(),
+            format!("{:<12} {:>12} {:>7.2}%", "total", total, 100.0)
+        );
+    }
+
+    #[test]
+    fn formats_an_empty_profile() {
+        let profile = OpcodeProfile::new();
+        assert_eq!(profile.total(), 0);
+        assert_eq!(profile.to_string().lines().count(), 2);
+    }
+
+    #[test]
+    fn attributes_hits_to_calls_and_lines() {
+        let source = "fn square(n) {\n\
+                      \x20   return n * n;\n\
+                      }\n\
+                      let i = 0;\n\
+                      while i < 3 {\n\
+                      \x20   i = i + square(1);\n\
+                      }\n\
+                      let f = fn(x) { return square(x); };\n\
+                      let y = f(2);\n";
+        let statements = Parser::new(source).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.set_opt_level(OptLevel::None);
+        compiler.set_debug_info(true);
+        let mut vm = VM::new(compiler.compile(statements).unwrap(), 16);
+        let debug_info = compiler.debug_info().unwrap();
+        vm.set_hotspot_profiling(true);
+        vm.set_profiling(true);
+        vm.run().unwrap();
+        let hotspots = vm.hotspots().unwrap();
+
+        let square = debug_info.line_start(2).unwrap();
+        assert_eq!(hotspots.hits(square), 4);
+        let total: u64 = hotspots.by_pc().iter().map(|(_, n)| n).sum();
+        assert_eq!(total, vm.profile().unwrap().total());
+        let lines = hotspots.by_line(debug_info);
+        // The loop condition runs most; each of the 4 calls of `square`
+        // runs 6 instructions on line 2
+        assert_eq!(lines[0].0, 5);
+        assert!(lines.contains(&(2, 24)), "{:?}", lines);
+
+        let folded = hotspots.folded(Some(debug_info));
+        let stacks: Vec<&str> = folded
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0)
+            .collect();
+        assert!(stacks.contains(&"(top level);square;line 2"), "{}", folded);
+        assert!(
+            stacks.contains(&"(top level);closure@8:9;square;line 2"),
+            "{}",
+            folded
+        );
+        assert!(stacks.contains(&"(top level);line 6"), "{}", folded);
+        let counts: u64 = folded
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().1.parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(counts, total);
+        // Without debug info, functions and lines show as addresses
+        assert!(hotspots.folded(None).starts_with("(top level);000"));
+    }
+}
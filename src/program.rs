@@ -0,0 +1,666 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::bytecode::{self, Instruction, OperandEncoding};
+use crate::compiler::{CompileError, Compiler, DebugInfo, ModuleLoader, Span};
+use crate::stack_depth::StackError;
+use crate::svb::{self, SvbError, SvbFile};
+use crate::{Opcode, VMError, VM};
+
+#[derive(Debug, Error)]
+pub enum ProgramError {
+    #[error("{}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    /// A module that cannot be read or parsed, with the path in the message
+    #[error("{0}")]
+    Load(String),
+    /// A program that does not compile, with the file of the module the
+    /// error is in
+    #[error("{}:{source}", path.display())]
+    Compile {
+        path: PathBuf,
+        source: Box<CompileError>,
+    },
+    #[error("{}: {source}", path.display())]
+    Svb { path: PathBuf, source: SvbError },
+    #[error("{source}")]
+    Unverified {
+        #[from]
+        source: StackError,
+    },
+    /// The program faulted, at `span` if it was compiled with debug info
+    #[error("{}{}: {source}", path.display(), span.map(|span| format!(":{}", span)).unwrap_or_default())]
+    Runtime {
+        path: PathBuf,
+        span: Option<Span>,
+        source: VMError,
+    },
+    #[error("{}: still running after {limit} instructions", path.display())]
+    InstructionLimit { path: PathBuf, limit: u64 },
+    /// Bytecode that does not decode, so cannot be written as JSON
+    #[error("{0}")]
+    Decode(VMError),
+    #[error("Invalid JSON program: {0}")]
+    Json(String),
+}
+
+/// How `Program::vm` and `run_file` set up the VM a program runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmConfig {
+    /// Operand stack slots, and call depth, as with `VM::new`
+    pub stack_limit: usize,
+    /// Instructions the program may run before it is stopped, or `None`
+    /// to run it to the end
+    pub instruction_limit: Option<u64>,
+    /// Checks with `stack_depth::verify` that the program stays within
+    /// `stack_limit`, and runs it without stack checks
+    pub verified: bool,
+}
+
+impl Default for VmConfig {
+    /// The configuration `simple-vm run` uses.
+    fn default() -> Self {
+        VmConfig {
+            stack_limit: 1024,
+            instruction_limit: None,
+            verified: false,
+        }
+    }
+}
+
+/// Compiled bytecode, with the debug info it was compiled with if any.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Program {
+    pub bytecode: Vec<u8>,
+    pub debug_info: Option<DebugInfo>,
+}
+
+impl Program {
+    pub fn new(bytecode: Vec<u8>, debug_info: Option<DebugInfo>) -> Self {
+        Program {
+            bytecode,
+            debug_info,
+        }
+    }
+
+    /// Writes the program to a `.svb` file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ProgramError> {
+        let path = path.as_ref();
+        let svb = SvbFile::new(&self.bytecode, self.debug_info.clone());
+        fs::write(path, svb.to_bytes()).map_err(|source| ProgramError::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Reads a `.svb` file, or a file of bare bytecode such as an older
+    /// build wrote.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ProgramError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|source| ProgramError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        if !bytes.starts_with(&svb::MAGIC) {
+            return Ok(Program::new(bytes, None));
+        }
+        let svb = SvbFile::from_bytes(&bytes).map_err(|source| ProgramError::Svb {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Ok(Program::new(svb.program(), svb.debug_info))
+    }
+
+    /// A VM ready to run the program, for a host that sets its input,
+    /// output or host functions before running it.
+    pub fn vm(&self, config: &VmConfig) -> Result<VM, ProgramError> {
+        let mut vm = if config.verified {
+            VM::new_verified(self.bytecode.clone(), config.stack_limit)?
+        } else {
+            VM::new(self.bytecode.clone(), config.stack_limit)
+        };
+        if let Some(debug_info) = &self.debug_info {
+            vm.set_debug_info(debug_info.clone());
+        }
+        Ok(vm)
+    }
+
+    /// Writes the program as JSON: its operand encoding, its instructions
+    /// by opcode name, the data after them, and its debug info.
+    ///
+    /// A `push` of the address a jump, call or handler goes to, or of the
+    /// string or constant pool a `loadstr`, `bindhost` or `constpool` reads,
+    /// has a `target` label in place of its value, with the label on the
+    /// instruction or data it points to, so instructions can be added or
+    /// removed in the JSON. Other pushes, such as of functions used as
+    /// values, keep their values. An LEB128 operand wider than its value
+    /// needs has a `width`, so `from_json` gives back the same bytecode.
+    pub fn to_json(&self) -> Result<String, ProgramError> {
+        let json = JsonProgram::of(&self.bytecode).map_err(ProgramError::Decode)?;
+        let json = JsonProgram {
+            debug_info: self.debug_info.clone(),
+            ..json
+        };
+        Ok(serde_json::to_string_pretty(&json).expect("programs serialize"))
+    }
+
+    /// Reads a program written by `to_json`, laying its instructions and
+    /// data out again and filling in the addresses of their labels.
+    pub fn from_json(json: &str) -> Result<Self, ProgramError> {
+        let json: JsonProgram =
+            serde_json::from_str(json).map_err(|error| ProgramError::Json(error.to_string()))?;
+        let bytecode = json.assemble().map_err(ProgramError::Json)?;
+        Ok(Program::new(bytecode, json.debug_info))
+    }
+}
+
+/// A program as `Program::to_json` writes it.
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonProgram {
+    encoding: OperandEncoding,
+    code: Vec<JsonInstruction>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    data: Vec<JsonData>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    debug_info: Option<DebugInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonInstruction {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    op: Opcode,
+    /// The value of a `push` or the index of a `pushconst`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<i64>,
+    /// The label whose address a `push` pushes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    /// Bytes of an LEB128 `push` operand, if not the fewest its value needs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    width: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonData {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(flatten)]
+    value: DataValue,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DataValue {
+    /// A string as `loadstr` reads it: its length, then its bytes
+    String(String),
+    /// A constant pool: its length, then its values
+    Constants(Vec<i64>),
+    /// Anything else, as is
+    Bytes(Vec<u8>),
+}
+
+impl DataValue {
+    fn len(&self) -> usize {
+        match self {
+            DataValue::String(value) => 8 + value.len(),
+            DataValue::Constants(values) => 8 + 8 * values.len(),
+            DataValue::Bytes(bytes) => bytes.len(),
+        }
+    }
+}
+
+/// Bytes the signed LEB128 encoding of `value` takes.
+fn sleb128_len(value: i64) -> usize {
+    let mut bytes = Vec::new();
+    bytecode::write_sleb128(&mut bytes, value);
+    bytes.len()
+}
+
+impl JsonProgram {
+    fn of(program: &[u8]) -> Result<Self, VMError> {
+        let (encoding, start) = bytecode::read_header(program)?;
+        let instructions = bytecode::decode(program)?;
+        let code_end = instructions.last().map_or(start, Instruction::next_addr);
+
+        // Pushes of an address, by index, with what reads the address
+        let mut addresses: HashMap<usize, Opcode> = HashMap::new();
+        for (index, instruction) in instructions.iter().enumerate() {
+            let push = match instruction.opcode {
+                Opcode::Jump
+                | Opcode::JumpIf
+                | Opcode::Call
+                | Opcode::PushHandler
+                | Opcode::LoadStr
+                | Opcode::ConstPool => index.checked_sub(1),
+                Opcode::BindHost => index.checked_sub(2),
+                _ => None,
+            };
+            if let Some(push) = push.filter(|&push| instructions[push].opcode == Opcode::Push) {
+                addresses.insert(push, instruction.opcode);
+            }
+        }
+        let pushed = |opcodes: &[Opcode]| -> Vec<usize> {
+            addresses
+                .iter()
+                .filter(|(_, opcode)| opcodes.contains(opcode))
+                .filter_map(|(&push, _)| usize::try_from(instructions[push].operand?).ok())
+                .collect()
+        };
+
+        // The data after the code, split at each string and pool read from it
+        let pools = pushed(&[Opcode::ConstPool]);
+        let mut starts: Vec<usize> = pushed(&[Opcode::LoadStr, Opcode::BindHost]);
+        starts.extend(&pools);
+        starts.retain(|offset| (code_end..program.len()).contains(offset));
+        starts.push(code_end);
+        starts.sort_unstable();
+        starts.dedup();
+        let mut data: Vec<(usize, DataValue)> = Vec::new();
+        let mut offset = code_end;
+        while offset < program.len() {
+            let next = starts
+                .iter()
+                .copied()
+                .find(|&start| start > offset)
+                .unwrap_or(program.len());
+            let string = || {
+                let len = program.get(offset..offset + 8)?;
+                let len = usize::try_from(i64::from_le_bytes(len.try_into().unwrap())).ok()?;
+                let bytes = program.get(offset + 8..(offset + 8).checked_add(len)?)?;
+                String::from_utf8(bytes.to_vec()).ok()
+            };
+            let value = if !starts.contains(&offset) {
+                None
+            } else if pools.contains(&offset) {
+                bytecode::constant_pool(program, offset)
+                    .map(|(_, values)| DataValue::Constants(values))
+            } else {
+                string().map(DataValue::String)
+            };
+            let value = value.unwrap_or_else(|| DataValue::Bytes(program[offset..next].to_vec()));
+            let len = value.len();
+            data.push((offset, value));
+            offset += len;
+        }
+
+        // Labels for the instructions and data that pushed addresses point to
+        let targets: Vec<usize> = addresses
+            .keys()
+            .filter_map(|&push| usize::try_from(instructions[push].operand?).ok())
+            .collect();
+        let mut labels: BTreeMap<usize, String> = BTreeMap::new();
+        for instruction in &instructions {
+            if targets.contains(&instruction.addr) {
+                labels.insert(instruction.addr, format!("L{}", labels.len()));
+            }
+        }
+        let mut strings = 0;
+        for (offset, value) in &data {
+            let label = match value {
+                _ if !targets.contains(offset) => continue,
+                DataValue::Constants(_) => "pool".to_string(),
+                _ => {
+                    strings += 1;
+                    format!("S{}", strings - 1)
+                }
+            };
+            labels.insert(*offset, label);
+        }
+
+        let code = instructions
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| {
+                let target = addresses
+                    .contains_key(&index)
+                    .then(|| usize::try_from(instruction.operand?).ok())
+                    .flatten()
+                    .and_then(|addr| labels.get(&addr).cloned());
+                let width = instruction.size - 1;
+                let natural = match (&target, instruction.operand) {
+                    (Some(_), _) => bytecode::PATCHABLE_LEB128_LEN,
+                    (None, Some(value)) => sleb128_len(value),
+                    (None, None) => width,
+                };
+                JsonInstruction {
+                    label: labels.get(&instruction.addr).cloned(),
+                    op: instruction.opcode,
+                    value: instruction.operand.filter(|_| target.is_none()),
+                    target,
+                    width: (encoding == OperandEncoding::Leb128
+                        && instruction.opcode == Opcode::Push
+                        && width != natural)
+                        .then_some(width),
+                }
+            })
+            .collect();
+        let data = data
+            .into_iter()
+            .map(|(offset, value)| JsonData {
+                label: labels.get(&offset).cloned(),
+                value,
+            })
+            .collect();
+        Ok(JsonProgram {
+            encoding,
+            code,
+            data,
+            debug_info: None,
+        })
+    }
+
+    /// Lays the program out and encodes it, or says what is wrong with it.
+    fn assemble(&self) -> Result<Vec<u8>, String> {
+        let header = self.encoding.header();
+        let leb128 = self.encoding == OperandEncoding::Leb128;
+        let operand_len = |instruction: &JsonInstruction| -> Result<usize, String> {
+            let name = format!("{:?}", instruction.op).to_lowercase();
+            match (instruction.op, instruction.value, &instruction.target) {
+                (Opcode::Push, Some(_), Some(_)) => {
+                    Err("push has both a value and a target".to_string())
+                }
+                (Opcode::Push, None, None) => Err("push needs a value or a target".to_string()),
+                (Opcode::Push, _, _) if !leb128 => Ok(8),
+                (Opcode::Push, value, _) => {
+                    let natural = value.map_or(bytecode::PATCHABLE_LEB128_LEN, sleb128_len);
+                    match instruction.width {
+                        Some(width) if width < natural || width > 10 => {
+                            Err(format!("push cannot be {} bytes wide", width))
+                        }
+                        width => Ok(width.unwrap_or(natural)),
+                    }
+                }
+                (Opcode::PushConst, Some(index), None) => match u16::try_from(index) {
+                    Ok(_) if !leb128 => Ok(2),
+                    Ok(index) => {
+                        let mut bytes = Vec::new();
+                        bytecode::write_uleb128(&mut bytes, index as u64);
+                        Ok(bytes.len())
+                    }
+                    Err(_) => Err(format!("invalid constant pool index {}", index)),
+                },
+                (Opcode::PushConst, _, _) => Err("pushconst needs a value".to_string()),
+                (_, None, None) => Ok(0),
+                _ => Err(format!("{} takes no operand", name)),
+            }
+        };
+
+        let mut labels: HashMap<String, usize> = HashMap::new();
+        let mut define = |label: &Option<String>, addr: usize| match label {
+            Some(label) if labels.insert(label.clone(), addr).is_some() => {
+                Err(format!("label '{}' is defined twice", label))
+            }
+            _ => Ok(()),
+        };
+        let mut addr = header.len();
+        let mut operand_lens = Vec::with_capacity(self.code.len());
+        for instruction in &self.code {
+            define(&instruction.label, addr)?;
+            let len = operand_len(instruction)?;
+            operand_lens.push(len);
+            addr += 1 + len;
+        }
+        for item in &self.data {
+            define(&item.label, addr)?;
+            addr += item.value.len();
+        }
+
+        let mut program = header;
+        for (instruction, len) in self.code.iter().zip(operand_lens) {
+            program.push(instruction.op as u8);
+            let value = match (&instruction.target, instruction.value) {
+                (Some(target), _) => match labels.get(target) {
+                    Some(&addr) => addr as i64,
+                    None => return Err(format!("label '{}' is not defined", target)),
+                },
+                (None, Some(value)) => value,
+                (None, None) => continue,
+            };
+            match (instruction.op, leb128) {
+                (Opcode::Push, false) => program.extend_from_slice(&value.to_le_bytes()),
+                (Opcode::PushConst, false) => {
+                    program.extend_from_slice(&(value as u16).to_le_bytes())
+                }
+                (Opcode::Push, true) => {
+                    let start = program.len();
+                    program.resize(start + len, 0);
+                    bytecode::patch_sleb128(&mut program[start..], value);
+                }
+                _ => bytecode::write_uleb128(&mut program, value as u64),
+            }
+        }
+        for item in &self.data {
+            match &item.value {
+                DataValue::String(value) => {
+                    program.extend_from_slice(&(value.len() as i64).to_le_bytes());
+                    program.extend_from_slice(value.as_bytes());
+                }
+                DataValue::Constants(values) => {
+                    program.extend_from_slice(&(values.len() as i64).to_le_bytes());
+                    for value in values {
+                        program.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+                DataValue::Bytes(bytes) => program.extend_from_slice(bytes),
+            }
+        }
+        Ok(program)
+    }
+}
+
+/// Loads a program with the modules it imports and compiles it with debug
+/// info, at the default optimization level and with the prelude.
+pub fn compile_file(path: impl AsRef<Path>) -> Result<Program, ProgramError> {
+    let path = path.as_ref();
+    let mut loader = ModuleLoader::new();
+    let statements = loader.load(path).map_err(ProgramError::Load)?;
+    let mut compiler = Compiler::new();
+    compiler.set_debug_info(true);
+    let bytecode = compiler
+        .compile(statements)
+        .map_err(|source| ProgramError::Compile {
+            path: loader
+                .files()
+                .get(source.span().file)
+                .cloned()
+                .unwrap_or_else(|| path.to_path_buf()),
+            source: Box::new(source),
+        })?;
+    let mut debug_info = compiler.debug_info().cloned().unwrap_or_default();
+    debug_info.set_files(
+        loader
+            .files()
+            .iter()
+            .map(|file| file.display().to_string())
+            .collect(),
+    );
+    Ok(Program::new(bytecode, Some(debug_info)))
+}
+
+/// Runs a program on stdin and stdout: a `.svm` source file, compiled with
+/// `compile_file`, or a bytecode file read with `Program::load`. Returns
+/// the VM the program ended in, for its exit code and memory.
+pub fn run_file(path: impl AsRef<Path>, config: VmConfig) -> Result<VM, ProgramError> {
+    let path = path.as_ref();
+    let program = if path.extension().is_some_and(|ext| ext == "svm") {
+        compile_file(path)?
+    } else {
+        Program::load(path)?
+    };
+    let mut vm = program.vm(&config)?;
+    let result = match config.instruction_limit {
+        Some(limit) => vm.run_for(limit).map(|running| (running, limit)),
+        None => vm.run().map(|()| (false, 0)),
+    };
+    match result {
+        Ok((false, _)) => Ok(vm),
+        Ok((true, limit)) => Err(ProgramError::InstructionLimit {
+            path: path.to_path_buf(),
+            limit,
+        }),
+        Err(source) => {
+            // A fault in an imported module names that module
+            let span = vm.current_span();
+            let file = span.and_then(|span| vm.debug_info()?.file(span.file));
+            Err(ProgramError::Runtime {
+                path: file.map_or_else(|| path.to_path_buf(), PathBuf::from),
+                span,
+                source,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{compile_file, run_file, Program, ProgramError, VmConfig};
+    use crate::bytecode::OperandEncoding;
+    use crate::compiler::{Compiler, Parser};
+    use crate::golden::Snapshot;
+
+    /// A fresh directory under the system's temporary one.
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("simple-vm-program-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn compiles_saves_loads_and_runs_files() {
+        let dir = scratch("round-trip");
+        let source = dir.join("main.svm");
+        fs::write(&source, "let x = 6 * 7;\nexit(x);\n").unwrap();
+
+        let program = compile_file(&source).unwrap();
+        assert!(program.debug_info.is_some());
+        let saved = dir.join("main.svb");
+        program.save(&saved).unwrap();
+        assert_eq!(Program::load(&saved).unwrap(), program);
+
+        for path in [&source, &saved] {
+            let vm = run_file(path, VmConfig::default()).unwrap();
+            assert_eq!(vm.get_exit_code(), 42);
+        }
+        let verified = VmConfig {
+            verified: true,
+            ..VmConfig::default()
+        };
+        assert_eq!(run_file(&saved, verified).unwrap().get_exit_code(), 42);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_where_files_fail() {
+        let dir = scratch("failures");
+        let faulty = dir.join("faulty.svm");
+        fs::write(&faulty, "let x = 0;\nprint 1 / x;\n").unwrap();
+        let Err(error) = run_file(&faulty, VmConfig::default()) else {
+            panic!("dividing by zero succeeded");
+        };
+        assert!(matches!(error, ProgramError::Runtime { span: Some(_), .. }));
+        assert!(error
+            .to_string()
+            .starts_with(&format!("{}:2:", faulty.display())));
+
+        let main = dir.join("main.svm");
+        fs::write(&main, "import \"faulty_lib.svm\";\nprint f(0);\n").unwrap();
+        fs::write(
+            dir.join("faulty_lib.svm"),
+            "fn f(x) {\n    return 1 / x;\n}\n",
+        )
+        .unwrap();
+        let Err(error) = run_file(&main, VmConfig::default()) else {
+            panic!("dividing by zero succeeded");
+        };
+        assert!(error
+            .to_string()
+            .starts_with(&format!("{}:2:", dir.join("faulty_lib.svm").display())));
+
+        let endless = dir.join("endless.svm");
+        fs::write(&endless, "while true { }\n").unwrap();
+        let limited = VmConfig {
+            instruction_limit: Some(1000),
+            ..VmConfig::default()
+        };
+        assert!(matches!(
+            run_file(&endless, limited),
+            Err(ProgramError::InstructionLimit { limit: 1000, .. })
+        ));
+
+        let invalid = dir.join("invalid.svm");
+        fs::write(&invalid, "print y;\n").unwrap();
+        let error = compile_file(&invalid).err().unwrap();
+        assert!(matches!(error, ProgramError::Compile { .. }));
+        assert_eq!(
+            error.to_string(),
+            format!("{}:1:7: Undefined variable 'y'", invalid.display())
+        );
+        assert!(matches!(
+            Program::load(dir.join("missing.svb")),
+            Err(ProgramError::Io { .. })
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    const JSON_SOURCE: &str = "
+        fn half(x) { if x % 2 == 1 { throw x; } return x / 2; }
+        let scale = 1.5;
+        let area = scale * 2.5 + 1.5;
+        try { print half(8); print half(3); } catch (e) { print \"odd\"; }
+        let i = 0;
+        while i < 3 { i = i + 1; }
+        print i;
+    ";
+
+    #[test]
+    fn round_trips_programs_through_json() {
+        for encoding in [OperandEncoding::Fixed, OperandEncoding::Leb128] {
+            let statements = Parser::new(JSON_SOURCE).parse_program().unwrap();
+            let mut compiler = Compiler::new();
+            compiler.set_operand_encoding(encoding);
+            compiler.set_debug_info(true);
+            let program = Program::new(
+                compiler.compile(statements).unwrap(),
+                compiler.debug_info().cloned(),
+            );
+            let json = program.to_json().unwrap();
+            assert!(json.contains(r#""op": "jumpif""#), "{}", json);
+            assert!(json.contains(r#""target": "L0""#), "{}", json);
+            assert!(json.contains(r#""string": "odd""#), "{}", json);
+            assert_eq!(Program::from_json(&json).unwrap(), program, "{}", json);
+        }
+    }
+
+    #[test]
+    fn lays_out_edited_json_again() {
+        let statements = Parser::new(JSON_SOURCE).parse_program().unwrap();
+        let bytecode = Compiler::new().compile(statements).unwrap();
+        let expected = Snapshot::of_program(bytecode.clone());
+
+        // A no-op ahead of everything moves every address
+        let json = Program::new(bytecode, None).to_json().unwrap();
+        let edited = json.replacen(
+            r#""code": ["#,
+            r#""code": [{"op": "push", "value": 0}, {"op": "pop"},"#,
+            1,
+        );
+        let program = Program::from_json(&edited).unwrap();
+        assert_eq!(Snapshot::of_program(program.bytecode), expected);
+
+        let undefined = json.replacen(r#""target": "L0""#, r#""target": "nowhere""#, 1);
+        assert!(matches!(
+            Program::from_json(&undefined),
+            Err(ProgramError::Json(message)) if message.contains("nowhere")
+        ));
+    }
+}
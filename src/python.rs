@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::compiler::{self, Compiler, Parser};
+use crate::golden::Capture;
+use crate::{StopReason, VMError, VM};
+
+/// Instructions `Vm.run` executes between checks for signals, such as the
+/// Ctrl-C that interrupts a notebook cell.
+const SLICE: u64 = 10_000;
+
+create_exception!(
+    simple_vm,
+    CompileError,
+    PyException,
+    "A program that does not compile."
+);
+create_exception!(
+    simple_vm,
+    VmError,
+    PyException,
+    "A fault while a program ran."
+);
+
+/// Compiles a program's source to bytecode for `Vm`.
+#[pyfunction]
+fn compile<'py>(py: Python<'py>, source: &str) -> PyResult<Bound<'py, PyBytes>> {
+    let statements = Parser::new(source).parse_program().map_err(|errors| {
+        CompileError::new_err(compiler::CompileError::Parse(errors).to_string())
+    })?;
+    let program = Compiler::new()
+        .compile(statements)
+        .map_err(|error| CompileError::new_err(error.to_string()))?;
+    Ok(PyBytes::new(py, &program))
+}
+
+/// A VM running a program, with the text it prints kept in `output` rather
+/// than written to stdout, which a notebook would not show.
+#[pyclass(unsendable, name = "Vm", module = "simple_vm")]
+pub struct Vm {
+    vm: VM,
+    output: Capture,
+}
+
+impl Vm {
+    fn fault(&self, error: VMError) -> PyErr {
+        match self.vm.current_span() {
+            Some(span) => VmError::new_err(format!("{}: {}", span, error)),
+            None => VmError::new_err(error.to_string()),
+        }
+    }
+}
+
+#[pymethods]
+impl Vm {
+    /// Loads `bytecode`, with the lines of `input` for the program to
+    /// `read`.
+    #[new]
+    #[pyo3(signature = (bytecode, input = "", stack_limit = 1024))]
+    fn new(bytecode: &[u8], input: &str, stack_limit: usize) -> Self {
+        let output = Capture::default();
+        let mut vm = VM::new(bytecode.to_vec(), stack_limit);
+        vm.set_input(Cursor::new(input.as_bytes().to_vec()));
+        vm.set_output(output.clone());
+        Vm { vm, output }
+    }
+
+    /// Runs the program until it halts or exits, raising
+    /// `KeyboardInterrupt` if interrupted, after which `run` carries on
+    /// where it stopped.
+    fn run(&mut self, py: Python<'_>) -> PyResult<()> {
+        loop {
+            match self.vm.run_for(SLICE) {
+                Ok(true) => py.check_signals()?,
+                Ok(false) => return Ok(()),
+                Err(error) => return Err(self.fault(error)),
+            }
+        }
+    }
+
+    /// Executes one instruction, returning whether the program goes on.
+    fn step(&mut self) -> PyResult<bool> {
+        match self.vm.step() {
+            Ok(StopReason::Halted) => Ok(false),
+            Ok(_) => Ok(true),
+            Err(error) => Err(self.fault(error)),
+        }
+    }
+
+    /// The operand stack, bottom first.
+    #[getter]
+    fn stack(&self) -> Vec<i64> {
+        self.vm.get_stack().to_vec()
+    }
+
+    /// Memory cells in use, by address.
+    #[getter]
+    fn memory(&self) -> BTreeMap<usize, i64> {
+        self.vm
+            .get_memory()
+            .iter()
+            .map(|(&addr, &value)| (addr, value))
+            .collect()
+    }
+
+    /// Everything the program has printed.
+    #[getter]
+    fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output.0.borrow()).into_owned()
+    }
+
+    #[getter]
+    fn pc(&self) -> usize {
+        self.vm.pc()
+    }
+
+    #[getter]
+    fn exit_code(&self) -> i64 {
+        self.vm.get_exit_code()
+    }
+}
+
+/// The `simple_vm` Python module.
+#[pymodule]
+fn simple_vm(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(compile, module)?)?;
+    module.add_class::<Vm>()?;
+    module.add("CompileError", module.py().get_type::<CompileError>())?;
+    module.add("VmError", module.py().get_type::<VmError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pyo3::ffi::c_str;
+    use pyo3::prelude::*;
+    use pyo3::types::{PyDict, PyModule};
+
+    /// Runs Python `code` with the module imported as `simple_vm`.
+    fn run_python(code: &std::ffi::CStr) {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new(py, "simple_vm").unwrap();
+            super::simple_vm(&module).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("simple_vm", module).unwrap();
+            if let Err(error) = py.run(code, Some(&globals), None) {
+                panic!("{}", error);
+            }
+        });
+    }
+
+    #[test]
+    fn runs_and_steps_programs_from_python() {
+        run_python(c_str!(
+            "
+vm = simple_vm.Vm(simple_vm.compile('let x = read(); print x * 2; exit(3);'), input='21\\n')
+vm.run()
+assert vm.output == 'Output: 42\\n', vm.output
+assert vm.exit_code == 3
+assert 21 in vm.memory.values(), vm.memory
+
+# Longer than a slice of `run`
+vm = simple_vm.Vm(simple_vm.compile('let i = 0; while i < 5000 { i = i + 1; }'))
+vm.run()
+assert vm.memory[0] == 5000, vm.memory
+
+vm = simple_vm.Vm(simple_vm.compile('let x = 1 + 2;'))
+steps = 0
+while vm.step():
+    steps += 1
+assert steps > 0 and vm.stack == []
+"
+        ));
+    }
+
+    #[test]
+    fn raises_compile_and_runtime_errors() {
+        run_python(c_str!(
+            "
+try:
+    simple_vm.compile('print y;')
+    assert False
+except simple_vm.CompileError as error:
+    assert 'y' in str(error)
+
+vm = simple_vm.Vm(simple_vm.compile('let x = 0; print 1 / x;'))
+try:
+    vm.run()
+    assert False
+except simple_vm.VmError as error:
+    assert 'Division by zero' in str(error), error
+"
+        ));
+    }
+}
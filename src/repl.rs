@@ -0,0 +1,245 @@
+use thiserror::Error;
+
+use crate::{
+    bytecode::{self, Instruction},
+    compiler::{CompileError, OptLevel, Parser, Session, Span},
+    VMError, VM,
+};
+
+/// Operand stack slots, and call depth, of the REPL's VM.
+const STACK_LIMIT: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum ReplError {
+    #[error("{0}")]
+    Compile(#[from] CompileError),
+    #[error("{0}")]
+    Runtime(#[from] VMError),
+    /// The input changes the code of earlier inputs, which the VM has
+    /// already run, such as by redefining a prelude function or declaring
+    /// an `extern`
+    #[error("The input would move the code of earlier inputs")]
+    CodeMoved,
+}
+
+/// Evaluates a program one input at a time, for interactive use. Variables,
+/// functions, structs and constants of earlier inputs stay defined, and the
+/// values they hold stay in the VM's memory.
+///
+/// Every input is compiled along with the inputs before it by a `Session`,
+/// which reuses the code of unchanged functions, and the VM runs only the
+/// code of the new input. An input that is an expression, with or without a
+/// trailing `;`, has its value printed. An input that fails to compile or
+/// run is forgotten, although what it changed before failing stays changed.
+pub struct Repl {
+    session: Session,
+    /// The inputs evaluated so far, one per line
+    source: String,
+    vm: VM,
+    /// Instructions of the program run last, without its final `Halt`. The
+    /// next program must lay them out the same way, since values in memory
+    /// may hold their addresses.
+    code: Vec<Instruction>,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Repl {
+            session: Session::new(),
+            source: String::new(),
+            vm: VM::new(Vec::new(), STACK_LIMIT),
+            code: Vec::new(),
+        }
+    }
+
+    /// Enables or disables linking the standard prelude (on by default).
+    /// Set it before the first input.
+    pub fn set_prelude(&mut self, enabled: bool) {
+        self.session.set_prelude(enabled);
+    }
+
+    /// Selects the optimization passes (`OptLevel::Default` unless set).
+    /// Set it before the first input.
+    pub fn set_opt_level(&mut self, level: OptLevel) {
+        self.session.set_opt_level(level);
+    }
+
+    /// The VM running the inputs, to inspect its memory.
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    /// The VM running the inputs, to register host functions or set its
+    /// input.
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    /// Compiles and runs one input: statements, or an expression to print.
+    /// Errors are located in the input, as if it were the whole source.
+    pub fn eval(&mut self, input: &str) -> Result<(), ReplError> {
+        let input = input.trim();
+        if input.is_empty() {
+            return Ok(());
+        }
+        let unterminated = input.trim_end_matches(';');
+        // Each way of reading the input, with how much it adds ahead of it
+        let candidates = [
+            (input.to_string(), 0),
+            (format!("print {};", unterminated), "print ".len()),
+            (format!("{};", unterminated), 0),
+        ];
+        let mut first_error = None;
+        for (candidate, prefix) in candidates {
+            if Parser::new(&candidate).parse_program().is_err() {
+                continue;
+            }
+            let source = format!("{}{}\n", self.source, candidate);
+            match self.compile(&source) {
+                Ok((program, code)) => return self.run(source, program, code),
+                Err(mut error) => {
+                    if let ReplError::Compile(error) = &mut error {
+                        if let Some(span) = error.span_mut() {
+                            self.rebase(span, prefix);
+                        }
+                    }
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+        if let Some(error) = first_error {
+            return Err(error);
+        }
+        let error = Parser::new(input).parse_program().unwrap_err();
+        Err(CompileError::from(error).into())
+    }
+
+    /// Moves a span in the program with a new input, `prefix` bytes ahead
+    /// of which the REPL added, into the input. Spans in the prelude or in
+    /// earlier inputs are left as they are.
+    fn rebase(&self, span: &mut Span, prefix: usize) {
+        if span.file != 0 || span.offset < self.source.len() {
+            return;
+        }
+        let offset = span.offset - self.source.len();
+        span.offset = offset.saturating_sub(prefix);
+        span.len = span.len.saturating_sub(prefix.saturating_sub(offset));
+        // Every input is a line of its own
+        span.line -= self.source.lines().count();
+        if span.line == 1 {
+            span.col = span.col.saturating_sub(prefix).max(1);
+        }
+    }
+
+    /// Compiles the program with a new input, checking that the code of
+    /// the earlier inputs stays where it is.
+    fn compile(&mut self, source: &str) -> Result<(Vec<u8>, Vec<Instruction>), ReplError> {
+        let program = self.session.compile(source)?;
+        let mut code = bytecode::decode(&program)?;
+        code.pop();
+        let layout = |instruction: &Instruction| (instruction.addr, instruction.opcode);
+        if code.len() < self.code.len()
+            || !self
+                .code
+                .iter()
+                .map(layout)
+                .eq(code.iter().map(layout).take(self.code.len()))
+        {
+            return Err(ReplError::CodeMoved);
+        }
+        Ok((program, code))
+    }
+
+    /// Runs the code a new input added to the program.
+    fn run(
+        &mut self,
+        source: String,
+        program: Vec<u8>,
+        code: Vec<Instruction>,
+    ) -> Result<(), ReplError> {
+        let start = match self.code.last() {
+            Some(instruction) => instruction.next_addr(),
+            None => bytecode::read_header(&program)?.1,
+        };
+        self.vm.load_program(program, start);
+        self.vm.run()?;
+        self.source = source;
+        self.code = code;
+        Ok(())
+    }
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Repl, ReplError};
+    use crate::compiler::CompileError;
+
+    fn global(repl: &Repl, addr: usize) -> i64 {
+        repl.vm().get_memory()[&addr]
+    }
+
+    #[test]
+    fn keeps_definitions_and_values_between_inputs() {
+        let mut repl = Repl::new();
+        for input in [
+            "let x = 5;",
+            "x = x + 1",
+            "fn twice(f, v) { return f(f(v)); }",
+            "let add = fn(v) { return v + x; };",
+            "let s = \"ab\";",
+            "x + 1",
+            "let y = twice(add, gcd(12, 18)) + len(s + \"c\");",
+        ] {
+            repl.eval(input).unwrap();
+        }
+        assert_eq!(global(&repl, 0), 6);
+        assert_eq!(global(&repl, 3), 6 + 6 + 6 + 3);
+    }
+
+    #[test]
+    fn forgets_inputs_that_fail() {
+        let mut repl = Repl::new();
+        repl.eval("let a = [1, 2];").unwrap();
+        assert!(matches!(
+            repl.eval("let b = c;"),
+            Err(ReplError::Compile(CompileError::UndefinedVariable { .. }))
+        ));
+        assert!(matches!(
+            repl.eval("let b = a[5];"),
+            Err(ReplError::Runtime(_))
+        ));
+        assert!(matches!(repl.eval("let b = ;"), Err(ReplError::Compile(_))));
+        repl.eval("let b = a[1] * 10;").unwrap();
+        assert_eq!(global(&repl, 1), 20);
+
+        // The prelude's `gcd` was compiled ahead of the code that has run
+        assert!(matches!(
+            repl.eval("fn gcd(a, b) { return 0; }"),
+            Err(ReplError::CodeMoved)
+        ));
+        repl.eval("let c = gcd(4, 6);").unwrap();
+        assert_eq!(global(&repl, 2), 2);
+    }
+
+    #[test]
+    fn locates_errors_in_the_input() {
+        let mut repl = Repl::new();
+        repl.eval("let x = 1;").unwrap();
+        repl.eval("x + 1").unwrap();
+        let span = |error: ReplError| match error {
+            ReplError::Compile(error) => (error.span().line, error.span().col, error.span().offset),
+            error => panic!("{}", error),
+        };
+        // The same place in a compile error and a syntax error
+        assert_eq!(span(repl.eval("let y = z;").unwrap_err()), (1, 9, 8));
+        assert_eq!(span(repl.eval("let y = ;").unwrap_err()), (1, 9, 8));
+        // An expression is compiled as a `print` of it
+        assert_eq!(span(repl.eval("x + z").unwrap_err()), (1, 5, 4));
+    }
+}
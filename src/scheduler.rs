@@ -0,0 +1,247 @@
+use std::fmt;
+
+use crate::channel::Channel;
+use crate::{VMError, VM};
+
+/// Identifies an actor of a `Scheduler`. Ids start at 1, and each one is
+/// also the channel the other actors send to it on.
+pub type ActorId = usize;
+
+/// The channel an actor receives its own messages on.
+pub const MAILBOX: i64 = 0;
+
+/// Where an actor stands after the scheduler last ran it.
+#[derive(Debug)]
+pub enum ActorState {
+    /// It has instructions left and runs in the next round
+    Ready,
+    /// It waits at a `recv` on an empty channel, and runs again once
+    /// something was sent
+    Blocked(i64),
+    /// It halted or called `exit`, with this code
+    Finished(i64),
+    /// It faulted, which ends it without stopping the others
+    Faulted(VMError),
+}
+
+impl ActorState {
+    /// Whether the actor halted or faulted, so it never runs again.
+    pub fn is_done(&self) -> bool {
+        matches!(self, ActorState::Finished(_) | ActorState::Faulted(_))
+    }
+}
+
+impl fmt::Display for ActorState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ActorState::Ready => write!(f, "ready"),
+            ActorState::Blocked(channel) => write!(f, "blocked on channel {}", channel),
+            ActorState::Finished(code) => write!(f, "finished with exit code {}", code),
+            ActorState::Faulted(error) => write!(f, "faulted: {}", error),
+        }
+    }
+}
+
+struct Actor {
+    vm: VM,
+    mailbox: Channel,
+    state: ActorState,
+}
+
+/// Runs many VMs as actors that share the host thread: each round gives
+/// every actor that is not done a slice of `fuel` instructions in turn, and
+/// actors talk by `send(id, value)` to another actor's mailbox and
+/// `recv(0)` from their own. A fault ends only the actor that made it.
+pub struct Scheduler {
+    actors: Vec<Actor>,
+    fuel: u64,
+}
+
+impl Scheduler {
+    /// A scheduler that runs each actor for at most `fuel` instructions at
+    /// a time.
+    pub fn new(fuel: u64) -> Self {
+        Scheduler {
+            actors: Vec::new(),
+            fuel: fuel.max(1),
+        }
+    }
+
+    /// Adds `vm` as an actor, attaching its mailbox as channel `MAILBOX`
+    /// and the mailbox of every actor, this one included, as the channel of
+    /// that actor's id. Channels the host attached under other ids stay.
+    pub fn spawn(&mut self, mut vm: VM) -> ActorId {
+        let id = self.actors.len() + 1;
+        let mailbox = Channel::new();
+        for (other_id, other) in self.actors.iter_mut().enumerate() {
+            other.vm.attach_channel(id as i64, mailbox.clone());
+            vm.attach_channel(other_id as i64 + 1, other.mailbox.clone());
+        }
+        vm.attach_channel(id as i64, mailbox.clone());
+        vm.attach_channel(MAILBOX, mailbox.clone());
+        self.actors.push(Actor {
+            vm,
+            mailbox,
+            state: ActorState::Ready,
+        });
+        id
+    }
+
+    /// Puts `value` in the mailbox of actor `id`, returning false if there
+    /// is no such actor.
+    pub fn send(&self, id: ActorId, value: i64) -> bool {
+        match self.actor(id) {
+            Some(actor) => {
+                actor.mailbox.send(value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs every actor that is not done for one slice, in the order they
+    /// were spawned, and returns whether any of them executed an
+    /// instruction. An actor blocked on a channel is skipped until
+    /// something was sent on it.
+    pub fn run_round(&mut self) -> bool {
+        let fuel = self.fuel;
+        let mut progressed = false;
+        for actor in &mut self.actors {
+            let waiting = match actor.state {
+                ActorState::Blocked(channel) => {
+                    actor.vm.channel(channel).is_ok_and(Channel::is_empty)
+                }
+                _ => actor.state.is_done(),
+            };
+            if waiting {
+                continue;
+            }
+            let retired = actor.vm.metrics().instructions_retired;
+            actor.state = match actor.vm.run_for(fuel) {
+                Ok(true) => ActorState::Ready,
+                Ok(false) => ActorState::Finished(actor.vm.get_exit_code()),
+                Err(VMError::ChannelEmpty(channel)) => ActorState::Blocked(channel),
+                Err(error) => ActorState::Faulted(error),
+            };
+            progressed |= actor.vm.metrics().instructions_retired > retired;
+        }
+        progressed
+    }
+
+    /// Runs rounds until every actor is done or waits for a message no one
+    /// is left to send. An actor that never ends keeps this running; use
+    /// `run_round` to bound the work.
+    pub fn run(&mut self) {
+        while self.run_round() {}
+    }
+
+    /// The state of actor `id`.
+    pub fn state(&self, id: ActorId) -> Option<&ActorState> {
+        self.actor(id).map(|actor| &actor.state)
+    }
+
+    /// Every actor with its state, in the order they were spawned.
+    pub fn states(&self) -> impl Iterator<Item = (ActorId, &ActorState)> {
+        (1..).zip(self.actors.iter().map(|actor| &actor.state))
+    }
+
+    /// The actors that faulted, with their errors.
+    pub fn faults(&self) -> impl Iterator<Item = (ActorId, &VMError)> {
+        self.states().filter_map(|(id, state)| match state {
+            ActorState::Faulted(error) => Some((id, error)),
+            _ => None,
+        })
+    }
+
+    /// The VM of actor `id`.
+    pub fn vm(&self, id: ActorId) -> Option<&VM> {
+        self.actor(id).map(|actor| &actor.vm)
+    }
+
+    /// The VM of actor `id`, to change what it is configured with.
+    pub fn vm_mut(&mut self, id: ActorId) -> Option<&mut VM> {
+        self.actors
+            .get_mut(id.checked_sub(1)?)
+            .map(|actor| &mut actor.vm)
+    }
+
+    fn actor(&self, id: ActorId) -> Option<&Actor> {
+        self.actors.get(id.checked_sub(1)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActorState, Scheduler};
+    use crate::test_util::compile;
+    use crate::{Opcode, VMError, VM};
+
+    fn actor(source: &str) -> VM {
+        VM::new(compile(source), 64)
+    }
+
+    #[test]
+    fn round_robins_actors_that_exchange_messages() {
+        // A pinger and a ponger bounce a counter, with small slices so they
+        // take many turns each
+        let mut scheduler = Scheduler::new(5);
+        let ping = scheduler.spawn(actor(
+            "let n = 0; while n < 10 { send(2, n + 1); n = recv(0); } exit(n);",
+        ));
+        let pong = scheduler.spawn(actor(
+            "while true { let n = recv(0); send(1, n + 1); if n >= 9 { exit(n); } }",
+        ));
+        scheduler.run();
+        assert!(matches!(
+            scheduler.state(ping),
+            Some(ActorState::Finished(10))
+        ));
+        assert!(matches!(
+            scheduler.state(pong),
+            Some(ActorState::Finished(9))
+        ));
+        assert_eq!(scheduler.faults().count(), 0);
+    }
+
+    #[test]
+    fn reports_faults_and_deadlocks_per_actor() {
+        let mut scheduler = Scheduler::new(100);
+        let faulty = scheduler.spawn(actor("let x = 0; print 1 / x;"));
+        let waiting = scheduler.spawn(actor("exit(recv(0));"));
+        let working = scheduler.spawn(actor("let i = 0; while i < 1000 { i = i + 1; } exit(i);"));
+        scheduler.run();
+
+        let faults: Vec<_> = scheduler.faults().collect();
+        assert!(matches!(faults[..], [(1, VMError::DivisionByZero)]));
+        assert!(matches!(
+            scheduler.state(working),
+            Some(ActorState::Finished(1000))
+        ));
+        // Nobody sends to it, so it is left waiting, without retrying its
+        // `recv` each round
+        assert!(matches!(
+            scheduler.state(waiting),
+            Some(ActorState::Blocked(0))
+        ));
+        let recvs = |scheduler: &Scheduler| {
+            let metrics = scheduler.vm(waiting).unwrap().metrics();
+            metrics.opcodes.count(Opcode::Recv)
+        };
+        assert_eq!(recvs(&scheduler), 1);
+        assert!(!scheduler.run_round());
+        assert_eq!(recvs(&scheduler), 1);
+        assert_eq!(
+            scheduler.state(faulty).unwrap().to_string(),
+            "faulted: Division by zero"
+        );
+
+        // A message from the host wakes it
+        assert!(scheduler.send(waiting, 7));
+        assert!(!scheduler.send(9, 7));
+        scheduler.run();
+        assert!(matches!(
+            scheduler.state(waiting),
+            Some(ActorState::Finished(7))
+        ));
+    }
+}
@@ -0,0 +1,521 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+
+use thiserror::Error;
+
+use crate::bytecode;
+use crate::cfg::{BasicBlock, Cfg, EdgeKind};
+use crate::{Opcode, VMError};
+
+/// How many values an instruction takes off the operand stack, and how many
+/// it puts back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+    pub pops: usize,
+    pub pushes: usize,
+}
+
+/// The stack effect of an opcode, or `None` if it depends on an operand
+/// (`NewArray`, `CallHost`) or on the function called (`Call`, `CallClosure`).
+/// A `Ret` leaves the return value for the caller, so it pops nothing.
+pub fn stack_effect(opcode: Opcode) -> Option<StackEffect> {
+    let (pops, pushes) = match opcode {
+        Opcode::Halt | Opcode::Ret | Opcode::PopHandler => (0, 0),
+        Opcode::Push | Opcode::PushConst | Opcode::Read | Opcode::NewMap => (0, 1),
+        Opcode::Pop
+        | Opcode::Assert
+        | Opcode::Print
+        | Opcode::PrintFloat
+        | Opcode::PrintStr
+        | Opcode::PrintChar
+        | Opcode::WriteInt
+        | Opcode::WriteFloat
+        | Opcode::WriteStr
+        | Opcode::Jump
+        | Opcode::PushHandler
+        | Opcode::Throw
+        | Opcode::Enter
+        | Opcode::ConstPool
+        | Opcode::Exit => (1, 0),
+        Opcode::Dup => (1, 2),
+        Opcode::Neg
+        | Opcode::Abs
+        | Opcode::Inc
+        | Opcode::Dec
+        | Opcode::SqrtInt
+        | Opcode::FNeg
+        | Opcode::IntToFloat
+        | Opcode::FloatToInt
+        | Opcode::Load
+        | Opcode::LoadLocal
+        | Opcode::LoadStr
+        | Opcode::Recv => (1, 1),
+        Opcode::Store | Opcode::StoreLocal | Opcode::JumpIf | Opcode::BindHost | Opcode::Send => {
+            (2, 0)
+        }
+        Opcode::Add
+        | Opcode::Sub
+        | Opcode::Mul
+        | Opcode::Div
+        | Opcode::Mod
+        | Opcode::Shl
+        | Opcode::Shr
+        | Opcode::Equal
+        | Opcode::NotEqual
+        | Opcode::Less
+        | Opcode::LessEqual
+        | Opcode::Greater
+        | Opcode::GreaterEqual
+        | Opcode::Min
+        | Opcode::Max
+        | Opcode::Pow
+        | Opcode::FAdd
+        | Opcode::FSub
+        | Opcode::FMul
+        | Opcode::FDiv
+        | Opcode::FMod
+        | Opcode::FEqual
+        | Opcode::FNotEqual
+        | Opcode::FLess
+        | Opcode::FLessEqual
+        | Opcode::FGreater
+        | Opcode::FGreaterEqual
+        | Opcode::Concat
+        | Opcode::Index
+        | Opcode::MapGet
+        | Opcode::MapHas => (2, 1),
+        Opcode::SetIndex | Opcode::MapSet => (3, 0),
+        Opcode::NewArray | Opcode::CallHost | Opcode::Call | Opcode::CallClosure => return None,
+    };
+    Some(StackEffect { pops, pushes })
+}
+
+#[derive(Debug, Error)]
+pub enum StackError {
+    #[error(transparent)]
+    Decode(#[from] VMError),
+    #[error("Stack underflow at pc {pc}")]
+    Underflow { pc: usize },
+    #[error("Stack depth {depth} at pc {pc} exceeds the limit of {limit}")]
+    Overflow {
+        pc: usize,
+        depth: usize,
+        limit: usize,
+    },
+    #[error("Stack depth at pc {pc} is {first} on one path and {second} on another")]
+    Mismatch {
+        pc: usize,
+        first: isize,
+        second: isize,
+    },
+    #[error("Stack grows without bound through the recursive call at pc {pc}")]
+    Unbounded { pc: usize },
+    #[error("Cannot determine the stack effect of {opcode:?} at pc {pc}")]
+    Dynamic { pc: usize, opcode: Opcode },
+    #[error("{opcode:?} at pc {pc} jumps to {target}, which is not an instruction")]
+    InvalidTarget {
+        pc: usize,
+        opcode: Opcode,
+        target: usize,
+    },
+}
+
+/// What the analysis found out about a program that passed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackAnalysis {
+    /// The most values the operand stack holds at once, on any path
+    pub max_depth: usize,
+    /// An instruction that leaves the stack `max_depth` deep
+    pub deepest_pc: usize,
+}
+
+/// Computes the stack depth before every reachable instruction and checks
+/// that no path pops more than it pushed and that every path into an
+/// instruction arrives at the same depth.
+///
+/// Functions are analyzed on their own, relative to the depth they are
+/// called at, and summarized by how many values they consume and leave;
+/// their bodies are found through the `Call`s that reach them. Programs
+/// that call function values with `CallClosure` are rejected, since the
+/// callee is only known at runtime, as are programs whose stack grows with
+/// the depth of a recursion.
+pub fn analyze(bytecode: &[u8]) -> Result<StackAnalysis, StackError> {
+    let cfg = Cfg::build(bytecode)?;
+    // `CallHost` refers to bindings by the order they were made in
+    let host_arities: Vec<usize> = cfg
+        .blocks
+        .iter()
+        .flat_map(|block| {
+            (0..block.instructions.len())
+                .filter(|&index| block.instructions[index].opcode == Opcode::BindHost)
+                .filter_map(|index| bytecode::pushed_operand(&block.instructions, index))
+        })
+        .collect();
+    let analyzer = Analyzer {
+        cfg: &cfg,
+        host_arities,
+    };
+
+    // The program starts at its first instruction, after any header
+    let Some(main) = cfg.blocks.first().map(|block| block.start) else {
+        return Ok(StackAnalysis {
+            max_depth: 0,
+            deepest_pc: 0,
+        });
+    };
+    let mut summaries: BTreeMap<usize, Summary> = BTreeMap::from([(main, Summary::new(main))]);
+    loop {
+        let mut changed = false;
+        let entries: Vec<usize> = summaries.keys().copied().collect();
+        for entry in entries {
+            let summary = analyzer.function(entry, &summaries)?;
+            for &(_, _, callee) in &summary.calls {
+                if let Entry::Vacant(vacant) = summaries.entry(callee) {
+                    vacant.insert(Summary::new(callee));
+                    changed = true;
+                }
+            }
+            if summaries[&entry] != summary {
+                summaries.insert(entry, summary);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let program = &summaries[&main];
+    if program.lowest.0 < 0 {
+        return Err(StackError::Underflow {
+            pc: program.lowest.1,
+        });
+    }
+    if let Some((_, pc)) = program.returns {
+        // Returning needs a call to return from
+        return Err(StackError::Underflow { pc });
+    }
+
+    // The deepest point of a function includes the deepest point of the
+    // functions it calls, stacked on the depth of the call
+    let mut peaks: BTreeMap<usize, (isize, usize)> = summaries
+        .iter()
+        .map(|(&entry, summary)| (entry, summary.peak))
+        .collect();
+    for round in 0..=summaries.len() {
+        let mut raised = None;
+        for (entry, summary) in &summaries {
+            for &(pc, depth, callee) in &summary.calls {
+                let (callee_peak, deepest_pc) = peaks[&callee];
+                if depth + callee_peak > peaks[entry].0 {
+                    peaks.insert(*entry, (depth + callee_peak, deepest_pc));
+                    raised = Some(pc);
+                }
+            }
+        }
+        match raised {
+            None => break,
+            // Still growing after every acyclic path was accounted for
+            Some(pc) if round == summaries.len() => return Err(StackError::Unbounded { pc }),
+            Some(_) => {}
+        }
+    }
+    let (max_depth, deepest_pc) = peaks[&main];
+    Ok(StackAnalysis {
+        max_depth: max_depth as usize,
+        deepest_pc,
+    })
+}
+
+/// Analyzes a program and checks that it never needs more than `limit`
+/// stack slots. A VM running a verified program can skip its own check.
+pub fn verify(bytecode: &[u8], limit: usize) -> Result<StackAnalysis, StackError> {
+    let analysis = analyze(bytecode)?;
+    if analysis.max_depth > limit {
+        return Err(StackError::Overflow {
+            pc: analysis.deepest_pc,
+            depth: analysis.max_depth,
+            limit,
+        });
+    }
+    Ok(analysis)
+}
+
+/// A function's effect on its caller's stack. Depths are relative to the
+/// depth at entry, which includes the arguments and environment pointer.
+#[derive(Debug, Clone, PartialEq)]
+struct Summary {
+    /// Lowest depth reached, counting the needs of callees, and where
+    lowest: (isize, usize),
+    /// Depth at `Ret` and the first `Ret` seen, once one has been reached
+    returns: Option<(isize, usize)>,
+    /// Deepest point of the body itself, and where
+    peak: (isize, usize),
+    /// Calls made: where, the depth once the address is popped, and the callee
+    calls: Vec<(usize, isize, usize)>,
+}
+
+impl Summary {
+    fn new(entry: usize) -> Self {
+        Summary {
+            lowest: (0, entry),
+            returns: None,
+            peak: (0, entry),
+            calls: Vec::new(),
+        }
+    }
+
+    /// Values the function takes from its caller's stack.
+    fn inputs(&self) -> isize {
+        (-self.lowest.0).max(0)
+    }
+
+    fn lower(&mut self, depth: isize, pc: usize) {
+        if depth < self.lowest.0 {
+            self.lowest = (depth, pc);
+        }
+    }
+
+    fn raise(&mut self, depth: isize, pc: usize) {
+        if depth > self.peak.0 {
+            self.peak = (depth, pc);
+        }
+    }
+}
+
+struct Analyzer<'a> {
+    cfg: &'a Cfg,
+    host_arities: Vec<usize>,
+}
+
+impl Analyzer<'_> {
+    /// Walks the blocks of the function at `entry` with the current
+    /// summaries of its callees. The code after a call to a function not
+    /// yet known to return is left for a later round.
+    fn function(
+        &self,
+        entry: usize,
+        summaries: &BTreeMap<usize, Summary>,
+    ) -> Result<Summary, StackError> {
+        let mut summary = Summary::new(entry);
+        let mut depths = HashMap::from([(entry, 0)]);
+        let mut worklist = vec![entry];
+        'blocks: while let Some(start) = worklist.pop() {
+            let block = self.cfg.block_at(start).unwrap();
+            let mut depth = depths[&start];
+            for (index, instruction) in block.instructions.iter().enumerate() {
+                let pc = instruction.addr;
+                let effect = match instruction.opcode {
+                    Opcode::Call => {
+                        let callee = self.target(block, index)?;
+                        depth -= 1;
+                        let callee_summary = summaries.get(&callee);
+                        let inputs = callee_summary.map_or(0, Summary::inputs);
+                        summary.lower(depth - inputs, pc);
+                        summary.calls.push((pc, depth, callee));
+                        match callee_summary.and_then(|callee| callee.returns) {
+                            Some((returns, _)) => depth += returns,
+                            None => continue 'blocks,
+                        }
+                        summary.raise(depth, pc);
+                        continue;
+                    }
+                    Opcode::NewArray => StackEffect {
+                        pops: self.operand(block, index)? + 1,
+                        pushes: 1,
+                    },
+                    Opcode::CallHost => {
+                        let binding = self.operand(block, index)?;
+                        let arity = *self.host_arities.get(binding).ok_or(StackError::Dynamic {
+                            pc,
+                            opcode: Opcode::CallHost,
+                        })?;
+                        StackEffect {
+                            pops: arity + 1,
+                            pushes: 1,
+                        }
+                    }
+                    opcode => stack_effect(opcode).ok_or(StackError::Dynamic { pc, opcode })?,
+                };
+                depth -= effect.pops as isize;
+                summary.lower(depth, pc);
+                depth += effect.pushes as isize;
+                summary.raise(depth, pc);
+
+                match instruction.opcode {
+                    Opcode::Jump | Opcode::JumpIf => {
+                        self.target(block, index)?;
+                    }
+                    // The handler starts with the thrown value on the stack
+                    Opcode::PushHandler => {
+                        let handler = self.target(block, index)?;
+                        summary.raise(depth + 1, pc);
+                        visit(&mut depths, &mut worklist, handler, depth + 1)?;
+                    }
+                    Opcode::Ret => match summary.returns {
+                        Some((first, _)) if first != depth => {
+                            return Err(StackError::Mismatch {
+                                pc,
+                                first,
+                                second: depth,
+                            })
+                        }
+                        Some(_) => {}
+                        None => summary.returns = Some((depth, pc)),
+                    },
+                    _ => {}
+                }
+            }
+            for edge in &block.successors {
+                if matches!(
+                    edge.kind,
+                    EdgeKind::FallThrough | EdgeKind::Jump | EdgeKind::Branch
+                ) {
+                    visit(&mut depths, &mut worklist, edge.target, depth)?;
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /// The static target of the transfer at `index`, which must start a block.
+    fn target(&self, block: &BasicBlock, index: usize) -> Result<usize, StackError> {
+        let instruction = block.instructions[index];
+        let target =
+            bytecode::pushed_operand(&block.instructions, index).ok_or(StackError::Dynamic {
+                pc: instruction.addr,
+                opcode: instruction.opcode,
+            })?;
+        match self.cfg.block_at(target) {
+            Some(_) => Ok(target),
+            None => Err(StackError::InvalidTarget {
+                pc: instruction.addr,
+                opcode: instruction.opcode,
+                target,
+            }),
+        }
+    }
+
+    /// The count or binding index pushed for the instruction at `index`.
+    fn operand(&self, block: &BasicBlock, index: usize) -> Result<usize, StackError> {
+        let instruction = block.instructions[index];
+        bytecode::pushed_operand(&block.instructions, index).ok_or(StackError::Dynamic {
+            pc: instruction.addr,
+            opcode: instruction.opcode,
+        })
+    }
+}
+
+/// Records the depth a block is entered at, queueing it the first time.
+fn visit(
+    depths: &mut HashMap<usize, isize>,
+    worklist: &mut Vec<usize>,
+    start: usize,
+    depth: isize,
+) -> Result<(), StackError> {
+    match depths.get(&start) {
+        Some(&first) if first != depth => Err(StackError::Mismatch {
+            pc: start,
+            first,
+            second: depth,
+        }),
+        Some(_) => Ok(()),
+        None => {
+            depths.insert(start, depth);
+            worklist.push(start);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{analyze, verify, StackError};
+    use crate::test_util::compile;
+    use crate::{Opcode, VM};
+
+    #[test]
+    fn tracks_depth_of_straight_line_code() {
+        let bytecode = crate::bytecode![push 1, push 2, push 3, mul, add, print, halt];
+        let analysis = analyze(&bytecode).unwrap();
+        assert_eq!(analysis.max_depth, 3);
+        assert_eq!(analysis.deepest_pc, 18);
+    }
+
+    #[test]
+    fn accepts_compiled_programs() {
+        let programs = [
+            "let i = 0; while i < 10 { if i % 2 == 0 { print i; } else { print -i; } i++; }",
+            "fn add(a, b) { return a + b; } print add(1, add(2, 3)); add(4, 5);",
+            "fn count(n) { if n == 0 { return 0; } return count(n - 1); } print count(5);",
+            "let a = [1, 2, 3]; a[1] = 5; print array_sum(a), gcd(12, 18), len(\"abc\");",
+            "let m = {1: 2}; m[3] = 4; print has(m, 3) ? m[1] : 0;",
+            "match 2 { 1 => { print 1; } 2 => { print \"two\"; } _ => { } }",
+            "fn check(n) { if n < 0 { throw n; } return n; }
+             try { print check(-3); } catch (e) { print \"caught {}\", e; }",
+            "struct P { x, y } let p = P { x: 1, y: 2 }; print p.x + p.y;",
+            "extern fn log(x); log(3); print 1.5 * 2.0;",
+        ];
+        for program in programs {
+            if let Err(error) = analyze(&compile(program)) {
+                panic!("{}: {}", program, error);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_underflow_and_mismatched_paths() {
+        let underflow = crate::bytecode![push 1, add, halt];
+        assert!(matches!(
+            analyze(&underflow),
+            Err(StackError::Underflow { pc: 9 })
+        ));
+
+        // Only the fall-through path pushes before the join at 28
+        let mismatch = crate::bytecode![push 1, push 28, jumpif, push 7, halt];
+        assert!(matches!(
+            analyze(&mismatch),
+            Err(StackError::Mismatch { pc: 28, .. })
+        ));
+
+        let returns = crate::bytecode![push 0, ret];
+        assert!(matches!(
+            analyze(&returns),
+            Err(StackError::Underflow { pc: 9 })
+        ));
+    }
+
+    #[test]
+    fn bounds_recursion_only_when_the_stack_does_not_grow() {
+        let tail = "fn count(n) { if n == 0 { return 0; } return count(n - 1); } print count(9);";
+        assert!(analyze(&compile(tail)).is_ok());
+        let fact = "fn fact(n) { if n < 2 { return 1; } return n * fact(n - 1); } print fact(5);";
+        assert!(matches!(
+            analyze(&compile(fact)),
+            Err(StackError::Unbounded { .. })
+        ));
+        let closure = "let f = fn(x) { return x + 1; }; print f(1);";
+        assert!(matches!(
+            analyze(&compile(closure)),
+            Err(StackError::Dynamic {
+                opcode: Opcode::CallClosure,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn verified_programs_run_without_limit_checks() {
+        let program =
+            compile("fn sq(n) { return n * n; } let i = 0; while i < 3 { print sq(i) + i; i++; }");
+        let depth = analyze(&program).unwrap().max_depth;
+        assert!(matches!(
+            verify(&program, depth - 1),
+            Err(StackError::Overflow { .. })
+        ));
+        assert!(VM::new_verified(program.clone(), depth - 1).is_err());
+        let mut vm = VM::new_verified(program, depth).unwrap();
+        vm.run().unwrap();
+        assert!(vm.get_stack().is_empty());
+    }
+}
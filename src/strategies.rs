@@ -0,0 +1,527 @@
+//! proptest strategies, with the `proptest` feature: bytecode the stack
+//! verifier accepts, bytecode that also calls closures, source programs that compile, and VMs stopped partway
+//! through a program, for property tests of the VM and the compiler.
+
+use std::io;
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::sample::select;
+
+use crate::asm::Builder;
+use crate::golden::STACK_LIMIT;
+use crate::stack_depth::stack_effect;
+use crate::{Opcode, StopReason, VM};
+
+/// Opcodes `valid_bytecode` programs are made of, all with a fixed stack
+/// effect. Jumps are only made by `Step::Skip`, calls and handlers need
+/// code laid out for them, and `Enter` fills as many cells as it is told
+/// to. Strings, arrays, maps and constant pools are used wherever the
+/// operands point, so that the VM is given addresses it never allocated.
+const OPCODES: &[Opcode] = &[
+    Opcode::Pop,
+    Opcode::Dup,
+    Opcode::Assert,
+    Opcode::Read,
+    Opcode::Neg,
+    Opcode::Abs,
+    Opcode::Inc,
+    Opcode::Dec,
+    Opcode::SqrtInt,
+    Opcode::Add,
+    Opcode::Sub,
+    Opcode::Mul,
+    Opcode::Div,
+    Opcode::Mod,
+    Opcode::Shl,
+    Opcode::Shr,
+    Opcode::Min,
+    Opcode::Max,
+    Opcode::Pow,
+    Opcode::Equal,
+    Opcode::NotEqual,
+    Opcode::Less,
+    Opcode::LessEqual,
+    Opcode::Greater,
+    Opcode::GreaterEqual,
+    Opcode::FAdd,
+    Opcode::FSub,
+    Opcode::FMul,
+    Opcode::FDiv,
+    Opcode::FMod,
+    Opcode::FNeg,
+    Opcode::FEqual,
+    Opcode::FNotEqual,
+    Opcode::FLess,
+    Opcode::FLessEqual,
+    Opcode::FGreater,
+    Opcode::FGreaterEqual,
+    Opcode::IntToFloat,
+    Opcode::FloatToInt,
+    Opcode::Load,
+    Opcode::Store,
+    Opcode::LoadLocal,
+    Opcode::StoreLocal,
+    Opcode::LoadStr,
+    Opcode::Concat,
+    Opcode::Index,
+    Opcode::SetIndex,
+    Opcode::NewMap,
+    Opcode::MapGet,
+    Opcode::MapSet,
+    Opcode::MapHas,
+    Opcode::ConstPool,
+    Opcode::Print,
+    Opcode::PrintChar,
+    Opcode::PrintFloat,
+    Opcode::WriteInt,
+    Opcode::WriteFloat,
+    Opcode::Exit,
+    Opcode::Halt,
+];
+
+/// A piece of a generated program, before `Assembler` makes it valid.
+#[derive(Debug, Clone)]
+enum Step {
+    Push(i64),
+    /// An instruction, with the value pushed for each operand the stack
+    /// does not hold
+    Op(Opcode, i64),
+    /// A `pushconst` of any index, into whichever pool `ConstPool` set
+    PushConst(u16),
+    /// A `newarray` of this many values, with the value pushed for each
+    /// the stack does not hold
+    NewArray(usize, i64),
+    /// A `callclosure` of the value on top of the stack, with the value
+    /// pushed for it if there is none
+    CallClosure(i64),
+    /// Steps jumped over when the value on top of the stack is not zero,
+    /// with the value pushed for it if there is none
+    Skip(i64, Vec<Step>),
+}
+
+/// Steps, with `CallClosure`s only if `closures`, since the verifier
+/// rejects them.
+fn step(closures: bool) -> BoxedStrategy<Step> {
+    let leaf = prop_oneof![
+        4 => value().prop_map(Step::Push),
+        6 => (select(OPCODES), value()).prop_map(|(opcode, value)| Step::Op(opcode, value)),
+        1 => any::<u16>().prop_map(Step::PushConst),
+        1 => (0usize..4, value()).prop_map(|(count, value)| Step::NewArray(count, value)),
+    ];
+    let leaf = if closures {
+        prop_oneof![10 => leaf, 1 => value().prop_map(Step::CallClosure)].boxed()
+    } else {
+        leaf.boxed()
+    };
+    leaf.prop_recursive(3, 64, 8, |inner| {
+        (value(), vec(inner, 0..8)).prop_map(|(value, body)| Step::Skip(value, body))
+    })
+    .boxed()
+}
+
+/// Integers and float bits, mostly small, with the edges of the `i64` range.
+fn value() -> impl Strategy<Value = i64> {
+    prop_oneof![
+        4 => -16i64..16,
+        2 => any::<i64>(),
+        1 => any::<f64>().prop_map(|value| value.to_bits() as i64),
+        1 => Just(i64::MIN),
+        1 => Just(i64::MAX),
+    ]
+}
+
+/// Lays out steps, tracking the stack depth so that nothing pops more than
+/// was pushed and both paths out of a `Skip` leave the stack as deep.
+struct Assembler {
+    builder: Builder,
+    depth: usize,
+    skips: usize,
+}
+
+impl Assembler {
+    /// Assembles steps that may only pop down to `floor`, pushing the
+    /// operands an instruction needs that the stack above it does not hold.
+    fn steps(&mut self, steps: &[Step], floor: usize) {
+        for step in steps {
+            match step {
+                Step::Push(value) => self.push(*value),
+                Step::Op(opcode, value) => {
+                    let effect = stack_effect(*opcode).expect("opcodes have a fixed effect");
+                    while self.depth - floor < effect.pops {
+                        self.push(*value);
+                    }
+                    if matches!(opcode, Opcode::LoadStr | Opcode::ConstPool) {
+                        // `bytecode::decode` takes an offset pushed right
+                        // before these for where the code ends
+                        self.builder.instruction("dup");
+                        self.builder.instruction("pop");
+                    }
+                    self.builder.instruction(&format!("{:?}", opcode));
+                    self.depth = self.depth - effect.pops + effect.pushes;
+                }
+                Step::PushConst(index) => {
+                    self.builder.operand("pushconst", *index);
+                    self.depth += 1;
+                }
+                Step::NewArray(count, value) => {
+                    while self.depth - floor < *count {
+                        self.push(*value);
+                    }
+                    self.builder.operand("push", *count);
+                    self.builder.instruction("newarray");
+                    self.depth = self.depth - count + 1;
+                }
+                Step::CallClosure(value) => {
+                    if self.depth == floor {
+                        self.push(*value);
+                    }
+                    // The closure is popped and pushed back as the callee's
+                    // environment; what the callee leaves is not known
+                    self.builder.instruction("callclosure");
+                }
+                Step::Skip(value, body) => {
+                    if self.depth == floor {
+                        self.push(*value);
+                    }
+                    let label = format!("skip{}", self.skips);
+                    self.skips += 1;
+                    self.builder.target("jumpif", &label);
+                    self.depth -= 1;
+                    let depth = self.depth;
+                    self.steps(body, depth);
+                    while self.depth > depth {
+                        self.builder.instruction("pop");
+                        self.depth -= 1;
+                    }
+                    self.builder.label(&label);
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, value: i64) {
+        self.builder.operand("push", value);
+        self.depth += 1;
+    }
+}
+
+/// Programs that `stack_depth::verify` accepts and that always end: runs
+/// of stack, arithmetic, float, memory, string, array, map, constant and
+/// output instructions, with forward `jumpif`s over some of them, ending
+/// with `halt`. Operands are anything, so programs still fault, dividing
+/// by zero, failing an `assert` or indexing memory that is not an array.
+pub fn valid_bytecode() -> impl Strategy<Value = Vec<u8>> {
+    bytecode(false)
+}
+
+/// Programs like `valid_bytecode`'s that also `callclosure` any value, for
+/// the engines that do not need the verifier to accept them. Calls go to
+/// wherever the value's record points, so these programs can loop, and
+/// only end within a bounded number of instructions.
+pub fn closure_bytecode() -> impl Strategy<Value = Vec<u8>> {
+    bytecode(true)
+}
+
+fn bytecode(closures: bool) -> impl Strategy<Value = Vec<u8>> {
+    vec(step(closures), 0..32).prop_map(|steps| {
+        let mut assembler = Assembler {
+            builder: Builder::new(),
+            depth: 0,
+            skips: 0,
+        };
+        assembler.steps(&steps, 0);
+        assembler.builder.instruction("halt");
+        assembler.builder.finish()
+    })
+}
+
+/// Input for `read`: a few lines, each an integer.
+pub fn input() -> impl Strategy<Value = Vec<u8>> {
+    vec(value(), 0..4).prop_map(|values| {
+        let lines: String = values.iter().map(|value| format!("{}\n", value)).collect();
+        lines.into_bytes()
+    })
+}
+
+/// A program with its input, and how far to run it.
+#[derive(Debug, Clone)]
+pub struct Execution {
+    pub bytecode: Vec<u8>,
+    pub input: Vec<u8>,
+    pub steps: u64,
+}
+
+impl Execution {
+    /// A VM running the program with its output dropped, stepped `steps`
+    /// instructions in, or fewer if the program ends or faults first.
+    pub fn vm(&self) -> VM {
+        let mut vm = VM::new(self.bytecode.clone(), STACK_LIMIT);
+        vm.set_input(io::Cursor::new(self.input.clone()));
+        vm.set_output(io::sink());
+        for _ in 0..self.steps {
+            if !matches!(vm.step(), Ok(StopReason::Stepped)) {
+                break;
+            }
+        }
+        vm
+    }
+}
+
+/// `valid_bytecode` programs stopped partway, for properties of the states
+/// a VM passes through.
+pub fn execution() -> impl Strategy<Value = Execution> {
+    (valid_bytecode(), input(), 0u64..64).prop_map(|(bytecode, input, steps)| Execution {
+        bytecode,
+        input,
+        steps,
+    })
+}
+
+/// Variables every generated source program declares.
+const VARIABLES: [&str; 3] = ["a", "b", "c"];
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Number(i64),
+    Variable(usize),
+    Negate(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Call(&'static str, Vec<Expr>),
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Compare(&'static str, Expr, Expr),
+    Logic(&'static str, Box<Condition>, Box<Condition>),
+}
+
+#[derive(Debug, Clone)]
+enum Statement {
+    Print(Expr),
+    Assign(usize, Expr),
+    If(Condition, Vec<Statement>, Vec<Statement>),
+    /// A loop run a fixed number of times, counting with a variable of its own
+    Repeat(u8, Vec<Statement>),
+}
+
+/// Integers a literal can be written as, since `i64::MIN` cannot be negated
+/// from a literal.
+fn number() -> impl Strategy<Value = i64> {
+    prop_oneof![3 => -100i64..100, 1 => -i64::MAX..=i64::MAX]
+}
+
+fn expr() -> impl Strategy<Value = Expr> {
+    let leaf = prop_oneof![
+        number().prop_map(Expr::Number),
+        (0..VARIABLES.len()).prop_map(Expr::Variable),
+    ];
+    leaf.prop_recursive(4, 32, 2, |inner| {
+        prop_oneof![
+            inner.clone().prop_map(|expr| Expr::Negate(Box::new(expr))),
+            (
+                select(&["+", "-", "*", "/", "%", "<<", ">>"][..]),
+                inner.clone(),
+                inner.clone()
+            )
+                .prop_map(|(op, left, right)| Expr::Binary(
+                    op,
+                    Box::new(left),
+                    Box::new(right)
+                )),
+            inner.clone().prop_map(|expr| Expr::Call("abs", vec![expr])),
+            (select(&["min", "max"][..]), inner.clone(), inner)
+                .prop_map(|(name, a, b)| Expr::Call(name, vec![a, b])),
+        ]
+    })
+}
+
+fn condition() -> impl Strategy<Value = Condition> {
+    let compare = (
+        select(&["<", "<=", ">", ">=", "==", "!="][..]),
+        expr(),
+        expr(),
+    )
+        .prop_map(|(op, left, right)| Condition::Compare(op, left, right));
+    compare.prop_recursive(1, 2, 2, |inner| {
+        (select(&["&&", "||"][..]), inner.clone(), inner)
+            .prop_map(|(op, left, right)| Condition::Logic(op, Box::new(left), Box::new(right)))
+    })
+}
+
+fn statement() -> impl Strategy<Value = Statement> {
+    let leaf = prop_oneof![
+        expr().prop_map(Statement::Print),
+        (0..VARIABLES.len(), expr()).prop_map(|(var, expr)| Statement::Assign(var, expr)),
+    ];
+    leaf.prop_recursive(3, 24, 4, |inner| {
+        prop_oneof![
+            (
+                condition(),
+                vec(inner.clone(), 0..4),
+                vec(inner.clone(), 0..3)
+            )
+                .prop_map(|(condition, then, otherwise)| Statement::If(condition, then, otherwise)),
+            (1u8..5, vec(inner, 0..4)).prop_map(|(times, body)| Statement::Repeat(times, body)),
+        ]
+    })
+}
+
+/// Writes generated statements as source, naming each loop's counter.
+#[derive(Default)]
+struct Writer {
+    source: String,
+    indent: usize,
+    counters: usize,
+}
+
+impl Writer {
+    fn line(&mut self, line: &str) {
+        self.source.push_str(&"    ".repeat(self.indent));
+        self.source.push_str(line);
+        self.source.push('\n');
+    }
+
+    fn block(&mut self, statements: &[Statement]) {
+        self.indent += 1;
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.indent -= 1;
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Print(expr) => self.line(&format!("print {};", expr_source(expr))),
+            Statement::Assign(var, expr) => {
+                self.line(&format!("{} = {};", VARIABLES[*var], expr_source(expr)))
+            }
+            Statement::If(condition, then, otherwise) => {
+                self.line(&format!("if {} {{", condition_source(condition)));
+                self.block(then);
+                if otherwise.is_empty() {
+                    self.line("}");
+                } else {
+                    self.line("} else {");
+                    self.block(otherwise);
+                    self.line("}");
+                }
+            }
+            Statement::Repeat(times, body) => {
+                let counter = format!("i{}", self.counters);
+                self.counters += 1;
+                self.line(&format!("let {} = 0;", counter));
+                self.line(&format!("while {} < {} {{", counter, times));
+                self.block(body);
+                self.indent += 1;
+                self.line(&format!("{}++;", counter));
+                self.indent -= 1;
+                self.line("}");
+            }
+        }
+    }
+}
+
+fn expr_source(expr: &Expr) -> String {
+    match expr {
+        Expr::Number(value) if *value < 0 => format!("({})", value),
+        Expr::Number(value) => value.to_string(),
+        Expr::Variable(var) => VARIABLES[*var].to_string(),
+        Expr::Negate(expr) => format!("(-{})", expr_source(expr)),
+        Expr::Binary(op, left, right) => {
+            format!("({} {} {})", expr_source(left), op, expr_source(right))
+        }
+        Expr::Call(name, args) => {
+            let args: Vec<_> = args.iter().map(expr_source).collect();
+            format!("{}({})", name, args.join(", "))
+        }
+    }
+}
+
+fn condition_source(condition: &Condition) -> String {
+    match condition {
+        Condition::Compare(op, left, right) => {
+            format!("{} {} {}", expr_source(left), op, expr_source(right))
+        }
+        Condition::Logic(op, left, right) => {
+            format!(
+                "({}) {} ({})",
+                condition_source(left),
+                op,
+                condition_source(right)
+            )
+        }
+    }
+}
+
+/// Source programs that compile and always end: integer variables that
+/// are assigned and printed, `if`/`else`, and `while` loops counting to at
+/// most 4. Expressions use every integer operator, `abs`, `min` and `max`,
+/// so programs may still divide by zero or shift too far.
+pub fn source_program() -> impl Strategy<Value = String> {
+    (vec(number(), VARIABLES.len()), vec(statement(), 0..8)).prop_map(|(values, statements)| {
+        let mut writer = Writer::default();
+        for (name, value) in VARIABLES.iter().zip(values) {
+            writer.line(&format!(
+                "let {} = {};",
+                name,
+                expr_source(&Expr::Number(value))
+            ));
+        }
+        for statement in &statements {
+            writer.statement(statement);
+        }
+        writer.source
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{closure_bytecode, execution, input, source_program, valid_bytecode};
+    use crate::compiler::{Compiler, Parser};
+    use crate::differential::{compare, Engine};
+    use crate::golden::STACK_LIMIT;
+    use crate::stack_depth;
+
+    proptest! {
+        #[test]
+        fn verified_programs_run_the_same_on_every_engine(
+            bytecode in valid_bytecode(),
+            input in input(),
+        ) {
+            prop_assert!(stack_depth::verify(&bytecode, STACK_LIMIT).is_ok());
+            for engine in [Engine::Verified, Engine::Stepped] {
+                let compared = compare(&bytecode, &input, Engine::Interpreter, engine);
+                prop_assert!(compared.is_ok(), "{}", compared.unwrap_err());
+            }
+        }
+
+        #[test]
+        fn programs_calling_closures_run_the_same_unverified(
+            bytecode in closure_bytecode(),
+            input in input(),
+        ) {
+            let compared = compare(&bytecode, &input, Engine::Interpreter, Engine::Stepped);
+            prop_assert!(compared.is_ok(), "{}", compared.unwrap_err());
+        }
+
+        #[test]
+        fn executions_stay_within_the_verified_depth(execution in execution()) {
+            let analysis = stack_depth::verify(&execution.bytecode, STACK_LIMIT).unwrap();
+            prop_assert!(execution.vm().get_stack().len() <= analysis.max_depth);
+        }
+
+        #[test]
+        fn source_programs_compile_and_end(source in source_program()) {
+            let statements = Parser::new(&source).parse_program();
+            prop_assert!(statements.is_ok(), "{}\n{:?}", source, statements.unwrap_err());
+            let bytecode = Compiler::new().compile(statements.unwrap());
+            prop_assert!(bytecode.is_ok(), "{}\n{}", source, bytecode.unwrap_err());
+            let snapshot = compare(&bytecode.unwrap(), b"", Engine::Interpreter, Engine::Stepped);
+            prop_assert!(snapshot.is_ok(), "{}", snapshot.unwrap_err());
+            prop_assert!(!snapshot.unwrap().result.starts_with("still running"), "{}", source);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    bytecode::{self, OperandEncoding},
+    compiler::DebugInfo,
+    Opcode,
+};
+
+/// First bytes of every `.svb` file.
+pub const MAGIC: [u8; 4] = *b"\x7fSVB";
+
+/// Version of the layout written by `SvbFile::to_bytes`.
+pub const VERSION: u16 = 1;
+
+/// Magic, version, section count and checksum.
+const HEADER_LEN: usize = 4 + 2 + 2 + 8;
+
+/// Kind, offset and length of a section.
+const ENTRY_LEN: usize = 4 + 8 + 8;
+
+#[derive(Debug, Error)]
+pub enum SvbError {
+    #[error("Not a .svb file")]
+    BadMagic,
+    #[error("Unsupported .svb version {0} (this build reads version {VERSION})")]
+    UnsupportedVersion(u16),
+    #[error("The file is truncated")]
+    Truncated,
+    #[error("The file is corrupted: its checksum does not match")]
+    ChecksumMismatch,
+    #[error("Section {0} lies outside the file")]
+    SectionOutOfBounds(u32),
+    #[error("Section {0} appears more than once")]
+    DuplicateSection(u32),
+    #[error("Unknown section kind {0}")]
+    UnknownSection(u32),
+    #[error("The file has no code section")]
+    MissingCode,
+    #[error("Invalid debug info: {0}")]
+    InvalidDebugInfo(String),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+/// The sections of a `.svb` file, with the kind each is tagged with in the
+/// section table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Code = 1,
+    Data = 2,
+    Constants = 3,
+    DebugInfo = 4,
+}
+
+impl TryFrom<u32> for Section {
+    type Error = SvbError;
+
+    fn try_from(kind: u32) -> Result<Self, SvbError> {
+        match kind {
+            1 => Ok(Section::Code),
+            2 => Ok(Section::Data),
+            3 => Ok(Section::Constants),
+            4 => Ok(Section::DebugInfo),
+            _ => Err(SvbError::UnknownSection(kind)),
+        }
+    }
+}
+
+/// A compiled program as stored in a `.svb` file, split into its code, its
+/// string data, its constant pool and, optionally, the debug info it was
+/// compiled with.
+///
+/// The file starts with `MAGIC`, the format `VERSION`, the number of
+/// sections and an FNV-1a checksum of everything after the header, all
+/// little-endian. A table follows with the kind, offset and length of each
+/// section, then the sections themselves. Debug info is stored as JSON.
+/// The code, data and constant sections are consecutive parts of the
+/// program, whose operands hold offsets into the whole, so `program`
+/// joins them back in that order.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SvbFile {
+    /// The instructions, header included
+    pub code: Vec<u8>,
+    /// String constants
+    pub data: Vec<u8>,
+    /// The constant pool
+    pub constants: Vec<u8>,
+    pub debug_info: Option<DebugInfo>,
+}
+
+impl SvbFile {
+    /// Splits a program into sections. A program that cannot be decoded,
+    /// such as one assembled with data between its instructions, is kept
+    /// whole in the code section.
+    pub fn new(program: &[u8], debug_info: Option<DebugInfo>) -> Self {
+        let (code_end, pool) = match bytecode::decode(program) {
+            Ok(instructions) => {
+                let code_end = instructions
+                    .last()
+                    .map(|instruction| instruction.next_addr())
+                    .unwrap_or(program.len());
+                let pool = instructions
+                    .iter()
+                    .position(|instruction| instruction.opcode == Opcode::ConstPool)
+                    .and_then(|index| bytecode::pushed_operand(&instructions, index))
+                    .filter(|pool| (code_end..=program.len()).contains(pool));
+                (code_end, pool.unwrap_or(program.len()))
+            }
+            Err(_) => (program.len(), program.len()),
+        };
+        SvbFile {
+            code: program[..code_end].to_vec(),
+            data: program[code_end..pool].to_vec(),
+            constants: program[pool..].to_vec(),
+            debug_info,
+        }
+    }
+
+    /// The program, ready to run on a `VM`.
+    pub fn program(&self) -> Vec<u8> {
+        [&self.code[..], &self.data, &self.constants].concat()
+    }
+
+    /// How the program's inline operands are encoded.
+    pub fn encoding(&self) -> OperandEncoding {
+        bytecode::read_header(&self.code)
+            .map(|(encoding, _)| encoding)
+            .unwrap_or_default()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let debug_info = self
+            .debug_info
+            .as_ref()
+            .map(|debug_info| serde_json::to_vec(debug_info).expect("debug info serializes"));
+        let mut sections = vec![
+            (Section::Code, &self.code[..]),
+            (Section::Data, &self.data[..]),
+            (Section::Constants, &self.constants[..]),
+        ];
+        if let Some(debug_info) = &debug_info {
+            sections.push((Section::DebugInfo, &debug_info[..]));
+        }
+
+        let mut body = Vec::new();
+        let mut offset = HEADER_LEN + sections.len() * ENTRY_LEN;
+        for (section, contents) in &sections {
+            body.extend_from_slice(&(*section as u32).to_le_bytes());
+            body.extend_from_slice(&(offset as u64).to_le_bytes());
+            body.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+            offset += contents.len();
+        }
+        for (_, contents) in &sections {
+            body.extend_from_slice(contents);
+        }
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(sections.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&checksum(&body).to_le_bytes());
+        bytes.extend(body);
+        bytes
+    }
+
+    /// Reads and validates a file written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SvbError> {
+        if !bytes.starts_with(&MAGIC) {
+            return Err(SvbError::BadMagic);
+        }
+        let header = bytes.get(..HEADER_LEN).ok_or(SvbError::Truncated)?;
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != VERSION {
+            return Err(SvbError::UnsupportedVersion(version));
+        }
+        let count = u16::from_le_bytes([header[6], header[7]]) as usize;
+        if checksum(&bytes[HEADER_LEN..]) != u64::from_le_bytes(header[8..].try_into().unwrap()) {
+            return Err(SvbError::ChecksumMismatch);
+        }
+
+        let table = bytes
+            .get(HEADER_LEN..HEADER_LEN + count * ENTRY_LEN)
+            .ok_or(SvbError::Truncated)?;
+        let mut sections: [Option<&[u8]>; 4] = [None; 4];
+        for entry in table.chunks(ENTRY_LEN) {
+            let kind = u32::from_le_bytes(entry[..4].try_into().unwrap());
+            let offset = u64::from_le_bytes(entry[4..12].try_into().unwrap());
+            let len = u64::from_le_bytes(entry[12..].try_into().unwrap());
+            let section = Section::try_from(kind)?;
+            let contents = usize::try_from(offset)
+                .ok()
+                .zip(usize::try_from(len).ok())
+                .and_then(|(offset, len)| bytes.get(offset..offset.checked_add(len)?))
+                .ok_or(SvbError::SectionOutOfBounds(kind))?;
+            let slot = &mut sections[section as usize - 1];
+            if slot.replace(contents).is_some() {
+                return Err(SvbError::DuplicateSection(kind));
+            }
+        }
+
+        let [code, data, constants, debug_info] = sections;
+        let debug_info = debug_info
+            .map(serde_json::from_slice)
+            .transpose()
+            .map_err(|error| SvbError::InvalidDebugInfo(error.to_string()))?;
+        Ok(SvbFile {
+            code: code.ok_or(SvbError::MissingCode)?.to_vec(),
+            data: data.unwrap_or_default().to_vec(),
+            constants: constants.unwrap_or_default().to_vec(),
+            debug_info,
+        })
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    pub fn read(mut reader: impl Read) -> Result<Self, SvbError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// 64-bit FNV-1a hash, enough to catch truncated or damaged files.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SvbError, SvbFile, HEADER_LEN};
+    use crate::{bytecode::OperandEncoding, compiler::Compiler, test_util::compile_with, VM};
+
+    fn compile(code: &str, encoding: OperandEncoding) -> (Vec<u8>, SvbFile) {
+        let mut compiler = Compiler::new();
+        compiler.set_debug_info(true);
+        compiler.set_operand_encoding(encoding);
+        let program = compile_with(&mut compiler, code);
+        let file = SvbFile::new(&program, compiler.debug_info().cloned());
+        (program, file)
+    }
+
+    #[test]
+    fn round_trips_programs_by_section() {
+        let code = "
+            let s = \"ab\" + \"cd\";
+            let x = 1000 * 1000 + 1000;
+            let y = x / 1000 + 1000000;
+        ";
+        for encoding in [OperandEncoding::Fixed, OperandEncoding::Leb128] {
+            let (program, file) = compile(code, encoding);
+            assert_eq!(file.encoding(), encoding);
+            assert!(file.data.windows(2).any(|bytes| bytes == b"ab"));
+            // The pool's length, then its values
+            assert!(!file.constants.is_empty());
+            assert_eq!(file.constants.len() % 8, 0);
+
+            let read = SvbFile::read(&file.to_bytes()[..]).unwrap();
+            assert_eq!(read, file);
+            assert_eq!(read.program(), program);
+            let mut vm = VM::new(read.program(), 100);
+            vm.set_debug_info(read.debug_info.unwrap());
+            vm.run().unwrap();
+            assert_eq!(vm.get_memory()[&2], 1001001);
+        }
+    }
+
+    #[test]
+    fn rejects_damaged_files() {
+        let (_, file) = compile("print 1;", OperandEncoding::Fixed);
+        let bytes = file.to_bytes();
+        assert!(matches!(
+            SvbFile::from_bytes(b"\0SVM\0"),
+            Err(SvbError::BadMagic)
+        ));
+        assert!(matches!(
+            SvbFile::from_bytes(&bytes[..HEADER_LEN - 1]),
+            Err(SvbError::Truncated)
+        ));
+        assert!(matches!(
+            SvbFile::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SvbError::ChecksumMismatch)
+        ));
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert!(matches!(
+            SvbFile::from_bytes(&newer),
+            Err(SvbError::UnsupportedVersion(2))
+        ));
+        let mut flipped = bytes;
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            SvbFile::from_bytes(&flipped),
+            Err(SvbError::ChecksumMismatch)
+        ));
+    }
+}
@@ -0,0 +1,29 @@
+//! Fixtures shared by the unit tests.
+
+use crate::compiler::{Compiler, Parser};
+use crate::VM;
+
+/// Compiles `source` with the defaults of `Compiler::new`, panicking if it
+/// does not compile.
+pub(crate) fn compile(source: &str) -> Vec<u8> {
+    compile_with(&mut Compiler::new(), source)
+}
+
+/// Compiles `source` with `compiler`, which keeps what it built alongside
+/// the program, such as its debug info.
+pub(crate) fn compile_with(compiler: &mut Compiler, source: &str) -> Vec<u8> {
+    let statements = Parser::new(source).parse_program().unwrap();
+    compiler.compile(statements).unwrap()
+}
+
+/// Compiles `source` and runs it to its end.
+pub(crate) fn run(source: &str) -> VM {
+    run_bytecode(compile(source))
+}
+
+/// Runs `bytecode` to its end, on a stack of 100 values.
+pub(crate) fn run_bytecode(bytecode: Vec<u8>) -> VM {
+    let mut vm = VM::new(bytecode, 100);
+    vm.run().unwrap();
+    vm
+}
@@ -0,0 +1,397 @@
+use std::io;
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, Paragraph},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    disasm::{self, DisasmKind, DisasmLine},
+    StopReason, VMError, VM,
+};
+
+/// Where the debugged program is.
+#[derive(Debug, Clone, PartialEq)]
+enum Status {
+    Paused,
+    /// Stopped at a breakpoint by `resume`
+    Breakpoint,
+    Halted,
+    /// The program failed with this error
+    Failed(String),
+}
+
+/// A terminal debugger: the disassembly around the program counter, the
+/// stack, memory, and the source line being run when the program has debug
+/// info, driven by the keyboard.
+///
+/// `s` (or space) steps one instruction, `n` steps over calls, `o` steps
+/// out of the current call, `c` continues to the next breakpoint, `b`
+/// toggles a breakpoint on the instruction under the cursor, the arrow keys
+/// (or `j` / `k`) and page keys move the cursor, and `q` quits. The
+/// program's output goes to the terminal under the debugger, so the screen
+/// is redrawn in full whenever the program has run.
+pub struct Debugger {
+    vm: VM,
+    /// The disassembly, one entry per line of the code pane
+    lines: Vec<DisasmLine>,
+    /// The program's source, for a program with debug info
+    source: Option<String>,
+    /// Index into `lines` of the line under the cursor
+    cursor: usize,
+    status: Status,
+    /// Whether the program has run since the screen was last drawn in full
+    ran: bool,
+}
+
+impl Debugger {
+    /// Debugs the program loaded in `vm`, which has not started yet.
+    pub fn new(vm: VM, source: Option<String>) -> Self {
+        let lines = disasm::disassemble(vm.program());
+        let mut debugger = Debugger {
+            vm,
+            lines,
+            source,
+            cursor: 0,
+            status: Status::Paused,
+            ran: false,
+        };
+        debugger.follow_pc();
+        debugger
+    }
+
+    /// The VM being debugged.
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    /// Runs the debugger on the terminal until the user quits.
+    pub fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            if std::mem::take(&mut self.ran) {
+                terminal.clear()?;
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.handle_key(key.code) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Acts on a key press, returning `false` for the key that quits.
+    pub fn handle_key(&mut self, key: KeyCode) -> bool {
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('s') | KeyCode::Char(' ') => self.step(),
+            KeyCode::Char('n') => self.step_over(),
+            KeyCode::Char('o') => self.step_out(),
+            KeyCode::Char('c') => self.resume(),
+            KeyCode::Char('b') => self.toggle_breakpoint(),
+            KeyCode::Up | KeyCode::Char('k') => self.move_cursor(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_cursor(1),
+            KeyCode::PageUp => self.move_cursor(-20),
+            KeyCode::PageDown => self.move_cursor(20),
+            _ => {}
+        }
+        true
+    }
+
+    /// Executes one instruction, stepping into calls.
+    pub fn step(&mut self) {
+        self.advance(VM::step);
+    }
+
+    /// Executes one instruction, running the calls it makes to completion.
+    pub fn step_over(&mut self) {
+        self.advance(VM::step_over);
+    }
+
+    /// Runs until the current call returns.
+    pub fn step_out(&mut self) {
+        self.advance(VM::step_out);
+    }
+
+    /// Runs to the next breakpoint, or the end of the program.
+    pub fn resume(&mut self) {
+        self.advance(VM::resume);
+    }
+
+    fn advance(&mut self, run: fn(&mut VM) -> Result<StopReason, VMError>) {
+        if !self.is_running() {
+            return;
+        }
+        self.ran = true;
+        self.status = match run(&mut self.vm) {
+            Ok(StopReason::Stepped) => Status::Paused,
+            Ok(StopReason::Breakpoint(_)) => Status::Breakpoint,
+            Ok(StopReason::Halted) => Status::Halted,
+            Err(error) => Status::Failed(error.to_string()),
+        };
+        self.follow_pc();
+    }
+
+    /// Sets or clears a breakpoint on the instruction under the cursor.
+    pub fn toggle_breakpoint(&mut self) {
+        let Some(line) = self.lines.get(self.cursor) else {
+            return;
+        };
+        if !matches!(line.kind, DisasmKind::Instruction { .. }) {
+            return;
+        }
+        if !self.vm.remove_breakpoint(line.offset) {
+            self.vm.add_breakpoint(line.offset);
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        matches!(self.status, Status::Paused | Status::Breakpoint)
+    }
+
+    fn move_cursor(&mut self, by: isize) {
+        let last = self.lines.len().saturating_sub(1);
+        self.cursor = self.cursor.saturating_add_signed(by).min(last);
+    }
+
+    /// Moves the cursor to the next instruction to execute.
+    fn follow_pc(&mut self) {
+        let pc = self.vm.pc();
+        if let Some(index) = self.lines.iter().position(|line| line.offset == pc) {
+            self.cursor = index;
+        }
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
+        let [title, main, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(main);
+        let [stack, memory] =
+            Layout::vertical([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(right);
+
+        frame.render_widget(Line::from(self.title()).bold(), title);
+        if self.source.is_some() {
+            let [code, source] =
+                Layout::vertical([Constraint::Percentage(60), Constraint::Percentage(40)])
+                    .areas(left);
+            self.draw_code(frame, code);
+            self.draw_source(frame, source);
+        } else {
+            self.draw_code(frame, left);
+        }
+        self.draw_stack(frame, stack);
+        self.draw_memory(frame, memory);
+        frame.render_widget(
+            Line::from("s step  n next  o out  c continue  b breakpoint  ↑↓ move  q quit").dim(),
+            help,
+        );
+    }
+
+    fn title(&self) -> String {
+        let pc = self.vm.pc();
+        let at = match self.vm.debug_info().and_then(|info| info.span_at(pc)) {
+            Some(span) => format!("{:06} ({})", pc, span),
+            None => format!("{:06}", pc),
+        };
+        match &self.status {
+            Status::Paused => format!("Paused at {}", at),
+            Status::Breakpoint => format!("Breakpoint at {}", at),
+            Status::Halted => format!("Halted, exit code {}", self.vm.get_exit_code()),
+            Status::Failed(error) => format!("Error: {}", error),
+        }
+    }
+
+    fn draw_code(&self, frame: &mut Frame, area: Rect) {
+        let height = area.height.saturating_sub(2) as usize;
+        let first = self.cursor.saturating_sub(height / 2);
+        let pc = self.vm.pc();
+        let lines: Vec<Line> = self.lines[first..]
+            .iter()
+            .enumerate()
+            .take(height)
+            .map(|(index, line)| {
+                let breakpoint = if self.vm.breakpoints().any(|bp| bp == line.offset) {
+                    '●'
+                } else {
+                    ' '
+                };
+                let current = if line.offset == pc && self.is_running() {
+                    '▶'
+                } else {
+                    ' '
+                };
+                let mut text = Line::from(format!("{}{} {}", breakpoint, current, line));
+                if line.offset == pc {
+                    text = text.yellow();
+                }
+                if first + index == self.cursor {
+                    text = text.add_modifier(Modifier::REVERSED);
+                }
+                text
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Code")),
+            area,
+        );
+    }
+
+    fn draw_source(&self, frame: &mut Frame, area: Rect) {
+        let source = self.source.as_deref().unwrap_or_default();
+        let current = self
+            .vm
+            .debug_info()
+            .and_then(|info| info.span_at(self.vm.pc()))
+            // Only the entry file's source is shown
+            .filter(|span| span.file == 0)
+            .map(|span| span.line);
+        let height = area.height.saturating_sub(2) as usize;
+        let first = current.unwrap_or(1).saturating_sub(height / 2).max(1);
+        let lines: Vec<Line> = source
+            .lines()
+            .enumerate()
+            .skip(first - 1)
+            .take(height)
+            .map(|(index, text)| {
+                let line = Line::from(format!("{:>4} {}", index + 1, text));
+                if Some(index + 1) == current {
+                    line.style(Style::new().yellow())
+                } else {
+                    line
+                }
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Source")),
+            area,
+        );
+    }
+
+    fn draw_stack(&self, frame: &mut Frame, area: Rect) {
+        let stack = self.vm.get_stack();
+        let lines: Vec<Line> = stack
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(index, value)| Line::from(format!("{:>4}  {}", index, value)))
+            .collect();
+        let title = format!("Stack ({})", stack.len());
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    fn draw_memory(&self, frame: &mut Frame, area: Rect) {
+        let mut cells: Vec<_> = self.vm.get_memory().iter().collect();
+        cells.sort();
+        let debug_info = self.vm.debug_info();
+        let lines: Vec<Line> = cells
+            .iter()
+            .map(
+                |(addr, value)| match debug_info.and_then(|info| info.global_name(**addr)) {
+                    Some(name) => Line::from(format!("{:>8}  {} = {}", addr, name, value)),
+                    None => Line::from(format!("{:>8}  {}", addr, value)),
+                },
+            )
+            .collect();
+        let title = format!("Memory ({} cells)", cells.len());
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::{backend::TestBackend, crossterm::event::KeyCode, Terminal};
+
+    use super::{Debugger, Status};
+    use crate::{
+        compiler::{Compiler, OptLevel, Parser},
+        VM,
+    };
+
+    fn debugger(source: &str) -> Debugger {
+        let statements = Parser::new(source).parse_program().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_prelude(false);
+        compiler.set_opt_level(OptLevel::None);
+        compiler.set_debug_info(true);
+        let program = compiler.compile(statements).unwrap();
+        let mut vm = VM::new(program, 64);
+        vm.set_debug_info(compiler.debug_info().unwrap().clone());
+        Debugger::new(vm, Some(source.to_string()))
+    }
+
+    fn screen(debugger: &Debugger) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(80, 20)).unwrap();
+        terminal.draw(|frame| debugger.draw(frame)).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer
+            .content()
+            .chunks(buffer.area.width as usize)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>() + "\n")
+            .collect()
+    }
+
+    #[test]
+    fn steps_and_stops_at_breakpoints() {
+        let mut debugger = debugger("let x = 1;\nlet y = x + 2;\nlet z = y * 3;\n");
+        assert!(screen(&debugger).contains("Paused at 000000 (1:9)"));
+
+        // Three steps store `x`; the cursor follows
+        for _ in 0..3 {
+            assert!(debugger.handle_key(KeyCode::Char('s')));
+        }
+        assert_eq!(debugger.vm().get_memory()[&0], 1);
+        assert!(screen(&debugger).contains("▶ 000019  push 0"));
+
+        // A breakpoint on the start of line 3
+        for _ in 0..6 {
+            debugger.handle_key(KeyCode::Down);
+        }
+        debugger.handle_key(KeyCode::Char('b'));
+        assert_eq!(debugger.vm().breakpoints().collect::<Vec<_>>(), [49]);
+        debugger.handle_key(KeyCode::Char('c'));
+        assert_eq!(debugger.status, Status::Breakpoint);
+        let screen = screen(&debugger);
+        assert!(screen.contains("Breakpoint at 000049 (3:9)"));
+        assert!(screen.contains("●▶ 000049  push 1"));
+        assert!(screen.contains("       1  y = 3"));
+
+        debugger.handle_key(KeyCode::Char('c'));
+        assert_eq!(debugger.status, Status::Halted);
+        assert_eq!(debugger.vm().get_memory()[&2], 9);
+        // Nothing left to run
+        debugger.handle_key(KeyCode::Char('s'));
+        assert_eq!(debugger.status, Status::Halted);
+        assert!(!debugger.handle_key(KeyCode::Char('q')));
+    }
+
+    #[test]
+    fn reports_runtime_errors() {
+        let mut debugger = debugger("let a = [1];\nprint a[2];\n");
+        debugger.handle_key(KeyCode::Char('c'));
+        assert!(matches!(debugger.status, Status::Failed(_)));
+        assert!(screen(&debugger).contains("Error: "));
+    }
+
+    #[test]
+    fn toggles_nothing_in_an_empty_program() {
+        let mut debugger = Debugger::new(VM::new(Vec::new(), 64), None);
+        debugger.handle_key(KeyCode::Char('b'));
+        assert_eq!(debugger.vm().breakpoints().count(), 0);
+    }
+}
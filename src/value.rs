@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::stack_depth::stack_effect;
+use crate::{Opcode, VMError, VM};
+
+/// The kind of a value, which a VM with type checking enabled tracks for
+/// each stack slot and memory cell it knows it for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Int,
+    Float,
+    Bool,
+    Str,
+    /// What a memory cell that was never written holds
+    Nil,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Kind::Int => "int",
+            Kind::Float => "float",
+            Kind::Bool => "bool",
+            Kind::Str => "string",
+            Kind::Nil => "nil",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A value read from the operand stack as what it is rather than as the
+/// `i64` cell holding it: floats are stored as their bits, booleans as 0
+/// and 1, and strings as the address of their length-prefixed characters.
+/// Values whose kind the VM does not know, such as literal operands and
+/// the addresses of arrays and maps, are `Int`s.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Nil,
+}
+
+impl Value {
+    pub fn kind(&self) -> Kind {
+        match self {
+            Value::Int(_) => Kind::Int,
+            Value::Float(_) => Kind::Float,
+            Value::Bool(_) => Kind::Bool,
+            Value::Str(_) => Kind::Str,
+            Value::Nil => Kind::Nil,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{}", value),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Str(value) => write!(f, "{:?}", value),
+            Value::Nil => write!(f, "nil"),
+        }
+    }
+}
+
+/// The kinds an operand of an opcode may have. Operands of unknown kind
+/// are always accepted.
+#[derive(Debug, Clone, Copy)]
+enum Accepts {
+    /// An integer, booleans included
+    Integer,
+    Float,
+    Str,
+    /// A memory address, which strings are
+    Address,
+    Any,
+}
+
+impl Accepts {
+    fn allows(self, kind: Kind) -> bool {
+        match self {
+            Accepts::Integer => matches!(kind, Kind::Int | Kind::Bool),
+            Accepts::Float => kind == Kind::Float,
+            Accepts::Str => kind == Kind::Str,
+            Accepts::Address => matches!(kind, Kind::Int | Kind::Str),
+            Accepts::Any => true,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Accepts::Integer => "int",
+            Accepts::Float => "float",
+            Accepts::Str => "string",
+            Accepts::Address => "address",
+            Accepts::Any => "any value",
+        }
+    }
+}
+
+/// What the operands `opcode` pops must be, bottom first. Operands that
+/// are left out, such as the arguments of a host function, are not
+/// checked.
+fn operands(opcode: Opcode) -> &'static [Accepts] {
+    use Accepts::{Address, Any, Float, Integer, Str};
+    match opcode {
+        Opcode::Add
+        | Opcode::Sub
+        | Opcode::Mul
+        | Opcode::Div
+        | Opcode::Mod
+        | Opcode::Shl
+        | Opcode::Shr
+        | Opcode::Min
+        | Opcode::Max
+        | Opcode::Pow
+        | Opcode::Less
+        | Opcode::LessEqual
+        | Opcode::Greater
+        | Opcode::GreaterEqual
+        | Opcode::JumpIf => &[Integer, Integer],
+        Opcode::Neg
+        | Opcode::Abs
+        | Opcode::Inc
+        | Opcode::Dec
+        | Opcode::SqrtInt
+        | Opcode::IntToFloat
+        | Opcode::Print
+        | Opcode::PrintChar
+        | Opcode::WriteInt
+        | Opcode::Assert
+        | Opcode::Exit
+        | Opcode::Throw
+        | Opcode::NewArray
+        | Opcode::LoadStr
+        | Opcode::LoadLocal
+        | Opcode::Jump
+        | Opcode::Call
+        | Opcode::PushHandler
+        | Opcode::Enter
+        | Opcode::ConstPool
+        | Opcode::Recv => &[Integer],
+        Opcode::FAdd
+        | Opcode::FSub
+        | Opcode::FMul
+        | Opcode::FDiv
+        | Opcode::FMod
+        | Opcode::FEqual
+        | Opcode::FNotEqual
+        | Opcode::FLess
+        | Opcode::FLessEqual
+        | Opcode::FGreater
+        | Opcode::FGreaterEqual => &[Float, Float],
+        Opcode::FNeg | Opcode::FloatToInt | Opcode::PrintFloat | Opcode::WriteFloat => &[Float],
+        Opcode::Concat => &[Str, Str],
+        Opcode::PrintStr | Opcode::WriteStr => &[Str],
+        Opcode::Load => &[Address],
+        Opcode::Store => &[Any, Address],
+        Opcode::StoreLocal | Opcode::Send => &[Any, Integer],
+        Opcode::Equal | Opcode::NotEqual => &[Any, Any],
+        Opcode::Index | Opcode::MapGet | Opcode::MapHas => &[Any, Integer],
+        Opcode::SetIndex | Opcode::MapSet => &[Any, Integer, Any],
+        Opcode::Dup | Opcode::CallClosure => &[Any],
+        _ => &[],
+    }
+}
+
+/// The kind of what `opcode` pushes, if it always pushes the same kind.
+fn result(opcode: Opcode) -> Option<Kind> {
+    match opcode {
+        Opcode::Add
+        | Opcode::Sub
+        | Opcode::Mul
+        | Opcode::Div
+        | Opcode::Mod
+        | Opcode::Shl
+        | Opcode::Shr
+        | Opcode::Min
+        | Opcode::Max
+        | Opcode::Pow
+        | Opcode::Neg
+        | Opcode::Abs
+        | Opcode::Inc
+        | Opcode::Dec
+        | Opcode::SqrtInt
+        | Opcode::FloatToInt
+        | Opcode::Read => Some(Kind::Int),
+        Opcode::FAdd
+        | Opcode::FSub
+        | Opcode::FMul
+        | Opcode::FDiv
+        | Opcode::FMod
+        | Opcode::FNeg
+        | Opcode::IntToFloat => Some(Kind::Float),
+        Opcode::Equal
+        | Opcode::NotEqual
+        | Opcode::Less
+        | Opcode::LessEqual
+        | Opcode::Greater
+        | Opcode::GreaterEqual
+        | Opcode::FEqual
+        | Opcode::FNotEqual
+        | Opcode::FLess
+        | Opcode::FLessEqual
+        | Opcode::FGreater
+        | Opcode::FGreaterEqual
+        | Opcode::MapHas => Some(Kind::Bool),
+        Opcode::LoadStr | Opcode::Concat => Some(Kind::Str),
+        _ => None,
+    }
+}
+
+/// The kinds a VM knows its values by, for `VM::set_type_checking`. A slot
+/// or cell whose kind is `None` holds a word of unknown kind, such as a
+/// literal operand or the address of an array, which any opcode accepts.
+#[derive(Debug, Default)]
+pub(crate) struct TypeTracker {
+    /// Kind of each stack slot, bottom first
+    pub(crate) stack: Vec<Option<Kind>>,
+    /// Kinds of the memory cells written with a value of known kind
+    memory: HashMap<usize, Kind>,
+}
+
+/// What an instruction popped and read, to bring the kinds up to date once
+/// it has run.
+pub(crate) struct Pending {
+    /// The operands `operands` lists, bottom first, with their kinds
+    operands: Vec<(i64, Option<Kind>)>,
+    /// Kind of the memory cell a load reads
+    read: Option<Kind>,
+    /// Kinds of the values a `NewArray` takes
+    elements: Vec<Option<Kind>>,
+}
+
+impl TypeTracker {
+    /// Starts tracking a VM whose stack holds `depth` values of unknown
+    /// kind.
+    pub(crate) fn new(depth: usize) -> Self {
+        TypeTracker {
+            stack: vec![None; depth],
+            memory: HashMap::new(),
+        }
+    }
+
+    /// Forgets the kinds of every memory cell, for a VM whose memory was
+    /// cleared.
+    pub(crate) fn clear_memory(&mut self) {
+        self.memory.clear();
+    }
+
+    /// The kind of the value at `addr`: `Nil` for a cell never written.
+    fn memory_kind(&self, vm: &VM, addr: usize) -> Option<Kind> {
+        match self.memory.get(&addr) {
+            Some(kind) => Some(*kind),
+            None if vm.memory.contains_key(&addr) => None,
+            None => Some(Kind::Nil),
+        }
+    }
+
+    fn set_memory_kind(&mut self, addr: usize, kind: Option<Kind>) {
+        match kind {
+            Some(kind) => self.memory.insert(addr, kind),
+            None => self.memory.remove(&addr),
+        };
+    }
+
+    /// Checks the kinds of the operands `opcode` is about to pop, failing
+    /// with `TypeMismatch` for the first one it does not accept.
+    pub(crate) fn check(&self, vm: &VM, opcode: Opcode) -> Result<Pending, VMError> {
+        let accepts = operands(opcode);
+        let mut pending = Pending {
+            operands: Vec::new(),
+            read: None,
+            elements: Vec::new(),
+        };
+        // Too few operands is left for the instruction to report
+        let Some(base) = vm.stack.len().checked_sub(accepts.len()) else {
+            return Ok(pending);
+        };
+        for (i, accepts) in accepts.iter().enumerate() {
+            let (value, kind) = (vm.stack[base + i], self.stack[base + i]);
+            if let Some(found) = kind.filter(|kind| !accepts.allows(*kind)) {
+                return Err(VMError::TypeMismatch {
+                    opcode,
+                    expected: accepts.name(),
+                    found,
+                });
+            }
+            pending.operands.push((value, kind));
+        }
+        let operand = |i: usize| pending.operands[i].0;
+        pending.read = match opcode {
+            Opcode::Load => Some(operand(0) as usize),
+            Opcode::LoadLocal => Some(vm.fp.wrapping_add(operand(0) as usize)),
+            Opcode::Index => vm.element_addr(operand(0) as usize, operand(1)).ok(),
+            Opcode::MapGet => vm
+                .map_slot(operand(0) as usize, operand(1))
+                .ok()
+                .filter(|(_, found)| *found)
+                .map(|(slot, _)| slot + 2),
+            _ => None,
+        }
+        .and_then(|addr| self.memory_kind(vm, addr));
+        if opcode == Opcode::NewArray {
+            let count = usize::try_from(operand(0)).unwrap_or(usize::MAX);
+            if let Some(start) = base.checked_sub(count) {
+                pending.elements = self.stack[start..base].to_vec();
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Brings the kinds up to date with what `opcode` did, or, if it
+    /// failed, with the depth of the stack it left.
+    pub(crate) fn update(&mut self, vm: &VM, opcode: Opcode, pending: Pending, ran: bool) {
+        if !ran {
+            self.stack.resize(vm.stack.len(), None);
+            return;
+        }
+        let kind = |i: usize| pending.operands.get(i).and_then(|(_, kind)| *kind);
+        let results = match opcode {
+            Opcode::Dup => vec![kind(0); 2],
+            Opcode::Load | Opcode::LoadLocal | Opcode::Index | Opcode::MapGet => {
+                vec![pending.read]
+            }
+            // The closure is pushed back as the callee's environment
+            Opcode::CallClosure | Opcode::Throw => vec![kind(0)],
+            Opcode::NewArray | Opcode::CallHost => vec![None],
+            opcode => {
+                let pushes = stack_effect(opcode).map_or(0, |effect| effect.pushes);
+                vec![result(opcode); pushes]
+            }
+        };
+        let kept = vm.stack.len().saturating_sub(results.len());
+        self.stack.resize(kept, None);
+        self.stack.extend(results);
+        self.stack.resize(vm.stack.len(), None);
+
+        let operand = |i: usize| pending.operands[i].0;
+        match opcode {
+            Opcode::Store => self.set_memory_kind(operand(1) as usize, kind(0)),
+            Opcode::StoreLocal => {
+                self.set_memory_kind(vm.fp.wrapping_add(operand(1) as usize), kind(0))
+            }
+            Opcode::SetIndex => {
+                if let Ok(addr) = vm.element_addr(operand(0) as usize, operand(1)) {
+                    self.set_memory_kind(addr, kind(2));
+                }
+            }
+            Opcode::MapSet => {
+                if let Ok((slot, _)) = vm.map_slot(operand(0) as usize, operand(1)) {
+                    self.set_memory_kind(slot + 2, kind(2));
+                }
+            }
+            Opcode::NewArray => {
+                let array = vm.stack.last().map_or(0, |addr| *addr as usize);
+                for (i, kind) in pending.elements.into_iter().enumerate() {
+                    self.set_memory_kind(array + 1 + i, kind);
+                }
+            }
+            // A new frame's locals start out as zeros of unknown kind
+            Opcode::Enter => {
+                for addr in vm.fp..vm.frame_top {
+                    self.memory.remove(&addr);
+                }
+            }
+            _ => {}
+        }
+    }
+}